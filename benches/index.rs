@@ -0,0 +1,103 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Compare writing and reading json and binary indexes.
+//!
+//! Run with `cargo bench --bench index`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use conserve::blockdir::Address;
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::ScratchArchive;
+use conserve::*;
+
+const ENTRIES: usize = 100_000;
+const ENTRIES_PER_HUNK: usize = 10_000;
+
+/// Make entries like those of a typical source tree: files with owners and
+/// permissions, in directories of a hundred files, several to a block.
+fn sample_entries() -> Vec<IndexEntry> {
+    let hashes: Vec<BlockHash> = (0..ENTRIES / 10)
+        .map(|i| BlockHash::hash_bytes(&i.to_le_bytes()))
+        .collect();
+    let owner = Owner {
+        user: Some("alice".to_owned()),
+        group: Some("staff".to_owned()),
+        uid: None,
+        gid: None,
+    };
+    let mut entries: Vec<IndexEntry> = (0..ENTRIES)
+        .map(|i| IndexEntry {
+            apath: format!("/src/module{:04}/file{i:06}.rs", i / 100).into(),
+            kind: Kind::File,
+            mtime: 1_700_000_000 + i as i64,
+            mtime_nanos: (i as u32).wrapping_mul(7919) % 1_000_000_000,
+            unix_mode: UnixMode::from(0o644),
+            owner: owner.clone(),
+            acls: Acls::default(),
+            capability: None,
+            addrs: vec![Address {
+                hash: hashes[i / 10].clone(),
+                start: (i % 10) as u64 * 4000,
+                len: 4000,
+            }],
+            holes: Vec::new(),
+            target: None,
+            link_group: None,
+            device: None,
+        })
+        .collect();
+    entries.sort_by(|a, b| a.apath.cmp(&b.apath));
+    entries
+}
+
+fn write_index(band: &Band, format: IndexFormat, entries: &[IndexEntry]) {
+    let monitor = TestMonitor::arc();
+    let mut writer = band.index_builder().with_format(format);
+    for hunk in entries.chunks(ENTRIES_PER_HUNK) {
+        for entry in hunk {
+            writer.push_entry(entry.clone());
+        }
+        writer.finish_hunk(monitor.clone()).unwrap();
+    }
+    writer.finish(monitor).unwrap();
+}
+
+fn index(c: &mut Criterion) {
+    let entries = sample_entries();
+    let archive = ScratchArchive::new();
+    let mut group = c.benchmark_group("index");
+    group.throughput(Throughput::Elements(ENTRIES as u64));
+    group.sample_size(20);
+    for format in [IndexFormat::Json, IndexFormat::Binary] {
+        let band = Band::create(&archive).unwrap();
+        group.bench_with_input(
+            BenchmarkId::new("write", format!("{format:?}")),
+            &format,
+            |b, format| b.iter(|| write_index(&band, *format, &entries)),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("read", format!("{format:?}")),
+            &format,
+            |b, _format| {
+                b.iter(|| {
+                    assert_eq!(band.index().iter_entries().count(), ENTRIES);
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, index);
+criterion_main!(benches);
@@ -0,0 +1,117 @@
+/* Conserve backup system: C interface.
+ * Copyright 2024 Martin Pool.
+ *
+ * This program is free software; you can redistribute it and/or modify
+ * it under the terms of the GNU General Public License as published by
+ * the Free Software Foundation; either version 2 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ */
+
+#ifndef CONSERVE_H
+#define CONSERVE_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Strings passed in must be nul-terminated UTF-8. Strings passed out are owned
+ * by the library, and are valid only as described for each function. */
+
+typedef enum ConserveStatus {
+    CONSERVE_OK = 0,
+    /* The call failed: see conserve_last_error(). */
+    CONSERVE_ERROR = 1,
+    /* The operation was stopped by the should_cancel callback. */
+    CONSERVE_CANCELLED = 2,
+} ConserveStatus;
+
+/* What happened to an entry, passed to the entry_finished callback. */
+typedef enum ConserveOutcome {
+    CONSERVE_WRITTEN = 0,
+    /* The entry was the same as in the previous backup, or in the restore
+     * destination. */
+    CONSERVE_UNCHANGED = 1,
+    CONSERVE_SKIPPED = 2,
+    /* An error about the entry was passed to the error callback. */
+    CONSERVE_FAILED = 3,
+} ConserveOutcome;
+
+typedef struct ConserveArchive ConserveArchive;
+
+/* One version in an archive. The strings are valid only during the callback. */
+typedef struct ConserveVersion {
+    const char *band_id;
+    /* Unix time when the backup started. */
+    int64_t start_time;
+    /* Unix time when the backup finished, or -1 if it's incomplete. */
+    int64_t end_time;
+    bool complete;
+    /* The label given to the backup, or NULL. */
+    const char *label;
+} ConserveVersion;
+
+typedef void (*ConserveVersionCallback)(const ConserveVersion *version, void *user_data);
+
+/* Functions called to report the progress of a backup or restore; any may be
+ * NULL. They may be called from several threads at once. Strings passed to them
+ * are valid only during the call. */
+typedef struct ConserveCallbacks {
+    void (*entry_started)(const char *apath, void *user_data);
+    void (*entry_finished)(const char *apath, ConserveOutcome outcome, uint64_t bytes,
+                           void *user_data);
+    /* A problem that doesn't stop the operation, such as an unreadable file. */
+    void (*error)(const char *message, void *user_data);
+    /* Return true to stop the operation as soon as it safely can. */
+    bool (*should_cancel)(void *user_data);
+    void *user_data;
+} ConserveCallbacks;
+
+/* The version of the Conserve library. */
+const char *conserve_version(void);
+
+/* Why the last failed call on this thread failed, or NULL if the last call
+ * succeeded. Valid until the next call on this thread. */
+const char *conserve_last_error(void);
+
+/* Open an archive, given its URL or local path. On success, *archive_out is set
+ * to an archive that must be closed by conserve_archive_free(). */
+ConserveStatus conserve_archive_open(const char *url, ConserveArchive **archive_out);
+
+/* Close an archive. NULL is ignored. */
+void conserve_archive_free(ConserveArchive *archive);
+
+/* Call callback for each version in the archive, from first to last. Versions
+ * whose metadata can't be read are left out. It's an error for callback to be
+ * NULL. */
+ConserveStatus conserve_list_versions(const ConserveArchive *archive,
+                                      ConserveVersionCallback callback, void *user_data);
+
+/* Back up a source directory into a new version in the archive. label and
+ * callbacks may be NULL. If errors_out is not NULL, it's set to the number of
+ * problems, such as unreadable files, that didn't stop the backup. */
+ConserveStatus conserve_backup(const ConserveArchive *archive, const char *source,
+                               const char *label, const ConserveCallbacks *callbacks,
+                               size_t *errors_out);
+
+/* Restore a version into an empty or new destination directory. If band_id is
+ * NULL, the latest complete version is restored. callbacks may be NULL. If
+ * errors_out is not NULL, it's set to the number of problems, such as files that
+ * couldn't be written, that didn't stop the restore. */
+ConserveStatus conserve_restore(const ConserveArchive *archive, const char *band_id,
+                                const char *destination, const ConserveCallbacks *callbacks,
+                                size_t *errors_out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CONSERVE_H */
@@ -0,0 +1,389 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! C interface to Conserve, so that programs written in other languages, such as
+//! GUI backup frontends, can open archives, list their versions, and make and
+//! restore backups without running the command line tool.
+//!
+//! The functions are declared for C in `include/conserve.h`.
+//!
+//! Functions that can fail return a [ConserveStatus], and a description of the
+//! last failure on the calling thread is available from [conserve_last_error].
+//! Panics are caught and reported as errors, rather than unwinding into the caller.
+
+#![allow(clippy::missing_safety_doc)] // Described in the header.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use conserve::counters::Counter;
+use conserve::monitor::task::{Task, TaskList};
+use conserve::monitor::{EntryOutcome, Monitor};
+use conserve::{
+    backup, open_transport, restore, Apath, Archive, BackupOptions, BandId, BandSelectionPolicy,
+    Error, Kind, RestoreConflict, RestoreOptions,
+};
+
+/// Whether a call succeeded.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ConserveStatus {
+    Ok = 0,
+    /// The call failed: see [conserve_last_error].
+    Error = 1,
+    /// The operation was stopped by the `should_cancel` callback.
+    Cancelled = 2,
+}
+
+/// What happened to an entry, passed to the `entry_finished` callback.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ConserveOutcome {
+    Written = 0,
+    Unchanged = 1,
+    Skipped = 2,
+    Failed = 3,
+}
+
+impl From<EntryOutcome> for ConserveOutcome {
+    fn from(outcome: EntryOutcome) -> Self {
+        match outcome {
+            EntryOutcome::Written => ConserveOutcome::Written,
+            EntryOutcome::Unchanged => ConserveOutcome::Unchanged,
+            EntryOutcome::Skipped => ConserveOutcome::Skipped,
+            EntryOutcome::Failed => ConserveOutcome::Failed,
+        }
+    }
+}
+
+/// An open archive, from [conserve_archive_open].
+pub struct ConserveArchive {
+    archive: Archive,
+}
+
+/// One version in an archive, passed to the callback of [conserve_list_versions].
+///
+/// The strings are only valid during the callback.
+#[repr(C)]
+pub struct ConserveVersion {
+    pub band_id: *const c_char,
+    /// Unix time when the backup started.
+    pub start_time: i64,
+    /// Unix time when the backup finished, or -1 if it's incomplete.
+    pub end_time: i64,
+    pub complete: bool,
+    /// The label given to the backup, or NULL.
+    pub label: *const c_char,
+}
+
+/// Called for each version by [conserve_list_versions]; may be NULL from C, which is
+/// reported as an error.
+pub type ConserveVersionCallback =
+    Option<extern "C" fn(version: *const ConserveVersion, user_data: *mut c_void)>;
+
+/// Functions called to report progress of a backup or restore; any may be NULL.
+///
+/// They may be called from several threads at once. Strings passed to them are
+/// only valid during the call.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct ConserveCallbacks {
+    pub entry_started: Option<extern "C" fn(apath: *const c_char, user_data: *mut c_void)>,
+    pub entry_finished: Option<
+        extern "C" fn(
+            apath: *const c_char,
+            outcome: ConserveOutcome,
+            bytes: u64,
+            user_data: *mut c_void,
+        ),
+    >,
+    /// A problem that doesn't stop the operation, such as an unreadable file.
+    pub error: Option<extern "C" fn(message: *const c_char, user_data: *mut c_void)>,
+    /// Return true to stop the operation as soon as it safely can.
+    pub should_cancel: Option<extern "C" fn(user_data: *mut c_void) -> bool>,
+    pub user_data: *mut c_void,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Why a call failed.
+enum Failure {
+    Cancelled,
+    Message(String),
+}
+
+impl From<Error> for Failure {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Cancelled => Failure::Cancelled,
+            err => Failure::Message(err.to_string()),
+        }
+    }
+}
+
+/// Convert a string for C, dropping any nul characters.
+fn c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', "")).expect("String has no nuls")
+}
+
+/// Run the body of an exported function, catching panics, and remembering any
+/// failure for [conserve_last_error].
+fn call(body: impl FnOnce() -> Result<(), Failure>) -> ConserveStatus {
+    let (status, message) = match catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => (ConserveStatus::Ok, None),
+        Ok(Err(Failure::Cancelled)) => (ConserveStatus::Cancelled, Some("Cancelled".to_owned())),
+        Ok(Err(Failure::Message(message))) => (ConserveStatus::Error, Some(message)),
+        Err(_) => (ConserveStatus::Error, Some("Conserve panicked".to_owned())),
+    };
+    LAST_ERROR.with(|last| *last.borrow_mut() = message.as_deref().map(c_string));
+    status
+}
+
+/// Read a string argument, which must not be NULL.
+unsafe fn str_arg<'a>(s: *const c_char, name: &str) -> Result<&'a str, Failure> {
+    opt_str_arg(s, name)?.ok_or_else(|| Failure::Message(format!("{name} is NULL")))
+}
+
+/// Read a string argument that may be NULL.
+unsafe fn opt_str_arg<'a>(s: *const c_char, name: &str) -> Result<Option<&'a str>, Failure> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(Some)
+        .map_err(|_| Failure::Message(format!("{name} is not valid UTF-8")))
+}
+
+unsafe fn archive_arg<'a>(archive: *const ConserveArchive) -> Result<&'a Archive, Failure> {
+    archive
+        .as_ref()
+        .map(|archive| &archive.archive)
+        .ok_or_else(|| Failure::Message("archive is NULL".to_owned()))
+}
+
+/// Passes events from the library to the caller's callbacks, and counts errors.
+struct CallbackMonitor {
+    callbacks: Option<ConserveCallbacks>,
+    errors: AtomicUsize,
+    task_list: Mutex<TaskList>,
+}
+
+// Safety: callers of the C API promise that the callbacks and their user data may
+// be used from any thread.
+unsafe impl Send for CallbackMonitor {}
+unsafe impl Sync for CallbackMonitor {}
+
+impl CallbackMonitor {
+    unsafe fn new(callbacks: *const ConserveCallbacks) -> Arc<CallbackMonitor> {
+        Arc::new(CallbackMonitor {
+            callbacks: callbacks.as_ref().copied(),
+            errors: AtomicUsize::new(0),
+            task_list: Mutex::new(TaskList::default()),
+        })
+    }
+
+    fn error_count(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+}
+
+impl Monitor for CallbackMonitor {
+    fn count(&self, _counter: Counter, _increment: usize) {}
+
+    fn set_counter(&self, _counter: Counter, _value: usize) {}
+
+    fn error(&self, error: Error) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        if let Some(ConserveCallbacks {
+            error: Some(callback),
+            user_data,
+            ..
+        }) = self.callbacks
+        {
+            callback(c_string(&error.to_string()).as_ptr(), user_data);
+        }
+    }
+
+    fn restore_conflict(&self, _conflict: RestoreConflict) {}
+
+    fn start_task(&self, name: String) -> Task {
+        self.task_list.lock().unwrap().start_task(name)
+    }
+
+    fn entry_started(&self, apath: &Apath, _kind: Kind) {
+        if let Some(ConserveCallbacks {
+            entry_started: Some(callback),
+            user_data,
+            ..
+        }) = self.callbacks
+        {
+            callback(c_string(apath).as_ptr(), user_data);
+        }
+    }
+
+    fn entry_finished(&self, apath: &Apath, _kind: Kind, outcome: EntryOutcome, bytes: u64) {
+        if let Some(ConserveCallbacks {
+            entry_finished: Some(callback),
+            user_data,
+            ..
+        }) = self.callbacks
+        {
+            callback(c_string(apath).as_ptr(), outcome.into(), bytes, user_data);
+        }
+    }
+
+    fn should_cancel(&self) -> bool {
+        match self.callbacks {
+            Some(ConserveCallbacks {
+                should_cancel: Some(callback),
+                user_data,
+                ..
+            }) => callback(user_data),
+            _ => false,
+        }
+    }
+}
+
+/// The version of the Conserve library.
+#[no_mangle]
+pub extern "C" fn conserve_version() -> *const c_char {
+    static VERSION: OnceLock<CString> = OnceLock::new();
+    VERSION
+        .get_or_init(|| c_string(conserve::version()))
+        .as_ptr()
+}
+
+/// A description of why the last failed call on this thread failed, or NULL if the
+/// last call succeeded.
+///
+/// The string is valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn conserve_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |s| s.as_ptr()))
+}
+
+/// Open an archive, given its URL or local path.
+#[no_mangle]
+pub unsafe extern "C" fn conserve_archive_open(
+    url: *const c_char,
+    archive_out: *mut *mut ConserveArchive,
+) -> ConserveStatus {
+    call(|| {
+        if archive_out.is_null() {
+            return Err(Failure::Message("archive_out is NULL".to_owned()));
+        }
+        let archive = Archive::open(open_transport(str_arg(url, "url")?)?)?;
+        *archive_out = Box::into_raw(Box::new(ConserveArchive { archive }));
+        Ok(())
+    })
+}
+
+/// Close an archive opened by [conserve_archive_open]. NULL is ignored.
+#[no_mangle]
+pub unsafe extern "C" fn conserve_archive_free(archive: *mut ConserveArchive) {
+    if !archive.is_null() {
+        drop(Box::from_raw(archive));
+    }
+}
+
+/// Call `callback` for each version in the archive, from first to last.
+///
+/// Versions whose metadata can't be read are left out.
+#[no_mangle]
+pub unsafe extern "C" fn conserve_list_versions(
+    archive: *const ConserveArchive,
+    callback: ConserveVersionCallback,
+    user_data: *mut c_void,
+) -> ConserveStatus {
+    call(|| {
+        let archive = archive_arg(archive)?;
+        let callback = callback.ok_or_else(|| Failure::Message("callback is NULL".to_owned()))?;
+        for info in archive.list_band_infos(CallbackMonitor::new(ptr::null()))? {
+            let band_id = c_string(&info.id.to_string());
+            let label = info.label.as_deref().map(c_string);
+            let version = ConserveVersion {
+                band_id: band_id.as_ptr(),
+                start_time: info.start_time.unix_timestamp(),
+                end_time: info.end_time.map_or(-1, |t| t.unix_timestamp()),
+                complete: info.is_closed,
+                label: label.as_ref().map_or(ptr::null(), |l| l.as_ptr()),
+            };
+            callback(&version, user_data);
+        }
+        Ok(())
+    })
+}
+
+/// Back up a source directory into a new version in the archive.
+///
+/// `label` and `callbacks` may be NULL. If `errors_out` is not NULL, it's set to
+/// the number of problems, such as unreadable files, that didn't stop the backup.
+#[no_mangle]
+pub unsafe extern "C" fn conserve_backup(
+    archive: *const ConserveArchive,
+    source: *const c_char,
+    label: *const c_char,
+    callbacks: *const ConserveCallbacks,
+    errors_out: *mut usize,
+) -> ConserveStatus {
+    call(|| {
+        let archive = archive_arg(archive)?;
+        let source = str_arg(source, "source")?;
+        let options =
+            BackupOptions::default().with_label(opt_str_arg(label, "label")?.map(String::from));
+        let monitor = CallbackMonitor::new(callbacks);
+        let result = backup(archive, source.as_ref(), &options, monitor.clone());
+        if let Some(errors_out) = errors_out.as_mut() {
+            *errors_out = monitor.error_count();
+        }
+        result?;
+        Ok(())
+    })
+}
+
+/// Restore a version from the archive into an empty or new destination directory.
+///
+/// If `band_id` is NULL, the latest complete version is restored. `callbacks` may
+/// be NULL. If `errors_out` is not NULL, it's set to the number of problems, such
+/// as files that couldn't be written, that didn't stop the restore.
+#[no_mangle]
+pub unsafe extern "C" fn conserve_restore(
+    archive: *const ConserveArchive,
+    band_id: *const c_char,
+    destination: *const c_char,
+    callbacks: *const ConserveCallbacks,
+    errors_out: *mut usize,
+) -> ConserveStatus {
+    call(|| {
+        let archive = archive_arg(archive)?;
+        let destination = str_arg(destination, "destination")?;
+        let mut options = RestoreOptions::default();
+        if let Some(band_id) = opt_str_arg(band_id, "band_id")? {
+            let band_id: BandId = band_id.parse()?;
+            options = options.with_band_selection(BandSelectionPolicy::Specified(band_id));
+        }
+        let monitor = CallbackMonitor::new(callbacks);
+        let result = restore(archive, destination.as_ref(), &options, monitor.clone());
+        if let Some(errors_out) = errors_out.as_mut() {
+            *errors_out = monitor.error_count();
+        }
+        result?;
+        Ok(())
+    })
+}
@@ -0,0 +1,270 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests of the C interface, called from Rust as a C program would.
+
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::fs;
+use std::path::Path;
+use std::ptr;
+use std::sync::Mutex;
+
+use tempfile::TempDir;
+
+use conserve::Archive;
+use conserve_ffi::*;
+
+fn c_path(path: &Path) -> CString {
+    CString::new(path.to_str().unwrap()).unwrap()
+}
+
+fn last_error() -> Option<String> {
+    let message = conserve_last_error();
+    (!message.is_null()).then(|| {
+        unsafe { CStr::from_ptr(message) }
+            .to_str()
+            .unwrap()
+            .to_owned()
+    })
+}
+
+/// What the callbacks saw.
+#[derive(Default)]
+struct Seen {
+    started: Mutex<Vec<String>>,
+    finished: Mutex<Vec<(String, ConserveOutcome, u64)>>,
+    /// Ask to cancel when this apath is finished.
+    cancel_after: Option<String>,
+    cancelled: Mutex<bool>,
+}
+
+unsafe fn seen<'a>(user_data: *mut c_void) -> &'a Seen {
+    &*(user_data as *const Seen)
+}
+
+extern "C" fn entry_started(apath: *const c_char, user_data: *mut c_void) {
+    let apath = unsafe { CStr::from_ptr(apath) }
+        .to_str()
+        .unwrap()
+        .to_owned();
+    unsafe { seen(user_data) }
+        .started
+        .lock()
+        .unwrap()
+        .push(apath);
+}
+
+extern "C" fn entry_finished(
+    apath: *const c_char,
+    outcome: ConserveOutcome,
+    bytes: u64,
+    user_data: *mut c_void,
+) {
+    let apath = unsafe { CStr::from_ptr(apath) }
+        .to_str()
+        .unwrap()
+        .to_owned();
+    let seen = unsafe { seen(user_data) };
+    if seen.cancel_after.as_ref() == Some(&apath) {
+        *seen.cancelled.lock().unwrap() = true;
+    }
+    seen.finished.lock().unwrap().push((apath, outcome, bytes));
+}
+
+extern "C" fn should_cancel(user_data: *mut c_void) -> bool {
+    *unsafe { seen(user_data) }.cancelled.lock().unwrap()
+}
+
+fn callbacks(seen: &Seen) -> ConserveCallbacks {
+    ConserveCallbacks {
+        entry_started: Some(entry_started),
+        entry_finished: Some(entry_finished),
+        error: None,
+        should_cancel: Some(should_cancel),
+        user_data: seen as *const Seen as *mut c_void,
+    }
+}
+
+extern "C" fn collect_version(version: *const ConserveVersion, user_data: *mut c_void) {
+    let version = unsafe { &*version };
+    let versions = unsafe { &mut *(user_data as *mut Vec<(String, bool, Option<String>)>) };
+    let label = (!version.label.is_null()).then(|| {
+        unsafe { CStr::from_ptr(version.label) }
+            .to_str()
+            .unwrap()
+            .to_owned()
+    });
+    assert!(version.start_time > 0);
+    assert_eq!(version.complete, version.end_time >= version.start_time);
+    versions.push((
+        unsafe { CStr::from_ptr(version.band_id) }
+            .to_str()
+            .unwrap()
+            .to_owned(),
+        version.complete,
+        label,
+    ));
+}
+
+/// Make an empty archive and a source tree with two files.
+fn setup() -> (TempDir, *mut ConserveArchive) {
+    let tmp = TempDir::new().unwrap();
+    Archive::create_path(&tmp.path().join("archive")).unwrap();
+    fs::create_dir(tmp.path().join("src")).unwrap();
+    fs::write(tmp.path().join("src/hello"), "hello").unwrap();
+    fs::write(tmp.path().join("src/world"), "world!").unwrap();
+    let mut archive = ptr::null_mut();
+    let status = unsafe {
+        conserve_archive_open(c_path(&tmp.path().join("archive")).as_ptr(), &mut archive)
+    };
+    assert_eq!(status, ConserveStatus::Ok);
+    assert!(!archive.is_null());
+    (tmp, archive)
+}
+
+#[test]
+fn version() {
+    let version = unsafe { CStr::from_ptr(conserve_version()) };
+    assert_eq!(version.to_str().unwrap(), conserve::version());
+}
+
+#[test]
+fn open_nonexistent_archive() {
+    let tmp = TempDir::new().unwrap();
+    let mut archive = ptr::null_mut();
+    let status = unsafe { conserve_archive_open(c_path(tmp.path()).as_ptr(), &mut archive) };
+    assert_eq!(status, ConserveStatus::Error);
+    assert!(archive.is_null());
+    assert!(last_error().unwrap().contains("Not a Conserve archive"));
+
+    let status = unsafe { conserve_archive_open(ptr::null(), &mut archive) };
+    assert_eq!(status, ConserveStatus::Error);
+    assert_eq!(last_error().unwrap(), "url is NULL");
+}
+
+#[test]
+fn backup_list_and_restore() {
+    let (tmp, archive) = setup();
+    let seen = Seen::default();
+    let mut errors = 99;
+    let label = CString::new("first").unwrap();
+    let status = unsafe {
+        conserve_backup(
+            archive,
+            c_path(&tmp.path().join("src")).as_ptr(),
+            label.as_ptr(),
+            &callbacks(&seen),
+            &mut errors,
+        )
+    };
+    assert_eq!(status, ConserveStatus::Ok);
+    assert_eq!(last_error(), None);
+    assert_eq!(errors, 0);
+    assert_eq!(*seen.started.lock().unwrap(), ["/", "/hello", "/world"]);
+    assert_eq!(
+        *seen.finished.lock().unwrap(),
+        [
+            ("/".to_owned(), ConserveOutcome::Written, 0),
+            ("/hello".to_owned(), ConserveOutcome::Written, 5),
+            ("/world".to_owned(), ConserveOutcome::Written, 6),
+        ]
+    );
+
+    let status = unsafe {
+        conserve_backup(
+            archive,
+            c_path(&tmp.path().join("src")).as_ptr(),
+            ptr::null(),
+            ptr::null(),
+            ptr::null_mut(),
+        )
+    };
+    assert_eq!(status, ConserveStatus::Ok);
+
+    let mut versions: Vec<(String, bool, Option<String>)> = Vec::new();
+    let status = unsafe {
+        conserve_list_versions(
+            archive,
+            Some(collect_version),
+            &mut versions as *mut _ as *mut c_void,
+        )
+    };
+    assert_eq!(status, ConserveStatus::Ok);
+    assert_eq!(
+        versions,
+        [
+            ("b0000".to_owned(), true, Some("first".to_owned())),
+            ("b0001".to_owned(), true, None),
+        ]
+    );
+
+    let band_id = CString::new("b0000").unwrap();
+    let dest = tmp.path().join("dest");
+    let status = unsafe {
+        conserve_restore(
+            archive,
+            band_id.as_ptr(),
+            c_path(&dest).as_ptr(),
+            ptr::null(),
+            ptr::null_mut(),
+        )
+    };
+    assert_eq!(status, ConserveStatus::Ok);
+    assert_eq!(fs::read_to_string(dest.join("world")).unwrap(), "world!");
+
+    unsafe { conserve_archive_free(archive) };
+}
+
+#[test]
+fn list_versions_with_null_callback() {
+    let (_tmp, archive) = setup();
+    let status = unsafe { conserve_list_versions(archive, None, ptr::null_mut()) };
+    assert_eq!(status, ConserveStatus::Error);
+    assert_eq!(last_error().unwrap(), "callback is NULL");
+    unsafe { conserve_archive_free(archive) };
+}
+
+#[test]
+fn cancel_restore() {
+    let (tmp, archive) = setup();
+    let status = unsafe {
+        conserve_backup(
+            archive,
+            c_path(&tmp.path().join("src")).as_ptr(),
+            ptr::null(),
+            ptr::null(),
+            ptr::null_mut(),
+        )
+    };
+    assert_eq!(status, ConserveStatus::Ok);
+
+    let seen = Seen {
+        cancel_after: Some("/hello".to_owned()),
+        ..Default::default()
+    };
+    let dest = tmp.path().join("dest");
+    let status = unsafe {
+        conserve_restore(
+            archive,
+            ptr::null(),
+            c_path(&dest).as_ptr(),
+            &callbacks(&seen),
+            ptr::null_mut(),
+        )
+    };
+    assert_eq!(status, ConserveStatus::Cancelled);
+    assert_eq!(last_error().unwrap(), "Cancelled");
+    assert!(dest.join("hello").is_file());
+    assert!(!dest.join("world").exists());
+
+    unsafe { conserve_archive_free(archive) };
+}
@@ -0,0 +1,375 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! POSIX access control lists.
+//!
+//! On Linux, ACLs are stored in the `system.posix_acl_access` and
+//! `system.posix_acl_default` extended attributes. In the archive they're
+//! stored in a text form like `user::rw-,user:1000:r--,group::r--,mask::r--,other::---`.
+//!
+//! Users and groups in ACLs are recorded by numeric id.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+use linux::{read_acls, write_acls};
+
+/// Version number at the start of the Linux ACL xattr format.
+const XATTR_VERSION: u32 = 2;
+
+const TAG_USER_OBJ: u16 = 0x01;
+const TAG_USER: u16 = 0x02;
+const TAG_GROUP_OBJ: u16 = 0x04;
+const TAG_GROUP: u16 = 0x08;
+const TAG_MASK: u16 = 0x10;
+const TAG_OTHER: u16 = 0x20;
+
+/// Id stored in the xattr for entries that don't name a user or group.
+const UNDEFINED_ID: u32 = u32::MAX;
+
+/// Who an ACL entry applies to.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AclTag {
+    /// The file's owner.
+    UserObj,
+    /// A user, by uid.
+    User(u32),
+    /// The file's group.
+    GroupObj,
+    /// A group, by gid.
+    Group(u32),
+    /// The maximum permissions granted to named users and groups.
+    Mask,
+    /// Everyone else.
+    Other,
+}
+
+/// One entry in an ACL: a tag, and read/write/execute permission bits.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct AclEntry {
+    pub tag: AclTag,
+    /// Permission bits: 4 for read, 2 for write, 1 for execute.
+    pub perm: u8,
+}
+
+/// A POSIX access control list.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Acl {
+    pub entries: Vec<AclEntry>,
+}
+
+/// The access ACL and, for directories, the default ACL of a file.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Acls {
+    /// Controls access to the file itself, if it has more than the Unix permissions.
+    #[serde(rename = "acl", default, skip_serializing_if = "Option::is_none")]
+    pub access: Option<Acl>,
+
+    /// Inherited by new files created inside a directory.
+    #[serde(
+        rename = "default_acl",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub default: Option<Acl>,
+}
+
+impl Acls {
+    pub fn is_none(&self) -> bool {
+        self.access.is_none() && self.default.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.access = None;
+        self.default = None;
+    }
+
+    /// Read the ACLs of a file or directory.
+    ///
+    /// Returns no ACLs on platforms and filesystems that don't support them.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Acls> {
+        #[cfg(target_os = "linux")]
+        {
+            read_acls(path.as_ref())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = path;
+            Ok(Acls::default())
+        }
+    }
+
+    /// Set the ACLs on a file or directory.
+    ///
+    /// Does nothing if there are no ACLs. Fails with [io::ErrorKind::Unsupported]
+    /// if there are ACLs, but they can't be set on this platform or filesystem.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        if self.is_none() {
+            return Ok(());
+        }
+        #[cfg(target_os = "linux")]
+        {
+            write_acls(self, path.as_ref())
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = path;
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+}
+
+impl Acl {
+    /// Decode an ACL from the Linux extended attribute format.
+    pub fn from_xattr(value: &[u8]) -> Result<Acl> {
+        let invalid = || Error::InvalidAcl {
+            acl: format!("{value:?}"),
+        };
+        if value.len() < 4
+            || u32::from_le_bytes(value[..4].try_into().unwrap()) != XATTR_VERSION
+            || value.len() % 8 != 4
+        {
+            return Err(invalid());
+        }
+        let mut entries = Vec::with_capacity(value.len() / 8);
+        for entry in value[4..].chunks_exact(8) {
+            let tag = u16::from_le_bytes([entry[0], entry[1]]);
+            let perm = u16::from_le_bytes([entry[2], entry[3]]);
+            let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+            let tag = match tag {
+                TAG_USER_OBJ => AclTag::UserObj,
+                TAG_USER => AclTag::User(id),
+                TAG_GROUP_OBJ => AclTag::GroupObj,
+                TAG_GROUP => AclTag::Group(id),
+                TAG_MASK => AclTag::Mask,
+                TAG_OTHER => AclTag::Other,
+                _ => return Err(invalid()),
+            };
+            entries.push(AclEntry {
+                tag,
+                perm: u8::try_from(perm)
+                    .ok()
+                    .filter(|p| *p <= 7)
+                    .ok_or_else(invalid)?,
+            });
+        }
+        Ok(Acl { entries })
+    }
+
+    /// Encode this ACL in the Linux extended attribute format.
+    pub fn to_xattr(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4 + 8 * self.entries.len());
+        out.extend_from_slice(&XATTR_VERSION.to_le_bytes());
+        for entry in &self.entries {
+            let (tag, id) = match entry.tag {
+                AclTag::UserObj => (TAG_USER_OBJ, UNDEFINED_ID),
+                AclTag::User(uid) => (TAG_USER, uid),
+                AclTag::GroupObj => (TAG_GROUP_OBJ, UNDEFINED_ID),
+                AclTag::Group(gid) => (TAG_GROUP, gid),
+                AclTag::Mask => (TAG_MASK, UNDEFINED_ID),
+                AclTag::Other => (TAG_OTHER, UNDEFINED_ID),
+            };
+            out.extend_from_slice(&tag.to_le_bytes());
+            out.extend_from_slice(&u16::from(entry.perm).to_le_bytes());
+            out.extend_from_slice(&id.to_le_bytes());
+        }
+        out
+    }
+}
+
+impl fmt::Display for AclEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.tag {
+            AclTag::UserObj => write!(f, "user::")?,
+            AclTag::User(uid) => write!(f, "user:{uid}:")?,
+            AclTag::GroupObj => write!(f, "group::")?,
+            AclTag::Group(gid) => write!(f, "group:{gid}:")?,
+            AclTag::Mask => write!(f, "mask::")?,
+            AclTag::Other => write!(f, "other::")?,
+        }
+        for (bit, c) in [(4, 'r'), (2, 'w'), (1, 'x')] {
+            write!(f, "{}", if self.perm & bit != 0 { c } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for AclEntry {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<AclEntry> {
+        let invalid = || Error::InvalidAcl { acl: s.to_owned() };
+        let mut parts = s.split(':');
+        let (Some(tag), Some(id), Some(perm_str), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        let parse_id = |id: &str| id.parse::<u32>().map_err(|_| invalid());
+        let tag = match (tag, id) {
+            ("user", "") => AclTag::UserObj,
+            ("user", uid) => AclTag::User(parse_id(uid)?),
+            ("group", "") => AclTag::GroupObj,
+            ("group", gid) => AclTag::Group(parse_id(gid)?),
+            ("mask", "") => AclTag::Mask,
+            ("other", "") => AclTag::Other,
+            _ => return Err(invalid()),
+        };
+        let mut perm = 0;
+        let mut chars = perm_str.chars();
+        for (bit, c) in [(4, 'r'), (2, 'w'), (1, 'x')] {
+            match chars.next() {
+                Some(x) if x == c => perm |= bit,
+                Some('-') => {}
+                _ => return Err(invalid()),
+            }
+        }
+        if chars.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(AclEntry { tag, perm })
+    }
+}
+
+impl fmt::Display for Acl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{entry}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Acl {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Acl> {
+        if s.is_empty() {
+            return Ok(Acl {
+                entries: Vec::new(),
+            });
+        }
+        Ok(Acl {
+            entries: s.split(',').map(str::parse).collect::<Result<_>>()?,
+        })
+    }
+}
+
+impl From<Acl> for String {
+    fn from(acl: Acl) -> String {
+        acl.to_string()
+    }
+}
+
+impl TryFrom<String> for Acl {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Acl> {
+        s.parse()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn text_round_trip() {
+        let text = "user::rw-,user:1000:r--,group::r-x,group:20:rwx,mask::rwx,other::---";
+        let acl: Acl = text.parse().unwrap();
+        assert_eq!(acl.entries.len(), 6);
+        assert_eq!(
+            acl.entries[1],
+            AclEntry {
+                tag: AclTag::User(1000),
+                perm: 4
+            }
+        );
+        assert_eq!(acl.to_string(), text);
+    }
+
+    #[test]
+    fn empty_acl_round_trip() {
+        let acl = Acl {
+            entries: Vec::new(),
+        };
+        assert_eq!(acl.to_string(), "");
+        assert_eq!("".parse::<Acl>().unwrap(), acl);
+        let acls = Acls {
+            access: Some(acl),
+            default: None,
+        };
+        let json = serde_json::to_string(&acls).unwrap();
+        assert_eq!(json, r#"{"acl":""}"#);
+        assert_eq!(serde_json::from_str::<Acls>(&json).unwrap(), acls);
+    }
+
+    #[test]
+    fn xattr_round_trip() {
+        let acl: Acl = "user::rwx,user:1000:r--,group::r-x,mask::r-x,other::r--"
+            .parse()
+            .unwrap();
+        let xattr = acl.to_xattr();
+        assert_eq!(xattr.len(), 4 + 5 * 8);
+        assert_eq!(&xattr[..4], &[2, 0, 0, 0]);
+        assert_eq!(Acl::from_xattr(&xattr).unwrap(), acl);
+    }
+
+    #[test]
+    fn invalid_acls() {
+        for bad in [
+            ",",
+            "user",
+            "user::rw",
+            "user::rwxx",
+            "wibble::rwx",
+            "mask:1:rwx",
+            "user::xwr",
+        ] {
+            assert!(bad.parse::<Acl>().is_err(), "{bad:?} should be invalid");
+        }
+        assert!(Acl::from_xattr(b"\x02\x00\x00\x00\x01").is_err());
+        assert!(Acl::from_xattr(b"\x01\x00\x00\x00").is_err());
+    }
+
+    #[test]
+    fn serialize_acls() {
+        let acls = Acls {
+            access: Some(
+                "user::rw-,user:1:r--,group::r--,mask::r--,other::---"
+                    .parse()
+                    .unwrap(),
+            ),
+            default: None,
+        };
+        let json = serde_json::to_string(&acls).unwrap();
+        assert_eq!(
+            json,
+            r#"{"acl":"user::rw-,user:1:r--,group::r--,mask::r--,other::---"}"#
+        );
+        assert_eq!(serde_json::from_str::<Acls>(&json).unwrap(), acls);
+        assert_eq!(serde_json::to_string(&Acls::default()).unwrap(), "{}");
+    }
+}
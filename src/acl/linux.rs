@@ -0,0 +1,60 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Linux implementation of ACLs, through extended attributes.
+
+use std::io;
+use std::path::Path;
+
+use super::{Acl, Acls};
+
+const ACCESS_XATTR: &str = "system.posix_acl_access";
+const DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+fn is_unsupported(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(nix::errno::Errno::EOPNOTSUPP as i32)
+        || err.kind() == io::ErrorKind::Unsupported
+}
+
+fn read_acl(path: &Path, name: &str) -> io::Result<Option<Acl>> {
+    match xattr::get(path, name) {
+        // An ACL with no entries grants nothing, so it's not stored.
+        Ok(Some(value)) => Acl::from_xattr(&value)
+            .map(|acl| Some(acl).filter(|acl| !acl.entries.is_empty()))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        Ok(None) => Ok(None),
+        Err(err) if is_unsupported(&err) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+pub(super) fn read_acls(path: &Path) -> io::Result<Acls> {
+    Ok(Acls {
+        access: read_acl(path, ACCESS_XATTR)?,
+        default: read_acl(path, DEFAULT_XATTR)?,
+    })
+}
+
+pub(super) fn write_acls(acls: &Acls, path: &Path) -> io::Result<()> {
+    for (name, acl) in [(ACCESS_XATTR, &acls.access), (DEFAULT_XATTR, &acls.default)] {
+        if let Some(acl) = acl {
+            xattr::set(path, name, &acl.to_xattr()).map_err(|err| {
+                if is_unsupported(&err) {
+                    io::ErrorKind::Unsupported.into()
+                } else {
+                    err
+                }
+            })?;
+        }
+    }
+    Ok(())
+}
@@ -37,7 +37,7 @@ use serde::{Deserialize, Serialize};
 /// string ordering.
 ///
 /// Apaths must start with `/` and not end with `/` unless they have length 1.
-#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct Apath(String);
 
 impl Apath {
@@ -84,6 +84,41 @@ impl Apath {
         }
     }
 
+    /// The parent directory of this apath, or None for the root.
+    #[must_use]
+    pub fn parent(&self) -> Option<Apath> {
+        if self.0 == "/" {
+            return None;
+        }
+        match self.0.rfind('/') {
+            Some(0) => Some(Apath::root()),
+            Some(i) => Some(Apath(self.0[..i].to_owned())),
+            None => unreachable!("apath {:?} has no slash", self.0),
+        }
+    }
+
+    /// True if self sorts after `subtree` and everything inside it.
+    ///
+    /// Because the children of a directory sort after all its siblings, a subtree
+    /// is not contiguous in apath order: only paths inside a later sibling directory,
+    /// of the subtree or of one of its parents, come after all of it.
+    #[must_use]
+    pub fn is_after_subtree(&self, subtree: &Apath) -> bool {
+        if self.0 == "/" || subtree.0 == "/" {
+            return false;
+        }
+        let mut ours = self.0[1..].split('/');
+        let mut theirs = subtree.0[1..].split('/');
+        loop {
+            match (ours.next(), theirs.next()) {
+                (Some(a), Some(b)) if a == b => continue,
+                (Some(a), Some(b)) => return a > b && ours.next().is_some(),
+                // One is inside the other.
+                _ => return false,
+            }
+        }
+    }
+
     /// Return a PathBuf for this Apath below a tree root directory.
     #[must_use]
     pub fn below<R: Into<PathBuf>>(&self, tree_root: R) -> PathBuf {
@@ -334,6 +369,36 @@ mod test {
             .not());
     }
 
+    #[test]
+    fn parent() {
+        assert_eq!(Apath::root().parent(), None);
+        assert_eq!(Apath::from("/a").parent(), Some(Apath::root()));
+        assert_eq!(Apath::from("/a/b/c").parent(), Some(Apath::from("/a/b")));
+    }
+
+    #[test]
+    fn is_after_subtree() {
+        let subtree = Apath::from("/b/b");
+        for before in [
+            "/", "/a", "/b", "/b/b", "/b/c", "/z", "/b/b/a", "/b/a/zz", "/b/b/z/z",
+        ] {
+            assert!(
+                !Apath::from(before).is_after_subtree(&subtree),
+                "{before:?} is not after {subtree:?}"
+            );
+        }
+        let deep_inside = Apath::from("/b/b/z/z/z");
+        for after in ["/b/c/a", "/c/a", "/c/a/b"] {
+            let after = Apath::from(after);
+            assert!(
+                after.is_after_subtree(&subtree),
+                "{after:?} is after {subtree:?}"
+            );
+            assert!(after > deep_inside);
+        }
+        assert!(!Apath::from("/a/b").is_after_subtree(&Apath::root()));
+    }
+
     #[test]
     pub fn invalid() {
         let invalid_cases = [
@@ -13,26 +13,49 @@
 
 //! Archives holding backup material.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use bytes::Bytes;
 use itertools::Itertools;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, warn};
+use tracing::field::Empty;
+use tracing::{debug, info_span, warn, Span};
 
+use crate::compress::Compression;
 use crate::jsonio::{read_json, write_json};
-use crate::monitor::Monitor;
+use crate::monitor::{check_cancel, Monitor};
+use crate::stats::{ratio, ArchiveInfo, ValidateStats};
 use crate::transport::local::LocalTransport;
+use crate::transport::readonly::ReadOnlyTransport;
 use crate::*;
 
 const HEADER_FILENAME: &str = "CONSERVE";
+const ZSTD_DICTIONARY_FILENAME: &str = "ZSTD_DICTIONARY";
+/// Bands found to be damaged by validation, and their problems.
+const DAMAGED_BANDS_FILENAME: &str = "DAMAGED_BANDS";
+/// Bands and blocks found to be valid, so that incremental validation can skip them.
+const VALIDATED_CONTENT_FILENAME: &str = "VALIDATED_CONTENT";
+/// Records when the archive was last validated without finding problems.
+const LAST_VALIDATED_FILENAME: &str = "LAST_VALIDATED";
 static BLOCK_DIR: &str = "d";
+/// At most this many blocks are decompressed to estimate the size of the content.
+const INFO_SAMPLE_BLOCKS: usize = 100;
 
 /// An archive holding backup material.
+/// Bands and blocks found to be valid by [Archive::validate], as stored in json.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ValidatedContent {
+    /// Closed bands whose metadata and index were valid.
+    bands: Vec<String>,
+    /// Blocks whose content matched their hash, and their uncompressed length.
+    blocks: HashMap<BlockHash, usize>,
+}
+
 #[derive(Clone, Debug)]
 pub struct Archive {
     /// Holds body content for all file versions.
@@ -40,17 +63,30 @@ pub struct Archive {
 
     /// Transport to the root directory of the archive.
     transport: Arc<dyn Transport>,
+
+    /// Compression for new blocks, unless overridden by the backup options.
+    default_compression: Compression,
+
+    /// True if the archive was opened with [Archive::open_readonly].
+    readonly: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct ArchiveHeader {
     conserve_archive_version: String,
+
+    /// Compression for new blocks, if not the default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    default_compression: Option<Compression>,
 }
 
 #[derive(Default, Debug)]
 pub struct DeleteOptions {
     pub dry_run: bool,
     pub break_lock: bool,
+    /// Don't delete unreferenced blocks written more recently than this, in case
+    /// they belong to a backup that hasn't yet written the index referencing them.
+    pub min_block_age: Option<Duration>,
 }
 
 impl Archive {
@@ -61,6 +97,16 @@ impl Archive {
 
     /// Make a new archive in a new directory accessed by a Transport.
     pub fn create(transport: Arc<dyn Transport>) -> Result<Archive> {
+        Archive::create_with_compression(transport, None)
+    }
+
+    /// Make a new archive, recording a default compression for new blocks.
+    ///
+    /// If `default_compression` is None, blocks are compressed with [Compression::default].
+    pub fn create_with_compression(
+        transport: Arc<dyn Transport>,
+        default_compression: Option<Compression>,
+    ) -> Result<Archive> {
         transport.create_dir("")?;
         let names = transport.list_dir("")?;
         if !names.files.is_empty() || !names.dirs.is_empty() {
@@ -72,11 +118,14 @@ impl Archive {
             HEADER_FILENAME,
             &ArchiveHeader {
                 conserve_archive_version: String::from(ARCHIVE_VERSION),
+                default_compression,
             },
         )?;
         Ok(Archive {
             block_dir,
             transport,
+            default_compression: default_compression.unwrap_or_default(),
+            readonly: false,
         })
     }
 
@@ -96,17 +145,171 @@ impl Archive {
             });
         }
         let block_dir = Arc::new(BlockDir::open(transport.sub_transport(BLOCK_DIR)));
+        match transport.read_file(ZSTD_DICTIONARY_FILENAME) {
+            Ok(dictionary) => block_dir.set_zstd_dictionary(dictionary),
+            Err(err) if err.is_not_found() => {}
+            Err(err) => return Err(err.into()),
+        }
         debug!(?header, "Opened archive");
         Ok(Archive {
             block_dir,
             transport,
+            default_compression: header.default_compression.unwrap_or_default(),
+            readonly: false,
         })
     }
 
+    /// Open an existing archive so that nothing in it can be changed, for example
+    /// because it's on read-only media or in a snapshot.
+    ///
+    /// Every attempt to write, create, or delete a file through the archive fails
+    /// with [transport::ErrorKind::ReadOnly], so no lock files or markers are
+    /// written and nothing is garbage collected. Validation doesn't record what it
+    /// found.
+    pub fn open_readonly(transport: Arc<dyn Transport>) -> Result<Archive> {
+        let mut archive = Archive::open(Arc::new(ReadOnlyTransport::new(transport)))?;
+        archive.readonly = true;
+        Ok(archive)
+    }
+
+    /// True if this archive was opened with [Archive::open_readonly].
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
     pub fn block_dir(&self) -> &BlockDir {
         &self.block_dir
     }
 
+    /// The compression used for new blocks, unless the backup specifies otherwise.
+    pub fn default_compression(&self) -> Compression {
+        self.default_compression
+    }
+
+    /// The zstd dictionary for small files, if one has been trained.
+    pub fn zstd_dictionary(&self) -> Option<Bytes> {
+        self.block_dir.zstd_dictionary().cloned()
+    }
+
+    /// Store a zstd dictionary in the archive.
+    ///
+    /// The dictionary is never changed once written, because blocks may depend on it.
+    /// If another process stored a dictionary since this archive was opened, that
+    /// dictionary is used instead.
+    pub(crate) fn write_zstd_dictionary(&self, dictionary: Bytes) -> Result<()> {
+        if self.zstd_dictionary().is_some() {
+            return Err(Error::ZstdDictionaryExists);
+        }
+        let dictionary = match self
+            .transport
+            .write_new_file(ZSTD_DICTIONARY_FILENAME, &dictionary)
+        {
+            Ok(()) => dictionary,
+            Err(err) if err.is_already_exists() => {
+                debug!("Another process stored a zstd dictionary first; using it");
+                self.transport.read_file(ZSTD_DICTIONARY_FILENAME)?
+            }
+            Err(err) => return Err(err.into()),
+        };
+        self.block_dir.set_zstd_dictionary(dictionary);
+        Ok(())
+    }
+
+    /// Bands that [Archive::validate] found to have unreadable metadata, with a
+    /// description of the problem.
+    ///
+    /// Backups don't use these bands to decide which files are unchanged.
+    pub fn damaged_bands(&self) -> Result<BTreeMap<BandId, String>> {
+        let damaged: BTreeMap<String, String> =
+            read_json(&self.transport, DAMAGED_BANDS_FILENAME)?.unwrap_or_default();
+        Ok(damaged
+            .into_iter()
+            .filter_map(|(band_id, problem)| match band_id.parse() {
+                Ok(band_id) => Some((band_id, problem)),
+                Err(_) => {
+                    warn!(band_id, "Invalid band id in list of damaged bands");
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Replace the list of damaged bands, if it has changed.
+    pub(crate) fn set_damaged_bands(&self, damaged: &BTreeMap<BandId, String>) -> Result<()> {
+        if self.damaged_bands().ok().as_ref() == Some(damaged) {
+            return Ok(());
+        }
+        if damaged.is_empty() {
+            self.transport.remove_file(DAMAGED_BANDS_FILENAME)?;
+            return Ok(());
+        }
+        let damaged: BTreeMap<String, &String> = damaged
+            .iter()
+            .map(|(band_id, problem)| (band_id.to_string(), problem))
+            .collect();
+        write_json(&self.transport, DAMAGED_BANDS_FILENAME, &damaged)?;
+        Ok(())
+    }
+
+    /// Remove bands from the list of damaged bands.
+    pub(crate) fn clear_damaged_bands(&self, band_ids: &[BandId]) -> Result<()> {
+        let mut damaged = self.damaged_bands()?;
+        damaged.retain(|band_id, _| !band_ids.contains(band_id));
+        self.set_damaged_bands(&damaged)
+    }
+
+    /// When the archive was last validated without finding any problems, if ever.
+    pub fn last_validated(&self) -> Result<Option<ValidationMarker>> {
+        Ok(read_json(&self.transport, LAST_VALIDATED_FILENAME)?)
+    }
+
+    /// Record that the archive was validated without finding any problems.
+    pub fn set_last_validated(&self, marker: &ValidationMarker) -> Result<()> {
+        write_json(&self.transport, LAST_VALIDATED_FILENAME, marker)?;
+        Ok(())
+    }
+
+    /// Bands and blocks that were found to be valid by previous validations,
+    /// and that incremental validation doesn't need to check again.
+    pub(crate) fn validated_content(
+        &self,
+    ) -> Result<(BTreeSet<BandId>, HashMap<BlockHash, usize>)> {
+        let content: ValidatedContent =
+            read_json(&self.transport, VALIDATED_CONTENT_FILENAME)?.unwrap_or_default();
+        let bands = content
+            .bands
+            .iter()
+            .filter_map(|band_id| band_id.parse().ok())
+            .collect();
+        Ok((bands, content.blocks))
+    }
+
+    /// Replace the record of bands and blocks that were found to be valid.
+    pub(crate) fn set_validated_content(
+        &self,
+        bands: &BTreeSet<BandId>,
+        blocks: HashMap<BlockHash, usize>,
+    ) -> Result<()> {
+        let content = ValidatedContent {
+            bands: bands.iter().map(BandId::to_string).collect(),
+            blocks,
+        };
+        write_json(&self.transport, VALIDATED_CONTENT_FILENAME, &content)?;
+        Ok(())
+    }
+
+    /// Remove bands from the record of validated bands, so that they're checked
+    /// again by the next incremental validation.
+    pub(crate) fn forget_validated_bands(&self, band_ids: &[BandId]) -> Result<()> {
+        let (mut bands, blocks) = self.validated_content()?;
+        let len = bands.len();
+        bands.retain(|band_id| !band_ids.contains(band_id));
+        if bands.len() == len {
+            return Ok(());
+        }
+        self.set_validated_content(&bands, blocks)
+    }
+
     pub fn band_exists(&self, band_id: BandId) -> Result<bool> {
         self.transport
             .is_file(&format!("{}/{}", band_id, crate::BAND_HEAD_FILENAME))
@@ -138,6 +341,31 @@ impl Archive {
         Ok(band_ids)
     }
 
+    /// Return info about every band, in order from first to last.
+    ///
+    /// The bands are read concurrently. Bands whose head or tail can't be read are
+    /// reported to the monitor and left out.
+    pub fn list_band_infos(&self, monitor: Arc<dyn Monitor>) -> Result<Vec<BandInfo>> {
+        Ok(self
+            .read_band_infos()?
+            .into_iter()
+            .filter_map(|(_band_id, info)| info.map_err(|err| monitor.error(err)).ok())
+            .collect())
+    }
+
+    /// Read the info of every band concurrently, returning the id and result for each
+    /// band in order.
+    pub(crate) fn read_band_infos(&self) -> Result<Vec<(BandId, Result<BandInfo>)>> {
+        Ok(self
+            .list_band_ids()?
+            .into_par_iter()
+            .map(|band_id| {
+                let info = Band::open(self, band_id).and_then(|band| band.get_info());
+                (band_id, info)
+            })
+            .collect())
+    }
+
     pub(crate) fn transport(&self) -> &dyn Transport {
         self.transport.as_ref()
     }
@@ -150,6 +378,23 @@ impl Archive {
                 .ok_or(Error::NoCompleteBands),
             BandSelectionPolicy::Specified(band_id) => Ok(band_id),
             BandSelectionPolicy::Latest => self.last_band_id()?.ok_or(Error::ArchiveEmpty),
+            BandSelectionPolicy::Label(label) => {
+                for band_id in self.list_band_ids()?.into_iter().rev() {
+                    if Band::open(self, band_id)?.label() == Some(label.as_str()) {
+                        return Ok(band_id);
+                    }
+                }
+                Err(Error::NoBandWithLabel { label })
+            }
+            BandSelectionPolicy::AsOf(time) => {
+                for band_id in self.list_band_ids()?.into_iter().rev() {
+                    let info = Band::open(self, band_id)?.get_info()?;
+                    if info.is_closed && info.start_time <= time {
+                        return Ok(band_id);
+                    }
+                }
+                Err(Error::NoBandAsOf { time })
+            }
         }
     }
 
@@ -189,6 +434,80 @@ impl Archive {
         Ok(None)
     }
 
+    /// Summarize the archive: its format, bands, and blocks.
+    ///
+    /// Unlike [archive_stats], this doesn't read any band's index. The blocks are
+    /// listed and measured, and the uncompressed size of all of them is estimated
+    /// from a sample of at most 100 blocks. Bands or blocks that can't be read are
+    /// reported to the monitor and counted as errors.
+    pub fn info(&self, monitor: Arc<dyn Monitor>) -> Result<ArchiveInfo> {
+        let mut info = ArchiveInfo {
+            format_version: ARCHIVE_VERSION.to_owned(),
+            compression: self.default_compression,
+            zstd_dictionary: self.zstd_dictionary().is_some(),
+            ..Default::default()
+        };
+        for (_band_id, band_info) in self.read_band_infos()? {
+            info.bands += 1;
+            match band_info {
+                Ok(band_info) => {
+                    if !band_info.is_closed {
+                        info.incomplete_bands += 1;
+                    }
+                    info.first_backup_time.get_or_insert(band_info.start_time);
+                    info.last_backup_time = Some(band_info.start_time);
+                }
+                Err(err) => {
+                    monitor.error(err);
+                    info.errors += 1;
+                }
+            }
+        }
+
+        let mut blocks: Vec<BlockHash> = self.block_dir.blocks(monitor.clone())?.collect();
+        blocks.sort_unstable();
+        info.blocks = blocks.len();
+        let task = monitor.start_task("Measure blocks".to_string());
+        task.set_total(blocks.len());
+        let sample_step = blocks.len().div_ceil(INFO_SAMPLE_BLOCKS).max(1);
+        let sizes: Vec<(u64, Option<u64>)> = blocks
+            .par_iter()
+            .enumerate()
+            .filter_map(|(i, hash)| {
+                task.increment(1);
+                let compressed = self
+                    .block_dir
+                    .compressed_size(hash)
+                    .map_err(|err| monitor.error(err))
+                    .ok()?;
+                let uncompressed = if i % sample_step == 0 {
+                    match self.block_dir.read_block_uncached(hash, monitor.clone()) {
+                        Ok(content) => Some(content.len() as u64),
+                        Err(err) => {
+                            monitor.error(err);
+                            return None;
+                        }
+                    }
+                } else {
+                    None
+                };
+                Some((compressed, uncompressed))
+            })
+            .collect();
+        info.errors += blocks.len() - sizes.len();
+        info.stored_bytes = sizes.iter().map(|(compressed, _)| compressed).sum();
+        let (sample_compressed, sample_uncompressed) = sizes
+            .iter()
+            .filter_map(|(compressed, uncompressed)| Some((*compressed, (*uncompressed)?)))
+            .fold((0, 0), |(c, u), (compressed, uncompressed)| {
+                (c + compressed, u + uncompressed)
+            });
+        info.estimated_content_bytes = (info.stored_bytes as f64
+            * ratio(sample_uncompressed, sample_compressed))
+        .round() as u64;
+        Ok(info)
+    }
+
     /// Returns all blocks referenced by all bands.
     ///
     /// Shows a progress bar as they're collected.
@@ -223,10 +542,97 @@ impl Archive {
             .filter(move |h| !referenced.contains(h)))
     }
 
+    /// Copy a complete band into another archive, as a new band there with the same
+    /// head, and with the next band id after those already in that archive.
+    ///
+    /// Only blocks not already present in the destination are copied, so the copy
+    /// is deduplicated against what the destination already holds. Blocks and
+    /// index hunks are copied as they're stored, except that blocks compressed
+    /// with this archive's zstd dictionary are recompressed for the destination.
+    ///
+    /// The new band is written like a backup: it's incomplete until all of its
+    /// blocks and index hunks are copied, and the copy is refused if the
+    /// destination is locked for garbage collection.
+    pub fn copy_band_to(
+        &self,
+        dest: &Archive,
+        band_id: BandId,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<CopyBandStats> {
+        self.copy_band(dest, band_id, None, monitor)
+    }
+
+    /// Copy a complete band into another archive, as by [Archive::copy_band_to], or
+    /// if `resume` is given, into that incomplete band left by an earlier copy.
+    pub(crate) fn copy_band(
+        &self,
+        dest: &Archive,
+        band_id: BandId,
+        resume: Option<Band>,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<CopyBandStats> {
+        let start = Instant::now();
+        if gc_lock::GarbageCollectionLock::is_locked(dest)? {
+            return Err(Error::GarbageCollectionLockHeld);
+        }
+        let band = Band::open(self, band_id)?;
+        if !band.is_closed()? {
+            return Err(Error::BandIncomplete { band_id });
+        }
+        let mut blocks = HashSet::new();
+        for (_hunk_number, entries) in band.index().read_each_hunk()? {
+            blocks.extend(
+                entries?
+                    .into_iter()
+                    .flat_map(|entry| entry.addrs)
+                    .map(|addr| addr.hash),
+            );
+        }
+        check_cancel(monitor.as_ref())?;
+
+        let dest_band = match resume {
+            Some(dest_band) => dest_band,
+            None => band.create_copy(dest)?,
+        };
+        debug!(%band_id, dest_band_id = %dest_band.id(), "Copying band");
+        let dest_block_dir = dest.block_dir();
+        let task = monitor.start_task("Copy blocks".to_string());
+        task.set_total(blocks.len());
+        let copied: Vec<Option<u64>> = blocks
+            .par_iter()
+            .map(|hash| {
+                check_cancel(monitor.as_ref())?;
+                let copied = if dest_block_dir.contains(hash, monitor.clone())? {
+                    None
+                } else {
+                    Some(dest_block_dir.copy_block_from(&self.block_dir, hash)?)
+                };
+                task.increment(1);
+                Ok(copied)
+            })
+            .collect::<Result<_>>()?;
+        drop(task);
+        check_cancel(monitor.as_ref())?;
+
+        let index_hunks = band.index().copy_hunks_to(&dest_band.index())?;
+        dest_band.copy_tail_from(&band)?;
+        Ok(CopyBandStats {
+            band_id: dest_band.id(),
+            blocks: blocks.len(),
+            deduplicated_blocks: copied.iter().filter(|c| c.is_none()).count(),
+            copied_blocks: copied.iter().flatten().count(),
+            copied_block_bytes: copied.iter().flatten().sum(),
+            index_hunks,
+            elapsed: start.elapsed(),
+        })
+    }
+
     /// Delete bands, and the blocks that they reference.
     ///
     /// If `delete_band_ids` is empty, this deletes no bands, but will delete any garbage
     /// blocks referenced by no existing bands.
+    ///
+    /// Nothing is deleted if any of the bands don't exist, or are held.
     pub fn delete_bands(
         &self,
         delete_band_ids: &[BandId],
@@ -247,15 +653,30 @@ impl Archive {
         let block_dir = self.block_dir();
         debug!("List band ids...");
         let mut keep_band_ids = self.list_band_ids()?;
+        if let Some(band_id) = delete_band_ids
+            .iter()
+            .find(|band_id| !keep_band_ids.contains(band_id))
+        {
+            return Err(Error::BandNotFound { band_id: *band_id });
+        }
+        // Bands whose head can't be read can still be deleted.
+        if let Some(band_id) = delete_band_ids
+            .iter()
+            .find(|band_id| Band::open(self, **band_id).is_ok_and(|band| band.is_held()))
+        {
+            return Err(Error::BandHeld { band_id: *band_id });
+        }
         keep_band_ids.retain(|b| !delete_band_ids.contains(b));
 
         debug!("List referenced blocks...");
         let referenced = self.referenced_blocks(&keep_band_ids, monitor.clone())?;
         debug!(referenced.len = referenced.len());
+        check_cancel(monitor.as_ref())?;
 
         debug!("Find present blocks...");
         let present: HashSet<BlockHash> = self.block_dir.blocks(monitor.clone())?.collect();
         debug!(present.len = present.len());
+        check_cancel(monitor.as_ref())?;
 
         debug!("Find unreferenced blocks...");
         let unref = present.difference(&referenced).collect_vec();
@@ -266,38 +687,103 @@ impl Archive {
         debug!("Measure unreferenced blocks...");
         let task = monitor.start_task("Measure unreferenced blocks".to_string());
         task.set_total(unref_count);
-        let total_bytes = unref
+        let unref_metadata: Vec<Option<transport::Metadata>> = unref
             .par_iter()
-            .enumerate()
             .inspect(|_| {
                 task.increment(1);
             })
-            .map(|(_i, block_id)| block_dir.compressed_size(block_id).unwrap_or_default())
-            .sum();
+            .map(|block_id| block_dir.block_file_metadata(block_id).ok())
+            .collect();
         drop(task);
-        stats.unreferenced_block_bytes = total_bytes;
+        stats.unreferenced_block_bytes = unref_metadata.iter().flatten().map(|m| m.len).sum();
+
+        // Blocks whose age can't be determined are kept when there's a minimum age.
+        let cutoff = options
+            .min_block_age
+            .map(|age| SystemTime::now().checked_sub(age).unwrap_or(UNIX_EPOCH));
+        let garbage: Vec<(&BlockHash, u64)> = unref
+            .iter()
+            .zip(&unref_metadata)
+            .filter(|(_, metadata)| {
+                cutoff.map_or(true, |cutoff| {
+                    metadata
+                        .as_ref()
+                        .and_then(|m| m.modified)
+                        .is_some_and(|modified| modified <= cutoff)
+                })
+            })
+            .map(|(block_hash, metadata)| (*block_hash, metadata.as_ref().map_or(0, |m| m.len)))
+            .collect();
+        stats.recent_block_count = unref_count - garbage.len();
+        stats.reclaimable_block_count = garbage.len();
+        stats.reclaimable_block_bytes = garbage.iter().map(|(_, size)| size).sum();
+        debug!(stats.recent_block_count, stats.reclaimable_block_count);
 
         if !options.dry_run {
+            check_cancel(monitor.as_ref())?;
             delete_guard.check()?;
             let task = monitor.start_task("Delete bands".to_string());
 
+            // Once some bands are deleted, their blocks are garbage whether or not
+            // they're deleted now, so cancelling between any steps is safe.
             for band_id in delete_band_ids.iter() {
+                check_cancel(monitor.as_ref())?;
                 Band::delete(self, *band_id)?;
                 stats.deleted_band_count += 1;
                 task.increment(1);
             }
+            if let Err(err) = self.clear_damaged_bands(delete_band_ids) {
+                warn!(?err, "Failed to update the list of damaged bands");
+            }
+            if let Err(err) = self.forget_validated_bands(delete_band_ids) {
+                warn!(?err, "Failed to update the record of validated bands");
+            }
+
+            // Packed blocks are removed by rewriting each pack that holds any of them.
+            let mut packed: HashMap<Arc<str>, HashSet<BlockHash>> = HashMap::new();
+            let mut loose = Vec::new();
+            for &(block_hash, size) in &garbage {
+                match block_dir.pack_holding(block_hash)? {
+                    Some(pack) => {
+                        packed.entry(pack).or_default().insert(block_hash.clone());
+                    }
+                    None => loose.push((block_hash, size)),
+                }
+            }
+            let sizes: HashMap<&BlockHash, u64> = garbage.iter().copied().collect();
+            check_cancel(monitor.as_ref())?;
 
             let task = monitor.start_task("Delete blocks".to_string());
-            task.set_total(unref_count);
-            let error_count = unref
+            task.set_total(garbage.len());
+            let (deleted_count, deleted_bytes) = packed
                 .par_iter()
-                .filter(|block_hash| {
-                    task.increment(1);
-                    block_dir.delete_block(block_hash).is_err()
+                .map(|(pack, block_hashes)| {
+                    task.increment(block_hashes.len());
+                    if monitor.should_cancel() {
+                        return (0, 0);
+                    }
+                    match block_dir.remove_from_pack(pack, block_hashes) {
+                        Ok(removed) => (removed, block_hashes.iter().map(|h| sizes[h]).sum()),
+                        Err(err) => {
+                            warn!(%pack, ?err, "Failed to remove blocks from pack");
+                            (0, 0)
+                        }
+                    }
                 })
-                .count();
-            stats.deletion_errors += error_count;
-            stats.deleted_block_count += unref_count - error_count;
+                .chain(
+                    loose
+                        .par_iter()
+                        .filter(|(block_hash, _size)| {
+                            task.increment(1);
+                            !monitor.should_cancel() && block_dir.delete_block(block_hash).is_ok()
+                        })
+                        .map(|(_block_hash, size)| (1, *size)),
+                )
+                .reduce(|| (0, 0), |(c1, b1), (c2, b2)| (c1 + c2, b1 + b2));
+            stats.deletion_errors += garbage.len() - deleted_count;
+            stats.deleted_block_count += deleted_count;
+            stats.deleted_block_bytes += deleted_bytes;
+            check_cancel(monitor.as_ref())?;
         }
 
         stats.elapsed = start.elapsed();
@@ -309,50 +795,240 @@ impl Archive {
     /// If problems are found, they are emitted as `warn` or `error` level
     /// tracing messages. This function only returns an error if validation
     /// stops due to a fatal error.
-    pub fn validate(&self, options: &ValidateOptions, monitor: Arc<dyn Monitor>) -> Result<()> {
+    ///
+    /// With [ValidateOptions::incremental], bands and blocks that were found to be
+    /// valid by an earlier incremental validation are skipped, and those found
+    /// to be valid now are recorded in the archive, unless it's open read-only.
+    pub fn validate(
+        &self,
+        options: &ValidateOptions,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<ValidateStats> {
+        options
+            .parallelism
+            .install(|| self.validate_archive(options, monitor))
+    }
+
+    fn validate_archive(
+        &self,
+        options: &ValidateOptions,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<ValidateStats> {
+        let start = Instant::now();
+        let span = validate_span(None);
+        let _enter = span.enter();
+        let mut stats = ValidateStats::default();
         self.validate_archive_dir(monitor.clone())?;
 
         debug!("List bands...");
-        let band_ids = self.list_band_ids()?;
-        debug!("Check {} bands...", band_ids.len());
-
-        // 1. Walk all indexes, collecting a list of (block_hash6, min_length)
-        //    values referenced by all the indexes.
-        let referenced_lens = validate::validate_bands(self, &band_ids, monitor.clone())?;
-
-        if options.skip_block_hashes {
-            // 3a. Check that all referenced blocks are present, without spending time reading their
-            // content.
-            debug!("List blocks...");
-            // TODO: Check for unexpected files or directories in the blockdir.
-            let present_blocks: HashSet<BlockHash> =
-                self.block_dir.blocks(monitor.clone())?.collect();
-            for hash in referenced_lens.keys() {
-                if !present_blocks.contains(hash) {
-                    monitor.error(Error::BlockMissing { hash: hash.clone() })
+        let all_band_ids = self.list_band_ids()?;
+        let (validated_bands, validated_blocks) = if options.incremental {
+            self.validated_content().unwrap_or_else(|err| {
+                warn!("Failed to read the record of validated content: {err}");
+                Default::default()
+            })
+        } else {
+            Default::default()
+        };
+        let band_ids: Vec<BandId> = all_band_ids
+            .iter()
+            .filter(|band_id| !validated_bands.contains(band_id))
+            .copied()
+            .collect();
+        stats.bands = band_ids.len();
+        stats.skipped_bands = all_band_ids.len() - band_ids.len();
+        debug!(
+            "Check {} bands, skipping {} already validated",
+            stats.bands, stats.skipped_bands
+        );
+
+        // 1. Walk all indexes, collecting a list of (block_hash, min_length)
+        //    values referenced by all the indexes, while checking the hash of
+        //    blocks is correct and remembering how long the uncompressed data is.
+        //    Bands and blocks are checked concurrently: the indexes are walked on
+        //    some threads of the pool while blocks are listed and hashed on others.
+        // TODO: Check for unexpected files or directories in the blockdir.
+        // Spans aren't inherited by other threads, so they're entered explicitly.
+        let (bands_result, blocks_result) = rayon::join(
+            || span.in_scope(|| validate::validate_bands(self, &band_ids, monitor.clone())),
+            || {
+                span.in_scope(|| {
+                    self.block_dir.validate_new_blocks(
+                        &validated_blocks,
+                        |hash| options.should_read_block(hash),
+                        monitor.clone(),
+                    )
+                })
+            },
+        );
+        let bands = bands_result?;
+        // Bands and blocks that were skipped after cancellation aren't known to be
+        // damaged, so nothing is recorded.
+        check_cancel(monitor.as_ref())?;
+        if !self.readonly {
+            self.set_damaged_bands(&bands.damaged)?;
+        }
+        let (block_lengths, unread_blocks) = blocks_result?;
+        let known_blocks = block_lengths
+            .keys()
+            .filter(|hash| validated_blocks.contains_key(hash))
+            .count();
+        stats.blocks = block_lengths.len() - known_blocks;
+        stats.skipped_blocks = known_blocks + unread_blocks.len();
+        // 2. Check that all referenced blocks are present, and that the referenced
+        //    ranges are inside the data of the blocks that were read.
+        let mut short_blocks = HashMap::new();
+        for (hash, &referenced_len) in &bands.block_lens {
+            if let Some(&actual_len) = block_lengths.get(hash) {
+                if referenced_len > actual_len as u64 {
+                    stats.missing_blocks += 1;
+                    short_blocks.insert(hash.clone(), actual_len);
                 }
+            } else if !unread_blocks.contains(hash) {
+                stats.missing_blocks += 1;
+                monitor.error(Error::BlockMissing { hash: hash.clone() })
             }
-        } else {
-            // 2. Check the hash of all blocks are correct, and remember how long
-            //    the uncompressed data is.
-            let block_lengths: HashMap<BlockHash, usize> =
-                self.block_dir.validate(monitor.clone())?;
-            // 3b. Check that all referenced ranges are inside the present data.
-            for (hash, referenced_len) in referenced_lens {
-                if let Some(&actual_len) = block_lengths.get(&hash) {
-                    if referenced_len > actual_len as u64 {
-                        monitor.error(Error::BlockTooShort {
-                            hash: hash.clone(),
-                            actual_len,
-                            referenced_len: referenced_len as usize,
-                        });
+        }
+        if !short_blocks.is_empty() {
+            validate::report_addresses_out_of_range(
+                self,
+                &band_ids,
+                &short_blocks,
+                monitor.clone(),
+            );
+        }
+        // 3. Check that referenced blocks that weren't read are plausible, without
+        //    reading their content.
+        let unread_referenced: Vec<&BlockHash> = bands
+            .block_lens
+            .keys()
+            .filter(|hash| unread_blocks.contains(hash))
+            .collect();
+        if !unread_referenced.is_empty() {
+            let task = monitor.start_task("Check block files".to_string());
+            task.set_total(unread_referenced.len());
+            stats.missing_blocks += unread_referenced
+                .into_par_iter()
+                .filter(|hash| {
+                    if monitor.should_cancel() {
+                        return false;
                     }
-                } else {
-                    monitor.error(Error::BlockMissing { hash: hash.clone() })
+                    let result = self.block_dir.check_block_file(hash);
+                    task.increment(1);
+                    result.map_err(|err| monitor.error(err)).is_err()
+                })
+                .count();
+        }
+        check_cancel(monitor.as_ref())?;
+        stats.damaged_bands = bands.damaged.len();
+
+        // 4. Remember what was found to be valid, for later incremental validation.
+        if options.incremental && !self.readonly {
+            // If any referenced block is missing or too short, we don't know which bands
+            // referenced them, so none of the bands are recorded as valid.
+            let valid_bands = if stats.missing_blocks > 0 {
+                BTreeSet::new()
+            } else {
+                validated_bands
+                    .into_iter()
+                    .filter(|band_id| all_band_ids.contains(band_id))
+                    .chain(bands.valid)
+                    .collect()
+            };
+            self.set_validated_content(&valid_bands, block_lengths)?;
+        }
+        stats.elapsed = start.elapsed();
+        record_validate_stats(&span, &stats);
+        Ok(stats)
+    }
+
+    /// Check one band's metadata and index, and the blocks that it references.
+    ///
+    /// This is much faster than validating the whole archive, for example to check a
+    /// backup before restoring it. [ValidateOptions::incremental] is ignored, and
+    /// nothing is recorded in the archive.
+    pub fn validate_band(
+        &self,
+        band_id: BandId,
+        options: &ValidateOptions,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<ValidateStats> {
+        options
+            .parallelism
+            .install(|| self.validate_one_band(band_id, options, monitor))
+    }
+
+    fn validate_one_band(
+        &self,
+        band_id: BandId,
+        options: &ValidateOptions,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<ValidateStats> {
+        let start = Instant::now();
+        let span = validate_span(Some(band_id));
+        let _enter = span.enter();
+        if !self.list_band_ids()?.contains(&band_id) {
+            return Err(Error::BandNotFound { band_id });
+        }
+        let mut stats = ValidateStats {
+            bands: 1,
+            ..Default::default()
+        };
+        let bands = validate::validate_bands(self, &[band_id], monitor.clone())?;
+        check_cancel(monitor.as_ref())?;
+        stats.damaged_bands = bands.damaged.len();
+
+        let task = monitor.start_task(format!("Validate blocks of {band_id}"));
+        task.set_total(bands.block_lens.len());
+        let short_blocks = Mutex::new(HashMap::new());
+        // For each block, Some(true) if it was read and is valid, Some(false) if it's
+        // present but wasn't read, or None if there's a problem.
+        let outcomes: Vec<Option<bool>> = bands
+            .block_lens
+            .par_iter()
+            .map(|(hash, &referenced_len)| {
+                if monitor.should_cancel() {
+                    return None;
                 }
+                let result = if options.should_read_block(hash) {
+                    match self.block_dir.read_block_uncached(hash, monitor.clone()) {
+                        Ok(bytes) if referenced_len > bytes.len() as u64 => {
+                            // Reported below, for each entry that references past the end.
+                            short_blocks
+                                .lock()
+                                .unwrap()
+                                .insert(hash.clone(), bytes.len());
+                            task.increment(1);
+                            return None;
+                        }
+                        Ok(_) => Ok(true),
+                        Err(Error::Transport { source }) if source.is_not_found() => {
+                            Err(Error::BlockMissing { hash: hash.clone() })
+                        }
+                        Err(err) => Err(err),
+                    }
+                } else {
+                    self.block_dir.check_block_file(hash).map(|()| false)
+                };
+                task.increment(1);
+                result.map_err(|err| monitor.error(err)).ok()
+            })
+            .collect();
+        check_cancel(monitor.as_ref())?;
+        for outcome in outcomes {
+            match outcome {
+                Some(true) => stats.blocks += 1,
+                Some(false) => stats.skipped_blocks += 1,
+                None => stats.missing_blocks += 1,
             }
         }
-        Ok(())
+        let short_blocks = short_blocks.into_inner().unwrap();
+        if !short_blocks.is_empty() {
+            validate::report_addresses_out_of_range(self, &[band_id], &short_blocks, monitor);
+        }
+        stats.elapsed = start.elapsed();
+        record_validate_stats(&span, &stats);
+        Ok(stats)
     }
 
     fn validate_archive_dir(&self, monitor: Arc<dyn Monitor>) -> Result<()> {
@@ -378,6 +1054,10 @@ impl Archive {
         }
         for name in list_dir.files {
             if !name.eq_ignore_ascii_case(HEADER_FILENAME)
+                && !name.eq_ignore_ascii_case(ZSTD_DICTIONARY_FILENAME)
+                && !name.eq_ignore_ascii_case(DAMAGED_BANDS_FILENAME)
+                && !name.eq_ignore_ascii_case(VALIDATED_CONTENT_FILENAME)
+                && !name.eq_ignore_ascii_case(LAST_VALIDATED_FILENAME)
                 && !name.eq_ignore_ascii_case(crate::gc_lock::GC_LOCK)
                 && !name.eq_ignore_ascii_case(".DS_Store")
             {
@@ -388,3 +1068,24 @@ impl Archive {
         Ok(())
     }
 }
+
+/// Make a span covering validation of the whole archive, or of one band.
+///
+/// The totals are recorded when validation finishes.
+fn validate_span(band_id: Option<BandId>) -> Span {
+    info_span!(
+        "validate",
+        band_id = band_id.map(tracing::field::display),
+        bands = Empty,
+        blocks = Empty,
+        missing_blocks = Empty,
+        damaged_bands = Empty,
+    )
+}
+
+fn record_validate_stats(span: &Span, stats: &ValidateStats) {
+    span.record("bands", stats.bands);
+    span.record("blocks", stats.blocks);
+    span.record("missing_blocks", stats.missing_blocks);
+    span.record("damaged_bands", stats.damaged_bands);
+}
@@ -0,0 +1,109 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Measure how much content an archive holds, and how well it's deduplicated
+//! across bands.
+//!
+//! This reads every band's index, in order, and notes which blocks each band
+//! references for the first time. The content of blocks isn't read: the
+//! uncompressed length of each block is taken to be the furthest extent of it
+//! referenced by any band, which is the whole block unless some of it is unused.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use rayon::prelude::*;
+
+use crate::monitor::Monitor;
+use crate::stats::{ArchiveStats, BandDedupStats};
+use crate::*;
+
+/// Count the blocks referenced by each band and by the whole archive.
+///
+/// Index hunks that can't be read, and referenced blocks that are missing, are
+/// reported to the monitor, counted as errors, and otherwise skipped.
+pub fn archive_stats(archive: &Archive, monitor: Arc<dyn Monitor>) -> Result<ArchiveStats> {
+    let band_ids = archive.list_band_ids()?;
+    let mut stats = ArchiveStats::default();
+    // For each block referenced so far, the furthest extent of it that's referenced.
+    let mut extents: HashMap<BlockHash, u64> = HashMap::new();
+    let task = monitor.start_task("Count blocks in bands".to_string());
+    task.set_total(band_ids.len());
+    for band_id in band_ids {
+        let hunks = match Band::open_index(archive, band_id).read_each_hunk() {
+            Ok(hunks) => hunks,
+            Err(err) => {
+                monitor.error(err);
+                stats.errors += 1;
+                continue;
+            }
+        };
+        let mut band_extents: HashMap<BlockHash, u64> = HashMap::new();
+        let mut referenced_bytes = 0;
+        for (_hunk_number, result) in hunks {
+            let entries = match result {
+                Ok(entries) => entries,
+                Err(err) => {
+                    monitor.error(err);
+                    stats.errors += 1;
+                    continue;
+                }
+            };
+            for addr in entries.iter().flat_map(|entry| &entry.addrs) {
+                referenced_bytes += addr.len;
+                let extent = band_extents.entry(addr.hash.clone()).or_default();
+                *extent = (*extent).max(addr.start + addr.len);
+            }
+        }
+        let mut band_stats = BandDedupStats {
+            band_id,
+            blocks: band_extents.len(),
+            new_blocks: 0,
+            referenced_bytes,
+            new_bytes: 0,
+        };
+        for (hash, band_extent) in band_extents {
+            let extent = extents.entry(hash).or_insert_with(|| {
+                band_stats.new_blocks += 1;
+                band_stats.new_bytes += band_extent;
+                0
+            });
+            *extent = (*extent).max(band_extent);
+        }
+        stats.referenced_bytes += band_stats.referenced_bytes;
+        stats.bands.push(band_stats);
+        task.increment(1);
+    }
+    drop(task);
+
+    stats.unique_blocks = extents.len();
+    stats.unique_bytes = extents.values().sum();
+    let task = monitor.start_task("Measure blocks".to_string());
+    task.set_total(extents.len());
+    let block_dir = archive.block_dir();
+    let (stored_bytes, errors) = extents
+        .par_iter()
+        .map(|(hash, _)| {
+            task.increment(1);
+            match block_dir.compressed_size(hash) {
+                Ok(size) => (size, 0),
+                Err(err) => {
+                    monitor.error(err);
+                    (0, 1)
+                }
+            }
+        })
+        .reduce(|| (0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+    stats.stored_bytes = stored_bytes;
+    stats.errors += errors;
+    Ok(stats)
+}
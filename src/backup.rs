@@ -14,29 +14,48 @@
 //! Make a backup by walking a source directory and copying the contents
 //! into an archive.
 
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fmt;
 use std::io::prelude::*;
+use std::io::SeekFrom;
 use std::mem::take;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::mpsc::{sync_channel, Receiver};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 use derive_more::{Add, AddAssign};
+use fastcdc::v2020::StreamCDC;
 use itertools::Itertools;
-use tracing::{trace, warn};
+use serde::Serialize;
+use time::OffsetDateTime;
+use tracing::field::{display, Empty};
+use tracing::{debug, debug_span, info_span, trace, trace_span, warn, Span};
 
 use crate::blockdir::Address;
 use crate::change::Change;
+use crate::change_cache::{CachedEntry, ChangeCacheReader, ChangeCacheWriter};
+use crate::compress::{Compression, DEFAULT_ZSTD_LEVEL, MAX_BLOCK_SIZE};
 use crate::counters::Counter;
+use crate::entry::KindMeta;
 use crate::io::read_with_retries;
-use crate::monitor::Monitor;
-use crate::stats::{write_compressed_size, write_count, write_duration, write_size};
+use crate::monitor::{check_cancel, EntryOutcome, Monitor};
+use crate::parallelism::{thread_pool, Semaphore};
+use crate::snapshot::SourceSnapshots;
+use crate::sparse::{data_ranges, find_holes, Hole};
+use crate::stats::{ratio, write_compressed_size, write_count, write_duration, write_size};
 use crate::stitch::IterStitchedIndexHunks;
+use crate::throttle::{ReadThrottle, ThrottledRead};
 use crate::*;
 
 /// Configuration of how to make a backup.
+///
+/// New options may be added in future, so construct this from [Default] and the
+/// `with_` methods, or by setting fields of a default value.
+#[non_exhaustive]
 pub struct BackupOptions<'cb> {
     /// Exclude these globs from the backup.
     pub exclude: Exclude,
@@ -53,6 +72,92 @@ pub struct BackupOptions<'cb> {
 
     /// Record the user/group owners on Unix.
     pub owner: bool,
+
+    /// Record POSIX ACLs on Linux.
+    pub acls: bool,
+
+    /// Compress new blocks with this algorithm and level.
+    ///
+    /// If None, use the archive's default compression.
+    pub compression: Option<Compression>,
+
+    /// Compress blocks of combined small files with zstd, using a dictionary stored in
+    /// the archive.
+    ///
+    /// If the archive has no dictionary yet, one is trained from a sample of small files
+    /// in the source tree. The zstd level comes from `compression` if that's zstd, and
+    /// otherwise is the default.
+    pub zstd_dictionary: bool,
+
+    /// Encode the index of the new band in this format.
+    ///
+    /// The binary format is faster to write and read, but adds a format flag to the
+    /// band so that older versions of Conserve refuse to read it.
+    pub index_format: IndexFormat,
+
+    /// End each index hunk with its number of entries and a checksum, so that damage
+    /// to the index is reported precisely.
+    ///
+    /// This adds a format flag to the band so that older versions of Conserve refuse
+    /// to read it.
+    pub index_checksums: bool,
+
+    /// Keep a cache of the files in the backup in this local file, outside the archive.
+    ///
+    /// If the cache describes the last band in the archive, files whose size, mtime,
+    /// and inode match the cache are recorded without reading their content, the
+    /// basis index, or checking their blocks are present. The cache is then
+    /// rewritten to describe the new band.
+    pub change_cache: Option<PathBuf>,
+
+    /// Whether to store the targets of symlinks in the source tree, rather than the
+    /// symlinks themselves.
+    pub follow_symlinks: FollowSymlinks,
+
+    /// Record this short name for the backup in the band head, so that it can later
+    /// be selected by [BandSelectionPolicy::Label].
+    pub label: Option<String>,
+
+    /// Record this free-form description of the backup in the band head.
+    pub message: Option<String>,
+
+    /// Record in the band head that this backup may be deleted by
+    /// [prune](crate::prune::prune) once this much time has passed since it started,
+    /// regardless of the retention policy given to prune.
+    pub expire_after: Option<Duration>,
+
+    /// Split large files into blocks at boundaries chosen from their content, using
+    /// FastCDC, rather than at fixed offsets.
+    ///
+    /// This lets data after an insertion or deletion in the middle of a large file
+    /// deduplicate against the blocks stored by previous backups, at the cost of
+    /// somewhat smaller blocks, of at most [CDC_MAX_BLOCK_SIZE].
+    pub content_defined_chunking: bool,
+
+    /// On Windows, back up from a Volume Shadow Copy of each source volume, so that
+    /// files locked open by other programs are read consistently.
+    ///
+    /// Creating shadow copies needs Administrator privileges. On other platforms,
+    /// the backup fails if this is set.
+    pub use_vss: bool,
+
+    /// Back up from a snapshot of the filesystem containing each source directory,
+    /// made just before the backup starts and deleted afterwards, so that the
+    /// backup is crash-consistent.
+    ///
+    /// This is not used if `use_vss` is set.
+    pub snapshot: Option<SnapshotMethod>,
+
+    /// Read the source files no faster than this many bytes per second, on average.
+    ///
+    /// None or zero means no limit.
+    pub max_read_rate: Option<u64>,
+
+    /// Threads, concurrent writes, and memory used to store new blocks, while source
+    /// files continue to be read.
+    ///
+    /// With one thread, blocks are stored on the thread that reads the files, one at a time.
+    pub parallelism: Parallelism,
 }
 
 impl Default for BackupOptions<'_> {
@@ -64,6 +169,171 @@ impl Default for BackupOptions<'_> {
             max_block_size: 20 << 20,
             small_file_cap: 1 << 20,
             owner: true,
+            acls: true,
+            compression: None,
+            zstd_dictionary: false,
+            index_format: IndexFormat::Json,
+            index_checksums: false,
+            change_cache: None,
+            follow_symlinks: FollowSymlinks::Never,
+            label: None,
+            message: None,
+            expire_after: None,
+            content_defined_chunking: false,
+            use_vss: false,
+            snapshot: None,
+            max_read_rate: None,
+            parallelism: Parallelism::default(),
+        }
+    }
+}
+
+impl<'cb> BackupOptions<'cb> {
+    /// Exclude entries matching these globs from the backup.
+    pub fn with_exclude(self, exclude: Exclude) -> Self {
+        BackupOptions { exclude, ..self }
+    }
+
+    /// Write at most this many entries in each index hunk.
+    pub fn with_max_entries_per_hunk(self, max_entries_per_hunk: usize) -> Self {
+        BackupOptions {
+            max_entries_per_hunk,
+            ..self
+        }
+    }
+
+    /// Call this callback as each entry is stored.
+    pub fn with_change_callback(self, change_callback: Option<ChangeCallback<'cb>>) -> Self {
+        BackupOptions {
+            change_callback,
+            ..self
+        }
+    }
+
+    /// Split files into blocks of at most this many bytes, and no more than
+    /// [crate::compress::MAX_BLOCK_SIZE].
+    pub fn with_max_block_size(self, max_block_size: usize) -> Self {
+        BackupOptions {
+            max_block_size,
+            ..self
+        }
+    }
+
+    /// Combine files smaller than this into shared blocks.
+    pub fn with_small_file_cap(self, small_file_cap: u64) -> Self {
+        BackupOptions {
+            small_file_cap,
+            ..self
+        }
+    }
+
+    /// Record the user and group owners of files, on Unix.
+    pub fn with_owner(self, owner: bool) -> Self {
+        BackupOptions { owner, ..self }
+    }
+
+    /// Record POSIX ACLs, on Linux.
+    pub fn with_acls(self, acls: bool) -> Self {
+        BackupOptions { acls, ..self }
+    }
+
+    /// Compress new blocks this way, rather than with the archive's default.
+    pub fn with_compression(self, compression: Option<Compression>) -> Self {
+        BackupOptions {
+            compression,
+            ..self
+        }
+    }
+
+    /// Compress combined small files with zstd and a dictionary stored in the archive.
+    pub fn with_zstd_dictionary(self, zstd_dictionary: bool) -> Self {
+        BackupOptions {
+            zstd_dictionary,
+            ..self
+        }
+    }
+
+    /// Write the index of the new band in this format.
+    pub fn with_index_format(self, index_format: IndexFormat) -> Self {
+        BackupOptions {
+            index_format,
+            ..self
+        }
+    }
+
+    /// End each index hunk with its entry count and a checksum.
+    pub fn with_index_checksums(self, index_checksums: bool) -> Self {
+        BackupOptions {
+            index_checksums,
+            ..self
+        }
+    }
+
+    /// Keep a cache of the backed-up files in this local file.
+    pub fn with_change_cache(self, change_cache: Option<PathBuf>) -> Self {
+        BackupOptions {
+            change_cache,
+            ..self
+        }
+    }
+
+    /// Choose whether to store symlinks, or the files they point to.
+    pub fn with_follow_symlinks(self, follow_symlinks: FollowSymlinks) -> Self {
+        BackupOptions {
+            follow_symlinks,
+            ..self
+        }
+    }
+
+    /// Give the backup a short name, which can be used to select it.
+    pub fn with_label(self, label: Option<String>) -> Self {
+        BackupOptions { label, ..self }
+    }
+
+    /// Give the backup a free-form description.
+    pub fn with_message(self, message: Option<String>) -> Self {
+        BackupOptions { message, ..self }
+    }
+
+    /// Let prune delete the backup once this much time has passed.
+    pub fn with_expire_after(self, expire_after: Option<Duration>) -> Self {
+        BackupOptions {
+            expire_after,
+            ..self
+        }
+    }
+
+    /// Split large files at boundaries chosen from their content.
+    pub fn with_content_defined_chunking(self, content_defined_chunking: bool) -> Self {
+        BackupOptions {
+            content_defined_chunking,
+            ..self
+        }
+    }
+
+    /// Back up from Volume Shadow Copies, on Windows.
+    pub fn with_use_vss(self, use_vss: bool) -> Self {
+        BackupOptions { use_vss, ..self }
+    }
+
+    /// Back up from a filesystem snapshot made just before the backup.
+    pub fn with_snapshot(self, snapshot: Option<SnapshotMethod>) -> Self {
+        BackupOptions { snapshot, ..self }
+    }
+
+    /// Read source files no faster than this many bytes per second.
+    pub fn with_max_read_rate(self, max_read_rate: Option<u64>) -> Self {
+        BackupOptions {
+            max_read_rate,
+            ..self
+        }
+    }
+
+    /// Limit the threads, concurrent writes, and memory used to store blocks.
+    pub fn with_parallelism(self, parallelism: Parallelism) -> Self {
+        BackupOptions {
+            parallelism,
+            ..self
         }
     }
 }
@@ -88,26 +358,161 @@ pub fn backup(
     source_path: &Path,
     options: &BackupOptions,
     monitor: Arc<dyn Monitor>,
+) -> Result<BackupStats> {
+    let mut snapshots = SourceSnapshots::new(options);
+    let snapshot_path = snapshots.source_path(source_path)?;
+    backup_tree(
+        archive,
+        &LiveTree::open(snapshot_path)?.with_follow_symlinks(options.follow_symlinks),
+        &[source_path],
+        options,
+        monitor,
+    )
+}
+
+/// Backup several source directories into a single new band in the archive.
+///
+/// Each directory is stored at the top of the band under its own name, so for
+/// example `/etc` and `/home` are stored as `/etc` and `/home`. The names
+/// must be distinct.
+///
+/// Returns statistics about what was copied.
+pub fn backup_sources<P: AsRef<Path>>(
+    archive: &Archive,
+    source_paths: &[P],
+    options: &BackupOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<BackupStats> {
+    let mut snapshots = SourceSnapshots::new(options);
+    let snapshot_paths = source_paths
+        .iter()
+        .map(|path| snapshots.source_path(path.as_ref()))
+        .collect::<Result<Vec<PathBuf>>>()?;
+    backup_tree(
+        archive,
+        &LiveTree::open_multiple(&snapshot_paths)?.with_follow_symlinks(options.follow_symlinks),
+        &source_paths
+            .iter()
+            .map(AsRef::as_ref)
+            .collect::<Vec<&Path>>(),
+        options,
+        monitor,
+    )
+}
+
+/// Backup a single file read from a stream, such as stdin, into a new band.
+///
+/// The file is stored at `apath`, within directories that are added to the band
+/// as needed. The stream is read to the end and stored in blocks as it arrives,
+/// so its size needn't be known in advance.
+///
+/// Returns statistics about what was copied.
+pub fn backup_stream(
+    archive: &Archive,
+    apath: &Apath,
+    from: &mut dyn Read,
+    options: &BackupOptions,
+    monitor: Arc<dyn Monitor>,
 ) -> Result<BackupStats> {
     let start = Instant::now();
-    let mut writer = BackupWriter::begin(archive, options, monitor.clone())?;
+    if *apath == Apath::root() {
+        return Err(Error::InvalidMetadata {
+            details: "Can't store a stream at the root of a backup".to_owned(),
+        });
+    }
+    let span = backup_span();
+    span.record("apath", display(apath));
+    let mut writer = BackupWriter::begin(archive, &[], options, monitor.clone())?;
+    span.record("band_id", display(writer.band.id()));
+    let mtime = OffsetDateTime::now_utc();
+    let entry = |apath: Apath, kind_meta: KindMeta| EntryValue {
+        apath,
+        kind_meta,
+        mtime,
+        unix_mode: UnixMode::default(),
+        owner: Owner::default(),
+        acls: Acls::default(),
+        capability: None,
+        link_group: None,
+        inode: None,
+    };
+    let mut dir = Apath::root();
+    writer.copy_dir(&entry(dir.clone(), KindMeta::Dir), monitor.as_ref())?;
+    let names = apath[1..].split('/').collect::<Vec<_>>();
+    let (file_name, dir_names) = names.split_last().expect("apath has a name");
+    for name in dir_names {
+        dir = dir.append(name);
+        writer.copy_dir(&entry(dir.clone(), KindMeta::Dir), monitor.as_ref())?;
+    }
+    let file_apath = dir.append(file_name);
+    monitor.count(Counter::Files, 1);
+    let addrs = store_file_content(
+        &file_apath,
+        &mut ThrottledRead::new(from, writer.read_throttle.clone()),
+        &mut writer.block_writer,
+        writer.compression,
+        &mut writer.stats,
+        options,
+        monitor.clone(),
+    )?;
+    let size = addrs.iter().map(|addr| addr.len).sum();
+    let file_entry = entry(file_apath, KindMeta::File { size });
+    writer.stats.files += 1;
+    writer.stats.new_files += 1;
+    writer.index_builder.push_entry(IndexEntry {
+        addrs,
+        ..IndexEntry::metadata_from(&file_entry)
+    });
+    if let Some(cb) = &options.change_callback {
+        cb(&EntryChange::added(&file_entry))?;
+    }
+    writer.flush_group(monitor.clone())?;
+    let mut stats = writer.finish(monitor)?;
+    stats.elapsed = start.elapsed();
+    record_backup_stats(&span, &stats);
+    Ok(stats)
+}
+
+fn backup_tree(
+    archive: &Archive,
+    source_tree: &LiveTree,
+    source_paths: &[&Path],
+    options: &BackupOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<BackupStats> {
+    let start = Instant::now();
+    let span = backup_span();
+    if options.zstd_dictionary && archive.zstd_dictionary().is_none() {
+        train_zstd_dictionary(archive, source_tree, options, monitor.clone())?;
+    }
+    let mut writer = BackupWriter::begin(archive, source_paths, options, monitor.clone())?;
+    span.record("band_id", display(writer.band.id()));
     let mut stats = BackupStats::default();
-    let source_tree = LiveTree::open(source_path)?;
 
     let task = monitor.start_task("Backup".to_string());
 
-    let entry_iter =
+    let mut entry_iter =
         source_tree.iter_entries(Apath::root(), options.exclude.clone(), monitor.clone())?;
-    for entry_group in entry_iter.chunks(options.max_entries_per_hunk).into_iter() {
+    for entry_group in entry_iter
+        .by_ref()
+        .chunks(options.max_entries_per_hunk)
+        .into_iter()
+    {
         for mut entry in entry_group {
             if !options.owner {
                 entry.owner.clear();
             }
-            match writer.copy_entry(&entry, &source_tree, options, monitor.clone()) {
+            if !options.acls {
+                entry.acls.clear();
+            }
+            check_cancel(monitor.as_ref())?;
+            monitor.entry_started(entry.apath(), entry.kind());
+            let outcome = match writer.copy_entry(&entry, source_tree, options, monitor.clone()) {
+                Err(Error::Cancelled) => return Err(Error::Cancelled),
                 Err(err) => {
                     monitor.error(err);
                     stats.errors += 1;
-                    continue;
+                    EntryOutcome::Failed
                 }
                 Ok(Some(entry_change)) => {
                     match entry_change.change {
@@ -120,13 +525,33 @@ pub fn backup(
                     if let Some(cb) = &options.change_callback {
                         cb(&entry_change)?;
                     }
+                    if let Change::Unchanged { .. } = entry_change.change {
+                        EntryOutcome::Unchanged
+                    } else {
+                        EntryOutcome::Written
+                    }
                 }
-                Ok(_) => {}
+                Ok(None) if entry.kind() == Kind::Unknown => EntryOutcome::Skipped,
+                Ok(None) => EntryOutcome::Written,
+            };
+            let bytes = match (outcome, entry.kind()) {
+                (EntryOutcome::Written, Kind::File) => entry.size().unwrap_or_default(),
+                _ => 0,
+            };
+            monitor.entry_finished(entry.apath(), entry.kind(), outcome, bytes);
+            if outcome == EntryOutcome::Failed {
+                continue;
             }
             task.set_name(format!("Backup {}", entry.apath()));
         }
         writer.flush_group(monitor.clone())?;
     }
+    stats.cachedirs_skipped = entry_iter.stats().cachedirs_skipped;
+    stats.marked_dirs_skipped = entry_iter.stats().marked_dirs_skipped;
+    stats.large_files_skipped = entry_iter.stats().large_files_skipped;
+    stats.large_file_bytes_skipped = entry_iter.stats().large_file_bytes_skipped;
+    stats.old_files_skipped = entry_iter.stats().old_files_skipped;
+    stats.mount_points_skipped = entry_iter.stats().mount_points_skipped;
     stats += writer.finish(monitor.clone())?;
     stats.elapsed = start.elapsed();
     let block_stats = &archive.block_dir.stats;
@@ -134,69 +559,370 @@ pub fn backup(
     stats.read_blocks_compressed_bytes = block_stats.read_block_compressed_bytes.load(Relaxed);
     stats.read_blocks_uncompressed_bytes = block_stats.read_block_uncompressed_bytes.load(Relaxed);
     // TODO: Merge in stats from the source tree?
+    record_backup_stats(&span, &stats);
     Ok(stats)
 }
 
+/// Enter a span covering the whole of writing one band.
+///
+/// The band id and totals are recorded as they become known.
+pub(crate) fn backup_span() -> tracing::span::EnteredSpan {
+    info_span!(
+        "backup",
+        apath = Empty,
+        band_id = Empty,
+        files = Empty,
+        written_blocks = Empty,
+        uncompressed_bytes = Empty,
+        compressed_bytes = Empty,
+        deduplicated_bytes = Empty,
+        errors = Empty,
+    )
+    .entered()
+}
+
+pub(crate) fn record_backup_stats(span: &Span, stats: &BackupStats) {
+    span.record("files", stats.files);
+    span.record("written_blocks", stats.written_blocks);
+    span.record("uncompressed_bytes", stats.uncompressed_bytes);
+    span.record("compressed_bytes", stats.compressed_bytes);
+    span.record("deduplicated_bytes", stats.deduplicated_bytes);
+    span.record("errors", stats.errors);
+}
+
+/// Train a zstd dictionary from a sample of small files in the source tree, and
+/// store it in the archive.
+///
+/// If there are too few small files to train a dictionary, this logs a warning and
+/// leaves the archive without one.
+fn train_zstd_dictionary(
+    archive: &Archive,
+    source_tree: &LiveTree,
+    options: &BackupOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<()> {
+    /// Maximum size of the trained dictionary, following the zstd default.
+    const MAX_DICTIONARY_SIZE: usize = 110 << 10;
+    /// Read about this much sample data; zstd suggests roughly 100 times the dictionary size.
+    const SAMPLE_BYTES: u64 = 100 * MAX_DICTIONARY_SIZE as u64;
+
+    let task = monitor.start_task("Train zstd dictionary".to_string());
+    let mut samples: Vec<Vec<u8>> = Vec::new();
+    let mut sample_bytes = 0;
+    for entry in
+        source_tree.iter_entries(Apath::root(), options.exclude.clone(), monitor.clone())?
+    {
+        if sample_bytes >= SAMPLE_BYTES {
+            break;
+        }
+        if entry.kind() != Kind::File {
+            continue;
+        }
+        match entry.size() {
+            Some(size) if size > 0 && size <= options.small_file_cap => {}
+            _ => continue,
+        }
+        let mut content = Vec::new();
+        // Files that can't be read now will be reported when they're backed up.
+        if let Ok(mut file) = source_tree.open_file(&entry) {
+            if file.read_to_end(&mut content).is_ok() && !content.is_empty() {
+                sample_bytes += content.len() as u64;
+                samples.push(content);
+                task.set_name(format!("Train zstd dictionary: {}", entry.apath()));
+            }
+        }
+    }
+    match compress::zstd::train_dictionary(&samples, MAX_DICTIONARY_SIZE) {
+        Ok(dictionary) => {
+            debug!(
+                samples = samples.len(),
+                sample_bytes,
+                dictionary_len = dictionary.len(),
+                "Trained zstd dictionary"
+            );
+            archive.write_zstd_dictionary(dictionary)
+        }
+        Err(err) => {
+            warn!(
+                ?err,
+                samples = samples.len(),
+                "Failed to train a zstd dictionary; small files will be compressed without one"
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Where to find the previous state of each file.
+enum Basis {
+    /// The index of the last band in the archive.
+    Index(Box<index::IndexEntryIter<IterStitchedIndexHunks>>),
+    /// A local change cache describing the last band.
+    Cache(Box<ChangeCacheReader>),
+}
+
+impl Basis {
+    fn advance_to(&mut self, apath: &Apath) -> Option<CachedEntry> {
+        match self {
+            Basis::Index(iter) => iter
+                .advance_to(apath)
+                .map(|entry| CachedEntry { entry, inode: None }),
+            Basis::Cache(reader) => reader.advance_to(apath),
+        }
+    }
+}
+
 /// Accepts files to write in the archive (in apath order.)
-struct BackupWriter {
-    band: Band,
-    index_builder: IndexWriter,
-    stats: BackupStats,
+pub(crate) struct BackupWriter {
+    pub(crate) band: Band,
+    pub(crate) index_builder: IndexWriter,
+    pub(crate) stats: BackupStats,
     block_dir: Arc<BlockDir>,
 
-    /// The index for the last stored band, used as hints for whether newly
+    /// Compression for newly written blocks.
+    pub(crate) compression: Compression,
+
+    /// The previous state of each file, used as hints for whether newly
     /// stored files have changed.
-    basis_index: crate::index::IndexEntryIter<crate::stitch::IterStitchedIndexHunks>,
+    basis: Basis,
 
-    file_combiner: FileCombiner,
+    /// Write a change cache describing the new band, if requested.
+    change_cache: Option<ChangeCacheWriter>,
+
+    /// Inode numbers of the files in the current group, for the change cache.
+    inodes: HashMap<Apath, u64>,
+
+    pub(crate) file_combiner: FileCombiner,
+
+    /// Stores new blocks, possibly on other threads.
+    pub(crate) block_writer: BlockWriter,
+
+    /// Limits the rate of reading source files, if requested.
+    read_throttle: Option<Arc<ReadThrottle>>,
 }
 
 impl BackupWriter {
     /// Create a new BackupWriter.
     ///
-    /// This currently makes a new top-level band.
-    pub fn begin(
+    /// This currently makes a new top-level band, recording the source directories
+    /// it backs up, if any.
+    pub(crate) fn begin(
         archive: &Archive,
+        source_paths: &[&Path],
         options: &BackupOptions,
         monitor: Arc<dyn Monitor>,
     ) -> Result<Self> {
         if gc_lock::GarbageCollectionLock::is_locked(archive)? {
             return Err(Error::GarbageCollectionLockHeld);
         }
-        let basis_index = if let Some(basis_band_id) = archive.last_band_id()? {
-            IterStitchedIndexHunks::new(archive, basis_band_id, monitor)
+        let compression = options
+            .compression
+            .unwrap_or_else(|| archive.default_compression());
+        // Bands that validation found to be damaged aren't trusted to say which
+        // files are unchanged.
+        let damaged_bands = archive.damaged_bands().unwrap_or_else(|err| {
+            warn!(?err, "Failed to read the list of damaged bands");
+            BTreeMap::new()
+        });
+        let basis_band_id = archive.list_band_ids()?.into_iter().rev().find(|band_id| {
+            let damaged = damaged_bands.contains_key(band_id);
+            if damaged {
+                warn!(%band_id, "Not using damaged band as the basis for this backup");
+            }
+            !damaged
+        });
+        let basis_band = basis_band_id.map(|id| Band::open(archive, id));
+        // Unchanged files may refer to tagged blocks written by the basis band, so
+        // once any band uses tagged blocks, all later bands must also be flagged.
+        let basis_has_tagged_blocks = match &basis_band {
+            Some(Ok(basis_band)) => basis_band
+                .format_flags()
+                .iter()
+                .any(|f| f == band::flags::TAGGED_BLOCKS),
+            Some(Err(err)) => {
+                warn!(
+                    ?err,
+                    "Failed to read basis band head; assuming tagged blocks"
+                );
+                true
+            }
+            None => false,
+        };
+        let cache_reader = match (&options.change_cache, &basis_band) {
+            (Some(path), Some(Ok(basis_band))) => ChangeCacheReader::open(path, basis_band),
+            _ => None,
+        };
+        let basis = if let Some(cache_reader) = cache_reader {
+            Basis::Cache(Box::new(cache_reader))
         } else {
-            IterStitchedIndexHunks::empty(archive, monitor)
-        }
-        .iter_entries(Apath::root(), Exclude::nothing());
+            Basis::Index(Box::new(
+                if let Some(basis_band_id) = basis_band_id {
+                    IterStitchedIndexHunks::new(archive, basis_band_id, monitor)
+                } else {
+                    IterStitchedIndexHunks::empty(archive, monitor)
+                }
+                .iter_entries(Apath::root(), Exclude::nothing()),
+            ))
+        };
+
+        // Blocks of small files are compressed with zstd using the archive's dictionary.
+        let (small_file_compression, dictionary) =
+            match (options.zstd_dictionary, archive.zstd_dictionary()) {
+                (true, Some(dictionary)) => {
+                    let level = match compression {
+                        Compression::Zstd { level } => level,
+                        _ => DEFAULT_ZSTD_LEVEL,
+                    };
+                    (Compression::Zstd { level }, Some(dictionary))
+                }
+                _ => (compression, None),
+            };
 
         // Create the new band only after finding the basis band!
-        let band = Band::create(archive)?;
+        let format_flags: &[_] =
+            if compression.is_legacy() && dictionary.is_none() && !basis_has_tagged_blocks {
+                band::flags::DEFAULT
+            } else {
+                &[Cow::Borrowed(band::flags::TAGGED_BLOCKS)]
+            };
+        let mut band =
+            Band::create_with_flags_and_compression(archive, format_flags, Some(compression))?;
+        if options.label.is_some() || options.message.is_some() {
+            band.set_description(options.label.clone(), options.message.clone())?;
+        }
+        if let Some(expire_after) = options.expire_after {
+            band.set_expire_after(expire_after)?;
+        }
+        if !source_paths.is_empty() {
+            // Relative paths aren't much use to someone looking at the archive later.
+            band.set_sources(
+                source_paths
+                    .iter()
+                    .map(|path| {
+                        path.canonicalize()
+                            .unwrap_or_else(|_| path.to_path_buf())
+                            .display()
+                            .to_string()
+                    })
+                    .collect(),
+            )?;
+        }
+        if options.index_format != IndexFormat::Json {
+            band.set_index_format(options.index_format)?;
+        }
+        if options.index_checksums {
+            band.add_format_flag(band::flags::INDEX_CHECKSUMS)?;
+        }
+        // Unchanged files, and new files with the same content, may refer to packed blocks.
+        if archive.block_dir().has_packs()? {
+            band.add_format_flag(band::flags::PACKED_BLOCKS)?;
+        }
         let index_builder = band.index_builder();
+        let change_cache = options.change_cache.as_ref().and_then(|path| {
+            match ChangeCacheWriter::create(path, &band) {
+                Ok(writer) => Some(writer),
+                Err(err) => {
+                    warn!(?path, ?err, "Failed to create change cache");
+                    None
+                }
+            }
+        });
         Ok(BackupWriter {
             band,
             index_builder,
             block_dir: archive.block_dir.clone(),
+            compression,
             stats: BackupStats::default(),
-            basis_index,
-            file_combiner: FileCombiner::new(archive.block_dir.clone(), options.max_block_size),
+            basis,
+            change_cache,
+            inodes: HashMap::new(),
+            file_combiner: FileCombiner::new(
+                options.max_block_size.min(MAX_BLOCK_SIZE),
+                small_file_compression,
+                dictionary,
+            ),
+            block_writer: BlockWriter::new(archive.block_dir.clone(), &options.parallelism),
+            read_throttle: options
+                .max_read_rate
+                .filter(|rate| *rate > 0)
+                .map(|rate| Arc::new(ReadThrottle::new(rate))),
         })
     }
 
-    fn finish(self, monitor: Arc<dyn Monitor>) -> Result<BackupStats> {
+    pub(crate) fn finish(self, monitor: Arc<dyn Monitor>) -> Result<BackupStats> {
         let hunks = self.index_builder.finish(monitor)?;
         self.band.close(hunks as u64)?;
+        if let Some(change_cache) = self.change_cache {
+            if let Err(err) = change_cache.finish() {
+                warn!(?err, "Failed to write change cache");
+            }
+        }
         Ok(BackupStats { ..self.stats })
     }
 
     /// Write out any pending data blocks, and then the pending index entries.
+    ///
+    /// Files with blocks that couldn't be stored are reported as errors, and left out
+    /// of the index.
     fn flush_group(&mut self, monitor: Arc<dyn Monitor>) -> Result<()> {
-        let (stats, mut entries) = self.file_combiner.drain(monitor.clone())?;
+        let _span = debug_span!("flush_group").entered();
+        let (stats, results) = self.drain_blocks(monitor.clone())?;
         self.stats += stats;
+        let mut entries = Vec::with_capacity(results.len());
+        for result in results {
+            match result {
+                Ok(entry) => entries.push(entry),
+                Err(err) => {
+                    monitor.error(err);
+                    self.stats.errors += 1;
+                }
+            }
+        }
         self.index_builder.append_entries(&mut entries);
+        self.write_change_cache();
         self.index_builder.finish_hunk(monitor)
     }
 
+    /// Store any pending small files, and wait until all queued blocks are written.
+    ///
+    /// Returns the stats for storing the blocks, and the entries for files whose
+    /// addresses weren't known until their blocks were written, or the errors for
+    /// those whose blocks couldn't be stored.
+    pub(crate) fn drain_blocks(
+        &mut self,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<(BackupStats, Vec<Result<IndexEntry>>)> {
+        let mut stats = self.file_combiner.drain(&mut self.block_writer, monitor)?;
+        let (block_stats, entries) = self.block_writer.drain();
+        stats += block_stats;
+        Ok((stats, entries))
+    }
+
+    /// Add the files in the current group to the change cache.
+    ///
+    /// If the cache can't be written, it's abandoned, but the backup continues.
+    fn write_change_cache(&mut self) {
+        let Some(change_cache) = &mut self.change_cache else {
+            return;
+        };
+        let mut entries = self
+            .index_builder
+            .pending_entries()
+            .iter()
+            .filter(|entry| entry.kind == Kind::File)
+            .map(|entry| CachedEntry {
+                entry: entry.clone(),
+                inode: self.inodes.get(&entry.apath).copied(),
+            })
+            .collect_vec();
+        entries.sort_unstable_by(|a, b| a.entry.apath.cmp(&b.entry.apath));
+        if let Err(err) = change_cache.write_entries(&entries) {
+            warn!(?err, "Failed to write change cache");
+            self.change_cache = None;
+        }
+        self.inodes.clear();
+    }
+
     /// Add one entry to the backup.
     ///
     /// Return an indication of whether it changed (if it's a file), or
@@ -214,11 +940,11 @@ impl BackupWriter {
             Kind::Dir => self.copy_dir(entry, monitor.as_ref()),
             Kind::File => self.copy_file(entry, source, options, monitor.clone()),
             Kind::Symlink => self.copy_symlink(entry, monitor.as_ref()),
+            Kind::CharDevice | Kind::BlockDevice | Kind::Fifo | Kind::Socket => {
+                self.copy_special(entry, monitor.as_ref())
+            }
             Kind::Unknown => {
                 self.stats.unknown_kind += 1;
-                // TODO: Perhaps eventually we could backup and restore pipes,
-                // sockets, etc. Or at least count them. For now, silently skip.
-                // https://github.com/sourcefrog/conserve/issues/82
                 Ok(None)
             }
         }
@@ -247,14 +973,36 @@ impl BackupWriter {
         self.stats.files += 1;
         monitor.count(Counter::Files, 1);
         let apath = source_entry.apath();
-        let result = if let Some(basis_entry) = self.basis_index.advance_to(apath) {
-            if content_heuristically_unchanged(source_entry, &basis_entry) {
-                if all_blocks_present(&basis_entry.addrs, &self.block_dir, &monitor) {
+        if source_entry
+            .link_group()
+            .is_some_and(|first| first != apath)
+        {
+            // The content is stored again, but it will be deduplicated against the first link.
+            self.stats.hardlinks += 1;
+            monitor.count(Counter::Hardlinks, 1);
+        }
+        if let (Some(_), Some(inode)) = (&self.change_cache, source_entry.inode) {
+            self.inodes.insert(apath.clone(), inode);
+        }
+        // Blocks referenced by the cache were written by the last complete band, so
+        // are trusted to still be present.
+        let from_cache = matches!(self.basis, Basis::Cache(_));
+        let result = if let Some(CachedEntry {
+            entry: basis_entry,
+            inode: basis_inode,
+        }) = self.basis.advance_to(apath)
+        {
+            if content_heuristically_unchanged(source_entry, &basis_entry)
+                && (!from_cache || basis_inode == source_entry.inode)
+            {
+                if from_cache || all_blocks_present(&basis_entry.addrs, &self.block_dir, &monitor) {
                     self.stats.unmodified_files += 1;
                     let new_entry = IndexEntry {
                         addrs: basis_entry.addrs.clone(),
+                        holes: basis_entry.holes.clone(),
                         ..IndexEntry::metadata_from(source_entry)
                     };
+                    self.record_holes(&new_entry.holes)?;
                     let change = if new_entry == basis_entry {
                         EntryChange::unchanged(&basis_entry)
                     } else {
@@ -284,29 +1032,84 @@ impl BackupWriter {
             self.stats.empty_files += 1;
             monitor.count(Counter::EmptyFiles, 1);
         } else {
-            let mut source_file = from_tree.open_file(source_entry)?;
-            if size <= options.small_file_cap {
-                self.file_combiner
-                    .push_file(source_entry, &mut source_file, monitor.clone())?;
-                monitor.count(Counter::SmallFiles, 1);
-            } else {
-                let addrs = store_file_content(
-                    apath,
+            let source_file = from_tree.open_file(source_entry)?;
+            if size <= options.small_file_cap.min(MAX_BLOCK_SIZE as u64) {
+                let mut source_file = ThrottledRead::new(source_file, self.read_throttle.clone());
+                self.file_combiner.push_file(
+                    source_entry,
                     &mut source_file,
-                    &self.block_dir,
-                    &mut self.stats,
-                    options.max_block_size,
+                    &mut self.block_writer,
                     monitor.clone(),
                 )?;
-                self.index_builder.push_entry(IndexEntry {
-                    addrs,
-                    ..IndexEntry::metadata_from(source_entry)
+                monitor.count(Counter::SmallFiles, 1);
+            } else {
+                let holes = find_holes(&source_file, size).unwrap_or_else(|err| {
+                    warn!(%apath, ?err, "Failed to find holes in file; storing it all");
+                    Vec::new()
                 });
+                let mut source_file = ThrottledRead::new(source_file, self.read_throttle.clone());
+                let queued = if holes.is_empty() {
+                    queue_file_content(
+                        apath,
+                        &mut source_file,
+                        &mut self.block_writer,
+                        self.compression,
+                        &mut self.stats,
+                        options,
+                        monitor.clone(),
+                    )?
+                } else {
+                    self.stats.sparse_files += 1;
+                    self.record_holes(&holes)?;
+                    queue_sparse_file_content(
+                        apath,
+                        &mut source_file,
+                        &holes,
+                        size,
+                        &mut self.block_writer,
+                        self.compression,
+                        &mut self.stats,
+                        options,
+                        monitor.clone(),
+                    )?
+                };
+                // The entry is complete once its blocks are written.
+                self.block_writer.push_file(
+                    IndexEntry {
+                        holes,
+                        ..IndexEntry::metadata_from(source_entry)
+                    },
+                    queued,
+                );
             }
         }
         Ok(result)
     }
 
+    /// Record a device node, FIFO, or socket, which has no content.
+    fn copy_special(
+        &mut self,
+        source_entry: &EntryValue,
+        monitor: &dyn Monitor,
+    ) -> Result<Option<EntryChange>> {
+        monitor.count(Counter::SpecialFiles, 1);
+        self.stats.special_files += 1;
+        self.band.add_format_flag(band::flags::SPECIAL_FILES)?;
+        self.index_builder
+            .push_entry(IndexEntry::metadata_from(source_entry));
+        // TODO: Emit the actual change.
+        Ok(None)
+    }
+
+    /// Mark the band as containing sparse files, if these holes are not empty.
+    fn record_holes(&mut self, holes: &[Hole]) -> Result<()> {
+        if holes.is_empty() {
+            Ok(())
+        } else {
+            self.band.add_format_flag(band::flags::SPARSE_FILES)
+        }
+    }
+
     fn copy_symlink(
         &mut self,
         source_entry: &EntryValue,
@@ -335,36 +1138,157 @@ fn all_blocks_present(
         .all(|hash| block_dir.contains(hash, monitor.clone()).unwrap_or(false))
 }
 
-fn store_file_content(
+/// Store the content of a file, returning its addresses once its blocks are written.
+pub(crate) fn store_file_content(
     apath: &Apath,
     from_file: &mut dyn Read,
-    block_dir: &BlockDir,
+    block_writer: &mut BlockWriter,
+    compression: Compression,
     stats: &mut BackupStats,
-    max_block_size: usize,
+    options: &BackupOptions,
     monitor: Arc<dyn Monitor>,
 ) -> Result<Vec<Address>> {
-    let mut addresses = Vec::<Address>::with_capacity(1);
-    loop {
-        let buffer = read_with_retries(max_block_size, from_file).map_err(|source| {
-            Error::ReadSourceFile {
+    let queued = queue_file_content(
+        apath,
+        from_file,
+        block_writer,
+        compression,
+        stats,
+        options,
+        monitor,
+    )?;
+    block_writer.addresses(apath, &queued)
+}
+
+/// Read a file and queue its blocks to be stored, returning their future addresses.
+fn queue_file_content(
+    apath: &Apath,
+    from_file: &mut dyn Read,
+    block_writer: &mut BlockWriter,
+    compression: Compression,
+    stats: &mut BackupStats,
+    options: &BackupOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<Vec<QueuedAddress>> {
+    let mut addresses = Vec::with_capacity(1);
+    queue_blocks(
+        apath,
+        from_file,
+        block_writer,
+        compression,
+        options,
+        monitor.clone(),
+        &mut addresses,
+    )?;
+    count_file_blocks(addresses.len(), stats, monitor.as_ref());
+    Ok(addresses)
+}
+
+/// Queue the data between the holes of a sparse file to be stored.
+#[allow(clippy::too_many_arguments)]
+fn queue_sparse_file_content(
+    apath: &Apath,
+    from_file: &mut (impl Read + Seek),
+    holes: &[Hole],
+    size: u64,
+    block_writer: &mut BlockWriter,
+    compression: Compression,
+    stats: &mut BackupStats,
+    options: &BackupOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<Vec<QueuedAddress>> {
+    let mut addresses = Vec::new();
+    for (start, len) in data_ranges(holes, size) {
+        from_file
+            .seek(SeekFrom::Start(start))
+            .map_err(|source| Error::ReadSourceFile {
                 path: apath.to_string().into(),
                 source,
-            }
-        })?;
-        if buffer.is_empty() {
-            break;
-        }
-        let buffer = buffer.freeze();
+            })?;
+        queue_blocks(
+            apath,
+            &mut from_file.take(len),
+            block_writer,
+            compression,
+            options,
+            monitor.clone(),
+            &mut addresses,
+        )?;
+    }
+    // A file that's entirely a hole has no blocks, but isn't empty.
+    if !addresses.is_empty() {
+        count_file_blocks(addresses.len(), stats, monitor.as_ref());
+    }
+    Ok(addresses)
+}
+
+/// Read blocks until the end of the input and queue them to be stored, appending
+/// their future addresses.
+fn queue_blocks(
+    apath: &Apath,
+    from_file: &mut dyn Read,
+    block_writer: &mut BlockWriter,
+    compression: Compression,
+    options: &BackupOptions,
+    monitor: Arc<dyn Monitor>,
+    addresses: &mut Vec<QueuedAddress>,
+) -> Result<()> {
+    let span = trace_span!("store_blocks", %apath, blocks = Empty, bytes = Empty).entered();
+    let first_address = addresses.len();
+    let read_error = |source| Error::ReadSourceFile {
+        path: apath.to_string().into(),
+        source,
+    };
+    let mut queue_block = |buffer: Bytes| -> Result<()> {
+        check_cancel(monitor.as_ref())?;
         monitor.count(Counter::FileBytes, buffer.len());
         let len = buffer.len() as u64;
-        let hash = block_dir.store_or_deduplicate(buffer, stats, monitor.clone())?;
-        addresses.push(Address {
-            hash,
+        let block = block_writer.submit(buffer, compression, None, monitor.clone());
+        addresses.push(QueuedAddress {
+            block,
             start: 0,
             len,
         });
+        Ok(())
+    };
+    if options.content_defined_chunking {
+        let (min_size, avg_size, max_size) = cdc_block_sizes(options.max_block_size);
+        for chunk in StreamCDC::new(from_file, min_size, avg_size, max_size) {
+            let chunk = chunk.map_err(|err| read_error(err.into()))?;
+            queue_block(chunk.data.into())?;
+        }
+    } else {
+        loop {
+            let buffer = read_with_retries(options.max_block_size.min(MAX_BLOCK_SIZE), from_file)
+                .map_err(read_error)?;
+            if buffer.is_empty() {
+                break;
+            }
+            queue_block(buffer.freeze())?;
+        }
     }
-    match addresses.len() {
+    let queued = &addresses[first_address..];
+    span.record("blocks", queued.len());
+    span.record("bytes", queued.iter().map(|addr| addr.len).sum::<u64>());
+    Ok(())
+}
+
+/// The largest block written by content-defined chunking.
+///
+/// Blocks are also no larger than [BackupOptions::max_block_size].
+pub const CDC_MAX_BLOCK_SIZE: usize = 4 << 20;
+
+/// Return the minimum, average, and maximum block sizes for content-defined chunking.
+fn cdc_block_sizes(max_block_size: usize) -> (u32, u32, u32) {
+    let max_size =
+        max_block_size.clamp(fastcdc::v2020::MAXIMUM_MIN as usize, CDC_MAX_BLOCK_SIZE) as u32;
+    let avg_size = (max_size / 4).max(fastcdc::v2020::AVERAGE_MIN);
+    let min_size = (max_size / 16).max(fastcdc::v2020::MINIMUM_MIN);
+    (min_size, avg_size, max_size)
+}
+
+fn count_file_blocks(blocks: usize, stats: &mut BackupStats, monitor: &dyn Monitor) {
+    match blocks {
         0 => {
             // This doesn't duplicate the call to monitor.count above, because
             // in this case we only discovered that it was empty after reading the
@@ -381,22 +1305,216 @@ fn store_file_content(
             stats.multi_block_files += 1
         }
     }
-    Ok(addresses)
+}
+
+/// The result of storing a block on a worker thread: its hash, and the stats for
+/// writing or deduplicating it.
+type StoredBlock = Result<(BlockHash, BackupStats)>;
+
+/// Hashes, compresses, and writes blocks, optionally on a pool of worker threads,
+/// while the caller continues reading source files.
+///
+/// Blocks are numbered in the order they're submitted, and their hashes are known
+/// only once they're written. Files whose content is in queued blocks are held here
+/// until [BlockWriter::drain], which returns their completed index entries.
+///
+/// If a block can't be stored, every file referencing it fails, but other files
+/// are unaffected.
+pub(crate) struct BlockWriter {
+    block_dir: Arc<BlockDir>,
+    /// Threads that store blocks, or None to store them on the caller's thread.
+    pool: Option<rayon::ThreadPool>,
+    /// Limits how many threads write to the transport at once.
+    write_limit: Arc<Semaphore>,
+    /// Maximum bytes of blocks submitted but not yet collected.
+    max_queued_bytes: u64,
+    /// Hashes of blocks stored since the last drain, by block number, or the
+    /// error from storing them.
+    hashes: Vec<std::result::Result<BlockHash, Arc<Error>>>,
+    /// Lengths of blocks submitted to the pool, and their results, in submission order.
+    queued: VecDeque<(u64, Receiver<StoredBlock>)>,
+    /// Total length of the queued blocks.
+    queued_bytes: u64,
+    /// Files waiting for their blocks to be stored.
+    files: Vec<(IndexEntry, Vec<QueuedAddress>)>,
+    stats: BackupStats,
+}
+
+/// The future address of some content in a block that's been submitted to a
+/// [BlockWriter].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct QueuedAddress {
+    /// Block number, counted since the last drain.
+    block: usize,
+    start: u64,
+    len: u64,
+}
+
+impl BlockWriter {
+    pub(crate) fn new(block_dir: Arc<BlockDir>, parallelism: &Parallelism) -> BlockWriter {
+        BlockWriter {
+            block_dir,
+            pool: thread_pool(parallelism.threads, "conserve-block"),
+            write_limit: Arc::new(Semaphore::new(parallelism.transport_concurrency)),
+            max_queued_bytes: parallelism.max_buffered_bytes,
+            hashes: Vec::new(),
+            queued: VecDeque::new(),
+            queued_bytes: 0,
+            files: Vec::new(),
+            stats: BackupStats::default(),
+        }
+    }
+
+    /// Queue a block to be stored, returning its block number.
+    ///
+    /// If the queued blocks would then take more than the memory limit, this first waits
+    /// for the oldest queued blocks to be stored.
+    ///
+    /// Errors storing the block are reported when the addresses of files in it are
+    /// resolved.
+    pub(crate) fn submit(
+        &mut self,
+        block_data: Bytes,
+        compression: Compression,
+        dictionary: Option<Bytes>,
+        monitor: Arc<dyn Monitor>,
+    ) -> usize {
+        let block = self.hashes.len() + self.queued.len();
+        if self.pool.is_none() {
+            let result = self.block_dir.store_or_deduplicate(
+                block_data,
+                compression,
+                dictionary.as_deref(),
+                &mut self.stats,
+                monitor,
+            );
+            self.hashes.push(result.map_err(Arc::new));
+            return block;
+        }
+        let len = block_data.len() as u64;
+        while !self.queued.is_empty() && self.queued_bytes + len > self.max_queued_bytes {
+            self.collect_one();
+        }
+        let pool = self.pool.as_ref().expect("pool exists");
+        let (sender, receiver) = sync_channel(1);
+        let block_dir = self.block_dir.clone();
+        let write_limit = self.write_limit.clone();
+        pool.spawn(move || {
+            let mut stats = BackupStats::default();
+            let result = block_dir
+                .store_or_deduplicate_limited(
+                    block_data,
+                    compression,
+                    dictionary.as_deref(),
+                    &mut stats,
+                    monitor,
+                    Some(&write_limit),
+                )
+                .map(|hash| (hash, stats));
+            // The receiver is gone only if the backup has already failed.
+            let _ = sender.send(result);
+        });
+        self.queued.push_back((len, receiver));
+        self.queued_bytes += len;
+        block
+    }
+
+    /// Wait for the oldest queued block to be stored, and record its hash or error.
+    fn collect_one(&mut self) {
+        let (len, receiver) = self.queued.pop_front().expect("a block is queued");
+        self.queued_bytes -= len;
+        let result = receiver.recv().expect("block writer thread panicked");
+        self.hashes
+            .push(result.map_err(Arc::new).map(|(hash, stats)| {
+                self.stats += stats;
+                hash
+            }));
+    }
+
+    /// Wait for the blocks holding some content of the file `apath`, and return its
+    /// addresses, or an error if any of them couldn't be stored.
+    pub(crate) fn addresses(
+        &mut self,
+        apath: &Apath,
+        queued: &[QueuedAddress],
+    ) -> Result<Vec<Address>> {
+        if let Some(last) = queued.iter().map(|addr| addr.block).max() {
+            while self.hashes.len() <= last {
+                self.collect_one();
+            }
+        }
+        queued
+            .iter()
+            .map(|addr| match &self.hashes[addr.block] {
+                Ok(hash) => Ok(Address {
+                    hash: hash.clone(),
+                    start: addr.start,
+                    len: addr.len,
+                }),
+                Err(err) => Err(Error::StoreFileBlock {
+                    apath: apath.clone(),
+                    source: err.clone(),
+                }),
+            })
+            .collect()
+    }
+
+    /// Hold an index entry, complete apart from its addresses, until its blocks are stored.
+    pub(crate) fn push_file(&mut self, entry: IndexEntry, addresses: Vec<QueuedAddress>) {
+        self.files.push((entry, addresses));
+    }
+
+    /// Wait for all queued blocks to be stored, and return the stats for storing them
+    /// along with the completed entries for all pending files, in the order they
+    /// were pushed, or for each file with a block that couldn't be stored, the error.
+    ///
+    /// Block numbers then restart from zero.
+    pub(crate) fn drain(&mut self) -> (BackupStats, Vec<Result<IndexEntry>>) {
+        while !self.queued.is_empty() {
+            self.collect_one();
+        }
+        let files = take(&mut self.files);
+        let entries = files
+            .into_iter()
+            .map(|(entry, queued)| {
+                Ok(IndexEntry {
+                    addrs: self.addresses(&entry.apath, &queued)?,
+                    ..entry
+                })
+            })
+            .collect();
+        self.hashes.clear();
+        (take(&mut self.stats), entries)
+    }
 }
 
 /// Combines multiple small files into a single block.
 ///
-/// When the block is finished, and only then, this returns the index entries with the addresses
-/// completed.
-struct FileCombiner {
+/// Each combined block is submitted to a [BlockWriter], which holds the files'
+/// entries until the block is stored.
+pub(crate) struct FileCombiner {
     /// Buffer of concatenated data from small files.
     buf: BytesMut,
     queue: Vec<QueuedFile>,
-    /// Entries for files that have been written to the blockdir, and that have complete addresses.
-    finished: Vec<IndexEntry>,
     stats: BackupStats,
-    block_dir: Arc<BlockDir>,
     max_block_size: usize,
+    compression: Compression,
+    /// zstd dictionary to compress combined blocks, if any.
+    dictionary: Option<Bytes>,
+    /// Where the content of each small file stored since the last drain is, by the
+    /// hash of its content, so that files with the same content are stored once.
+    stored: HashMap<BlockHash, StoredSmallFile>,
+    /// Hashes of the files whose content is in `buf`.
+    buffered: Vec<BlockHash>,
+}
+
+/// Where the content of a small file was put.
+#[derive(Debug, Clone, Copy)]
+enum StoredSmallFile {
+    /// In the current combine buffer, at this offset and length.
+    Buffered { start: usize, len: usize },
+    /// In a combined block that's been submitted.
+    Submitted(QueuedAddress),
 }
 
 /// A file in the process of being written into a combined block.
@@ -414,68 +1532,97 @@ struct QueuedFile {
 }
 
 impl FileCombiner {
-    fn new(block_dir: Arc<BlockDir>, max_block_size: usize) -> FileCombiner {
+    fn new(
+        max_block_size: usize,
+        compression: Compression,
+        dictionary: Option<Bytes>,
+    ) -> FileCombiner {
         FileCombiner {
-            block_dir,
             buf: BytesMut::new(),
             queue: Vec::new(),
-            finished: Vec::new(),
             stats: BackupStats::default(),
             max_block_size,
+            compression,
+            dictionary,
+            stored: HashMap::new(),
+            buffered: Vec::new(),
         }
     }
 
-    /// Flush any pending files, and return accumulated file entries and stats.
+    /// Submit any pending files, and return accumulated stats.
     /// The FileCombiner is then empty and ready for reuse.
-    fn drain(&mut self, monitor: Arc<dyn Monitor>) -> Result<(BackupStats, Vec<IndexEntry>)> {
-        self.flush(monitor)?;
+    pub(crate) fn drain(
+        &mut self,
+        block_writer: &mut BlockWriter,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<BackupStats> {
+        self.flush(block_writer, monitor)?;
         debug_assert!(self.queue.is_empty());
         debug_assert!(self.buf.is_empty());
-        Ok((
-            std::mem::take(&mut self.stats),
-            std::mem::take(&mut self.finished),
-        ))
+        // Block numbers restart after the block writer is drained.
+        self.stored.clear();
+        Ok(take(&mut self.stats))
     }
 
-    /// Write all the content from the combined block to a blockdir.
-    ///
-    /// Returns the fully populated entries for all files in this combined block.
+    /// Submit the combined block to be stored, passing the entries for all files in it
+    /// to the block writer.
     ///
     /// After this call the FileCombiner is empty and can be reused for more files into a new
     /// block.
-    fn flush(&mut self, monitor: Arc<dyn Monitor>) -> Result<()> {
+    fn flush(&mut self, block_writer: &mut BlockWriter, monitor: Arc<dyn Monitor>) -> Result<()> {
         if self.queue.is_empty() {
             debug_assert!(self.buf.is_empty());
             return Ok(());
         }
-        let hash = self.block_dir.store_or_deduplicate(
+        let _span = debug_span!(
+            "store_combined_block",
+            files = self.queue.len(),
+            bytes = self.buf.len()
+        )
+        .entered();
+        let block = block_writer.submit(
             take(&mut self.buf).freeze(),
-            &mut self.stats,
+            self.compression,
+            self.dictionary.clone(),
             monitor,
-        )?;
+        );
         self.stats.combined_blocks += 1;
-        self.finished
-            .extend(self.queue.drain(..).map(|qf| IndexEntry {
-                addrs: vec![Address {
-                    hash: hash.clone(),
+        for hash in self.buffered.drain(..) {
+            let place = self.stored.get_mut(&hash).expect("buffered file is stored");
+            if let StoredSmallFile::Buffered { start, len } = *place {
+                *place = StoredSmallFile::Submitted(QueuedAddress {
+                    block,
+                    start: start as u64,
+                    len: len as u64,
+                });
+            }
+        }
+        for qf in self.queue.drain(..) {
+            block_writer.push_file(
+                qf.entry,
+                vec![QueuedAddress {
+                    block,
                     start: qf.start.try_into().unwrap(),
                     len: qf.len.try_into().unwrap(),
                 }],
-                ..qf.entry
-            }));
+            );
+        }
         Ok(())
     }
 
     /// Add the contents of a small file into this combiner.
     ///
+    /// If a file with the same content was already added since the last drain, the
+    /// new file refers to that copy rather than storing the content again.
+    ///
     /// `entry` should be an IndexEntry that's complete apart from the block addresses.
-    fn push_file(
+    pub(crate) fn push_file(
         &mut self,
         entry: &EntryValue,
         from_file: &mut dyn Read,
+        block_writer: &mut BlockWriter,
         monitor: Arc<dyn Monitor>,
     ) -> Result<()> {
-        let start = self.buf.len();
         let expected_len: usize = entry
             .size()
             .expect("small file has no length")
@@ -484,9 +1631,15 @@ impl FileCombiner {
         let index_entry = IndexEntry::metadata_from(entry);
         if expected_len == 0 {
             self.stats.empty_files += 1;
-            self.finished.push(index_entry);
+            block_writer.push_file(index_entry, Vec::new());
             return Ok(());
         }
+        // Start a new block rather than letting this file take the block past the
+        // maximum size, unless the file is too big to fit anyhow.
+        if !self.buf.is_empty() && self.buf.len() + expected_len > self.max_block_size {
+            self.flush(block_writer, monitor.clone())?;
+        }
+        let start = self.buf.len();
         self.buf.resize(start + expected_len, 0);
         let len =
             from_file
@@ -498,22 +1651,44 @@ impl FileCombiner {
         self.buf.truncate(start + len);
         if len == 0 {
             self.stats.empty_files += 1;
-            self.finished.push(index_entry);
+            block_writer.push_file(index_entry, Vec::new());
             return Ok(());
         }
-        // TODO: Check whether this file is exactly the same as, or a prefix of,
-        // one already stored inside this block. In that case trim the buffer and
-        // use the existing start/len.
         self.stats.small_combined_files += 1;
+        let hash = BlockHash::hash_bytes(&self.buf[start..]);
+        match self.stored.get(&hash) {
+            Some(&StoredSmallFile::Buffered {
+                start: existing_start,
+                len: existing_len,
+            }) => {
+                self.buf.truncate(start);
+                self.stats.deduplicated_small_files += 1;
+                self.queue.push(QueuedFile {
+                    start: existing_start,
+                    len: existing_len,
+                    entry: index_entry,
+                });
+                return Ok(());
+            }
+            Some(StoredSmallFile::Submitted(address)) => {
+                self.buf.truncate(start);
+                self.stats.deduplicated_small_files += 1;
+                block_writer.push_file(index_entry, vec![*address]);
+                return Ok(());
+            }
+            None => {
+                self.stored
+                    .insert(hash.clone(), StoredSmallFile::Buffered { start, len });
+                self.buffered.push(hash);
+            }
+        }
         self.queue.push(QueuedFile {
             start,
             len,
             entry: index_entry,
         });
-        // TODO: This can overrun by one small file; it would be better to check
-        // in advance and perhaps start a new combined block that it will fit inside.
         if self.buf.len() >= self.max_block_size {
-            self.flush(monitor)
+            self.flush(block_writer, monitor)
         } else {
             Ok(())
         }
@@ -533,7 +1708,7 @@ fn content_heuristically_unchanged<E: EntryTrait, O: EntryTrait>(
         && basis_entry.size() == new_entry.size()
 }
 
-#[derive(Add, AddAssign, Debug, Default, Eq, PartialEq, Clone)]
+#[derive(Add, AddAssign, Debug, Default, Eq, PartialEq, Clone, Serialize)]
 pub struct BackupStats {
     // TODO: Have separate more-specific stats for backup and restore, and then
     // each can have a single Display method.
@@ -541,11 +1716,27 @@ pub struct BackupStats {
     pub files: usize,
     pub symlinks: usize,
     pub directories: usize,
+    /// Device nodes, FIFOs, and sockets.
+    pub special_files: usize,
     pub unknown_kind: usize,
+    /// Directories that were skipped because they contain a `CACHEDIR.TAG` file.
+    pub cachedirs_skipped: usize,
+    /// Directories that were skipped because they contain an exclusion marker file.
+    pub marked_dirs_skipped: usize,
+    /// Files that were skipped because they're larger than the limit.
+    pub large_files_skipped: usize,
+    /// Total size of the files skipped for being too large.
+    pub large_file_bytes_skipped: u64,
+    /// Files that were skipped because they were not modified recently enough.
+    pub old_files_skipped: usize,
+    /// Mount points whose contents were skipped to stay on one filesystem.
+    pub mount_points_skipped: usize,
 
     pub unmodified_files: usize,
     pub modified_files: usize,
     pub new_files: usize,
+    /// Files that are hard links to a file earlier in the tree.
+    pub hardlinks: usize,
 
     /// Files that were previously stored and that have been stored again because
     /// some of their blocks were damaged.
@@ -564,8 +1755,13 @@ pub struct BackupStats {
 
     pub empty_files: usize,
     pub small_combined_files: usize,
+    /// Small files with the same content as another stored earlier in the same backup,
+    /// which refer to that copy rather than storing it again.
+    pub deduplicated_small_files: usize,
     pub single_block_files: usize,
     pub multi_block_files: usize,
+    /// Files stored with holes.
+    pub sparse_files: usize,
 
     pub errors: usize,
 
@@ -582,20 +1778,38 @@ impl fmt::Display for BackupStats {
         write_count(w, "  unmodified files", self.unmodified_files);
         write_count(w, "  modified files", self.modified_files);
         write_count(w, "  new files", self.new_files);
+        write_count(w, "  hard links", self.hardlinks);
         write_count(w, "symlinks", self.symlinks);
         write_count(w, "directories", self.directories);
+        write_count(w, "special files", self.special_files);
         write_count(w, "unsupported file kind", self.unknown_kind);
+        write_count(w, "skipped cache directories", self.cachedirs_skipped);
+        write_count(w, "skipped marked directories", self.marked_dirs_skipped);
+        write_count(w, "skipped large files", self.large_files_skipped);
+        write_size(w, "  skipped size", self.large_file_bytes_skipped);
+        write_count(w, "skipped old files", self.old_files_skipped);
+        write_count(w, "skipped mount points", self.mount_points_skipped);
         writeln!(w).unwrap();
 
         write_count(w, "files stored:", self.new_files + self.modified_files);
         write_count(w, "  empty files", self.empty_files);
         write_count(w, "  small combined files", self.small_combined_files);
+        write_count(w, "    duplicates of others", self.deduplicated_small_files);
         write_count(w, "  single block files", self.single_block_files);
         write_count(w, "  multi-block files", self.multi_block_files);
+        write_count(w, "  sparse files", self.sparse_files);
         writeln!(w).unwrap();
 
         write_count(w, "data blocks deduplicated:", self.deduplicated_blocks);
         write_size(w, "  saved", self.deduplicated_bytes);
+        writeln!(
+            w,
+            "{:>12.1}x     deduplication ratio",
+            ratio(
+                self.deduplicated_bytes + self.uncompressed_bytes,
+                self.uncompressed_bytes
+            )
+        )?;
         writeln!(w).unwrap();
 
         write_count(w, "new data blocks written:", self.written_blocks);
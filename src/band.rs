@@ -23,13 +23,17 @@
 
 use std::borrow::Cow;
 use std::sync::Arc;
+use std::time::Duration;
 
+use fail::fail_point;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
 use tracing::{debug, warn};
 
-use crate::jsonio::{read_json, write_json};
+use crate::blockhash::BLOCK_HASH_ALGORITHM;
+use crate::compress::{BlockFormat, Compression};
+use crate::jsonio::{read_json, write_json, write_new_json};
 use crate::misc::remove_item;
 use crate::monitor::Monitor;
 use crate::transport::ListDir;
@@ -44,8 +48,36 @@ pub mod flags {
     /// Default flags for newly created bands.
     pub static DEFAULT: &[Cow<'static, str>] = &[];
 
+    /// Blocks referenced by this band may be in the tagged encoding, with a
+    /// header identifying their compression.
+    pub const TAGGED_BLOCKS: &str = "tagged_blocks";
+
+    /// Index entries may describe holes in sparse files, which must be
+    /// recreated on restore.
+    pub const SPARSE_FILES: &str = "sparse_files";
+
+    /// The index may contain device nodes, FIFOs, and sockets.
+    pub const SPECIAL_FILES: &str = "special_files";
+
+    /// Index hunks may be in the binary format.
+    pub const BINARY_INDEX: &str = "binary_index";
+
+    /// Index hunks end with a trailer holding their entry count and a checksum.
+    pub const INDEX_CHECKSUMS: &str = "index_checksums";
+
+    /// Blocks referenced by this band may be stored in packs rather than in
+    /// their own files.
+    pub const PACKED_BLOCKS: &str = "packed_blocks";
+
     /// All the flags understood by this version of Conserve.
-    pub static SUPPORTED: &[&str] = &[];
+    pub static SUPPORTED: &[&str] = &[
+        TAGGED_BLOCKS,
+        SPARSE_FILES,
+        SPECIAL_FILES,
+        BINARY_INDEX,
+        INDEX_CHECKSUMS,
+        PACKED_BLOCKS,
+    ];
 }
 
 /// Describes how to select a band from an archive.
@@ -57,6 +89,18 @@ pub enum BandSelectionPolicy {
     Latest,
     /// Open the band with the specified id.
     Specified(BandId),
+    /// Open the latest band with this label, regardless of whether it's complete.
+    Label(String),
+    /// Open the latest complete band that started at or before this time.
+    AsOf(OffsetDateTime),
+}
+
+fn block_format(format_flags: &[Cow<'static, str>]) -> BlockFormat {
+    if format_flags.iter().any(|f| f == flags::TAGGED_BLOCKS) {
+        BlockFormat::Tagged
+    } else {
+        BlockFormat::Legacy
+    }
 }
 
 fn band_version_requirement() -> semver::VersionReq {
@@ -81,7 +125,7 @@ pub struct Band {
     head: Head,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Head {
     /// Seconds since the Unix epoch when writing of this band began.
     start_time: i64,
@@ -94,6 +138,62 @@ struct Head {
     /// referenced data correctly.
     #[serde(default)]
     format_flags: Vec<Cow<'static, str>>,
+
+    /// How blocks referenced by this band are encoded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    block_format: Option<BlockFormat>,
+
+    /// Compression used for new blocks written by this band.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compression: Option<Compression>,
+
+    /// Algorithm used to name blocks by the hash of their content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    block_hash: Option<String>,
+
+    /// How entries are encoded in the index hunks written for this band; if absent,
+    /// json.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    index_format: Option<IndexFormat>,
+
+    /// Short human-readable name for this backup, which can be used to select it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+
+    /// Free-form description of this backup.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+
+    /// Seconds since the Unix epoch after which this backup may be deleted by
+    /// [prune](crate::prune::prune), regardless of the archive's retention policy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<i64>,
+
+    /// True if this band was damaged, and what could be read has been recovered
+    /// by [repair](crate::repair::repair).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    recovered: bool,
+
+    /// True if this band is held, so that it's not deleted by [prune](crate::prune::prune)
+    /// or [Archive::delete_bands] until the hold is released.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    held: bool,
+
+    /// Name of the host that made this backup.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hostname: Option<String>,
+
+    /// Name of the user that made this backup.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+
+    /// Version of Conserve that made this backup.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    conserve_version: Option<String>,
+
+    /// Source directories that were backed up.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sources: Vec<String>,
 }
 
 /// Format of the on-disk tail file.
@@ -109,7 +209,8 @@ struct Tail {
 }
 
 /// Readonly summary info about a band, from `Band::get_info`.
-pub struct Info {
+#[derive(Debug, Clone)]
+pub struct BandInfo {
     pub id: BandId,
     pub is_closed: bool,
 
@@ -121,6 +222,48 @@ pub struct Info {
 
     /// Number of hunks present in the index, if that is known.
     pub index_hunk_count: Option<u64>,
+
+    /// Format flags from the band head.
+    pub format_flags: Vec<String>,
+
+    /// How referenced blocks are encoded, if recorded.
+    pub block_format: Option<BlockFormat>,
+
+    /// Compression used for new blocks, if recorded.
+    pub compression: Option<Compression>,
+
+    /// Algorithm used to hash blocks, if recorded.
+    pub block_hash: Option<String>,
+
+    /// How index hunks are written for this band.
+    pub index_format: IndexFormat,
+
+    /// Label given to this backup, if any.
+    pub label: Option<String>,
+
+    /// Description given to this backup, if any.
+    pub message: Option<String>,
+
+    /// Time after which this backup may be deleted by pruning, if one was set.
+    pub expires: Option<OffsetDateTime>,
+
+    /// True if this band was damaged and has been partially recovered.
+    pub recovered: bool,
+
+    /// True if this band is held, so that it won't be deleted.
+    pub held: bool,
+
+    /// Name of the host that made this backup, if recorded.
+    pub hostname: Option<String>,
+
+    /// Name of the user that made this backup, if recorded.
+    pub username: Option<String>,
+
+    /// Version of Conserve that made this backup, if recorded.
+    pub conserve_version: Option<String>,
+
+    /// Source directories that were backed up, if recorded.
+    pub sources: Vec<String>,
 }
 
 // TODO: Maybe merge Band with StoredTree and/or with the Index classes? The distinction seems
@@ -136,16 +279,19 @@ impl Band {
     pub fn create_with_flags(
         archive: &Archive,
         format_flags: &[Cow<'static, str>],
+    ) -> Result<Band> {
+        Band::create_with_flags_and_compression(archive, format_flags, None)
+    }
+
+    /// Make a new band, recording the compression that will be used for its blocks.
+    pub fn create_with_flags_and_compression(
+        archive: &Archive,
+        format_flags: &[Cow<'static, str>],
+        compression: Option<Compression>,
     ) -> Result<Band> {
         format_flags
             .iter()
             .for_each(|f| assert!(flags::SUPPORTED.contains(&f.as_ref()), "unknown flag {f:?}"));
-        let band_id = archive
-            .last_band_id()?
-            .map_or_else(BandId::zero, |b| b.next_sibling());
-        let transport = archive.transport().sub_transport(&band_id.to_string());
-        transport.create_dir("")?;
-        transport.create_dir(INDEX_DIR)?;
         let band_format_version = if format_flags.is_empty() {
             Some("0.6.3".to_owned())
         } else {
@@ -155,28 +301,163 @@ impl Band {
             start_time: OffsetDateTime::now_utc().unix_timestamp(),
             band_format_version,
             format_flags: format_flags.into(),
+            block_format: Some(block_format(format_flags)),
+            compression,
+            block_hash: Some(BLOCK_HASH_ALGORITHM.to_owned()),
+            index_format: None,
+            label: None,
+            message: None,
+            expires: None,
+            recovered: false,
+            held: false,
+            hostname: owner::host_name(),
+            username: owner::current_user_name(),
+            conserve_version: Some(crate::VERSION.to_owned()),
+            sources: Vec::new(),
         };
-        write_json(&transport, BAND_HEAD_FILENAME, &head)?;
-        Ok(Band {
-            band_id,
-            head,
-            transport,
-        })
+        Band::create_with_head(archive, head)
+    }
+
+    /// Make a new band in another archive, with the same head as this band.
+    ///
+    /// The new band gets the next id after those that already exist there, and
+    /// has an empty index until it's filled by the caller.
+    pub(crate) fn create_copy(&self, archive: &Archive) -> Result<Band> {
+        Band::create_with_head(archive, self.head.clone())
+    }
+
+    /// Make a new band with the next id after those that already exist, and write
+    /// its head.
+    fn create_with_head(archive: &Archive, head: Head) -> Result<Band> {
+        let mut band_id = archive
+            .last_band_id()?
+            .map_or_else(BandId::zero, |b| b.next_sibling());
+        // Lets tests widen the window in which another process can choose the same id.
+        fail_point!("band::create::chose-id");
+        // Another process may be creating a band at the same time: whichever first
+        // writes the head owns the band id, and the others move on to the next id.
+        loop {
+            let transport = archive.transport().sub_transport(&band_id.to_string());
+            transport.create_dir("")?;
+            transport.create_dir(INDEX_DIR)?;
+            match write_new_json(&transport, BAND_HEAD_FILENAME, &head) {
+                Ok(()) => {
+                    return Ok(Band {
+                        band_id,
+                        head,
+                        transport,
+                    })
+                }
+                Err(jsonio::Error::Transport { source }) if source.is_already_exists() => {
+                    debug!(%band_id, "Band was created concurrently; trying the next id");
+                    band_id = band_id.next_sibling();
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
     }
 
     /// Mark this band closed: no more blocks should be written after this.
     pub fn close(&self, index_hunk_count: u64) -> Result<()> {
+        self.write_tail(OffsetDateTime::now_utc().unix_timestamp(), index_hunk_count)
+    }
+
+    /// Close this band with the same end time and number of index hunks as another
+    /// band, which must be closed.
+    pub(crate) fn copy_tail_from(&self, source: &Band) -> Result<()> {
+        let tail: Tail =
+            read_json(&source.transport, BAND_TAIL_FILENAME)?.ok_or(Error::BandIncomplete {
+                band_id: source.band_id,
+            })?;
+        write_json(&self.transport, BAND_TAIL_FILENAME, &tail).map_err(Error::from)
+    }
+
+    fn write_tail(&self, end_time: i64, index_hunk_count: u64) -> Result<()> {
         write_json(
             &self.transport,
             BAND_TAIL_FILENAME,
             &Tail {
-                end_time: OffsetDateTime::now_utc().unix_timestamp(),
+                end_time,
                 index_hunk_count: Some(index_hunk_count),
             },
         )
         .map_err(Error::from)
     }
 
+    /// Make a replacement head for a band whose head is missing or unreadable,
+    /// marked as recovered. The head is not written until [Band::write_head].
+    ///
+    /// The original format flags aren't known, so all supported flags are set, so
+    /// that older versions that might misread the band refuse to read it. The
+    /// start time is taken from the tail if it's readable, and otherwise is now.
+    pub(crate) fn replacement_head(archive: &Archive, band_id: BandId) -> Band {
+        let transport = archive.transport().sub_transport(&band_id.to_string());
+        let start_time = match read_json::<Tail, _>(&transport, BAND_TAIL_FILENAME) {
+            Ok(Some(tail)) => tail.end_time,
+            _ => OffsetDateTime::now_utc().unix_timestamp(),
+        };
+        let format_flags: Vec<Cow<'static, str>> =
+            flags::SUPPORTED.iter().map(|f| Cow::Borrowed(*f)).collect();
+        let head = Head {
+            start_time,
+            band_format_version: Some("23.2.0".to_owned()),
+            block_format: Some(block_format(&format_flags)),
+            format_flags,
+            compression: None,
+            block_hash: Some(BLOCK_HASH_ALGORITHM.to_owned()),
+            index_format: None,
+            label: None,
+            message: None,
+            expires: None,
+            recovered: true,
+            held: false,
+            hostname: None,
+            username: None,
+            conserve_version: None,
+            sources: Vec::new(),
+        };
+        Band {
+            band_id,
+            head,
+            transport,
+        }
+    }
+
+    /// Check that the tail, if there is one, is readable and records this number of
+    /// index hunks, and if not, rewrite it, unless this is a dry run.
+    ///
+    /// Bands without a tail were never finished, and are left incomplete.
+    /// Returns true if the tail needed to be rewritten.
+    pub(crate) fn repair_tail(&self, index_hunk_count: u64, dry_run: bool) -> Result<bool> {
+        let end_time = match read_json::<Tail, _>(&self.transport, BAND_TAIL_FILENAME) {
+            Ok(None) => return Ok(false),
+            Ok(Some(tail)) => {
+                if tail
+                    .index_hunk_count
+                    .map_or(true, |count| count == index_hunk_count)
+                {
+                    return Ok(false);
+                }
+                warn!(
+                    band_id = %self.band_id,
+                    tail_hunks = tail.index_hunk_count,
+                    index_hunk_count,
+                    "Band tail doesn't match the number of index hunks"
+                );
+                tail.end_time
+            }
+            Err(jsonio::Error::Json { source, .. }) => {
+                warn!(band_id = %self.band_id, %source, "Band tail is unreadable");
+                self.head.start_time
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if !dry_run {
+            self.write_tail(end_time, index_hunk_count)?;
+        }
+        Ok(true)
+    }
+
     /// Open the band with the given id.
     pub fn open(archive: &Archive, band_id: BandId) -> Result<Band> {
         let transport = archive.transport().sub_transport(&band_id.to_string());
@@ -214,6 +495,15 @@ impl Band {
         })
     }
 
+    /// Open the index of a band without reading the band head, which might be damaged.
+    pub(crate) fn open_index(archive: &Archive, band_id: BandId) -> IndexRead {
+        IndexRead::open(
+            archive
+                .transport()
+                .sub_transport(&format!("{band_id}/{INDEX_DIR}")),
+        )
+    }
+
     /// Delete a band.
     pub fn delete(archive: &Archive, band_id: BandId) -> Result<()> {
         // TODO: Count how many files were deleted, and the total size?
@@ -249,8 +539,145 @@ impl Band {
         &self.head.format_flags
     }
 
+    /// Record that blocks referenced by this band have been rewritten with new
+    /// compression, rewriting the band head.
+    ///
+    /// If the compression isn't readable by older versions, this adds the
+    /// `tagged_blocks` format flag.
+    pub(crate) fn set_compression(&mut self, compression: Compression) -> Result<()> {
+        if !compression.is_legacy() {
+            self.insert_format_flag(flags::TAGGED_BLOCKS);
+        }
+        self.head.compression = Some(compression);
+        self.head.block_format = Some(block_format(&self.head.format_flags));
+        self.head.block_hash = Some(BLOCK_HASH_ALGORITHM.to_owned());
+        self.write_head()
+    }
+
+    /// How index hunks are written for this band.
+    pub fn index_format(&self) -> IndexFormat {
+        self.head.index_format.unwrap_or_default()
+    }
+
+    /// Record how index hunks will be written for this band, rewriting the band head.
+    ///
+    /// Binary indexes add the `binary_index` format flag, so that older versions
+    /// refuse to read the band.
+    pub(crate) fn set_index_format(&mut self, index_format: IndexFormat) -> Result<()> {
+        if index_format == IndexFormat::Binary {
+            self.insert_format_flag(flags::BINARY_INDEX);
+        }
+        self.head.index_format = Some(index_format);
+        self.write_head()
+    }
+
+    /// Get the label given to this band, if any.
+    pub fn label(&self) -> Option<&str> {
+        self.head.label.as_deref()
+    }
+
+    /// Record a label and description for this band, rewriting the band head.
+    pub(crate) fn set_description(
+        &mut self,
+        label: Option<String>,
+        message: Option<String>,
+    ) -> Result<()> {
+        self.head.label = label;
+        self.head.message = message;
+        self.write_head()
+    }
+
+    /// Record the source directories backed up into this band, rewriting the band head.
+    pub(crate) fn set_sources(&mut self, sources: Vec<String>) -> Result<()> {
+        self.head.sources = sources;
+        self.write_head()
+    }
+
+    /// Record that this band may be deleted by pruning once this much time has passed
+    /// since it started, rewriting the band head.
+    pub(crate) fn set_expire_after(&mut self, expire_after: Duration) -> Result<()> {
+        let expires = OffsetDateTime::from_unix_timestamp(self.head.start_time)
+            .ok()
+            .zip(time::Duration::try_from(expire_after).ok())
+            .and_then(|(start_time, expire_after)| start_time.checked_add(expire_after));
+        let Some(expires) = expires else {
+            warn!(
+                ?expire_after,
+                "Expiry time is out of range; not recording it"
+            );
+            return Ok(());
+        };
+        self.head.expires = Some(expires.unix_timestamp());
+        self.write_head()
+    }
+
+    /// Add a format flag, if it's not already present, rewriting the band head.
+    pub(crate) fn add_format_flag(&mut self, flag: &'static str) -> Result<()> {
+        if self.head.format_flags.iter().any(|f| f == flag) {
+            return Ok(());
+        }
+        self.insert_format_flag(flag);
+        self.write_head()
+    }
+
+    /// Add a format flag to the in-memory head, and raise the format version to
+    /// the minimum that understands flags.
+    fn insert_format_flag(&mut self, flag: &'static str) {
+        debug_assert!(flags::SUPPORTED.contains(&flag));
+        if !self.head.format_flags.iter().any(|f| f == flag) {
+            self.head.format_flags.push(flag.into());
+        }
+        let min_version = semver::Version::new(23, 2, 0);
+        if self
+            .head
+            .band_format_version
+            .as_deref()
+            .and_then(|v| semver::Version::parse(v).ok())
+            .map_or(true, |v| v < min_version)
+        {
+            self.head.band_format_version = Some(min_version.to_string());
+        }
+    }
+
+    /// True if this band was damaged and has been partially recovered.
+    pub fn is_recovered(&self) -> bool {
+        self.head.recovered
+    }
+
+    /// Mark this band as partially recovered, rewriting the band head.
+    pub(crate) fn set_recovered(&mut self) -> Result<()> {
+        self.head.recovered = true;
+        self.write_head()
+    }
+
+    /// True if this band is held, so that it won't be deleted.
+    pub fn is_held(&self) -> bool {
+        self.head.held
+    }
+
+    /// Hold this band so that it won't be deleted, or release the hold, rewriting
+    /// the band head.
+    pub fn set_held(&mut self, held: bool) -> Result<()> {
+        if self.head.held == held {
+            return Ok(());
+        }
+        self.head.held = held;
+        self.write_head()
+    }
+
+    pub(crate) fn write_head(&self) -> Result<()> {
+        write_json(&self.transport, BAND_HEAD_FILENAME, &self.head).map_err(Error::from)
+    }
+
     pub fn index_builder(&self) -> IndexWriter {
         IndexWriter::new(self.transport.sub_transport(INDEX_DIR))
+            .with_format(self.index_format())
+            .with_checksums(
+                self.head
+                    .format_flags
+                    .iter()
+                    .any(|f| f == flags::INDEX_CHECKSUMS),
+            )
     }
 
     /// Get read-only access to the index of this band.
@@ -259,7 +686,7 @@ impl Band {
     }
 
     /// Return info about the state of this band.
-    pub fn get_info(&self) -> Result<Info> {
+    pub fn get_info(&self) -> Result<BandInfo> {
         let tail_option: Option<Tail> = read_json(&self.transport, BAND_TAIL_FILENAME)?;
         let start_time =
             OffsetDateTime::from_unix_timestamp(self.head.start_time).map_err(|_| {
@@ -277,12 +704,40 @@ impl Band {
                 })
             })
             .transpose()?;
-        Ok(Info {
+        let expires = self
+            .head
+            .expires
+            .map(|expires| {
+                OffsetDateTime::from_unix_timestamp(expires).map_err(|_| Error::InvalidMetadata {
+                    details: format!("Invalid band expiry timestamp {expires:?}"),
+                })
+            })
+            .transpose()?;
+        Ok(BandInfo {
             id: self.band_id,
             is_closed: tail_option.is_some(),
             start_time,
             end_time,
             index_hunk_count: tail_option.as_ref().and_then(|tail| tail.index_hunk_count),
+            format_flags: self
+                .head
+                .format_flags
+                .iter()
+                .map(|f| f.to_string())
+                .collect(),
+            block_format: self.head.block_format,
+            compression: self.head.compression,
+            block_hash: self.head.block_hash.clone(),
+            index_format: self.index_format(),
+            label: self.head.label.clone(),
+            message: self.head.message.clone(),
+            expires,
+            recovered: self.head.recovered,
+            held: self.head.held,
+            hostname: self.head.hostname.clone(),
+            username: self.head.username.clone(),
+            conserve_version: self.head.conserve_version.clone(),
+            sources: self.head.sources.clone(),
         })
     }
 
@@ -344,6 +799,12 @@ mod tests {
         assert_eq!(info.id.to_string(), "b0000");
         assert!(info.is_closed);
         assert_eq!(info.index_hunk_count, Some(0));
+        assert!(info.format_flags.is_empty());
+        assert_eq!(info.block_format, Some(BlockFormat::Legacy));
+        assert_eq!(info.compression, None);
+        assert_eq!(info.block_hash.as_deref(), Some("blake2b-512"));
+        assert_eq!(info.conserve_version.as_deref(), Some(crate::VERSION));
+        assert!(info.sources.is_empty());
         let dur = info.end_time.expect("info has an end_time") - info.start_time;
         // Test should have taken (much) less than 5s between starting and finishing
         // the band.  (It might fail if you set a breakpoint right there.)
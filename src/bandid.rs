@@ -16,7 +16,7 @@
 use std::fmt;
 use std::str::FromStr;
 
-use serde::Serialize;
+use serde::{Serialize, Serializer};
 
 use crate::errors::Error;
 
@@ -78,6 +78,15 @@ impl From<u32> for BandId {
     }
 }
 
+/// Serialize a band id as its string form, like `b0001`, for use with
+/// `#[serde(serialize_with)]` in reports.
+pub(crate) fn serialize_band_id<S: Serializer>(
+    band_id: &BandId,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(band_id)
+}
+
 impl fmt::Display for BandId {
     /// Returns the string representation of this BandId.
     ///
@@ -0,0 +1,415 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Measure how fast this machine and a transport can do the main steps of a
+//! backup, to guide the choice of compression and index format.
+//!
+//! This is a quick guide to one machine's performance; the criterion benchmarks
+//! under `benches/` are better for comparing changes to Conserve itself.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::blockdir::Address;
+use crate::compress::{compress_block, decompress_block};
+use crate::monitor::{check_cancel, Monitor};
+use crate::stats::{mb_string, ratio, StatsJson};
+use crate::*;
+
+/// Options for [bench].
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    /// Size of each sample block.
+    pub block_size: usize,
+    /// Number of sample blocks to hash, compress, write, and read.
+    pub blocks: usize,
+    /// Compression settings to measure.
+    pub compressions: Vec<Compression>,
+    /// Number of index entries to write and read in each format.
+    pub index_entries: usize,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions {
+            block_size: 1 << 20,
+            blocks: 32,
+            compressions: vec![
+                Compression::Snappy,
+                Compression::Lz4,
+                Compression::Zstd { level: 1 },
+                Compression::Zstd { level: 3 },
+                Compression::Zstd { level: 9 },
+            ],
+            index_entries: 100_000,
+        }
+    }
+}
+
+/// The result of one measurement made by [bench].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BenchResult {
+    /// What was measured, like "compress zstd:3".
+    pub name: String,
+    /// Uncompressed bytes processed; for index benchmarks, the size of the
+    /// encoded entries before compression.
+    pub bytes: u64,
+    /// Number of index entries processed, for index benchmarks.
+    pub entries: Option<u64>,
+    /// Uncompressed size divided by compressed size, for compression benchmarks.
+    pub compression_ratio: Option<f64>,
+    pub elapsed: Duration,
+}
+
+impl BenchResult {
+    /// Uncompressed bytes processed per second.
+    pub fn bytes_per_second(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+/// All the measurements made by [bench], in the order they were made.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BenchReport {
+    pub results: Vec<BenchResult>,
+}
+
+impl StatsJson for BenchReport {}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            w,
+            "{:<24} {:>10} {:>14} {:>8}",
+            "benchmark", "MB/s", "entries/s", "ratio"
+        )?;
+        for result in &self.results {
+            let rate = result.bytes_per_second();
+            let entries = match result.entries {
+                Some(entries) => {
+                    ((entries as f64 / result.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)) as u64)
+                        .to_string()
+                }
+                None => String::new(),
+            };
+            let compression_ratio = match result.compression_ratio {
+                Some(r) => format!("{r:.2}"),
+                None => String::new(),
+            };
+            writeln!(
+                w,
+                "{:<24} {:>10} {:>14} {:>8}",
+                result.name,
+                mb_string(rate as u64),
+                entries,
+                compression_ratio
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Measure hashing, each compression setting, writing and reading blocks through
+/// a transport, and writing and reading indexes in each format.
+///
+/// Blocks and indexes are written into a new temporary subdirectory of the
+/// transport, which is deleted afterwards. The sample data is text-like, so it
+/// compresses about as well as source code or documents.
+///
+/// Reads from a local transport are likely to come from the operating system's
+/// cache, so they show the best case.
+pub fn bench(
+    transport: Arc<dyn Transport>,
+    options: &BenchOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<BenchReport> {
+    let blocks: Vec<Bytes> = (0..options.blocks)
+        .map(|i| Bytes::from(sample_data(i as u64, options.block_size)))
+        .collect();
+    let total_bytes: u64 = blocks.iter().map(|b| b.len() as u64).sum();
+    let mut report = BenchReport::default();
+    let mut measure_blocks =
+        |name: String, compression_ratio: Option<f64>, elapsed: Duration| -> Result<()> {
+            check_cancel(monitor.as_ref())?;
+            report.results.push(BenchResult {
+                name,
+                bytes: total_bytes,
+                entries: None,
+                compression_ratio,
+                elapsed,
+            });
+            Ok(())
+        };
+
+    let _task = monitor.start_task("Benchmark hashing".to_string());
+    let start = Instant::now();
+    let hashes: Vec<BlockHash> = blocks.iter().map(|b| BlockHash::hash_bytes(b)).collect();
+    measure_blocks("hash".to_owned(), None, start.elapsed())?;
+
+    for &compression in &options.compressions {
+        let _task = monitor.start_task(format!("Benchmark {compression}"));
+        let start = Instant::now();
+        let compressed = blocks
+            .iter()
+            .map(|b| compress_block(compression, None, b))
+            .collect::<Result<Vec<Bytes>>>()?;
+        let compress_elapsed = start.elapsed();
+        let compressed_bytes = compressed.iter().map(|c| c.len() as u64).sum();
+        let compression_ratio = Some(ratio(total_bytes, compressed_bytes));
+        measure_blocks(
+            format!("compress {compression}"),
+            compression_ratio,
+            compress_elapsed,
+        )?;
+        let start = Instant::now();
+        for c in &compressed {
+            decompress_block(c, None)?;
+        }
+        measure_blocks(
+            format!("decompress {compression}"),
+            compression_ratio,
+            start.elapsed(),
+        )?;
+    }
+
+    let scratch_name = scratch_dir_name();
+    transport.create_dir(&scratch_name)?;
+    let scratch = transport.sub_transport(&scratch_name);
+    let result = bench_transport(&scratch, &blocks, &hashes, options, &mut report, monitor);
+    let cleanup = transport.remove_dir_all(&scratch_name);
+    result?;
+    cleanup?;
+    Ok(report)
+}
+
+/// Measure writing and reading blocks and indexes in a scratch directory.
+fn bench_transport(
+    scratch: &Arc<dyn Transport>,
+    blocks: &[Bytes],
+    hashes: &[BlockHash],
+    options: &BenchOptions,
+    report: &mut BenchReport,
+    monitor: Arc<dyn Monitor>,
+) -> Result<()> {
+    let total_bytes: u64 = blocks.iter().map(|b| b.len() as u64).sum();
+
+    // Blocks are written uncompressed, so that this measures the transport and
+    // hashing, which is also done for every block written or read.
+    let _task = monitor.start_task("Benchmark block writes".to_string());
+    let block_dir = BlockDir::create(scratch.sub_transport("d"))?;
+    let mut stats = BackupStats::default();
+    let start = Instant::now();
+    for block in blocks {
+        block_dir.store_or_deduplicate(
+            block.clone(),
+            Compression::None,
+            None,
+            &mut stats,
+            monitor.clone(),
+        )?;
+    }
+    report.results.push(BenchResult {
+        name: "write blocks".to_owned(),
+        bytes: total_bytes,
+        entries: None,
+        compression_ratio: None,
+        elapsed: start.elapsed(),
+    });
+    check_cancel(monitor.as_ref())?;
+
+    let _task = monitor.start_task("Benchmark block reads".to_string());
+    let block_dir = BlockDir::open(scratch.sub_transport("d"));
+    let start = Instant::now();
+    for hash in hashes {
+        block_dir.read_block_uncached(hash, monitor.clone())?;
+    }
+    report.results.push(BenchResult {
+        name: "read blocks".to_owned(),
+        bytes: total_bytes,
+        entries: None,
+        compression_ratio: None,
+        elapsed: start.elapsed(),
+    });
+    check_cancel(monitor.as_ref())?;
+
+    let entries = sample_entries(options.index_entries, hashes);
+    for (format, format_name) in [(IndexFormat::Json, "json"), (IndexFormat::Binary, "binary")] {
+        let _task = monitor.start_task(format!("Benchmark {format_name} index"));
+        let index_transport = scratch.sub_transport(format_name);
+        index_transport.create_dir("")?;
+        let mut writer = IndexWriter::new(index_transport.clone()).with_format(format);
+        let start = Instant::now();
+        for hunk in entries.chunks(INDEX_HUNK_ENTRIES) {
+            for entry in hunk {
+                writer.push_entry(entry.clone());
+            }
+            writer.finish_hunk(monitor.clone())?;
+        }
+        writer.finish(monitor.clone())?;
+        let write_elapsed = start.elapsed();
+        check_cancel(monitor.as_ref())?;
+
+        let start = Instant::now();
+        // Errors reading hunks are reported to the monitor.
+        let mut hunks = IndexRead::open(index_transport)
+            .iter_hunks()
+            .with_monitor(monitor.clone());
+        hunks.by_ref().for_each(drop);
+        let read_elapsed = start.elapsed();
+        let encoded_bytes = hunks.stats.uncompressed_index_bytes;
+        for (direction, elapsed) in [("write", write_elapsed), ("read", read_elapsed)] {
+            report.results.push(BenchResult {
+                name: format!("{direction} {format_name} index"),
+                bytes: encoded_bytes,
+                entries: Some(entries.len() as u64),
+                compression_ratio: None,
+                elapsed,
+            });
+        }
+        check_cancel(monitor.as_ref())?;
+    }
+    Ok(())
+}
+
+/// Number of entries in each index hunk written by the benchmark.
+const INDEX_HUNK_ENTRIES: usize = 1000;
+
+/// A name for a scratch directory that won't collide with another run.
+fn scratch_dir_name() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!("conserve-bench-{}-{nanos}", std::process::id())
+}
+
+/// Make text-like data that's different for each seed.
+fn sample_data(seed: u64, len: usize) -> Vec<u8> {
+    const WORDS: &[&str] = &[
+        "the", "of", "and", "to", "in", "is", "for", "fn", "let", "mut", "self", "return", "match",
+        "Some", "None", "Ok", "Err", "result", "value", "index", "block", "file", "archive",
+        "backup", "struct", "impl", "pub", "use", "{", "}", "(", ")", ";", "=",
+    ];
+    let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    let mut data = Vec::with_capacity(len + 16);
+    while data.len() < len {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        if state % 16 == 0 {
+            // Some numbers, which don't repeat.
+            data.extend_from_slice((state >> 40).to_string().as_bytes());
+        } else {
+            data.extend_from_slice(WORDS[(state >> 32) as usize % WORDS.len()].as_bytes());
+        }
+        data.push(if state % 11 == 0 { b'\n' } else { b' ' });
+    }
+    data.truncate(len);
+    data
+}
+
+/// Make index entries like those of a source tree, referring to the sample blocks.
+fn sample_entries(count: usize, hashes: &[BlockHash]) -> Vec<IndexEntry> {
+    let owner = Owner {
+        user: Some("user".to_owned()),
+        group: Some("staff".to_owned()),
+        uid: None,
+        gid: None,
+    };
+    let mut entries: Vec<IndexEntry> = (0..count)
+        .map(|i| IndexEntry {
+            apath: format!("/src/module{:04}/file{i:06}.rs", i / 100).into(),
+            kind: Kind::File,
+            mtime: 1_700_000_000 + i as i64,
+            mtime_nanos: (i as u32).wrapping_mul(7919) % 1_000_000_000,
+            unix_mode: UnixMode::from(0o644),
+            owner: owner.clone(),
+            acls: Acls::default(),
+            capability: None,
+            addrs: hashes
+                .get(i % hashes.len().max(1))
+                .map(|hash| Address {
+                    hash: hash.clone(),
+                    start: (i % 100) as u64 * 4000,
+                    len: 4000,
+                })
+                .into_iter()
+                .collect(),
+            holes: Vec::new(),
+            target: None,
+            link_group: None,
+            device: None,
+        })
+        .collect();
+    entries.sort_by(|a, b| a.apath.cmp(&b.apath));
+    entries
+}
+
+#[cfg(test)]
+mod test {
+    use assert_fs::TempDir;
+
+    use super::*;
+    use crate::monitor::test::TestMonitor;
+    use crate::transport::local::LocalTransport;
+
+    #[test]
+    fn bench_reports_each_measurement_and_cleans_up() {
+        let temp = TempDir::new().unwrap();
+        let transport = Arc::new(LocalTransport::new(temp.path()));
+        let options = BenchOptions {
+            block_size: 10_000,
+            blocks: 3,
+            compressions: vec![Compression::Snappy, Compression::Zstd { level: 3 }],
+            index_entries: 2500,
+        };
+        let monitor = TestMonitor::arc();
+        let report = bench(transport, &options, monitor.clone()).unwrap();
+        monitor.assert_no_errors();
+        let names: Vec<&str> = report.results.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "hash",
+                "compress snappy",
+                "decompress snappy",
+                "compress zstd:3",
+                "decompress zstd:3",
+                "write blocks",
+                "read blocks",
+                "write json index",
+                "read json index",
+                "write binary index",
+                "read binary index",
+            ]
+        );
+        assert!(report.results[1].compression_ratio.unwrap() > 1.5);
+        assert_eq!(report.results[0].bytes, 30_000);
+        assert_eq!(report.results[7].entries, Some(2500));
+        assert!(report.results[9].bytes < report.results[7].bytes);
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 0);
+        assert!(report.to_string().starts_with("benchmark "));
+    }
+
+    #[test]
+    fn sample_data_differs_by_seed() {
+        let a = sample_data(0, 1000);
+        assert_eq!(a.len(), 1000);
+        assert_eq!(a, sample_data(0, 1000));
+        assert_ne!(a, sample_data(1, 1000));
+    }
+}
@@ -15,19 +15,22 @@
 
 use std::cell::RefCell;
 use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, Write};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::TcpListener;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use clap::builder::{styling, Styles};
 use clap::{Parser, Subcommand};
 use conserve::change::Change;
 use rayon::prelude::ParallelIterator;
-use time::UtcOffset;
+use serde::Serialize;
+use time::{OffsetDateTime, UtcOffset};
 #[allow(unused_imports)]
 use tracing::{debug, error, info, trace, warn, Level};
 
+use conserve::misc::{parse_date, parse_duration, parse_percentage, parse_size};
 use conserve::termui::{enable_tracing, TermUiMonitor, TraceTimeStyle};
 use conserve::*;
 
@@ -66,6 +69,33 @@ struct Args {
     #[arg(long, global = true)]
     log_json: Option<PathBuf>,
 
+    /// Print results as json on stdout: statistics as one object, and lists such as
+    /// versions, entries, or changes as one object per line. Progress and log
+    /// messages still go to stderr.
+    #[arg(long, short, global = true)]
+    json: bool,
+
+    /// Open archives read-only, so that nothing in them is changed, not even lock files or
+    /// validation markers.
+    #[arg(long, global = true)]
+    readonly: bool,
+
+    /// Use at most this many threads for parallel work, such as compressing, validating, or recompressing blocks.
+    #[arg(long, global = true)]
+    threads: Option<usize>,
+
+    /// Read or write at most this many blocks in the archive at once; by default, the number of threads.
+    #[arg(long, global = true)]
+    transport_concurrency: Option<usize>,
+
+    /// Hold at most this much block content in memory while it waits to be written, like "1GiB".
+    #[arg(long, global = true, value_parser = parse_size)]
+    max_buffer: Option<u64>,
+
+    /// Keep up to this much recently read block content in memory, like "1GiB"; by default 256MiB.
+    #[arg(long, global = true, value_parser = parse_size)]
+    block_cache: Option<u64>,
+
     /// Write metrics to this file: deprecated and ignored.
     #[arg(long, global = true, hide = true)]
     metrics_json: Option<PathBuf>,
@@ -77,8 +107,14 @@ enum Command {
     Backup {
         /// Path of an existing archive.
         archive: String,
-        /// Source directory to copy from.
-        source: PathBuf,
+        /// Source directories to copy from.
+        ///
+        /// If there is more than one, each is stored at the top of the backup under its own name.
+        #[arg(required_unless_present = "stdin_name")]
+        source: Vec<PathBuf>,
+        /// Instead of reading source directories, store the data read from stdin as a single file with this name, like "/dumps/db.sql".
+        #[arg(long, conflicts_with = "source")]
+        stdin_name: Option<Apath>,
         /// Write a list of changes to this file.
         #[arg(long)]
         changes_json: Option<PathBuf>,
@@ -96,6 +132,131 @@ enum Command {
         /// Show permissions, owner, and group in verbose output.
         #[arg(long, short = 'l')]
         long_listing: bool,
+        /// Compression for new blocks, like "zstd:9" or "none"; by default, the archive's setting.
+        #[arg(long)]
+        compression: Option<Compression>,
+        /// Compress small files with zstd using a dictionary trained from them, and stored in the archive.
+        #[arg(long)]
+        zstd_dictionary: bool,
+        /// Encoding for the index: "json" (the default), or "binary", which is smaller and faster but can't be read by older versions.
+        #[arg(long, value_enum, default_value_t = IndexFormat::Json)]
+        index_format: IndexFormat,
+        /// End each index hunk with a checksum, so damage is detected precisely; older versions can't read these backups.
+        #[arg(long)]
+        index_checksums: bool,
+        /// Don't record POSIX ACLs.
+        #[arg(long)]
+        no_acls: bool,
+        /// Keep a cache of stored files in this local file, to skip unchanged files more quickly next time.
+        #[arg(long)]
+        change_cache: Option<PathBuf>,
+        /// Back up directories containing a CACHEDIR.TAG file, which are skipped by default.
+        #[arg(long)]
+        include_cache_dirs: bool,
+        /// Skip any directory containing a file with this name, like ".nobackup".
+        #[arg(long)]
+        exclude_if_present: Vec<String>,
+        /// Skip files larger than this size, like "1GB" or "500MiB".
+        #[arg(long, value_parser = parse_size)]
+        exclude_larger_than: Option<u64>,
+        /// Only back up files modified within this time before now, like "90d" or "12h".
+        #[arg(long, value_parser = parse_duration, conflicts_with = "modified_since")]
+        modified_within: Option<std::time::Duration>,
+        /// Only back up files modified since this date, like "2024-01-31" or an RFC 3339 timestamp.
+        #[arg(long, value_parser = parse_date)]
+        modified_since: Option<OffsetDateTime>,
+        /// Don't descend into directories on other filesystems, such as /proc or network mounts.
+        #[arg(long, short = 'x')]
+        one_file_system: bool,
+        /// Store the targets of symlinks rather than the symlinks: never, only for the source directories themselves, or always.
+        #[arg(long, value_enum, default_value_t = FollowSymlinks::Never)]
+        follow_symlinks: FollowSymlinks,
+        /// Split large files into blocks at boundaries chosen from their content, so that insertions don't prevent deduplication of the rest of the file.
+        #[arg(long)]
+        content_defined_chunking: bool,
+        /// On Windows, back up from a Volume Shadow Copy, so that files locked by other programs are read consistently. Needs Administrator privileges.
+        #[arg(long)]
+        use_vss: bool,
+        /// Back up from a snapshot of each source filesystem, deleted afterwards: "btrfs", "zfs", "lvm", or "lvm:SIZE".
+        #[arg(long, conflicts_with = "use_vss")]
+        snapshot: Option<SnapshotMethod>,
+        /// A short name for this backup, which can later be used to select it with `--label`.
+        #[arg(long)]
+        label: Option<String>,
+        /// A description of this backup, shown by `conserve versions`.
+        #[arg(long, short)]
+        message: Option<String>,
+        /// Let `conserve prune` delete this backup once this much time has passed, like "90d",
+        /// regardless of the retention policy given to prune.
+        #[arg(long, value_parser = parse_duration)]
+        expire_after: Option<std::time::Duration>,
+        /// Read source files no faster than this many bytes per second, like "20MB".
+        #[arg(long, value_parser = parse_size)]
+        max_read_rate: Option<u64>,
+        /// Lower the scheduling priority of the backup by this niceness increment, on Unix.
+        #[arg(long, value_parser = clap::value_parser!(i32).range(1..=19))]
+        nice: Option<i32>,
+    },
+
+    /// Measure how fast this machine hashes and compresses data, and how fast blocks
+    /// and indexes can be written to and read from a directory or URL, to help choose
+    /// settings.
+    Bench {
+        /// Path or URL of a directory in which to write scratch files, which are deleted
+        /// afterwards; by default, the system temporary directory.
+        location: Option<String>,
+        /// Measure this compression setting, like "zstd:9"; may be repeated. By default,
+        /// several common settings are measured.
+        #[arg(long)]
+        compression: Vec<Compression>,
+        /// Size of each sample block, like "1MiB".
+        #[arg(long, value_parser = parse_size, default_value = "1MiB")]
+        block_size: u64,
+        /// Number of sample blocks.
+        #[arg(long, default_value_t = 32)]
+        blocks: usize,
+        /// Number of index entries to write and read in each format.
+        #[arg(long, default_value_t = 100_000)]
+        index_entries: usize,
+    },
+
+    /// Write the content of one stored file to stdout.
+    Cat {
+        /// Path or URL of an existing archive.
+        archive: String,
+        /// The apath of the file, like "/etc/hosts".
+        apath: Apath,
+        /// Select the version from the archive: by default, the latest.
+        #[arg(long, short)]
+        backup: Option<BandId>,
+        /// Select the latest version with this label.
+        #[arg(long, conflicts_with = "backup")]
+        label: Option<String>,
+        /// Select the latest complete version started at or before this time, like "2024-06-01" or an RFC 3339 timestamp.
+        #[arg(long, value_parser = parse_date, conflicts_with_all = ["backup", "label"])]
+        before: Option<OffsetDateTime>,
+    },
+
+    /// Gather small blocks into packs, so there are fewer files in the archive.
+    ///
+    /// Archives with packed blocks can't be read by older versions of Conserve.
+    Compact {
+        /// Path of an existing archive.
+        archive: String,
+        /// Pack blocks smaller than this, like "1MiB".
+        #[arg(long, value_parser = parse_size, default_value = "1MiB")]
+        max_block_size: u64,
+        /// Make packs of up to this size, like "16MiB".
+        #[arg(long, value_parser = parse_size, default_value = "16MiB")]
+        pack_size: u64,
+        /// Report what would be packed, without changing the archive.
+        #[arg(long)]
+        dry_run: bool,
+        /// Break a lock left behind by a previous interrupted gc or compact operation.
+        #[arg(long)]
+        break_lock: bool,
+        #[arg(long)]
+        no_stats: bool,
     },
 
     #[command(subcommand)]
@@ -127,22 +288,160 @@ enum Command {
         /// Select the version from the archive to compare: by default, the latest.
         #[arg(long, short)]
         backup: Option<BandId>,
+        /// Select the latest version with this label.
+        #[arg(long, conflicts_with = "backup")]
+        label: Option<String>,
+        /// Select the latest complete version started at or before this time, like "2024-06-01" or an RFC 3339 timestamp.
+        #[arg(long, value_parser = parse_date, conflicts_with_all = ["backup", "label"])]
+        before: Option<OffsetDateTime>,
         #[arg(long, short)]
         exclude: Vec<String>,
         #[arg(long, short = 'E')]
         exclude_from: Vec<String>,
         #[arg(long)]
         include_unchanged: bool,
+    },
 
-        /// Print the diff as json.
+    /// Show how much space each directory of a stored tree takes, both the apparent
+    /// size of its files and the size of the distinct blocks holding them.
+    Du {
+        /// Path or URL of an existing archive.
+        archive: String,
+        /// Select the version from the archive: by default, the latest.
         #[arg(long, short)]
-        json: bool,
+        backup: Option<BandId>,
+        /// Select the latest version with this label.
+        #[arg(long, conflicts_with = "backup")]
+        label: Option<String>,
+        /// Select the latest complete version started at or before this time, like "2024-06-01" or an RFC 3339 timestamp.
+        #[arg(long, value_parser = parse_date, conflicts_with_all = ["backup", "label"])]
+        before: Option<OffsetDateTime>,
+        /// Only show directories at most this many levels below the root.
+        #[arg(long, short)]
+        depth: Option<usize>,
+        /// Count in bytes, not megabytes.
+        #[arg(long)]
+        bytes: bool,
+        #[arg(long, short)]
+        exclude: Vec<String>,
+        #[arg(long, short = 'E')]
+        exclude_from: Vec<String>,
+    },
+
+    /// Write a stored tree as a tar stream, to stdout or a file.
+    ExportTar {
+        /// Path or URL of an existing archive.
+        archive: String,
+        /// Select the version from the archive: by default, the latest.
+        #[arg(long, short)]
+        backup: Option<BandId>,
+        /// Select the latest version with this label.
+        #[arg(long, conflicts_with = "backup")]
+        label: Option<String>,
+        /// Select the latest complete version started at or before this time, like "2024-06-01" or an RFC 3339 timestamp.
+        #[arg(long, value_parser = parse_date, conflicts_with_all = ["backup", "label"])]
+        before: Option<OffsetDateTime>,
+        /// Write the tar stream to this file, rather than stdout.
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+        /// Compress the stream with zstd, optionally at this level, making a .tar.zst.
+        #[arg(long, value_name = "LEVEL", num_args = 0..=1, default_missing_value = "3")]
+        zstd: Option<i32>,
+        #[arg(long, short)]
+        exclude: Vec<String>,
+        #[arg(long, short = 'E')]
+        exclude_from: Vec<String>,
+    },
+
+    /// Find every stored version of files whose path matches a pattern, in all backups.
+    Find {
+        /// Path or URL of an existing archive.
+        archive: String,
+        /// Glob pattern matching apaths, like "*.txt" or "/src/*.rs", or a regex with --regex.
+        pattern: String,
+        /// Interpret the pattern as a regular expression matched against the apath.
+        #[arg(long)]
+        regex: bool,
+        /// Show only versions that were added or changed since the previous backup.
+        #[arg(long)]
+        changes: bool,
+        /// Show times in UTC.
+        #[arg(long)]
+        utc: bool,
+    },
+
+    /// Show each distinct stored version of one file, from the oldest backup to the newest.
+    History {
+        /// Path or URL of an existing archive.
+        archive: String,
+        /// The apath of the file, like "/etc/hosts".
+        apath: Apath,
+        /// Show the file in every backup that holds it, not only the versions that changed.
+        #[arg(long)]
+        all: bool,
+        /// Show times in UTC.
+        #[arg(long)]
+        utc: bool,
+    },
+
+    /// Hold backups so that they're not deleted by `prune` or `delete`, or release the hold.
+    Hold {
+        /// Path of an existing archive.
+        archive: String,
+        /// Backup to hold, as an id like 'b1'. May be repeated with commas.
+        #[arg(long, short, value_delimiter = ',', required(true))]
+        backup: Vec<BandId>,
+        /// Release the hold, so that the backups can be deleted again.
+        #[arg(long)]
+        release: bool,
+    },
+
+    /// Store a tar stream, optionally compressed with zstd, as a new backup.
+    ImportTar {
+        /// Path or URL of an existing archive.
+        archive: String,
+        /// Read the tar stream from this file, rather than stdin.
+        #[arg(long, short)]
+        input: Option<PathBuf>,
+        /// Write a list of changes to this file.
+        #[arg(long)]
+        changes_json: Option<PathBuf>,
+        /// Print imported file names.
+        #[arg(long, short)]
+        verbose: bool,
+        #[arg(long, short)]
+        exclude: Vec<String>,
+        /// Read a list of globs to exclude from this file.
+        #[arg(long, short = 'E')]
+        exclude_from: Vec<String>,
+        /// Don't print statistics after the import completes.
+        #[arg(long)]
+        no_stats: bool,
+        /// Compression for new blocks, like "zstd:9" or "none"; by default, the archive's setting.
+        #[arg(long)]
+        compression: Option<Compression>,
+        /// A short name for this backup, which can later be used to select it with `--label`.
+        #[arg(long)]
+        label: Option<String>,
+        /// A description of this backup, shown by `conserve versions`.
+        #[arg(long, short)]
+        message: Option<String>,
+    },
+
+    /// Summarize an archive: its format, how many backups it holds and when they were made,
+    /// and how much space its blocks take.
+    Info {
+        /// Path or URL of an existing archive.
+        archive: String,
     },
 
     /// Create a new archive.
     Init {
         /// Path for new archive.
         archive: String,
+        /// Default compression for new blocks: "snappy" (the default), "zstd", "zstd:LEVEL", "lz4", or "none".
+        #[arg(long)]
+        compression: Option<Compression>,
     },
 
     /// Delete blocks unreferenced by any index.
@@ -155,6 +454,10 @@ enum Command {
         /// Break a lock left behind by a previous interrupted gc operation, and then gc.
         #[arg(long)]
         break_lock: bool,
+        /// Keep unreferenced blocks written more recently than this, like "1h" or "2d",
+        /// in case they belong to a backup that's still running.
+        #[arg(long, value_parser = parse_duration)]
+        min_age: Option<std::time::Duration>,
         #[arg(long)]
         no_stats: bool,
     },
@@ -170,13 +473,97 @@ enum Command {
         #[arg(long, short = 'E')]
         exclude_from: Vec<String>,
 
-        /// Print entries as json.
-        #[arg(long, short)]
-        json: bool,
-
         /// Show permissions, owner, and group.
         #[arg(short = 'l')]
         long_listing: bool,
+
+        /// Like `ls -l`, show the kind, permissions, owner, group, size, and mtime of
+        /// each entry, and the target of symlinks.
+        #[arg(long, conflicts_with_all = ["json", "long_listing"])]
+        long: bool,
+
+        /// Show times in UTC.
+        #[arg(long)]
+        utc: bool,
+
+        /// Only list entries of these kinds, separated by commas.
+        #[arg(long, value_delimiter = ',')]
+        kind: Vec<Kind>,
+    },
+
+    /// Delete old backups, keeping the last few, or the newest from each recent day, week, and month.
+    ///
+    /// The newest complete backup is always kept. Blocks no longer referenced by any
+    /// kept backup are then deleted.
+    Prune {
+        /// Archive to prune.
+        archive: String,
+        /// Keep this many of the newest complete backups, not counting damaged backups.
+        #[arg(long, default_value_t = 0)]
+        keep_last: usize,
+        /// Keep the newest backup from each of this many days that have backups.
+        #[arg(
+            long,
+            default_value_t = 0,
+            required_unless_present_any = ["keep_last", "keep_weekly", "keep_monthly", "delete_older_than"]
+        )]
+        keep_daily: usize,
+        /// Keep the newest backup from each of this many ISO weeks that have backups.
+        #[arg(long, default_value_t = 0)]
+        keep_weekly: usize,
+        /// Keep the newest backup from each of this many months that have backups.
+        #[arg(long, default_value_t = 0)]
+        keep_monthly: usize,
+        /// Delete backups started longer ago than this, like "180d", unless kept by another rule.
+        #[arg(long, value_parser = parse_duration)]
+        delete_older_than: Option<std::time::Duration>,
+        /// Count days, weeks, and months in UTC rather than the local timezone.
+        #[arg(long)]
+        utc: bool,
+        /// Don't actually delete, just check what could be deleted.
+        #[arg(long)]
+        dry_run: bool,
+        /// Break a lock left behind by a previous interrupted gc operation, and then gc.
+        #[arg(long)]
+        break_lock: bool,
+        #[arg(long)]
+        no_stats: bool,
+    },
+
+    /// Rewrite all blocks in an archive with a different compression.
+    Recompress {
+        /// Path of an existing archive.
+        archive: String,
+        /// New compression for all blocks, like "zstd:9", "lz4", "snappy", or "none".
+        #[arg(long)]
+        compression: Compression,
+        /// Break a lock left behind by a previous interrupted gc or recompress operation.
+        #[arg(long)]
+        break_lock: bool,
+        #[arg(long)]
+        no_stats: bool,
+    },
+
+    /// Repair damaged bands, so that what remains of them can be read.
+    Repair {
+        /// Path of an existing archive.
+        archive: String,
+        /// Report what would be repaired, without changing the archive.
+        #[arg(long)]
+        dry_run: bool,
+        #[arg(long)]
+        no_stats: bool,
+    },
+
+    /// Copy the backups in one archive that aren't yet in another, such as an off-site
+    /// copy, along with only the blocks that it's missing.
+    Replicate {
+        /// Path or URL of the archive to copy from.
+        source: String,
+        /// Path or URL of an existing archive to copy into.
+        dest: String,
+        #[arg(long)]
+        no_stats: bool,
     },
 
     /// Copy a stored tree to a restore directory.
@@ -185,24 +572,96 @@ enum Command {
         destination: PathBuf,
         #[arg(long, short)]
         backup: Option<BandId>,
+        /// Restore the latest version with this label.
+        #[arg(long, conflicts_with = "backup")]
+        label: Option<String>,
+        /// Select the latest complete version started at or before this time, like "2024-06-01" or an RFC 3339 timestamp.
+        #[arg(long, value_parser = parse_date, conflicts_with_all = ["backup", "label"])]
+        before: Option<OffsetDateTime>,
         /// Write a list of restored files to this json file.
         #[arg(long)]
         changes_json: Option<PathBuf>,
-        #[arg(long, short)]
+        /// Replace anything that already exists in the destination; the same as `--overwrite=always`.
+        #[arg(long, short, conflicts_with = "overwrite")]
         force_overwrite: bool,
+        /// What to do about entries that already exist in the destination.
+        #[arg(long, value_enum, default_value_t = Overwrite::Fail)]
+        overwrite: Overwrite,
         #[arg(long, short)]
         verbose: bool,
         #[arg(long, short)]
         exclude: Vec<String>,
         #[arg(long, short = 'E')]
         exclude_from: Vec<String>,
-        #[arg(long = "only", short = 'i')]
-        only_subtree: Option<Apath>,
+        /// Restore only these apaths or globs, like "/home/**/*.rs", and everything inside them.
+        #[arg(long, short = 'i')]
+        only: Vec<String>,
         #[arg(long)]
         no_stats: bool,
         /// Show permissions, owner, and group in verbose output.
         #[arg(long, short = 'l')]
         long_listing: bool,
+        /// Don't restore POSIX ACLs.
+        #[arg(long)]
+        no_acls: bool,
+        /// Set owners by their recorded numeric uid and gid, rather than by user and group name.
+        #[arg(long)]
+        numeric_ids: bool,
+        /// Restore files owned by a recorded user as another, like "1000:2000" or "alice:bob".
+        #[arg(long, value_name = "FROM:TO")]
+        map_user: Vec<String>,
+        /// Restore files owned by a recorded group as another, like "1000:2000" or "staff:users".
+        #[arg(long, value_name = "FROM:TO")]
+        map_group: Vec<String>,
+        /// Don't set owners, so that all restored files are owned by the current user.
+        /// This is the default when not running as root.
+        #[arg(long, conflicts_with_all = ["map_user", "map_group", "numeric_ids"])]
+        restore_as_current_user: bool,
+        /// Leave holes for long runs of zeros, even in files that weren't recorded as sparse.
+        #[arg(long)]
+        sparse: bool,
+        /// Read back each restored file and report any that don't match the backup.
+        #[arg(long)]
+        verify: bool,
+        /// Keep existing files that already match the backup, checking their size and mtime, or
+        /// with "content", their content. Other existing files are overwritten.
+        #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "metadata")]
+        skip_unchanged: Option<UnchangedCheck>,
+        /// On Linux, clone or copy file content within the filesystem, without reading it,
+        /// from unchanged original files or from uncompressed blocks in a local archive.
+        /// This content isn't checked against the backup unless --verify is also given.
+        #[arg(long)]
+        zero_copy: bool,
+    },
+
+    /// Write every file that can still be read from a damaged archive into an empty directory.
+    Salvage {
+        /// Path or URL of the damaged archive.
+        archive: String,
+        /// Empty directory for the recovered files, and a manifest of what was and wasn't recovered.
+        destination: PathBuf,
+        #[arg(long)]
+        no_stats: bool,
+    },
+
+    /// Serve a read-only web interface for browsing versions and downloading files.
+    ///
+    /// Directories can be downloaded as tar files.
+    ///
+    /// The server has no authentication, so by default it listens only on localhost.
+    Serve {
+        /// Path or URL of an existing archive.
+        archive: String,
+        /// Listen for HTTP connections on this address and port.
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        http: String,
+    },
+
+    /// Show how much content the archive holds, how well it's deduplicated, and how
+    /// many new blocks each backup added.
+    Stats {
+        /// Path or URL of an existing archive.
+        archive: String,
     },
 
     /// Show the total size of files in a stored tree or source directory, with exclusions.
@@ -211,7 +670,7 @@ enum Command {
         stos: StoredTreeOrSource,
 
         /// Count in bytes, not megabytes.
-        #[arg(long)]
+        #[arg(long, conflicts_with = "json")]
         bytes: bool,
 
         #[arg(long, short)]
@@ -225,18 +684,116 @@ enum Command {
         /// Path of the archive to check.
         archive: String,
 
-        /// Skip reading and checking the content of data blocks.
+        /// Only check the structure of the archive, and that every referenced block file
+        /// exists with a plausible size, without reading the content of data blocks.
         #[arg(long, short = 'q')]
         quick: bool,
+
+        /// Only check this version, and the blocks it references.
+        #[arg(long, short, conflicts_with = "incremental")]
+        backup: Option<BandId>,
+
+        /// Only check bands and blocks added since they were last validated.
+        #[arg(long)]
+        incremental: bool,
+
+        /// Only read and check the content of a random sample of blocks, such as `5%`.
+        #[arg(long, value_parser = parse_percentage, conflicts_with = "quick")]
+        sample: Option<f64>,
+
+        /// Seed choosing which blocks are sampled: the same seed checks the same blocks.
+        /// By default a new seed is chosen each time.
+        #[arg(long, requires = "sample")]
+        sample_seed: Option<u64>,
         #[arg(long)]
         no_stats: bool,
     },
 
+    /// Check that a source directory still matches a stored tree, including the content
+    /// of files, without restoring it.
+    ///
+    /// Added, removed, and changed entries are shown as by `diff`, and files whose content
+    /// differs although their size is unchanged are shown with `!`.
+    Verify {
+        /// Path or URL of an existing archive.
+        archive: String,
+        /// Source directory to check.
+        source: PathBuf,
+        /// Select the version from the archive to compare: by default, the latest.
+        #[arg(long, short)]
+        backup: Option<BandId>,
+        /// Select the latest version with this label.
+        #[arg(long, conflicts_with = "backup")]
+        label: Option<String>,
+        /// Select the latest complete version started at or before this time, like "2024-06-01" or an RFC 3339 timestamp.
+        #[arg(long, value_parser = parse_date, conflicts_with_all = ["backup", "label"])]
+        before: Option<OffsetDateTime>,
+        #[arg(long, short)]
+        exclude: Vec<String>,
+        #[arg(long, short = 'E')]
+        exclude_from: Vec<String>,
+        #[arg(long)]
+        no_stats: bool,
+    },
+
+    /// Make backups repeatedly at an interval, until interrupted, optionally pruning
+    /// old backups after each one.
+    Watch {
+        /// Path of an existing archive.
+        archive: String,
+        /// Source directories to copy from.
+        ///
+        /// If there is more than one, each is stored at the top of the backup under its own name.
+        #[arg(required = true)]
+        source: Vec<PathBuf>,
+        /// Time from the start of one backup to the start of the next, like "1h" or "30m".
+        #[arg(long, value_parser = parse_duration)]
+        interval: std::time::Duration,
+        /// Wait up to this much longer, chosen at random, before each backup after the first; by default, a tenth of the interval.
+        #[arg(long, value_parser = parse_duration)]
+        jitter: Option<std::time::Duration>,
+        /// Stop after this many backups.
+        #[arg(long)]
+        runs: Option<usize>,
+        #[arg(long, short)]
+        exclude: Vec<String>,
+        /// Read a list of globs to exclude from this file.
+        #[arg(long, short = 'E')]
+        exclude_from: Vec<String>,
+        /// Compression for new blocks, like "zstd:9" or "none"; by default, the archive's setting.
+        #[arg(long)]
+        compression: Option<Compression>,
+        /// Keep a cache of stored files in this local file, to skip unchanged files more quickly next time.
+        #[arg(long)]
+        change_cache: Option<PathBuf>,
+        /// A short name for each backup, which can later be used to select it with `--label`.
+        #[arg(long)]
+        label: Option<String>,
+        /// After each backup, prune to keep this many of the newest complete backups.
+        #[arg(long, default_value_t = 0)]
+        keep_last: usize,
+        /// After each backup, prune to keep the newest backup from each of this many days.
+        #[arg(long, default_value_t = 0)]
+        keep_daily: usize,
+        /// After each backup, prune to keep the newest backup from each of this many ISO weeks.
+        #[arg(long, default_value_t = 0)]
+        keep_weekly: usize,
+        /// After each backup, prune to keep the newest backup from each of this many months.
+        #[arg(long, default_value_t = 0)]
+        keep_monthly: usize,
+        /// After each backup, delete backups started longer ago than this, unless kept by another rule.
+        #[arg(long, value_parser = parse_duration)]
+        delete_older_than: Option<std::time::Duration>,
+        /// Count days, weeks, and months in UTC rather than the local timezone.
+        #[arg(long)]
+        utc: bool,
+    },
+
     /// List backup versions in an archive.
     Versions {
         archive: String,
         /// Show only version names.
-        #[arg(long, short = 'q')]
+        #[arg(long, short = 'q', conflicts_with = "json")]
         short: bool,
         /// Sort bands to show most recent first.
         #[arg(long, short = 'n')]
@@ -247,6 +804,9 @@ enum Command {
         /// Show times in UTC.
         #[arg(long)]
         utc: bool,
+        /// Show the user, host, Conserve version, and source directories that made each version.
+        #[arg(long, short, conflicts_with = "short")]
+        verbose: bool,
     },
 }
 
@@ -266,6 +826,14 @@ struct StoredTreeOrSource {
 
     #[arg(long, short, conflicts_with = "source")]
     backup: Option<BandId>,
+
+    /// Select the latest version with this label.
+    #[arg(long, conflicts_with_all = ["source", "backup"])]
+    label: Option<String>,
+
+    /// Select the latest complete version started at or before this time, like "2024-06-01" or an RFC 3339 timestamp.
+    #[arg(long, value_parser = parse_date, conflicts_with_all = ["source", "backup", "label"])]
+    before: Option<OffsetDateTime>,
 }
 
 /// Show debugging information.
@@ -291,10 +859,29 @@ enum Debug {
     Unreferenced { archive: String },
 }
 
+/// Process exit codes, which scripts can rely on.
 enum ExitCode {
     Success = 0,
     Failure = 1,
+    /// The command completed, but with problems such as unreadable source files.
     NonFatalErrors = 2,
+    /// The archive is damaged.
+    ArchiveDamaged = 3,
+    /// The archive is locked, or in use by another process.
+    ArchiveBusy = 4,
+    /// `verify` found that the source differs from the backup.
+    Differences = 5,
+}
+
+impl ExitCode {
+    /// The exit code for a command that failed with an error.
+    fn for_error(err: &Error) -> ExitCode {
+        match err.class() {
+            ErrorClass::ArchiveDamaged => ExitCode::ArchiveDamaged,
+            ErrorClass::ArchiveBusy => ExitCode::ArchiveBusy,
+            _ => ExitCode::Failure,
+        }
+    }
 }
 
 impl std::process::Termination for ExitCode {
@@ -304,41 +891,149 @@ impl std::process::Termination for ExitCode {
 }
 
 impl Command {
-    fn run(&self, monitor: Arc<TermUiMonitor>) -> Result<ExitCode> {
+    fn run(
+        &self,
+        readonly: bool,
+        block_cache: Option<u64>,
+        json: bool,
+        parallelism: Parallelism,
+        monitor: Arc<TermUiMonitor>,
+    ) -> Result<ExitCode> {
         let mut stdout = std::io::stdout();
         match self {
             Command::Backup {
                 archive,
+                change_cache,
                 changes_json,
+                compression,
+                content_defined_chunking,
                 exclude,
                 exclude_from,
+                exclude_if_present,
+                exclude_larger_than,
+                expire_after,
+                follow_symlinks,
+                include_cache_dirs,
+                index_format,
+                index_checksums,
+                label,
                 long_listing,
+                max_read_rate,
+                modified_since,
+                modified_within,
+                message,
+                nice,
+                no_acls,
                 no_stats,
+                one_file_system,
+                snapshot,
                 source,
+                stdin_name,
+                use_vss,
                 verbose,
+                zstd_dictionary,
             } => {
-                let options = BackupOptions {
-                    exclude: Exclude::from_patterns_and_files(exclude, exclude_from)?,
-                    change_callback: make_change_callback(
+                let options = BackupOptions::default()
+                    .with_exclude(
+                        Exclude::from_patterns_and_files(exclude, exclude_from)?
+                            .with_skip_cachedirs(!include_cache_dirs)
+                            .with_exclude_if_present(exclude_if_present.iter().cloned())
+                            .with_exclude_larger_than(*exclude_larger_than)
+                            .with_exclude_modified_before(
+                                modified_within
+                                    .map(|within| OffsetDateTime::now_utc() - within)
+                                    .or(*modified_since),
+                            )
+                            .with_one_file_system(*one_file_system),
+                    )
+                    .with_change_callback(make_change_callback(
                         *verbose,
                         *long_listing,
+                        json,
                         &changes_json.as_deref(),
-                    )?,
-                    ..Default::default()
+                    )?)
+                    .with_compression(*compression)
+                    .with_zstd_dictionary(*zstd_dictionary)
+                    .with_index_format(*index_format)
+                    .with_index_checksums(*index_checksums)
+                    .with_acls(!*no_acls)
+                    .with_change_cache(change_cache.clone())
+                    .with_follow_symlinks(*follow_symlinks)
+                    .with_label(label.clone())
+                    .with_message(message.clone())
+                    .with_expire_after(*expire_after)
+                    .with_content_defined_chunking(*content_defined_chunking)
+                    .with_use_vss(*use_vss)
+                    .with_snapshot(snapshot.clone())
+                    .with_max_read_rate(*max_read_rate)
+                    .with_parallelism(parallelism);
+                if let Some(nice) = nice {
+                    throttle::lower_priority(*nice)?;
+                }
+                let archive = open_archive(archive, readonly, block_cache)?;
+                let stats = if let Some(stdin_name) = stdin_name {
+                    backup_stream(
+                        &archive,
+                        stdin_name,
+                        &mut std::io::stdin().lock(),
+                        &options,
+                        monitor.clone(),
+                    )?
+                } else if let [source] = source.as_slice() {
+                    backup(&archive, source, &options, monitor.clone())?
+                } else {
+                    backup_sources(&archive, source, &options, monitor.clone())?
                 };
-                let stats = backup(
-                    &Archive::open(open_transport(archive)?)?,
-                    source,
-                    &options,
-                    monitor,
-                )?;
-                if !no_stats {
+                if json {
+                    print_json(&monitor, &Tagged::stats(&stats))?;
+                } else if !no_stats {
                     info!("Backup complete.\n{stats}");
                 }
             }
+            Command::Bench {
+                location,
+                compression,
+                block_size,
+                blocks,
+                index_entries,
+            } => {
+                let transport = match location {
+                    Some(location) => open_transport(location)?,
+                    None => open_transport(&std::env::temp_dir().to_string_lossy())?,
+                };
+                let mut options = BenchOptions {
+                    block_size: *block_size as usize,
+                    blocks: *blocks,
+                    index_entries: *index_entries,
+                    ..Default::default()
+                };
+                if !compression.is_empty() {
+                    options.compressions.clone_from(compression);
+                }
+                let report = bench(transport, &options, monitor.clone())?;
+                if json {
+                    print_json(&monitor, &report)?;
+                } else {
+                    monitor.clear_progress_bars();
+                    print!("{report}");
+                }
+            }
+            Command::Cat {
+                archive,
+                apath,
+                backup,
+                label,
+                before,
+            } => {
+                let st =
+                    stored_tree_from_opt(archive, backup, label, before, readonly, block_cache)?;
+                let mut file = st.open_file(apath, monitor.clone())?;
+                monitor.clear_progress_bars();
+                std::io::copy(&mut file, &mut stdout.lock())?;
+            }
             Command::Debug(Debug::Blocks { archive }) => {
                 let mut bw = BufWriter::new(stdout);
-                for hash in Archive::open(open_transport(archive)?)?
+                for hash in open_archive(archive, readonly, block_cache)?
                     .block_dir()
                     .blocks(monitor)?
                     .collect::<Vec<BlockHash>>()
@@ -348,12 +1043,13 @@ impl Command {
                 }
             }
             Command::Debug(Debug::Index { archive, backup }) => {
-                let st = stored_tree_from_opt(archive, backup)?;
+                let st =
+                    stored_tree_from_opt(archive, backup, &None, &None, readonly, block_cache)?;
                 show::show_index_json(st.band(), &mut stdout)?;
             }
             Command::Debug(Debug::Referenced { archive }) => {
                 let mut bw = BufWriter::new(stdout);
-                let archive = Archive::open(open_transport(archive)?)?;
+                let archive = open_archive(archive, readonly, block_cache)?;
                 for hash in archive.referenced_blocks(&archive.list_band_ids()?, monitor)? {
                     writeln!(bw, "{hash}")?;
                 }
@@ -361,13 +1057,38 @@ impl Command {
             Command::Debug(Debug::Unreferenced { archive }) => {
                 print!(
                     "{}",
-                    Archive::open(open_transport(archive)?)?
+                    open_archive(archive, readonly, block_cache)?
                         .unreferenced_blocks(monitor)?
                         .map(|hash| format!("{}\n", hash))
                         .collect::<Vec<String>>()
                         .join("")
                 );
             }
+            Command::Compact {
+                archive,
+                max_block_size,
+                pack_size,
+                dry_run,
+                break_lock,
+                no_stats,
+            } => {
+                let archive = open_archive(archive, readonly, block_cache)?;
+                let stats = compact(
+                    &archive,
+                    &CompactOptions {
+                        max_block_size: *max_block_size,
+                        pack_size: *pack_size,
+                        dry_run: *dry_run,
+                        break_lock: *break_lock,
+                    },
+                    monitor.clone(),
+                )?;
+                if json {
+                    print_json(&monitor, &Tagged::stats(&stats))?;
+                } else if !no_stats {
+                    info!(%stats);
+                }
+            }
             Command::Delete {
                 archive,
                 backup,
@@ -375,15 +1096,18 @@ impl Command {
                 break_lock,
                 no_stats,
             } => {
-                let stats = Archive::open(open_transport(archive)?)?.delete_bands(
+                let stats = open_archive(archive, readonly, block_cache)?.delete_bands(
                     backup,
                     &DeleteOptions {
                         dry_run: *dry_run,
                         break_lock: *break_lock,
+                        ..Default::default()
                     },
                     monitor.clone(),
                 )?;
-                if !no_stats {
+                if json {
+                    print_json(&monitor, &Tagged::stats(&stats))?;
+                } else if !no_stats {
                     monitor.clear_progress_bars();
                     println!("{stats}");
                 }
@@ -392,12 +1116,14 @@ impl Command {
                 archive,
                 source,
                 backup,
+                label,
+                before,
                 exclude,
                 exclude_from,
                 include_unchanged,
-                json,
             } => {
-                let st = stored_tree_from_opt(archive, backup)?;
+                let st =
+                    stored_tree_from_opt(archive, backup, label, before, readonly, block_cache)?;
                 let lt = LiveTree::open(source)?;
                 let options = DiffOptions {
                     exclude: Exclude::from_patterns_and_files(exclude, exclude_from)?,
@@ -405,51 +1131,216 @@ impl Command {
                 };
                 let mut bw = BufWriter::new(stdout);
                 for change in diff(&st, &lt, &options, monitor.clone())? {
-                    if *json {
+                    if json {
                         serde_json::to_writer(&mut bw, &change)?;
+                        writeln!(bw)?;
                     } else {
                         writeln!(bw, "{change}")?;
                     }
                 }
             }
+            Command::ExportTar {
+                archive,
+                backup,
+                label,
+                before,
+                output,
+                zstd,
+                exclude,
+                exclude_from,
+            } => {
+                let st =
+                    stored_tree_from_opt(archive, backup, label, before, readonly, block_cache)?;
+                let options = ExportTarOptions {
+                    exclude: Exclude::from_patterns_and_files(exclude, exclude_from)?,
+                    zstd_level: *zstd,
+                    ..Default::default()
+                };
+                if let Some(output) = output {
+                    let mut out = BufWriter::new(File::create(output)?);
+                    export_tar(&st, &mut out, &options, monitor.clone())?;
+                    out.flush()?;
+                } else {
+                    let mut out = BufWriter::new(stdout.lock());
+                    export_tar(&st, &mut out, &options, monitor.clone())?;
+                }
+            }
+            Command::Du {
+                archive,
+                backup,
+                label,
+                before,
+                depth,
+                bytes,
+                exclude,
+                exclude_from,
+            } => {
+                let stored_tree =
+                    stored_tree_from_opt(archive, backup, label, before, readonly, block_cache)?;
+                let options = DiskUsageOptions {
+                    exclude: Exclude::from_patterns_and_files(exclude, exclude_from)?,
+                    max_depth: *depth,
+                };
+                let usage = disk_usage(&stored_tree, &options, monitor.clone())?;
+                monitor.clear_progress_bars();
+                if json {
+                    let mut bw = BufWriter::new(std::io::stdout());
+                    for dir in &usage {
+                        serde_json::to_writer(&mut bw, dir)?;
+                        writeln!(bw)?;
+                    }
+                } else {
+                    show_disk_usage(&usage, *bytes, &mut std::io::stdout())?;
+                }
+            }
+            Command::Find {
+                archive,
+                pattern,
+                regex,
+                changes,
+                utc,
+            } => {
+                let archive = open_archive(archive, readonly, block_cache)?;
+                let pattern = if *regex {
+                    FindPattern::regex(pattern)?
+                } else {
+                    FindPattern::glob(pattern)?
+                };
+                let versions = find(&archive, &pattern, monitor.clone())?;
+                monitor.clear_progress_bars();
+                print_found_versions(&versions, *changes, json, *utc)?;
+            }
             Command::Gc {
                 archive,
                 dry_run,
                 break_lock,
+                min_age,
                 no_stats,
             } => {
-                let archive = Archive::open(open_transport(archive)?)?;
+                let archive = open_archive(archive, readonly, block_cache)?;
                 let stats = archive.delete_bands(
                     &[],
                     &DeleteOptions {
                         dry_run: *dry_run,
                         break_lock: *break_lock,
+                        min_block_age: *min_age,
                     },
-                    monitor,
+                    monitor.clone(),
                 )?;
-                if !no_stats {
+                if json {
+                    print_json(&monitor, &Tagged::stats(&stats))?;
+                } else if !no_stats {
                     info!(%stats);
                 }
             }
-            Command::Init { archive } => {
-                Archive::create(open_transport(archive)?)?;
+            Command::History {
+                archive,
+                apath,
+                all,
+                utc,
+            } => {
+                let archive = open_archive(archive, readonly, block_cache)?;
+                let versions = history(&archive, apath, monitor.clone())?;
+                monitor.clear_progress_bars();
+                print_found_versions(&versions, !*all, json, *utc)?;
+            }
+            Command::Hold {
+                archive,
+                backup,
+                release,
+            } => {
+                let archive = open_archive(archive, readonly, block_cache)?;
+                for band_id in backup {
+                    Band::open(&archive, *band_id)?.set_held(!release)?;
+                }
+            }
+            Command::ImportTar {
+                archive,
+                input,
+                changes_json,
+                verbose,
+                exclude,
+                exclude_from,
+                no_stats,
+                compression,
+                label,
+                message,
+            } => {
+                let options = BackupOptions::default()
+                    .with_exclude(Exclude::from_patterns_and_files(exclude, exclude_from)?)
+                    .with_change_callback(make_change_callback(
+                        *verbose,
+                        false,
+                        json,
+                        &changes_json.as_deref(),
+                    )?)
+                    .with_compression(*compression)
+                    .with_label(label.clone())
+                    .with_message(message.clone())
+                    .with_parallelism(parallelism);
+                let archive = open_archive(archive, readonly, block_cache)?;
+                let stats = if let Some(input) = input {
+                    let mut file = BufReader::new(File::open(input)?);
+                    import_tar(&archive, &mut file, &options, monitor.clone())?
+                } else {
+                    import_tar(
+                        &archive,
+                        &mut std::io::stdin().lock(),
+                        &options,
+                        monitor.clone(),
+                    )?
+                };
+                if json {
+                    print_json(&monitor, &Tagged::stats(&stats))?;
+                } else if !no_stats {
+                    info!("Import complete.\n{stats}");
+                }
+            }
+            Command::Info { archive } => {
+                let archive = open_archive(archive, readonly, block_cache)?;
+                let info = archive.info(monitor.clone())?;
+                if json {
+                    print_json(&monitor, &info)?;
+                } else {
+                    monitor.clear_progress_bars();
+                    print!("{info}");
+                }
+                if info.errors > 0 {
+                    return Ok(ExitCode::NonFatalErrors);
+                }
+            }
+            Command::Init {
+                archive,
+                compression,
+            } => {
+                Archive::create_with_compression(open_transport(archive)?, *compression)?;
                 debug!("Created new archive in {archive:?}");
             }
             Command::Ls {
-                json,
                 stos,
                 exclude,
                 exclude_from,
                 long_listing,
+                long,
+                utc,
+                kind,
             } => {
-                let exclude = Exclude::from_patterns_and_files(exclude, exclude_from)?;
+                let exclude = Exclude::from_patterns_and_files(exclude, exclude_from)?
+                    .with_only_kinds((!kind.is_empty()).then(|| kind.clone()));
                 let entry_iter: Box<dyn Iterator<Item = EntryValue>> =
                     if let Some(archive) = &stos.archive {
                         // TODO: Option for subtree.
                         Box::new(
-                            stored_tree_from_opt(archive, &stos.backup)?
-                                .iter_entries(Apath::root(), exclude, monitor.clone())?
-                                .map(|it| it.into()),
+                            stored_tree_from_opt(
+                                archive,
+                                &stos.backup,
+                                &stos.label,
+                                &stos.before,
+                                readonly,
+                                block_cache,
+                            )?
+                            .iter_entries(Apath::root(), exclude, monitor.clone())?
+                            .map(|it| it.into()),
                         )
                     } else {
                         Box::new(LiveTree::open(stos.source.clone().unwrap())?.iter_entries(
@@ -459,43 +1350,206 @@ impl Command {
                         )?)
                     };
                 monitor.clear_progress_bars();
-                if *json {
+                if json {
                     for entry in entry_iter {
                         println!("{}", serde_json::ser::to_string(&entry)?);
                     }
+                } else if *long {
+                    let timezone = if *utc {
+                        None
+                    } else {
+                        Some(*LOCAL_OFFSET.read().unwrap())
+                    };
+                    show_long_listing(entry_iter, timezone, &mut stdout)?;
                 } else {
                     show::show_entry_names(entry_iter, &mut stdout, *long_listing)?;
                 }
             }
+            Command::Prune {
+                archive,
+                keep_last,
+                keep_daily,
+                keep_weekly,
+                keep_monthly,
+                delete_older_than,
+                utc,
+                dry_run,
+                break_lock,
+                no_stats,
+            } => {
+                let timezone = if *utc {
+                    None
+                } else {
+                    Some(*LOCAL_OFFSET.read().unwrap())
+                };
+                let options = PruneOptions {
+                    keep_last: *keep_last,
+                    keep_daily: *keep_daily,
+                    keep_weekly: *keep_weekly,
+                    keep_monthly: *keep_monthly,
+                    delete_older_than: *delete_older_than,
+                    timezone,
+                    dry_run: *dry_run,
+                    break_lock: *break_lock,
+                };
+                let stats = prune(
+                    &open_archive(archive, readonly, block_cache)?,
+                    &options,
+                    monitor.clone(),
+                )?;
+                if json {
+                    print_json(&monitor, &Tagged::stats(&stats))?;
+                } else if !no_stats {
+                    monitor.clear_progress_bars();
+                    println!("{stats}");
+                }
+            }
+            Command::Recompress {
+                archive,
+                compression,
+                break_lock,
+                no_stats,
+            } => {
+                let archive = open_archive(archive, readonly, block_cache)?;
+                let stats = recompress(
+                    &archive,
+                    &RecompressOptions {
+                        compression: *compression,
+                        break_lock: *break_lock,
+                    },
+                    monitor.clone(),
+                )?;
+                if json {
+                    print_json(&monitor, &Tagged::stats(&stats))?;
+                } else if !no_stats {
+                    info!(%stats);
+                }
+            }
+            Command::Repair {
+                archive,
+                dry_run,
+                no_stats,
+            } => {
+                let archive = open_archive(archive, readonly, block_cache)?;
+                let stats = repair(
+                    &archive,
+                    &RepairOptions { dry_run: *dry_run },
+                    monitor.clone(),
+                )?;
+                if json {
+                    print_json(&monitor, &Tagged::stats(&stats))?;
+                } else if !no_stats {
+                    info!(%stats);
+                }
+            }
+            Command::Replicate {
+                source,
+                dest,
+                no_stats,
+            } => {
+                let source = open_archive(source, true, block_cache)?;
+                let dest = open_archive(dest, readonly, block_cache)?;
+                let stats = replicate(&source, &dest, monitor.clone())?;
+                if json {
+                    print_json(&monitor, &Tagged::stats(&stats))?;
+                } else if !no_stats {
+                    info!(%stats);
+                }
+            }
             Command::Restore {
                 archive,
                 destination,
                 backup,
+                label,
+                before,
                 changes_json,
                 verbose,
                 force_overwrite,
+                overwrite,
                 exclude,
                 exclude_from,
-                only_subtree,
+                only,
                 long_listing,
+                no_acls,
                 no_stats,
+                numeric_ids,
+                map_user,
+                map_group,
+                restore_as_current_user,
+                sparse,
+                verify,
+                skip_unchanged,
+                zero_copy,
             } => {
-                let band_selection = band_selection_policy_from_opt(backup);
-                let archive = Archive::open(open_transport(archive)?)?;
-                let _ = no_stats; // accepted but ignored; we never currently print stats
-                let options = RestoreOptions {
-                    exclude: Exclude::from_patterns_and_files(exclude, exclude_from)?,
-                    only_subtree: only_subtree.clone(),
-                    band_selection,
-                    overwrite: *force_overwrite,
-                    change_callback: make_change_callback(
+                let band_selection = band_selection_policy_from_opt(backup, label, before);
+                let archive = open_archive(archive, readonly, block_cache)?;
+                let options = RestoreOptions::default()
+                    .with_exclude(Exclude::from_patterns_and_files(exclude, exclude_from)?)
+                    .with_only(Include::from_strings(only)?)
+                    .with_band_selection(band_selection)
+                    .with_overwrite(if *force_overwrite {
+                        Overwrite::Always
+                    } else {
+                        *overwrite
+                    })
+                    .with_change_callback(make_change_callback(
                         *verbose,
                         *long_listing,
+                        json,
                         &changes_json.as_deref(),
-                    )?,
-                };
-                restore(&archive, destination, &options, monitor)?;
-                debug!("Restore complete");
+                    )?)
+                    .with_acls(!*no_acls)
+                    .with_numeric_ids(*numeric_ids)
+                    .with_zero_runs_as_holes(*sparse)
+                    .with_verify(*verify)
+                    .with_set_owners(if *restore_as_current_user {
+                        SetOwners::Never
+                    } else {
+                        SetOwners::IfRoot
+                    })
+                    .with_owner_map(OwnerMap::from_strings(map_user, map_group)?)
+                    .with_skip_unchanged(*skip_unchanged)
+                    .with_parallelism(parallelism)
+                    .with_zero_copy(*zero_copy);
+                let stats = restore(&archive, destination, &options, monitor.clone())?;
+                if json {
+                    print_json(&monitor, &Tagged::stats(&stats))?;
+                } else if !no_stats {
+                    info!("Restore complete.\n{stats}");
+                }
+            }
+            Command::Salvage {
+                archive,
+                destination,
+                no_stats,
+            } => {
+                let archive = open_archive(archive, readonly, block_cache)?;
+                let stats = salvage(&archive, destination, monitor.clone())?;
+                if json {
+                    print_json(&monitor, &Tagged::stats(&stats))?;
+                } else if !no_stats {
+                    info!(%stats);
+                }
+            }
+            Command::Serve { archive, http } => {
+                // The server never changes the archive, whether or not --readonly was given.
+                let archive = open_archive(archive, true, block_cache)?;
+                let listener = TcpListener::bind(http)?;
+                info!("Serving archive at http://{}/", listener.local_addr()?);
+                serve(&archive, listener, monitor.clone())?;
+            }
+            Command::Stats { archive } => {
+                let archive = open_archive(archive, readonly, block_cache)?;
+                let stats = archive_stats(&archive, monitor.clone())?;
+                if json {
+                    print_json(&monitor, &stats)?;
+                } else {
+                    monitor.clear_progress_bars();
+                    print!("{stats}");
+                }
+                if stats.errors > 0 {
+                    return Ok(ExitCode::NonFatalErrors);
+                }
             }
             Command::Size {
                 stos,
@@ -505,51 +1559,185 @@ impl Command {
             } => {
                 let exclude = Exclude::from_patterns_and_files(exclude, exclude_from)?;
                 let size = if let Some(archive) = &stos.archive {
-                    stored_tree_from_opt(archive, &stos.backup)?
-                        .size(exclude, monitor.clone())?
-                        .file_bytes
+                    stored_tree_from_opt(
+                        archive,
+                        &stos.backup,
+                        &stos.label,
+                        &stos.before,
+                        readonly,
+                        block_cache,
+                    )?
+                    .size(exclude, monitor.clone())?
                 } else {
-                    LiveTree::open(stos.source.as_ref().unwrap())?
-                        .size(exclude, monitor.clone())?
-                        .file_bytes
+                    LiveTree::open(stos.source.as_ref().unwrap())?.size(exclude, monitor.clone())?
                 };
                 monitor.clear_progress_bars();
-                if *bytes {
-                    println!("{size}");
+                if json {
+                    print_json(&monitor, &size)?;
+                } else if *bytes {
+                    println!("{}", size.file_bytes);
                 } else {
-                    println!("{}", conserve::bytes_to_human_mb(size));
+                    println!("{}", conserve::bytes_to_human_mb(size.file_bytes));
                 }
             }
-            Command::Validate { archive, quick, .. } => {
-                let options = ValidateOptions {
-                    skip_block_hashes: *quick,
+            Command::Validate {
+                archive,
+                quick,
+                backup,
+                incremental,
+                sample,
+                sample_seed,
+                no_stats,
+            } => {
+                let sample = sample.map(|fraction| {
+                    let seed = sample_seed.unwrap_or_else(|| {
+                        SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map_or(0, |d| d.as_nanos() as u64)
+                    });
+                    info!(
+                        "Checking {}% of blocks, with --sample-seed {seed}",
+                        fraction * 100.0
+                    );
+                    BlockSample { fraction, seed }
+                });
+                let options = ValidateOptions::default()
+                    .with_skip_block_hashes(*quick)
+                    .with_incremental(*incremental)
+                    .with_sample(sample)
+                    .with_parallelism(parallelism);
+                let archive = open_archive(archive, readonly, block_cache)?;
+                let stats = if let Some(band_id) = backup {
+                    archive.validate_band(*band_id, &options, monitor.clone())?
+                } else {
+                    archive.validate(&options, monitor.clone())?
                 };
-                Archive::open(open_transport(archive)?)?.validate(&options, monitor.clone())?;
+                if json {
+                    print_json(&monitor, &Tagged::stats(&stats))?;
+                } else if !no_stats {
+                    info!("Validation complete.\n{stats}");
+                }
                 if monitor.error_count() != 0 {
                     warn!("Archive has some problems.");
+                } else if let Some(band_id) = backup {
+                    info!("Backup {band_id} is OK.");
                 } else {
                     info!("Archive is OK.");
+                    if readonly {
+                        debug!("Not recording validation in a read-only archive");
+                    } else if let Err(err) =
+                        archive.set_last_validated(&ValidationMarker::new(&options, stats))
+                    {
+                        warn!("Failed to record that the archive was validated: {err}");
+                    }
+                }
+            }
+            Command::Verify {
+                archive,
+                source,
+                backup,
+                label,
+                before,
+                exclude,
+                exclude_from,
+                no_stats,
+            } => {
+                let st =
+                    stored_tree_from_opt(archive, backup, label, before, readonly, block_cache)?;
+                let lt = LiveTree::open(source)?;
+                let bw = RefCell::new(BufWriter::new(stdout));
+                let options = VerifyOptions {
+                    exclude: Exclude::from_patterns_and_files(exclude, exclude_from)?,
+                    drift_callback: Some(Box::new(|drift| {
+                        let mut bw = bw.borrow_mut();
+                        if json {
+                            serde_json::to_writer(&mut *bw, &Tagged::new("drift", drift))?;
+                            writeln!(bw)?;
+                        } else {
+                            writeln!(bw, "{drift}")?;
+                        }
+                        Ok(())
+                    })),
+                };
+                let stats = verify(&st, &lt, &options, monitor.clone())?;
+                bw.borrow_mut().flush()?;
+                if json {
+                    print_json(&monitor, &Tagged::stats(&stats))?;
+                } else if !no_stats {
+                    info!("Verify complete.\n{stats}");
+                }
+                if stats.metadata_drift + stats.content_drift > 0 {
+                    return Ok(ExitCode::Differences);
                 }
             }
+            Command::Watch {
+                archive,
+                source,
+                interval,
+                jitter,
+                runs,
+                exclude,
+                exclude_from,
+                compression,
+                change_cache,
+                label,
+                keep_last,
+                keep_daily,
+                keep_weekly,
+                keep_monthly,
+                delete_older_than,
+                utc,
+            } => {
+                let backup_options = BackupOptions::default()
+                    .with_exclude(Exclude::from_patterns_and_files(exclude, exclude_from)?)
+                    .with_compression(*compression)
+                    .with_change_cache(change_cache.clone())
+                    .with_label(label.clone())
+                    .with_parallelism(parallelism);
+                let retention = *keep_last + *keep_daily + *keep_weekly + *keep_monthly > 0
+                    || delete_older_than.is_some();
+                let prune = retention.then(|| PruneOptions {
+                    keep_last: *keep_last,
+                    keep_daily: *keep_daily,
+                    keep_weekly: *keep_weekly,
+                    keep_monthly: *keep_monthly,
+                    delete_older_than: *delete_older_than,
+                    timezone: (!utc).then(|| *LOCAL_OFFSET.read().unwrap()),
+                    ..Default::default()
+                });
+                let options = WatchOptions {
+                    interval: *interval,
+                    jitter: jitter.unwrap_or(*interval / 10),
+                    prune,
+                    max_runs: *runs,
+                };
+                let archive = open_archive(archive, readonly, block_cache)?;
+                watch(&archive, source, &backup_options, &options, monitor)?;
+            }
             Command::Versions {
                 archive,
                 short,
                 newest,
                 sizes,
                 utc,
+                verbose,
             } => {
                 let timezone = if *utc {
                     None
                 } else {
                     Some(*LOCAL_OFFSET.read().unwrap())
                 };
-                let archive = Archive::open(open_transport(archive)?)?;
+                let archive = open_archive(archive, readonly, block_cache)?;
                 let options = ShowVersionsOptions {
                     newest_first: *newest,
                     tree_size: *sizes,
                     timezone,
                     start_time: !*short,
                     backup_duration: !*short,
+                    description: !*short,
+                    origin: *verbose,
+                    last_validated: !*short,
+                    json,
                 };
                 conserve::show_versions(&archive, &options, monitor)?;
             }
@@ -558,23 +1746,114 @@ impl Command {
     }
 }
 
-fn stored_tree_from_opt(archive_location: &str, backup: &Option<BandId>) -> Result<StoredTree> {
-    let archive = Archive::open(open_transport(archive_location)?)?;
-    let policy = band_selection_policy_from_opt(backup);
+/// Open an archive, read-only if requested, and with the requested block cache size.
+fn open_archive(
+    archive_location: &str,
+    readonly: bool,
+    block_cache: Option<u64>,
+) -> Result<Archive> {
+    let transport = open_transport(archive_location)?;
+    let archive = if readonly {
+        Archive::open_readonly(transport)?
+    } else {
+        Archive::open(transport)?
+    };
+    if let Some(size) = block_cache {
+        archive.block_dir().set_cache_capacity(size);
+    }
+    Ok(archive)
+}
+
+fn stored_tree_from_opt(
+    archive_location: &str,
+    backup: &Option<BandId>,
+    label: &Option<String>,
+    before: &Option<OffsetDateTime>,
+    readonly: bool,
+    block_cache: Option<u64>,
+) -> Result<StoredTree> {
+    let archive = open_archive(archive_location, readonly, block_cache)?;
+    let policy = band_selection_policy_from_opt(backup, label, before);
     archive.open_stored_tree(policy)
 }
 
-fn band_selection_policy_from_opt(backup: &Option<BandId>) -> BandSelectionPolicy {
+fn band_selection_policy_from_opt(
+    backup: &Option<BandId>,
+    label: &Option<String>,
+    before: &Option<OffsetDateTime>,
+) -> BandSelectionPolicy {
     if let Some(band_id) = backup {
         BandSelectionPolicy::Specified(*band_id)
+    } else if let Some(label) = label {
+        BandSelectionPolicy::Label(label.clone())
+    } else if let Some(time) = before {
+        BandSelectionPolicy::AsOf(*time)
     } else {
         BandSelectionPolicy::Latest
     }
 }
 
+/// A json record with a `type` field, so that readers can tell apart the kinds of
+/// record in output that mixes them, such as changes followed by statistics.
+#[derive(Serialize)]
+struct Tagged<'a, T> {
+    #[serde(rename = "type")]
+    record_type: &'static str,
+    #[serde(flatten)]
+    value: &'a T,
+}
+
+impl<'a, T> Tagged<'a, T> {
+    fn new(record_type: &'static str, value: &'a T) -> Self {
+        Tagged { record_type, value }
+    }
+
+    fn stats(value: &'a T) -> Self {
+        Tagged::new("stats", value)
+    }
+}
+
+/// Print a value to stdout as one line of json, such as the statistics at the end of
+/// a command run with `--json`.
+fn print_json<T: Serialize>(monitor: &TermUiMonitor, value: &T) -> Result<()> {
+    monitor.clear_progress_bars();
+    println!("{}", serde_json::to_string(value)?);
+    Ok(())
+}
+
+/// Print versions found by `find` or `history` to stdout, as text or json lines.
+fn print_found_versions(
+    versions: &[FoundVersion],
+    changes_only: bool,
+    json: bool,
+    utc: bool,
+) -> Result<()> {
+    if json {
+        let mut bw = BufWriter::new(std::io::stdout());
+        for version in versions {
+            if changes_only && version.change == VersionChange::Unchanged {
+                continue;
+            }
+            serde_json::to_writer(&mut bw, version)?;
+            writeln!(bw)?;
+        }
+        Ok(())
+    } else {
+        let timezone = if utc {
+            None
+        } else {
+            Some(*LOCAL_OFFSET.read().unwrap())
+        };
+        show_found_versions(versions, timezone, changes_only, &mut std::io::stdout())
+    }
+}
+
+/// Make a callback that prints changes to stdout, as text or with `json` as json
+/// lines, and also writes them to a json file if `changes_json` is given.
 fn make_change_callback<'a>(
     print_changes: bool,
     ls_long: bool,
+    json: bool,
     changes_json: &Option<&Path>,
 ) -> Result<Option<ChangeCallback<'a>>> {
     if !print_changes && !ls_long && changes_json.is_none() {
@@ -596,7 +1875,13 @@ fn make_change_callback<'a>(
         if matches!(entry_change.change, Change::Unchanged { .. }) {
             return Ok(());
         }
-        if ls_long {
+        if json && (print_changes || ls_long) {
+            println!(
+                "{}",
+                serde_json::to_string(&Tagged::new("change", entry_change))
+                    .expect("Failed to serialize change")
+            );
+        } else if ls_long {
             let change_meta = entry_change.change.primary_metadata();
             println!(
                 "{} {} {} {}",
@@ -632,9 +1917,28 @@ fn main() -> Result<ExitCode> {
     } else {
         Level::INFO
     };
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("Configure thread pool");
+    }
     let monitor = Arc::new(TermUiMonitor::new(!args.no_progress));
     let _flush_tracing = enable_tracing(&monitor, &args.trace_time, console_level, &args.log_json);
-    let result = args.command.run(monitor.clone());
+    let mut parallelism = Parallelism::default();
+    if let Some(transport_concurrency) = args.transport_concurrency {
+        parallelism = parallelism.with_transport_concurrency(transport_concurrency);
+    }
+    if let Some(max_buffer) = args.max_buffer {
+        parallelism = parallelism.with_max_buffered_bytes(max_buffer);
+    }
+    let result = args.command.run(
+        args.readonly,
+        args.block_cache,
+        args.json,
+        parallelism,
+        monitor.clone(),
+    );
     debug!(elapsed = ?start_time.elapsed());
     if let Some(metrics_path) = args.metrics_json {
         serde_json::to_writer_pretty(
@@ -648,8 +1952,11 @@ fn main() -> Result<ExitCode> {
     }
     match result {
         Err(err) => {
-            error!("{err:#}");
-            Ok(ExitCode::Failure)
+            error!(code = err.code(), "{err:#}");
+            Ok(ExitCode::for_error(&err))
+        }
+        Ok(ExitCode::Success | ExitCode::NonFatalErrors) if monitor.damage_count() != 0 => {
+            Ok(ExitCode::ArchiveDamaged)
         }
         Ok(ExitCode::Success) if monitor.error_count() != 0 => Ok(ExitCode::NonFatalErrors),
         Ok(exit_code) => Ok(exit_code),
@@ -661,3 +1968,23 @@ fn verify_clap() {
     use clap::CommandFactory;
     Args::command().debug_assert()
 }
+
+#[test]
+fn exit_code_for_errors() {
+    assert_eq!(
+        ExitCode::for_error(&Error::GarbageCollectionLockHeld) as u8,
+        ExitCode::ArchiveBusy as u8
+    );
+    assert_eq!(
+        ExitCode::for_error(&Error::GarbageCollectionLockHeldDuringBackup) as u8,
+        ExitCode::ArchiveBusy as u8
+    );
+    assert_eq!(
+        ExitCode::for_error(&Error::MissingZstdDictionary) as u8,
+        ExitCode::ArchiveDamaged as u8
+    );
+    assert_eq!(
+        ExitCode::for_error(&Error::Cancelled) as u8,
+        ExitCode::Failure as u8
+    );
+}
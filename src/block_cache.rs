@@ -0,0 +1,170 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! An in-memory cache of the uncompressed content of blocks, limited by the total
+//! size of the blocks it holds.
+//!
+//! Blocks vary in size from a few bytes to tens of megabytes, so limiting the cache
+//! by the number of blocks would either use too much memory for large blocks or
+//! keep too few small ones. This cache instead evicts the least recently used
+//! blocks once their total size exceeds a budget.
+
+use std::sync::Mutex;
+
+use bytes::Bytes;
+use lru::LruCache;
+
+use crate::BlockHash;
+
+/// The default budget for cached block content, in bytes.
+pub(crate) const DEFAULT_BLOCK_CACHE_BYTES: u64 = 256 << 20;
+
+/// A least-recently-used cache of block content, limited by the total size of the
+/// content.
+///
+/// This can be shared between threads.
+#[derive(Debug)]
+pub(crate) struct BlockCache {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    blocks: LruCache<BlockHash, Bytes>,
+    /// Total length of the content of all cached blocks.
+    bytes: u64,
+    /// Maximum total length of the cached blocks.
+    capacity: u64,
+}
+
+impl Inner {
+    /// Remove the least recently used blocks until the content fits in the capacity.
+    fn evict(&mut self) {
+        while self.bytes > self.capacity {
+            let (_hash, content) = self.blocks.pop_lru().expect("cache holds some bytes");
+            self.bytes -= content.len() as u64;
+        }
+    }
+}
+
+impl BlockCache {
+    /// Make a new empty cache holding at most `capacity` bytes of block content.
+    pub(crate) fn new(capacity: u64) -> BlockCache {
+        BlockCache {
+            inner: Mutex::new(Inner {
+                blocks: LruCache::unbounded(),
+                bytes: 0,
+                capacity,
+            }),
+        }
+    }
+
+    /// Change the budget, evicting blocks if they no longer fit.
+    pub(crate) fn set_capacity(&self, capacity: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.capacity = capacity;
+        inner.evict();
+    }
+
+    /// Return the content of a block, if it's cached, and mark it as recently used.
+    pub(crate) fn get(&self, hash: &BlockHash) -> Option<Bytes> {
+        self.inner.lock().unwrap().blocks.get(hash).cloned()
+    }
+
+    /// True if the block is cached, without changing how recently it was used.
+    pub(crate) fn contains(&self, hash: &BlockHash) -> bool {
+        self.inner.lock().unwrap().blocks.contains(hash)
+    }
+
+    /// Add a block to the cache, evicting older blocks to make room for it.
+    ///
+    /// A block larger than the whole budget is not cached.
+    pub(crate) fn insert(&self, hash: BlockHash, content: Bytes) {
+        let mut inner = self.inner.lock().unwrap();
+        let len = content.len() as u64;
+        if len > inner.capacity {
+            return;
+        }
+        if let Some(old) = inner.blocks.put(hash, content) {
+            inner.bytes -= old.len() as u64;
+        }
+        inner.bytes += len;
+        inner.evict();
+    }
+
+    /// Forget a block, if it's cached.
+    pub(crate) fn remove(&self, hash: &BlockHash) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(old) = inner.blocks.pop(hash) {
+            inner.bytes -= old.len() as u64;
+        }
+    }
+
+    /// Return the number of cached blocks and the total length of their content.
+    pub(crate) fn usage(&self) -> (usize, u64) {
+        let inner = self.inner.lock().unwrap();
+        (inner.blocks.len(), inner.bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block(content: &[u8]) -> (BlockHash, Bytes) {
+        (
+            BlockHash::hash_bytes(content),
+            Bytes::copy_from_slice(content),
+        )
+    }
+
+    #[test]
+    fn least_recently_used_blocks_are_evicted_by_size() {
+        let cache = BlockCache::new(10);
+        let (a, a_content) = block(b"aaaa");
+        let (b, b_content) = block(b"bbbb");
+        let (c, c_content) = block(b"cccc");
+        cache.insert(a.clone(), a_content.clone());
+        cache.insert(b.clone(), b_content);
+        assert_eq!(cache.usage(), (2, 8));
+        // Using a makes b the least recently used, so it's evicted to make room for c.
+        assert_eq!(cache.get(&a), Some(a_content));
+        cache.insert(c.clone(), c_content);
+        assert_eq!(cache.usage(), (2, 8));
+        assert!(cache.contains(&a));
+        assert!(!cache.contains(&b));
+        assert!(cache.contains(&c));
+
+        cache.remove(&a);
+        assert_eq!(cache.usage(), (1, 4));
+        cache.set_capacity(3);
+        assert_eq!(cache.usage(), (0, 0));
+    }
+
+    #[test]
+    fn block_larger_than_capacity_is_not_cached() {
+        let cache = BlockCache::new(3);
+        let (a, a_content) = block(b"aaaa");
+        cache.insert(a.clone(), a_content);
+        assert!(!cache.contains(&a));
+        assert_eq!(cache.usage(), (0, 0));
+    }
+
+    #[test]
+    fn replacing_a_block_counts_its_size_once() {
+        let cache = BlockCache::new(10);
+        let (a, a_content) = block(b"aaaa");
+        cache.insert(a.clone(), a_content.clone());
+        cache.insert(a.clone(), a_content);
+        assert_eq!(cache.usage(), (1, 4));
+    }
+}
@@ -20,22 +20,43 @@
 //! and which range of uncompressed bytes.
 //!
 //! The structure is: archive > blockdir > subdir > file.
+//!
+//! Small blocks can instead be gathered into packs, by [crate::compact]. A pack
+//! holds the compressed blocks one after another, followed by a json index of where
+//! each block starts, and then the length of the index as a little-endian `u64`.
+//! Blocks are read from a pack if they don't have their own file.
+//!
+//! Tools outside Conserve, such as deduplication analyzers or replication scripts,
+//! can use the [BlockDir] of an [Archive]: [BlockDir::blocks] lists the blocks,
+//! [BlockDir::contains] checks whether a block is present,
+//! [BlockDir::get_block_content] reads and checks its uncompressed content, and
+//! [BlockDir::read_compressed] and [BlockDir::pack_holding] give its stored form.
+//! Blocks shouldn't be written or deleted except through Conserve's own operations,
+//! which take the necessary locks.
 
 use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, RwLock};
 
 use bytes::Bytes;
+use fail::fail_point;
 use lru::LruCache;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, warn};
-use tracing::{instrument, trace};
+use tracing::{debug_span, instrument, trace};
 
-use crate::compress::snappy::{Compressor, Decompressor};
+use crate::block_cache::{BlockCache, DEFAULT_BLOCK_CACHE_BYTES};
+use crate::compress::{
+    compress_block, decompress_block, stored_content_offset, uses_zstd_dictionary, Compression,
+    DEFAULT_ZSTD_LEVEL,
+};
 use crate::counters::Counter;
-use crate::monitor::Monitor;
+use crate::monitor::{check_cancel, Monitor};
+use crate::parallelism::Semaphore;
+use crate::stats::RecompressStats;
 use crate::transport::ListDir;
 use crate::*;
 
@@ -44,6 +65,12 @@ use crate::*;
 /// Take this many characters from the block hash to form the subdirectory name.
 const SUBDIR_NAME_CHARS: usize = 3;
 
+/// Subdirectory of the blockdir holding packs of small blocks.
+pub const PACK_DIR: &str = "packs";
+
+/// Length of the trailer at the end of each pack, giving the length of its index.
+const PACK_TRAILER_LEN: u64 = 8;
+
 /// Points to some compressed data inside the block dir.
 ///
 /// Identifiers are: which file contains it, at what (pre-compression) offset,
@@ -62,15 +89,69 @@ pub struct Address {
     pub len: u64,
 }
 
+/// Where one block is stored in a pack.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct PackEntry {
+    hash: BlockHash,
+    /// Offset of the compressed block from the start of the pack.
+    start: u64,
+    /// Length of the compressed block.
+    len: u64,
+}
+
+/// The index at the end of each pack.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PackIndex {
+    blocks: Vec<PackEntry>,
+}
+
+/// The location of a block that's stored in a pack.
+#[derive(Clone, Debug)]
+struct PackedBlock {
+    pack: Arc<str>,
+    start: u64,
+    len: u64,
+}
+
+/// The packs whose indexes have been read, and the blocks they hold.
+#[derive(Debug, Default)]
+struct Packs {
+    names: HashSet<Arc<str>>,
+    blocks: HashMap<BlockHash, PackedBlock>,
+}
+
+impl Packs {
+    fn add(&mut self, name: &str, index: PackIndex) {
+        let pack: Arc<str> = name.into();
+        for PackEntry { hash, start, len } in index.blocks {
+            self.blocks.entry(hash).or_insert_with(|| PackedBlock {
+                pack: pack.clone(),
+                start,
+                len,
+            });
+        }
+        self.names.insert(pack);
+    }
+}
+
 /// A readable, writable directory within a band holding data blocks.
 #[derive(Debug)]
 pub struct BlockDir {
     transport: Arc<dyn Transport>,
     pub stats: BlockDirStats,
-    // TODO: There are fancier caches and they might help, but this one works, and Stretto did not work for me.
-    cache: RwLock<LruCache<BlockHash, Bytes>>,
+    /// Recently read or written block content, shared by everything reading this archive.
+    cache: BlockCache,
     /// Presence means that we know that this block exists, even if we don't have its content.
     exists: RwLock<LruCache<BlockHash, ()>>,
+    /// The archive's zstd dictionary, needed to read blocks compressed with it.
+    zstd_dictionary: OnceLock<Bytes>,
+    /// The packed blocks, or None if the packs haven't been read yet.
+    packs: RwLock<Option<Packs>>,
+    /// Blocks that are being written by some thread, so that other threads storing
+    /// the same content wait for that write rather than writing it again.
+    writing: Mutex<HashSet<BlockHash>>,
+    /// Notified when a block is no longer being written.
+    written: Condvar,
 }
 
 /// Returns the transport-relative subdirectory name.
@@ -84,52 +165,164 @@ pub fn block_relpath(hash: &BlockHash) -> String {
     format!("{}/{}", subdir_relpath(&hash_hex), hash_hex)
 }
 
+/// Return the transport-relative file for a pack.
+fn pack_relpath(name: &str) -> String {
+    format!("{PACK_DIR}/{name}")
+}
+
 impl BlockDir {
     pub fn open(transport: Arc<dyn Transport>) -> BlockDir {
-        /// Cache this many blocks in memory.
-        // TODO: Change to a cache that tracks the size of stored blocks?
-        // As a safe conservative value, 100 blocks of 20MB each would be 2GB.
-        const BLOCK_CACHE_SIZE: usize = 100;
-
         /// Remember the existence of this many blocks, even if we don't have their content.
         const EXISTENCE_CACHE_SIZE: usize = (64 << 20) / BLAKE_HASH_SIZE_BYTES;
 
         BlockDir {
             transport,
             stats: BlockDirStats::default(),
-            cache: RwLock::new(LruCache::new(BLOCK_CACHE_SIZE.try_into().unwrap())),
+            cache: BlockCache::new(DEFAULT_BLOCK_CACHE_BYTES),
             exists: RwLock::new(LruCache::new(EXISTENCE_CACHE_SIZE.try_into().unwrap())),
+            zstd_dictionary: OnceLock::new(),
+            packs: RwLock::new(None),
+            writing: Mutex::new(HashSet::new()),
+            written: Condvar::new(),
         }
     }
 
+    /// Keep at most this many bytes of recently used block content in memory.
+    ///
+    /// The cache is shared by everything reading or writing blocks in this archive,
+    /// so that blocks read repeatedly, such as those combining many small files,
+    /// are fetched from the transport only once while they're in use. The default
+    /// is 256MiB. Zero disables the cache.
+    pub fn set_cache_capacity(&self, bytes: u64) {
+        self.cache.set_capacity(bytes);
+    }
+
+    /// Return the number of blocks in the cache, and the total size of their content.
+    pub fn cache_usage(&self) -> (usize, u64) {
+        self.cache.usage()
+    }
+
     pub fn create(transport: Arc<dyn Transport>) -> Result<BlockDir> {
         transport.create_dir("")?;
         Ok(BlockDir::open(transport))
     }
 
+    /// Set the zstd dictionary used to read blocks that were compressed with it.
+    ///
+    /// The dictionary can only be set once.
+    pub(crate) fn set_zstd_dictionary(&self, dictionary: Bytes) {
+        if self.zstd_dictionary.set(dictionary).is_err() {
+            panic!("zstd dictionary is already set");
+        }
+    }
+
+    /// The archive's zstd dictionary, if it has one.
+    pub(crate) fn zstd_dictionary(&self) -> Option<&Bytes> {
+        self.zstd_dictionary.get()
+    }
+
     /// Store block data, if it's not already present, and return the hash.
     ///
     /// The block data must be less than the maximum block size.
+    ///
+    /// If the block is not already present it's written with the given compression,
+    /// and with the zstd dictionary if one is given.
     pub(crate) fn store_or_deduplicate(
         &self,
         block_data: Bytes,
+        compression: Compression,
+        dictionary: Option<&[u8]>,
+        stats: &mut BackupStats,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<BlockHash> {
+        self.store_or_deduplicate_limited(block_data, compression, dictionary, stats, monitor, None)
+    }
+
+    /// Store a block, as [BlockDir::store_or_deduplicate], but if `write_limit` is
+    /// given, take a permit from it while writing to the transport.
+    pub(crate) fn store_or_deduplicate_limited(
+        &self,
+        block_data: Bytes,
+        compression: Compression,
+        dictionary: Option<&[u8]>,
         stats: &mut BackupStats,
         monitor: Arc<dyn Monitor>,
+        write_limit: Option<&Semaphore>,
     ) -> Result<BlockHash> {
         let hash = BlockHash::hash_bytes(&block_data);
-        let uncomp_len = block_data.len() as u64;
-        if self.contains(&hash, monitor.clone())? {
+        let deduplicated = |stats: &mut BackupStats| {
             stats.deduplicated_blocks += 1;
-            stats.deduplicated_bytes += uncomp_len;
+            stats.deduplicated_bytes += block_data.len() as u64;
             monitor.count(Counter::DeduplicatedBlocks, 1);
             monitor.count(Counter::DeduplicatedBlockBytes, block_data.len());
+        };
+        if self.contains(&hash, monitor.clone())? {
+            deduplicated(stats);
             return Ok(hash);
         }
-        let compressed = Compressor::new().compress(&block_data)?;
+        // The block is in the existence cache before it's unclaimed, so one that's
+        // neither claimed nor cached, once we hold the claim, is not yet written.
+        let mut writing = self.writing.lock().unwrap();
+        while !writing.insert(hash.clone()) {
+            // Another thread is writing this block. Wait for it to finish, and if it
+            // failed, try writing the block here.
+            writing = self
+                .written
+                .wait_while(writing, |writing| writing.contains(&hash))
+                .unwrap();
+            if self.exists.read().unwrap().contains(&hash) {
+                drop(writing);
+                deduplicated(stats);
+                return Ok(hash);
+            }
+        }
+        drop(writing);
+        if self.exists.read().unwrap().contains(&hash) {
+            self.release_claim(&hash);
+            deduplicated(stats);
+            return Ok(hash);
+        }
+        let result = self.write_block(
+            &hash,
+            block_data,
+            compression,
+            dictionary,
+            stats,
+            monitor,
+            write_limit,
+        );
+        self.release_claim(&hash);
+        result.map(|()| hash)
+    }
+
+    /// Release the claim on writing a block, waking any threads waiting to store it.
+    fn release_claim(&self, hash: &BlockHash) {
+        self.writing.lock().unwrap().remove(hash);
+        self.written.notify_all();
+    }
+
+    /// Compress and write a block that's not present.
+    #[allow(clippy::too_many_arguments)]
+    fn write_block(
+        &self,
+        hash: &BlockHash,
+        block_data: Bytes,
+        compression: Compression,
+        dictionary: Option<&[u8]>,
+        stats: &mut BackupStats,
+        monitor: Arc<dyn Monitor>,
+        write_limit: Option<&Semaphore>,
+    ) -> Result<()> {
+        let uncomp_len = block_data.len() as u64;
+        let compressed = compress_block(compression, dictionary, &block_data)?;
         monitor.count(Counter::BlockWriteUncompressedBytes, block_data.len());
         let comp_len: u64 = compressed.len().try_into().unwrap();
         let hex_hash = hash.to_string();
-        let relpath = block_relpath(&hash);
+        let relpath = block_relpath(hash);
+        let _permit = write_limit.map(Semaphore::acquire);
+        fail_point!("blockdir::write-block", |_| Err(Error::IOError {
+            source: std::io::Error::other("Simulated failure writing a block")
+        }));
         self.transport.create_dir(subdir_relpath(&hex_hash))?;
         self.transport.write_file(&relpath, &compressed)?;
         stats.written_blocks += 1;
@@ -138,12 +331,9 @@ impl BlockDir {
         monitor.count(Counter::BlockWrites, 1);
         monitor.count(Counter::BlockWriteCompressedBytes, compressed.len());
         // Only update caches after everything succeeded
-        self.cache
-            .write()
-            .expect("Lock cache")
-            .put(hash.clone(), block_data);
+        self.cache.insert(hash.clone(), block_data);
         self.exists.write().unwrap().push(hash.clone(), ());
-        Ok(hash)
+        Ok(())
     }
 
     /// True if the named block is present and apparently in this blockdir.
@@ -155,31 +345,74 @@ impl BlockDir {
     /// So, these are specifically treated as missing, so there's a chance to heal
     /// them later.
     pub fn contains(&self, hash: &BlockHash, monitor: Arc<dyn Monitor>) -> Result<bool> {
-        if self.cache.read().expect("Lock cache").contains(hash)
-            || self.exists.read().unwrap().contains(hash)
-        {
+        if self.cache.contains(hash) || self.exists.read().unwrap().contains(hash) {
             monitor.count(Counter::BlockExistenceCacheHit, 1);
             self.stats.cache_hit.fetch_add(1, Relaxed);
             return Ok(true);
         }
         monitor.count(Counter::BlockExistenceCacheMiss, 1);
+        let present = self.contains_uncached(hash)?;
+        if present {
+            self.exists.write().unwrap().put(hash.clone(), ());
+        }
+        Ok(present)
+    }
+
+    /// Check if a block is present in the directory, without looking in or
+    /// adding to the caches.
+    fn contains_uncached(&self, hash: &BlockHash) -> Result<bool> {
         match self.transport.metadata(&block_relpath(hash)) {
-            Err(err) if err.is_not_found() => Ok(false),
+            Err(err) if err.is_not_found() => {}
             Err(err) => {
                 warn!(?err, ?hash, "Error checking presence of block");
-                Err(err.into())
+                return Err(err.into());
             }
-            Ok(metadata) if metadata.kind == Kind::File && metadata.len > 0 => {
-                self.exists.write().unwrap().put(hash.clone(), ());
-                Ok(true)
+            Ok(metadata) if metadata.kind == Kind::File && metadata.len > 0 => return Ok(true),
+            Ok(_) => {}
+        }
+        Ok(self.packed_block(hash)?.is_some())
+    }
+
+    /// Check that a block file exists and has a plausible size, without reading it.
+    ///
+    /// This doesn't use or fill the caches.
+    pub(crate) fn check_block_file(&self, hash: &BlockHash) -> Result<()> {
+        match self.transport.metadata(&block_relpath(hash)) {
+            Err(err) if err.is_not_found() => match self.packed_block(hash)? {
+                Some(_) => Ok(()),
+                None => Err(Error::BlockMissing { hash: hash.clone() }),
+            },
+            Err(err) => Err(err.into()),
+            // Even an empty block has an encoding tag.
+            Ok(metadata) if metadata.kind != Kind::File || metadata.len == 0 => {
+                Err(Error::BlockFileImplausible {
+                    hash: hash.clone(),
+                    size: metadata.len,
+                })
             }
-            Ok(_) => Ok(false),
+            Ok(_) => Ok(()),
         }
     }
 
     /// Returns the compressed on-disk size of a block.
     pub fn compressed_size(&self, hash: &BlockHash) -> Result<u64> {
-        Ok(self.transport.metadata(&block_relpath(hash))?.len)
+        Ok(self.block_file_metadata(hash)?.len)
+    }
+
+    /// Returns the metadata of the file holding a block, including its size and mtime.
+    ///
+    /// For a packed block, this is the size of the block and the mtime of its pack.
+    pub(crate) fn block_file_metadata(&self, hash: &BlockHash) -> Result<transport::Metadata> {
+        match self.transport.metadata(&block_relpath(hash)) {
+            Err(err) if err.is_not_found() => match self.packed_block(hash)? {
+                Some(packed) => Ok(transport::Metadata {
+                    len: packed.len,
+                    ..self.transport.metadata(&pack_relpath(&packed.pack))?
+                }),
+                None => Err(err.into()),
+            },
+            result => Ok(result?),
+        }
     }
 
     /// Read back some content addressed by an [Address] (a block hash, start and end).
@@ -204,26 +437,28 @@ impl BlockDir {
     /// Checks that the hash is correct with the contents.
     #[instrument(skip(self, monitor))]
     pub fn get_block_content(&self, hash: &BlockHash, monitor: Arc<dyn Monitor>) -> Result<Bytes> {
-        if let Some(hit) = self.cache.write().expect("Lock cache").get(hash) {
+        if let Some(hit) = self.cache.get(hash) {
             monitor.count(Counter::BlockContentCacheHit, 1);
             self.stats.cache_hit.fetch_add(1, Relaxed);
             trace!("Block cache hit");
-            return Ok(hit.clone());
+            return Ok(hit);
         }
         monitor.count(Counter::BlockContentCacheMiss, 1);
-        let mut decompressor = Decompressor::new();
-        let block_relpath = block_relpath(hash);
-        let compressed_bytes = self.transport.read_file(&block_relpath)?;
-        let decompressed_bytes = decompressor.decompress(&compressed_bytes)?;
-        let actual_hash = BlockHash::hash_bytes(&decompressed_bytes);
-        if actual_hash != *hash {
-            return Err(Error::BlockCorrupt { hash: hash.clone() });
-        }
-        self.cache
-            .write()
-            .expect("Lock cache")
-            .put(hash.clone(), decompressed_bytes.clone());
+        let decompressed_bytes = self.read_block_uncached(hash, monitor)?;
+        self.cache.insert(hash.clone(), decompressed_bytes.clone());
         self.exists.write().unwrap().put(hash.clone(), ());
+        Ok(decompressed_bytes)
+    }
+
+    /// Read, decompress, and check the hash of a block, without looking in or
+    /// adding to the cache.
+    pub(crate) fn read_block_uncached(
+        &self,
+        hash: &BlockHash,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<Bytes> {
+        let compressed_bytes = self.read_compressed(hash)?;
+        let decompressed_bytes = self.decompress_checked(hash, &compressed_bytes)?;
         self.stats.read_blocks.fetch_add(1, Relaxed);
         monitor.count(Counter::BlockReads, 1);
         self.stats
@@ -240,21 +475,360 @@ impl BlockDir {
         Ok(decompressed_bytes)
     }
 
+    /// Read the compressed form of a block, from its own file or from a pack.
+    ///
+    /// The content is returned as it's stored, without being decompressed or checked
+    /// against the hash, and without using the cache. Blocks compressed with the
+    /// archive's zstd dictionary can only be decompressed with that dictionary.
+    pub fn read_compressed(&self, hash: &BlockHash) -> Result<Bytes> {
+        match self.transport.read_file(&block_relpath(hash)) {
+            Err(err) if err.is_not_found() => match self.read_packed(hash)? {
+                Some(bytes) => Ok(bytes),
+                None => Err(err.into()),
+            },
+            result => Ok(result?),
+        }
+    }
+
+    /// If the content at an address is stored uncompressed, in a block file of its
+    /// own on the local filesystem, return that file and the offset of the content
+    /// within it, so that it can be copied without reading it into memory.
+    ///
+    /// Content copied this way isn't checked against the block's hash.
+    pub(crate) fn local_stored_content(&self, address: &Address) -> Option<(PathBuf, u64)> {
+        let relpath = block_relpath(&address.hash);
+        let path = self.transport.local_path(&relpath)?;
+        let header = self.transport.read_range(&relpath, 0, 2).ok()?;
+        let offset = stored_content_offset(&header)?;
+        Some((path, offset + address.start))
+    }
+
+    /// Read the compressed form of a block from a pack, or None if it's not packed.
+    ///
+    /// Another process might have packed the block, or rewritten its pack, since the
+    /// packs were read, so they're read again if the block or its pack is missing.
+    fn read_packed(&self, hash: &BlockHash) -> Result<Option<Bytes>> {
+        let mut reloaded = false;
+        loop {
+            if let Some(packed) = self.packed_block(hash)? {
+                let relpath = pack_relpath(&packed.pack);
+                match self
+                    .transport
+                    .read_range(&relpath, packed.start, packed.len)
+                {
+                    Err(err) if err.is_not_found() && !reloaded => {}
+                    result => return Ok(Some(result?)),
+                }
+            } else if reloaded {
+                return Ok(None);
+            }
+            self.load_packs()?;
+            reloaded = true;
+        }
+    }
+
+    /// Decompress a block and check that its content matches the hash.
+    fn decompress_checked(&self, hash: &BlockHash, compressed: &[u8]) -> Result<Bytes> {
+        let content = decompress_block(compressed, self.zstd_dictionary().map(|d| d.as_ref()))?;
+        if BlockHash::hash_bytes(&content) != *hash {
+            return Err(Error::BlockCorrupt { hash: hash.clone() });
+        }
+        Ok(content)
+    }
+
+    /// Read the compressed content of a block from its own file, checking that it's
+    /// intact.
+    pub(crate) fn read_block_file_checked(&self, hash: &BlockHash) -> Result<Bytes> {
+        let compressed = self.transport.read_file(&block_relpath(hash))?;
+        self.decompress_checked(hash, &compressed)?;
+        Ok(compressed)
+    }
+
+    /// Check that the packed copy of a block is intact.
+    pub(crate) fn check_packed_block(&self, hash: &BlockHash) -> Result<()> {
+        let compressed = self
+            .read_packed(hash)?
+            .ok_or_else(|| Error::BlockMissing { hash: hash.clone() })?;
+        self.decompress_checked(hash, &compressed)?;
+        Ok(())
+    }
+
+    /// Rewrite a block with a different compression.
+    ///
+    /// The content is checked against the hash before it's rewritten, so the block
+    /// keeps the same name.
+    ///
+    /// If the encoded block is unchanged, it is not written.
+    ///
+    /// Packed blocks keep their compression, since rewriting them would mean
+    /// rewriting their whole pack.
+    pub(crate) fn recompress_block(
+        &self,
+        hash: &BlockHash,
+        compression: Compression,
+        stats: &mut RecompressStats,
+    ) -> Result<()> {
+        let relpath = block_relpath(hash);
+        let old_compressed = match self.transport.read_file(&relpath) {
+            Err(err) if err.is_not_found() && self.packed_block(hash)?.is_some() => {
+                debug!(%hash, "Not recompressing packed block");
+                return Ok(());
+            }
+            result => result?,
+        };
+        let content = self.decompress_checked(hash, &old_compressed)?;
+        let new_compressed = compress_block(compression, None, &content)?;
+        if new_compressed != old_compressed {
+            self.transport.write_file(&relpath, &new_compressed)?;
+            stats.rewritten_blocks += 1;
+        }
+        stats.old_compressed_bytes += old_compressed.len() as u64;
+        stats.new_compressed_bytes += new_compressed.len() as u64;
+        Ok(())
+    }
+
+    /// Copy a block from another blockdir into its own file here, returning the
+    /// number of compressed bytes written.
+    ///
+    /// The block is checked against its hash before it's written, and otherwise
+    /// keeps its compression, except that blocks compressed with the other blockdir's
+    /// zstd dictionary are recompressed with this blockdir's dictionary, if any,
+    /// unless the dictionaries are the same.
+    pub(crate) fn copy_block_from(&self, source: &BlockDir, hash: &BlockHash) -> Result<u64> {
+        let compressed = source.read_compressed(hash)?;
+        let content = source.decompress_checked(hash, &compressed)?;
+        let compressed = if uses_zstd_dictionary(&compressed)
+            && self.zstd_dictionary() != source.zstd_dictionary()
+        {
+            compress_block(
+                Compression::Zstd {
+                    level: DEFAULT_ZSTD_LEVEL,
+                },
+                self.zstd_dictionary().map(|d| d.as_ref()),
+                &content,
+            )?
+        } else {
+            compressed
+        };
+        self.transport
+            .create_dir(subdir_relpath(&hash.to_string()))?;
+        self.transport
+            .write_file(&block_relpath(hash), &compressed)?;
+        self.exists.write().unwrap().push(hash.clone(), ());
+        Ok(compressed.len() as u64)
+    }
+
+    /// Delete the file holding a block.
+    ///
+    /// This doesn't remove any copy of the block in a pack: see [BlockDir::remove_from_pack].
     pub fn delete_block(&self, hash: &BlockHash) -> Result<()> {
-        self.cache.write().expect("Lock cache").pop(hash);
+        self.cache.remove(hash);
         self.exists.write().unwrap().pop(hash);
         self.transport
             .remove_file(&block_relpath(hash))
             .map_err(Error::from)
     }
 
+    /// Find where a block is stored in a pack, reading the packs if they haven't
+    /// been read yet.
+    fn packed_block(&self, hash: &BlockHash) -> Result<Option<PackedBlock>> {
+        if self.packs.read().unwrap().is_none() {
+            self.load_packs()?;
+        }
+        Ok(self
+            .packs
+            .read()
+            .unwrap()
+            .as_ref()
+            .and_then(|packs| packs.blocks.get(hash).cloned()))
+    }
+
+    /// Return the name of the pack holding a block, if it's packed.
+    ///
+    /// Packs are in the [PACK_DIR] subdirectory of the blockdir. A block that's
+    /// not packed is in the file named by [block_relpath], if it's present at all.
+    pub fn pack_holding(&self, hash: &BlockHash) -> Result<Option<Arc<str>>> {
+        Ok(self.packed_block(hash)?.map(|packed| packed.pack))
+    }
+
+    /// True if any blocks are stored in packs.
+    pub(crate) fn has_packs(&self) -> Result<bool> {
+        match self.transport.list_dir(PACK_DIR) {
+            Ok(ListDir { files, .. }) => {
+                Ok(files.iter().any(|name| name.parse::<BlockHash>().is_ok()))
+            }
+            Err(err) if err.is_not_found() => Ok(false),
+            Err(source) => Err(Error::ListBlocks { source }),
+        }
+    }
+
+    /// Return the hashes of all the packed blocks.
+    pub(crate) fn packed_blocks(&self) -> Result<HashSet<BlockHash>> {
+        self.load_packs()?;
+        Ok(self
+            .packs
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|packs| packs.blocks.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    /// Read the indexes of packs that haven't been read yet.
+    ///
+    /// If any packs that were read before have since been removed, all the indexes
+    /// are read again, in case their blocks are also in other packs.
+    ///
+    /// Packs whose index can't be read are skipped with a warning, so their blocks
+    /// appear to be missing.
+    fn load_packs(&self) -> Result<()> {
+        let names: HashSet<String> = match self.transport.list_dir(PACK_DIR) {
+            Ok(ListDir { files, .. }) => files
+                .into_iter()
+                .filter(|name| name.parse::<BlockHash>().is_ok())
+                .collect(),
+            Err(err) if err.is_not_found() => HashSet::new(),
+            Err(source) => return Err(Error::ListBlocks { source }),
+        };
+        let mut guard = self.packs.write().unwrap();
+        let packs = guard.get_or_insert_with(Packs::default);
+        if packs
+            .names
+            .iter()
+            .any(|name| !names.contains(name.as_ref()))
+        {
+            debug!("Some packs were removed; reread all pack indexes");
+            *packs = Packs::default();
+        }
+        for name in names {
+            if packs.names.contains(name.as_str()) {
+                continue;
+            }
+            match self.read_pack_index(&name) {
+                Ok(index) => packs.add(&name, index),
+                Err(err) => warn!("Failed to read block pack {name}: {err}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the index from the end of a pack.
+    fn read_pack_index(&self, name: &str) -> Result<PackIndex> {
+        let relpath = pack_relpath(name);
+        let damaged = |details: String| Error::PackDamaged {
+            name: name.to_owned(),
+            details,
+        };
+        let pack_len = self.transport.metadata(&relpath)?.len;
+        let Some(trailer_start) = pack_len.checked_sub(PACK_TRAILER_LEN) else {
+            return Err(damaged(format!("Pack is only {pack_len} bytes long")));
+        };
+        let trailer = self
+            .transport
+            .read_range(&relpath, trailer_start, PACK_TRAILER_LEN)?;
+        let index_len = u64::from_le_bytes(trailer[..].try_into().expect("Trailer is 8 bytes"));
+        let Some(index_start) = trailer_start.checked_sub(index_len) else {
+            return Err(damaged(format!(
+                "Index length {index_len} is longer than the pack"
+            )));
+        };
+        let index_json = self
+            .transport
+            .read_range(&relpath, index_start, index_len)?;
+        let index: PackIndex =
+            serde_json::from_slice(&index_json).map_err(|source| Error::DeserializeJson {
+                path: relpath.clone(),
+                source,
+            })?;
+        if let Some(entry) = index.blocks.iter().find(|entry| {
+            entry
+                .start
+                .checked_add(entry.len)
+                .map_or(true, |end| end > index_start)
+        }) {
+            return Err(damaged(format!(
+                "Block {} extends past the end of the packed blocks",
+                entry.hash
+            )));
+        }
+        Ok(index)
+    }
+
+    /// Write a new pack holding the given compressed blocks, and return its name.
+    ///
+    /// Packs are named by the hash of their content.
+    pub(crate) fn write_pack(&self, blocks: &[(BlockHash, Bytes)]) -> Result<String> {
+        let mut content = Vec::new();
+        let mut index = PackIndex::default();
+        for (hash, compressed) in blocks {
+            index.blocks.push(PackEntry {
+                hash: hash.clone(),
+                start: content.len() as u64,
+                len: compressed.len() as u64,
+            });
+            content.extend_from_slice(compressed);
+        }
+        let index_json = serde_json::to_vec(&index)?;
+        content.extend_from_slice(&index_json);
+        content.extend_from_slice(&(index_json.len() as u64).to_le_bytes());
+        let name = BlockHash::hash_bytes(&content).to_string();
+        self.transport.create_dir(PACK_DIR)?;
+        self.transport.write_file(&pack_relpath(&name), &content)?;
+        debug!(%name, blocks = blocks.len(), len = content.len(), "Wrote pack");
+        if let Some(packs) = self.packs.write().unwrap().as_mut() {
+            packs.add(&name, index);
+        }
+        Ok(name)
+    }
+
+    /// Remove some blocks from a pack, by writing a new pack holding the other blocks,
+    /// if there are any, and then removing the old pack.
+    ///
+    /// Returns the number of blocks that were removed.
+    pub(crate) fn remove_from_pack(
+        &self,
+        pack: &str,
+        remove: &HashSet<BlockHash>,
+    ) -> Result<usize> {
+        let relpath = pack_relpath(pack);
+        let (removed, kept): (Vec<PackEntry>, Vec<PackEntry>) = self
+            .read_pack_index(pack)?
+            .blocks
+            .into_iter()
+            .partition(|entry| remove.contains(&entry.hash));
+        if removed.is_empty() {
+            return Ok(0);
+        }
+        if !kept.is_empty() {
+            let content = self.transport.read_file(&relpath)?;
+            let blocks: Vec<(BlockHash, Bytes)> = kept
+                .into_iter()
+                .map(|entry| {
+                    let range = entry.start as usize..(entry.start + entry.len) as usize;
+                    (entry.hash, content.slice(range))
+                })
+                .collect();
+            self.write_pack(&blocks)?;
+        }
+        self.transport.remove_file(&relpath)?;
+        // Forget all the packs, so that they're reread if needed.
+        *self.packs.write().unwrap() = None;
+        for entry in &removed {
+            self.cache.remove(&entry.hash);
+            self.exists.write().unwrap().pop(&entry.hash);
+        }
+        Ok(removed.len())
+    }
+
     /// Return an iterator of block subdirectories, in arbitrary order.
     ///
     /// Errors, other than failure to open the directory at all, are logged and discarded.
     fn subdirs(&self) -> Result<Vec<String>> {
         let ListDir { mut dirs, .. } = self.transport.list_dir("")?;
         dirs.retain(|dirname| {
-            if dirname.len() == SUBDIR_NAME_CHARS {
+            if dirname == PACK_DIR {
+                false
+            } else if dirname.len() == SUBDIR_NAME_CHARS {
                 true
             } else {
                 warn!("Unexpected subdirectory in blockdir: {dirname:?}");
@@ -264,10 +838,24 @@ impl BlockDir {
         Ok(dirs)
     }
 
-    /// Return all the blocknames in the blockdir, in arbitrary order.
+    /// Return all the blocknames in the blockdir, whether they're packed or in their own
+    /// files, in arbitrary order.
     pub fn blocks(
         &self,
         monitor: Arc<dyn Monitor>,
+    ) -> Result<impl ParallelIterator<Item = BlockHash>> {
+        let packed = self.packed_blocks()?;
+        let packed_list: Vec<BlockHash> = packed.iter().cloned().collect();
+        Ok(self
+            .loose_blocks(monitor)?
+            .filter(move |hash| !packed.contains(hash))
+            .chain(packed_list))
+    }
+
+    /// Return the names of the blocks stored in their own files, in arbitrary order.
+    pub(crate) fn loose_blocks(
+        &self,
+        monitor: Arc<dyn Monitor>,
     ) -> Result<impl ParallelIterator<Item = BlockHash>> {
         let transport = self.transport.clone();
         let task = monitor.start_task("List block subdir".to_string());
@@ -297,22 +885,67 @@ impl BlockDir {
     ///
     /// Return a dict describing which blocks are present, and the length of their uncompressed
     /// data.
+    ///
+    /// Blocks are hashed in parallel on the rayon thread pool. Their content is
+    /// not kept in the cache, so memory use is bounded by the number of threads,
+    /// rather than growing with the size of the archive.
     pub fn validate(&self, monitor: Arc<dyn Monitor>) -> Result<HashMap<BlockHash, usize>> {
+        let (block_lens, _unread) = self.validate_new_blocks(&HashMap::new(), |_| true, monitor)?;
+        Ok(block_lens)
+    }
+
+    /// Check the content of the blocks chosen by `select`, except for those in `known`,
+    /// which are assumed to be valid and to have the given uncompressed length if they're
+    /// still present.
+    ///
+    /// Return a dict of the uncompressed length of the blocks that are known or were
+    /// found to be valid, and the set of present blocks that weren't read.
+    pub(crate) fn validate_new_blocks<F>(
+        &self,
+        known: &HashMap<BlockHash, usize>,
+        select: F,
+        monitor: Arc<dyn Monitor>,
+    ) -> Result<(HashMap<BlockHash, usize>, HashSet<BlockHash>)>
+    where
+        F: Fn(&BlockHash) -> bool,
+    {
         // TODO: In the top-level directory, no files or directories other than prefix
         // directories of the right length.
         // TODO: Test having a block with the right compression but the wrong contents.
         // TODO: Warn on blocks in the wrong subdir.
         debug!("Start list blocks");
-        let blocks = self
-            .blocks(monitor.clone())?
-            .collect::<HashSet<BlockHash>>();
-        debug!("Check {} blocks", blocks.len());
+        let mut known_present = HashMap::new();
+        let mut unread = HashSet::new();
+        let mut blocks = Vec::new();
+        for hash in self.blocks(monitor.clone())?.collect::<Vec<BlockHash>>() {
+            if let Some(&len) = known.get(&hash) {
+                known_present.insert(hash, len);
+            } else if select(&hash) {
+                blocks.push(hash);
+            } else {
+                unread.insert(hash);
+            }
+        }
+        debug!(
+            "Check {} blocks, skipping {} already validated and {} not selected",
+            blocks.len(),
+            known_present.len(),
+            unread.len(),
+        );
+        let span = debug_span!(
+            "validate_blocks",
+            blocks = blocks.len(),
+            skipped_blocks = known_present.len() + unread.len(),
+            bytes = tracing::field::Empty,
+        )
+        .entered();
         let task = monitor.start_task("Validate blocks".to_string());
         task.set_total(blocks.len());
-        let block_lens = blocks
+        let mut block_lens: HashMap<BlockHash, usize> = blocks
             .into_par_iter()
+            .filter(|_| !monitor.should_cancel())
             .flat_map(
-                |hash| match self.get_block_content(&hash, monitor.clone()) {
+                |hash| match self.read_block_uncached(&hash, monitor.clone()) {
                     Ok(bytes) => {
                         task.increment(1);
                         Some((hash, bytes.len()))
@@ -324,11 +957,14 @@ impl BlockDir {
                 },
             )
             .collect();
-        Ok(block_lens)
+        check_cancel(monitor.as_ref())?;
+        span.record("bytes", block_lens.values().sum::<usize>());
+        block_lens.extend(known_present);
+        Ok((block_lens, unread))
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Serialize)]
 pub struct BlockDirStats {
     pub read_blocks: AtomicUsize,
     pub read_block_compressed_bytes: AtomicUsize,
@@ -347,6 +983,41 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn block_is_written_again_if_the_write_in_progress_fails() {
+        let tempdir = TempDir::new().unwrap();
+        let blockdir = BlockDir::open(open_local_transport(tempdir.path()).unwrap());
+        let content = Bytes::from("stuff");
+        let hash = BlockHash::hash_bytes(&content);
+        // Another thread claims the block, and then fails to write it.
+        blockdir.writing.lock().unwrap().insert(hash.clone());
+        let stats = std::thread::scope(|scope| {
+            let storing = scope.spawn(|| {
+                let mut stats = BackupStats::default();
+                blockdir
+                    .store_or_deduplicate(
+                        content.clone(),
+                        Compression::default(),
+                        None,
+                        &mut stats,
+                        TestMonitor::arc(),
+                    )
+                    .unwrap();
+                stats
+            });
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            assert!(
+                !storing.is_finished(),
+                "store waits for the write in progress"
+            );
+            blockdir.release_claim(&hash);
+            storing.join().unwrap()
+        });
+        assert_eq!(stats.written_blocks, 1);
+        assert_eq!(stats.deduplicated_blocks, 0);
+        assert!(blockdir.contains(&hash, TestMonitor::arc()).unwrap());
+    }
+
     #[test]
     fn empty_block_file_counts_as_not_present() {
         // Due to an interruption or system crash we might end up with a block
@@ -357,7 +1028,13 @@ mod test {
         let mut stats = BackupStats::default();
         let monitor = TestMonitor::arc();
         let hash = blockdir
-            .store_or_deduplicate(Bytes::from("stuff"), &mut stats, monitor.clone())
+            .store_or_deduplicate(
+                Bytes::from("stuff"),
+                Compression::default(),
+                None,
+                &mut stats,
+                monitor.clone(),
+            )
             .unwrap();
         assert_eq!(monitor.get_counter(Counter::BlockWrites), 1);
         assert_eq!(monitor.get_counter(Counter::DeduplicatedBlocks), 0);
@@ -406,7 +1083,13 @@ mod test {
         let mut stats = BackupStats::default();
         let content = Bytes::from("stuff");
         let hash = blockdir
-            .store_or_deduplicate(content.clone(), &mut stats, TestMonitor::arc())
+            .store_or_deduplicate(
+                content.clone(),
+                Compression::default(),
+                None,
+                &mut stats,
+                TestMonitor::arc(),
+            )
             .unwrap();
         assert_eq!(blockdir.stats.cache_hit.load(Relaxed), 0);
 
@@ -429,6 +1112,104 @@ mod test {
         assert_eq!(blockdir.stats.cache_hit.load(Relaxed), 3); // hit again
     }
 
+    #[test]
+    fn validate_does_not_fill_cache() {
+        let tempdir = TempDir::new().unwrap();
+        let blockdir = BlockDir::open(open_local_transport(tempdir.path()).unwrap());
+        let mut stats = BackupStats::default();
+        let content = Bytes::from("stuff");
+        let hash = blockdir
+            .store_or_deduplicate(
+                content.clone(),
+                Compression::default(),
+                None,
+                &mut stats,
+                TestMonitor::arc(),
+            )
+            .unwrap();
+
+        // reopen
+        let blockdir = BlockDir::open(open_local_transport(tempdir.path()).unwrap());
+        let monitor = TestMonitor::arc();
+        let block_lens = blockdir.validate(monitor.clone()).unwrap();
+        assert_eq!(block_lens.get(&hash), Some(&content.len()));
+        assert_eq!(monitor.get_counter(Counter::BlockReads), 1);
+        assert_eq!(blockdir.cache_usage(), (0, 0));
+    }
+
+    /// Store some blocks and return their hashes and compressed content.
+    fn store_blocks(blockdir: &BlockDir, contents: &[&str]) -> Vec<(BlockHash, Bytes)> {
+        contents
+            .iter()
+            .map(|content| {
+                let hash = blockdir
+                    .store_or_deduplicate(
+                        Bytes::copy_from_slice(content.as_bytes()),
+                        Compression::default(),
+                        None,
+                        &mut BackupStats::default(),
+                        TestMonitor::arc(),
+                    )
+                    .unwrap();
+                let compressed = blockdir.read_block_file_checked(&hash).unwrap();
+                (hash, compressed)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn blocks_packed_by_another_process_are_found() {
+        let tempdir = TempDir::new().unwrap();
+        let blockdir = BlockDir::open(open_local_transport(tempdir.path()).unwrap());
+        let blocks = store_blocks(&blockdir, &["one", "two"]);
+        let reader = BlockDir::open(open_local_transport(tempdir.path()).unwrap());
+        assert!(reader.packed_blocks().unwrap().is_empty());
+
+        blockdir.write_pack(&blocks).unwrap();
+        for (hash, _) in &blocks {
+            blockdir.delete_block(hash).unwrap();
+        }
+        let monitor = TestMonitor::arc();
+        assert_eq!(
+            reader
+                .get_block_content(&blocks[1].0, monitor.clone())
+                .unwrap(),
+            "two"
+        );
+        assert_eq!(
+            reader.compressed_size(&blocks[0].0).unwrap(),
+            blocks[0].1.len() as u64
+        );
+        assert_eq!(reader.blocks(monitor).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn damaged_pack_is_skipped() {
+        let tempdir = TempDir::new().unwrap();
+        let blockdir = BlockDir::open(open_local_transport(tempdir.path()).unwrap());
+        let blocks = store_blocks(&blockdir, &["one", "two"]);
+        let name = blockdir.write_pack(&blocks).unwrap();
+        for (hash, _) in &blocks {
+            blockdir.delete_block(hash).unwrap();
+        }
+        write(
+            tempdir.path().join(pack_relpath(&name)),
+            b"\xff\xff\xff\xff\xff\xff\xff\xff",
+        )
+        .unwrap();
+
+        let blockdir = BlockDir::open(open_local_transport(tempdir.path()).unwrap());
+        assert!(matches!(
+            blockdir.read_pack_index(&name),
+            Err(Error::PackDamaged { .. })
+        ));
+        assert!(!blockdir.contains(&blocks[0].0, TestMonitor::arc()).unwrap());
+        assert!(matches!(
+            blockdir.check_block_file(&blocks[0].0),
+            Err(Error::BlockMissing { .. })
+        ));
+    }
+
     #[test]
     fn existence_cache_hit() {
         let tempdir = TempDir::new().unwrap();
@@ -437,7 +1218,13 @@ mod test {
         let content = Bytes::from("stuff");
         let monitor = TestMonitor::arc();
         let hash = blockdir
-            .store_or_deduplicate(content.clone(), &mut stats, monitor.clone())
+            .store_or_deduplicate(
+                content.clone(),
+                Compression::default(),
+                None,
+                &mut stats,
+                monitor.clone(),
+            )
             .unwrap();
 
         // reopen
@@ -24,6 +24,9 @@ use serde::{Deserialize, Serialize};
 
 use crate::*;
 
+/// Name of the algorithm used to hash blocks, as recorded in band heads.
+pub const BLOCK_HASH_ALGORITHM: &str = "blake2b-512";
+
 /// The hash of a block of body data.
 ///
 /// Stored in memory as compact bytes, but translatable to and from
@@ -40,6 +43,18 @@ impl BlockHash {
     pub fn hash_bytes(bytes: &[u8]) -> Self {
         BlockHash::from(blake2b(BLAKE_HASH_SIZE_BYTES, &[], bytes))
     }
+
+    /// The binary form of the hash.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.bin
+    }
+
+    /// Make a hash from its binary form, or None if it's the wrong length.
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<BlockHash> {
+        Some(BlockHash {
+            bin: bytes.try_into().ok()?,
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -113,7 +128,7 @@ impl Ord for BlockHash {
 
 impl PartialOrd for BlockHash {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.bin.cmp(&other.bin))
+        Some(self.cmp(other))
     }
 }
 
@@ -0,0 +1,159 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Linux file capabilities, such as `cap_net_raw` on `ping`.
+//!
+//! Capabilities are stored in the `security.capability` extended attribute. They're
+//! recorded in the index as the hex encoding of the attribute value, so that they're
+//! restored exactly, even though other extended attributes are not recorded.
+//!
+//! The kernel clears a file's capabilities when its owner or content changes, so
+//! they're restored after both.
+
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// Name of the extended attribute holding file capabilities.
+#[cfg(target_os = "linux")]
+const XATTR_NAME: &str = "security.capability";
+
+/// Mask for the revision in the first word of the attribute value.
+const REVISION_MASK: u32 = 0xFF00_0000;
+
+/// The value of the `security.capability` extended attribute of a file.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Capability {
+    value: Vec<u8>,
+}
+
+impl Capability {
+    /// Make a capability from the value of the extended attribute, checking that it
+    /// has the length expected for its revision.
+    pub fn from_xattr(value: &[u8]) -> Result<Capability> {
+        let invalid = || Error::InvalidCapability {
+            capability: hex::encode(value),
+        };
+        let magic = u32::from_le_bytes(value.get(..4).ok_or_else(invalid)?.try_into().unwrap());
+        let expected_len = match magic & REVISION_MASK {
+            0x0100_0000 => 12,
+            0x0200_0000 => 20,
+            0x0300_0000 => 24,
+            _ => return Err(invalid()),
+        };
+        if value.len() != expected_len {
+            return Err(invalid());
+        }
+        Ok(Capability {
+            value: value.to_owned(),
+        })
+    }
+
+    /// The value of the extended attribute.
+    pub fn as_xattr(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// Read the capabilities of a file, if it has any.
+    ///
+    /// Returns None on platforms and filesystems that don't support them.
+    pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Option<Capability>> {
+        #[cfg(target_os = "linux")]
+        {
+            match xattr::get(path.as_ref(), XATTR_NAME) {
+                Ok(Some(value)) => Capability::from_xattr(&value)
+                    .map(Some)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+                Ok(None) => Ok(None),
+                Err(err) if is_unsupported(&err) => Ok(None),
+                Err(err) => Err(err),
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = path;
+            Ok(None)
+        }
+    }
+
+    /// Set the capabilities of a file.
+    ///
+    /// Setting capabilities typically needs root. Fails with
+    /// [io::ErrorKind::Unsupported] if they can't be set on this platform or
+    /// filesystem.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            xattr::set(path.as_ref(), XATTR_NAME, &self.value).map_err(|err| {
+                if is_unsupported(&err) {
+                    io::ErrorKind::Unsupported.into()
+                } else {
+                    err
+                }
+            })
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = path;
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_unsupported(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(nix::errno::Errno::EOPNOTSUPP as i32)
+        || err.kind() == io::ErrorKind::Unsupported
+}
+
+impl From<Capability> for String {
+    fn from(capability: Capability) -> String {
+        hex::encode(capability.value)
+    }
+}
+
+impl TryFrom<String> for Capability {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Capability> {
+        let value = hex::decode(&s).map_err(|_| Error::InvalidCapability { capability: s })?;
+        Capability::from_xattr(&value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// `cap_net_raw=ep`, as set on `ping`.
+    const CAP_NET_RAW: &str = "0100000200200000000000000000000000000000";
+
+    #[test]
+    fn hex_round_trip() {
+        let capability = Capability::try_from(CAP_NET_RAW.to_owned()).unwrap();
+        assert_eq!(capability.as_xattr().len(), 20);
+        assert_eq!(String::from(capability), CAP_NET_RAW);
+    }
+
+    #[test]
+    fn invalid_capabilities() {
+        assert!(Capability::from_xattr(b"").is_err());
+        assert!(Capability::from_xattr(b"\x00\x00\x00\x02").is_err());
+        // Revision 2 with a revision 1 length.
+        assert!(Capability::from_xattr(&[0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+        assert!(Capability::try_from("not hex".to_owned()).is_err());
+    }
+}
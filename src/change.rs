@@ -18,7 +18,7 @@ use std::fmt;
 use serde::Serialize;
 use time::OffsetDateTime;
 
-use crate::{Apath, EntryTrait, Kind, Owner, Result, UnixMode};
+use crate::{Apath, DeviceNumber, EntryTrait, Kind, Owner, Result, UnixMode};
 
 /// Summary of some kind of change to an entry from backup, diff, restore, etc.
 #[derive(Debug, Clone, Eq, PartialEq, Serialize)]
@@ -30,17 +30,7 @@ pub struct EntryChange {
 
 impl EntryChange {
     pub(crate) fn diff_metadata<AE: EntryTrait, BE: EntryTrait>(a: &AE, b: &BE) -> Self {
-        debug_assert_eq!(a.apath(), b.apath());
-        let ak = a.kind();
-        // mtime is only treated as a significant change for files, because
-        // the behavior on directories is not consistent between Unix and
-        // Windows (and maybe not across filesystems even on Unix.)
-        if ak != b.kind()
-            || a.owner() != b.owner()
-            || a.unix_mode() != b.unix_mode()
-            || (ak == Kind::File && (a.size() != b.size() || a.mtime() != b.mtime()))
-            || (ak == Kind::Symlink && (a.symlink_target() != b.symlink_target()))
-        {
+        if metadata_differs(a, b) {
             EntryChange::changed(a, b)
         } else {
             EntryChange::unchanged(a)
@@ -87,6 +77,25 @@ impl EntryChange {
     }
 }
 
+/// True if the metadata of two entries for the same apath differs in a way
+/// that counts as a change for diff and backup.
+pub(crate) fn metadata_differs<AE: EntryTrait, BE: EntryTrait>(a: &AE, b: &BE) -> bool {
+    debug_assert_eq!(a.apath(), b.apath());
+    let ak = a.kind();
+    // mtime is only treated as a significant change for files, because
+    // the behavior on directories is not consistent between Unix and
+    // Windows (and maybe not across filesystems even on Unix.)
+    ak != b.kind()
+        || !a.owner().matches(b.owner())
+        || a.unix_mode() != b.unix_mode()
+        || a.acls() != b.acls()
+        || a.capability() != b.capability()
+        || a.link_group() != b.link_group()
+        || a.device() != b.device()
+        || (ak == Kind::File && (a.size() != b.size() || a.mtime() != b.mtime()))
+        || (ak == Kind::Symlink && (a.symlink_target() != b.symlink_target()))
+}
+
 impl fmt::Display for EntryChange {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{} {}", self.change.sigil(), self.apath)
@@ -161,6 +170,10 @@ pub enum KindMetadata {
     File { size: u64 },
     Dir,
     Symlink { target: String },
+    CharDevice { device: DeviceNumber },
+    BlockDevice { device: DeviceNumber },
+    Fifo,
+    Socket,
 }
 
 impl From<&dyn EntryTrait> for KindMetadata {
@@ -173,6 +186,14 @@ impl From<&dyn EntryTrait> for KindMetadata {
             Kind::Symlink => KindMetadata::Symlink {
                 target: entry.symlink_target().unwrap().to_owned(),
             },
+            Kind::CharDevice => KindMetadata::CharDevice {
+                device: entry.device().unwrap(),
+            },
+            Kind::BlockDevice => KindMetadata::BlockDevice {
+                device: entry.device().unwrap(),
+            },
+            Kind::Fifo => KindMetadata::Fifo,
+            Kind::Socket => KindMetadata::Socket,
             Kind::Unknown => panic!("unexpected Kind::Unknown on {:?}", entry.apath()),
         }
     }
@@ -0,0 +1,221 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A local cache of the files stored by the last backup, kept outside the archive.
+//!
+//! The cache records the size, mtime, inode, and block addresses of each file
+//! in the band written by the backup that wrote the cache. The next backup can
+//! then recognize unchanged files without reading the basis index from the archive,
+//! or checking that its blocks are still present, which can be slow on remote
+//! archives.
+//!
+//! The cache is only used if it describes the last band in the archive, and that
+//! band is complete; otherwise the backup falls back to the basis index.
+//!
+//! The cache is a text file with a JSON header line, followed by one JSON object
+//! per line for each file, in apath order.
+
+use std::cmp::Ordering;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Lines, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::*;
+
+/// Format version of the cache file, incremented for incompatible changes.
+const CACHE_VERSION: u32 = 1;
+
+/// First line of the cache file, identifying the band it describes.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct Header {
+    conserve_change_cache: u32,
+    band_id: String,
+    /// Seconds since the Unix epoch when the band was started, to distinguish
+    /// bands with the same id in different archives.
+    band_start_time: i64,
+}
+
+impl Header {
+    fn for_band(band: &Band) -> Result<Header> {
+        Ok(Header {
+            conserve_change_cache: CACHE_VERSION,
+            band_id: band.id().to_string(),
+            band_start_time: band.get_info()?.start_time.unix_timestamp(),
+        })
+    }
+}
+
+/// A file recorded in the cache.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CachedEntry {
+    #[serde(flatten)]
+    pub entry: IndexEntry,
+
+    /// The inode number of the source file, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inode: Option<u64>,
+}
+
+/// Read a change cache, in apath order.
+pub(crate) struct ChangeCacheReader {
+    path: PathBuf,
+    lines: Lines<BufReader<File>>,
+    next: Option<CachedEntry>,
+}
+
+impl ChangeCacheReader {
+    /// Open a cache file, if it exists and describes this complete band.
+    ///
+    /// Problems reading the cache are logged and treated as if there is no cache,
+    /// since the backup can still proceed from the basis index.
+    pub(crate) fn open(path: &Path, band: &Band) -> Option<ChangeCacheReader> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                debug!(?path, "Change cache does not exist yet");
+                return None;
+            }
+            Err(err) => {
+                warn!(?path, ?err, "Failed to open change cache");
+                return None;
+            }
+        };
+        let mut lines = BufReader::new(file).lines();
+        let header: Header = match lines.next().map(|line| {
+            line.map_err(Error::from)
+                .and_then(|line| serde_json::from_str(&line).map_err(Error::from))
+        }) {
+            Some(Ok(header)) => header,
+            Some(Err(err)) => {
+                warn!(?path, ?err, "Failed to read change cache header");
+                return None;
+            }
+            None => {
+                warn!(?path, "Change cache is empty");
+                return None;
+            }
+        };
+        match (Header::for_band(band), band.is_closed()) {
+            (Ok(band_header), Ok(true)) if band_header == header => {}
+            (Ok(_), Ok(true)) => {
+                debug!(
+                    ?path,
+                    cache_band_id = header.band_id,
+                    "Change cache is for a different band"
+                );
+                return None;
+            }
+            (Ok(_), Ok(false)) => {
+                debug!(
+                    ?path,
+                    "Change cache is not used because the last band is incomplete"
+                );
+                return None;
+            }
+            (Err(err), _) | (_, Err(err)) => {
+                warn!(?err, "Failed to read basis band info");
+                return None;
+            }
+        }
+        debug!(?path, band_id = header.band_id, "Using change cache");
+        Some(ChangeCacheReader {
+            path: path.to_owned(),
+            lines,
+            next: None,
+        })
+    }
+
+    /// Return the cached entry for this apath, if there is one.
+    ///
+    /// As for [index::IndexEntryIter::advance_to], entries before this apath are
+    /// discarded, so apaths must be requested in order.
+    pub(crate) fn advance_to(&mut self, apath: &Apath) -> Option<CachedEntry> {
+        loop {
+            if self.next.is_none() {
+                self.next = self.read_next();
+            }
+            let cand = self.next.as_ref()?;
+            match cand.entry.apath.cmp(apath) {
+                Ordering::Less => self.next = None,
+                Ordering::Equal => return self.next.take(),
+                Ordering::Greater => return None,
+            }
+        }
+    }
+
+    fn read_next(&mut self) -> Option<CachedEntry> {
+        let line = match self.lines.next()? {
+            Ok(line) => line,
+            Err(err) => {
+                warn!(path = ?self.path, ?err, "Failed to read change cache");
+                return None;
+            }
+        };
+        match serde_json::from_str(&line) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                warn!(path = ?self.path, ?err, "Failed to parse change cache entry");
+                None
+            }
+        }
+    }
+}
+
+/// Write a new change cache describing the band being written.
+///
+/// The cache is written to a temporary file, and only replaces the old cache
+/// when the backup is complete.
+pub(crate) struct ChangeCacheWriter {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    file: BufWriter<File>,
+}
+
+impl ChangeCacheWriter {
+    pub(crate) fn create(path: &Path, band: &Band) -> Result<ChangeCacheWriter> {
+        let mut tmp_path: OsString = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut file = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut file, &Header::for_band(band)?)?;
+        file.write_all(b"\n")?;
+        Ok(ChangeCacheWriter {
+            path: path.to_owned(),
+            tmp_path,
+            file,
+        })
+    }
+
+    /// Write entries for files, which must come after any previously written
+    /// entries in apath order.
+    pub(crate) fn write_entries<'a>(
+        &mut self,
+        entries: impl IntoIterator<Item = &'a CachedEntry>,
+    ) -> Result<()> {
+        for entry in entries {
+            serde_json::to_writer(&mut self.file, entry)?;
+            self.file.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// Replace the old cache with the newly written one.
+    pub(crate) fn finish(self) -> io::Result<()> {
+        let file = self.file.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        fs::rename(&self.tmp_path, &self.path)
+    }
+}
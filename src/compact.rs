@@ -0,0 +1,223 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Gather small blocks into packs, so there are fewer files to list and read.
+//!
+//! Packed blocks are still identified by their hash, so the indexes don't need to
+//! change. Before any blocks are packed, every band is marked with the
+//! [band::flags::PACKED_BLOCKS] format flag, as are bands written later, so that
+//! versions of Conserve that can't read packs refuse to open them, rather than
+//! reporting the packed blocks as missing.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
+use rayon::prelude::*;
+use tracing::debug;
+
+use crate::monitor::Monitor;
+use crate::stats::CompactStats;
+use crate::*;
+
+/// Options for [compact].
+#[derive(Debug, Clone)]
+pub struct CompactOptions {
+    /// Pack blocks whose compressed size is smaller than this.
+    pub max_block_size: u64,
+
+    /// Start a new pack when adding another block would make it larger than this.
+    pub pack_size: u64,
+
+    /// Report what would be packed, without changing the archive.
+    pub dry_run: bool,
+
+    /// Break a lock left behind by a previous interrupted gc or compact operation.
+    pub break_lock: bool,
+}
+
+impl Default for CompactOptions {
+    fn default() -> Self {
+        CompactOptions {
+            max_block_size: 1 << 20,
+            pack_size: 16 << 20,
+            dry_run: false,
+            break_lock: false,
+        }
+    }
+}
+
+/// Gather small blocks that are stored in their own files into packs.
+///
+/// Each block is checked before it's packed, and its file is deleted once the pack
+/// is written. Files left behind for blocks that are already packed, for example
+/// by an interrupted compaction, are deleted if the packed copy is intact.
+///
+/// This takes the garbage collection lock, so it can't run concurrently with a
+/// backup or gc.
+pub fn compact(
+    archive: &Archive,
+    options: &CompactOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<CompactStats> {
+    let start = Instant::now();
+    let mut stats = CompactStats::default();
+    let _lock = if options.break_lock {
+        GarbageCollectionLock::break_lock(archive)?
+    } else {
+        GarbageCollectionLock::new(archive)?
+    };
+    debug!("Got gc lock");
+
+    let block_dir = archive.block_dir();
+    let packed = block_dir.packed_blocks()?;
+    let (duplicates, loose): (Vec<BlockHash>, Vec<BlockHash>) = block_dir
+        .loose_blocks(monitor.clone())?
+        .partition(|hash| packed.contains(hash));
+    stats.loose_blocks = loose.len() + duplicates.len();
+    for hash in duplicates {
+        match block_dir.check_packed_block(&hash) {
+            Ok(()) if options.dry_run => stats.removed_duplicates += 1,
+            Ok(()) => match block_dir.delete_block(&hash) {
+                Ok(()) => stats.removed_duplicates += 1,
+                Err(err) => {
+                    monitor.error(err);
+                    stats.errors += 1;
+                }
+            },
+            Err(err) => {
+                monitor.error(err);
+                stats.errors += 1;
+            }
+        }
+    }
+
+    let task = monitor.start_task("Measure blocks".to_string());
+    task.set_total(loose.len());
+    let sizes: Vec<(BlockHash, Result<u64>)> = loose
+        .into_par_iter()
+        .map(|hash| {
+            let size = block_dir.compressed_size(&hash);
+            task.increment(1);
+            (hash, size)
+        })
+        .collect();
+    drop(task);
+    let mut small = Vec::new();
+    for (hash, size) in sizes {
+        match size {
+            Ok(size) if size > 0 && size < options.max_block_size => small.push((hash, size)),
+            Ok(_) => (),
+            Err(err) => {
+                monitor.error(err);
+                stats.errors += 1;
+            }
+        }
+    }
+    small.sort();
+
+    let mut groups: Vec<Vec<(BlockHash, u64)>> = Vec::new();
+    let mut group_size = 0;
+    for (hash, size) in small {
+        match groups.last_mut() {
+            Some(group) if group_size + size <= options.pack_size => group.push((hash, size)),
+            _ => {
+                groups.push(vec![(hash, size)]);
+                group_size = 0;
+            }
+        }
+        group_size += size;
+    }
+    // There's no point packing a single block.
+    groups.retain(|group| group.len() > 1);
+    debug!(packs = groups.len(), "Grouped small blocks into packs");
+    stats.packs_to_write = groups.len();
+    stats.packable_blocks = groups.iter().map(Vec::len).sum();
+    stats.packable_bytes = groups.iter().flatten().map(|(_, size)| size).sum();
+    if options.dry_run {
+        stats.elapsed = start.elapsed();
+        return Ok(stats);
+    }
+    if !groups.is_empty() {
+        flag_bands(archive, &mut stats, monitor.clone())?;
+    }
+
+    let task = monitor.start_task("Pack blocks".to_string());
+    task.set_total(groups.len());
+    stats += groups
+        .par_iter()
+        .map(|group| {
+            let group_stats = pack_group(block_dir, group, monitor.clone());
+            task.increment(1);
+            group_stats
+        })
+        .reduce(CompactStats::default, |a, b| a + b);
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
+
+/// Mark every band as possibly referring to packed blocks.
+///
+/// Bands whose head can't be read are reported and left unchanged: they can't be
+/// read by any version anyway.
+fn flag_bands(
+    archive: &Archive,
+    stats: &mut CompactStats,
+    monitor: Arc<dyn Monitor>,
+) -> Result<()> {
+    for band_id in archive.list_band_ids()? {
+        if let Err(err) = Band::open(archive, band_id)
+            .and_then(|mut band| band.add_format_flag(band::flags::PACKED_BLOCKS))
+        {
+            monitor.error(err);
+            stats.errors += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Write one pack holding the blocks in a group, and then delete their files.
+fn pack_group(
+    block_dir: &BlockDir,
+    group: &[(BlockHash, u64)],
+    monitor: Arc<dyn Monitor>,
+) -> CompactStats {
+    let mut stats = CompactStats::default();
+    let mut blocks: Vec<(BlockHash, Bytes)> = Vec::new();
+    for (hash, _size) in group {
+        match block_dir.read_block_file_checked(hash) {
+            Ok(compressed) => blocks.push((hash.clone(), compressed)),
+            Err(err) => {
+                monitor.error(err);
+                stats.errors += 1;
+            }
+        }
+    }
+    if blocks.len() < 2 {
+        return stats;
+    }
+    if let Err(err) = block_dir.write_pack(&blocks) {
+        monitor.error(err);
+        stats.errors += 1;
+        return stats;
+    }
+    stats.packs_written = 1;
+    stats.packed_blocks = blocks.len();
+    stats.packed_bytes = blocks.iter().map(|(_, bytes)| bytes.len() as u64).sum();
+    for (hash, _) in &blocks {
+        if let Err(err) = block_dir.delete_block(hash) {
+            monitor.error(err);
+            stats.errors += 1;
+        }
+    }
+    stats
+}
@@ -0,0 +1,83 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! LZ4 compression glue.
+//!
+//! Data is stored as an LZ4 block prefixed by its uncompressed length as a
+//! little-endian u32.
+
+use bytes::Bytes;
+use lz4_flex::block::DecompressError;
+
+use super::MAX_BLOCK_SIZE;
+use crate::{Error, Result};
+
+/// Length of the uncompressed length prefix.
+const PREFIX_LEN: usize = 4;
+
+/// Compress bytes into a length-prefixed LZ4 block.
+pub(crate) fn compress(input: &[u8]) -> Bytes {
+    lz4_flex::block::compress_prepend_size(input).into()
+}
+
+/// Decompress a length-prefixed LZ4 block.
+///
+/// The length is checked before any memory is allocated for the output, so that a
+/// damaged prefix can't cause a huge allocation.
+pub(crate) fn decompress(input: &[u8]) -> Result<Bytes> {
+    if input.len() < PREFIX_LEN {
+        return Err(Error::Lz4 {
+            source: DecompressError::ExpectedAnotherByte,
+        });
+    }
+    let (prefix, compressed) = input.split_at(PREFIX_LEN);
+    let len = u32::from_le_bytes(prefix.try_into().expect("prefix has four bytes")) as usize;
+    if len > MAX_BLOCK_SIZE {
+        return Err(Error::BlockTooLarge { len });
+    }
+    lz4_flex::block::decompress(compressed, len)
+        .map(Bytes::from)
+        .map_err(|source| Error::Lz4 { source })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let data = b"hello world ".repeat(100);
+        assert_eq!(decompress(&compress(&data)).unwrap(), data);
+        assert_eq!(decompress(&compress(b"")).unwrap(), b"".as_slice());
+    }
+
+    #[test]
+    fn oversized_length_prefix_is_an_error() {
+        let mut block = compress(b"hello").to_vec();
+        block[..PREFIX_LEN].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = decompress(&block).unwrap_err();
+        assert!(
+            matches!(err, Error::BlockTooLarge { len } if len == u32::MAX as usize),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn truncated_block_is_an_error() {
+        assert!(matches!(decompress(b"\x05\x00"), Err(Error::Lz4 { .. })));
+        let block = compress(&b"hello world ".repeat(100));
+        assert!(matches!(
+            decompress(&block[..block.len() - 1]),
+            Err(Error::Lz4 { .. })
+        ));
+    }
+}
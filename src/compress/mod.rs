@@ -1,4 +1,4 @@
-// Copyright 2017, 2019, 2020 Martin Pool.
+// Copyright 2017-2024 Martin Pool.
 
 // This program is free software; you can redistribute it and/or modify
 // it under the terms of the GNU General Public License as published by
@@ -11,4 +11,357 @@
 // GNU General Public License for more details.
 
 //! Data compression algorithms.
+//!
+//! Data blocks are written in one of two encodings:
+//!
+//! * Legacy blocks are raw Snappy data with no header. Since blocks are never
+//!   empty, the first byte (the start of the Snappy length varint) is never zero.
+//!
+//! * Tagged blocks start with a zero byte, followed by a byte identifying the
+//!   compression algorithm, followed by the compressed data.
+//!
+//! Tagged zstd blocks may be compressed with a dictionary trained on small files
+//! and stored in the archive, which must then be supplied to decompress them.
+//!
+//! Blocks written with the default [Compression::Snappy] use the legacy encoding,
+//! so that they can be read by older versions of Conserve.
+//!
+//! When writing tagged blocks, data that looks already compressed (such as
+//! photos, video, or archives) is stored uncompressed rather than wasting time
+//! trying to compress it.
+
+use std::fmt;
+use std::str::FromStr;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+pub mod lz4;
 pub mod snappy;
+pub mod zstd;
+
+/// First byte of a tagged block: this can never start a legacy Snappy block.
+const TAGGED_BLOCK_MARKER: u8 = 0;
+
+/// Tag for a block stored with no compression.
+const TAG_STORED: u8 = 0;
+
+/// Tag for a block compressed with zstd.
+const TAG_ZSTD: u8 = 1;
+
+/// Tag for a block compressed with zstd using the archive's dictionary.
+const TAG_ZSTD_DICTIONARY: u8 = 2;
+
+/// Tag for a block compressed with LZ4.
+const TAG_LZ4: u8 = 3;
+
+/// The largest uncompressed block that's written or read.
+///
+/// Backups keep blocks within this even if [crate::BackupOptions] ask for larger
+/// ones, so that a larger length in a damaged block can be rejected before
+/// allocating memory for it.
+pub const MAX_BLOCK_SIZE: usize = 1 << 30;
+
+/// Compression level for zstd, if none is specified.
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// Data with more than this many bits of entropy per byte is assumed to be incompressible.
+const INCOMPRESSIBLE_ENTROPY: f64 = 7.95;
+
+/// How data blocks referenced by a band are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlockFormat {
+    /// Untagged Snappy blocks, readable by all versions of Conserve.
+    Legacy,
+    /// Blocks may also be tagged with their compression.
+    Tagged,
+}
+
+/// Compression algorithm and settings used when writing new data blocks.
+///
+/// Every block records how it was compressed, so blocks written with different
+/// settings can be mixed in one archive.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum Compression {
+    /// Store blocks uncompressed.
+    None,
+    /// Snappy, as written by all previous versions of Conserve.
+    #[default]
+    Snappy,
+    /// Zstandard, at the given level.
+    Zstd { level: i32 },
+    /// LZ4, which is faster but compresses less than zstd.
+    Lz4,
+}
+
+impl Compression {
+    /// True if blocks are written in the legacy untagged format that can be
+    /// read by older versions of Conserve.
+    pub fn is_legacy(&self) -> bool {
+        *self == Compression::Snappy
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Snappy => write!(f, "snappy"),
+            Compression::Zstd { level } => write!(f, "zstd:{level}"),
+            Compression::Lz4 => write!(f, "lz4"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    /// Parse a compression setting like `none`, `snappy`, `lz4`, `zstd`, or `zstd:9`.
+    fn from_str(s: &str) -> Result<Compression> {
+        let invalid = || Error::InvalidCompression { spec: s.to_owned() };
+        let (algorithm, level) = match s.split_once(':') {
+            Some((algorithm, level)) => (algorithm, Some(level)),
+            None => (s, None),
+        };
+        match (algorithm, level) {
+            ("none", None) => Ok(Compression::None),
+            ("snappy", None) => Ok(Compression::Snappy),
+            ("lz4", None) => Ok(Compression::Lz4),
+            ("zstd", None) => Ok(Compression::Zstd {
+                level: DEFAULT_ZSTD_LEVEL,
+            }),
+            ("zstd", Some(level)) => {
+                let level: i32 = level.parse().map_err(|_| invalid())?;
+                if ::zstd::compression_level_range().contains(&level) {
+                    Ok(Compression::Zstd { level })
+                } else {
+                    Err(invalid())
+                }
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Compress the content of a data block, including any header identifying the
+/// compression.
+///
+/// If a zstd `dictionary` is given, it's used when the compression is zstd, and
+/// ignored otherwise.
+pub(crate) fn compress_block(
+    compression: Compression,
+    dictionary: Option<&[u8]>,
+    data: &[u8],
+) -> Result<Bytes> {
+    debug_assert!(!data.is_empty(), "blocks should never be empty");
+    let (tag, compressed) = match (compression, dictionary) {
+        (Compression::Snappy, _) => return snappy::Compressor::new().compress(data),
+        (Compression::None, _) => (TAG_STORED, Bytes::copy_from_slice(data)),
+        _ if looks_incompressible(data) => (TAG_STORED, Bytes::copy_from_slice(data)),
+        (Compression::Lz4, _) => (TAG_LZ4, lz4::compress(data)),
+        (Compression::Zstd { level }, None) => (TAG_ZSTD, zstd::compress(data, level)?),
+        (Compression::Zstd { level }, Some(dictionary)) => (
+            TAG_ZSTD_DICTIONARY,
+            zstd::compress_with_dictionary(data, level, dictionary)?,
+        ),
+    };
+    let (tag, compressed) = if tag != TAG_STORED && compressed.len() >= data.len() {
+        // Compression didn't help, so don't make readers spend time decompressing it.
+        (TAG_STORED, Bytes::copy_from_slice(data))
+    } else {
+        (tag, compressed)
+    };
+    let mut out = BytesMut::with_capacity(compressed.len() + 2);
+    out.put_u8(TAGGED_BLOCK_MARKER);
+    out.put_u8(tag);
+    out.put_slice(&compressed);
+    Ok(out.freeze())
+}
+
+/// Guess whether data is already compressed or encrypted, from the entropy of a sample.
+fn looks_incompressible(data: &[u8]) -> bool {
+    /// Look at this many evenly spaced chunks of the data.
+    const SAMPLE_CHUNKS: usize = 64;
+    /// Length of each sampled chunk.
+    const CHUNK_LEN: usize = 4096;
+
+    // Small blocks give too little evidence, and are cheap to compress anyway.
+    if data.len() < CHUNK_LEN {
+        return false;
+    }
+    let mut counts = [0u64; 256];
+    let stride = (data.len() / SAMPLE_CHUNKS).max(CHUNK_LEN);
+    let mut total = 0;
+    for chunk_start in (0..data.len()).step_by(stride) {
+        let chunk = &data[chunk_start..(chunk_start + CHUNK_LEN).min(data.len())];
+        for &byte in chunk {
+            counts[byte as usize] += 1;
+        }
+        total += chunk.len();
+    }
+    let total = total as f64;
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total;
+            -p * p.log2()
+        })
+        .sum();
+    entropy > INCOMPRESSIBLE_ENTROPY
+}
+
+/// Decompress a data block written in either the legacy or tagged format.
+///
+/// `dictionary` is the archive's zstd dictionary, if it has one.
+pub(crate) fn decompress_block(data: &[u8], dictionary: Option<&[u8]>) -> Result<Bytes> {
+    match data {
+        [TAGGED_BLOCK_MARKER, TAG_STORED, rest @ ..] => Ok(Bytes::copy_from_slice(rest)),
+        [TAGGED_BLOCK_MARKER, TAG_ZSTD, rest @ ..] => zstd::decompress(rest),
+        [TAGGED_BLOCK_MARKER, TAG_LZ4, rest @ ..] => lz4::decompress(rest),
+        [TAGGED_BLOCK_MARKER, TAG_ZSTD_DICTIONARY, rest @ ..] => match dictionary {
+            Some(dictionary) => zstd::decompress_with_dictionary(rest, dictionary),
+            None => Err(Error::MissingZstdDictionary),
+        },
+        [TAGGED_BLOCK_MARKER, tag, ..] => Err(Error::UnsupportedBlockEncoding { tag: *tag }),
+        _ => snappy::Decompressor::new().decompress(data),
+    }
+}
+
+/// True if a data block was compressed with the archive's zstd dictionary, and so
+/// can only be decompressed with that dictionary.
+pub(crate) fn uses_zstd_dictionary(data: &[u8]) -> bool {
+    data.starts_with(&[TAGGED_BLOCK_MARKER, TAG_ZSTD_DICTIONARY])
+}
+
+/// Length of the header of a tagged block.
+const TAGGED_HEADER_LEN: u64 = 2;
+
+/// If a data block, of which at least the first two bytes are given, is stored
+/// uncompressed, return the offset of its content from the start of the block.
+pub(crate) fn stored_content_offset(data: &[u8]) -> Option<u64> {
+    data.starts_with(&[TAGGED_BLOCK_MARKER, TAG_STORED])
+        .then_some(TAGGED_HEADER_LEN)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_compression() {
+        assert_eq!("none".parse::<Compression>().unwrap(), Compression::None);
+        assert_eq!(
+            "snappy".parse::<Compression>().unwrap(),
+            Compression::Snappy
+        );
+        assert_eq!(
+            "zstd".parse::<Compression>().unwrap(),
+            Compression::Zstd { level: 3 }
+        );
+        assert_eq!(
+            "zstd:19".parse::<Compression>().unwrap(),
+            Compression::Zstd { level: 19 }
+        );
+        assert!("zstd:1000".parse::<Compression>().is_err());
+        assert!("snappy:2".parse::<Compression>().is_err());
+        assert!("gzip".parse::<Compression>().is_err());
+        assert_eq!("lz4".parse::<Compression>().unwrap(), Compression::Lz4);
+        assert!("lz4:1".parse::<Compression>().is_err());
+        assert_eq!(Compression::Zstd { level: 7 }.to_string(), "zstd:7");
+    }
+
+    #[test]
+    fn block_round_trip() {
+        let data = b"hello world, hello world, hello world, hello world";
+        for compression in [
+            Compression::None,
+            Compression::Snappy,
+            Compression::Zstd { level: 1 },
+            Compression::Zstd { level: 19 },
+            Compression::Lz4,
+        ] {
+            let compressed = compress_block(compression, None, data).unwrap();
+            assert_eq!(
+                compressed[0] == TAGGED_BLOCK_MARKER,
+                !compression.is_legacy()
+            );
+            assert_eq!(decompress_block(&compressed, None).unwrap(), &data[..]);
+        }
+    }
+
+    #[test]
+    fn legacy_snappy_block_is_untagged() {
+        let compressed = compress_block(Compression::Snappy, None, b"hello world").unwrap();
+        assert_eq!(compressed.as_ref(), b"\x0b(hello world");
+    }
+
+    #[test]
+    fn dictionary_round_trip() {
+        let samples: Vec<String> = (0..1000)
+            .map(|i| format!("From: user{i}@example.com\nSubject: Message number {i}\n\nHello!\n"))
+            .collect();
+        let dictionary = zstd::train_dictionary(&samples, 4096).unwrap();
+        let data = b"From: someone@example.com\nSubject: Message number 12345\n\nHello!\n";
+        let compression = Compression::Zstd { level: 3 };
+        let compressed = compress_block(compression, Some(&dictionary), data).unwrap();
+        assert_eq!(compressed[1], TAG_ZSTD_DICTIONARY);
+        assert!(uses_zstd_dictionary(&compressed));
+        assert!(!uses_zstd_dictionary(
+            &compress_block(compression, None, data).unwrap()
+        ));
+        assert!(compressed.len() < compress_block(compression, None, data).unwrap().len());
+        assert_eq!(
+            decompress_block(&compressed, Some(&dictionary)).unwrap(),
+            &data[..]
+        );
+        assert!(matches!(
+            decompress_block(&compressed, None),
+            Err(Error::MissingZstdDictionary)
+        ));
+    }
+
+    #[test]
+    fn incompressible_data_is_stored() {
+        let mut data = vec![0u8; 1 << 20];
+        rand::Rng::fill(&mut rand::thread_rng(), &mut data[..]);
+        assert!(looks_incompressible(&data));
+        let compressed = compress_block(Compression::Zstd { level: 3 }, None, &data).unwrap();
+        assert_eq!(compressed[..2], [TAGGED_BLOCK_MARKER, TAG_STORED]);
+        assert_eq!(compressed.len(), data.len() + 2);
+        assert_eq!(decompress_block(&compressed, None).unwrap(), data);
+        assert_eq!(stored_content_offset(&compressed), Some(2));
+
+        // Legacy blocks have nowhere to record the choice, so are always compressed.
+        let compressed = compress_block(Compression::Snappy, None, &data).unwrap();
+        assert_ne!(compressed[0], TAGGED_BLOCK_MARKER);
+        assert_eq!(stored_content_offset(&compressed), None);
+    }
+
+    #[test]
+    fn compressible_data_is_compressed() {
+        let data = b"hello world ".repeat(10_000);
+        assert!(!looks_incompressible(&data));
+        let compressed = compress_block(Compression::Zstd { level: 3 }, None, &data).unwrap();
+        assert_eq!(compressed[..2], [TAGGED_BLOCK_MARKER, TAG_ZSTD]);
+    }
+
+    #[test]
+    fn lz4_block_is_tagged() {
+        let data = b"hello world ".repeat(1000);
+        let compressed = compress_block(Compression::Lz4, None, &data).unwrap();
+        assert_eq!(compressed[..2], [TAGGED_BLOCK_MARKER, TAG_LZ4]);
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress_block(&compressed, None).unwrap(), data);
+    }
+
+    #[test]
+    fn unknown_tag_is_an_error() {
+        let err = decompress_block(b"\x00\xffwhatever", None).unwrap_err();
+        assert!(matches!(err, Error::UnsupportedBlockEncoding { tag: 0xff }));
+    }
+}
@@ -0,0 +1,63 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Zstandard compression glue.
+
+use std::io::Read;
+
+use bytes::Bytes;
+
+use crate::{Error, Result};
+
+/// Compress bytes into a single zstd frame.
+pub(crate) fn compress(input: &[u8], level: i32) -> Result<Bytes> {
+    ::zstd::bulk::compress(input, level)
+        .map(Bytes::from)
+        .map_err(|source| Error::Zstd { source })
+}
+
+/// Decompress a zstd frame.
+pub(crate) fn decompress(input: &[u8]) -> Result<Bytes> {
+    ::zstd::stream::decode_all(input)
+        .map(Bytes::from)
+        .map_err(|source| Error::Zstd { source })
+}
+
+/// Compress bytes into a single zstd frame, using a dictionary.
+pub(crate) fn compress_with_dictionary(
+    input: &[u8],
+    level: i32,
+    dictionary: &[u8],
+) -> Result<Bytes> {
+    ::zstd::bulk::Compressor::with_dictionary(level, dictionary)
+        .and_then(|mut compressor| compressor.compress(input))
+        .map(Bytes::from)
+        .map_err(|source| Error::Zstd { source })
+}
+
+/// Decompress a zstd frame that was compressed with a dictionary.
+pub(crate) fn decompress_with_dictionary(input: &[u8], dictionary: &[u8]) -> Result<Bytes> {
+    let mut out = Vec::new();
+    ::zstd::stream::Decoder::with_dictionary(input, dictionary)
+        .and_then(|mut decoder| decoder.read_to_end(&mut out))
+        .map_err(|source| Error::Zstd { source })?;
+    Ok(out.into())
+}
+
+/// Train a dictionary from samples of similar data.
+///
+/// Fails if there are too few samples to train a useful dictionary.
+pub(crate) fn train_dictionary<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> Result<Bytes> {
+    ::zstd::dict::from_samples(samples, max_size)
+        .map(Bytes::from)
+        .map_err(|source| Error::Zstd { source })
+}
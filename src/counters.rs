@@ -28,6 +28,10 @@ pub enum Counter {
     Dirs,
     /// Number of symlinks processed.
     Symlinks,
+    /// Number of files that are hard links to another file processed earlier.
+    Hardlinks,
+    /// Number of device nodes, FIFOs, and sockets processed.
+    SpecialFiles,
     /// Number of entries (files etc) that are unchanged from the basis backup.
     EntriesUnchanged,
     /// Number of entries changed since the basis backup.
@@ -36,6 +40,14 @@ pub enum Counter {
     EntriesAdded,
     /// Number of entries deleted relative to the basis backup.
     EntriesDeleted,
+    /// Number of existing files that restore didn't rewrite, because they already
+    /// had the stored content.
+    UnchangedFiles,
+    /// Number of restored files that were read back and matched the backup.
+    VerifiedFiles,
+    /// Number of restored files whose content was cloned or copied within the
+    /// filesystem, rather than written from blocks.
+    CopiedFiles,
     /// Number of files with length zero.
     EmptyFiles,
     /// Number of small files packed into combined blocks.
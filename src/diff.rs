@@ -17,8 +17,7 @@
 
 use std::sync::Arc;
 
-use readahead_iterator::IntoReadahead;
-
+use crate::merge::merge_trees;
 use crate::monitor::Monitor;
 use crate::*;
 
@@ -40,22 +39,16 @@ impl Default for DiffOptions {
 }
 
 /// Generate an iter of per-entry diffs between two trees.
+///
+/// See also [compare], which yields the full entries that differ.
 pub fn diff(
     st: &StoredTree,
     lt: &LiveTree,
     options: &DiffOptions,
     monitor: Arc<dyn Monitor>,
 ) -> Result<impl Iterator<Item = EntryChange>> {
-    let readahead = 1000;
     let include_unchanged: bool = options.include_unchanged; // Copy out to avoid lifetime problems in the callback
-    let ait = st
-        .iter_entries(Apath::root(), options.exclude.clone(), monitor.clone())?
-        .readahead(readahead);
-    let bit = lt
-        .iter_entries(Apath::root(), options.exclude.clone(), monitor.clone())?
-        .filter(|le| le.kind() != Kind::Unknown)
-        .readahead(readahead);
-    Ok(MergeTrees::new(ait, bit)
+    Ok(merge_trees(st, lt, &options.exclude, monitor)?
         .map(|me| me.to_entry_change())
         .filter(move |c: &EntryChange| include_unchanged || !c.change.is_unchanged()))
 }
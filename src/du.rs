@@ -0,0 +1,178 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Measure how much space each directory of a stored tree takes, like `du`.
+//!
+//! Each directory is measured in two ways: the apparent size of the files in it
+//! and its subdirectories, as they'd be restored, and the compressed size of the
+//! distinct blocks holding them, which is what they take in the archive. A block
+//! referenced by several files in a directory is counted once, so the stored
+//! size reflects deduplication within the directory. Blocks shared with files in
+//! other directories, or in other bands, are counted in each of them, so stored
+//! sizes of sibling directories don't add up to the size of their parent.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem::swap;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::monitor::Monitor;
+use crate::*;
+
+/// Options for [disk_usage].
+#[derive(Debug, Clone)]
+pub struct DiskUsageOptions {
+    /// Leave out entries matching these patterns.
+    pub exclude: Exclude,
+
+    /// Only report directories at most this many levels below the root, which is
+    /// at depth 0. Deeper directories are still counted in their parents.
+    pub max_depth: Option<usize>,
+}
+
+impl Default for DiskUsageOptions {
+    fn default() -> Self {
+        DiskUsageOptions {
+            exclude: Exclude::nothing(),
+            max_depth: None,
+        }
+    }
+}
+
+/// The space taken by one directory and everything below it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DirUsage {
+    pub apath: Apath,
+    /// The number of files in this directory and its subdirectories.
+    pub files: usize,
+    /// The total length of those files.
+    pub apparent_bytes: u64,
+    /// The compressed size of the distinct blocks holding those files.
+    pub stored_bytes: u64,
+}
+
+/// The files directly in one directory, or, once its subdirectories are added,
+/// in its whole subtree.
+#[derive(Default)]
+struct Totals {
+    files: usize,
+    apparent_bytes: u64,
+    blocks: HashSet<BlockHash>,
+}
+
+/// Measure every directory in a stored tree, returned in apath order.
+///
+/// Blocks whose size can't be found are reported to the monitor and counted as
+/// empty.
+pub fn disk_usage(
+    stored_tree: &StoredTree,
+    options: &DiskUsageOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<Vec<DirUsage>> {
+    let task = monitor.start_task("Measure directories".to_string());
+    let mut dirs: BTreeMap<Apath, Totals> = BTreeMap::new();
+    for entry in
+        stored_tree.iter_entries(Apath::root(), options.exclude.clone(), monitor.clone())?
+    {
+        task.increment(1);
+        match entry.kind() {
+            Kind::Dir => {
+                dirs.entry(entry.apath).or_default();
+            }
+            Kind::File => {
+                let parent = entry.apath.parent().expect("File has a parent directory");
+                let totals = dirs.entry(parent).or_default();
+                totals.files += 1;
+                totals.apparent_bytes += entry.size().unwrap_or_default();
+                totals
+                    .blocks
+                    .extend(entry.addrs.iter().map(|addr| addr.hash.clone()));
+            }
+            _ => (),
+        }
+    }
+    drop(task);
+
+    // Subtrees aren't contiguous in apath order, so roll each directory's totals up
+    // into its parent once everything has been read, starting from the deepest.
+    let block_dir = stored_tree.block_dir();
+    let mut block_sizes: HashMap<BlockHash, u64> = HashMap::new();
+    let mut usage = Vec::new();
+    let deepest = dirs.keys().map(depth).max().unwrap_or_default();
+    for level in (0..=deepest).rev() {
+        let apaths: Vec<Apath> = dirs
+            .keys()
+            .filter(|apath| depth(apath) == level)
+            .cloned()
+            .collect();
+        for apath in apaths {
+            let mut totals = dirs.remove(&apath).expect("Directory is in the map");
+            let stored_bytes = totals
+                .blocks
+                .iter()
+                .map(|hash| {
+                    *block_sizes.entry(hash.clone()).or_insert_with(|| {
+                        block_dir.compressed_size(hash).unwrap_or_else(|err| {
+                            monitor.error(err);
+                            0
+                        })
+                    })
+                })
+                .sum();
+            if options
+                .max_depth
+                .map_or(true, |max_depth| level <= max_depth)
+            {
+                usage.push(DirUsage {
+                    apath: apath.clone(),
+                    files: totals.files,
+                    apparent_bytes: totals.apparent_bytes,
+                    stored_bytes,
+                });
+            }
+            if let Some(parent) = apath.parent() {
+                let parent_totals = dirs.entry(parent).or_default();
+                parent_totals.files += totals.files;
+                parent_totals.apparent_bytes += totals.apparent_bytes;
+                if parent_totals.blocks.len() < totals.blocks.len() {
+                    swap(&mut parent_totals.blocks, &mut totals.blocks);
+                }
+                parent_totals.blocks.extend(totals.blocks);
+            }
+        }
+    }
+    usage.sort_unstable_by(|a, b| a.apath.cmp(&b.apath));
+    Ok(usage)
+}
+
+/// The number of directories between the root and an apath.
+fn depth(apath: &Apath) -> usize {
+    let apath: &str = apath.as_ref();
+    if apath == "/" {
+        0
+    } else {
+        apath.matches('/').count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn depth_of_apaths() {
+        assert_eq!(depth(&Apath::root()), 0);
+        assert_eq!(depth(&"/a".into()), 1);
+        assert_eq!(depth(&"/a/b/c".into()), 3);
+    }
+}
@@ -25,16 +25,40 @@ use crate::*;
 /// A description of an file, directory, or symlink in a tree, independent
 /// of whether it's recorded in a archive (an [IndexEntry]), or
 /// in a source tree.
+///
+/// The Unix metadata of an entry is available from [EntryTrait::mode_bits],
+/// [EntryTrait::uid], [EntryTrait::gid], and [EntryTrait::mtime], without
+/// needing to look inside the stored forms.
 // TODO: Maybe keep this entirely in memory and explicitly look things
 // up when needed.
 pub trait EntryTrait: Debug {
     fn apath(&self) -> &Apath;
     fn kind(&self) -> Kind;
+    /// Modification time, with nanosecond precision if it was recorded.
     fn mtime(&self) -> OffsetDateTime;
     fn size(&self) -> Option<u64>;
     fn symlink_target(&self) -> Option<&str>;
     fn unix_mode(&self) -> UnixMode;
     fn owner(&self) -> &Owner;
+    fn acls(&self) -> &Acls;
+    fn capability(&self) -> Option<&Capability>;
+    fn link_group(&self) -> Option<&Apath>;
+    fn device(&self) -> Option<DeviceNumber>;
+
+    /// The permission, set-id, and sticky bits, such as `0o4755`, if they're known.
+    fn mode_bits(&self) -> Option<u32> {
+        self.unix_mode().bits()
+    }
+
+    /// Numeric id of the user owning this entry, if it's known.
+    fn uid(&self) -> Option<u32> {
+        self.owner().uid
+    }
+
+    /// Numeric id of the group owning this entry, if it's known.
+    fn gid(&self) -> Option<u32> {
+        self.owner().gid
+    }
 }
 
 /// Per-kind metadata.
@@ -44,6 +68,10 @@ pub enum KindMeta {
     File { size: u64 },
     Dir,
     Symlink { target: String },
+    CharDevice { device: DeviceNumber },
+    BlockDevice { device: DeviceNumber },
+    Fifo,
+    Socket,
     Unknown,
 }
 
@@ -53,17 +81,22 @@ impl From<&KindMeta> for Kind {
             KindMeta::Dir => Kind::Dir,
             KindMeta::File { .. } => Kind::File,
             KindMeta::Symlink { .. } => Kind::Symlink,
+            KindMeta::CharDevice { .. } => Kind::CharDevice,
+            KindMeta::BlockDevice { .. } => Kind::BlockDevice,
+            KindMeta::Fifo => Kind::Fifo,
+            KindMeta::Socket => Kind::Socket,
             KindMeta::Unknown => Kind::Unknown,
         }
     }
 }
 
-/// An in-memory [Entry] describing a file/dir/symlink, with no addresses.
+/// An in-memory [Entry] describing a file/dir/symlink/special file, with no addresses.
 #[derive(Debug, Serialize, Clone, Eq, PartialEq)]
 pub struct EntryValue {
     pub(crate) apath: Apath,
 
-    /// Is it a file, dir, or symlink, and for files the size and for symlinks the target.
+    /// Is it a file, dir, symlink, or special file, and for files the size, for symlinks
+    /// the target, and for devices the device number.
     #[serde(flatten)]
     pub(crate) kind_meta: KindMeta,
 
@@ -72,6 +105,18 @@ pub struct EntryValue {
     pub(crate) unix_mode: UnixMode,
     #[serde(flatten)]
     pub(crate) owner: Owner,
+    #[serde(flatten)]
+    pub(crate) acls: Acls,
+    /// Linux file capabilities, if the file has any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) capability: Option<Capability>,
+    /// For files with multiple hard links, the apath of the first link in the tree.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) link_group: Option<Apath>,
+    /// For files in a live tree, the inode number, used to notice files that were
+    /// replaced since the last backup.
+    #[serde(skip)]
+    pub(crate) inode: Option<u64>,
 }
 
 impl<B: Borrow<EntryValue> + Debug> EntryTrait for B {
@@ -109,4 +154,23 @@ impl<B: Borrow<EntryValue> + Debug> EntryTrait for B {
     fn owner(&self) -> &Owner {
         &self.borrow().owner
     }
+
+    fn acls(&self) -> &Acls {
+        &self.borrow().acls
+    }
+
+    fn capability(&self) -> Option<&Capability> {
+        self.borrow().capability.as_ref()
+    }
+
+    fn link_group(&self) -> Option<&Apath> {
+        self.borrow().link_group.as_ref()
+    }
+
+    fn device(&self) -> Option<DeviceNumber> {
+        match self.borrow().kind_meta {
+            KindMeta::CharDevice { device } | KindMeta::BlockDevice { device } => Some(device),
+            _ => None,
+        }
+    }
 }
@@ -0,0 +1,148 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A [Stream] of the entries in a stored tree, with index hunks read ahead on a
+//! background thread.
+//!
+//! The reader thread stops when the channel to the stream is full, so at most a few
+//! hunks are held in memory however large the tree is, and it stops altogether if
+//! the stream is dropped.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use std::vec;
+
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::executor::block_on;
+use futures::{ready, SinkExt, Stream, StreamExt};
+
+use crate::counters::Counter;
+use crate::monitor::task::Task;
+use crate::monitor::Monitor;
+use crate::restore::RestoreConflict;
+use crate::stitch::IterStitchedIndexHunks;
+use crate::*;
+
+type Batch = Result<Vec<IndexEntry>>;
+
+/// A stream of the entries in a stored tree, in apath order, returned by
+/// [StoredTree::entry_stream].
+///
+/// Problems reading the index, such as bands or hunks that can't be read, are
+/// returned as errors in the stream, which then continues with the following
+/// entries.
+pub struct IndexEntryStream {
+    receiver: Receiver<Batch>,
+    entries: vec::IntoIter<IndexEntry>,
+}
+
+impl IndexEntryStream {
+    pub(crate) fn new(
+        archive: &Archive,
+        band_id: BandId,
+        subtree: Apath,
+        exclude: Exclude,
+        readahead: usize,
+        monitor: Arc<dyn Monitor>,
+    ) -> IndexEntryStream {
+        let (sender, receiver) = channel(readahead);
+        let monitor = Arc::new(ErrorsToStream {
+            monitor,
+            sender: sender.clone(),
+        });
+        let hunks =
+            IterStitchedIndexHunks::new(archive, band_id, monitor).seek_to_subtree(&subtree);
+        thread::Builder::new()
+            .name("index_readahead".to_owned())
+            .spawn(move || send_hunks(hunks, subtree, exclude, sender))
+            .expect("Spawn index readahead thread");
+        IndexEntryStream {
+            receiver,
+            entries: Vec::new().into_iter(),
+        }
+    }
+}
+
+impl Stream for IndexEntryStream {
+    type Item = Result<IndexEntry>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                return Poll::Ready(Some(Ok(entry)));
+            }
+            match ready!(self.receiver.poll_next_unpin(cx)) {
+                Some(Ok(entries)) => self.entries = entries.into_iter(),
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// Send the wanted entries from each hunk, stopping after the end of the subtree
+/// or when the stream is dropped.
+fn send_hunks(
+    hunks: IterStitchedIndexHunks,
+    subtree: Apath,
+    exclude: Exclude,
+    mut sender: Sender<Batch>,
+) {
+    for mut entries in hunks {
+        let finished = entries
+            .last()
+            .is_some_and(|entry| entry.apath.is_after_subtree(&subtree));
+        entries.retain(|entry| {
+            subtree.is_prefix_of(&entry.apath)
+                && !exclude.matches(&entry.apath)
+                && exclude.includes_kind(entry.kind())
+        });
+        if !entries.is_empty() && block_on(sender.send(Ok(entries))).is_err() {
+            return; // The stream was dropped.
+        }
+        if finished {
+            return;
+        }
+    }
+}
+
+/// Passes everything through to another monitor, except errors, which are sent to
+/// the stream.
+struct ErrorsToStream {
+    monitor: Arc<dyn Monitor>,
+    sender: Sender<Batch>,
+}
+
+impl Monitor for ErrorsToStream {
+    fn count(&self, counter: Counter, increment: usize) {
+        self.monitor.count(counter, increment)
+    }
+
+    fn set_counter(&self, counter: Counter, value: usize) {
+        self.monitor.set_counter(counter, value)
+    }
+
+    fn error(&self, error: Error) {
+        // If the stream was dropped, nobody is interested in the error.
+        let _ = block_on(self.sender.clone().send(Err(error)));
+    }
+
+    fn restore_conflict(&self, conflict: RestoreConflict) {
+        self.monitor.restore_conflict(conflict)
+    }
+
+    fn start_task(&self, name: String) -> Task {
+        self.monitor.start_task(name)
+    }
+}
@@ -15,15 +15,24 @@
 
 use std::borrow::Cow;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use serde::Serialize;
+use strum_macros::IntoStaticStr;
 use thiserror::Error;
+use time::OffsetDateTime;
 
 use crate::*;
 
 /// Conserve specific error.
+///
+/// Each error has a stable machine-readable [Error::code], and a broad [ErrorClass].
+/// The apath, band, block, or local path it concerns, if any, are available from
+/// [Error::apath], [Error::band_id], [Error::block_hash], and [Error::path].
 #[non_exhaustive]
-#[derive(Debug, Error)]
+#[derive(Debug, Error, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
 pub enum Error {
     #[error("Block file {hash:?} corrupt: does not have the expected hash")]
     BlockCorrupt { hash: BlockHash },
@@ -31,6 +40,22 @@ pub enum Error {
     #[error("Referenced block {hash} is missing")]
     BlockMissing { hash: BlockHash },
 
+    #[error(
+        "Index entry {apath} in {band_id} references {len} bytes at offset {start} of block {hash}, \
+        which is only {block_len} bytes long"
+    )]
+    AddressOutOfRange {
+        band_id: BandId,
+        apath: Apath,
+        hash: BlockHash,
+        start: u64,
+        len: u64,
+        block_len: usize,
+    },
+
+    #[error("Block file {hash} has an implausible size of {size} bytes")]
+    BlockFileImplausible { hash: BlockHash, size: u64 },
+
     #[error("Block {hash} is too short: actual len {actual_len}, referenced len {referenced_len}")]
     BlockTooShort {
         hash: BlockHash,
@@ -38,6 +63,9 @@ pub enum Error {
         referenced_len: usize,
     },
 
+    #[error("Block pack {name} is damaged: {details}")]
+    PackDamaged { name: String, details: String },
+
     #[error("Failed to list blocks")]
     ListBlocks {
         #[source]
@@ -63,6 +91,12 @@ pub enum Error {
     #[error("Archive has no complete bands")]
     NoCompleteBands,
 
+    #[error("No band has label {label:?}")]
+    NoBandWithLabel { label: String },
+
+    #[error("No complete band started at or before {time}")]
+    NoBandAsOf { time: OffsetDateTime },
+
     #[error("Unsupported band format flags {unsupported_flags:?} in {band_id}")]
     UnsupportedBandFormatFlags {
         band_id: BandId,
@@ -96,12 +130,24 @@ pub enum Error {
     #[error("A backup was created while the garbage collection lock was held; CHECK ARCHIVE NOW")]
     GarbageCollectionLockHeldDuringBackup,
 
+    #[error("Cancelled")]
+    Cancelled,
+
     #[error(transparent)]
     ParseGlob {
         #[from]
         source: globset::Error,
     },
 
+    #[error(transparent)]
+    ParseRegex {
+        #[from]
+        source: regex::Error,
+    },
+
+    #[error("Index hunk {path:?} is damaged: {details}")]
+    IndexHunkDamaged { path: String, details: String },
+
     #[error("Failed to deserialize json from {:?}", path)]
     DeserializeJson {
         path: String,
@@ -121,6 +167,12 @@ pub enum Error {
     #[error("Band not found: {band_id}")]
     BandNotFound { band_id: BandId },
 
+    #[error("Band {band_id} is held, and can't be deleted until the hold is released")]
+    BandHeld { band_id: BandId },
+
+    #[error("Band {band_id} is incomplete")]
+    BandIncomplete { band_id: BandId },
+
     #[error("Failed to list bands")]
     ListBands { source: io::Error },
 
@@ -130,18 +182,64 @@ pub enum Error {
     #[error("Unsupported source file kind: {path:?}")]
     UnsupportedSourceKind { path: PathBuf },
 
+    #[error("Invalid tar stream at byte {offset}: {details}")]
+    InvalidTar { offset: u64, details: String },
+
+    #[error("Can't import tar member {name:?}: {details}")]
+    UnsupportedTarMember { name: String, details: String },
+
+    #[error("Source directory {path:?} has no name to store it under")]
+    UnnamedSource { path: PathBuf },
+
+    #[error("More than one source directory is named {name:?}")]
+    DuplicateSourceName { name: String },
+
     #[error("Unsupported symlink encoding: {path:?}")]
     UnsupportedTargetEncoding { path: PathBuf },
 
     #[error("Failed to read source tree {:?}", path)]
     ListSourceTree { path: PathBuf, source: io::Error },
 
+    #[error("Failed to create a shadow copy for {path:?}: {details}")]
+    ShadowCopy { path: PathBuf, details: String },
+
+    #[error("Volume Shadow Copy is only supported on Windows")]
+    ShadowCopyUnsupported,
+
+    #[error("Failed to snapshot the filesystem containing {path:?}: {details}")]
+    Snapshot { path: PathBuf, details: String },
+
+    #[error("Failed to set process priority")]
+    SetPriority { source: io::Error },
+
     #[error("Failed to restore file {:?}", path)]
     RestoreFile { path: PathBuf, source: io::Error },
 
+    #[error("Invalid owner mapping {mapping:?}; expected FROM:TO")]
+    InvalidOwnerMapping { mapping: String },
+
+    #[error("Restored file {path:?} doesn't match the backup")]
+    RestoredFileMismatch { path: PathBuf },
+
+    #[error("Failed to verify restored file {path:?}")]
+    VerifyRestoredFile { path: PathBuf, source: Box<Error> },
+
+    #[error("Failed to replace existing {path:?}")]
+    ReplaceExisting { path: PathBuf, source: io::Error },
+
     #[error("Failed to restore symlink {path:?}")]
     RestoreSymlink { path: PathBuf, source: io::Error },
 
+    #[error("Failed to restore special file {path:?}")]
+    RestoreSpecialFile { path: PathBuf, source: io::Error },
+
+    #[error("Failed to restore hard link {path:?} to {target:?}")]
+    RestoreHardlink {
+        path: PathBuf,
+        target: PathBuf,
+        source: io::Error,
+    },
+
     #[error("Failed to read block content {hash} for {apath}")]
     RestoreFileBlock {
         apath: Apath,
@@ -149,9 +247,21 @@ pub enum Error {
         source: Box<Error>,
     },
 
+    #[error("Failed to store a block of {apath}")]
+    StoreFileBlock { apath: Apath, source: Arc<Error> },
+
+    #[error("No file {apath} in the stored tree")]
+    StoredFileNotFound { apath: Apath },
+
+    #[error("{apath} is a {kind:?}, not a file")]
+    NotAFile { apath: Apath, kind: Kind },
+
     #[error("Failed to restore directory {:?}", path)]
     RestoreDirectory { path: PathBuf, source: io::Error },
 
+    #[error("Not restoring {path:?} because {parent:?} is not a directory")]
+    ParentNotADirectory { path: PathBuf, parent: PathBuf },
+
     #[error("Failed to restore ownership of {:?}", path)]
     RestoreOwnership { path: PathBuf, source: io::Error },
 
@@ -161,6 +271,24 @@ pub enum Error {
     #[error("Failed to restore modification time on {:?}", path)]
     RestoreModificationTime { path: PathBuf, source: io::Error },
 
+    #[error("Failed to restore ACLs on {:?}", path)]
+    RestoreAcl { path: PathBuf, source: io::Error },
+
+    #[error(
+        "Filesystem does not support ACLs on {:?}; use --no-acls to skip them",
+        path
+    )]
+    RestoreAclUnsupported { path: PathBuf },
+
+    #[error("Invalid ACL {acl:?}")]
+    InvalidAcl { acl: String },
+
+    #[error("Failed to restore file capabilities on {:?}", path)]
+    RestoreCapability { path: PathBuf, source: io::Error },
+
+    #[error("Invalid file capability {capability:?}")]
+    InvalidCapability { capability: String },
+
     #[error("Unsupported URL scheme {:?}", scheme)]
     UrlScheme { scheme: String },
 
@@ -184,6 +312,51 @@ pub enum Error {
         source: snap::Error,
     },
 
+    #[error("Zstd compression error")]
+    Zstd {
+        #[source]
+        source: io::Error,
+    },
+
+    #[error("LZ4 decompression error")]
+    Lz4 {
+        #[source]
+        source: lz4_flex::block::DecompressError,
+    },
+
+    #[error("Invalid compression setting {spec:?}")]
+    InvalidCompression { spec: String },
+
+    #[error("Invalid include pattern {pattern:?}")]
+    InvalidInclude { pattern: String },
+
+    #[error("Invalid snapshot method {spec:?}")]
+    InvalidSnapshotMethod { spec: String },
+
+    #[error("Invalid size {spec:?}")]
+    InvalidSize { spec: String },
+
+    #[error("Invalid duration {spec:?}")]
+    InvalidDuration { spec: String },
+
+    #[error("Invalid date {spec:?}")]
+    InvalidDate { spec: String },
+
+    #[error("Invalid percentage {spec:?}")]
+    InvalidPercentage { spec: String },
+
+    #[error("Block claims to decompress to {len} bytes, more than the largest block size")]
+    BlockTooLarge { len: usize },
+
+    #[error("Unsupported block encoding tag {tag}")]
+    UnsupportedBlockEncoding { tag: u8 },
+
+    #[error("Block is compressed with a zstd dictionary, but the archive has none")]
+    MissingZstdDictionary,
+
+    #[error("The archive already has a zstd dictionary")]
+    ZstdDictionaryExists,
+
     #[error(transparent)]
     Transport {
         #[from]
@@ -203,3 +376,245 @@ impl From<jsonio::Error> for Error {
         }
     }
 }
+
+/// Broad categories of errors, so that callers can decide how to react without
+/// matching every variant.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, IntoStaticStr)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ErrorClass {
+    /// Data in the archive is missing, damaged, or can't be parsed.
+    ArchiveDamaged,
+    /// The archive is locked, or was changed by another process.
+    ArchiveBusy,
+    /// A problem with one file or directory being backed up or restored, such as
+    /// an unreadable source file.
+    Entry,
+    /// Something asked for isn't there, such as a band, a label, or the archive.
+    NotFound,
+    /// An invalid argument or option.
+    InvalidArgument,
+    /// The operation was stopped by [crate::monitor::Monitor::should_cancel].
+    Cancelled,
+    /// Anything else, such as an error reaching the archive.
+    Other,
+}
+
+impl Error {
+    /// A stable identifier for the kind of error, such as `block_missing`, for
+    /// scripts and programs to match on rather than the message.
+    ///
+    /// The code is the name of the variant in snake case. Variants aren't renamed,
+    /// so that codes stay the same from one release to the next.
+    pub fn code(&self) -> &'static str {
+        self.into()
+    }
+
+    /// The broad category of this error.
+    pub fn class(&self) -> ErrorClass {
+        use Error::*;
+        match self {
+            BlockCorrupt { .. }
+            | BlockMissing { .. }
+            | AddressOutOfRange { .. }
+            | BlockFileImplausible { .. }
+            | BlockTooShort { .. }
+            | PackDamaged { .. }
+            | BandHeadMissing { .. }
+            | IndexHunkDamaged { .. }
+            | DeserializeJson { .. }
+            | InvalidMetadata { .. }
+            | UnexpectedFile { .. }
+            | SnapCompressionError { .. }
+            | Lz4 { .. }
+            | BlockTooLarge { .. }
+            | UnsupportedBlockEncoding { .. }
+            | MissingZstdDictionary
+            | RestoreFileBlock { .. } => ErrorClass::ArchiveDamaged,
+            DeleteWithIncompleteBackup { .. }
+            | DeleteWithConcurrentActivity
+            | GarbageCollectionLockHeld
+            | GarbageCollectionLockHeldDuringBackup => ErrorClass::ArchiveBusy,
+            ReadSourceFile { .. }
+            | UnsupportedSourceKind { .. }
+            | UnsupportedTarMember { .. }
+            | UnsupportedTargetEncoding { .. }
+            | ListSourceTree { .. }
+            | RestoreFile { .. }
+            | RestoredFileMismatch { .. }
+            | VerifyRestoredFile { .. }
+            | ReplaceExisting { .. }
+            | RestoreSymlink { .. }
+            | RestoreSpecialFile { .. }
+            | RestoreHardlink { .. }
+            | RestoreDirectory { .. }
+            | ParentNotADirectory { .. }
+            | RestoreOwnership { .. }
+            | RestorePermissions { .. }
+            | RestoreModificationTime { .. }
+            | RestoreAcl { .. }
+            | RestoreAclUnsupported { .. }
+            | RestoreCapability { .. }
+            | SetOwner { .. } => ErrorClass::Entry,
+            NotAnArchive
+            | ArchiveEmpty
+            | NoCompleteBands
+            | NoBandWithLabel { .. }
+            | NoBandAsOf { .. }
+            | BandNotFound { .. }
+            | StoredFileNotFound { .. }
+            | NotAFile { .. } => ErrorClass::NotFound,
+            DestinationNotEmpty
+            | NewArchiveDirectoryNotEmpty
+            | InvalidVersion { .. }
+            | ParseGlob { .. }
+            | ParseRegex { .. }
+            | UnnamedSource { .. }
+            | DuplicateSourceName { .. }
+            | InvalidOwnerMapping { .. }
+            | UrlScheme { .. }
+            | InvalidCompression { .. }
+            | InvalidInclude { .. }
+            | InvalidSnapshotMethod { .. }
+            | InvalidSize { .. }
+            | InvalidDuration { .. }
+            | InvalidDate { .. }
+            | InvalidPercentage { .. }
+            | InvalidTar { .. }
+            | ZstdDictionaryExists
+            | BandHeld { .. }
+            | BandIncomplete { .. } => ErrorClass::InvalidArgument,
+            Cancelled => ErrorClass::Cancelled,
+            ListBlocks { .. }
+            | UnsupportedArchiveVersion { .. }
+            | UnsupportedBandVersion { .. }
+            | UnsupportedBandFormatFlags { .. }
+            | SerializeJson { .. }
+            | ListBands { .. }
+            | ShadowCopy { .. }
+            | ShadowCopyUnsupported
+            | Snapshot { .. }
+            | SetPriority { .. }
+            | InvalidAcl { .. }
+            | InvalidCapability { .. }
+            | IOError { .. }
+            | Zstd { .. }
+            | StoreFileBlock { .. }
+            | Transport { .. } => ErrorClass::Other,
+        }
+    }
+
+    /// The apath of the entry in the archive that this error concerns, if any.
+    pub fn apath(&self) -> Option<&Apath> {
+        match self {
+            Error::AddressOutOfRange { apath, .. }
+            | Error::RestoreFileBlock { apath, .. }
+            | Error::StoreFileBlock { apath, .. }
+            | Error::StoredFileNotFound { apath }
+            | Error::NotAFile { apath, .. } => Some(apath),
+            _ => None,
+        }
+    }
+
+    /// The band that this error concerns, if any.
+    pub fn band_id(&self) -> Option<BandId> {
+        match self {
+            Error::AddressOutOfRange { band_id, .. }
+            | Error::UnsupportedBandVersion { band_id, .. }
+            | Error::UnsupportedBandFormatFlags { band_id, .. }
+            | Error::BandHeadMissing { band_id }
+            | Error::DeleteWithIncompleteBackup { band_id }
+            | Error::BandNotFound { band_id }
+            | Error::BandHeld { band_id }
+            | Error::BandIncomplete { band_id } => Some(*band_id),
+            _ => None,
+        }
+    }
+
+    /// The hash of the block that this error concerns, if any.
+    pub fn block_hash(&self) -> Option<&BlockHash> {
+        match self {
+            Error::BlockCorrupt { hash }
+            | Error::BlockMissing { hash }
+            | Error::AddressOutOfRange { hash, .. }
+            | Error::BlockFileImplausible { hash, .. }
+            | Error::BlockTooShort { hash, .. }
+            | Error::RestoreFileBlock { hash, .. } => Some(hash),
+            _ => None,
+        }
+    }
+
+    /// The local file or directory, being backed up or restored, that this error
+    /// concerns, if any.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Error::ReadSourceFile { path, .. }
+            | Error::UnsupportedSourceKind { path }
+            | Error::UnnamedSource { path }
+            | Error::UnsupportedTargetEncoding { path }
+            | Error::ListSourceTree { path, .. }
+            | Error::ShadowCopy { path, .. }
+            | Error::Snapshot { path, .. }
+            | Error::RestoreFile { path, .. }
+            | Error::RestoredFileMismatch { path }
+            | Error::VerifyRestoredFile { path, .. }
+            | Error::ReplaceExisting { path, .. }
+            | Error::RestoreSymlink { path, .. }
+            | Error::RestoreSpecialFile { path, .. }
+            | Error::RestoreHardlink { path, .. }
+            | Error::RestoreDirectory { path, .. }
+            | Error::ParentNotADirectory { path, .. }
+            | Error::RestoreOwnership { path, .. }
+            | Error::RestorePermissions { path, .. }
+            | Error::RestoreModificationTime { path, .. }
+            | Error::RestoreAcl { path, .. }
+            | Error::RestoreAclUnsupported { path }
+            | Error::RestoreCapability { path, .. }
+            | Error::SetOwner { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn code_and_context() {
+        let hash: BlockHash = "fec91c70284c72d0d4e3684788a90de9338a5b2f47f01fedbe203cafd68708718ae5672d10eca804a8121904047d40d1d6cf11e7a76419357a9469af41f22d01".parse().unwrap();
+        let err = Error::RestoreFileBlock {
+            apath: "/hello".into(),
+            hash: hash.clone(),
+            source: Box::new(Error::BlockMissing { hash: hash.clone() }),
+        };
+        assert_eq!(err.code(), "restore_file_block");
+        assert_eq!(err.class(), ErrorClass::ArchiveDamaged);
+        assert_eq!(err.apath(), Some(&Apath::from("/hello")));
+        assert_eq!(err.block_hash(), Some(&hash));
+        assert_eq!(err.band_id(), None);
+        assert_eq!(err.path(), None);
+
+        let err = Error::BandNotFound {
+            band_id: BandId::new(&[2]),
+        };
+        assert_eq!(err.code(), "band_not_found");
+        assert_eq!(err.class(), ErrorClass::NotFound);
+        assert_eq!(err.band_id(), Some(BandId::new(&[2])));
+
+        let err = Error::from(io::Error::from(io::ErrorKind::Other));
+        assert_eq!(err.code(), "io_error");
+        assert_eq!(err.class(), ErrorClass::Other);
+
+        assert_eq!(
+            Error::GarbageCollectionLockHeld.class(),
+            ErrorClass::ArchiveBusy
+        );
+        assert_eq!(
+            Error::GarbageCollectionLockHeldDuringBackup.class(),
+            ErrorClass::ArchiveBusy
+        );
+        assert_eq!(Error::Cancelled.code(), "cancelled");
+    }
+}
@@ -18,13 +18,17 @@
 //! Patterns that start with a slash match only against full paths from the top
 //! of the tree. Patterns that do not start with a slash match the suffix of the
 //! path.
+//!
+//! `.conserveignore` files in the source tree hold patterns with gitignore-style
+//! semantics, relative to the directory containing the file.
 
 use std::borrow::Cow;
 use std::fs;
 use std::iter::empty;
 use std::path::Path;
 
-use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use globset::{escape, GlobBuilder, GlobSet, GlobSetBuilder};
+use time::OffsetDateTime;
 
 use super::*;
 
@@ -32,9 +36,26 @@ use super::*;
 #[derive(Clone, Debug)]
 pub struct Exclude {
     globset: GlobSet,
-    // TODO: Control of matching cachedir.
+    /// For each glob in the set, true if it's a negated pattern that re-includes
+    /// matching paths. Empty if there are no negated patterns.
+    negated: Vec<bool>,
+    /// Skip directories in source trees that contain a `CACHEDIR.TAG` file.
+    skip_cachedirs: bool,
+    /// Skip directories in source trees that contain a file with any of these names.
+    if_present: Vec<String>,
+    /// Skip files in source trees larger than this many bytes.
+    larger_than: Option<u64>,
+    /// Skip files in source trees last modified before this time.
+    modified_before: Option<OffsetDateTime>,
+    /// Don't descend into directories on a different filesystem from their parent.
+    one_file_system: bool,
+    /// Only return entries of these kinds, if set.
+    kinds: Option<Vec<Kind>>,
 }
 
+/// The name of files in the source tree containing exclusion patterns.
+pub const IGNORE_FILE_NAME: &str = ".conserveignore";
+
 impl Exclude {
     /// Create an [Exclude] from a list of glob strings.
     ///
@@ -63,16 +84,182 @@ impl Exclude {
         }
         Ok(Exclude {
             globset: gsb.build()?,
+            negated: Vec::new(),
+            skip_cachedirs: true,
+            if_present: Vec::new(),
+            larger_than: None,
+            modified_before: None,
+            one_file_system: false,
+            kinds: None,
         })
     }
 
-    /// Exclude nothing, even items that might be excluded by default.
+    /// Build from the contents of a `.conserveignore` file in directory `dir`.
+    ///
+    /// The patterns have gitignore-style semantics: patterns containing a slash are
+    /// relative to `dir`, and others match at any depth below it. A pattern starting
+    /// with `!` re-includes paths excluded by an earlier pattern in the same file,
+    /// or by a file in a parent directory. A trailing slash is accepted but,
+    /// unlike git, the pattern also matches files.
+    pub fn from_ignore_file(dir: &Apath, content: &str) -> Result<Exclude> {
+        let mut gsb = GlobSetBuilder::new();
+        let mut negated = Vec::new();
+        let dir = if *dir == Apath::root() {
+            String::new()
+        } else {
+            escape(dir)
+        };
+        for line in content.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (is_negated, pat) = match line.strip_prefix('!') {
+                Some(pat) => (true, pat),
+                None => (false, line.strip_prefix('\\').unwrap_or(line)),
+            };
+            let pat = pat.strip_suffix('/').unwrap_or(pat);
+            if pat.is_empty() {
+                continue;
+            }
+            let pattern = if let Some(rooted) = pat.strip_prefix('/') {
+                format!("{dir}/{rooted}")
+            } else if pat.contains('/') {
+                format!("{dir}/{pat}")
+            } else {
+                format!("{dir}/**/{pat}")
+            };
+            add_glob_and_children(&mut gsb, &pattern)?;
+            negated.extend([is_negated; 2]);
+        }
+        if !negated.contains(&true) {
+            negated.clear();
+        }
+        Ok(Exclude {
+            globset: gsb.build()?,
+            negated,
+            skip_cachedirs: true,
+            if_present: Vec::new(),
+            larger_than: None,
+            modified_before: None,
+            one_file_system: false,
+            kinds: None,
+        })
+    }
+
+    /// Exclude nothing, except source directories marked with a `CACHEDIR.TAG` file.
     pub fn nothing() -> Exclude {
         Exclude {
             globset: GlobSet::empty(),
+            negated: Vec::new(),
+            skip_cachedirs: true,
+            if_present: Vec::new(),
+            larger_than: None,
+            modified_before: None,
+            one_file_system: false,
+            kinds: None,
+        }
+    }
+
+    /// Set whether directories in source trees containing a valid `CACHEDIR.TAG` file
+    /// are skipped, which they are by default.
+    ///
+    /// See <https://bford.info/cachedir/>.
+    #[must_use]
+    pub fn with_skip_cachedirs(self, skip_cachedirs: bool) -> Exclude {
+        Exclude {
+            skip_cachedirs,
+            ..self
         }
     }
 
+    /// True if directories marked with a `CACHEDIR.TAG` file should be skipped.
+    pub fn skips_cachedirs(&self) -> bool {
+        self.skip_cachedirs
+    }
+
+    /// Also skip directories in source trees that contain a file or directory with
+    /// any of these names, such as `.nobackup`.
+    #[must_use]
+    pub fn with_exclude_if_present<I, S>(mut self, names: I) -> Exclude
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.if_present.extend(names.into_iter().map(Into::into));
+        self
+    }
+
+    /// Also skip files in source trees that are larger than this many bytes.
+    #[must_use]
+    pub fn with_exclude_larger_than(self, larger_than: Option<u64>) -> Exclude {
+        Exclude {
+            larger_than,
+            ..self
+        }
+    }
+
+    /// True if a source file of this size should be skipped.
+    pub(crate) fn is_too_large(&self, size: u64) -> bool {
+        self.larger_than.is_some_and(|limit| size > limit)
+    }
+
+    /// Also skip files in source trees that were last modified before this time.
+    ///
+    /// Directories are still visited, so that recently changed files within them
+    /// are included.
+    #[must_use]
+    pub fn with_exclude_modified_before(self, modified_before: Option<OffsetDateTime>) -> Exclude {
+        Exclude {
+            modified_before,
+            ..self
+        }
+    }
+
+    /// True if a source file with this mtime should be skipped.
+    pub(crate) fn is_too_old(&self, mtime: OffsetDateTime) -> bool {
+        self.modified_before.is_some_and(|cutoff| mtime < cutoff)
+    }
+
+    /// Set whether to stay on one filesystem: mount points in source trees are included,
+    /// but their contents are not.
+    #[must_use]
+    pub fn with_one_file_system(self, one_file_system: bool) -> Exclude {
+        Exclude {
+            one_file_system,
+            ..self
+        }
+    }
+
+    /// Only return entries of these kinds, or entries of any kind if None.
+    ///
+    /// Directories are still visited when they're not returned, so that entries
+    /// of the selected kinds within them are found. This is meant for listing:
+    /// in a backup, entries of other kinds aren't stored.
+    #[must_use]
+    pub fn with_only_kinds(self, kinds: Option<Vec<Kind>>) -> Exclude {
+        Exclude { kinds, ..self }
+    }
+
+    /// True if entries of this kind should be returned.
+    pub(crate) fn includes_kind(&self, kind: Kind) -> bool {
+        self.kinds
+            .as_ref()
+            .map_or(true, |kinds| kinds.contains(&kind))
+    }
+
+    /// True if the walk should not cross mount points.
+    pub fn stays_on_one_file_system(&self) -> bool {
+        self.one_file_system
+    }
+
+    /// True if this source directory should be skipped because it contains a marker file.
+    pub(crate) fn has_marker_file(&self, dir_path: &Path) -> bool {
+        self.if_present
+            .iter()
+            .any(|name| fs::symlink_metadata(dir_path.join(name)).is_ok())
+    }
+
     /// True if this apath should be excluded.
     pub fn matches<'a, A>(&self, apath: &'a A) -> bool
     where
@@ -80,7 +267,21 @@ impl Exclude {
         A: ?Sized,
     {
         let apath: Apath = apath.into();
-        self.globset.is_match(apath)
+        self.decide(&apath).unwrap_or(false)
+    }
+
+    /// Decide whether this apath is excluded: None if no pattern matches it, or
+    /// otherwise whether the last matching pattern excludes it.
+    pub(crate) fn decide(&self, apath: &Apath) -> Option<bool> {
+        if self.negated.is_empty() {
+            self.globset.is_match(apath).then_some(true)
+        } else {
+            self.globset
+                .matches(apath)
+                .into_iter()
+                .max()
+                .map(|i| !self.negated[i])
+        }
     }
 }
 
@@ -91,8 +292,13 @@ fn add_pattern(gsb: &mut GlobSetBuilder, pattern: &str) -> Result<()> {
     } else {
         Cow::Owned(format!("**/{pattern}"))
     };
+    add_glob_and_children(gsb, &pattern)
+}
+
+/// Add a glob, and another that matches everything inside paths that match it.
+fn add_glob_and_children(gsb: &mut GlobSetBuilder, pattern: &str) -> Result<()> {
     gsb.add(
-        GlobBuilder::new(&pattern)
+        GlobBuilder::new(pattern)
             .literal_separator(true)
             .build()
             .map_err(|source| Error::ParseGlob { source })?,
@@ -169,6 +375,44 @@ mod tests {
         assert!(!exclude.matches("/a"));
     }
 
+    #[test]
+    fn ignore_file_patterns() {
+        let content = "\
+# Build outputs
+target/
+*.o
+!keep.o
+/local
+docs/*.html
+";
+        let exclude = Exclude::from_ignore_file(&Apath::from("/src"), content).unwrap();
+        assert!(exclude.matches("/src/target"));
+        assert!(exclude.matches("/src/target/debug/conserve"));
+        assert!(exclude.matches("/src/sub/target"));
+        assert!(exclude.matches("/src/main.o"));
+        assert!(exclude.matches("/src/sub/lib.o"));
+        assert!(!exclude.matches("/src/keep.o"));
+        assert!(!exclude.matches("/src/sub/keep.o"));
+        assert!(exclude.matches("/src/local"));
+        assert!(!exclude.matches("/src/sub/local"));
+        assert!(exclude.matches("/src/docs/index.html"));
+        assert!(!exclude.matches("/src/sub/docs/index.html"));
+        // Patterns in the file only apply below its directory.
+        assert!(!exclude.matches("/main.o"));
+        assert!(!exclude.matches("/other/target"));
+        assert_eq!(exclude.decide(&Apath::from("/src/keep.o")), Some(false));
+        assert_eq!(exclude.decide(&Apath::from("/src/main.rs")), None);
+    }
+
+    #[test]
+    fn ignore_file_in_root() {
+        let exclude = Exclude::from_ignore_file(&Apath::root(), "*.tmp\n/cache\n").unwrap();
+        assert!(exclude.matches("/a.tmp"));
+        assert!(exclude.matches("/sub/a.tmp"));
+        assert!(exclude.matches("/cache"));
+        assert!(!exclude.matches("/sub/cache"));
+    }
+
     #[test]
     fn nothing_parse() {
         let exclude = Exclude::nothing();
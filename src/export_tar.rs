@@ -0,0 +1,342 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Export a stored tree as a tar stream, so that it can be read by people who
+//! don't use Conserve, without restoring it to the local filesystem.
+//!
+//! The stream is written by the `tar` crate in the GNU format: names too long for
+//! the header are in GNU long name members, and numbers too large for it are in
+//! base-256. Sparse files are written in full, with their holes as zeros. Sockets
+//! can't be represented in tar and are skipped, and ACLs and capabilities are not
+//! exported. Times before 1970 are written as 1970, and user and group names too
+//! long for the header are left out.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use tar::{Builder, EntryType, Header};
+use tracing::warn;
+
+use crate::counters::Counter;
+use crate::monitor::Monitor;
+use crate::*;
+
+/// Tar streams are padded to a whole number of records of this many bytes.
+const RECORD_SIZE: u64 = 20 * 512;
+
+/// Options for [export_tar].
+#[derive(Debug, Clone)]
+pub struct ExportTarOptions {
+    /// Export only this directory and everything below it.
+    pub subtree: Apath,
+
+    /// Leave out entries matching these patterns.
+    pub exclude: Exclude,
+
+    /// Compress the stream with zstd at this level, making a `.tar.zst`.
+    pub zstd_level: Option<i32>,
+}
+
+impl Default for ExportTarOptions {
+    fn default() -> Self {
+        ExportTarOptions {
+            subtree: Apath::root(),
+            exclude: Exclude::nothing(),
+            zstd_level: None,
+        }
+    }
+}
+
+/// Write a stored tree to a tar stream.
+///
+/// Entries are named relative to the top of the tree, so that `/etc/hosts` is
+/// stored as `etc/hosts`, even when only a subtree is exported. Hard links
+/// within the export are written as tar hard links to the first member of
+/// their group.
+pub fn export_tar(
+    stored_tree: &StoredTree,
+    out: &mut dyn Write,
+    options: &ExportTarOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<()> {
+    if let Some(level) = options.zstd_level {
+        let mut encoder = zstd::Encoder::new(out, level)?;
+        write_tar(stored_tree, &mut encoder, options, monitor)?;
+        encoder.finish()?;
+        Ok(())
+    } else {
+        write_tar(stored_tree, out, options, monitor)
+    }
+}
+
+fn write_tar(
+    stored_tree: &StoredTree,
+    out: &mut dyn Write,
+    options: &ExportTarOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<()> {
+    let mut tar = Builder::new(CountingWriter { out, len: 0 });
+    let task = monitor.start_task("Export tar".to_string());
+    // For each group of hard links, the name of the member that was written first.
+    let mut link_groups: HashMap<Apath, String> = HashMap::new();
+    for entry in stored_tree.iter_entries(
+        options.subtree.clone(),
+        options.exclude.clone(),
+        monitor.clone(),
+    )? {
+        if entry.apath == Apath::root() {
+            continue;
+        }
+        task.set_name(format!("Export {}", entry.apath));
+        let name = entry.apath[1..].to_owned();
+        let mut header = header_for(&entry);
+        match entry.kind() {
+            Kind::Dir => {
+                monitor.count(Counter::Dirs, 1);
+                header.set_entry_type(EntryType::Directory);
+                tar.append_data(&mut header, format!("{name}/"), io::empty())?;
+            }
+            Kind::File => {
+                monitor.count(Counter::Files, 1);
+                if let Some(first) = entry.link_group().and_then(|group| link_groups.get(group)) {
+                    monitor.count(Counter::Hardlinks, 1);
+                    header.set_entry_type(EntryType::Link);
+                    tar.append_link(&mut header, &name, first)?;
+                    continue;
+                }
+                let size = entry.size().unwrap_or_default();
+                header.set_size(size);
+                if let Some(group) = entry.link_group() {
+                    link_groups.insert(group.clone(), name.clone());
+                }
+                let content = stored_tree.open_entry(entry, monitor.clone())?;
+                tar.append_data(
+                    &mut header,
+                    &name,
+                    ExactReader {
+                        inner: content,
+                        remaining: size,
+                    },
+                )?;
+                monitor.count(Counter::FileBytes, size as usize);
+            }
+            Kind::Symlink => {
+                monitor.count(Counter::Symlinks, 1);
+                header.set_entry_type(EntryType::Symlink);
+                tar.append_link(
+                    &mut header,
+                    &name,
+                    entry.symlink_target().unwrap_or_default(),
+                )?;
+            }
+            Kind::CharDevice | Kind::BlockDevice | Kind::Fifo => {
+                monitor.count(Counter::SpecialFiles, 1);
+                header.set_entry_type(match entry.kind() {
+                    Kind::CharDevice => EntryType::Char,
+                    Kind::BlockDevice => EntryType::Block,
+                    _ => EntryType::Fifo,
+                });
+                if let Some(device) = entry.device() {
+                    header
+                        .set_device_major(u32::try_from(device.major).map_err(io::Error::other)?)?;
+                    header
+                        .set_device_minor(u32::try_from(device.minor).map_err(io::Error::other)?)?;
+                }
+                tar.append_data(&mut header, &name, io::empty())?;
+            }
+            Kind::Socket => {
+                warn!("Can't export socket {} to tar", entry.apath);
+            }
+            Kind::Unknown => {
+                monitor.error(Error::InvalidMetadata {
+                    details: format!("Unknown file kind {:?}", entry.apath),
+                });
+            }
+        }
+    }
+    let mut counter = tar.into_inner()?;
+    while counter.len % RECORD_SIZE != 0 {
+        let pad = (RECORD_SIZE - counter.len % RECORD_SIZE) as usize;
+        counter.write_all(&vec![0; pad])?;
+    }
+    counter.flush()?;
+    Ok(())
+}
+
+/// Make a header with the metadata of an entry, except for its type and size.
+fn header_for(entry: &IndexEntry) -> Header {
+    let mut header = Header::new_gnu();
+    let owner = entry.owner();
+    let default_mode = if entry.kind() == Kind::Dir {
+        0o755
+    } else {
+        0o644
+    };
+    header.set_mode(entry.unix_mode().bits().unwrap_or(default_mode));
+    header.set_uid(owner.uid.unwrap_or_default().into());
+    header.set_gid(owner.gid.unwrap_or_default().into());
+    if let Some(user) = &owner.user {
+        if header.set_username(user).is_err() {
+            warn!(
+                "User name of {} is too long for tar; leaving it out",
+                entry.apath
+            );
+        }
+    }
+    if let Some(group) = &owner.group {
+        if header.set_groupname(group).is_err() {
+            warn!(
+                "Group name of {} is too long for tar; leaving it out",
+                entry.apath
+            );
+        }
+    }
+    header.set_mtime(entry.mtime().unix_timestamp().try_into().unwrap_or(0));
+    header.set_size(0);
+    header
+}
+
+/// Reads exactly the expected length of a file, failing if it's shorter, so that
+/// a truncated file or tar member is an error rather than silently short.
+pub(crate) struct ExactReader<R> {
+    pub(crate) inner: R,
+    pub(crate) remaining: u64,
+}
+
+impl<R: Read> Read for ExactReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let max = self.remaining.min(buf.len() as u64) as usize;
+        let len = self.inner.read(&mut buf[..max])?;
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
+
+/// Counts the bytes written to the stream, so that it can be padded to a whole record.
+struct CountingWriter<'w> {
+    out: &'w mut dyn Write,
+    len: u64,
+}
+
+impl Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.out.write(buf)?;
+        self.len += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::monitor::test::TestMonitor;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    /// A member read back from a tar stream.
+    #[derive(Debug, PartialEq)]
+    struct Member {
+        name: String,
+        entry_type: EntryType,
+        linkname: String,
+        content: Vec<u8>,
+    }
+
+    fn read_tar(data: &[u8]) -> Vec<Member> {
+        assert_eq!(data.len() as u64 % RECORD_SIZE, 0);
+        tar::Archive::new(data)
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content).unwrap();
+                Member {
+                    name: entry.path().unwrap().to_str().unwrap().to_owned(),
+                    entry_type: entry.header().entry_type(),
+                    linkname: entry
+                        .link_name()
+                        .unwrap()
+                        .map(|link| link.to_str().unwrap().to_owned())
+                        .unwrap_or_default(),
+                    content,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn export_tree() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file_with_contents("hello", b"hello world\n");
+        srcdir.create_dir("subdir");
+        let long_name = format!("subdir/{}", "long".repeat(40));
+        srcdir.create_file_with_contents(&long_name, b"long\n");
+        #[cfg(unix)]
+        srcdir.create_symlink("link", &"target".repeat(30));
+        backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+        let stored_tree = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+
+        let mut tar = Vec::new();
+        let monitor = TestMonitor::arc();
+        export_tar(&stored_tree, &mut tar, &Default::default(), monitor.clone()).unwrap();
+        monitor.assert_no_errors();
+        let members = read_tar(&tar);
+        let mut expected = vec![Member {
+            name: "hello".to_owned(),
+            entry_type: EntryType::Regular,
+            linkname: String::new(),
+            content: b"hello world\n".to_vec(),
+        }];
+        #[cfg(unix)]
+        expected.push(Member {
+            name: "link".to_owned(),
+            entry_type: EntryType::Symlink,
+            linkname: "target".repeat(30),
+            content: Vec::new(),
+        });
+        expected.extend([
+            Member {
+                name: "subdir/".to_owned(),
+                entry_type: EntryType::Directory,
+                linkname: String::new(),
+                content: Vec::new(),
+            },
+            Member {
+                name: long_name,
+                entry_type: EntryType::Regular,
+                linkname: String::new(),
+                content: b"long\n".to_vec(),
+            },
+        ]);
+        assert_eq!(members, expected);
+
+        let options = ExportTarOptions {
+            zstd_level: Some(3),
+            ..Default::default()
+        };
+        let mut compressed = Vec::new();
+        export_tar(&stored_tree, &mut compressed, &options, TestMonitor::arc()).unwrap();
+        assert_eq!(zstd::decode_all(compressed.as_slice()).unwrap(), tar);
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Find every stored version of the entries whose apath matches a pattern, or of
+//! one entry, to answer questions like "when did this file exist, and when did it
+//! change?"
+//!
+//! [find] reads the whole index of every band, while [history] reads only the
+//! index hunks that might hold its entry. No file content is read.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use globset::{GlobBuilder, GlobMatcher};
+use rayon::prelude::*;
+use regex::Regex;
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use crate::bandid::serialize_band_id;
+use crate::monitor::Monitor;
+use crate::*;
+
+/// Which apaths to look for.
+#[derive(Debug, Clone)]
+pub enum FindPattern {
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl FindPattern {
+    /// Match apaths against a glob.
+    ///
+    /// As for exclusions, patterns starting with `/` match the whole apath, and other
+    /// patterns match the end of the apath, so `*.txt` finds text files anywhere.
+    pub fn glob(pattern: &str) -> Result<FindPattern> {
+        let pattern: Cow<str> = if pattern.starts_with('/') {
+            Cow::Borrowed(pattern)
+        } else {
+            Cow::Owned(format!("**/{pattern}"))
+        };
+        Ok(FindPattern::Glob(
+            GlobBuilder::new(&pattern)
+                .literal_separator(true)
+                .build()?
+                .compile_matcher(),
+        ))
+    }
+
+    /// Match apaths against a regular expression, which matches anywhere in the apath
+    /// unless it's anchored.
+    pub fn regex(pattern: &str) -> Result<FindPattern> {
+        Ok(FindPattern::Regex(Regex::new(pattern)?))
+    }
+
+    pub fn matches(&self, apath: &Apath) -> bool {
+        let apath: &str = apath.as_ref();
+        match self {
+            FindPattern::Glob(matcher) => matcher.is_match(apath),
+            FindPattern::Regex(regex) => regex.is_match(apath),
+        }
+    }
+}
+
+/// How a version of an entry differs from the entry in the previous band.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionChange {
+    /// The entry isn't in the previous band, or this is the first band.
+    Added,
+    /// The entry's kind, size, mtime, content, or symlink target changed.
+    Changed,
+    Unchanged,
+}
+
+/// One stored version of an entry matching a [FindPattern].
+#[derive(Debug, Clone, Serialize)]
+pub struct FoundVersion {
+    pub apath: Apath,
+    #[serde(serialize_with = "serialize_band_id")]
+    pub band_id: BandId,
+    pub kind: Kind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub mtime: OffsetDateTime,
+    /// For files, a hash of the addresses of the blocks holding the content.
+    ///
+    /// Versions with the same content hash have the same content, but the same
+    /// content stored again, for example after the file was deleted and restored,
+    /// may have a different hash.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<BlockHash>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symlink_target: Option<String>,
+    pub change: VersionChange,
+}
+
+impl FoundVersion {
+    fn new(band_id: BandId, entry: &IndexEntry) -> FoundVersion {
+        let content_hash = (entry.kind() == Kind::File).then(|| {
+            BlockHash::hash_bytes(
+                &serde_json::to_vec(&(&entry.addrs, &entry.holes))
+                    .expect("Serialize block addresses"),
+            )
+        });
+        FoundVersion {
+            apath: entry.apath.clone(),
+            band_id,
+            kind: entry.kind(),
+            size: entry.size(),
+            mtime: entry.mtime(),
+            content_hash,
+            symlink_target: entry.symlink_target().map(str::to_owned),
+            change: VersionChange::Added,
+        }
+    }
+
+    /// True if this version is the same as another version of the entry.
+    fn same_as(&self, other: &FoundVersion) -> bool {
+        self.kind == other.kind
+            && self.size == other.size
+            && self.mtime == other.mtime
+            && self.content_hash == other.content_hash
+            && self.symlink_target == other.symlink_target
+    }
+}
+
+/// Find every version of the entries matching a pattern, in every band.
+///
+/// Results are ordered by apath, and then by band. Each version records whether
+/// it was added or changed since the previous band whose index could be read.
+/// Indexes and index hunks that can't be read are reported to the monitor and
+/// skipped.
+pub fn find(
+    archive: &Archive,
+    pattern: &FindPattern,
+    monitor: Arc<dyn Monitor>,
+) -> Result<Vec<FoundVersion>> {
+    let band_ids = archive.list_band_ids()?;
+    let task = monitor.start_task("Search bands".to_string());
+    task.set_total(band_ids.len());
+    let bands: Vec<Option<Vec<FoundVersion>>> = band_ids
+        .par_iter()
+        .map(|band_id| {
+            let found = find_in_band(archive, *band_id, pattern, monitor.clone());
+            task.increment(1);
+            found
+        })
+        .collect();
+    drop(task);
+    Ok(compare_versions(bands))
+}
+
+/// Find every version of one entry, from the oldest band to the newest.
+///
+/// Only the index hunks that might hold the entry are read from each band. As for
+/// [find], each version records whether it was added or changed since the previous
+/// band whose index could be read, so the distinct versions are those that aren't
+/// [VersionChange::Unchanged]. Indexes that can't be read are reported to the
+/// monitor and skipped.
+pub fn history(
+    archive: &Archive,
+    apath: &Apath,
+    monitor: Arc<dyn Monitor>,
+) -> Result<Vec<FoundVersion>> {
+    let band_ids = archive.list_band_ids()?;
+    let task = monitor.start_task("Search bands".to_string());
+    task.set_total(band_ids.len());
+    let bands: Vec<Option<Vec<FoundVersion>>> = band_ids
+        .par_iter()
+        .map(|band_id| {
+            let found = match Band::open_index(archive, *band_id).lookup(apath) {
+                Ok(entry) => Some(
+                    entry
+                        .map(|entry| FoundVersion::new(*band_id, &entry))
+                        .into_iter()
+                        .collect(),
+                ),
+                Err(err) => {
+                    monitor.error(err);
+                    None
+                }
+            };
+            task.increment(1);
+            found
+        })
+        .collect();
+    drop(task);
+    Ok(compare_versions(bands))
+}
+
+/// Given the versions found in each band in order, or None for bands whose index
+/// couldn't be read, order them by apath and then band, and mark how each differs
+/// from the version in the previous readable band.
+fn compare_versions(bands: Vec<Option<Vec<FoundVersion>>>) -> Vec<FoundVersion> {
+    // Group the versions of each apath, numbering bands with readable indexes so
+    // that each version can be compared to the one in the previous band.
+    let mut by_apath: BTreeMap<Apath, Vec<(usize, FoundVersion)>> = BTreeMap::new();
+    for (band_number, found) in bands.into_iter().flatten().enumerate() {
+        for version in found {
+            by_apath
+                .entry(version.apath.clone())
+                .or_default()
+                .push((band_number, version));
+        }
+    }
+    let mut results = Vec::new();
+    for versions in by_apath.into_values() {
+        let mut previous: Option<(usize, FoundVersion)> = None;
+        for (band_number, mut version) in versions {
+            if let Some((previous_number, previous_version)) = &previous {
+                if previous_number + 1 == band_number {
+                    version.change = if version.same_as(previous_version) {
+                        VersionChange::Unchanged
+                    } else {
+                        VersionChange::Changed
+                    };
+                }
+            }
+            previous = Some((band_number, version.clone()));
+            results.push(version);
+        }
+    }
+    results
+}
+
+/// Return the matching entries in one band, or None if its index can't be read.
+fn find_in_band(
+    archive: &Archive,
+    band_id: BandId,
+    pattern: &FindPattern,
+    monitor: Arc<dyn Monitor>,
+) -> Option<Vec<FoundVersion>> {
+    let hunks = match Band::open_index(archive, band_id).read_each_hunk() {
+        Ok(hunks) => hunks,
+        Err(err) => {
+            monitor.error(err);
+            return None;
+        }
+    };
+    let mut found = Vec::new();
+    for (_hunk_number, result) in hunks {
+        match result {
+            Ok(entries) => found.extend(
+                entries
+                    .iter()
+                    .filter(|entry| pattern.matches(&entry.apath))
+                    .map(|entry| FoundVersion::new(band_id, entry)),
+            ),
+            Err(err) => monitor.error(err),
+        }
+    }
+    Some(found)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        let pattern = FindPattern::glob("*.txt").unwrap();
+        assert!(pattern.matches(&"/a.txt".into()));
+        assert!(pattern.matches(&"/sub/b.txt".into()));
+        assert!(!pattern.matches(&"/a.txt/c".into()));
+
+        let pattern = FindPattern::glob("/sub/*").unwrap();
+        assert!(pattern.matches(&"/sub/b.txt".into()));
+        assert!(!pattern.matches(&"/sub/deeper/c".into()));
+        assert!(!pattern.matches(&"/other/sub/b.txt".into()));
+    }
+
+    #[test]
+    fn regex_patterns() {
+        let pattern = FindPattern::regex(r"^/sub/.*\.txt$").unwrap();
+        assert!(pattern.matches(&"/sub/deeper/c.txt".into()));
+        assert!(!pattern.matches(&"/a.txt".into()));
+        assert!(FindPattern::regex("(").is_err());
+    }
+}
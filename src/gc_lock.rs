@@ -60,11 +60,11 @@ impl GarbageCollectionLock {
                 return Err(Error::DeleteWithIncompleteBackup { band_id });
             }
         }
-        if archive.transport().is_file(GC_LOCK).unwrap_or(true) {
-            return Err(Error::GarbageCollectionLockHeld);
+        match archive.transport().write_new_file(GC_LOCK, b"{}\n") {
+            Ok(()) => Ok(GarbageCollectionLock { archive, band_id }),
+            Err(err) if err.is_already_exists() => Err(Error::GarbageCollectionLockHeld),
+            Err(err) => Err(err.into()),
         }
-        archive.transport().write_file(GC_LOCK, b"{}\n")?;
-        Ok(GarbageCollectionLock { archive, band_id })
     }
 
     /// Take a lock on an archive, breaking any existing gc lock.
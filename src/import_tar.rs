@@ -0,0 +1,858 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Import a tar stream as a new band, so that backups kept as tarballs can be
+//! moved into a deduplicated archive.
+//!
+//! The stream may be compressed with zstd, which is detected from its first
+//! bytes. Members are read with the `tar` crate, which understands ustar, pax, and
+//! GNU long-name headers; pax global headers are applied here. Sparse members,
+//! and member types other than files, directories, links, devices and FIFOs, are
+//! reported as errors and skipped.
+//!
+//! Tar streams needn't be in any order, and can name the same path more than
+//! once, so the index entries are held in memory until the end of the stream.
+//! File content is stored as it's read.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Cursor, Read};
+use std::str;
+use std::sync::Arc;
+use std::time::Instant;
+
+use itertools::Itertools;
+use time::OffsetDateTime;
+use tracing::field::display;
+
+use crate::backup::{backup_span, record_backup_stats, store_file_content, BackupWriter};
+use crate::compress::MAX_BLOCK_SIZE;
+use crate::counters::Counter;
+use crate::entry::KindMeta;
+use crate::export_tar::ExactReader;
+use crate::monitor::{check_cancel, Monitor};
+use crate::*;
+
+const BLOCK_SIZE: u64 = 512;
+
+/// Magic number at the start of a zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Read a tar stream, optionally compressed with zstd, and store it as a new band.
+///
+/// Member names are stored relative to the root of the band, so both `etc/hosts`
+/// and `./etc/hosts` become `/etc/hosts`. Directories that contain members but
+/// aren't themselves in the stream are added. If a path occurs more than once, the
+/// last member wins, as it would when the tar is extracted.
+///
+/// Members that can't be stored are reported to the monitor and skipped; a stream
+/// that's not a valid tar fails with [Error::InvalidTar].
+pub fn import_tar(
+    archive: &Archive,
+    from: &mut dyn Read,
+    options: &BackupOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<BackupStats> {
+    let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+    (&mut *from)
+        .take(ZSTD_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    let mut from = Cursor::new(magic.clone()).chain(from);
+    if magic == ZSTD_MAGIC {
+        let mut decoder = zstd::Decoder::new(from)?;
+        import_members(archive, &mut decoder, options, monitor)
+    } else {
+        import_members(archive, &mut from, options, monitor)
+    }
+}
+
+fn import_members(
+    archive: &Archive,
+    from: &mut dyn Read,
+    options: &BackupOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<BackupStats> {
+    let start = Instant::now();
+    let span = backup_span();
+    let mut writer = BackupWriter::begin(archive, &[], options, monitor.clone())?;
+    span.record("band_id", display(writer.band.id()));
+    let mtime = OffsetDateTime::now_utc();
+    let task = monitor.start_task("Import tar".to_string());
+    let mut tar = tar::Archive::new(from);
+    // Values from pax global headers, which apply to all following members.
+    let mut globals: HashMap<String, String> = HashMap::new();
+    // The offset of the next header, for errors about it.
+    let mut offset = 0;
+    // Each entry is kept with the number of the member that set it, so that members
+    // stored in the file combiner, and hard links, are only used if they weren't
+    // replaced by a later member of the same name.
+    let mut entries: BTreeMap<Apath, (usize, IndexEntry)> = BTreeMap::new();
+    let mut combined_members: Vec<usize> = Vec::new();
+    let mut hardlinks: Vec<(usize, Apath, Apath)> = Vec::new();
+    let mut member_number = 0;
+    for tar_entry in tar.entries()? {
+        let mut tar_entry = tar_entry.map_err(|err| Error::InvalidTar {
+            offset,
+            details: err.to_string(),
+        })?;
+        offset = tar_entry.raw_file_position() + tar_entry.size() + padding(tar_entry.size());
+        check_cancel(monitor.as_ref())?;
+        if tar_entry.header().entry_type().is_pax_global_extensions() {
+            read_pax_values(&mut tar_entry, &mut globals).map_err(|err| Error::InvalidTar {
+                offset: tar_entry.raw_header_position(),
+                details: format!("Invalid pax global header: {err}"),
+            })?;
+            continue;
+        }
+        let member = Member::read(&mut tar_entry, &globals)?;
+        member_number += 1;
+        let entry = match member.entry_value(options) {
+            Ok(entry) => entry,
+            Err(err) => {
+                monitor.error(err);
+                continue;
+            }
+        };
+        let apath = entry.apath.clone();
+        if options.exclude.matches(&apath) {
+            continue;
+        }
+        task.set_name(format!("Import {apath}"));
+        let index_entry = match entry.kind() {
+            Kind::Dir => {
+                monitor.count(Counter::Dirs, 1);
+                IndexEntry::metadata_from(&entry)
+            }
+            Kind::Symlink => {
+                monitor.count(Counter::Symlinks, 1);
+                IndexEntry::metadata_from(&entry)
+            }
+            Kind::File if member.typeflag == b'1' => {
+                monitor.count(Counter::Hardlinks, 1);
+                match member_apath(&member.linkname) {
+                    Some(target) => hardlinks.push((member_number, apath.clone(), target)),
+                    None => {
+                        monitor.error(Error::UnsupportedTarMember {
+                            name: member.name,
+                            details: format!("Hard link to invalid name {:?}", member.linkname),
+                        });
+                        continue;
+                    }
+                }
+                IndexEntry::metadata_from(&entry)
+            }
+            Kind::File => {
+                monitor.count(Counter::Files, 1);
+                if member.size == 0 {
+                    IndexEntry::metadata_from(&entry)
+                } else if member.size <= options.small_file_cap.min(MAX_BLOCK_SIZE as u64) {
+                    let mut content = Vec::with_capacity(member.size as usize);
+                    ExactReader {
+                        inner: &mut tar_entry,
+                        remaining: member.size,
+                    }
+                    .read_to_end(&mut content)?;
+                    monitor.count(Counter::SmallFiles, 1);
+                    writer.file_combiner.push_file(
+                        &entry,
+                        &mut content.as_slice(),
+                        &mut writer.block_writer,
+                        monitor.clone(),
+                    )?;
+                    combined_members.push(member_number);
+                    // The entry is filled in with its address when the combined block
+                    // is written.
+                    IndexEntry::metadata_from(&entry)
+                } else {
+                    let addrs = store_file_content(
+                        &apath,
+                        &mut ExactReader {
+                            inner: &mut tar_entry,
+                            remaining: member.size,
+                        },
+                        &mut writer.block_writer,
+                        writer.compression,
+                        &mut writer.stats,
+                        options,
+                        monitor.clone(),
+                    )?;
+                    IndexEntry {
+                        addrs,
+                        ..IndexEntry::metadata_from(&entry)
+                    }
+                }
+            }
+            _ => {
+                monitor.count(Counter::SpecialFiles, 1);
+                IndexEntry::metadata_from(&entry)
+            }
+        };
+        entries.insert(apath, (member_number, index_entry));
+    }
+
+    let (combined_stats, combined_entries) = writer.drain_blocks(monitor.clone())?;
+    writer.stats += combined_stats;
+    let combined_entries = combined_entries.into_iter().collect::<Result<Vec<_>>>()?;
+    for (member_number, index_entry) in combined_members.into_iter().zip(combined_entries) {
+        if let Some(current) = entries.get_mut(&index_entry.apath) {
+            if current.0 == member_number {
+                current.1 = index_entry;
+            }
+        }
+    }
+    resolve_hardlinks(&mut entries, hardlinks, monitor.as_ref());
+    add_parent_directories(&mut entries, mtime);
+
+    for chunk in &entries.into_values().chunks(options.max_entries_per_hunk) {
+        for (_, entry) in chunk {
+            count_entry(&entry, &mut writer.stats);
+            if entry.kind.is_special() {
+                writer.band.add_format_flag(band::flags::SPECIAL_FILES)?;
+            }
+            if let Some(cb) = &options.change_callback {
+                cb(&EntryChange::added(&entry))?;
+            }
+            writer.index_builder.push_entry(entry);
+        }
+        writer.index_builder.finish_hunk(monitor.clone())?;
+    }
+    let mut stats = writer.finish(monitor)?;
+    stats.elapsed = start.elapsed();
+    record_backup_stats(&span, &stats);
+    Ok(stats)
+}
+
+/// Give hard links the content of the member they link to, and put each group of
+/// links in a link group named by its first apath.
+///
+/// Links are resolved in the order they occur in the stream, so that a link can
+/// refer to another link.
+fn resolve_hardlinks(
+    entries: &mut BTreeMap<Apath, (usize, IndexEntry)>,
+    hardlinks: Vec<(usize, Apath, Apath)>,
+    monitor: &dyn Monitor,
+) {
+    // For each linked apath, the apath whose content it shares.
+    let mut group_of: HashMap<Apath, Apath> = HashMap::new();
+    for (member_number, apath, target) in hardlinks {
+        if entries.get(&apath).map(|(n, _)| *n) != Some(member_number) {
+            continue; // Replaced by a later member.
+        }
+        let Some(target_entry) = entries
+            .get(&target)
+            .map(|(_, e)| e)
+            .filter(|e| e.kind == Kind::File && target != apath)
+            .cloned()
+        else {
+            entries.remove(&apath);
+            monitor.error(Error::UnsupportedTarMember {
+                name: apath[1..].to_owned(),
+                details: format!("Hard link target {target} is not a file in the stream"),
+            });
+            continue;
+        };
+        let group = group_of.get(&target).unwrap_or(&target).clone();
+        group_of.insert(target, group.clone());
+        group_of.insert(apath.clone(), group);
+        entries.insert(
+            apath.clone(),
+            (
+                member_number,
+                IndexEntry {
+                    apath,
+                    link_group: None,
+                    ..target_entry
+                },
+            ),
+        );
+    }
+    let mut groups: HashMap<Apath, Vec<Apath>> = HashMap::new();
+    for (apath, group) in group_of {
+        groups.entry(group).or_default().push(apath);
+    }
+    for members in groups.into_values() {
+        let first = members.iter().min().expect("group has members").clone();
+        for apath in members {
+            if let Some((_, entry)) = entries.get_mut(&apath) {
+                entry.link_group = Some(first.clone());
+            }
+        }
+    }
+}
+
+/// Add directory entries for the parents of members whose directories weren't in
+/// the stream, including the root.
+fn add_parent_directories(
+    entries: &mut BTreeMap<Apath, (usize, IndexEntry)>,
+    mtime: OffsetDateTime,
+) {
+    let mut missing = Vec::new();
+    for apath in entries.keys().chain([&Apath::root()]) {
+        let mut parent = Some(apath.clone());
+        while let Some(dir) = parent {
+            if !entries.contains_key(&dir) {
+                missing.push(dir.clone());
+            }
+            parent = dir.parent();
+        }
+    }
+    for apath in missing {
+        let entry = EntryValue {
+            apath: apath.clone(),
+            kind_meta: KindMeta::Dir,
+            mtime,
+            unix_mode: UnixMode::default(),
+            owner: Owner::default(),
+            acls: Acls::default(),
+            capability: None,
+            link_group: None,
+            inode: None,
+        };
+        entries.insert(apath, (0, IndexEntry::metadata_from(&entry)));
+    }
+}
+
+fn count_entry(entry: &IndexEntry, stats: &mut BackupStats) {
+    match entry.kind {
+        Kind::Dir => stats.directories += 1,
+        Kind::File => {
+            stats.files += 1;
+            stats.new_files += 1;
+            if entry.addrs.is_empty() {
+                stats.empty_files += 1;
+            }
+            if entry
+                .link_group
+                .as_ref()
+                .is_some_and(|first| *first != entry.apath)
+            {
+                stats.hardlinks += 1;
+            }
+        }
+        Kind::Symlink => stats.symlinks += 1,
+        Kind::CharDevice | Kind::BlockDevice | Kind::Fifo | Kind::Socket => {
+            stats.special_files += 1
+        }
+        Kind::Unknown => stats.unknown_kind += 1,
+    }
+}
+
+/// Convert a member name to an apath, or None if it escapes the root.
+fn member_apath(name: &str) -> Option<Apath> {
+    let mut apath = String::new();
+    for part in name.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => return None,
+            _ => {
+                apath.push('/');
+                apath.push_str(part);
+            }
+        }
+    }
+    if apath.is_empty() {
+        Some(Apath::root())
+    } else {
+        Some(apath.into())
+    }
+}
+
+/// A member header, with any extended headers applied.
+#[derive(Debug)]
+struct Member {
+    name: String,
+    typeflag: u8,
+    size: u64,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    uname: String,
+    gname: String,
+    mtime: OffsetDateTime,
+    linkname: String,
+    device: DeviceNumber,
+    /// True for GNU sparse members, in either the old GNU or the pax form.
+    sparse: bool,
+}
+
+impl Member {
+    /// Read the header of an entry, with its extended headers and the pax global
+    /// values applied.
+    fn read<R: Read>(
+        entry: &mut tar::Entry<R>,
+        globals: &HashMap<String, String>,
+    ) -> Result<Member> {
+        let offset = entry.raw_header_position();
+        let invalid = |details: String| Error::InvalidTar { offset, details };
+        let mut extended = HashMap::new();
+        read_pax_values(entry, &mut extended)
+            .map_err(|err| invalid(format!("Invalid pax extended header: {err}")))?;
+        let value = |key: &str| extended.get(key).or_else(|| globals.get(key));
+        let text = |bytes: &[u8]| {
+            str::from_utf8(bytes)
+                .map(str::to_owned)
+                .map_err(|_| invalid("Name is not UTF-8".to_owned()))
+        };
+        let header = entry.header();
+        let typeflag = header.entry_type().as_byte();
+        let number = |key: &str, field: io::Result<u64>| match value(key) {
+            Some(v) => v
+                .parse::<u64>()
+                .map_err(|_| invalid(format!("Invalid pax {key} {v:?}"))),
+            None => field.map_err(|err| invalid(err.to_string())),
+        };
+        let mtime = match value("mtime") {
+            Some(v) => {
+                parse_pax_time(v).ok_or_else(|| invalid(format!("Invalid pax mtime {v:?}")))?
+            }
+            None => header
+                .mtime()
+                .map_err(|err| invalid(err.to_string()))
+                .and_then(|seconds| {
+                    i64::try_from(seconds)
+                        .ok()
+                        .and_then(|seconds| OffsetDateTime::from_unix_timestamp(seconds).ok())
+                        .ok_or_else(|| invalid("Invalid mtime".to_owned()))
+                })?,
+        };
+        let device = if matches!(typeflag, b'3' | b'4') {
+            let field = |number: io::Result<Option<u32>>| {
+                number
+                    .map(|n| u64::from(n.unwrap_or_default()))
+                    .map_err(|err| invalid(err.to_string()))
+            };
+            DeviceNumber {
+                major: field(header.device_major())?,
+                minor: field(header.device_minor())?,
+            }
+        } else {
+            DeviceNumber { major: 0, minor: 0 }
+        };
+        Ok(Member {
+            name: text(&entry.path_bytes())?,
+            typeflag,
+            size: entry.size(),
+            mode: header.mode().map_err(|err| invalid(err.to_string()))?,
+            uid: number("uid", header.uid())?,
+            gid: number("gid", header.gid())?,
+            uname: match value("uname") {
+                Some(v) => v.clone(),
+                None => String::from_utf8_lossy(header.username_bytes().unwrap_or_default())
+                    .into_owned(),
+            },
+            gname: match value("gname") {
+                Some(v) => v.clone(),
+                None => String::from_utf8_lossy(header.groupname_bytes().unwrap_or_default())
+                    .into_owned(),
+            },
+            mtime,
+            linkname: text(&entry.link_name_bytes().unwrap_or_default())?,
+            device,
+            sparse: header.entry_type().is_gnu_sparse()
+                || extended.keys().any(|key| key.starts_with("GNU.sparse.")),
+        })
+    }
+
+    /// Describe this member as an entry to store, or return an error if it can't be
+    /// stored.
+    fn entry_value(&self, options: &BackupOptions) -> Result<EntryValue> {
+        let unsupported = |details: &str| Error::UnsupportedTarMember {
+            name: self.name.clone(),
+            details: details.to_owned(),
+        };
+        let apath = member_apath(&self.name).ok_or_else(|| unsupported("Name contains \"..\""))?;
+        if self.sparse {
+            return Err(unsupported("Sparse members are not supported"));
+        }
+        let kind_meta = match self.typeflag {
+            b'0' | b'7' | 0 | b'1' => KindMeta::File { size: self.size },
+            b'2' => KindMeta::Symlink {
+                target: self.linkname.clone(),
+            },
+            b'3' => KindMeta::CharDevice {
+                device: self.device,
+            },
+            b'4' => KindMeta::BlockDevice {
+                device: self.device,
+            },
+            b'5' => KindMeta::Dir,
+            b'6' => KindMeta::Fifo,
+            other => {
+                return Err(unsupported(&format!(
+                    "Unsupported member type {:?}",
+                    char::from(other)
+                )))
+            }
+        };
+        if apath == Apath::root() && kind_meta != KindMeta::Dir {
+            return Err(unsupported("Only a directory can be stored at the root"));
+        }
+        let owner = if options.owner {
+            Owner {
+                user: Some(self.uname.clone()).filter(|s| !s.is_empty()),
+                group: Some(self.gname.clone()).filter(|s| !s.is_empty()),
+                uid: self.uid.try_into().ok(),
+                gid: self.gid.try_into().ok(),
+            }
+        } else {
+            Owner::default()
+        };
+        Ok(EntryValue {
+            apath,
+            kind_meta,
+            mtime: self.mtime,
+            unix_mode: self.mode.into(),
+            owner,
+            acls: Acls::default(),
+            capability: None,
+            link_group: None,
+            inode: None,
+        })
+    }
+}
+
+/// Read the values from a pax extended header into `values`.
+///
+/// An empty value removes the key, so that a member can cancel a global value.
+fn read_pax_values<R: Read>(
+    entry: &mut tar::Entry<R>,
+    values: &mut HashMap<String, String>,
+) -> io::Result<()> {
+    if let Some(extensions) = entry.pax_extensions()? {
+        for extension in extensions {
+            let extension = extension?;
+            let key = extension.key().map_err(io::Error::other)?;
+            let value = extension.value().map_err(io::Error::other)?;
+            if value.is_empty() {
+                values.remove(key);
+            } else {
+                values.insert(key.to_owned(), value.to_owned());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Bytes of padding after content of this size, to the end of the block.
+fn padding(size: u64) -> u64 {
+    (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE
+}
+
+/// Parse a pax time, in decimal seconds with an optional fraction.
+fn parse_pax_time(value: &str) -> Option<OffsetDateTime> {
+    let (seconds, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let seconds: i64 = seconds.parse().ok()?;
+    let mut nanos: i64 = 0;
+    for (i, digit) in fraction.chars().take(9).enumerate() {
+        nanos += i64::from(digit.to_digit(10)?) * 10i64.pow(8 - i as u32);
+    }
+    if value.starts_with('-') {
+        nanos = -nanos;
+    }
+    OffsetDateTime::from_unix_timestamp_nanos(
+        i128::from(seconds) * 1_000_000_000 + i128::from(nanos),
+    )
+    .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::monitor::test::TestMonitor;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    /// Encode a ustar member, with its content and padding.
+    fn member(name: &str, typeflag: u8, linkname: &str, content: &[u8]) -> Vec<u8> {
+        let mut block = [0; BLOCK_SIZE as usize];
+        block[..name.len()].copy_from_slice(name.as_bytes());
+        block[100..108].copy_from_slice(b"0000644\0");
+        block[108..116].copy_from_slice(b"0001750\0");
+        block[116..124].copy_from_slice(b"0001750\0");
+        block[124..136].copy_from_slice(format!("{:011o}\0", content.len()).as_bytes());
+        block[136..148].copy_from_slice(b"14000000000\0");
+        block[156] = typeflag;
+        block[157..157 + linkname.len()].copy_from_slice(linkname.as_bytes());
+        block[257..263].copy_from_slice(b"ustar\0");
+        block[263..265].copy_from_slice(b"00");
+        block[148..156].fill(b' ');
+        let sum: u32 = block.iter().map(|&b| u32::from(b)).sum();
+        block[148..155].copy_from_slice(format!("{sum:06o}\0").as_bytes());
+        let mut data = block.to_vec();
+        data.extend_from_slice(content);
+        data.resize(data.len() + padding(content.len() as u64) as usize, 0);
+        data
+    }
+
+    /// Read the apath, link group, and content of every entry in the latest band.
+    fn stored_entries(af: &ScratchArchive) -> Vec<(String, Kind, Option<String>, Vec<u8>)> {
+        let stored_tree = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+        let monitor = TestMonitor::arc();
+        stored_tree
+            .iter_entries(Apath::root(), Exclude::nothing(), monitor.clone())
+            .unwrap()
+            .map(|entry| {
+                let mut content = Vec::new();
+                if entry.kind() == Kind::File {
+                    stored_tree
+                        .open_entry(entry.clone(), monitor.clone())
+                        .unwrap()
+                        .read_to_end(&mut content)
+                        .unwrap();
+                }
+                (
+                    entry.apath.to_string(),
+                    entry.kind(),
+                    entry.link_group.as_ref().map(Apath::to_string),
+                    content,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn import_exported_tree() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file_with_contents("hello", b"hello world\n");
+        srcdir.create_dir("subdir");
+        srcdir.create_file_with_contents("subdir/big", &vec![b'x'; 300_000]);
+        #[cfg(unix)]
+        srcdir.create_symlink("link", "hello");
+        backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+        let mut tar = Vec::new();
+        export_tar(
+            &af.open_stored_tree(BandSelectionPolicy::Latest).unwrap(),
+            &mut tar,
+            &ExportTarOptions {
+                zstd_level: Some(3),
+                ..Default::default()
+            },
+            TestMonitor::arc(),
+        )
+        .unwrap();
+
+        let imported = ScratchArchive::new();
+        let monitor = TestMonitor::arc();
+        let options = BackupOptions {
+            small_file_cap: 100_000,
+            ..Default::default()
+        };
+        let stats = import_tar(&imported, &mut tar.as_slice(), &options, monitor.clone()).unwrap();
+        monitor.assert_no_errors();
+        assert_eq!(stored_entries(&imported), stored_entries(&af));
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.small_combined_files, 1);
+        assert_eq!(stats.directories, 2);
+    }
+
+    #[test]
+    fn import_stream() {
+        let long_name = "long".repeat(40);
+        let mut tar = Vec::new();
+        tar.extend(member("pax_global_header", b'g', "", b"15 uname=alice\n"));
+        tar.extend(member("./a/b/file", b'0', "", b"hello"));
+        tar.extend(member("././@LongLink", b'L', "", long_name.as_bytes()));
+        tar.extend(member("truncated", b'0', "", b"long"));
+        tar.extend(member("../escape", b'0', "", b"escape"));
+        tar.extend(member("a/b/file", b'0', "", b"replaced"));
+        tar.extend(member("link", b'1', "a/b/file", b""));
+        tar.extend([0; 2 * BLOCK_SIZE as usize]);
+
+        let af = ScratchArchive::new();
+        let monitor = TestMonitor::arc();
+        import_tar(
+            &af,
+            &mut tar.as_slice(),
+            &Default::default(),
+            monitor.clone(),
+        )
+        .unwrap();
+        let errors = monitor.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(
+            matches!(&errors[0], Error::UnsupportedTarMember { name, .. } if name == "../escape"),
+            "{errors:?}"
+        );
+        // The first link in apath order names the group.
+        let group = Some("/link".to_owned());
+        assert_eq!(
+            stored_entries(&af),
+            [
+                ("/".to_owned(), Kind::Dir, None, Vec::new()),
+                ("/a".to_owned(), Kind::Dir, None, Vec::new()),
+                (
+                    "/link".to_owned(),
+                    Kind::File,
+                    group.clone(),
+                    b"replaced".to_vec()
+                ),
+                (format!("/{long_name}"), Kind::File, None, b"long".to_vec()),
+                ("/a/b".to_owned(), Kind::Dir, None, Vec::new()),
+                (
+                    "/a/b/file".to_owned(),
+                    Kind::File,
+                    group,
+                    b"replaced".to_vec()
+                ),
+            ]
+        );
+        let stored_tree = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+        let file = stored_tree
+            .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+            .unwrap()
+            .find(|entry| entry.apath == "/a/b/file")
+            .unwrap();
+        assert_eq!(file.owner.user.as_deref(), Some("alice"));
+        assert_eq!(file.mtime, 0o14000000000);
+    }
+
+    #[test]
+    fn damaged_header_is_an_error() {
+        let mut tar = member("file", b'0', "", b"hello");
+        tar[0] = b'F';
+        let err = import_tar(
+            &ScratchArchive::new(),
+            &mut tar.as_slice(),
+            &Default::default(),
+            TestMonitor::arc(),
+        )
+        .unwrap_err();
+        assert!(
+            matches!(err, Error::InvalidTar { offset: 0, .. }),
+            "{err:?}"
+        );
+    }
+
+    #[test]
+    fn pax_and_gnu_long_names() {
+        let long_target = "target".repeat(30);
+        let mut tar = Vec::new();
+        tar.extend(member(
+            "PaxHeaders/file",
+            b'x',
+            "",
+            b"17 path=pax/file\n17 mtime=1000.25\n",
+        ));
+        tar.extend(member("file", b'0', "", b"hello"));
+        tar.extend(member("././@LongLink", b'K', "", long_target.as_bytes()));
+        tar.extend(member("link", b'2', "short", b""));
+        tar.extend([0; 2 * BLOCK_SIZE as usize]);
+
+        let af = ScratchArchive::new();
+        let monitor = TestMonitor::arc();
+        import_tar(
+            &af,
+            &mut tar.as_slice(),
+            &Default::default(),
+            monitor.clone(),
+        )
+        .unwrap();
+        monitor.assert_no_errors();
+        let stored_tree = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+        let entries: Vec<_> = stored_tree
+            .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+            .unwrap()
+            .collect();
+        let file = entries.iter().find(|e| e.apath == "/pax/file").unwrap();
+        assert_eq!(file.mtime, 1000);
+        assert_eq!(file.mtime_nanos, 250_000_000);
+        let link = entries.iter().find(|e| e.apath == "/link").unwrap();
+        assert_eq!(link.symlink_target(), Some(long_target.as_str()));
+    }
+
+    #[test]
+    fn malformed_headers_are_errors() {
+        let valid = member("file", b'0', "", b"hello");
+        let with_field = |range: std::ops::Range<usize>, value: &[u8]| {
+            let mut tar = valid.clone();
+            tar[range].copy_from_slice(value);
+            // Fix the checksum, so that the field itself is what's wrong.
+            tar[148..156].fill(b' ');
+            let sum: u32 = tar[..BLOCK_SIZE as usize]
+                .iter()
+                .map(|&b| u32::from(b))
+                .sum();
+            tar[148..155].copy_from_slice(format!("{sum:06o}\0").as_bytes());
+            tar
+        };
+        let pax_before = |records: &[u8]| {
+            let mut tar = member("PaxHeaders/file", b'x', "", records);
+            tar.extend_from_slice(&valid);
+            tar
+        };
+        let cases = [
+            ("bad octal size", with_field(124..136, b"0000000008x\0")),
+            ("bad octal mode", with_field(100..108, b"0009644\0")),
+            ("bad octal mtime", with_field(136..148, b"1400000000z\0")),
+            ("pax record length too long", pax_before(b"99 path=file\n")),
+            ("pax record without a length", pax_before(b"path=file\n")),
+            ("pax record without a newline", pax_before(b"13 path=file ")),
+            ("non-numeric pax uid", pax_before(b"11 uid=abc\n")),
+            ("bad pax mtime", pax_before(b"13 mtime=1.x\n")),
+            (
+                "long name without a member",
+                member("././@LongLink", b'L', "", b"name"),
+            ),
+            ("truncated header", valid[..100].to_vec()),
+        ];
+        for (description, tar) in cases {
+            let result = import_tar(
+                &ScratchArchive::new(),
+                &mut tar.as_slice(),
+                &Default::default(),
+                TestMonitor::arc(),
+            );
+            assert!(
+                matches!(result, Err(Error::InvalidTar { .. })),
+                "{description}: {result:?}"
+            );
+        }
+
+        let truncated = &valid[..BLOCK_SIZE as usize + 2];
+        import_tar(
+            &ScratchArchive::new(),
+            &mut &truncated[..],
+            &Default::default(),
+            TestMonitor::arc(),
+        )
+        .unwrap_err();
+    }
+
+    proptest! {
+        #[test]
+        fn damaged_streams_do_not_panic(index in 0..3 * BLOCK_SIZE as usize, byte: u8) {
+            let mut tar = member("PaxHeaders/file", b'x', "", b"17 path=pax/file\n");
+            tar.extend(member("file", b'0', "", b"hello"));
+            tar[index] = byte;
+            let _ = import_tar(
+                &ScratchArchive::new(),
+                &mut tar.as_slice(),
+                &Default::default(),
+                TestMonitor::arc(),
+            );
+        }
+    }
+
+    #[test]
+    fn pax_times() {
+        assert_eq!(
+            parse_pax_time("1.5").unwrap().unix_timestamp_nanos(),
+            1_500_000_000
+        );
+        assert_eq!(
+            parse_pax_time("-1.25").unwrap().unix_timestamp_nanos(),
+            -1_250_000_000
+        );
+        assert_eq!(parse_pax_time("x"), None);
+    }
+}
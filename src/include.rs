@@ -0,0 +1,195 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Select only some files from a stored tree, by apath or glob.
+//!
+//! As for [Exclude], patterns that start with a slash match from the top of the
+//! tree, others match the end of the apath, and everything inside a matching
+//! directory is also selected.
+//!
+//! The leading components of each pattern that don't contain any wildcards
+//! determine a subtree that holds everything it can match, so that only those
+//! parts of the index need to be read.
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+use crate::*;
+
+/// Characters that make a pattern component a glob rather than a literal name.
+const GLOB_CHARS: &[char] = &['*', '?', '[', ']', '{', '}', '\\'];
+
+/// Describes which entries to select from a tree.
+#[derive(Clone, Debug)]
+pub struct Include {
+    /// Subtrees that contain everything that can match, in apath order, none
+    /// inside another.
+    subtrees: Vec<Apath>,
+    /// Matches selected apaths and their children, or None if everything in the
+    /// subtrees is selected.
+    globset: Option<GlobSet>,
+}
+
+impl Include {
+    /// Select everything.
+    pub fn everything() -> Include {
+        Include {
+            subtrees: vec![Apath::root()],
+            globset: None,
+        }
+    }
+
+    /// Select only a single subtree.
+    pub fn subtree(apath: Apath) -> Include {
+        Include {
+            subtrees: vec![apath],
+            globset: None,
+        }
+    }
+
+    /// Select entries matching any of these apaths or globs, and everything inside
+    /// them.
+    ///
+    /// If there are no patterns, everything is selected.
+    pub fn from_strings<I: IntoIterator<Item = S>, S: AsRef<str>>(patterns: I) -> Result<Include> {
+        let mut subtrees = Vec::new();
+        let mut gsb = GlobSetBuilder::new();
+        for pattern in patterns {
+            let pattern = pattern.as_ref();
+            let invalid = || Error::InvalidInclude {
+                pattern: pattern.to_owned(),
+            };
+            let trimmed = pattern.trim_end_matches('/');
+            let (subtree, glob) = if let Some(rooted) = trimmed.strip_prefix('/') {
+                let literal = rooted
+                    .split('/')
+                    .take_while(|part| !part.contains(GLOB_CHARS))
+                    .collect::<Vec<_>>()
+                    .join("/");
+                let subtree = format!("/{literal}");
+                if !Apath::is_valid(&subtree) {
+                    return Err(invalid());
+                }
+                (Apath::from(subtree), format!("/{rooted}"))
+            } else if trimmed.is_empty() {
+                return Err(invalid());
+            } else {
+                (Apath::root(), format!("**/{trimmed}"))
+            };
+            for glob in [glob.clone(), format!("{glob}/**")] {
+                gsb.add(
+                    GlobBuilder::new(&glob)
+                        .literal_separator(true)
+                        .build()
+                        .map_err(|source| Error::ParseGlob { source })?,
+                );
+            }
+            subtrees.push(subtree);
+        }
+        if subtrees.is_empty() {
+            return Ok(Include::everything());
+        }
+        subtrees.sort();
+        let mut outermost: Vec<Apath> = Vec::new();
+        for subtree in subtrees {
+            if !outermost.iter().any(|outer| outer.is_prefix_of(&subtree)) {
+                outermost.push(subtree);
+            }
+        }
+        Ok(Include {
+            subtrees: outermost,
+            globset: Some(gsb.build()?),
+        })
+    }
+
+    /// True if everything is selected.
+    pub fn is_everything(&self) -> bool {
+        self.globset.is_none() && self.subtrees == [Apath::root()]
+    }
+
+    /// The subtrees that contain everything that can be selected, in apath order,
+    /// none inside another.
+    pub fn subtrees(&self) -> &[Apath] {
+        &self.subtrees
+    }
+
+    /// True if this apath is selected.
+    ///
+    /// Directories that are only parents of selected entries don't match.
+    pub fn matches(&self, apath: &Apath) -> bool {
+        self.subtrees
+            .iter()
+            .any(|subtree| subtree.is_prefix_of(apath))
+            && self
+                .globset
+                .as_ref()
+                .map_or(true, |globset| globset.is_match(apath))
+    }
+}
+
+impl Default for Include {
+    fn default() -> Self {
+        Include::everything()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn everything() {
+        let include = Include::from_strings::<_, &str>([]).unwrap();
+        assert!(include.is_everything());
+        assert!(include.matches(&"/".into()));
+        assert!(include.matches(&"/a/b".into()));
+    }
+
+    #[test]
+    fn literal_apaths_select_subtrees() {
+        let include = Include::from_strings(["/home/mbp", "/etc/", "/home/mbp/src"]).unwrap();
+        assert_eq!(
+            include.subtrees(),
+            [Apath::from("/etc"), "/home/mbp".into()]
+        );
+        assert!(include.matches(&"/etc".into()));
+        assert!(include.matches(&"/etc/passwd".into()));
+        assert!(include.matches(&"/home/mbp/src/a.rs".into()));
+        assert!(!include.matches(&"/home".into()));
+        assert!(!include.matches(&"/home/mbpx".into()));
+        assert!(!include.matches(&"/var/log".into()));
+    }
+
+    #[test]
+    fn globs() {
+        let include = Include::from_strings(["/home/**/*.rs", "Cargo.toml"]).unwrap();
+        assert_eq!(include.subtrees(), [Apath::root()]);
+        assert!(include.matches(&"/home/a.rs".into()));
+        assert!(include.matches(&"/home/mbp/src/a.rs".into()));
+        assert!(include.matches(&"/Cargo.toml".into()));
+        assert!(include.matches(&"/home/mbp/Cargo.toml".into()));
+        assert!(!include.matches(&"/home".into()));
+        assert!(!include.matches(&"/home/mbp/a.c".into()));
+        assert!(!include.matches(&"/src/a.rs".into()));
+
+        let include = Include::from_strings(["/home/*/src"]).unwrap();
+        assert_eq!(include.subtrees(), [Apath::from("/home")]);
+        assert!(include.matches(&"/home/mbp/src/main.rs".into()));
+        assert!(!include.matches(&"/home/mbp/doc".into()));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert!(Include::from_strings(["/a/../b"]).is_err());
+        assert!(Include::from_strings([""]).is_err());
+        assert!(Include::from_strings(["/a/[b"]).is_err());
+    }
+}
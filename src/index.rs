@@ -20,20 +20,56 @@ use std::sync::Arc;
 use std::vec;
 
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
 use time::OffsetDateTime;
-use tracing::{debug, debug_span, error};
+use tracing::field::Empty;
+use tracing::{debug, debug_span, error, trace, warn};
 
 use crate::compress::snappy::{Compressor, Decompressor};
 use crate::counters::Counter;
 use crate::entry::KindMeta;
+use crate::jsonio::{read_json, write_json};
 use crate::monitor::Monitor;
+use crate::sparse::Hole;
 use crate::stats::IndexReadStats;
 use crate::transport::local::LocalTransport;
 use crate::unix_time::FromUnixAndNanos;
 use crate::*;
 
+mod binary;
+mod checksum;
+
 pub const HUNKS_PER_SUBDIR: u32 = 10_000;
 
+/// Name of the file in the index directory that records the range of apaths in
+/// each hunk.
+const HUNK_TABLE_FILENAME: &str = "HUNKS";
+
+/// The first and last apaths in one index hunk, as recorded in the hunk table.
+///
+/// The table lets readers seek to the hunk holding an apath without reading the
+/// hunks before it.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+struct HunkRange {
+    hunk: u32,
+    first: Apath,
+    last: Apath,
+}
+
+/// How entries are encoded in the hunks of a band's index.
+///
+/// Readers recognize the format of each hunk, so this only affects writing.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexFormat {
+    /// Json, readable by all versions of Conserve.
+    #[default]
+    Json,
+    /// A compact binary encoding, which is faster to write and read, but can't be
+    /// read by versions of Conserve before it was introduced.
+    Binary,
+}
+
 /// Description of one archived file.
 ///
 /// This struct is directly encoded/decoded to the json index file, and also can be constructed by
@@ -48,6 +84,8 @@ pub struct IndexEntry {
     pub kind: Kind,
 
     /// File modification time, in whole seconds past the Unix epoch.
+    ///
+    /// [EntryTrait::mtime] gives the time including [IndexEntry::mtime_nanos].
     #[serde(default)]
     pub mtime: i64,
 
@@ -59,6 +97,14 @@ pub struct IndexEntry {
     #[serde(default, flatten, skip_serializing_if = "Owner::is_none")]
     pub owner: Owner,
 
+    /// POSIX ACLs, if the file has any beyond its Unix permissions.
+    #[serde(default, flatten, skip_serializing_if = "Acls::is_none")]
+    pub acls: Acls,
+
+    /// Linux file capabilities, such as `cap_net_raw` on `ping`, if the file has any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capability: Option<Capability>,
+
     /// Fractional nanoseconds for modification time.
     ///
     /// This is zero in indexes written prior to 0.6.2, but treating it as
@@ -77,10 +123,30 @@ pub struct IndexEntry {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub addrs: Vec<blockdir::Address>,
 
+    /// For sparse files, the holes in the file, in order.
+    ///
+    /// The blocks in `addrs` hold the data between the holes.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub holes: Vec<Hole>,
+
     /// For symlinks only, the target of the symlink.
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub target: Option<String>,
+
+    /// For files with more than one hard link, the apath of the first file in the tree
+    /// linked to the same inode.
+    ///
+    /// This is set on every file in the group, including the first.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_group: Option<Apath>,
+
+    /// For device nodes only, the device number.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<DeviceNumber>,
 }
 // GRCOV_EXCLUDE_STOP
 
@@ -88,7 +154,7 @@ impl From<IndexEntry> for EntryValue {
     fn from(index_entry: IndexEntry) -> EntryValue {
         let kind_meta = match index_entry.kind {
             Kind::File => KindMeta::File {
-                size: index_entry.addrs.iter().map(|a| a.len).sum(),
+                size: index_entry.file_size(),
             },
             Kind::Symlink => KindMeta::Symlink {
                 // TODO: Should not be fatal
@@ -97,6 +163,19 @@ impl From<IndexEntry> for EntryValue {
                     .expect("symlink entry should have a target"),
             },
             Kind::Dir => KindMeta::Dir,
+            // Device entries without a number are rejected when the hunk is read.
+            Kind::CharDevice => KindMeta::CharDevice {
+                device: index_entry
+                    .device
+                    .expect("device entry has a device number"),
+            },
+            Kind::BlockDevice => KindMeta::BlockDevice {
+                device: index_entry
+                    .device
+                    .expect("device entry has a device number"),
+            },
+            Kind::Fifo => KindMeta::Fifo,
+            Kind::Socket => KindMeta::Socket,
             Kind::Unknown => KindMeta::Unknown,
         };
         EntryValue {
@@ -108,6 +187,10 @@ impl From<IndexEntry> for EntryValue {
             ),
             unix_mode: index_entry.unix_mode,
             owner: index_entry.owner,
+            acls: index_entry.acls,
+            capability: index_entry.capability,
+            link_group: index_entry.link_group,
+            inode: None,
         }
     }
 }
@@ -130,7 +213,7 @@ impl EntryTrait for IndexEntry {
 
     /// Size of the file, if it is a file. None for directories and symlinks.
     fn size(&self) -> Option<u64> {
-        Some(self.addrs.iter().map(|a| a.len).sum())
+        Some(self.file_size())
     }
 
     /// Target of the symlink, if this is a symlink.
@@ -146,6 +229,22 @@ impl EntryTrait for IndexEntry {
     fn owner(&self) -> &Owner {
         &self.owner
     }
+
+    fn acls(&self) -> &Acls {
+        &self.acls
+    }
+
+    fn capability(&self) -> Option<&Capability> {
+        self.capability.as_ref()
+    }
+
+    fn link_group(&self) -> Option<&Apath> {
+        self.link_group.as_ref()
+    }
+
+    fn device(&self) -> Option<DeviceNumber> {
+        self.device
+    }
 }
 
 impl IndexEntry {
@@ -162,13 +261,24 @@ impl IndexEntry {
             apath: source.apath().clone(),
             kind: source.kind(),
             addrs: Vec::new(),
+            holes: Vec::new(),
             target: source.symlink_target().map(|t| t.to_owned()),
             mtime: mtime.unix_timestamp(),
             mtime_nanos: mtime.nanosecond(),
             unix_mode: source.unix_mode(),
             owner: source.owner().to_owned(),
+            acls: source.acls().to_owned(),
+            capability: source.capability().cloned(),
+            link_group: source.link_group().cloned(),
+            device: source.device(),
         }
     }
+
+    /// Length of the file content, including any holes.
+    fn file_size(&self) -> u64 {
+        self.addrs.iter().map(|a| a.len).sum::<u64>()
+            + self.holes.iter().map(|h| h.len).sum::<u64>()
+    }
 }
 
 /// Write out index hunks.
@@ -194,6 +304,15 @@ pub struct IndexWriter {
     check_order: apath::DebugCheckOrder,
 
     compressor: Compressor,
+
+    /// How entries are encoded in new hunks.
+    format: IndexFormat,
+
+    /// The range of apaths in each hunk written so far.
+    hunk_ranges: Vec<HunkRange>,
+
+    /// Append a trailer with the entry count and a checksum to new hunks.
+    checksums: bool,
 }
 
 /// Accumulate and write out index entries into files in an index directory.
@@ -207,12 +326,34 @@ impl IndexWriter {
             hunks_written: 0,
             check_order: apath::DebugCheckOrder::new(),
             compressor: Compressor::new(),
+            format: IndexFormat::default(),
+            hunk_ranges: Vec::new(),
+            checksums: false,
         }
     }
 
-    /// Finish the last hunk of this index, and return the stats.
+    /// Write new hunks in this format.
+    #[must_use]
+    pub fn with_format(self, format: IndexFormat) -> IndexWriter {
+        IndexWriter { format, ..self }
+    }
+
+    /// Append a trailer with the number of entries and a checksum to each new hunk,
+    /// which older versions of Conserve can't read.
+    #[must_use]
+    pub fn with_checksums(self, checksums: bool) -> IndexWriter {
+        IndexWriter { checksums, ..self }
+    }
+
+    /// Finish the last hunk of this index, write the hunk table, and return the
+    /// number of hunks written.
+    ///
+    /// The hunk table only speeds up reading, so failing to write it is just a warning.
     pub fn finish(mut self, monitor: Arc<dyn Monitor>) -> Result<usize> {
         self.finish_hunk(monitor)?;
+        if let Err(err) = write_json(&self.transport, HUNK_TABLE_FILENAME, &self.hunk_ranges) {
+            warn!(?err, "Failed to write index hunk table");
+        }
         Ok(self.hunks_written)
     }
 
@@ -222,7 +363,7 @@ impl IndexWriter {
     /// sort after previously-written content.
     ///
     /// The new entry must sort after everything already written to the index.
-    pub(crate) fn push_entry(&mut self, entry: IndexEntry) {
+    pub fn push_entry(&mut self, entry: IndexEntry) {
         self.entries.push(entry);
     }
 
@@ -230,6 +371,11 @@ impl IndexWriter {
         self.entries.append(entries);
     }
 
+    /// Entries queued for the current hunk, in arbitrary order.
+    pub(crate) fn pending_entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
     /// Finish this hunk of the index.
     ///
     /// This writes all the currently queued entries into a new index file
@@ -247,17 +393,38 @@ impl IndexWriter {
         if self.entries.len() > 1 {
             self.check_order.check(&self.entries.last().unwrap().apath);
         }
+        let span = debug_span!(
+            "write_index_hunk",
+            hunk = self.sequence,
+            entries = self.entries.len(),
+            uncompressed_bytes = Empty,
+            compressed_bytes = Empty,
+        )
+        .entered();
         let relpath = hunk_relpath(self.sequence);
-        let json = serde_json::to_vec(&self.entries)?;
+        let mut encoded = match self.format {
+            IndexFormat::Json => serde_json::to_vec(&self.entries)?,
+            IndexFormat::Binary => binary::encode(&self.entries),
+        };
+        if self.checksums {
+            checksum::append(&mut encoded, self.entries.len());
+        }
         if (self.sequence % HUNKS_PER_SUBDIR) == 0 {
             self.transport.create_dir(&subdir_relpath(self.sequence))?;
         }
-        let compressed_bytes = self.compressor.compress(&json)?;
+        let compressed_bytes = self.compressor.compress(&encoded)?;
+        span.record("uncompressed_bytes", encoded.len());
+        span.record("compressed_bytes", compressed_bytes.len());
         self.transport.write_file(&relpath, &compressed_bytes)?;
         self.hunks_written += 1;
+        self.hunk_ranges.push(HunkRange {
+            hunk: self.sequence,
+            first: self.entries[0].apath.clone(),
+            last: self.entries.last().unwrap().apath.clone(),
+        });
         monitor.count(Counter::IndexWrites, 1);
         monitor.count(Counter::IndexWriteCompressedBytes, compressed_bytes.len());
-        monitor.count(Counter::IndexWriteUncompressedBytes, json.len());
+        monitor.count(Counter::IndexWriteUncompressedBytes, encoded.len());
         self.entries.clear(); // Ready for the next hunk.
         self.sequence += 1;
         Ok(())
@@ -283,6 +450,104 @@ pub struct IndexRead {
 }
 
 impl IndexRead {
+    /// Return the numbers of all hunks present in all directories, in order.
+    fn hunk_numbers(&self) -> Result<Vec<u32>> {
+        let subdirs = self
+            .transport
+            .list_dir("")?
+            .dirs
+            .into_iter()
+            .sorted()
+            .collect_vec();
+        debug!(?subdirs);
+        let hunks = subdirs
+            .into_iter()
+            .filter_map(|dir| self.transport.list_dir(&dir).ok())
+            .flat_map(|list| list.files)
+            .filter_map(|f| f.parse::<u32>().ok())
+            .sorted()
+            .collect_vec();
+        debug!(?hunks);
+        Ok(hunks)
+    }
+
+    /// Find the hunks that can't be read, or that hold entries out of order with
+    /// the hunks before them.
+    ///
+    /// Returns the numbers of the good hunks and of the bad hunks.
+    pub(crate) fn check_hunks(&self) -> Result<(Vec<u32>, Vec<u32>)> {
+        let mut good = Vec::new();
+        let mut bad = Vec::new();
+        let mut last_apath: Option<Apath> = None;
+        for (hunk_number, result) in self.read_each_hunk()? {
+            match result {
+                Ok(entries) => {
+                    let in_order = entries
+                        .iter()
+                        .map(|entry| &entry.apath)
+                        .tuple_windows()
+                        .all(|(a, b)| a < b)
+                        && entries
+                            .first()
+                            .zip(last_apath.as_ref())
+                            .map_or(true, |(first, last)| first.apath > *last);
+                    if in_order {
+                        if let Some(last) = entries.last() {
+                            last_apath = Some(last.apath.clone());
+                        }
+                        good.push(hunk_number);
+                    } else {
+                        warn!(hunk_number, "Index hunk is out of order");
+                        bad.push(hunk_number);
+                    }
+                }
+                Err(err) => {
+                    warn!(hunk_number, "Index hunk is unreadable: {err}");
+                    bad.push(hunk_number);
+                }
+            }
+        }
+        Ok((good, bad))
+    }
+
+    /// Read every hunk present in the index, returning the number of each hunk and
+    /// its entries or the error from reading it, rather than skipping errors.
+    pub(crate) fn read_each_hunk(
+        &self,
+    ) -> Result<impl Iterator<Item = (u32, Result<Vec<IndexEntry>>)>> {
+        let mut hunk_iter = self.iter_hunks();
+        Ok(self
+            .hunk_numbers()?
+            .into_iter()
+            // Hunks removed since they were listed are skipped.
+            .filter_map(move |hunk_number| {
+                hunk_iter
+                    .read_next_hunk(hunk_number)
+                    .transpose()
+                    .map(|result| (hunk_number, result))
+            }))
+    }
+
+    /// Delete one hunk from the index.
+    pub(crate) fn remove_hunk(&self, hunk_number: u32) -> Result<()> {
+        self.transport
+            .remove_file(&hunk_relpath(hunk_number))
+            .map_err(Error::from)
+    }
+
+    /// Copy every hunk of this index, as it's stored, into another index, returning
+    /// the number of hunks copied.
+    pub(crate) fn copy_hunks_to(&self, dest: &IndexRead) -> Result<usize> {
+        let hunk_numbers = self.hunk_numbers()?;
+        for &hunk_number in &hunk_numbers {
+            let relpath = hunk_relpath(hunk_number);
+            let bytes = self.transport.read_file(&relpath)?;
+            dest.transport.create_dir(&subdir_relpath(hunk_number))?;
+            dest.transport.write_file(&relpath, &bytes)?;
+        }
+        Ok(hunk_numbers.len())
+    }
+
     #[allow(unused)]
     pub(crate) fn open_path(path: &Path) -> IndexRead {
         IndexRead::open(Arc::new(LocalTransport::new(path)))
@@ -298,33 +563,53 @@ impl IndexRead {
         IndexEntryIter::new(self.iter_hunks(), Apath::root(), Exclude::nothing())
     }
 
+    /// Find the entry for one apath, reading only the hunks that might hold it.
+    ///
+    /// As when iterating, hunks that can't be read are logged and skipped.
+    pub(crate) fn lookup(&self, apath: &Apath) -> Result<Option<IndexEntry>> {
+        let mut hunk_iter = self.hunk_iter(self.hunk_numbers()?).seek_to(apath);
+        let Some(entries) = hunk_iter.next() else {
+            return Ok(None);
+        };
+        Ok(entries
+            .binary_search_by(|entry| entry.apath.cmp(apath))
+            .ok()
+            .map(|idx| entries[idx].clone()))
+    }
+
+    /// Return the apath of the last entry in this index, reading only the last
+    /// hunks.
+    ///
+    /// Hunks that can't be read are logged and skipped.
+    pub(crate) fn last_apath(&self) -> Result<Option<Apath>> {
+        for hunk_number in self.hunk_numbers()?.into_iter().rev() {
+            if let Some(last) = self
+                .hunk_iter(vec![hunk_number])
+                .next()
+                .and_then(|mut entries| entries.pop())
+            {
+                return Ok(Some(last.apath));
+            }
+        }
+        Ok(None)
+    }
+
     /// Make an iterator that returns hunks of entries from this index.
     pub fn iter_hunks(&self) -> IndexHunkIter {
         let _span = debug_span!("iter_hunks", ?self.transport).entered();
-        // All hunk numbers present in all directories.
-        let subdirs = self
-            .transport
-            .list_dir("")
-            .expect("list index dir") // TODO: Don't panic
-            .dirs
-            .into_iter()
-            .sorted()
-            .collect_vec();
-        debug!(?subdirs);
-        let hunks = subdirs
-            .into_iter()
-            .filter_map(|dir| self.transport.list_dir(&dir).ok())
-            .flat_map(|list| list.files)
-            .filter_map(|f| f.parse::<u32>().ok())
-            .sorted()
-            .collect_vec();
-        debug!(?hunks);
+        let hunks = self.hunk_numbers().expect("list index dir"); // TODO: Don't panic
+        self.hunk_iter(hunks)
+    }
+
+    fn hunk_iter(&self, hunks: Vec<u32>) -> IndexHunkIter {
         IndexHunkIter {
             hunks: hunks.into_iter(),
             transport: Arc::clone(&self.transport),
             decompressor: Decompressor::new(),
             stats: IndexReadStats::default(),
             after: None,
+            seek: None,
+            monitor: None,
         }
     }
 }
@@ -340,12 +625,20 @@ pub struct IndexHunkIter {
     pub stats: IndexReadStats,
     /// If set, yield only entries ordered after this apath.
     after: Option<Apath>,
+    /// If set, before reading any hunks, skip those that only hold entries ordered
+    /// before this apath.
+    seek: Option<Apath>,
+    /// If set, hunks that can't be read are reported here, rather than only logged.
+    monitor: Option<Arc<dyn Monitor>>,
 }
 
 impl Iterator for IndexHunkIter {
     type Item = Vec<IndexEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if let Some(seek) = self.seek.take() {
+            self.skip_hunks_before(&seek);
+        }
         loop {
             let hunk_number = self.hunks.next()?;
             let entries = match self.read_next_hunk(hunk_number) {
@@ -354,6 +647,9 @@ impl Iterator for IndexHunkIter {
                 Err(err) => {
                     self.stats.errors += 1;
                     error!("Error reading index hunk {hunk_number:?}: {err}");
+                    if let Some(monitor) = &self.monitor {
+                        monitor.error(err);
+                    }
                     continue;
                 }
             };
@@ -388,11 +684,113 @@ impl IndexHunkIter {
     pub fn advance_to_after(self, apath: &Apath) -> Self {
         IndexHunkIter {
             after: Some(apath.clone()),
+            ..self.seek_to(apath)
+        }
+    }
+
+    /// Report hunks that can't be read to this monitor, as well as logging them.
+    #[must_use]
+    pub(crate) fn with_monitor(self, monitor: Arc<dyn Monitor>) -> Self {
+        IndexHunkIter {
+            monitor: Some(monitor),
             ..self
         }
     }
 
+    /// Advance self so that reading starts from the hunk that would contain `apath`.
+    ///
+    /// Entries before `apath` in that hunk are still returned.
+    #[must_use]
+    pub fn seek_to(self, apath: &Apath) -> Self {
+        let seek = match self.seek {
+            Some(ref seek) if seek > apath => seek.clone(),
+            _ => apath.clone(),
+        };
+        IndexHunkIter {
+            seek: Some(seek),
+            ..self
+        }
+    }
+
+    /// Discard the hunks that only hold entries before `apath`.
+    ///
+    /// If the index has a hunk table describing exactly the hunks present, the
+    /// hunk is found from that without reading any hunks. Otherwise, this binary
+    /// searches for the first hunk whose last entry is not before `apath`, reading
+    /// hunks along the way. If a hunk can't be read or is empty, the search stops,
+    /// and any hunks that weren't skipped are read in order as usual.
+    fn skip_hunks_before(&mut self, apath: &Apath) {
+        let mut hunks = self.hunks.as_slice().to_vec();
+        let lo = match self.read_hunk_table() {
+            Some(table)
+                if table
+                    .iter()
+                    .map(|range| range.hunk)
+                    .eq(hunks.iter().copied()) =>
+            {
+                table.partition_point(|range| range.last < *apath)
+            }
+            _ => self.search_hunks(&hunks, apath),
+        };
+        trace!(%apath, skipped = lo, "Seek in index hunks");
+        self.hunks = hunks.split_off(lo).into_iter();
+    }
+
+    /// Binary search by reading hunks for the position of the first hunk whose
+    /// last entry is not before `apath`.
+    fn search_hunks(&mut self, hunks: &[u32], apath: &Apath) -> usize {
+        let (mut lo, mut hi) = (0, hunks.len());
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.read_next_hunk(hunks[mid]) {
+                Ok(Some(entries)) => match entries.last() {
+                    Some(last) if last.apath < *apath => lo = mid + 1,
+                    Some(_) => hi = mid,
+                    None => break,
+                },
+                // As when reading in order, a missing hunk is the end of the index.
+                Ok(None) => hi = mid,
+                Err(err) => {
+                    debug!(?err, "Failed to read index hunk while seeking");
+                    break;
+                }
+            }
+        }
+        lo
+    }
+
+    /// Read the hunk table, if the index has one, and it's readable and in order.
+    fn read_hunk_table(&self) -> Option<Vec<HunkRange>> {
+        match read_json::<Vec<HunkRange>, _>(&self.transport, HUNK_TABLE_FILENAME) {
+            Ok(Some(table))
+                if table
+                    .iter()
+                    .tuple_windows()
+                    .all(|(a, b)| a.hunk < b.hunk && a.last < b.first) =>
+            {
+                Some(table)
+            }
+            Ok(Some(_)) => {
+                warn!("Index hunk table is out of order");
+                None
+            }
+            Ok(None) => None,
+            Err(err) => {
+                warn!(?err, "Failed to read index hunk table");
+                None
+            }
+        }
+    }
+
     fn read_next_hunk(&mut self, hunk_number: u32) -> Result<Option<Vec<IndexEntry>>> {
+        let span = debug_span!(
+            "read_index_hunk",
+            hunk = hunk_number,
+            compressed_bytes = Empty,
+            uncompressed_bytes = Empty,
+            entries = Empty,
+        )
+        .entered();
         let path = hunk_relpath(hunk_number);
         let compressed_bytes = match self.transport.read_file(&path) {
             Ok(b) => b,
@@ -408,11 +806,44 @@ impl IndexHunkIter {
         self.stats.compressed_index_bytes += compressed_bytes.len() as u64;
         let index_bytes = self.decompressor.decompress(&compressed_bytes)?;
         self.stats.uncompressed_index_bytes += index_bytes.len() as u64;
-        let entries: Vec<IndexEntry> =
-            serde_json::from_slice(&index_bytes).map_err(|source| Error::DeserializeJson {
+        span.record("compressed_bytes", compressed_bytes.len());
+        span.record("uncompressed_bytes", index_bytes.len());
+        let (index_bytes, expected_count) =
+            checksum::strip(&index_bytes).map_err(|details| Error::IndexHunkDamaged {
                 path: path.clone(),
-                source,
+                details,
             })?;
+        let entries: Vec<IndexEntry> = if binary::is_binary(index_bytes) {
+            binary::decode(index_bytes).map_err(|details| Error::IndexHunkDamaged {
+                path: path.clone(),
+                details,
+            })?
+        } else {
+            serde_json::from_slice(index_bytes).map_err(|source| Error::DeserializeJson {
+                path: path.clone(),
+                source,
+            })?
+        };
+        if let Some(expected_count) = expected_count {
+            if entries.len() != expected_count {
+                return Err(Error::IndexHunkDamaged {
+                    path,
+                    details: format!(
+                        "expected {expected_count} entries but found {}",
+                        entries.len()
+                    ),
+                });
+            }
+        }
+        if let Some(entry) = entries.iter().find(|entry| {
+            matches!(entry.kind, Kind::CharDevice | Kind::BlockDevice) && entry.device.is_none()
+        }) {
+            return Err(Error::IndexHunkDamaged {
+                path,
+                details: format!("device {} has no device number", entry.apath),
+            });
+        }
+        span.record("entries", entries.len());
         if entries.is_empty() {
             // It's legal, it's just weird - and it can be produced by some old Conserve versions.
         }
@@ -448,13 +879,14 @@ impl<HI: Iterator<Item = Vec<IndexEntry>>> Iterator for IndexEntryIter<HI> {
     fn next(&mut self) -> Option<IndexEntry> {
         loop {
             if let Some(entry) = self.buffered_entries.next() {
-                // TODO: We could be smarter about skipping ahead if nothing
-                // in this page matches; or terminating early if we know
-                // nothing else in the index can be under this subtree.
+                if entry.apath.is_after_subtree(&self.subtree) {
+                    // Nothing else in the index can be in the subtree.
+                    return None;
+                }
                 if !self.subtree.is_prefix_of(&entry.apath) {
                     continue;
                 }
-                if self.exclude.matches(&entry.apath) {
+                if self.exclude.matches(&entry.apath) || !self.exclude.includes_kind(entry.kind()) {
                     continue;
                 }
                 return Some(entry);
@@ -538,6 +970,11 @@ mod tests {
             target: None,
             unix_mode: Default::default(),
             owner: Default::default(),
+            acls: Default::default(),
+            capability: None,
+            link_group: None,
+            holes: Vec::new(),
+            device: None,
         }
     }
 
@@ -552,6 +989,11 @@ mod tests {
             target: None,
             unix_mode: Default::default(),
             owner: Default::default(),
+            acls: Default::default(),
+            capability: None,
+            link_group: None,
+            holes: Vec::new(),
+            device: None,
         }];
         let index_json = serde_json::to_string(&entries).unwrap();
         println!("{index_json}");
@@ -637,6 +1079,142 @@ mod tests {
         assert!(it.next().is_none(), "Expected no more entries");
     }
 
+    #[test]
+    fn read_mixture_of_json_and_binary_hunks() {
+        let (testdir, ib) = setup();
+        let mut ib = ib.with_format(IndexFormat::Binary);
+        let monitor = TestMonitor::arc();
+        ib.push_entry(sample_entry("/apple"));
+        ib.finish_hunk(monitor.clone()).unwrap();
+        let binary_bytes = monitor.get_counter(Counter::IndexWriteUncompressedBytes);
+        assert!(binary_bytes < 50, "binary hunk is {binary_bytes} bytes");
+
+        let mut ib = ib.with_format(IndexFormat::Json);
+        ib.push_entry(sample_entry("/banana"));
+        ib.finish_hunk(monitor.clone()).unwrap();
+        let mut ib = ib.with_format(IndexFormat::Binary);
+        ib.push_entry(sample_entry("/cherry"));
+        assert_eq!(ib.finish(monitor.clone()).unwrap(), 3);
+
+        let index_read = IndexRead::open_path(testdir.path());
+        let apaths: Vec<String> = index_read
+            .iter_entries()
+            .map(|entry| entry.apath.to_string())
+            .collect();
+        assert_eq!(apaths, ["/apple", "/banana", "/cherry"]);
+    }
+
+    #[test]
+    fn damaged_binary_hunk_is_an_error() {
+        let testdir = TempDir::new().unwrap();
+        let mut bytes = binary::encode(&[sample_entry("/apple")]);
+        bytes.truncate(bytes.len() - 1);
+        std::fs::create_dir(testdir.path().join("00000")).unwrap();
+        std::fs::write(
+            testdir.path().join("00000").join("000000000"),
+            Compressor::new().compress(&bytes).unwrap(),
+        )
+        .unwrap();
+
+        let hunks: Vec<(u32, Result<Vec<IndexEntry>>)> = IndexRead::open_path(testdir.path())
+            .read_each_hunk()
+            .unwrap()
+            .collect();
+        assert_eq!(hunks.len(), 1);
+        assert!(matches!(
+            hunks[0].1,
+            Err(Error::IndexHunkDamaged { ref details, .. }) if details == "truncated"
+        ));
+    }
+
+    #[test]
+    fn hunks_with_checksums() {
+        let (testdir, ib) = setup();
+        let mut ib = ib.with_checksums(true);
+        ib.push_entry(sample_entry("/apple"));
+        ib.push_entry(sample_entry("/banana"));
+        ib.finish_hunk(TestMonitor::arc()).unwrap();
+        let mut ib = ib.with_format(IndexFormat::Binary);
+        ib.push_entry(sample_entry("/cherry"));
+        assert_eq!(ib.finish(TestMonitor::arc()).unwrap(), 2);
+
+        let apaths: Vec<String> = IndexRead::open_path(testdir.path())
+            .iter_entries()
+            .map(|entry| entry.apath.to_string())
+            .collect();
+        assert_eq!(apaths, ["/apple", "/banana", "/cherry"]);
+
+        // Damage the json hunk in a way that still parses.
+        let path = testdir.path().join("00000").join("000000000");
+        let mut bytes = Decompressor::new()
+            .decompress(&std::fs::read(&path).unwrap())
+            .unwrap()
+            .to_vec();
+        let pos = bytes.windows(7).position(|w| w == b"/banana").unwrap();
+        bytes[pos + 6] = b'b';
+        std::fs::write(&path, Compressor::new().compress(&bytes).unwrap()).unwrap();
+
+        let hunks: Vec<(u32, Result<Vec<IndexEntry>>)> = IndexRead::open_path(testdir.path())
+            .read_each_hunk()
+            .unwrap()
+            .collect();
+        assert!(matches!(
+            hunks[0].1,
+            Err(Error::IndexHunkDamaged { ref details, .. }) if details == "checksum mismatch"
+        ));
+        assert_eq!(hunks[1].1.as_ref().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn hunk_with_wrong_entry_count_is_an_error() {
+        let testdir = TempDir::new().unwrap();
+        let mut bytes = serde_json::to_vec(&[sample_entry("/apple")]).unwrap();
+        checksum::append(&mut bytes, 2);
+        std::fs::create_dir(testdir.path().join("00000")).unwrap();
+        std::fs::write(
+            testdir.path().join("00000").join("000000000"),
+            Compressor::new().compress(&bytes).unwrap(),
+        )
+        .unwrap();
+
+        let hunks: Vec<(u32, Result<Vec<IndexEntry>>)> = IndexRead::open_path(testdir.path())
+            .read_each_hunk()
+            .unwrap()
+            .collect();
+        assert!(matches!(
+            hunks[0].1,
+            Err(Error::IndexHunkDamaged { ref details, .. })
+                if details == "expected 2 entries but found 1"
+        ));
+    }
+
+    #[test]
+    fn device_without_device_number_is_an_error() {
+        let testdir = TempDir::new().unwrap();
+        let entry = IndexEntry {
+            kind: Kind::CharDevice,
+            ..sample_entry("/null")
+        };
+        std::fs::create_dir(testdir.path().join("00000")).unwrap();
+        std::fs::write(
+            testdir.path().join("00000").join("000000000"),
+            Compressor::new()
+                .compress(&binary::encode(&[entry]))
+                .unwrap(),
+        )
+        .unwrap();
+
+        let hunks: Vec<(u32, Result<Vec<IndexEntry>>)> = IndexRead::open_path(testdir.path())
+            .read_each_hunk()
+            .unwrap()
+            .collect();
+        assert!(matches!(
+            hunks[0].1,
+            Err(Error::IndexHunkDamaged { ref details, .. })
+                if details == "device /null has no device number"
+        ));
+    }
+
     #[test]
     fn multiple_hunks() {
         let (testdir, mut ib) = setup();
@@ -752,6 +1330,131 @@ mod tests {
         assert_eq!(names, [] as [&str; 0]);
     }
 
+    #[test]
+    fn lookup_one_entry() {
+        let (testdir, mut ib) = setup();
+        for i in 0..10 {
+            ib.append_entries(&mut vec![
+                sample_entry(&format!("/{i}.1")),
+                sample_entry(&format!("/{i}.3")),
+            ]);
+            ib.finish_hunk(TestMonitor::arc()).unwrap();
+        }
+        let index_read = IndexRead::open_path(testdir.path());
+        for apath in ["/0.1", "/4.3", "/9.3"] {
+            assert_eq!(
+                index_read.lookup(&apath.into()).unwrap().unwrap().apath,
+                apath
+            );
+        }
+        for apath in ["/0.0", "/4.2", "/9.4", "/a"] {
+            assert_eq!(index_read.lookup(&apath.into()).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn iter_hunks_seek_to_skips_earlier_hunks() {
+        let (testdir, mut ib) = setup();
+        for i in 0..10 {
+            ib.append_entries(&mut vec![
+                sample_entry(&format!("/{i}.1")),
+                sample_entry(&format!("/{i}.2")),
+            ]);
+            ib.finish_hunk(TestMonitor::arc()).unwrap();
+        }
+        let index_read = IndexRead::open_path(testdir.path());
+
+        let mut hunks = index_read.iter_hunks().seek_to(&"/7.2".into());
+        let names: Vec<String> = hunks
+            .by_ref()
+            .flatten()
+            .map(|entry| entry.apath.into())
+            .collect();
+        assert_eq!(names, ["/7.1", "/7.2", "/8.1", "/8.2", "/9.1", "/9.2"]);
+        // The binary search reads a few hunks, but not all of those before the target.
+        assert!(hunks.stats.index_hunks < 10, "{:?}", hunks.stats);
+
+        let names: Vec<String> = index_read
+            .iter_hunks()
+            .seek_to(&"/99".into())
+            .flatten()
+            .map(|entry| entry.apath.into())
+            .collect();
+        assert_eq!(names, [""; 0]);
+
+        let names: Vec<String> = index_read
+            .iter_hunks()
+            .seek_to(&"/".into())
+            .flatten()
+            .map(|entry| entry.apath.into())
+            .collect();
+        assert_eq!(names.len(), 20);
+    }
+
+    #[test]
+    fn hunk_table_finds_hunk_without_reading_earlier_hunks() {
+        let (testdir, mut ib) = setup();
+        for i in 0..10 {
+            ib.append_entries(&mut vec![
+                sample_entry(&format!("/{i}.1")),
+                sample_entry(&format!("/{i}.2")),
+            ]);
+            ib.finish_hunk(TestMonitor::arc()).unwrap();
+        }
+        ib.finish(TestMonitor::arc()).unwrap();
+        assert!(testdir.path().join(HUNK_TABLE_FILENAME).is_file());
+        let index_read = IndexRead::open_path(testdir.path());
+
+        let mut hunks = index_read.iter_hunks().seek_to(&"/7.2".into());
+        let names: Vec<String> = hunks
+            .by_ref()
+            .flatten()
+            .map(|entry| entry.apath.into())
+            .collect();
+        assert_eq!(names, ["/7.1", "/7.2", "/8.1", "/8.2", "/9.1", "/9.2"]);
+        assert_eq!(hunks.stats.index_hunks, 3);
+
+        assert_eq!(
+            index_read.lookup(&"/3.1".into()).unwrap().unwrap().apath,
+            "/3.1"
+        );
+        assert!(index_read.lookup(&"/3.3".into()).unwrap().is_none());
+
+        // Once a hunk is removed the table no longer matches, and seeking falls back
+        // to searching the hunks.
+        index_read.remove_hunk(8).unwrap();
+        let names: Vec<String> = index_read
+            .iter_hunks()
+            .seek_to(&"/7.2".into())
+            .flatten()
+            .map(|entry| entry.apath.into())
+            .collect();
+        assert_eq!(names, ["/7.1", "/7.2", "/9.1", "/9.2"]);
+
+        // So does a damaged table.
+        std::fs::write(testdir.path().join(HUNK_TABLE_FILENAME), b"[{").unwrap();
+        let names: Vec<String> = index_read
+            .iter_hunks()
+            .seek_to(&"/9".into())
+            .flatten()
+            .map(|entry| entry.apath.into())
+            .collect();
+        assert_eq!(names, ["/9.1", "/9.2"]);
+    }
+
+    #[test]
+    fn iter_entries_stops_after_subtree() {
+        let (testdir, mut ib) = setup();
+        for apath in ["/", "/a", "/b", "/c", "/b/1", "/b/2", "/c/1"] {
+            ib.push_entry(sample_entry(apath));
+        }
+        ib.finish_hunk(TestMonitor::arc()).unwrap();
+        let index_read = IndexRead::open_path(testdir.path());
+        let entries = IndexEntryIter::new(index_read.iter_hunks(), "/b".into(), Exclude::nothing());
+        let names: Vec<String> = entries.map(|entry| entry.apath.into()).collect();
+        assert_eq!(names, ["/b", "/b/1", "/b/2"]);
+    }
+
     #[test]
     fn advance() {
         let (testdir, mut ib) = setup();
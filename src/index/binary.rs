@@ -0,0 +1,643 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Binary encoding of index hunks, which is smaller than json and much faster to
+//! write and parse.
+//!
+//! A binary hunk starts with [MAGIC] and a version byte. Json hunks always start
+//! with `[`, so the format of each hunk is recognized when it's read, whatever the
+//! band head says.
+//!
+//! After the header come a table of the distinct owner names in the hunk, a table
+//! of the distinct block hashes, and then the entries. Each apath is stored as the
+//! length of the prefix it shares with the previous apath, followed by the rest of
+//! it. Each entry has a bitmap of which optional fields are present. Integers are
+//! LEB128 varints, with signed mtimes zigzag-encoded.
+
+use std::collections::HashMap;
+use std::str;
+
+use crate::acl::Acl;
+use crate::sparse::Hole;
+use crate::*;
+
+/// First bytes of a binary index hunk.
+pub(super) const MAGIC: &[u8] = b"\xc1CIX";
+
+/// Version of the encoding, following the magic.
+///
+/// Hunks with a different version are reported as unreadable, rather than guessed at.
+const VERSION: u8 = 1;
+
+const HAS_MTIME_NANOS: u64 = 1 << 0;
+const HAS_UNIX_MODE: u64 = 1 << 1;
+const HAS_USER: u64 = 1 << 2;
+const HAS_GROUP: u64 = 1 << 3;
+const HAS_UID: u64 = 1 << 4;
+const HAS_GID: u64 = 1 << 5;
+const HAS_ACL: u64 = 1 << 6;
+const HAS_DEFAULT_ACL: u64 = 1 << 7;
+const HAS_CAPABILITY: u64 = 1 << 8;
+const HAS_ADDRS: u64 = 1 << 9;
+const HAS_HOLES: u64 = 1 << 10;
+const HAS_TARGET: u64 = 1 << 11;
+const HAS_LINK_GROUP: u64 = 1 << 12;
+const HAS_DEVICE: u64 = 1 << 13;
+
+/// All the field bits understood by this version.
+const KNOWN_FIELDS: u64 = (HAS_DEVICE << 1) - 1;
+
+/// True if these (decompressed) bytes hold a binary index hunk.
+pub(super) fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Encode a hunk of entries, which should already be sorted.
+pub(super) fn encode(entries: &[IndexEntry]) -> Vec<u8> {
+    let mut names = Table::default();
+    let mut hashes = Table::default();
+    for entry in entries {
+        if let Some(user) = &entry.owner.user {
+            names.insert(user.as_str());
+        }
+        if let Some(group) = &entry.owner.group {
+            names.insert(group.as_str());
+        }
+        for addr in &entry.addrs {
+            hashes.insert(&addr.hash);
+        }
+    }
+
+    // A rough guess at the size of a typical entry.
+    let mut out = Vec::with_capacity(entries.len() * 48);
+    out.extend_from_slice(MAGIC);
+    out.push(VERSION);
+    put_varint(&mut out, names.values.len() as u64);
+    for name in &names.values {
+        put_bytes(&mut out, name.as_bytes());
+    }
+    put_varint(&mut out, hashes.values.len() as u64);
+    for hash in &hashes.values {
+        out.extend_from_slice(hash.as_bytes());
+    }
+    put_varint(&mut out, entries.len() as u64);
+    let mut previous_apath: &str = "";
+    for entry in entries {
+        let apath: &str = entry.apath.as_ref();
+        let shared = previous_apath
+            .bytes()
+            .zip(apath.bytes())
+            .take_while(|(a, b)| a == b)
+            .count();
+        put_varint(&mut out, shared as u64);
+        put_bytes(&mut out, &apath.as_bytes()[shared..]);
+        previous_apath = apath;
+
+        out.push(kind_tag(entry.kind));
+        let owner = &entry.owner;
+        let fields = [
+            (HAS_MTIME_NANOS, entry.mtime_nanos != 0),
+            (HAS_UNIX_MODE, entry.unix_mode.bits().is_some()),
+            (HAS_USER, owner.user.is_some()),
+            (HAS_GROUP, owner.group.is_some()),
+            (HAS_UID, owner.uid.is_some()),
+            (HAS_GID, owner.gid.is_some()),
+            (HAS_ACL, entry.acls.access.is_some()),
+            (HAS_DEFAULT_ACL, entry.acls.default.is_some()),
+            (HAS_CAPABILITY, entry.capability.is_some()),
+            (HAS_ADDRS, !entry.addrs.is_empty()),
+            (HAS_HOLES, !entry.holes.is_empty()),
+            (HAS_TARGET, entry.target.is_some()),
+            (HAS_LINK_GROUP, entry.link_group.is_some()),
+            (HAS_DEVICE, entry.device.is_some()),
+        ]
+        .into_iter()
+        .filter(|(_, present)| *present)
+        .fold(0, |fields, (bit, _)| fields | bit);
+        put_varint(&mut out, fields);
+        put_varint(&mut out, zigzag(entry.mtime));
+        if entry.mtime_nanos != 0 {
+            put_varint(&mut out, entry.mtime_nanos.into());
+        }
+        if let Some(bits) = entry.unix_mode.bits() {
+            put_varint(&mut out, bits.into());
+        }
+        if let Some(user) = &owner.user {
+            put_varint(&mut out, names.index(user.as_str()));
+        }
+        if let Some(group) = &owner.group {
+            put_varint(&mut out, names.index(group.as_str()));
+        }
+        if let Some(uid) = owner.uid {
+            put_varint(&mut out, uid.into());
+        }
+        if let Some(gid) = owner.gid {
+            put_varint(&mut out, gid.into());
+        }
+        if let Some(acl) = &entry.acls.access {
+            put_bytes(&mut out, &acl.to_xattr());
+        }
+        if let Some(acl) = &entry.acls.default {
+            put_bytes(&mut out, &acl.to_xattr());
+        }
+        if let Some(capability) = &entry.capability {
+            put_bytes(&mut out, capability.as_xattr());
+        }
+        if !entry.addrs.is_empty() {
+            put_varint(&mut out, entry.addrs.len() as u64);
+            for addr in &entry.addrs {
+                put_varint(&mut out, hashes.index(&addr.hash));
+                put_varint(&mut out, addr.start);
+                put_varint(&mut out, addr.len);
+            }
+        }
+        if !entry.holes.is_empty() {
+            put_varint(&mut out, entry.holes.len() as u64);
+            for hole in &entry.holes {
+                put_varint(&mut out, hole.start);
+                put_varint(&mut out, hole.len);
+            }
+        }
+        if let Some(target) = &entry.target {
+            put_bytes(&mut out, target.as_bytes());
+        }
+        if let Some(link_group) = &entry.link_group {
+            put_bytes(&mut out, link_group.as_bytes());
+        }
+        if let Some(device) = &entry.device {
+            put_varint(&mut out, device.major);
+            put_varint(&mut out, device.minor);
+        }
+    }
+    out
+}
+
+/// Decode a binary hunk, returning a description of the problem if it's damaged.
+pub(super) fn decode(bytes: &[u8]) -> std::result::Result<Vec<IndexEntry>, String> {
+    let mut r = Reader { bytes, pos: 0 };
+    if r.take(MAGIC.len())? != MAGIC {
+        return Err("missing magic".to_owned());
+    }
+    let version = r.byte()?;
+    if version != VERSION {
+        return Err(format!("unsupported binary index version {version}"));
+    }
+    let name_count = r.len()?;
+    let names = (0..name_count)
+        .map(|_| r.str())
+        .collect::<std::result::Result<Vec<&str>, String>>()?;
+    let hash_count = r.len()?;
+    let hashes = (0..hash_count)
+        .map(|_| {
+            let bin = r.take(BLAKE_HASH_SIZE_BYTES)?;
+            Ok(BlockHash::from_bytes(bin).expect("hash has the right length"))
+        })
+        .collect::<std::result::Result<Vec<BlockHash>, String>>()?;
+    let entry_count = r.len()?;
+    let mut entries = Vec::with_capacity(entry_count);
+    let mut previous_apath = String::new();
+    for _ in 0..entry_count {
+        let shared = r.len()?;
+        if shared > previous_apath.len() {
+            return Err("shared apath prefix is too long".to_owned());
+        }
+        let suffix = r.bytes()?;
+        let mut apath_bytes = Vec::with_capacity(shared + suffix.len());
+        apath_bytes.extend_from_slice(&previous_apath.as_bytes()[..shared]);
+        apath_bytes.extend_from_slice(suffix);
+        let apath_string = String::from_utf8(apath_bytes).map_err(|_| "apath is not UTF-8")?;
+        let apath: Apath = apath_string
+            .parse()
+            .map_err(|_| format!("invalid apath {apath_string:?}"))?;
+        previous_apath = apath_string;
+
+        let kind = kind_from_tag(r.byte()?)?;
+        let fields = r.varint()?;
+        if fields & !KNOWN_FIELDS != 0 {
+            return Err(format!("unknown entry fields {fields:#x}"));
+        }
+        let has = |bit: u64| fields & bit != 0;
+        let mtime = unzigzag(r.varint()?);
+        let mtime_nanos = if has(HAS_MTIME_NANOS) { r.u32()? } else { 0 };
+        let unix_mode = if has(HAS_UNIX_MODE) {
+            UnixMode::from(r.u32()?)
+        } else {
+            UnixMode::default()
+        };
+        let mut owner = Owner::default();
+        if has(HAS_USER) {
+            owner.user = Some(r.name(&names)?);
+        }
+        if has(HAS_GROUP) {
+            owner.group = Some(r.name(&names)?);
+        }
+        if has(HAS_UID) {
+            owner.uid = Some(r.u32()?);
+        }
+        if has(HAS_GID) {
+            owner.gid = Some(r.u32()?);
+        }
+        let mut acls = Acls::default();
+        if has(HAS_ACL) {
+            acls.access = Some(r.acl()?);
+        }
+        if has(HAS_DEFAULT_ACL) {
+            acls.default = Some(r.acl()?);
+        }
+        let capability = has(HAS_CAPABILITY)
+            .then(|| Capability::from_xattr(r.bytes()?).map_err(|err| err.to_string()))
+            .transpose()?;
+        let mut addrs = Vec::new();
+        if has(HAS_ADDRS) {
+            let count = r.len()?;
+            addrs.reserve(count);
+            for _ in 0..count {
+                let hash = hashes
+                    .get(r.len()?)
+                    .ok_or("block hash index is out of range")?
+                    .clone();
+                addrs.push(blockdir::Address {
+                    hash,
+                    start: r.varint()?,
+                    len: r.varint()?,
+                });
+            }
+        }
+        let mut holes = Vec::new();
+        if has(HAS_HOLES) {
+            let count = r.len()?;
+            holes.reserve(count);
+            for _ in 0..count {
+                holes.push(Hole {
+                    start: r.varint()?,
+                    len: r.varint()?,
+                });
+            }
+        }
+        let target = has(HAS_TARGET)
+            .then(|| r.str().map(str::to_owned))
+            .transpose()?;
+        let link_group = has(HAS_LINK_GROUP)
+            .then(|| {
+                let s = r.str()?;
+                s.parse::<Apath>()
+                    .map_err(|_| format!("invalid link group apath {s:?}"))
+            })
+            .transpose()?;
+        let device = has(HAS_DEVICE)
+            .then(|| {
+                Ok::<_, String>(DeviceNumber {
+                    major: r.varint()?,
+                    minor: r.varint()?,
+                })
+            })
+            .transpose()?;
+        entries.push(IndexEntry {
+            apath,
+            kind,
+            mtime,
+            unix_mode,
+            owner,
+            acls,
+            capability,
+            mtime_nanos,
+            addrs,
+            holes,
+            target,
+            link_group,
+            device,
+        });
+    }
+    if r.pos != bytes.len() {
+        return Err("unexpected data after the last entry".to_owned());
+    }
+    Ok(entries)
+}
+
+/// Distinct values used in a hunk, which entries refer to by their position.
+struct Table<'a, T: ?Sized + Eq + std::hash::Hash> {
+    values: Vec<&'a T>,
+    positions: HashMap<&'a T, u64>,
+}
+
+impl<T: ?Sized + Eq + std::hash::Hash> Default for Table<'_, T> {
+    fn default() -> Self {
+        Table {
+            values: Vec::new(),
+            positions: HashMap::new(),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + Eq + std::hash::Hash> Table<'a, T> {
+    fn insert(&mut self, value: &'a T) {
+        let next = self.values.len() as u64;
+        if let std::collections::hash_map::Entry::Vacant(vacant) = self.positions.entry(value) {
+            vacant.insert(next);
+            self.values.push(value);
+        }
+    }
+
+    fn index(&self, value: &T) -> u64 {
+        self.positions[value]
+    }
+}
+
+fn kind_tag(kind: Kind) -> u8 {
+    match kind {
+        Kind::File => 0,
+        Kind::Dir => 1,
+        Kind::Symlink => 2,
+        Kind::CharDevice => 3,
+        Kind::BlockDevice => 4,
+        Kind::Fifo => 5,
+        Kind::Socket => 6,
+        Kind::Unknown => 7,
+    }
+}
+
+fn kind_from_tag(tag: u8) -> std::result::Result<Kind, String> {
+    Ok(match tag {
+        0 => Kind::File,
+        1 => Kind::Dir,
+        2 => Kind::Symlink,
+        3 => Kind::CharDevice,
+        4 => Kind::BlockDevice,
+        5 => Kind::Fifo,
+        6 => Kind::Socket,
+        7 => Kind::Unknown,
+        _ => return Err(format!("unknown kind {tag}")),
+    })
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+fn unzigzag(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Write a length-prefixed byte string.
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> std::result::Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or("truncated")?;
+        let taken = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> std::result::Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> std::result::Result<u64, String> {
+        let mut value: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            if shift == 63 && byte > 1 {
+                break;
+            }
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("varint is too long".to_owned())
+    }
+
+    fn u32(&mut self) -> std::result::Result<u32, String> {
+        u32::try_from(self.varint()?).map_err(|_| "value is out of range".to_owned())
+    }
+
+    /// Read a length or index, which must fit in the remaining data to be plausible.
+    fn len(&mut self) -> std::result::Result<usize, String> {
+        usize::try_from(self.varint()?)
+            .ok()
+            .filter(|len| *len <= self.bytes.len())
+            .ok_or_else(|| "length is out of range".to_owned())
+    }
+
+    fn bytes(&mut self) -> std::result::Result<&'a [u8], String> {
+        let len = self.len()?;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> std::result::Result<&'a str, String> {
+        str::from_utf8(self.bytes()?).map_err(|_| "string is not UTF-8".to_owned())
+    }
+
+    /// Read a reference to an owner name in the table.
+    fn name(&mut self, names: &[&str]) -> std::result::Result<String, String> {
+        names
+            .get(self.len()?)
+            .map(|name| (*name).to_owned())
+            .ok_or_else(|| "owner name index is out of range".to_owned())
+    }
+
+    fn acl(&mut self) -> std::result::Result<Acl, String> {
+        Acl::from_xattr(self.bytes()?).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(apath: &str) -> IndexEntry {
+        IndexEntry {
+            apath: apath.into(),
+            kind: Kind::Dir,
+            mtime: 1_461_736_377,
+            mtime_nanos: 0,
+            unix_mode: Default::default(),
+            owner: Default::default(),
+            acls: Default::default(),
+            capability: None,
+            addrs: Vec::new(),
+            holes: Vec::new(),
+            target: None,
+            link_group: None,
+            device: None,
+        }
+    }
+
+    fn sample_entries() -> Vec<IndexEntry> {
+        let hash = BlockHash::hash_bytes(b"hello");
+        vec![
+            IndexEntry {
+                mtime: -5,
+                unix_mode: UnixMode::from(0o755),
+                owner: Owner {
+                    user: Some("alice".to_owned()),
+                    group: Some("staff".to_owned()),
+                    uid: Some(1000),
+                    gid: Some(20),
+                },
+                ..entry("/")
+            },
+            IndexEntry {
+                kind: Kind::File,
+                mtime_nanos: 123_456_789,
+                owner: Owner {
+                    user: Some("alice".to_owned()),
+                    ..Default::default()
+                },
+                acls: Acls {
+                    access: Some(
+                        "user::rw-,user:1000:r--,group::r--,mask::r--,other::---"
+                            .parse()
+                            .unwrap(),
+                    ),
+                    default: Some("user::rwx,group::r-x,other::---".parse().unwrap()),
+                },
+                capability: Some(
+                    Capability::from_xattr(
+                        &hex::decode("0000000200200000000000000000000000000000").unwrap(),
+                    )
+                    .unwrap(),
+                ),
+                addrs: vec![
+                    blockdir::Address {
+                        hash: hash.clone(),
+                        start: 0,
+                        len: 10,
+                    },
+                    blockdir::Address {
+                        hash: hash.clone(),
+                        start: 10,
+                        len: 1 << 40,
+                    },
+                ],
+                holes: vec![Hole {
+                    start: 10,
+                    len: 4096,
+                }],
+                link_group: Some("/caf\u{e9}".into()),
+                ..entry("/caf\u{e8}")
+            },
+            IndexEntry {
+                kind: Kind::File,
+                addrs: vec![blockdir::Address {
+                    hash,
+                    start: 3,
+                    len: 4,
+                }],
+                link_group: Some("/caf\u{e9}".into()),
+                ..entry("/caf\u{e9}")
+            },
+            IndexEntry {
+                kind: Kind::BlockDevice,
+                device: Some(DeviceNumber { major: 8, minor: 1 }),
+                ..entry("/dev")
+            },
+            IndexEntry {
+                kind: Kind::Symlink,
+                target: Some("../elsewhere".to_owned()),
+                mtime: i64::MAX,
+                ..entry("/link")
+            },
+            IndexEntry {
+                kind: Kind::Fifo,
+                mtime: i64::MIN,
+                ..entry("/sub/fifo")
+            },
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let entries = sample_entries();
+        let encoded = encode(&entries);
+        assert!(is_binary(&encoded));
+        assert_eq!(decode(&encoded).unwrap(), entries);
+    }
+
+    #[test]
+    fn empty_hunk() {
+        assert_eq!(decode(&encode(&[])).unwrap(), []);
+    }
+
+    #[test]
+    fn smaller_than_json() {
+        let entries = sample_entries();
+        assert!(encode(&entries).len() < serde_json::to_vec(&entries).unwrap().len() / 2);
+    }
+
+    #[test]
+    fn json_is_not_binary() {
+        assert!(!is_binary(&serde_json::to_vec(&sample_entries()).unwrap()));
+        assert!(!is_binary(b"[]"));
+    }
+
+    #[test]
+    fn damaged_hunks_are_errors() {
+        let encoded = encode(&sample_entries());
+        for len in 0..encoded.len() {
+            assert!(decode(&encoded[..len]).is_err(), "truncated to {len}");
+        }
+        let mut extra = encoded.clone();
+        extra.push(0);
+        assert!(decode(&extra).is_err());
+
+        let mut future = encoded.clone();
+        future[MAGIC.len()] = VERSION + 1;
+        assert_eq!(
+            decode(&future).unwrap_err(),
+            format!("unsupported binary index version {}", VERSION + 1)
+        );
+    }
+
+    #[test]
+    fn varints() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut out = Vec::new();
+            put_varint(&mut out, value);
+            let mut r = Reader {
+                bytes: &out,
+                pos: 0,
+            };
+            assert_eq!(r.varint().unwrap(), value);
+            assert_eq!(r.pos, out.len());
+        }
+        for value in [0, 1, -1, i64::MAX, i64::MIN] {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+        let mut r = Reader {
+            bytes: &[0xff; 11],
+            pos: 0,
+        };
+        assert!(r.varint().is_err());
+    }
+}
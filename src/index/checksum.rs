@@ -0,0 +1,95 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A trailer appended to index hunks, holding the number of entries and a checksum,
+//! so that a damaged hunk is reported as such before it's decoded.
+//!
+//! The trailer follows the encoded entries, in either encoding, and is compressed
+//! along with them. It holds the entry count as a little-endian u32, a 16-byte
+//! BLAKE2b hash of everything before it including the count, and then [MAGIC].
+//! Hunks without a trailer are still read.
+
+use blake2_rfc::blake2b::blake2b;
+
+/// Last bytes of a hunk with a trailer.
+///
+/// Json hunks always end with `]`, and binary hunks without a trailer are
+/// vanishingly unlikely to end with these bytes.
+const MAGIC: &[u8] = b"\xc1CIXSUM";
+
+const COUNT_LEN: usize = 4;
+const CHECKSUM_LEN: usize = 16;
+const TRAILER_LEN: usize = COUNT_LEN + CHECKSUM_LEN + MAGIC.len();
+
+/// Append a trailer to an encoded hunk of this many entries.
+pub(super) fn append(encoded: &mut Vec<u8>, entry_count: usize) {
+    let entry_count = u32::try_from(entry_count).expect("Hunk entry count fits in u32");
+    encoded.extend_from_slice(&entry_count.to_le_bytes());
+    let checksum = blake2b(CHECKSUM_LEN, &[], encoded);
+    encoded.extend_from_slice(checksum.as_bytes());
+    encoded.extend_from_slice(MAGIC);
+}
+
+/// Check and remove the trailer from a hunk, if it has one.
+///
+/// Returns the encoded entries, and the number of entries they should hold if there
+/// was a trailer, or a description of the problem if the checksum doesn't match.
+pub(super) fn strip(bytes: &[u8]) -> std::result::Result<(&[u8], Option<usize>), String> {
+    if !bytes.ends_with(MAGIC) {
+        return Ok((bytes, None));
+    }
+    if bytes.len() < TRAILER_LEN {
+        return Err("truncated trailer".to_owned());
+    }
+    let (counted, checksum) =
+        bytes[..bytes.len() - MAGIC.len()].split_at(bytes.len() - MAGIC.len() - CHECKSUM_LEN);
+    if blake2b(CHECKSUM_LEN, &[], counted).as_bytes() != checksum {
+        return Err("checksum mismatch".to_owned());
+    }
+    let (encoded, entry_count) = counted.split_at(counted.len() - COUNT_LEN);
+    let entry_count = u32::from_le_bytes(entry_count.try_into().expect("Count is 4 bytes"));
+    Ok((encoded, Some(entry_count as usize)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut bytes = b"[]".to_vec();
+        append(&mut bytes, 0);
+        assert_eq!(bytes.len(), 2 + TRAILER_LEN);
+        assert_eq!(strip(&bytes).unwrap(), (b"[]".as_slice(), Some(0)));
+    }
+
+    #[test]
+    fn hunk_without_trailer() {
+        assert_eq!(strip(b"[]").unwrap(), (b"[]".as_slice(), None));
+    }
+
+    #[test]
+    fn damage_is_detected() {
+        let mut bytes = b"[{\"apath\":\"/\"}]".to_vec();
+        append(&mut bytes, 1);
+        for i in 0..(bytes.len() - MAGIC.len()) {
+            let mut damaged = bytes.clone();
+            damaged[i] ^= 0x01;
+            assert_eq!(
+                strip(&damaged).unwrap_err(),
+                "checksum mismatch",
+                "flipped bit in byte {i}"
+            );
+        }
+        assert_eq!(strip(MAGIC).unwrap_err(), "truncated trailer");
+    }
+}
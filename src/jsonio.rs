@@ -49,15 +49,33 @@ where
     T: serde::Serialize,
     TR: AsRef<dyn Transport>,
 {
+    transport
+        .as_ref()
+        .write_file(relpath, to_json_line(relpath, obj)?.as_bytes())
+        .map_err(Error::from)
+}
+
+/// Write uncompressed json to a new file on a Transport, failing if it already exists.
+///
+/// See [Transport::write_new_file].
+pub(crate) fn write_new_json<T, TR>(transport: &TR, relpath: &str, obj: &T) -> Result<()>
+where
+    T: serde::Serialize,
+    TR: AsRef<dyn Transport>,
+{
+    transport
+        .as_ref()
+        .write_new_file(relpath, to_json_line(relpath, obj)?.as_bytes())
+        .map_err(Error::from)
+}
+
+fn to_json_line<T: serde::Serialize>(relpath: &str, obj: &T) -> Result<String> {
     let mut s: String = serde_json::to_string(&obj).map_err(|source| Error::Json {
         source,
         path: relpath.into(),
     })?;
     s.push('\n');
-    transport
-        .as_ref()
-        .write_file(relpath, s.as_bytes())
-        .map_err(Error::from)
+    Ok(s)
 }
 
 /// Read and deserialize uncompressed json from a file on a Transport.
@@ -20,17 +20,46 @@ use std::fs::FileType;
 use serde::{Deserialize, Serialize};
 
 /// Kind of file that can be stored in the archive.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Ord, PartialOrd)]
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Ord, PartialOrd, clap::ValueEnum,
+)]
 pub enum Kind {
     File,
     Dir,
     Symlink,
+    /// Unix character device node.
+    CharDevice,
+    /// Unix block device node.
+    BlockDevice,
+    /// Named pipe.
+    Fifo,
+    /// Unix domain socket.
+    Socket,
     /// Unknown file observed in local tree. Shouldn't be stored.
+    #[value(skip)]
     Unknown,
 }
 
+impl Kind {
+    /// True for device nodes, FIFOs, and sockets, which have no content.
+    pub fn is_special(self) -> bool {
+        matches!(
+            self,
+            Kind::CharDevice | Kind::BlockDevice | Kind::Fifo | Kind::Socket
+        )
+    }
+
+    /// True for character and block devices, which have device numbers.
+    pub fn is_device(self) -> bool {
+        matches!(self, Kind::CharDevice | Kind::BlockDevice)
+    }
+}
+
 impl From<FileType> for Kind {
     fn from(ft: FileType) -> Kind {
+        #[cfg(unix)]
+        use std::os::unix::fs::FileTypeExt;
+
         if ft.is_file() {
             Kind::File
         } else if ft.is_dir() {
@@ -38,7 +67,41 @@ impl From<FileType> for Kind {
         } else if ft.is_symlink() {
             Kind::Symlink
         } else {
+            #[cfg(unix)]
+            if ft.is_char_device() {
+                return Kind::CharDevice;
+            } else if ft.is_block_device() {
+                return Kind::BlockDevice;
+            } else if ft.is_fifo() {
+                return Kind::Fifo;
+            } else if ft.is_socket() {
+                return Kind::Socket;
+            }
             Kind::Unknown
         }
     }
 }
+
+/// Major and minor numbers of a device node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceNumber {
+    pub major: u64,
+    pub minor: u64,
+}
+
+#[cfg(target_os = "linux")]
+impl DeviceNumber {
+    /// Split a raw device number, as in `st_rdev`.
+    pub fn from_rdev(rdev: u64) -> DeviceNumber {
+        use nix::sys::stat::{major, minor};
+        DeviceNumber {
+            major: major(rdev),
+            minor: minor(rdev),
+        }
+    }
+
+    /// Combine into a raw device number.
+    pub fn rdev(&self) -> u64 {
+        nix::sys::stat::makedev(self.major, self.minor)
+    }
+}
@@ -12,21 +12,35 @@
 
 //! Conserve backup system.
 
+pub mod acl;
 pub mod apath;
 pub mod archive;
+mod archive_stats;
 pub mod backup;
 mod band;
 pub mod bandid;
+pub mod bench;
+mod block_cache;
 pub mod blockdir;
 pub mod blockhash;
+pub mod capability;
 pub mod change;
+mod change_cache;
+pub mod compact;
 pub mod compress;
 pub mod counters;
 mod diff;
+pub mod du;
 pub mod entry;
+#[cfg(feature = "async")]
+mod entry_stream;
 pub mod errors;
 pub mod excludes;
+pub mod export_tar;
+pub mod find;
 mod gc_lock;
+pub mod import_tar;
+pub mod include;
 pub mod index;
 mod io;
 mod jsonio;
@@ -36,47 +50,93 @@ mod merge;
 pub mod misc;
 pub mod monitor;
 pub mod owner;
+pub mod parallelism;
+pub mod prune;
+pub mod recompress;
+pub mod repair;
+pub mod replicate;
 pub mod restore;
+pub mod salvage;
+pub mod serve;
 pub mod show;
+mod simple;
+pub mod snapshot;
+pub mod sparse;
 pub mod stats;
 mod stitch;
 mod stored_tree;
 pub mod termui;
 pub mod test_fixtures;
+pub mod throttle;
 pub mod transport;
 mod tree;
 pub mod unix_mode;
 pub mod unix_time;
 pub mod validate;
+mod verify;
+mod vss;
+pub mod watch;
 
+pub use crate::acl::Acls;
 pub use crate::apath::Apath;
 pub use crate::archive::Archive;
 pub use crate::archive::DeleteOptions;
-pub use crate::backup::{backup, BackupOptions, BackupStats};
-pub use crate::band::{Band, BandSelectionPolicy};
+pub use crate::archive_stats::archive_stats;
+pub use crate::backup::{backup, backup_sources, backup_stream, BackupOptions, BackupStats};
+pub use crate::band::{Band, BandInfo, BandSelectionPolicy};
 pub use crate::bandid::BandId;
+pub use crate::bench::{bench, BenchOptions, BenchReport, BenchResult};
 pub use crate::blockdir::BlockDir;
 pub use crate::blockhash::BlockHash;
+pub use crate::capability::Capability;
 pub use crate::change::{ChangeCallback, EntryChange};
+pub use crate::compact::{compact, CompactOptions};
+pub use crate::compress::Compression;
 pub use crate::diff::{diff, DiffOptions};
+pub use crate::du::{disk_usage, DirUsage, DiskUsageOptions};
 pub use crate::entry::{EntryTrait, EntryValue};
-pub use crate::errors::Error;
+#[cfg(feature = "async")]
+pub use crate::entry_stream::IndexEntryStream;
+pub use crate::errors::{Error, ErrorClass};
 pub use crate::excludes::Exclude;
+pub use crate::export_tar::{export_tar, ExportTarOptions};
+pub use crate::find::{find, history, FindPattern, FoundVersion, VersionChange};
 pub use crate::gc_lock::GarbageCollectionLock;
-pub use crate::index::{IndexEntry, IndexRead, IndexWriter};
-pub use crate::kind::Kind;
-pub use crate::live_tree::LiveTree;
-pub use crate::merge::MergeTrees;
+pub use crate::import_tar::import_tar;
+pub use crate::include::Include;
+pub use crate::index::{IndexEntry, IndexFormat, IndexRead, IndexWriter};
+pub use crate::kind::{DeviceNumber, Kind};
+pub use crate::live_tree::{FollowSymlinks, LiveTree};
+pub use crate::merge::{compare, compare_entries, MatchedEntries, MergeTrees, TreeChange};
 pub use crate::misc::bytes_to_human_mb;
-pub use crate::owner::Owner;
-pub use crate::restore::{restore, RestoreOptions};
-pub use crate::show::{show_versions, ShowVersionsOptions};
-pub use crate::stats::DeleteStats;
-pub use crate::stored_tree::StoredTree;
+pub use crate::owner::{Owner, OwnerMap};
+pub use crate::parallelism::Parallelism;
+pub use crate::prune::{prune, PruneOptions};
+pub use crate::recompress::{recompress, RecompressOptions};
+pub use crate::repair::{repair, RepairOptions};
+pub use crate::replicate::replicate;
+pub use crate::restore::{
+    restore, ConflictResolution, Overwrite, RestoreConflict, RestoreOptions, SetOwners,
+    UnchangedCheck,
+};
+pub use crate::salvage::salvage;
+pub use crate::serve::serve;
+pub use crate::show::{
+    show_disk_usage, show_found_versions, show_long_listing, show_versions, ShowVersionsOptions,
+};
+pub use crate::simple::{backup_paths, restore_band, BackupSummary, RestoreSummary};
+pub use crate::snapshot::SnapshotMethod;
+pub use crate::stats::{
+    ArchiveInfo, ArchiveStats, BandDedupStats, CopyBandStats, DeleteStats, ReplicateStats,
+    RestoreStats, StatsJson, VerifyStats,
+};
+pub use crate::stored_tree::{StoredFile, StoredTree};
 pub use crate::transport::{open_transport, Transport};
 pub use crate::tree::{ReadTree, TreeSize};
 pub use crate::unix_mode::UnixMode;
-pub use crate::validate::ValidateOptions;
+pub use crate::validate::{BlockSample, ValidateOptions, ValidationMarker};
+pub use crate::verify::{verify, Drift, DriftCallback, VerifyOptions};
+pub use crate::watch::{watch, WatchOptions};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -14,13 +14,16 @@
 //! Access a "live" on-disk tree as a source for backups, destination for restores, etc.
 
 use std::collections::vec_deque::VecDeque;
+use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
-use std::io::ErrorKind;
+use std::io::{self, ErrorKind};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use tracing::{error, warn};
+use itertools::Itertools;
+use time::OffsetDateTime;
+use tracing::{error, info, warn};
 
 use crate::entry::KindMeta;
 use crate::monitor::Monitor;
@@ -30,7 +33,64 @@ use crate::*;
 /// A real tree on the filesystem, for use as a backup source or restore destination.
 #[derive(Clone)]
 pub struct LiveTree {
-    path: PathBuf,
+    roots: Roots,
+    follow_symlinks: FollowSymlinks,
+}
+
+/// Whether to follow symlinks while walking a source tree.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq, clap::ValueEnum)]
+pub enum FollowSymlinks {
+    /// Store symlinks as symlinks.
+    #[default]
+    Never,
+    /// Follow the source directories if they are themselves symlinks, but store
+    /// symlinks inside them as symlinks.
+    Root,
+    /// Store the files and directories that symlinks point to, in place of the symlinks.
+    ///
+    /// On Unix, symlinks to a directory that contains them are stored as symlinks,
+    /// to avoid loops. A directory reached through several symlinks is stored at
+    /// each of them. On other platforms, symlinks to directories are never followed.
+    Always,
+}
+
+/// The directories on the filesystem that make up a [LiveTree].
+#[derive(Clone, Debug)]
+enum Roots {
+    /// The tree is a single directory.
+    Single(PathBuf),
+    /// Each directory appears under its name at the top of the tree, sorted by name,
+    /// below a root that doesn't exist on the filesystem.
+    Multiple(Vec<(String, PathBuf)>),
+}
+
+impl Roots {
+    /// Return the filesystem path for an apath, or None for the root of a
+    /// multi-directory tree, or a top-level name that doesn't exist.
+    fn path_of(&self, apath: &Apath) -> Option<PathBuf> {
+        match self {
+            Roots::Single(path) => Some(apath.below(path)),
+            Roots::Multiple(sources) => {
+                let (name, rest) = apath[1..].split_once('/').unwrap_or((&apath[1..], ""));
+                let (_, path) = sources
+                    .iter()
+                    .find(|(source_name, _)| source_name == name)?;
+                if rest.is_empty() {
+                    Some(path.clone())
+                } else {
+                    Some(path.join(rest))
+                }
+            }
+        }
+    }
+
+    /// The source directories, in the order they appear in the tree.
+    fn paths(&self) -> Vec<&Path> {
+        match self {
+            Roots::Single(path) => vec![path],
+            Roots::Multiple(sources) => sources.iter().map(|(_, path)| path.as_path()).collect(),
+        }
+    }
 }
 
 impl LiveTree {
@@ -38,23 +98,77 @@ impl LiveTree {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<LiveTree> {
         // TODO: Maybe fail here if the root doesn't exist or isn't a directory?
         Ok(LiveTree {
-            path: path.as_ref().to_path_buf(),
+            roots: Roots::Single(path.as_ref().to_path_buf()),
+            follow_symlinks: FollowSymlinks::default(),
+        })
+    }
+
+    /// Open a tree combining several directories, each appearing at the top of the
+    /// tree under its own name: for example `/etc` and `/home/alice` become `/etc`
+    /// and `/alice`.
+    ///
+    /// The directories must have distinct names.
+    pub fn open_multiple<P: AsRef<Path>>(paths: &[P]) -> Result<LiveTree> {
+        let mut sources = Vec::with_capacity(paths.len());
+        for path in paths {
+            let path = path.as_ref();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| Error::UnnamedSource {
+                    path: path.to_owned(),
+                })?;
+            sources.push((name.to_owned(), path.to_owned()));
+        }
+        sources.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        if let Some(((name, _), _)) = sources.iter().tuple_windows().find(|(a, b)| a.0 == b.0) {
+            return Err(Error::DuplicateSourceName { name: name.clone() });
+        }
+        Ok(LiveTree {
+            roots: Roots::Multiple(sources),
+            follow_symlinks: FollowSymlinks::default(),
         })
     }
 
-    fn relative_path(&self, apath: &Apath) -> PathBuf {
-        apath.below(&self.path)
+    /// Set whether symlinks are followed when walking the tree.
+    #[must_use]
+    pub fn with_follow_symlinks(self, follow_symlinks: FollowSymlinks) -> LiveTree {
+        LiveTree {
+            follow_symlinks,
+            ..self
+        }
+    }
+
+    fn relative_path(&self, apath: &Apath) -> Result<PathBuf> {
+        self.roots
+            .path_of(apath)
+            .ok_or_else(|| Error::InvalidMetadata {
+                details: format!("No source directory contains {apath}"),
+            })
     }
 
     /// Return the root path for this tree.
+    ///
+    /// # Panics
+    ///
+    /// If the tree was opened with [LiveTree::open_multiple], which has no single
+    /// root path; use [LiveTree::paths] instead.
     pub fn path(&self) -> &Path {
-        &self.path
+        match &self.roots {
+            Roots::Single(path) => path,
+            Roots::Multiple(_) => panic!("a tree of several directories has no single path"),
+        }
+    }
+
+    /// Return the directories making up this tree.
+    pub fn paths(&self) -> Vec<&Path> {
+        self.roots.paths()
     }
 
     /// Open a file inside the tree to read.
     pub fn open_file(&self, entry: &EntryValue) -> Result<File> {
         assert_eq!(entry.kind(), Kind::File);
-        let path = self.relative_path(&entry.apath);
+        let path = self.relative_path(&entry.apath)?;
         fs::File::open(&path).map_err(|source| Error::ReadSourceFile { path, source })
     }
 }
@@ -69,7 +183,7 @@ impl tree::ReadTree for LiveTree {
         exclude: Exclude,
         _monitor: Arc<dyn Monitor>,
     ) -> Result<Self::IT> {
-        Iter::new(&self.path, subtree, exclude)
+        Iter::new(&self.roots, self.follow_symlinks, subtree, exclude)
     }
 }
 
@@ -106,6 +220,8 @@ fn entry_from_fs_metadata(
             }
         };
         KindMeta::Symlink { target }
+    } else if let Some(kind_meta) = special_kind_meta(metadata) {
+        kind_meta
     } else {
         return Err(Error::UnsupportedSourceKind {
             path: source_path.to_owned(),
@@ -113,15 +229,170 @@ fn entry_from_fs_metadata(
     };
     let owner = Owner::from(metadata);
     let unix_mode = UnixMode::from(metadata.permissions());
+    let acls = if metadata.is_symlink() {
+        Acls::default()
+    } else {
+        // The file can still be backed up, so this is only a warning, as for capabilities.
+        Acls::read(source_path).unwrap_or_else(|err| {
+            warn!("Failed to read ACLs of {source_path:?}: {err}");
+            Acls::default()
+        })
+    };
+    let capability = if metadata.is_file() {
+        Capability::read(source_path).unwrap_or_else(|err| {
+            warn!("Failed to read capabilities of {source_path:?}: {err}");
+            None
+        })
+    } else {
+        None
+    };
     Ok(EntryValue {
         apath,
         mtime,
         kind_meta,
         unix_mode,
         owner,
+        acls,
+        capability,
+        link_group: None,
+        inode: inode(metadata),
     })
 }
 
+/// The device containing a file, on platforms that have them.
+#[cfg(unix)]
+fn device_id(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device_id(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// The inode number of a file, on platforms that have them.
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+/// Describe a device node, FIFO, or socket.
+#[cfg(unix)]
+fn special_kind_meta(metadata: &fs::Metadata) -> Option<KindMeta> {
+    use std::os::unix::fs::FileTypeExt;
+    let file_type = metadata.file_type();
+    if file_type.is_fifo() {
+        Some(KindMeta::Fifo)
+    } else if file_type.is_socket() {
+        Some(KindMeta::Socket)
+    } else {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let device = DeviceNumber::from_rdev(metadata.rdev());
+            if file_type.is_char_device() {
+                return Some(KindMeta::CharDevice { device });
+            } else if file_type.is_block_device() {
+                return Some(KindMeta::BlockDevice { device });
+            }
+        }
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn special_kind_meta(_metadata: &fs::Metadata) -> Option<KindMeta> {
+    None
+}
+
+/// Identify the inode of a file that has more than one hard link.
+#[cfg(unix)]
+fn hardlink_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    if metadata.is_file() && metadata.nlink() > 1 {
+        file_id(metadata)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn hardlink_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// The (device, inode) identifying a file, on platforms that have them.
+#[cfg(unix)]
+fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Read the `.conserveignore` file in a directory, if there is one.
+///
+/// Errors are logged, and the file is then ignored.
+fn read_ignore_file(dir_apath: &Apath, dir_path: &Path) -> Option<Exclude> {
+    let path = dir_path.join(excludes::IGNORE_FILE_NAME);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return None,
+        Err(err) => {
+            error!("Failed to read {path:?}: {err}");
+            return None;
+        }
+    };
+    match Exclude::from_ignore_file(dir_apath, &content) {
+        Ok(exclude) => Some(exclude),
+        Err(err) => {
+            error!("Failed to parse {path:?}: {err}");
+            None
+        }
+    }
+}
+
+/// True if the innermost ignore file with a pattern matching this apath excludes it.
+fn is_ignored(ignore_files: &[Exclude], apath: &Apath) -> bool {
+    ignore_files
+        .iter()
+        .rev()
+        .find_map(|exclude| exclude.decide(apath))
+        .unwrap_or(false)
+}
+
+/// A child of a directory: its name, entry, and if it has multiple hard links, its
+/// (device, inode).
+type Child = (String, EntryValue, Option<(u64, u64)>);
+
+/// Patterns from the `.conserveignore` files in a directory and its parents,
+/// outermost first.
+type IgnoreFiles = Arc<Vec<Exclude>>;
+
+/// A subdirectory found in a directory: its apath and (device, inode).
+type Subdir = (Apath, Option<(u64, u64)>);
+
+/// A directory yet to be visited.
+#[derive(Debug)]
+struct PendingDir {
+    apath: Apath,
+    /// Ignore files that apply inside this directory.
+    ignore_files: IgnoreFiles,
+    /// When following all symlinks, the (device, inode) of this directory and all its
+    /// parents, to detect symlinks that would make a loop.
+    ancestors: Arc<Vec<(u64, u64)>>,
+}
+
 /// Recursive iterator of the contents of a live tree.
 ///
 /// Iterate source files descending through a source directory.
@@ -132,11 +403,11 @@ fn entry_from_fs_metadata(
 /// name.
 #[derive(Debug)]
 pub struct Iter {
-    /// Root of the source tree.
-    root_path: PathBuf,
+    /// Directories making up the source tree.
+    roots: Roots,
 
     /// Directories yet to be visited.
-    dir_deque: VecDeque<Apath>,
+    dir_deque: VecDeque<PendingDir>,
 
     /// All entries that have been seen but not yet returned by the iterator, in the order they
     /// should be returned.
@@ -148,31 +419,80 @@ pub struct Iter {
     /// Patterns to exclude from iteration.
     exclude: Exclude,
 
+    /// The first apath seen for each (device, inode) of files with multiple hard links.
+    hardlinks: HashMap<(u64, u64), Apath>,
+
+    follow_symlinks: FollowSymlinks,
+
     stats: LiveTreeIterStats,
 }
 
 impl Iter {
+    /// Statistics about the entries visited so far.
+    pub fn stats(&self) -> &LiveTreeIterStats {
+        &self.stats
+    }
+
     /// Construct a new iter that will visit everything below this root path,
     /// subject to some exclusions
-    fn new(root_path: &Path, subtree: Apath, exclude: Exclude) -> Result<Iter> {
-        let start_path = subtree.below(root_path);
-        let start_metadata = fs::symlink_metadata(&start_path)?;
+    fn new(
+        roots: &Roots,
+        follow_symlinks: FollowSymlinks,
+        subtree: Apath,
+        exclude: Exclude,
+    ) -> Result<Iter> {
+        let mut ancestors = Vec::new();
         // Preload iter to return the root and then recurse into it.
-        let entry_deque: VecDeque<EntryValue> = [entry_from_fs_metadata(
-            subtree.clone(),
-            &start_path,
-            &start_metadata,
-        )?]
-        .into();
+        let start_entry = if let Some(start_path) = roots.path_of(&subtree) {
+            let start_metadata = if follow_symlinks == FollowSymlinks::Never {
+                fs::symlink_metadata(&start_path)?
+            } else {
+                fs::metadata(&start_path)?
+            };
+            if follow_symlinks == FollowSymlinks::Always {
+                ancestors.extend(file_id(&start_metadata));
+            }
+            entry_from_fs_metadata(subtree.clone(), &start_path, &start_metadata)?
+        } else if subtree == Apath::root() {
+            // The root of a multi-directory tree exists only in the archive. Its mtime
+            // is the newest of the source directories, so it's unchanged if they are.
+            let mtime = roots
+                .paths()
+                .into_iter()
+                .filter_map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+                .max()
+                .map_or(OffsetDateTime::UNIX_EPOCH, OffsetDateTime::from);
+            EntryValue {
+                apath: subtree.clone(),
+                kind_meta: KindMeta::Dir,
+                mtime,
+                unix_mode: UnixMode::default(),
+                owner: Owner::default(),
+                acls: Acls::default(),
+                capability: None,
+                link_group: None,
+                inode: None,
+            }
+        } else {
+            return Err(io::Error::from(ErrorKind::NotFound).into());
+        };
+        let entry_deque: VecDeque<EntryValue> = [start_entry].into();
         // TODO: Consider the case where the root is not actually a directory?
         // Should that be supported?
-        let dir_deque: VecDeque<Apath> = [subtree].into();
+        let dir_deque: VecDeque<PendingDir> = [PendingDir {
+            apath: subtree,
+            ignore_files: IgnoreFiles::default(),
+            ancestors: Arc::new(ancestors),
+        }]
+        .into();
         Ok(Iter {
-            root_path: root_path.to_path_buf(),
+            roots: roots.clone(),
             entry_deque,
             dir_deque,
             check_order: apath::DebugCheckOrder::new(),
             exclude,
+            hardlinks: HashMap::new(),
+            follow_symlinks,
             stats: LiveTreeIterStats::default(),
         })
     }
@@ -181,19 +501,204 @@ impl Iter {
     ///
     /// Any errors occurring are logged but not returned; we'll continue to
     /// visit whatever can be read.
-    fn visit_next_directory(&mut self, parent_apath: &Apath) {
+    fn visit_next_directory(&mut self, dir: PendingDir) {
         self.stats.directories_visited += 1;
-        // Tuples of (name, entry) so that we can sort children by name.
-        let mut children = Vec::<(String, EntryValue)>::new();
-        let dir_path = parent_apath.below(&self.root_path);
-        let dir_iter = match fs::read_dir(&dir_path) {
+        let PendingDir {
+            apath: parent_apath,
+            mut ignore_files,
+            ancestors,
+        } = dir;
+        let mut children = Vec::new();
+        let mut subdirs: Vec<Subdir> = Vec::new();
+        match self.roots.path_of(&parent_apath) {
+            Some(dir_path) => {
+                if let Some(exclude) = read_ignore_file(&parent_apath, &dir_path) {
+                    Arc::make_mut(&mut ignore_files).push(exclude);
+                }
+                self.read_directory(
+                    &parent_apath,
+                    &dir_path,
+                    &ignore_files,
+                    &ancestors,
+                    &mut children,
+                    &mut subdirs,
+                )
+            }
+            None => self.read_sources(&parent_apath, &mut children, &mut subdirs),
+        }
+        // To get the right overall tree ordering, any new subdirectories
+        // discovered here should be visited together in apath order, but before
+        // any previously pending directories. In other words, in reverse order
+        // push them onto the front of the dir deque.
+        if !subdirs.is_empty() {
+            subdirs.sort_unstable();
+            self.dir_deque.reserve(subdirs.len());
+            for (apath, id) in subdirs.into_iter().rev() {
+                let ancestors = match id {
+                    Some(id) if self.follow_symlinks == FollowSymlinks::Always => {
+                        let mut ancestors = Vec::clone(&ancestors);
+                        ancestors.push(id);
+                        Arc::new(ancestors)
+                    }
+                    _ => ancestors.clone(),
+                };
+                self.dir_deque.push_front(PendingDir {
+                    apath,
+                    ignore_files: ignore_files.clone(),
+                    ancestors,
+                });
+            }
+        }
+        children.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        // Entries are visited in apath order, so the first link seen to each inode is
+        // the one that will be restored first.
+        for (_name, mut entry, hardlink_id) in children {
+            if let Some(hardlink_id) = hardlink_id {
+                entry.link_group = Some(
+                    self.hardlinks
+                        .entry(hardlink_id)
+                        .or_insert_with(|| entry.apath.clone())
+                        .clone(),
+                );
+            }
+            self.entry_deque.push_back(entry);
+        }
+    }
+
+    /// Read the top-level source directories of a multi-directory tree.
+    fn read_sources(
+        &mut self,
+        parent_apath: &Apath,
+        children: &mut Vec<Child>,
+        subdirs: &mut Vec<Subdir>,
+    ) {
+        let Roots::Multiple(sources) = self.roots.clone() else {
+            return;
+        };
+        for (name, path) in &sources {
+            let child_apath = parent_apath.append(name);
+            if self.exclude.matches(&child_apath) {
+                self.stats.exclusions += 1;
+                continue;
+            }
+            let metadata = if self.follow_symlinks == FollowSymlinks::Never {
+                fs::symlink_metadata(path)
+            } else {
+                fs::metadata(path)
+            };
+            let metadata = match metadata {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    error!("Failed to read source metadata from {path:?}: {err}");
+                    self.stats.metadata_error += 1;
+                    continue;
+                }
+            };
+            if self.is_skipped(path, &metadata) {
+                continue;
+            }
+            if metadata.is_dir() {
+                subdirs.push((child_apath.clone(), file_id(&metadata)));
+            }
+            match entry_from_fs_metadata(child_apath, path, &metadata) {
+                Ok(entry) => children.push((name.clone(), entry, hardlink_id(&metadata))),
+                Err(err) => error!("Failed to build entry for {path:?}: {err:?}"),
+            }
+        }
+    }
+
+    /// True if a file or directory should be skipped, because it's a cache directory,
+    /// a directory containing an exclusion marker, or a file that's too large or too
+    /// old; and if so count why.
+    fn is_skipped(&mut self, path: &Path, metadata: &fs::Metadata) -> bool {
+        if metadata.is_dir() && self.exclude.skips_cachedirs() {
+            match cachedir::is_tagged(path) {
+                Ok(true) => {
+                    self.stats.cachedirs_skipped += 1;
+                    return true;
+                }
+                Ok(false) => (),
+                Err(e) => {
+                    error!("Error checking CACHEDIR.TAG in {path:?}: {e}");
+                }
+            }
+        }
+        if metadata.is_dir() && self.exclude.has_marker_file(path) {
+            self.stats.marked_dirs_skipped += 1;
+            return true;
+        }
+        if metadata.is_file() && self.exclude.is_too_large(metadata.len()) {
+            self.stats.large_files_skipped += 1;
+            self.stats.large_file_bytes_skipped += metadata.len();
+            return true;
+        }
+        if metadata.is_file()
+            && metadata
+                .modified()
+                .is_ok_and(|mtime| self.exclude.is_too_old(mtime.into()))
+        {
+            self.stats.old_files_skipped += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Return the metadata of the target of a symlink, if it should be followed,
+    /// or otherwise the metadata of the symlink itself.
+    ///
+    /// Symlinks to directories containing the symlink are not followed, since that would
+    /// make a loop.
+    fn follow_symlink(
+        apath: &Apath,
+        path: &Path,
+        link_metadata: fs::Metadata,
+        ancestors: &[(u64, u64)],
+    ) -> fs::Metadata {
+        match fs::metadata(path) {
+            Ok(target_metadata) if target_metadata.is_dir() => {
+                if file_id(&target_metadata).is_some_and(|id| !ancestors.contains(&id)) {
+                    target_metadata
+                } else {
+                    warn!("Not following symlink {apath} to a directory containing it");
+                    link_metadata
+                }
+            }
+            Ok(target_metadata) => target_metadata,
+            Err(err) => {
+                warn!("Not following broken symlink {apath}: {err}");
+                link_metadata
+            }
+        }
+    }
+
+    /// Read the children of one directory on the filesystem.
+    fn read_directory(
+        &mut self,
+        parent_apath: &Apath,
+        dir_path: &Path,
+        ignore_files: &[Exclude],
+        ancestors: &[(u64, u64)],
+        children: &mut Vec<Child>,
+        subdirs: &mut Vec<Subdir>,
+    ) {
+        let dir_iter = match fs::read_dir(dir_path) {
             Ok(i) => i,
             Err(err) => {
                 error!("Error reading directory {dir_path:?}: {err}");
                 return;
             }
         };
-        let mut subdir_apaths: Vec<Apath> = Vec::new();
+        let dir_device = if self.exclude.stays_on_one_file_system() {
+            match fs::metadata(dir_path) {
+                Ok(metadata) => device_id(&metadata),
+                Err(err) => {
+                    error!("Failed to read metadata of directory {dir_path:?}: {err}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
         for dir_entry in dir_iter {
             let dir_entry = match dir_entry {
                 Ok(dir_entry) => dir_entry,
@@ -212,7 +717,7 @@ impl Iter {
             };
             let child_apath = parent_apath.append(child_name);
 
-            if self.exclude.matches(&child_apath) {
+            if self.exclude.matches(&child_apath) || is_ignored(ignore_files, &child_apath) {
                 self.stats.exclusions += 1;
                 continue;
             }
@@ -224,18 +729,6 @@ impl Iter {
                     continue;
                 }
             };
-            if ft.is_dir() {
-                // TODO: Count them?
-                // TODO: Perhaps an option to back them up anyhow?
-                match cachedir::is_tagged(dir_entry.path()) {
-                    Ok(true) => continue,
-                    Ok(false) => (),
-                    Err(e) => {
-                        error!("Error checking CACHEDIR.TAG in {dir_entry:?}: {e}");
-                    }
-                }
-            }
-
             let metadata = match dir_entry.metadata() {
                 Ok(metadata) => metadata,
                 Err(e) => {
@@ -253,16 +746,30 @@ impl Iter {
                     continue;
                 }
             };
+            let metadata = if ft.is_symlink() && self.follow_symlinks == FollowSymlinks::Always {
+                Iter::follow_symlink(&child_apath, &dir_entry.path(), metadata, ancestors)
+            } else {
+                metadata
+            };
 
-            if ft.is_dir() {
-                subdir_apaths.push(child_apath.clone());
+            if self.is_skipped(&dir_entry.path(), &metadata) {
+                continue;
+            }
+            if metadata.is_dir() {
+                if dir_device.is_some() && device_id(&metadata) != dir_device {
+                    // Keep the mount point itself, but not what's mounted on it.
+                    info!("Not descending into mount point {child_apath}");
+                    self.stats.mount_points_skipped += 1;
+                } else {
+                    subdirs.push((child_apath.clone(), file_id(&metadata)));
+                }
             }
             let child_path = dir_path.join(dir_entry.file_name());
             let entry = match entry_from_fs_metadata(child_apath, &child_path, &metadata) {
                 Ok(entry) => entry,
                 Err(Error::UnsupportedSourceKind { .. }) => {
-                    // It's not too surprising that there would be fifos or sockets or files
-                    // we don't support; don't log them.
+                    // It's not too surprising that there would be kinds of files
+                    // we don't support on this platform; don't log them.
                     continue;
                 }
                 Err(err) => {
@@ -270,21 +777,8 @@ impl Iter {
                     continue;
                 }
             };
-            children.push((child_name.to_string(), entry));
+            children.push((child_name.to_string(), entry, hardlink_id(&metadata)));
         }
-        // To get the right overall tree ordering, any new subdirectories
-        // discovered here should be visited together in apath order, but before
-        // any previously pending directories. In other words, in reverse order
-        // push them onto the front of the dir deque.
-        if !subdir_apaths.is_empty() {
-            subdir_apaths.sort_unstable();
-            self.dir_deque.reserve(subdir_apaths.len());
-            for a in subdir_apaths.into_iter().rev() {
-                self.dir_deque.push_front(a);
-            }
-        }
-        children.sort_unstable_by(|a, b| a.0.cmp(&b.0));
-        self.entry_deque.extend(children.into_iter().map(|x| x.1));
     }
 }
 
@@ -302,14 +796,18 @@ impl Iterator for Iter {
     fn next(&mut self) -> Option<EntryValue> {
         loop {
             if let Some(entry) = self.entry_deque.pop_front() {
-                // Have already found some entries, so just return the first.
-                self.stats.entries_returned += 1;
                 // Sanity check that all the returned paths are in correct order.
                 self.check_order.check(&entry.apath);
+                // Directories of other kinds are still visited, but not returned.
+                if !self.exclude.includes_kind(entry.kind()) {
+                    continue;
+                }
+                // Have already found some entries, so just return the first.
+                self.stats.entries_returned += 1;
                 return Some(entry);
-            } else if let Some(entry) = self.dir_deque.pop_front() {
+            } else if let Some(dir) = self.dir_deque.pop_front() {
                 // No entries already queued, visit a new directory to try to refill the queue.
-                self.visit_next_directory(&entry)
+                self.visit_next_directory(dir)
             } else {
                 // No entries queued and no more directories to visit.
                 return None;
@@ -330,6 +828,7 @@ mod test {
         let tf = TreeFixture::new();
         let lt = LiveTree::open(tf.path()).unwrap();
         assert_eq!(lt.path(), tf.path());
+        assert_eq!(lt.paths(), [tf.path()]);
     }
 
     #[test]
@@ -399,6 +898,86 @@ mod test {
         // assert_eq!(source_iter.stats.exclusions, 5);
     }
 
+    #[test]
+    fn only_kinds() {
+        let tf = TreeFixture::new();
+        tf.create_file("bar");
+        tf.create_dir("baz");
+        tf.create_file("baz/bar");
+        tf.create_dir("baz/deeper");
+
+        let lt = LiveTree::open(tf.path()).unwrap();
+        let exclude = Exclude::nothing().with_only_kinds(Some(vec![Kind::File]));
+        let names = entry_iter_to_apath_strings(
+            lt.iter_entries(Apath::root(), exclude, TestMonitor::arc())
+                .unwrap(),
+        );
+        assert_eq!(names, ["/bar", "/baz/bar"]);
+
+        let exclude = Exclude::nothing().with_only_kinds(Some(vec![Kind::Dir]));
+        let names = entry_iter_to_apath_strings(
+            lt.iter_entries(Apath::root(), exclude, TestMonitor::arc())
+                .unwrap(),
+        );
+        assert_eq!(names, ["/", "/baz", "/baz/deeper"]);
+    }
+
+    #[test]
+    fn ignore_files() {
+        let tf = TreeFixture::new();
+        tf.create_file_with_contents(".conserveignore", b"*.o\n/tmp\n");
+        tf.create_file("main.o");
+        tf.create_dir("tmp");
+        tf.create_file("tmp/junk");
+        tf.create_dir("src");
+        tf.create_file_with_contents("src/.conserveignore", b"!keep.o\ngen/\n");
+        tf.create_file("src/keep.o");
+        tf.create_file("src/lib.o");
+        tf.create_dir("src/gen");
+        tf.create_file("src/gen/out.c");
+        tf.create_dir("src/tmp");
+        tf.create_file("gen");
+
+        let lt = LiveTree::open(tf.path()).unwrap();
+        let names = entry_iter_to_apath_strings(
+            lt.iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+                .unwrap(),
+        );
+
+        assert_eq!(
+            names,
+            [
+                "/",
+                "/.conserveignore",
+                "/gen",
+                "/src",
+                "/src/.conserveignore",
+                "/src/keep.o",
+                "/src/tmp",
+            ]
+        );
+    }
+
+    #[test]
+    fn one_file_system_includes_subdirectories_on_the_same_filesystem() {
+        let tf = TreeFixture::new();
+        tf.create_dir("a");
+        tf.create_dir("a/b");
+        tf.create_file("a/b/c");
+
+        let lt = LiveTree::open(tf.path()).unwrap();
+        let mut iter = lt
+            .iter_entries(
+                Apath::root(),
+                Exclude::nothing().with_one_file_system(true),
+                TestMonitor::arc(),
+            )
+            .unwrap();
+        let names = entry_iter_to_apath_strings(iter.by_ref());
+        assert_eq!(names, ["/", "/a", "/a/b", "/a/b/c"]);
+        assert_eq!(iter.stats().mount_points_skipped, 0);
+    }
+
     #[cfg(unix)]
     #[test]
     fn symlinks() {
@@ -414,6 +993,75 @@ mod test {
         assert_eq!(names, ["/", "/from"]);
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn follow_symlinks_always() {
+        let tf = TreeFixture::new();
+        tf.create_dir("real");
+        tf.create_file("real/file");
+        tf.create_symlink("real/up", "..");
+        tf.create_symlink("link_dir", "real");
+        tf.create_symlink("link_file", "real/file");
+        tf.create_symlink("broken", "nowhere");
+
+        let lt = LiveTree::open(tf.path())
+            .unwrap()
+            .with_follow_symlinks(FollowSymlinks::Always);
+        let entries = lt
+            .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+            .unwrap()
+            .map(|entry| (entry.apath().to_string(), entry.kind()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            [
+                ("/".to_owned(), Kind::Dir),
+                ("/broken".to_owned(), Kind::Symlink),
+                ("/link_dir".to_owned(), Kind::Dir),
+                ("/link_file".to_owned(), Kind::File),
+                ("/real".to_owned(), Kind::Dir),
+                ("/link_dir/file".to_owned(), Kind::File),
+                // The symlink back up to the root would make a loop.
+                ("/link_dir/up".to_owned(), Kind::Symlink),
+                ("/real/file".to_owned(), Kind::File),
+                ("/real/up".to_owned(), Kind::Symlink),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follow_symlinks_root_only() {
+        let tf = TreeFixture::new();
+        tf.create_dir("real");
+        tf.create_file("real/file");
+        tf.create_symlink("real/link", "file");
+
+        let lt = LiveTree::open(tf.path().join("real"))
+            .unwrap()
+            .with_follow_symlinks(FollowSymlinks::Root);
+        let root_link = TreeFixture::new();
+        root_link.create_symlink("root", &tf.path().join("real").to_string_lossy());
+        let linked = LiveTree::open(root_link.path().join("root"))
+            .unwrap()
+            .with_follow_symlinks(FollowSymlinks::Root);
+        for tree in [lt, linked] {
+            let entries = tree
+                .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+                .unwrap()
+                .map(|entry| (entry.apath().to_string(), entry.kind()))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                entries,
+                [
+                    ("/".to_owned(), Kind::Dir),
+                    ("/file".to_owned(), Kind::File),
+                    ("/link".to_owned(), Kind::Symlink),
+                ]
+            );
+        }
+    }
+
     #[test]
     fn iter_subtree_entries() {
         let tf = TreeFixture::new();
@@ -14,11 +14,23 @@
 //!
 //! This is a foundation for showing the diff between a stored and
 //! live tree, or storing an incremental backup.
+//!
+//! [compare] walks two trees and yields only the entries that were added,
+//! removed, or changed, as [TreeChange]s.
 
 use std::cmp::Ordering;
+use std::sync::Arc;
+
+use readahead_iterator::IntoReadahead;
 
+use crate::change::metadata_differs;
+use crate::monitor::Monitor;
+use crate::tree::ReadTree;
 use crate::*;
 
+/// Number of entries to read ahead from each tree while merging.
+const READAHEAD: usize = 1000;
+
 /// When merging entries from two trees a particular apath might
 /// be present in either or both trees.
 ///
@@ -41,13 +53,140 @@ where
     AE: EntryTrait,
     BE: EntryTrait,
 {
-    pub(crate) fn to_entry_change(&self) -> EntryChange {
+    /// Summarize the metadata change between the two sides.
+    pub fn to_entry_change(&self) -> EntryChange {
         match self {
             MatchedEntries::Both(ae, be) => EntryChange::diff_metadata(ae, be),
             MatchedEntries::Left(ae) => EntryChange::deleted(ae),
             MatchedEntries::Right(be) => EntryChange::added(be),
         }
     }
+
+    /// Convert to a [TreeChange], or None if the entry is present on both
+    /// sides with the same metadata.
+    pub fn into_tree_change(self) -> Option<TreeChange<AE, BE>> {
+        match self {
+            MatchedEntries::Both(ae, be) => {
+                if metadata_differs(&ae, &be) {
+                    Some(TreeChange::Changed(ae, be))
+                } else {
+                    None
+                }
+            }
+            MatchedEntries::Left(ae) => Some(TreeChange::Removed(ae)),
+            MatchedEntries::Right(be) => Some(TreeChange::Added(be)),
+        }
+    }
+}
+
+/// A difference between two trees at one apath, holding the full entries
+/// from each side.
+///
+/// The left tree is the old one, and the right tree the new one.
+///
+/// Entries are compared by metadata only: a file with the same size and
+/// mtime is considered unchanged without reading its content.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TreeChange<AE, BE>
+where
+    AE: EntryTrait,
+    BE: EntryTrait,
+{
+    /// Present only in the new tree.
+    Added(BE),
+    /// Present only in the old tree.
+    Removed(AE),
+    /// Present in both trees with different metadata.
+    Changed(AE, BE),
+}
+
+impl<AE, BE> TreeChange<AE, BE>
+where
+    AE: EntryTrait,
+    BE: EntryTrait,
+{
+    pub fn apath(&self) -> &Apath {
+        match self {
+            TreeChange::Added(be) => be.apath(),
+            TreeChange::Removed(ae) => ae.apath(),
+            TreeChange::Changed(_ae, be) => be.apath(),
+        }
+    }
+
+    /// Summarize the metadata change, as shown by `conserve diff`.
+    pub fn to_entry_change(&self) -> EntryChange {
+        match self {
+            TreeChange::Added(be) => EntryChange::added(be),
+            TreeChange::Removed(ae) => EntryChange::deleted(ae),
+            TreeChange::Changed(ae, be) => EntryChange::changed(ae, be),
+        }
+    }
+}
+
+/// Compare two iterators of entries, each in apath order, yielding only the
+/// entries that differ.
+pub fn compare_entries<AE, BE, AIT, BIT>(
+    ait: AIT,
+    bit: BIT,
+) -> impl Iterator<Item = TreeChange<AE, BE>>
+where
+    AE: EntryTrait,
+    BE: EntryTrait,
+    AIT: Iterator<Item = AE>,
+    BIT: Iterator<Item = BE>,
+{
+    MergeTrees::new(ait, bit).filter_map(MatchedEntries::into_tree_change)
+}
+
+/// Merge the entries of two trees, such as a stored and a live tree, or two
+/// stored trees, reading ahead from each on a separate thread.
+///
+/// Entries of unknown kind, such as unsupported special files in a live tree,
+/// are skipped.
+pub(crate) fn merge_trees<A, B>(
+    a: &A,
+    b: &B,
+    exclude: &Exclude,
+    monitor: Arc<dyn Monitor>,
+) -> Result<impl Iterator<Item = MatchedEntries<A::Entry, B::Entry>>>
+where
+    A: ReadTree,
+    B: ReadTree,
+    A::Entry: Send,
+    B::Entry: Send,
+    A::IT: Send + 'static,
+    B::IT: Send + 'static,
+{
+    let ait = a
+        .iter_entries(Apath::root(), exclude.clone(), monitor.clone())?
+        .filter(|e| e.kind() != Kind::Unknown)
+        .readahead(READAHEAD);
+    let bit = b
+        .iter_entries(Apath::root(), exclude.clone(), monitor)?
+        .filter(|e| e.kind() != Kind::Unknown)
+        .readahead(READAHEAD);
+    Ok(MergeTrees::new(ait, bit))
+}
+
+/// Compare two trees, yielding a [TreeChange] for each entry that was added,
+/// removed, or changed going from `a` to `b`.
+///
+/// This can compare a stored tree to a live tree, or two stored trees.
+pub fn compare<A, B>(
+    a: &A,
+    b: &B,
+    exclude: &Exclude,
+    monitor: Arc<dyn Monitor>,
+) -> Result<impl Iterator<Item = TreeChange<A::Entry, B::Entry>>>
+where
+    A: ReadTree,
+    B: ReadTree,
+    A::Entry: Send,
+    B::Entry: Send,
+    A::IT: Send + 'static,
+    B::IT: Send + 'static,
+{
+    Ok(merge_trees(a, b, exclude, monitor)?.filter_map(MatchedEntries::into_tree_change))
 }
 
 /// Zip together entries from two trees, into an iterator of [MatchedEntries].
@@ -125,7 +264,7 @@ mod tests {
     use crate::test_fixtures::*;
     use crate::*;
 
-    use super::MatchedEntries;
+    use super::{compare, MatchedEntries, TreeChange};
 
     #[test]
     fn merge_entry_trees() {
@@ -153,5 +292,40 @@ mod tests {
         }
     }
 
-    // TODO: More tests of various diff situations.
+    #[test]
+    fn compare_entries_yields_only_differences() {
+        let ta = TreeFixture::new();
+        ta.create_file_with_contents("changed", b"old");
+        ta.create_file("removed");
+        ta.create_file("same");
+        let tb = TreeFixture::new();
+        tb.create_file_with_contents("added", b"new");
+        tb.create_file_with_contents("changed", b"new content");
+        tb.create_file("same");
+        let mtime = filetime::FileTime::from_unix_time(1_700_000_000, 0);
+        for path in [ta.path().join("same"), tb.path().join("same")] {
+            filetime::set_file_mtime(path, mtime).unwrap();
+        }
+        let monitor = TestMonitor::arc();
+        let changes = compare(
+            &ta.live_tree(),
+            &tb.live_tree(),
+            &Exclude::nothing(),
+            monitor.clone(),
+        )
+        .unwrap()
+        .collect::<Vec<_>>();
+        let summary = changes
+            .iter()
+            .map(|c| c.to_entry_change().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(summary, ["+ /added", "* /changed", "- /removed"]);
+        match &changes[1] {
+            TreeChange::Changed(old, new) => {
+                assert_eq!(old.size(), Some(3));
+                assert_eq!(new.size(), Some(11));
+            }
+            other => panic!("unexpected {other:#?}"),
+        }
+    }
 }
@@ -15,7 +15,12 @@
 
 use std::time::Duration;
 
+use time::format_description::well_known::Rfc3339;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime};
+
 use crate::stats::Sizes;
+use crate::{Error, Result};
 
 /// Remove and return an item from a vec, if it's present.
 pub(crate) fn remove_item<T, U: PartialEq<T>>(v: &mut Vec<T>, item: &U) {
@@ -49,6 +54,81 @@ pub(crate) fn zero_u64(a: &u64) -> bool {
     *a == 0
 }
 
+/// Parse a size like `1500`, `10k`, `1GB`, or `4GiB` into bytes.
+///
+/// Suffixes are case-insensitive. `k`, `M`, `G`, and `T`, optionally followed by
+/// `B`, are powers of 1000, consistent with how sizes are shown; `KiB`, `MiB`, `GiB`,
+/// and `TiB` are powers of 1024.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let invalid = || Error::InvalidSize { spec: s.to_owned() };
+    let s = s.trim();
+    let digits_end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(digits_end);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let multiplier: u64 = match suffix.trim_start().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => return Err(invalid()),
+    };
+    number.checked_mul(multiplier).ok_or_else(invalid)
+}
+
+/// Parse a duration like `30s`, `15m`, `12h`, `90d`, or `2w`.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let invalid = || Error::InvalidDuration { spec: s.to_owned() };
+    let s = s.trim();
+    let digits_end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(digits_end);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let unit_secs: u64 = match suffix.trim_start() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 7 * 86400,
+        _ => return Err(invalid()),
+    };
+    number
+        .checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .ok_or_else(invalid)
+}
+
+/// Parse a date like `2024-01-31`, meaning the start of that day in UTC, or an
+/// RFC 3339 timestamp like `2024-01-31T12:00:00+10:00`.
+pub fn parse_date(s: &str) -> Result<OffsetDateTime> {
+    let s = s.trim();
+    if let Ok(date) = Date::parse(s, format_description!("[year]-[month]-[day]")) {
+        Ok(date.midnight().assume_utc())
+    } else {
+        OffsetDateTime::parse(s, &Rfc3339).map_err(|_| Error::InvalidDate { spec: s.to_owned() })
+    }
+}
+
+/// Parse a percentage like `5%` or `0.5%` into a fraction between 0 and 1.
+pub fn parse_percentage(s: &str) -> Result<f64> {
+    let invalid = || Error::InvalidPercentage { spec: s.to_owned() };
+    let number: f64 = s
+        .trim()
+        .strip_suffix('%')
+        .ok_or_else(invalid)?
+        .trim_end()
+        .parse()
+        .map_err(|_| invalid())?;
+    if (0.0..=100.0).contains(&number) {
+        Ok(number / 100.0)
+    } else {
+        Err(invalid())
+    }
+}
+
 pub fn duration_to_hms(d: Duration) -> String {
     let elapsed_secs = d.as_secs();
     if elapsed_secs >= 3600 {
@@ -87,6 +167,70 @@ pub(crate) fn compression_ratio(s: &Sizes) -> f64 {
 mod tests {
     use super::*;
 
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("1500").unwrap(), 1500);
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("10k").unwrap(), 10_000);
+        assert_eq!(parse_size("1GB").unwrap(), 1_000_000_000);
+        assert_eq!(parse_size("2 MB").unwrap(), 2_000_000);
+        assert_eq!(parse_size("4GiB").unwrap(), 4 << 30);
+        assert_eq!(parse_size("1tib").unwrap(), 1 << 40);
+        assert!(parse_size("").is_err());
+        assert!(parse_size("GB").is_err());
+        assert!(parse_size("1.5GB").is_err());
+        assert!(parse_size("12 parsecs").is_err());
+        assert!(parse_size("99999999999TB").is_err());
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("15m").unwrap(), Duration::from_secs(900));
+        assert_eq!(
+            parse_duration("12h").unwrap(),
+            Duration::from_secs(12 * 3600)
+        );
+        assert_eq!(
+            parse_duration("90d").unwrap(),
+            Duration::from_secs(90 * 86400)
+        );
+        assert_eq!(
+            parse_duration("2w").unwrap(),
+            Duration::from_secs(14 * 86400)
+        );
+        assert!(parse_duration("90").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("1y").is_err());
+    }
+
+    #[test]
+    fn parse_dates() {
+        assert_eq!(
+            parse_date("2024-01-31").unwrap(),
+            time::macros::datetime!(2024-01-31 00:00 UTC)
+        );
+        assert_eq!(
+            parse_date("2024-01-31T12:00:00+10:00").unwrap(),
+            time::macros::datetime!(2024-01-31 02:00 UTC)
+        );
+        assert!(parse_date("yesterday").is_err());
+        assert!(parse_date("2024-13-01").is_err());
+    }
+
+    #[test]
+    fn parse_percentages() {
+        assert_eq!(parse_percentage("5%").unwrap(), 0.05);
+        assert_eq!(parse_percentage("100%").unwrap(), 1.0);
+        assert_eq!(parse_percentage("0.5 %").unwrap(), 0.005);
+        assert_eq!(parse_percentage("0%").unwrap(), 0.0);
+        assert!(parse_percentage("5").is_err());
+        assert!(parse_percentage("101%").is_err());
+        assert!(parse_percentage("-1%").is_err());
+        assert!(parse_percentage("NaN%").is_err());
+        assert!(parse_percentage("%").is_err());
+    }
+
     #[test]
     pub fn test_compression_ratio() {
         let ratio = compression_ratio(&Sizes {
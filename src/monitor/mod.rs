@@ -7,6 +7,8 @@ pub mod test;
 
 use self::task::Task;
 use crate::counters::Counter;
+use crate::restore::RestoreConflict;
+use crate::{Apath, Error, Kind, Result};
 
 /// A monitor receives events from the library and may collect them, report them
 /// to the terminal, log them, etc.
@@ -18,7 +20,55 @@ pub trait Monitor: Send + Sync + 'static {
     fn set_counter(&self, counter: Counter, value: usize);
 
     /// A non-fatal error occurred.
-    fn error(&self, error: crate::Error);
+    fn error(&self, error: Error);
+
+    /// An entry being restored conflicted with something already in the destination.
+    fn restore_conflict(&self, conflict: RestoreConflict);
 
     fn start_task(&self, name: String) -> Task;
+
+    /// Backup or restore started processing an entry.
+    ///
+    /// This and [Monitor::entry_finished] let a frontend show a log of each entry;
+    /// by default they do nothing.
+    fn entry_started(&self, _apath: &Apath, _kind: Kind) {}
+
+    /// Backup or restore finished processing an entry.
+    ///
+    /// `bytes` is the length of the file content that was copied, which is zero for
+    /// other kinds of entry, and for files that weren't written.
+    fn entry_finished(&self, _apath: &Apath, _kind: Kind, _outcome: EntryOutcome, _bytes: u64) {}
+
+    /// True if the operation should stop as soon as it safely can.
+    ///
+    /// This is checked between entries, blocks, and bands by backup, restore,
+    /// validate, and gc, which then return [Error::Cancelled]. A cancelled backup
+    /// leaves an incomplete band, as if it had been interrupted.
+    fn should_cancel(&self) -> bool {
+        false
+    }
+}
+
+/// Return [Error::Cancelled] if the monitor asks for the operation to stop.
+pub(crate) fn check_cancel(monitor: &dyn Monitor) -> Result<()> {
+    if monitor.should_cancel() {
+        Err(Error::Cancelled)
+    } else {
+        Ok(())
+    }
+}
+
+/// What happened to an entry, reported to [Monitor::entry_finished].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum EntryOutcome {
+    /// The entry was stored in the backup, or restored.
+    Written,
+    /// The entry was the same in the basis backup, or in the restore destination,
+    /// so its content wasn't copied again.
+    Unchanged,
+    /// The entry wasn't stored or restored: either it's of an unknown kind, or an
+    /// existing entry in the restore destination was kept.
+    Skipped,
+    /// An error about the entry was reported to [Monitor::error].
+    Failed,
 }
@@ -90,11 +90,9 @@ impl TaskState {
 
     pub fn percent(&self) -> usize {
         let total = self.total.load(Relaxed);
-        if total == 0 {
-            0
-        } else {
-            self.done.load(Relaxed) * 100 / total
-        }
+        (self.done.load(Relaxed) * 100)
+            .checked_div(total)
+            .unwrap_or(0)
     }
 }
 
@@ -3,17 +3,18 @@
 //! Collect monitored information so that it can be inspected by tests.
 
 use std::mem::take;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
 use super::task::{Task, TaskList};
-use super::Monitor;
+use super::{EntryOutcome, Monitor};
 use crate::counters::{Counter, Counters};
-use crate::{Apath, Error};
+use crate::{Apath, Error, Kind, RestoreConflict};
 
 /// A monitor that collects information for later inspection,
 /// particularly from tests.
 ///
-/// Errors are collected in a vector.
+/// Errors, restore conflicts, and finished entries are collected in vectors.
 ///
 /// Tasks are ignored.
 ///
@@ -21,9 +22,12 @@ use crate::{Apath, Error};
 #[derive(Default)]
 pub struct TestMonitor {
     errors: Mutex<Vec<Error>>,
+    conflicts: Mutex<Vec<RestoreConflict>>,
     counters: Counters,
     started_files: Mutex<Vec<Apath>>,
+    finished_entries: Mutex<Vec<(Apath, EntryOutcome, u64)>>,
     task_list: Mutex<TaskList>,
+    cancelled: AtomicBool,
 }
 
 impl TestMonitor {
@@ -45,6 +49,11 @@ impl TestMonitor {
         take(self.errors.lock().unwrap().as_mut())
     }
 
+    /// Return the list of restore conflicts, and clear it.
+    pub fn take_conflicts(&self) -> Vec<RestoreConflict> {
+        take(self.conflicts.lock().unwrap().as_mut())
+    }
+
     /// Assert that no errors have yet occurred (since the list was cleared.)
     ///
     /// Panic if any errors have been reported.
@@ -66,6 +75,16 @@ impl TestMonitor {
         take(self.started_files.lock().unwrap().as_mut())
     }
 
+    /// Return the apath, outcome, and bytes of each finished entry, and clear the list.
+    pub fn take_finished_entries(&self) -> Vec<(Apath, EntryOutcome, u64)> {
+        take(self.finished_entries.lock().unwrap().as_mut())
+    }
+
+    /// Ask the operation using this monitor to stop.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
     pub fn counters(&self) -> &Counters {
         &self.counters
     }
@@ -84,7 +103,28 @@ impl Monitor for TestMonitor {
         self.errors.lock().unwrap().push(error);
     }
 
+    fn restore_conflict(&self, conflict: RestoreConflict) {
+        self.conflicts.lock().unwrap().push(conflict);
+    }
+
     fn start_task(&self, name: String) -> Task {
         self.task_list.lock().unwrap().start_task(name)
     }
+
+    fn should_cancel(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn entry_started(&self, apath: &Apath, kind: Kind) {
+        if kind == Kind::File {
+            self.started_files.lock().unwrap().push(apath.clone());
+        }
+    }
+
+    fn entry_finished(&self, apath: &Apath, _kind: Kind, outcome: EntryOutcome, bytes: u64) {
+        self.finished_entries
+            .lock()
+            .unwrap()
+            .push((apath.clone(), outcome, bytes));
+    }
 }
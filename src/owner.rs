@@ -16,6 +16,13 @@
 //! There is potentially a more efficient way to do this, but this approach works
 //! better than just saving the uid and gid, so that backups may potentially
 //! be restored on a different system.
+//!
+//! On Unix, the numeric uid and gid are stored too, so that restores can use them
+//! when the names don't exist on the restoring system, or when asked to restore
+//! numeric ids.
+//!
+//! Owners can be changed on restore by an [OwnerMap], for example to restore
+//! another machine's files under local ids.
 
 use std::fmt::Display;
 use std::io;
@@ -23,54 +30,271 @@ use std::path::Path;
 
 use serde::{Deserialize, Serialize};
 
+use crate::{Error, Result};
+
 #[cfg(unix)]
 mod unix;
 #[cfg(unix)]
 use unix::set_owner;
+#[cfg(unix)]
+pub(crate) use unix::{current_user_name, host_name, running_as_root};
 
 #[cfg(windows)]
 mod windows;
 #[cfg(windows)]
 use windows::set_owner;
+#[cfg(windows)]
+pub(crate) use windows::{current_user_name, host_name, running_as_root};
 
 #[derive(Default, Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Owner {
     // TODO: Maybe the strings can be 'static references to the cache?
     pub user: Option<String>,
     pub group: Option<String>,
+    /// Numeric user id, on Unix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    /// Numeric group id, on Unix.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
 }
 
 impl Owner {
     pub fn is_none(&self) -> bool {
-        self.user.is_none() && self.group.is_none()
+        self.user.is_none() && self.group.is_none() && self.uid.is_none() && self.gid.is_none()
     }
 
+    /// Set the owner of a file.
+    ///
+    /// Users and groups are looked up by name, falling back to the numeric id if the
+    /// name doesn't exist on this system. If there's no owner, the file is unchanged.
     pub fn set_owner<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        if self.is_none() {
+            return Ok(());
+        }
         set_owner(self, path.as_ref())
     }
 
     pub fn clear(&mut self) {
-        self.user = None;
-        self.group = None;
+        *self = Owner::default();
+    }
+
+    /// Return an owner that is set by numeric id, ignoring the names of users and
+    /// groups that have ids.
+    ///
+    /// Names are kept if there is no id, as in archives written before ids were recorded.
+    pub fn numeric(&self) -> Owner {
+        Owner {
+            user: self.user.clone().filter(|_| self.uid.is_none()),
+            group: self.group.clone().filter(|_| self.gid.is_none()),
+            uid: self.uid,
+            gid: self.gid,
+        }
+    }
+
+    /// True if this describes the same owner as another, ignoring ids that are
+    /// missing from either, such as in archives written before ids were recorded.
+    pub fn matches(&self, other: &Owner) -> bool {
+        fn same_id(a: Option<u32>, b: Option<u32>) -> bool {
+            a.is_none() || b.is_none() || a == b
+        }
+        self.user == other.user
+            && self.group == other.group
+            && same_id(self.uid, other.uid)
+            && same_id(self.gid, other.gid)
+    }
+}
+
+/// A user or group, identified by name or numeric id.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum NameOrId {
+    Name(String),
+    Id(u32),
+}
+
+impl NameOrId {
+    fn parse(s: &str) -> Option<NameOrId> {
+        if s.is_empty() {
+            None
+        } else if let Ok(id) = s.parse() {
+            Some(NameOrId::Id(id))
+        } else {
+            Some(NameOrId::Name(s.to_owned()))
+        }
+    }
+
+    fn matches(&self, name: &Option<String>, id: Option<u32>) -> bool {
+        match self {
+            NameOrId::Name(n) => name.as_ref() == Some(n),
+            NameOrId::Id(i) => id == Some(*i),
+        }
+    }
+
+    fn into_name_and_id(self) -> (Option<String>, Option<u32>) {
+        match self {
+            NameOrId::Name(name) => (Some(name), None),
+            NameOrId::Id(id) => (None, Some(id)),
+        }
+    }
+}
+
+/// Changes the owners of restored files, replacing some users and groups by others.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct OwnerMap {
+    users: Vec<(NameOrId, NameOrId)>,
+    groups: Vec<(NameOrId, NameOrId)>,
+}
+
+impl OwnerMap {
+    /// Make a map from mappings like `"1000:2000"` or `"alice:bob"`, each from a
+    /// recorded user or group to the one to restore instead.
+    ///
+    /// Each side is a numeric id if it's a number, and otherwise a name.
+    pub fn from_strings<U, G, S>(users: U, groups: G) -> Result<OwnerMap>
+    where
+        U: IntoIterator<Item = S>,
+        G: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        fn parse_all<S: AsRef<str>>(
+            mappings: impl IntoIterator<Item = S>,
+        ) -> Result<Vec<(NameOrId, NameOrId)>> {
+            mappings
+                .into_iter()
+                .map(|mapping| {
+                    let mapping = mapping.as_ref();
+                    mapping
+                        .split_once(':')
+                        .and_then(|(from, to)| Some((NameOrId::parse(from)?, NameOrId::parse(to)?)))
+                        .ok_or_else(|| Error::InvalidOwnerMapping {
+                            mapping: mapping.to_owned(),
+                        })
+                })
+                .collect()
+        }
+        Ok(OwnerMap {
+            users: parse_all(users)?,
+            groups: parse_all(groups)?,
+        })
+    }
+
+    /// True if this map changes nothing.
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.groups.is_empty()
+    }
+
+    /// Return the owner to restore in place of a recorded owner.
+    ///
+    /// The first mapping that matches the user's name or id replaces both, and
+    /// likewise for the group.
+    pub fn map(&self, owner: &Owner) -> Owner {
+        let mut mapped = owner.clone();
+        if let Some((_, to)) = self
+            .users
+            .iter()
+            .find(|(from, _)| from.matches(&owner.user, owner.uid))
+        {
+            (mapped.user, mapped.uid) = to.clone().into_name_and_id();
+        }
+        if let Some((_, to)) = self
+            .groups
+            .iter()
+            .find(|(from, _)| from.matches(&owner.group, owner.gid))
+        {
+            (mapped.group, mapped.gid) = to.clone().into_name_and_id();
+        }
+        mapped
     }
 }
 
 impl Display for Owner {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let none = "none".to_string();
+        let name_or_id = |name: &Option<String>, id: Option<u32>| match (name, id) {
+            (Some(name), _) => name.clone(),
+            (None, Some(id)) => id.to_string(),
+            (None, None) => "none".to_string(),
+        };
         write!(
             f,
             "{:<10} {:<10}",
-            if let Some(user) = &self.user {
-                user
-            } else {
-                &none
-            },
-            if let Some(group) = &self.group {
-                group
-            } else {
-                &none
-            }
+            name_or_id(&self.user, self.uid),
+            name_or_id(&self.group, self.gid),
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn owner(user: Option<&str>, uid: Option<u32>) -> Owner {
+        Owner {
+            user: user.map(String::from),
+            group: Some("staff".to_owned()),
+            uid,
+            gid: Some(50),
+        }
+    }
+
+    #[test]
+    fn numeric_owner_drops_names_that_have_ids() {
+        assert_eq!(
+            owner(Some("alice"), Some(1000)).numeric(),
+            Owner {
+                user: None,
+                group: None,
+                uid: Some(1000),
+                gid: Some(50),
+            }
+        );
+        // Without an id, the name is all there is.
+        assert_eq!(
+            owner(Some("alice"), None).numeric().user.as_deref(),
+            Some("alice")
+        );
+    }
+
+    #[test]
+    fn matches_ignores_missing_ids() {
+        assert!(owner(Some("alice"), Some(1000)).matches(&owner(Some("alice"), None)));
+        assert!(!owner(Some("alice"), Some(1000)).matches(&owner(Some("alice"), Some(1001))));
+        assert!(!owner(Some("alice"), Some(1000)).matches(&owner(Some("bob"), Some(1000))));
+    }
+
+    #[test]
+    fn map_users_and_groups() {
+        let map = OwnerMap::from_strings(["1000:2000", "bob:carol"], ["staff:100"]).unwrap();
+        assert_eq!(
+            map.map(&owner(Some("alice"), Some(1000))),
+            Owner {
+                user: None,
+                group: None,
+                uid: Some(2000),
+                gid: Some(100),
+            }
+        );
+        let mapped = map.map(&owner(Some("bob"), Some(1001)));
+        assert_eq!(mapped.user.as_deref(), Some("carol"));
+        assert_eq!(mapped.uid, None);
+        // Unmapped users are unchanged.
+        let unmapped = owner(Some("dave"), Some(1002));
+        assert_eq!(map.map(&unmapped).user, unmapped.user);
+        assert_eq!(map.map(&unmapped).uid, unmapped.uid);
+    }
+
+    #[test]
+    fn invalid_owner_mappings() {
+        for mapping in ["1000", "1000:", ":2000", ""] {
+            assert!(
+                OwnerMap::from_strings([mapping], []).is_err(),
+                "{mapping:?} should be invalid"
+            );
+        }
+    }
+
+    #[test]
+    fn display_falls_back_to_ids() {
+        assert_eq!(owner(None, Some(1000)).to_string(), "1000       staff     ");
+        assert_eq!(Owner::default().to_string(), "none       none      ");
+    }
+}
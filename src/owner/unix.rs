@@ -37,10 +37,36 @@ impl From<&fs::Metadata> for Owner {
         let group: Option<String> = users_cache
             .get_group_by_gid(mdata.gid())
             .and_then(|group| group.name().to_str().map(String::from));
-        Self { user, group }
+        Self {
+            user,
+            group,
+            uid: Some(mdata.uid()),
+            gid: Some(mdata.gid()),
+        }
     }
 }
 
+/// The name of the user running this process, if it can be found.
+pub(crate) fn current_user_name() -> Option<String> {
+    USERS_CACHE
+        .lock()
+        .unwrap()
+        .get_current_username()
+        .and_then(|name| name.to_str().map(String::from))
+}
+
+/// The name of this host, if it can be found.
+pub(crate) fn host_name() -> Option<String> {
+    nix::unistd::gethostname()
+        .ok()
+        .and_then(|name| name.into_string().ok())
+}
+
+/// True if this process runs as root, and so can set the owner of files.
+pub(crate) fn running_as_root() -> bool {
+    nix::unistd::geteuid().is_root()
+}
+
 #[mutants::skip] // TODO: Difficult to test as non-root but we could at least test that at least groups are restored!
 pub(crate) fn set_owner(owner: &Owner, path: &Path) -> io::Result<()> {
     let users_cache = USERS_CACHE.lock().unwrap();
@@ -48,12 +74,14 @@ pub(crate) fn set_owner(owner: &Owner, path: &Path) -> io::Result<()> {
         .user
         .as_ref()
         .and_then(|user| users_cache.get_user_by_name(&user))
-        .map(|user| user.uid());
+        .map(|user| user.uid())
+        .or(owner.uid);
     let gid_opt = owner
         .group
         .as_ref()
         .and_then(|group| users_cache.get_group_by_name(&group))
-        .map(|group| group.gid());
+        .map(|group| group.gid())
+        .or(owner.gid);
     drop(users_cache);
     // TODO: use `std::os::unix::fs::chown(path, uid, gid)?;` once stable
     match lchown(path, uid_opt, gid_opt) {
@@ -24,13 +24,22 @@ use crate::Result;
 impl From<&Metadata> for Owner {
     fn from(_: &Metadata) -> Self {
         // TODO: Implement Windows user/group functionality
-        Self {
-            user: None,
-            group: None,
-        }
+        Self::default()
     }
 }
 
+pub(crate) fn current_user_name() -> Option<String> {
+    std::env::var("USERNAME").ok()
+}
+
+pub(crate) fn host_name() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+pub(crate) fn running_as_root() -> bool {
+    false
+}
+
 pub fn set_owner(_owner: &Owner, _path: &Path) -> io::Result<()> {
     Ok(())
 }
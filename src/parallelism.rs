@@ -0,0 +1,195 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Limits on the threads, concurrent transport operations, and memory used by
+//! backup, restore, and validate.
+
+use std::sync::{Condvar, Mutex};
+
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use tracing::{warn, Span};
+
+/// How much work backup, restore, and validate may do at once.
+///
+/// The defaults suit a local archive on a machine that's otherwise idle: a thread per
+/// core, as configured for the global rayon pool, and a moderate amount of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Parallelism {
+    /// Threads that hash and compress new blocks during backup, and that check bands
+    /// and hash blocks during validation.
+    pub threads: usize,
+
+    /// Blocks that may be written to the archive at once during backup, or read
+    /// ahead of where they're needed during restore.
+    ///
+    /// Archives on remote storage may benefit from more concurrent requests than
+    /// there are threads.
+    pub transport_concurrency: usize,
+
+    /// Bytes of block content that may be held in memory, during backup after it's
+    /// read from source files and before it's stored, or during restore after it's
+    /// read from the archive and before it's written out.
+    ///
+    /// At least one block is always allowed, however large.
+    pub max_buffered_bytes: u64,
+}
+
+impl Default for Parallelism {
+    fn default() -> Self {
+        let threads = rayon::current_num_threads();
+        Parallelism {
+            threads,
+            transport_concurrency: threads,
+            max_buffered_bytes: 256 << 20,
+        }
+    }
+}
+
+impl Parallelism {
+    /// Use this many threads to hash and compress blocks.
+    pub fn with_threads(self, threads: usize) -> Self {
+        Parallelism { threads, ..self }
+    }
+
+    /// Read or write this many blocks at once.
+    pub fn with_transport_concurrency(self, transport_concurrency: usize) -> Self {
+        Parallelism {
+            transport_concurrency,
+            ..self
+        }
+    }
+
+    /// Hold at most this many bytes of blocks in memory.
+    pub fn with_max_buffered_bytes(self, max_buffered_bytes: u64) -> Self {
+        Parallelism {
+            max_buffered_bytes,
+            ..self
+        }
+    }
+
+    /// Run `op` on a rayon pool of [Parallelism::threads] threads, so that parallel
+    /// iterators within it use that many.
+    ///
+    /// If the current pool already has that many threads, `op` is run directly.
+    pub(crate) fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        if self.threads.max(1) == rayon::current_num_threads() {
+            return op();
+        }
+        match build_pool(self.threads.max(1), "conserve-worker") {
+            Some(pool) => {
+                // Pool threads don't inherit the caller's span.
+                let span = Span::current();
+                pool.install(|| span.in_scope(op))
+            }
+            None => op(),
+        }
+    }
+}
+
+/// Start a pool of `threads` threads, or return None if there's only one thread, in
+/// which case work should be done on the caller's thread.
+///
+/// If the threads can't be started, a warning is logged and None is returned.
+pub(crate) fn thread_pool(threads: usize, name: &'static str) -> Option<ThreadPool> {
+    if threads <= 1 {
+        return None;
+    }
+    build_pool(threads, name)
+}
+
+fn build_pool(threads: usize, name: &'static str) -> Option<ThreadPool> {
+    ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(move |i| format!("{name}-{i}"))
+        .build()
+        .map_err(|err| warn!(?err, "Failed to start {name} threads"))
+        .ok()
+}
+
+/// A counting semaphore, limiting how many threads do something at once.
+#[derive(Debug)]
+pub(crate) struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Permission to proceed from a [Semaphore], returned to it when dropped.
+pub(crate) struct Permit<'s>(&'s Semaphore);
+
+impl Semaphore {
+    pub(crate) fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            available: Mutex::new(permits.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait until a permit is available, and take it.
+    pub(crate) fn acquire(&self) -> Permit<'_> {
+        let mut available = self
+            .released
+            .wait_while(self.available.lock().unwrap(), |available| *available == 0)
+            .unwrap();
+        *available -= 1;
+        Permit(self)
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().unwrap() += 1;
+        self.0.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn semaphore_limits_concurrency() {
+        let semaphore = Arc::new(Semaphore::new(2));
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let threads = (0..6)
+            .map(|_| {
+                let (semaphore, running, max_running) =
+                    (semaphore.clone(), running.clone(), max_running.clone());
+                thread::spawn(move || {
+                    let _permit = semaphore.acquire();
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert!(max_running.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn install_uses_the_requested_threads() {
+        for threads in [1, 3] {
+            let parallelism = Parallelism::default().with_threads(threads);
+            assert_eq!(parallelism.install(rayon::current_num_threads), threads);
+        }
+    }
+}
@@ -0,0 +1,433 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Delete old backups according to a retention policy.
+//!
+//! Like the familiar grandfather-father-son schemes, the newest complete backup
+//! from each of the most recent days, weeks, and months that have any backups is
+//! kept, and other backups are deleted. Periods are based on the start time of
+//! each backup. Alternatively, or as well, a number of the newest complete backups,
+//! or every backup newer than a given age, can be kept.
+//!
+//! Backups that were given their own expiry time, with
+//! [BackupOptions::expire_after], are instead kept until they expire and then
+//! deleted, and don't count towards the retention policy.
+//!
+//! Incomplete backups are never kept by these rules: they're deleted if a newer
+//! backup completed, and otherwise left alone, since they might still be running.
+//! Backups that validation found to be damaged are treated the same way, so they
+//! never take the place of a good backup.
+//!
+//! Held backups are never deleted, whatever the retention policy.
+
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use time::{OffsetDateTime, UtcOffset};
+use tracing::{info, warn};
+
+use crate::monitor::Monitor;
+use crate::*;
+
+/// Options for [prune].
+#[derive(Debug, Default, Clone)]
+pub struct PruneOptions {
+    /// Keep this many of the newest complete backups.
+    pub keep_last: usize,
+    /// Keep the newest complete backup from each of this many most recent days.
+    pub keep_daily: usize,
+    /// Keep the newest complete backup from each of this many most recent ISO weeks.
+    pub keep_weekly: usize,
+    /// Keep the newest complete backup from each of this many most recent months.
+    pub keep_monthly: usize,
+    /// Keep every complete backup started less than this long ago: with no other
+    /// rules, older backups are deleted.
+    pub delete_older_than: Option<Duration>,
+    /// Count days, weeks, and months in this timezone; by default, UTC.
+    pub timezone: Option<UtcOffset>,
+    /// Report what would be deleted, without changing the archive.
+    pub dry_run: bool,
+    /// Break a lock left behind by a previous interrupted gc operation.
+    pub break_lock: bool,
+}
+
+/// Maps a time to the day, week, or month it falls in, as a year and a number within it.
+type Period = fn(OffsetDateTime) -> (i32, u16);
+
+/// What's known about a band when deciding whether to keep it.
+#[derive(Debug, Clone, Copy)]
+struct BandSummary {
+    band_id: BandId,
+    start_time: OffsetDateTime,
+    is_closed: bool,
+    /// True if the band is in the archive's list of damaged bands.
+    is_damaged: bool,
+    is_held: bool,
+    expires: Option<OffsetDateTime>,
+}
+
+impl BandSummary {
+    /// True if the band is finished and undamaged, so it can be kept by the retention rules.
+    fn is_complete(&self) -> bool {
+        self.is_closed && !self.is_damaged
+    }
+}
+
+/// Choose which bands should be deleted according to the retention policy.
+///
+/// The newest complete band is always kept. Bands whose metadata can't be read
+/// are kept, with a warning.
+pub fn select_bands_to_prune(archive: &Archive, options: &PruneOptions) -> Result<Vec<BandId>> {
+    let damaged = archive.damaged_bands().unwrap_or_else(|err| {
+        warn!("Failed to read the list of damaged bands: {err}");
+        Default::default()
+    });
+    let mut bands = Vec::new();
+    for band_id in archive.list_band_ids()? {
+        match Band::open(archive, band_id).and_then(|band| band.get_info()) {
+            Ok(info) => bands.push(BandSummary {
+                band_id,
+                start_time: info.start_time,
+                is_closed: info.is_closed,
+                is_damaged: damaged.contains_key(&band_id),
+                is_held: info.held,
+                expires: info.expires,
+            }),
+            Err(err) => warn!(%band_id, "Keeping band whose metadata can't be read: {err}"),
+        }
+    }
+    Ok(choose_bands_to_delete(
+        &bands,
+        options,
+        OffsetDateTime::now_utc(),
+    ))
+}
+
+/// Delete the bands that aren't kept by the retention policy, and then any blocks
+/// that are no longer referenced.
+pub fn prune(
+    archive: &Archive,
+    options: &PruneOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<DeleteStats> {
+    let delete_band_ids = select_bands_to_prune(archive, options)?;
+    for band_id in &delete_band_ids {
+        info!(%band_id, "Prune band");
+    }
+    archive.delete_bands(
+        &delete_band_ids,
+        &DeleteOptions {
+            dry_run: options.dry_run,
+            break_lock: options.break_lock,
+            ..Default::default()
+        },
+        monitor,
+    )
+}
+
+fn choose_bands_to_delete(
+    bands: &[BandSummary],
+    options: &PruneOptions,
+    now: OffsetDateTime,
+) -> Vec<BandId> {
+    let timezone = options.timezone.unwrap_or(UtcOffset::UTC);
+    let mut complete: Vec<&BandSummary> = bands.iter().filter(|band| band.is_complete()).collect();
+    complete.sort_by_key(|band| std::cmp::Reverse(band.band_id));
+    let Some(newest_complete) = complete.first().map(|band| band.band_id) else {
+        return Vec::new();
+    };
+    let mut keep = BTreeSet::from([newest_complete]);
+    keep.extend(
+        complete
+            .iter()
+            .filter(|band| band.expires.is_none())
+            .take(options.keep_last)
+            .map(|band| band.band_id),
+    );
+    // For each rule, the number of periods to keep, and the period a time falls in.
+    let rules: [(usize, Period); 3] = [
+        (options.keep_daily, |time| (time.year(), time.ordinal())),
+        (options.keep_weekly, |time| {
+            let (year, week, _) = time.to_iso_week_date();
+            (year, week.into())
+        }),
+        (options.keep_monthly, |time| {
+            (time.year(), u8::from(time.month()).into())
+        }),
+    ];
+    if let Some(age) = options.delete_older_than {
+        // If the cutoff is before the earliest representable time, nothing is older.
+        let cutoff = time::Duration::try_from(age)
+            .ok()
+            .and_then(|age| now.checked_sub(age));
+        keep.extend(
+            complete
+                .iter()
+                .filter(|band| band.expires.is_none())
+                .filter(|band| cutoff.map_or(true, |cutoff| band.start_time >= cutoff))
+                .map(|band| band.band_id),
+        );
+    }
+    for (count, period) in rules {
+        let mut last_period = None;
+        let mut kept = 0;
+        for band in complete.iter().filter(|band| band.expires.is_none()) {
+            if kept >= count {
+                break;
+            }
+            let this_period = period(band.start_time.to_offset(timezone));
+            if last_period != Some(this_period) {
+                keep.insert(band.band_id);
+                kept += 1;
+                last_period = Some(this_period);
+            }
+        }
+    }
+    bands
+        .iter()
+        .filter(|band| {
+            if keep.contains(&band.band_id) || band.is_held {
+                false
+            } else if band.is_complete() {
+                band.expires.map_or(true, |expires| expires <= now)
+            } else {
+                band.band_id < newest_complete
+            }
+        })
+        .map(|band| band.band_id)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use time::macros::datetime;
+    use time::Duration;
+
+    use super::*;
+
+    /// After all the bands in these tests were made.
+    const NOW: OffsetDateTime = datetime!(2024-06-01 00:00 UTC);
+
+    fn band(n: u32, start_time: OffsetDateTime) -> BandSummary {
+        BandSummary {
+            band_id: BandId::new(&[n]),
+            start_time,
+            is_closed: true,
+            is_damaged: false,
+            is_held: false,
+            expires: None,
+        }
+    }
+
+    fn ids(ns: &[u32]) -> Vec<BandId> {
+        ns.iter().map(|&n| BandId::new(&[n])).collect()
+    }
+
+    /// One band per day at noon, for `days` days, starting on Monday 2024-01-01.
+    fn daily_bands(days: u32) -> Vec<BandSummary> {
+        (0..days)
+            .map(|n| {
+                band(
+                    n,
+                    datetime!(2024-01-01 12:00 UTC) + Duration::days(n.into()),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keep_last() {
+        let options = PruneOptions {
+            keep_last: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            choose_bands_to_delete(&daily_bands(5), &options, NOW),
+            ids(&[0, 1])
+        );
+    }
+
+    #[test]
+    fn keep_last_counts_only_complete_undamaged_bands() {
+        let mut bands = daily_bands(6);
+        bands[3].is_damaged = true;
+        bands[4].is_closed = false;
+        let options = PruneOptions {
+            keep_last: 2,
+            ..Default::default()
+        };
+        // Bands 5 and 2 are kept; the damaged and incomplete bands are older than
+        // the newest complete band, so they're deleted.
+        assert_eq!(
+            choose_bands_to_delete(&bands, &options, NOW),
+            ids(&[0, 1, 3, 4])
+        );
+
+        // A damaged band is never the newest complete band that's always kept, and
+        // the damaged and incomplete bands after band 2 are left alone.
+        bands[5].is_damaged = true;
+        assert_eq!(choose_bands_to_delete(&bands, &options, NOW), ids(&[0]));
+    }
+
+    #[test]
+    fn held_bands_are_never_deleted() {
+        let mut bands = daily_bands(5);
+        bands[0].is_held = true;
+        bands[1].is_held = true;
+        bands[1].expires = Some(NOW - Duration::days(1));
+        bands[2].is_held = true;
+        bands[2].is_closed = false;
+        let options = PruneOptions {
+            keep_last: 1,
+            ..Default::default()
+        };
+        // Held bands are kept even if they're expired, incomplete, or not kept by the rules.
+        assert_eq!(choose_bands_to_delete(&bands, &options, NOW), ids(&[3]));
+    }
+
+    #[test]
+    fn keep_daily() {
+        let options = PruneOptions {
+            keep_daily: 3,
+            ..Default::default()
+        };
+        assert_eq!(
+            choose_bands_to_delete(&daily_bands(10), &options, NOW),
+            ids(&[0, 1, 2, 3, 4, 5, 6])
+        );
+    }
+
+    #[test]
+    fn keep_newest_band_of_each_day() {
+        let bands = [
+            band(0, datetime!(2024-01-01 09:00 UTC)),
+            band(1, datetime!(2024-01-01 17:00 UTC)),
+            band(2, datetime!(2024-01-02 09:00 UTC)),
+            band(3, datetime!(2024-01-02 17:00 UTC)),
+        ];
+        let options = PruneOptions {
+            keep_daily: 7,
+            ..Default::default()
+        };
+        assert_eq!(choose_bands_to_delete(&bands, &options, NOW), ids(&[0, 2]));
+
+        // Days are counted in the given timezone: in UTC+10, bands 1 and 2 are
+        // both on 2024-01-02.
+        let options = PruneOptions {
+            keep_daily: 7,
+            timezone: Some(UtcOffset::from_hms(10, 0, 0).unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(choose_bands_to_delete(&bands, &options, NOW), ids(&[1]));
+    }
+
+    #[test]
+    fn keep_daily_weekly_and_monthly() {
+        // 2024-01-01 is a Monday; bands run to Saturday 2024-03-30.
+        let options = PruneOptions {
+            keep_daily: 2,
+            keep_weekly: 2,
+            keep_monthly: 3,
+            ..Default::default()
+        };
+        let deleted = choose_bands_to_delete(&daily_bands(90), &options, NOW);
+        let kept: Vec<u32> = (0..90)
+            .filter(|n| !deleted.contains(&BandId::new(&[*n])))
+            .collect();
+        // The last two days; the Sunday ending the previous week; and the last day
+        // of January and February.
+        assert_eq!(kept, [30, 59, 83, 88, 89]);
+    }
+
+    #[test]
+    fn delete_older_than() {
+        let bands = daily_bands(10);
+        let now = datetime!(2024-01-10 18:00 UTC);
+        let options = PruneOptions {
+            delete_older_than: Some(std::time::Duration::from_secs(3 * 86400)),
+            ..Default::default()
+        };
+        // Bands from noon on the 8th onwards are within three days.
+        assert_eq!(
+            choose_bands_to_delete(&bands, &options, now),
+            ids(&[0, 1, 2, 3, 4, 5, 6])
+        );
+
+        // The newest complete band is kept however old it is.
+        assert_eq!(
+            choose_bands_to_delete(&bands, &options, NOW),
+            ids(&[0, 1, 2, 3, 4, 5, 6, 7, 8])
+        );
+
+        // Other rules can keep older bands too.
+        let options = PruneOptions {
+            keep_weekly: 2,
+            ..options
+        };
+        assert_eq!(
+            choose_bands_to_delete(&bands, &options, now),
+            ids(&[0, 1, 2, 3, 4, 5])
+        );
+    }
+
+    #[test]
+    fn newest_complete_band_is_always_kept() {
+        assert_eq!(
+            choose_bands_to_delete(&daily_bands(3), &PruneOptions::default(), NOW),
+            ids(&[0, 1])
+        );
+    }
+
+    #[test]
+    fn incomplete_bands() {
+        let mut bands = daily_bands(4);
+        bands[1].is_closed = false;
+        bands[3].is_closed = false;
+        let options = PruneOptions {
+            keep_daily: 10,
+            ..Default::default()
+        };
+        // The older incomplete band was abandoned, but the newest might still be running.
+        assert_eq!(choose_bands_to_delete(&bands, &options, NOW), ids(&[1]));
+
+        for band in &mut bands {
+            band.is_closed = false;
+        }
+        assert_eq!(choose_bands_to_delete(&bands, &options, NOW), []);
+    }
+
+    #[test]
+    fn expiring_bands() {
+        let mut bands = daily_bands(10);
+        // Bands 2 and 4 were made by another job, with their own expiry.
+        bands[2].expires = Some(NOW - Duration::days(1));
+        bands[4].expires = Some(NOW + Duration::days(1));
+        let options = PruneOptions {
+            keep_daily: 3,
+            ..Default::default()
+        };
+        // The expired band is deleted; the unexpired band is kept; and the
+        // expiring bands don't take up any of the three daily slots.
+        assert_eq!(
+            choose_bands_to_delete(&bands, &options, NOW),
+            ids(&[0, 1, 2, 3, 5, 6])
+        );
+
+        // The newest complete band is kept even if it's expired.
+        bands[9].expires = Some(NOW - Duration::days(1));
+        assert_eq!(
+            choose_bands_to_delete(&bands, &options, NOW),
+            ids(&[0, 1, 2, 3, 5])
+        );
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Rewrite existing blocks with different compression.
+//!
+//! Blocks are named by the hash of their uncompressed content, so they keep the
+//! same names and the indexes don't need to change.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use rayon::prelude::*;
+use tracing::debug;
+
+use crate::compress::Compression;
+use crate::monitor::Monitor;
+use crate::stats::RecompressStats;
+use crate::*;
+
+/// Options for [recompress].
+#[derive(Debug, Default, Clone)]
+pub struct RecompressOptions {
+    /// Compression for the rewritten blocks.
+    pub compression: Compression,
+
+    /// Break a lock left behind by a previous interrupted gc or recompress operation.
+    pub break_lock: bool,
+}
+
+/// Rewrite every block in the archive with the given compression.
+///
+/// This takes the garbage collection lock, so it can't run concurrently with a
+/// backup or gc.
+///
+/// The new compression is recorded in every band head. If it can't be read by
+/// older versions of Conserve, every band is first marked as referencing tagged
+/// blocks.
+pub fn recompress(
+    archive: &Archive,
+    options: &RecompressOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<RecompressStats> {
+    let start = Instant::now();
+    let mut stats = RecompressStats::default();
+    let _lock = if options.break_lock {
+        GarbageCollectionLock::break_lock(archive)?
+    } else {
+        GarbageCollectionLock::new(archive)?
+    };
+    debug!("Got gc lock");
+
+    // Update the band heads before writing any tagged blocks, so that older
+    // versions refuse to read the bands rather than failing on the blocks.
+    for band_id in archive.list_band_ids()? {
+        Band::open(archive, band_id)?.set_compression(options.compression)?;
+    }
+
+    let block_dir = archive.block_dir();
+    let blocks: Vec<BlockHash> = block_dir.blocks(monitor.clone())?.collect();
+    stats.blocks = blocks.len();
+    let task = monitor.start_task("Recompress blocks".to_string());
+    task.set_total(blocks.len());
+    stats += blocks
+        .par_iter()
+        .map(|hash| {
+            let mut block_stats = RecompressStats::default();
+            if let Err(err) =
+                block_dir.recompress_block(hash, options.compression, &mut block_stats)
+            {
+                monitor.error(err);
+                block_stats.errors += 1;
+            }
+            task.increment(1);
+            block_stats
+        })
+        .reduce(RecompressStats::default, |a, b| a + b);
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
@@ -0,0 +1,122 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Repair damaged bands, so that what remains of them can be read.
+//!
+//! Bands with a missing or unreadable head get a replacement head; index hunks
+//! that can't be read, or that are out of order, are removed; and band tails
+//! that are unreadable or don't match the index are rewritten. Each band that
+//! was changed is marked as recovered, and bands that are now readable are removed
+//! from the archive's list of damaged bands.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use tracing::{debug, warn};
+
+use crate::monitor::Monitor;
+use crate::stats::RepairStats;
+use crate::*;
+
+/// Options for [repair].
+#[derive(Debug, Default, Clone)]
+pub struct RepairOptions {
+    /// Report what would be repaired, without changing the archive.
+    pub dry_run: bool,
+}
+
+/// Repair all the bands in an archive.
+///
+/// This shouldn't be run while a backup is writing to the archive, because the
+/// hunks of the band being written could be mistaken for damage.
+pub fn repair(
+    archive: &Archive,
+    options: &RepairOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<RepairStats> {
+    let start = Instant::now();
+    let mut stats = RepairStats::default();
+    let band_ids = archive.list_band_ids()?;
+    let task = monitor.start_task("Repair bands".to_string());
+    task.set_total(band_ids.len());
+    let mut readable_bands = Vec::new();
+    let mut repaired_bands = Vec::new();
+    for band_id in band_ids {
+        task.increment(1);
+        stats.bands += 1;
+        match repair_band(archive, band_id, options, &mut stats) {
+            Ok(repaired) => {
+                if repaired {
+                    stats.repaired_bands += 1;
+                    repaired_bands.push(band_id);
+                } else {
+                    debug!(%band_id, "Band is undamaged");
+                }
+                readable_bands.push(band_id);
+            }
+            Err(err) => {
+                stats.errors += 1;
+                monitor.error(err);
+            }
+        }
+    }
+    if !options.dry_run {
+        archive.clear_damaged_bands(&readable_bands)?;
+        archive.forget_validated_bands(&repaired_bands)?;
+    }
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
+
+/// Repair one band, returning true if it was damaged.
+fn repair_band(
+    archive: &Archive,
+    band_id: BandId,
+    options: &RepairOptions,
+    stats: &mut RepairStats,
+) -> Result<bool> {
+    let mut repaired = false;
+    let mut band = match Band::open(archive, band_id) {
+        Ok(band) => band,
+        Err(err @ (Error::BandHeadMissing { .. } | Error::DeserializeJson { .. })) => {
+            warn!(%band_id, "Replacing band head: {err}");
+            let band = Band::replacement_head(archive, band_id);
+            if !options.dry_run {
+                band.write_head()?;
+            }
+            stats.replaced_heads += 1;
+            repaired = true;
+            band
+        }
+        Err(err) => return Err(err),
+    };
+    let index = band.index();
+    let (good_hunks, bad_hunks) = index.check_hunks()?;
+    for hunk_number in &bad_hunks {
+        warn!(%band_id, hunk_number, "Removing damaged index hunk");
+        if !options.dry_run {
+            index.remove_hunk(*hunk_number)?;
+        }
+    }
+    if !bad_hunks.is_empty() {
+        stats.removed_index_hunks += bad_hunks.len();
+        repaired = true;
+    }
+    if band.repair_tail(good_hunks.len() as u64, options.dry_run)? {
+        stats.rewritten_tails += 1;
+        repaired = true;
+    }
+    if repaired && !options.dry_run && !band.is_recovered() {
+        band.set_recovered()?;
+    }
+    Ok(repaired)
+}
@@ -0,0 +1,222 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Replicate an archive into another, such as an off-site copy, by copying the
+//! bands that the destination doesn't have yet.
+//!
+//! Copied bands get new ids in the destination, so bands are matched by what's
+//! recorded in their heads: their start time, label, message, and where they
+//! were made. If several bands have the same head, as can happen for backups
+//! made within a second of each other, the first that many in the source are
+//! taken to be those already in the destination.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Instant;
+
+use time::OffsetDateTime;
+use tracing::{debug, info};
+
+use crate::monitor::{check_cancel, Monitor};
+use crate::stats::ReplicateStats;
+use crate::*;
+
+/// The parts of a band's head that identify it across archives.
+///
+/// Holds and expiry times can be changed after the band is written, so aren't
+/// included.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct BandKey {
+    start_time: OffsetDateTime,
+    label: Option<String>,
+    message: Option<String>,
+    hostname: Option<String>,
+    username: Option<String>,
+    sources: Vec<String>,
+}
+
+impl BandKey {
+    fn new(info: &BandInfo) -> BandKey {
+        BandKey {
+            start_time: info.start_time,
+            label: info.label.clone(),
+            message: info.message.clone(),
+            hostname: info.hostname.clone(),
+            username: info.username.clone(),
+            sources: info.sources.clone(),
+        }
+    }
+}
+
+/// Copy every complete band in the source archive that's not in the destination,
+/// along with the blocks it references that the destination doesn't have.
+///
+/// Bands are copied in order, and each is incomplete in the destination until
+/// all of its blocks and index hunks are copied. If replication is interrupted,
+/// running it again finishes the band that was being copied, without copying its
+/// blocks again, and then continues with the rest.
+///
+/// Bands that are incomplete in the source, because a backup is in progress or
+/// was interrupted, are skipped.
+pub fn replicate(
+    source: &Archive,
+    dest: &Archive,
+    monitor: Arc<dyn Monitor>,
+) -> Result<ReplicateStats> {
+    let start = Instant::now();
+    let mut stats = ReplicateStats::default();
+
+    // For each key, the number of complete bands in the destination, and any
+    // incomplete bands that can be resumed.
+    let mut present: HashMap<BandKey, usize> = HashMap::new();
+    let mut resumable: HashMap<BandKey, VecDeque<Band>> = HashMap::new();
+    for band_id in dest.list_band_ids()? {
+        let band = Band::open(dest, band_id)?;
+        let info = band.get_info()?;
+        let key = BandKey::new(&info);
+        if info.is_closed {
+            *present.entry(key).or_default() += 1;
+        } else {
+            resumable.entry(key).or_default().push_back(band);
+        }
+    }
+
+    for band_id in source.list_band_ids()? {
+        check_cancel(monitor.as_ref())?;
+        let info = Band::open(source, band_id)?.get_info()?;
+        if !info.is_closed {
+            debug!(%band_id, "Skipping incomplete band");
+            stats.incomplete_bands += 1;
+            continue;
+        }
+        stats.bands += 1;
+        let key = BandKey::new(&info);
+        if let Some(count) = present.get_mut(&key).filter(|count| **count > 0) {
+            *count -= 1;
+            stats.present_bands += 1;
+            continue;
+        }
+        let resume = resumable.get_mut(&key).and_then(VecDeque::pop_front);
+        if resume.is_some() {
+            stats.resumed_bands += 1;
+        }
+        let copy_stats = source.copy_band(dest, band_id, resume, monitor.clone())?;
+        info!(%band_id, dest_band_id = %copy_stats.band_id, "Copied band");
+        stats.copied_bands += 1;
+        stats.blocks += copy_stats.blocks;
+        stats.deduplicated_blocks += copy_stats.deduplicated_blocks;
+        stats.copied_blocks += copy_stats.copied_blocks;
+        stats.copied_block_bytes += copy_stats.copied_block_bytes;
+        stats.index_hunks += copy_stats.index_hunks;
+    }
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::monitor::test::TestMonitor;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    #[test]
+    fn replicate_is_incremental() {
+        let source = ScratchArchive::new();
+        let dest = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file_with_contents("a", b"aaa");
+        let options = BackupOptions::default().with_label(Some("first".to_owned()));
+        backup(&source, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+
+        let stats = replicate(&source, &dest, TestMonitor::arc()).unwrap();
+        assert_eq!(stats.bands, 1);
+        assert_eq!(stats.copied_bands, 1);
+        assert_eq!(stats.copied_blocks, 1);
+
+        // A backup made directly into the destination isn't affected.
+        backup(
+            &dest,
+            srcdir.path(),
+            &Default::default(),
+            TestMonitor::arc(),
+        )
+        .unwrap();
+        srcdir.create_file_with_contents("b", b"bbb");
+        let options = BackupOptions::default().with_label(Some("second".to_owned()));
+        backup(&source, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+
+        let stats = replicate(&source, &dest, TestMonitor::arc()).unwrap();
+        assert_eq!(stats.bands, 2);
+        assert_eq!(stats.present_bands, 1);
+        assert_eq!(stats.copied_bands, 1);
+        assert_eq!(stats.deduplicated_blocks, 1);
+        assert_eq!(stats.copied_blocks, 1);
+        let labels = dest
+            .list_band_ids()
+            .unwrap()
+            .into_iter()
+            .map(|band_id| {
+                Band::open(&dest, band_id)
+                    .unwrap()
+                    .label()
+                    .map(str::to_owned)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            [Some("first".to_owned()), None, Some("second".to_owned())]
+        );
+
+        let stats = replicate(&source, &dest, TestMonitor::arc()).unwrap();
+        assert_eq!(stats.present_bands, 2);
+        assert_eq!(stats.copied_bands, 0);
+    }
+
+    #[test]
+    fn interrupted_copy_is_resumed() {
+        let source = ScratchArchive::new();
+        let dest = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file_with_contents("a", b"aaa");
+        backup(
+            &source,
+            srcdir.path(),
+            &Default::default(),
+            TestMonitor::arc(),
+        )
+        .unwrap();
+        // Simulate an interrupted copy: the head was written, but not the tail.
+        let band = Band::open(&source, BandId::zero()).unwrap();
+        band.create_copy(&dest).unwrap();
+
+        let stats = replicate(&source, &dest, TestMonitor::arc()).unwrap();
+        assert_eq!(stats.copied_bands, 1);
+        assert_eq!(stats.resumed_bands, 1);
+        assert_eq!(dest.list_band_ids().unwrap(), [BandId::zero()]);
+        assert!(Band::open(&dest, BandId::zero())
+            .unwrap()
+            .is_closed()
+            .unwrap());
+    }
+
+    #[test]
+    fn incomplete_source_bands_are_skipped() {
+        let source = ScratchArchive::new();
+        let dest = ScratchArchive::new();
+        source.setup_incomplete_empty_band();
+
+        let stats = replicate(&source, &dest, TestMonitor::arc()).unwrap();
+        assert_eq!(stats.incomplete_bands, 1);
+        assert_eq!(stats.bands, 0);
+        assert!(dest.list_band_ids().unwrap().is_empty());
+    }
+}
@@ -12,64 +12,331 @@
 
 //! Restore from the archive to the filesystem.
 
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::fs::{self, File};
-use std::io::{self, Write};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver};
 use std::sync::Arc;
+use std::time::Instant;
+
+use bytes::Bytes;
 
 use fail::fail_point;
-use filetime::set_file_handle_times;
 #[cfg(unix)]
 use filetime::set_symlink_file_times;
+use filetime::{set_file_handle_times, FileTime};
+use rayon::{Scope, ThreadPool};
 use time::OffsetDateTime;
-use tracing::{instrument, trace, warn};
+use tracing::{debug, info_span, instrument, trace, warn};
 
+use crate::blockdir::Address;
 use crate::counters::Counter;
+use crate::index::IndexEntryIter;
 use crate::io::{directory_is_empty, ensure_dir_exists};
-use crate::monitor::Monitor;
+use crate::monitor::{check_cancel, EntryOutcome, Monitor};
+use crate::parallelism::thread_pool;
+use crate::stitch::IterStitchedIndexHunks;
 use crate::unix_time::ToFileTime;
 use crate::*;
 
 /// Description of how to restore a tree.
+///
+/// New options may be added in future, so construct this from [Default] and the
+/// `with_` methods, or by setting fields of a default value.
 // #[derive(Debug)]
+#[non_exhaustive]
 pub struct RestoreOptions<'cb> {
     pub exclude: Exclude,
-    /// Restore only this subdirectory.
-    pub only_subtree: Option<Apath>,
-    pub overwrite: bool,
+    /// Restore only entries matching these apaths or globs, along with their
+    /// parent directories.
+    pub only: Include,
+    /// What to do about entries that already exist in the destination.
+    pub overwrite: Overwrite,
     // The band to select, or by default the last complete one.
     pub band_selection: BandSelectionPolicy,
 
     // Call this callback as each entry is successfully restored.
     pub change_callback: Option<ChangeCallback<'cb>>,
+
+    /// Restore POSIX ACLs, if any were recorded.
+    pub acls: bool,
+
+    /// Set owners by their recorded numeric uid and gid, rather than looking up
+    /// the user and group names on this system.
+    pub numeric_ids: bool,
+
+    /// Also leave holes for long runs of zeros in files that weren't recorded as
+    /// sparse, such as in archives written by older versions.
+    ///
+    /// Holes recorded in the index are always recreated.
+    pub zero_runs_as_holes: bool,
+
+    /// Read back each file after it's restored, and report an error if its
+    /// content doesn't match the backup.
+    ///
+    /// On Linux, files are flushed and dropped from the page cache first, so
+    /// that what's read back comes from the disk.
+    pub verify: bool,
+
+    /// Whether to set the owners of restored files.
+    pub set_owners: SetOwners,
+
+    /// Replace some recorded users and groups by others when setting owners.
+    pub owner_map: OwnerMap,
+
+    /// Keep existing destination files that already have the stored content,
+    /// rather than rewriting them, checking them in this way.
+    ///
+    /// Their permissions, ownership, and modification time are still restored.
+    /// This allows restoring into a non-empty destination: if `overwrite` is
+    /// [Overwrite::Fail], files that differ are overwritten.
+    pub skip_unchanged: Option<UnchangedCheck>,
+
+    /// How many blocks to read from the archive at once, and how much memory they
+    /// may take while they wait to be written out.
+    pub parallelism: Parallelism,
+
+    /// On Linux, copy file content within the filesystem, without reading it into
+    /// memory, when it's available there: from the file it was backed up from, if
+    /// that still has the size and modification time recorded in the index, or
+    /// from blocks of a local archive that are stored uncompressed.
+    ///
+    /// On filesystems that support it, such as Btrfs and XFS, an unchanged original
+    /// is cloned so that the restored file shares its storage.
+    ///
+    /// Content copied this way isn't checked against the hashes in the index, so
+    /// combine this with [RestoreOptions::verify] to check it.
+    pub zero_copy: bool,
+}
+
+/// What restore does about entries that already exist in the destination.
+///
+/// Existing directories are always merged with the restored directory; the
+/// policy decides whether their permissions and other metadata are restored.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum Overwrite {
+    /// Fail before restoring anything if the destination isn't empty.
+    #[default]
+    Fail,
+    /// Replace anything that already exists.
+    Always,
+    /// Keep anything that already exists, and restore only missing entries.
+    SkipExisting,
+    /// Replace existing entries only if the stored entry has a newer modification time.
+    IfNewer,
+}
+
+/// An entry that already existed in the destination, reported to the [Monitor]
+/// with how the overwrite policy resolved it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RestoreConflict {
+    pub apath: Apath,
+    /// The existing path in the destination.
+    pub path: PathBuf,
+    pub resolution: ConflictResolution,
+}
+
+/// How a conflict with an existing destination entry was resolved.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ConflictResolution {
+    /// The existing entry was replaced by the stored entry.
+    Replaced,
+    /// The existing entry was kept, and the stored entry was not restored.
+    Kept,
+}
+
+impl fmt::Display for RestoreConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = match self.resolution {
+            ConflictResolution::Replaced => "Replaced",
+            ConflictResolution::Kept => "Kept",
+        };
+        write!(f, "{verb} existing {:?}", self.path)
+    }
+}
+
+/// Whether restore sets the owners of restored files.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum SetOwners {
+    /// Set owners only when running as root; otherwise restored files are owned
+    /// by the current user.
+    #[default]
+    IfRoot,
+    /// Always try to set owners, for example when the process has the capability
+    /// to change owners without being root, ignoring permission errors.
+    Always,
+    /// Never set owners, so that restored files are owned by the current user.
+    Never,
+}
+
+/// How restore decides that an existing destination file already has the stored
+/// content, so that it needn't be rewritten.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, clap::ValueEnum)]
+pub enum UnchangedCheck {
+    /// The file's size and modification time match the index.
+    Metadata,
+    /// The file's size and content match the backup.
+    ///
+    /// The content is compared by hashing it, and, for parts of blocks shared
+    /// with other files, reading the block from the archive.
+    Content,
 }
 
 impl Default for RestoreOptions<'_> {
     fn default() -> Self {
         RestoreOptions {
-            overwrite: false,
+            overwrite: Overwrite::Fail,
             band_selection: BandSelectionPolicy::LatestClosed,
             exclude: Exclude::nothing(),
-            only_subtree: None,
+            only: Include::everything(),
             change_callback: None,
+            acls: true,
+            numeric_ids: false,
+            zero_runs_as_holes: false,
+            verify: false,
+            set_owners: SetOwners::IfRoot,
+            owner_map: OwnerMap::default(),
+            skip_unchanged: None,
+            parallelism: Parallelism::default(),
+            zero_copy: false,
+        }
+    }
+}
+
+impl<'cb> RestoreOptions<'cb> {
+    /// Don't restore entries matching these globs.
+    pub fn with_exclude(self, exclude: Exclude) -> Self {
+        RestoreOptions { exclude, ..self }
+    }
+
+    /// Restore only entries matching these apaths or globs, and their parents.
+    pub fn with_only(self, only: Include) -> Self {
+        RestoreOptions { only, ..self }
+    }
+
+    /// Decide what to do about entries that already exist in the destination.
+    pub fn with_overwrite(self, overwrite: Overwrite) -> Self {
+        RestoreOptions { overwrite, ..self }
+    }
+
+    /// Choose which band to restore.
+    pub fn with_band_selection(self, band_selection: BandSelectionPolicy) -> Self {
+        RestoreOptions {
+            band_selection,
+            ..self
+        }
+    }
+
+    /// Call this callback as each entry is restored.
+    pub fn with_change_callback(self, change_callback: Option<ChangeCallback<'cb>>) -> Self {
+        RestoreOptions {
+            change_callback,
+            ..self
+        }
+    }
+
+    /// Restore POSIX ACLs, if any were recorded.
+    pub fn with_acls(self, acls: bool) -> Self {
+        RestoreOptions { acls, ..self }
+    }
+
+    /// Set owners by their recorded uid and gid, rather than by name.
+    pub fn with_numeric_ids(self, numeric_ids: bool) -> Self {
+        RestoreOptions {
+            numeric_ids,
+            ..self
+        }
+    }
+
+    /// Leave holes for long runs of zeros, even in files not recorded as sparse.
+    pub fn with_zero_runs_as_holes(self, zero_runs_as_holes: bool) -> Self {
+        RestoreOptions {
+            zero_runs_as_holes,
+            ..self
+        }
+    }
+
+    /// Read back each restored file and check its content.
+    pub fn with_verify(self, verify: bool) -> Self {
+        RestoreOptions { verify, ..self }
+    }
+
+    /// Choose whether to set the owners of restored files.
+    pub fn with_set_owners(self, set_owners: SetOwners) -> Self {
+        RestoreOptions { set_owners, ..self }
+    }
+
+    /// Replace some recorded users and groups by others when setting owners.
+    pub fn with_owner_map(self, owner_map: OwnerMap) -> Self {
+        RestoreOptions { owner_map, ..self }
+    }
+
+    /// Keep existing files that already have the stored content, checked this way.
+    pub fn with_skip_unchanged(self, skip_unchanged: Option<UnchangedCheck>) -> Self {
+        RestoreOptions {
+            skip_unchanged,
+            ..self
+        }
+    }
+
+    /// Limit how many blocks are read at once, and the memory they may take.
+    pub fn with_parallelism(self, parallelism: Parallelism) -> Self {
+        RestoreOptions {
+            parallelism,
+            ..self
         }
     }
+
+    /// Copy content within the filesystem when it's available there.
+    pub fn with_zero_copy(self, zero_copy: bool) -> Self {
+        RestoreOptions { zero_copy, ..self }
+    }
 }
 
 /// Restore a selected version, or by default the latest, to a destination directory.
+///
+/// Errors restoring individual entries are reported to the monitor, and counted in
+/// the returned stats, and the restore continues.
 pub fn restore(
     archive: &Archive,
     destination: &Path,
     options: &RestoreOptions,
     monitor: Arc<dyn Monitor>,
-) -> Result<()> {
+) -> Result<RestoreStats> {
+    let start = Instant::now();
+    let mut stats = RestoreStats::default();
     let st = archive.open_stored_tree(options.band_selection.clone())?;
+    let span = info_span!(
+        "restore",
+        band_id = %st.band().id(),
+        files = tracing::field::Empty,
+        file_bytes = tracing::field::Empty,
+    )
+    .entered();
     ensure_dir_exists(destination)?;
-    if !options.overwrite && !directory_is_empty(destination)? {
+    let overwrite = match options.overwrite {
+        Overwrite::Fail if options.skip_unchanged.is_some() => Overwrite::Always,
+        overwrite => overwrite,
+    };
+    if overwrite == Overwrite::Fail && !directory_is_empty(destination)? {
         return Err(Error::DestinationNotEmpty);
     }
+    let set_owners = match options.set_owners {
+        SetOwners::IfRoot => owner::running_as_root(),
+        SetOwners::Always => true,
+        SetOwners::Never => false,
+    };
     let task = monitor.start_task("Restore".to_string());
     let block_dir = archive.block_dir();
+    let read_pool = thread_pool(options.parallelism.transport_concurrency, "conserve-read");
+    // Where the band was backed up from, to look for unchanged originals.
+    let sources = if options.zero_copy {
+        st.band().get_info()?.sources
+    } else {
+        Vec::new()
+    };
     // // This causes us to walk the source tree twice, which is probably an acceptable option
     // // since it's nice to see realistic overall progress. We could keep all the entries
     // // in memory, and maybe we should, but it might get unreasonably big.
@@ -81,63 +348,328 @@ pub fn restore(
     //     // deleted or changed while this is running.
     //     progress_bar.set_bytes_total(st.size(options.excludes.clone())?.file_bytes as u64);
     // }
-    let entry_iter = st.iter_entries(
-        options.only_subtree.clone().unwrap_or_else(Apath::root),
-        options.exclude.clone(),
-        monitor.clone(),
-    )?;
+    let entry_iter = SelectedEntries::new(&st, options, monitor.clone());
     let mut deferrals = Vec::new();
-    for entry in entry_iter {
+    // For each group of hard links, the path where its content was first restored.
+    let mut link_groups: HashMap<Apath, PathBuf> = HashMap::new();
+    // Directories that couldn't be restored because something else is in the way,
+    // so that nothing is restored through them.
+    let mut not_dirs: Vec<Apath> = Vec::new();
+    for mut entry in entry_iter {
+        check_cancel(monitor.as_ref())?;
+        if !set_owners {
+            entry.owner.clear();
+        } else {
+            if !options.owner_map.is_empty() {
+                entry.owner = options.owner_map.map(&entry.owner);
+            }
+            if options.numeric_ids {
+                entry.owner = entry.owner.numeric();
+            }
+        }
         task.set_name(format!("Restore {}", entry.apath));
         let path = destination.join(&entry.apath[1..]);
-        match entry.kind() {
-            Kind::Dir => {
-                monitor.count(Counter::Dirs, 1);
-                if *entry.apath() != Apath::root() {
-                    if let Err(err) = create_dir(&path) {
-                        if err.kind() != io::ErrorKind::AlreadyExists {
-                            monitor.error(Error::RestoreDirectory {
+        monitor.entry_started(&entry.apath, entry.kind());
+        let outcome = 'entry: {
+            if let Some(dir) = not_dirs.iter().find(|dir| dir.is_prefix_of(&entry.apath)) {
+                monitor.error(Error::ParentNotADirectory {
+                    path: path.clone(),
+                    parent: destination.join(&dir[1..]),
+                });
+                break 'entry EntryOutcome::Failed;
+            }
+            match entry.kind() {
+                Kind::Dir => {
+                    monitor.count(Counter::Dirs, 1);
+                    let existing = fs::symlink_metadata(&path).ok();
+                    if let Some(existing) = existing.as_ref().filter(|existing| existing.is_dir()) {
+                        // Existing directories are merged, but their metadata is kept
+                        // unless the policy would replace them.
+                        if !should_replace(existing, &entry, overwrite) {
+                            break 'entry EntryOutcome::Skipped;
+                        }
+                    } else {
+                        // The directory's children are restored inside it, so anything
+                        // else there, such as a symlink that might lead outside the
+                        // destination, is replaced whatever the overwrite policy.
+                        let overwrite = if existing.is_some() {
+                            Overwrite::Always
+                        } else {
+                            overwrite
+                        };
+                        let made_way = make_way(&path, &entry, overwrite, monitor.as_ref());
+                        if let Err(err) = made_way.and_then(|_| {
+                            create_dir(&path).map_err(|source| Error::RestoreDirectory {
                                 path: path.clone(),
-                                source: err,
-                            });
-                            continue;
+                                source,
+                            })
+                        }) {
+                            monitor.error(err);
+                            if fs::symlink_metadata(&path).is_ok_and(|existing| !existing.is_dir())
+                            {
+                                not_dirs.push(entry.apath.clone());
+                            }
+                            break 'entry EntryOutcome::Failed;
                         }
                     }
+                    deferrals.push(DirDeferral {
+                        path,
+                        unix_mode: entry.unix_mode(),
+                        mtime: entry.mtime(),
+                        owner: entry.owner().clone(),
+                        acls: if options.acls {
+                            entry.acls().clone()
+                        } else {
+                            Acls::default()
+                        },
+                    })
                 }
-                deferrals.push(DirDeferral {
-                    path,
-                    unix_mode: entry.unix_mode(),
-                    mtime: entry.mtime(),
-                    owner: entry.owner().clone(),
-                })
-            }
-            Kind::File => {
-                monitor.count(Counter::Files, 1);
-                if let Err(err) = restore_file(path.clone(), &entry, block_dir, monitor.clone()) {
-                    monitor.error(err);
-                    continue;
+                Kind::File => {
+                    monitor.count(Counter::Files, 1);
+                    if let Some(check) = options.skip_unchanged {
+                        let link_target =
+                            entry.link_group().and_then(|group| link_groups.get(group));
+                        // Later members of a link group are only unchanged if they're
+                        // already linked to the first, so that the links are kept.
+                        let unchanged = match link_target {
+                            Some(target) => is_same_file(target, &path),
+                            None => existing_file_unchanged(
+                                &path,
+                                &entry,
+                                check,
+                                block_dir,
+                                monitor.clone(),
+                            ),
+                        };
+                        if unchanged {
+                            trace!(%entry.apath, "Existing file is unchanged");
+                            monitor.count(Counter::UnchangedFiles, 1);
+                            // Linked files share the metadata that was already restored.
+                            if link_target.is_none() {
+                                if let Err(source) =
+                                    filetime::set_file_mtime(&path, entry.mtime().to_file_time())
+                                {
+                                    monitor.error(Error::RestoreModificationTime {
+                                        path: path.clone(),
+                                        source,
+                                    });
+                                }
+                                restore_file_metadata(
+                                    &path,
+                                    &entry,
+                                    options.acls,
+                                    monitor.as_ref(),
+                                );
+                                if let Some(group) = entry.link_group() {
+                                    link_groups.insert(group.clone(), path.clone());
+                                }
+                            }
+                            if let Some(cb) = options.change_callback.as_ref() {
+                                cb(&EntryChange::unchanged(&entry))?;
+                            }
+                            break 'entry EntryOutcome::Unchanged;
+                        }
+                    }
+                    match make_way(&path, &entry, overwrite, monitor.as_ref()) {
+                        Ok(true) => (),
+                        Ok(false) => break 'entry EntryOutcome::Skipped,
+                        Err(err) => {
+                            monitor.error(err);
+                            break 'entry EntryOutcome::Failed;
+                        }
+                    }
+                    if let Some(target) =
+                        entry.link_group().and_then(|group| link_groups.get(group))
+                    {
+                        match restore_hardlink(target, &path) {
+                            Ok(()) => {
+                                monitor.count(Counter::Hardlinks, 1);
+                                stats.hardlinks += 1;
+                                break 'entry EntryOutcome::Written;
+                            }
+                            // Fall back to restoring an independent copy.
+                            Err(err) => monitor.error(err),
+                        }
+                    }
+                    match restore_file(
+                        path.clone(),
+                        &entry,
+                        block_dir,
+                        read_pool.as_ref(),
+                        &original_paths(&sources, &entry.apath),
+                        options,
+                        monitor.clone(),
+                    ) {
+                        Ok(true) => stats.copied_files += 1,
+                        Ok(false) => (),
+                        Err(Error::Cancelled) => return Err(Error::Cancelled),
+                        Err(err) => {
+                            monitor.error(err);
+                            break 'entry EntryOutcome::Failed;
+                        }
+                    }
+                    if let Some(group) = entry.link_group() {
+                        link_groups
+                            .entry(group.clone())
+                            .or_insert_with(|| path.clone());
+                    }
                 }
-            }
-            Kind::Symlink => {
-                monitor.count(Counter::Symlinks, 1);
-                if let Err(err) = restore_symlink(&path, &entry) {
-                    monitor.error(err);
-                    continue;
+                Kind::Symlink => {
+                    monitor.count(Counter::Symlinks, 1);
+                    match make_way(&path, &entry, overwrite, monitor.as_ref()) {
+                        Ok(true) => (),
+                        Ok(false) => break 'entry EntryOutcome::Skipped,
+                        Err(err) => {
+                            monitor.error(err);
+                            break 'entry EntryOutcome::Failed;
+                        }
+                    }
+                    if let Err(err) = restore_symlink(&path, &entry) {
+                        monitor.error(err);
+                        break 'entry EntryOutcome::Failed;
+                    }
+                }
+                Kind::CharDevice | Kind::BlockDevice | Kind::Fifo | Kind::Socket => {
+                    monitor.count(Counter::SpecialFiles, 1);
+                    match make_way(&path, &entry, overwrite, monitor.as_ref()) {
+                        Ok(true) => (),
+                        Ok(false) => break 'entry EntryOutcome::Skipped,
+                        Err(err) => {
+                            monitor.error(err);
+                            break 'entry EntryOutcome::Failed;
+                        }
+                    }
+                    if let Err(err) = restore_special(&path, &entry) {
+                        monitor.error(err);
+                        break 'entry EntryOutcome::Failed;
+                    }
+                }
+                Kind::Unknown => {
+                    monitor.error(Error::InvalidMetadata {
+                        details: format!("Unknown file kind {:?}", entry.apath()),
+                    });
+                    break 'entry EntryOutcome::Failed;
                 }
+            };
+            EntryOutcome::Written
+        };
+        let bytes = match (outcome, entry.kind()) {
+            (EntryOutcome::Written, Kind::File) => entry.size().unwrap_or_default(),
+            _ => 0,
+        };
+        monitor.entry_finished(&entry.apath, entry.kind(), outcome, bytes);
+        match outcome {
+            EntryOutcome::Written => (),
+            EntryOutcome::Unchanged => {
+                stats.unchanged_files += 1;
+                continue;
             }
-            Kind::Unknown => {
-                monitor.error(Error::InvalidMetadata {
-                    details: format!("Unknown file kind {:?}", entry.apath()),
-                });
+            EntryOutcome::Skipped => {
+                stats.skipped += 1;
+                continue;
             }
-        };
+            EntryOutcome::Failed => {
+                stats.errors += 1;
+                continue;
+            }
+        }
+        match entry.kind() {
+            Kind::File => {
+                stats.files += 1;
+                stats.file_bytes += bytes;
+            }
+            Kind::Dir => stats.dirs += 1,
+            Kind::Symlink => stats.symlinks += 1,
+            Kind::CharDevice | Kind::BlockDevice | Kind::Fifo | Kind::Socket => {
+                stats.special_files += 1
+            }
+            Kind::Unknown => (),
+        }
         if let Some(cb) = options.change_callback.as_ref() {
-            // Since we only restore to empty directories they're all added.
+            // Entries that replaced existing ones are also reported as added, since
+            // the old entries aren't known.
             cb(&EntryChange::added(&entry))?;
         }
     }
     apply_deferrals(&deferrals, monitor.clone())?;
-    Ok(())
+    span.record("files", stats.files);
+    span.record("file_bytes", stats.file_bytes);
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
+
+/// True if the overwrite policy says an existing destination entry should be
+/// replaced by `entry`.
+fn should_replace(existing: &fs::Metadata, entry: &IndexEntry, overwrite: Overwrite) -> bool {
+    match overwrite {
+        // The destination was empty, so this was created by this restore.
+        Overwrite::Fail | Overwrite::Always => true,
+        Overwrite::SkipExisting => false,
+        Overwrite::IfNewer => {
+            FileTime::from_last_modification_time(existing) < entry.mtime().to_file_time()
+        }
+    }
+}
+
+/// Resolve any conflict with something that already exists where `entry` will
+/// be restored, removing it if the overwrite policy says it should be replaced.
+///
+/// Returns false if the existing entry is kept, and `entry` should not be
+/// restored. Directories are only removed if they're empty.
+fn make_way(
+    path: &Path,
+    entry: &IndexEntry,
+    overwrite: Overwrite,
+    monitor: &dyn Monitor,
+) -> Result<bool> {
+    if overwrite == Overwrite::Fail {
+        return Ok(true);
+    }
+    let existing = match fs::symlink_metadata(path) {
+        Ok(existing) => existing,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(true),
+        Err(source) => {
+            return Err(Error::ReplaceExisting {
+                path: path.to_owned(),
+                source,
+            })
+        }
+    };
+    let replace = should_replace(&existing, entry, overwrite);
+    monitor.restore_conflict(RestoreConflict {
+        apath: entry.apath.clone(),
+        path: path.to_owned(),
+        resolution: if replace {
+            ConflictResolution::Replaced
+        } else {
+            ConflictResolution::Kept
+        },
+    });
+    if replace {
+        if existing.is_dir() {
+            fs::remove_dir(path)
+        } else {
+            fs::remove_file(path)
+        }
+        .map_err(|source| Error::ReplaceExisting {
+            path: path.to_owned(),
+            source,
+        })?;
+    }
+    Ok(replace)
+}
+
+/// Create a file to restore into, truncating any existing file, but without
+/// following a symlink at `path`.
+fn create_file(path: &Path) -> io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(nix::libc::O_NOFOLLOW);
+    }
+    options.open(path)
 }
 
 fn create_dir(path: &Path) -> io::Result<()> {
@@ -150,6 +682,134 @@ fn create_dir(path: &Path) -> io::Result<()> {
     fs::create_dir(path)
 }
 
+/// Entries selected for restore, each preceded by any of its parent directories
+/// that weren't already returned.
+///
+/// Each subtree that can contain selected entries is read in turn, seeking
+/// directly to it in the index. Directories that don't match are held back until
+/// something inside them matches, so that only the directories needed to hold
+/// the selected entries are restored.
+struct SelectedEntries<'a> {
+    stored_tree: &'a StoredTree,
+    only: &'a Include,
+    exclude: &'a Exclude,
+    monitor: Arc<dyn Monitor>,
+    subtrees: std::slice::Iter<'a, Apath>,
+    /// Entries from the current subtree, if any.
+    entries: Option<IndexEntryIter<IterStitchedIndexHunks>>,
+    /// Directories seen but not yet returned, because nothing inside them matched yet.
+    pending_dirs: BTreeMap<Apath, IndexEntry>,
+    /// Prune directories that can no longer have matching children when there
+    /// are more than this many pending.
+    prune_threshold: usize,
+    /// Parent directories that were already returned, for an earlier subtree.
+    returned_dirs: HashSet<Apath>,
+    /// Entries ready to be returned.
+    ready: VecDeque<IndexEntry>,
+}
+
+const MIN_PRUNE_THRESHOLD: usize = 1000;
+
+impl<'a> SelectedEntries<'a> {
+    fn new(
+        stored_tree: &'a StoredTree,
+        options: &'a RestoreOptions,
+        monitor: Arc<dyn Monitor>,
+    ) -> SelectedEntries<'a> {
+        SelectedEntries {
+            stored_tree,
+            only: &options.only,
+            exclude: &options.exclude,
+            monitor,
+            subtrees: options.only.subtrees().iter(),
+            entries: None,
+            pending_dirs: BTreeMap::new(),
+            prune_threshold: MIN_PRUNE_THRESHOLD,
+            returned_dirs: HashSet::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Start reading entries from a subtree, after looking up its parent
+    /// directories, other than the root.
+    fn start_subtree(&mut self, subtree: &Apath) -> Result<()> {
+        self.pending_dirs.clear();
+        self.prune_threshold = MIN_PRUNE_THRESHOLD;
+        let mut parent = subtree.parent();
+        while let Some(dir) = parent.filter(|dir| *dir != Apath::root()) {
+            parent = dir.parent();
+            if self.returned_dirs.contains(&dir) {
+                continue;
+            }
+            if let Some(entry) = self
+                .stored_tree
+                .iter_entries(dir.clone(), self.exclude.clone(), self.monitor.clone())?
+                .next()
+                .filter(|entry| entry.apath == dir && entry.kind() == Kind::Dir)
+            {
+                self.pending_dirs.insert(dir, entry);
+            }
+        }
+        self.entries = Some(self.stored_tree.iter_entries(
+            subtree.clone(),
+            self.exclude.clone(),
+            self.monitor.clone(),
+        )?);
+        Ok(())
+    }
+
+    /// Remember a directory that doesn't match, in case something inside it does.
+    fn hold_dir(&mut self, entry: IndexEntry) {
+        let apath = entry.apath.clone();
+        self.pending_dirs.insert(apath.clone(), entry);
+        if self.pending_dirs.len() > self.prune_threshold {
+            self.pending_dirs
+                .retain(|dir, _| !apath.is_after_subtree(dir));
+            self.prune_threshold = (self.pending_dirs.len() * 2).max(MIN_PRUNE_THRESHOLD);
+        }
+    }
+}
+
+impl Iterator for SelectedEntries<'_> {
+    type Item = IndexEntry;
+
+    fn next(&mut self) -> Option<IndexEntry> {
+        loop {
+            if let Some(entry) = self.ready.pop_front() {
+                return Some(entry);
+            }
+            let Some(entries) = &mut self.entries else {
+                let subtree = self.subtrees.next()?;
+                if let Err(err) = self.start_subtree(subtree) {
+                    self.monitor.error(err);
+                }
+                continue;
+            };
+            let Some(entry) = entries.next() else {
+                self.entries = None;
+                continue;
+            };
+            if !self.only.matches(&entry.apath) {
+                if entry.kind() == Kind::Dir {
+                    self.hold_dir(entry);
+                }
+                continue;
+            }
+            let mut parents = Vec::new();
+            let mut parent = entry.apath.parent();
+            while let Some(dir) = parent {
+                parent = dir.parent();
+                if let Some(dir_entry) = self.pending_dirs.remove(&dir) {
+                    self.returned_dirs.insert(dir);
+                    parents.push(dir_entry);
+                }
+            }
+            self.ready.extend(parents.into_iter().rev());
+            self.ready.push_back(entry);
+        }
+    }
+}
+
 /// Recorded changes to apply to directories after all their contents
 /// have been applied.
 ///
@@ -160,6 +820,7 @@ struct DirDeferral {
     unix_mode: UnixMode,
     mtime: OffsetDateTime,
     owner: Owner,
+    acls: Acls,
 }
 
 fn apply_deferrals(deferrals: &[DirDeferral], monitor: Arc<dyn Monitor>) -> Result<()> {
@@ -168,6 +829,7 @@ fn apply_deferrals(deferrals: &[DirDeferral], monitor: Arc<dyn Monitor>) -> Resu
         unix_mode,
         mtime,
         owner,
+        acls,
     } in deferrals
     {
         if let Err(source) = owner.set_owner(path) {
@@ -182,6 +844,9 @@ fn apply_deferrals(deferrals: &[DirDeferral], monitor: Arc<dyn Monitor>) -> Resu
                 source,
             });
         }
+        if let Err(err) = restore_acls(acls, path) {
+            monitor.error(err);
+        }
         if let Err(source) = filetime::set_file_mtime(path, (*mtime).to_file_time()) {
             monitor.error(Error::RestoreModificationTime {
                 path: path.clone(),
@@ -192,36 +857,205 @@ fn apply_deferrals(deferrals: &[DirDeferral], monitor: Arc<dyn Monitor>) -> Resu
     Ok(())
 }
 
+/// Reads the blocks for a file on a thread pool, ahead of where they're written out,
+/// returning their content in order.
+///
+/// At most [Parallelism::transport_concurrency] blocks are read at once, and the
+/// blocks read but not yet returned take at most [Parallelism::max_buffered_bytes],
+/// apart from always allowing one.
+struct ReadAhead<'a, 's> {
+    scope: &'a Scope<'s>,
+    block_dir: &'s BlockDir,
+    addrs: std::slice::Iter<'s, Address>,
+    /// Lengths of the addresses being read, and their results, in order.
+    reading: VecDeque<(u64, Receiver<Result<Bytes>>)>,
+    /// Total length of the addresses being read.
+    reading_bytes: u64,
+    max_reading: usize,
+    max_buffered_bytes: u64,
+    monitor: Arc<dyn Monitor>,
+}
+
+impl<'a, 's> ReadAhead<'a, 's> {
+    fn new(
+        scope: &'a Scope<'s>,
+        block_dir: &'s BlockDir,
+        addrs: &'s [Address],
+        parallelism: &Parallelism,
+        monitor: Arc<dyn Monitor>,
+    ) -> Self {
+        ReadAhead {
+            scope,
+            block_dir,
+            addrs: addrs.iter(),
+            reading: VecDeque::new(),
+            reading_bytes: 0,
+            max_reading: parallelism.transport_concurrency.max(1),
+            max_buffered_bytes: parallelism.max_buffered_bytes,
+            monitor,
+        }
+    }
+
+    /// Start reading more addresses, as far as the limits allow.
+    fn fill(&mut self) {
+        while self.reading.len() < self.max_reading {
+            let Some(addr) = self.addrs.as_slice().first() else {
+                return;
+            };
+            if !self.reading.is_empty() && self.reading_bytes + addr.len > self.max_buffered_bytes {
+                return;
+            }
+            self.addrs.next();
+            let (sender, receiver) = sync_channel(1);
+            let block_dir = self.block_dir;
+            let monitor = self.monitor.clone();
+            self.scope.spawn(move |_| {
+                // The receiver is gone only if restoring the file has already failed.
+                let _ = sender.send(block_dir.read_address(addr, monitor));
+            });
+            self.reading.push_back((addr.len, receiver));
+            self.reading_bytes += addr.len;
+        }
+    }
+}
+
+impl Iterator for ReadAhead<'_, '_> {
+    type Item = Result<Bytes>;
+
+    fn next(&mut self) -> Option<Result<Bytes>> {
+        self.fill();
+        let (len, receiver) = self.reading.pop_front()?;
+        self.reading_bytes -= len;
+        Some(receiver.recv().expect("block reader thread panicked"))
+    }
+}
+
 /// Copy in the contents of a file from another tree.
-#[instrument(skip(source_entry, block_dir, monitor))]
-fn restore_file(
+///
+/// `originals` are paths the file might have been backed up from, which are used
+/// if [RestoreOptions::zero_copy] is set.
+///
+/// Returns true if the content was copied within the filesystem, rather than
+/// written from blocks.
+#[instrument(
+    skip(source_entry, block_dir, read_pool, originals, options, monitor),
+    fields(blocks = source_entry.addrs.len(), bytes = source_entry.size().unwrap_or_default())
+)]
+pub(crate) fn restore_file(
     path: PathBuf,
     source_entry: &IndexEntry,
     block_dir: &BlockDir,
+    read_pool: Option<&ThreadPool>,
+    originals: &[PathBuf],
+    options: &RestoreOptions,
     monitor: Arc<dyn Monitor>,
-) -> Result<()> {
-    let mut out = File::create(&path).map_err(|err| Error::RestoreFile {
+) -> Result<bool> {
+    let mut out = create_file(&path).map_err(|err| Error::RestoreFile {
         path: path.clone(),
         source: err,
     })?;
-    for addr in &source_entry.addrs {
-        // TODO: We could combine small parts
-        // in memory, and then write them in a single system call. However
-        // for the probably common cases of files with one part, or
-        // many larger parts, sending everything through a BufWriter is
-        // probably a waste.
-        let bytes = block_dir
-            .read_address(addr, monitor.clone())
-            .map_err(|source| Error::RestoreFileBlock {
+    let copied = options.zero_copy
+        && copy_content(&out, source_entry, originals, block_dir, monitor.clone()).map_err(
+            |source| Error::RestoreFile {
+                path: path.clone(),
+                source,
+            },
+        )?;
+    // Position in the file, and the holes that are not yet passed.
+    let mut pos: u64 = 0;
+    let mut holes = source_entry.holes.iter().peekable();
+    // True if holes were left for runs of zeros, so the file must be extended over
+    // any at the end.
+    let mut skipped_zeros = false;
+    let mut write_blocks = |blocks: &mut dyn Iterator<Item = Result<Bytes>>| -> Result<()> {
+        for (addr, bytes) in source_entry.addrs.iter().zip(blocks) {
+            check_cancel(monitor.as_ref())?;
+            // TODO: We could combine small parts
+            // in memory, and then write them in a single system call. However
+            // for the probably common cases of files with one part, or
+            // many larger parts, sending everything through a BufWriter is
+            // probably a waste.
+            let bytes = bytes.map_err(|source| Error::RestoreFileBlock {
                 apath: source_entry.apath.clone(),
                 hash: addr.hash.clone(),
                 source: Box::new(source),
             })?;
-        out.write_all(&bytes).map_err(|err| Error::RestoreFile {
-            path: path.clone(),
-            source: err,
-        })?;
-        monitor.count(Counter::FileBytes, bytes.len());
+            let mut rest: &[u8] = &bytes;
+            while !rest.is_empty() {
+                // Seek past any holes here, leaving them unallocated.
+                let mut skipped = false;
+                while let Some(hole) = holes.next_if(|hole| hole.start <= pos) {
+                    pos = pos.max(hole.end());
+                    skipped = true;
+                }
+                if skipped {
+                    out.seek(SeekFrom::Start(pos))
+                        .map_err(|source| Error::RestoreFile {
+                            path: path.clone(),
+                            source,
+                        })?;
+                }
+                let len = holes.peek().map_or(rest.len(), |hole| {
+                    (hole.start - pos).min(rest.len() as u64) as usize
+                });
+                let data = &rest[..len];
+                let mut written = 0;
+                if options.zero_runs_as_holes {
+                    for run in sparse::zero_runs(data, pos) {
+                        out.write_all(&data[written..run.start])
+                            .and_then(|()| out.seek(SeekFrom::Current(run.len() as i64)))
+                            .map_err(|source| Error::RestoreFile {
+                                path: path.clone(),
+                                source,
+                            })?;
+                        written = run.end;
+                        skipped_zeros = true;
+                    }
+                }
+                out.write_all(&data[written..])
+                    .map_err(|err| Error::RestoreFile {
+                        path: path.clone(),
+                        source: err,
+                    })?;
+                pos += len as u64;
+                rest = &rest[len..];
+            }
+            monitor.count(Counter::FileBytes, bytes.len());
+        }
+        Ok(())
+    };
+    if copied {
+        monitor.count(Counter::CopiedFiles, 1);
+        monitor.count(
+            Counter::FileBytes,
+            source_entry.size().unwrap_or_default() as usize,
+        );
+    } else {
+        match read_pool {
+            Some(pool) if source_entry.addrs.len() > 1 => pool.in_place_scope(|scope| {
+                write_blocks(&mut ReadAhead::new(
+                    scope,
+                    block_dir,
+                    &source_entry.addrs,
+                    &options.parallelism,
+                    monitor.clone(),
+                ))
+            }),
+            _ => write_blocks(
+                &mut source_entry
+                    .addrs
+                    .iter()
+                    .map(|addr| block_dir.read_address(addr, monitor.clone())),
+            ),
+        }?;
+        if !source_entry.holes.is_empty() || skipped_zeros {
+            // Extend the file over any holes at the end.
+            let size = holes.fold(pos, |pos, hole| pos.max(hole.end()));
+            out.set_len(size).map_err(|source| Error::RestoreFile {
+                path: path.clone(),
+                source,
+            })?;
+        }
     }
     out.flush().map_err(|source| Error::RestoreFile {
         path: path.clone(),
@@ -234,10 +1068,198 @@ fn restore_file(
         source,
     })?;
 
+    simulate_corruption(&mut out).map_err(|source| Error::RestoreFile {
+        path: path.clone(),
+        source,
+    })?;
+    // Verify before restoring permissions, which might make the file unreadable.
+    if options.verify {
+        match verify_restored_file(&out, &path, source_entry, block_dir, monitor.clone()) {
+            Ok(()) => monitor.count(Counter::VerifiedFiles, 1),
+            Err(err) => monitor.error(err),
+        }
+    }
+
+    restore_file_metadata(&path, source_entry, options.acls, monitor.as_ref());
+    // TODO: Accumulate more stats.
+    trace!(copied, "Restored file");
+    Ok(copied)
+}
+
+/// Paths that the file at `apath` might have been backed up from, given the source
+/// paths recorded in its band.
+///
+/// A single source is stored either at the root of the band, or, if it was one of
+/// several sources, under its own name.
+fn original_paths(sources: &[String], apath: &Apath) -> Vec<PathBuf> {
+    let relpath = &apath[1..];
+    let mut paths = Vec::new();
+    if let [source] = sources {
+        paths.push(Path::new(source).join(relpath));
+    }
+    let (name, rest) = relpath.split_once('/').unwrap_or((relpath, ""));
+    for source in sources {
+        let source = Path::new(source);
+        if source.file_name().and_then(|n| n.to_str()) == Some(name) {
+            paths.push(if rest.is_empty() {
+                source.to_owned()
+            } else {
+                source.join(rest)
+            });
+        }
+    }
+    paths
+}
+
+/// Copy the content of a file within the filesystem, without reading it into
+/// memory: from an unchanged original, or from blocks that are stored uncompressed
+/// in a local archive.
+///
+/// Returns false, leaving `out` empty, if the content isn't available in this way,
+/// so that it should be written from blocks.
+#[cfg(target_os = "linux")]
+fn copy_content(
+    out: &File,
+    entry: &IndexEntry,
+    originals: &[PathBuf],
+    block_dir: &BlockDir,
+    monitor: Arc<dyn Monitor>,
+) -> io::Result<bool> {
+    use crate::io::{clone_file, copy_range};
+
+    let Some(size) = entry.size().filter(|size| *size > 0) else {
+        return Ok(false);
+    };
+    // If the original is the destination, it was already truncated, so it doesn't
+    // look unchanged.
+    for original in originals {
+        if !existing_file_unchanged(
+            original,
+            entry,
+            UnchangedCheck::Metadata,
+            block_dir,
+            monitor.clone(),
+        ) {
+            continue;
+        }
+        let Ok(from) = File::open(original) else {
+            continue;
+        };
+        if clone_file(&from, out) {
+            trace!(?original, "Cloned original file");
+            return Ok(true);
+        }
+        match copy_range(&from, 0, out, 0, size) {
+            Ok(true) => {
+                trace!(?original, "Copied original file");
+                return Ok(true);
+            }
+            Ok(false) => (),
+            Err(err) => {
+                debug!(?original, ?err, "Failed to copy original file");
+                out.set_len(0)?;
+            }
+        }
+    }
+    if !entry.holes.is_empty() {
+        // Holes are left by writing from blocks.
+        return Ok(false);
+    }
+    let mut pos = 0;
+    for addr in &entry.addrs {
+        let copied = block_dir
+            .local_stored_content(addr)
+            .map(|(block_path, offset)| {
+                File::open(block_path)
+                    .and_then(|from| copy_range(&from, offset, out, pos, addr.len))
+            });
+        match copied {
+            Some(Ok(true)) => pos += addr.len,
+            Some(Err(err)) => {
+                debug!(hash = %addr.hash, ?err, "Failed to copy stored block");
+                out.set_len(0)?;
+                return Ok(false);
+            }
+            _ => {
+                out.set_len(0)?;
+                return Ok(false);
+            }
+        }
+    }
+    trace!("Copied stored blocks");
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_content(
+    _out: &File,
+    _entry: &IndexEntry,
+    _originals: &[PathBuf],
+    _block_dir: &BlockDir,
+    _monitor: Arc<dyn Monitor>,
+) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Damage the start of a restored file, when enabled by a fail point, to test
+/// verification.
+fn simulate_corruption(_out: &mut File) -> io::Result<()> {
+    fail_point!("restore::corrupt-file", |_| {
+        _out.seek(SeekFrom::Start(0))?;
+        _out.write_all(&[0xff])
+    });
+    Ok(())
+}
+
+/// Read back a restored file and check that it matches the backup.
+fn verify_restored_file(
+    out: &File,
+    path: &Path,
+    entry: &IndexEntry,
+    block_dir: &BlockDir,
+    monitor: Arc<dyn Monitor>,
+) -> Result<()> {
+    let error = |source: Error| Error::VerifyRestoredFile {
+        path: path.to_owned(),
+        source: Box::new(source),
+    };
+    out.sync_data().map_err(|err| error(err.into()))?;
+    #[cfg(target_os = "linux")]
+    {
+        use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+        use std::os::fd::AsRawFd;
+        // This is only advice, so it doesn't matter if it fails.
+        let _ = posix_fadvise(
+            out.as_raw_fd(),
+            0,
+            0,
+            PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+        );
+    }
+    let len = out.metadata().map_err(|err| error(err.into()))?.len();
+    if Some(len) == entry.size()
+        && existing_content_matches(path, entry, block_dir, monitor).map_err(error)?
+    {
+        Ok(())
+    } else {
+        Err(Error::RestoredFileMismatch {
+            path: path.to_owned(),
+        })
+    }
+}
+
+/// Restore the permissions, ownership, ACLs, and capabilities of a file whose
+/// content is already in place.
+fn restore_file_metadata(
+    path: &Path,
+    source_entry: &IndexEntry,
+    acls: bool,
+    monitor: &dyn Monitor,
+) {
     // Restore permissions only if there are mode bits stored in the archive
-    if let Err(source) = source_entry.unix_mode().set_permissions(&path) {
+    if let Err(source) = source_entry.unix_mode().set_permissions(path) {
         monitor.error(Error::RestorePermissions {
-            path: path.clone(),
+            path: path.to_owned(),
             source,
         });
     }
@@ -245,19 +1267,219 @@ fn restore_file(
     // Restore ownership if possible.
     // TODO: Stats and warnings if a user or group is specified in the index but
     // does not exist on the local system.
-    if let Err(source) = source_entry.owner().set_owner(&path) {
+    if let Err(source) = source_entry.owner().set_owner(path) {
         monitor.error(Error::RestoreOwnership {
-            path: path.clone(),
+            path: path.to_owned(),
             source,
         });
     }
-    // TODO: Accumulate more stats.
-    trace!("Restored file");
+
+    // ACLs are set after the permissions, because setting the mode changes the ACL mask.
+    if acls {
+        if let Err(err) = restore_acls(source_entry.acls(), path) {
+            monitor.error(err);
+        }
+    }
+
+    // Capabilities are cleared by changing the owner, so must be set afterwards.
+    if let Some(capability) = source_entry.capability() {
+        if let Err(source) = capability.write(path) {
+            monitor.error(Error::RestoreCapability {
+                path: path.to_owned(),
+                source,
+            });
+        }
+    }
+}
+
+/// True if there's already a regular file at `path` with the content of `entry`.
+///
+/// Any problems reading the existing file are treated as a difference, so that
+/// it's rewritten.
+fn existing_file_unchanged(
+    path: &Path,
+    entry: &IndexEntry,
+    check: UnchangedCheck,
+    block_dir: &BlockDir,
+    monitor: Arc<dyn Monitor>,
+) -> bool {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return false;
+    };
+    if !metadata.is_file() || Some(metadata.len()) != entry.size() {
+        return false;
+    }
+    match check {
+        UnchangedCheck::Metadata => {
+            FileTime::from_last_modification_time(&metadata) == entry.mtime().to_file_time()
+        }
+        UnchangedCheck::Content => existing_content_matches(path, entry, block_dir, monitor)
+            .unwrap_or_else(|err| {
+                debug!(?path, ?err, "Failed to compare existing file");
+                false
+            }),
+    }
+}
+
+/// Compare the content of an existing file, of the right length, to a stored entry.
+///
+/// Parts of the file that are holes in the entry must be zero.
+fn existing_content_matches(
+    path: &Path,
+    entry: &IndexEntry,
+    block_dir: &BlockDir,
+    monitor: Arc<dyn Monitor>,
+) -> Result<bool> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut pos: u64 = 0;
+    let mut holes = entry.holes.iter().peekable();
+    for addr in &entry.addrs {
+        let mut data = Vec::with_capacity(addr.len as usize);
+        while (data.len() as u64) < addr.len {
+            while let Some(hole) = holes.next_if(|hole| hole.start <= pos) {
+                if hole.end() > pos {
+                    if !read_zeros(&mut file, hole.end() - pos)? {
+                        return Ok(false);
+                    }
+                    pos = hole.end();
+                }
+            }
+            let want = addr.len - data.len() as u64;
+            let len = holes.peek().map_or(want, |hole| want.min(hole.start - pos)) as usize;
+            let start = data.len();
+            data.resize(start + len, 0);
+            file.read_exact(&mut data[start..])?;
+            pos += len as u64;
+        }
+        // A whole block can be compared by its hash, without reading it.
+        let same = (addr.start == 0 && BlockHash::hash_bytes(&data) == addr.hash)
+            || block_dir.read_address(addr, monitor.clone())? == data;
+        if !same {
+            return Ok(false);
+        }
+    }
+    for hole in holes {
+        if hole.end() > pos {
+            if !read_zeros(&mut file, hole.end() - pos)? {
+                return Ok(false);
+            }
+            pos = hole.end();
+        }
+    }
+    Ok(true)
+}
+
+/// Read `len` bytes and return true if they're all zero.
+fn read_zeros(file: &mut impl Read, len: u64) -> io::Result<bool> {
+    let mut buf = [0; 64 << 10];
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = &mut buf[..remaining.min(64 << 10) as usize];
+        file.read_exact(chunk)?;
+        if chunk.iter().any(|b| *b != 0) {
+            return Ok(false);
+        }
+        remaining -= chunk.len() as u64;
+    }
+    Ok(true)
+}
+
+fn restore_acls(acls: &Acls, path: &Path) -> Result<()> {
+    acls.write(path).map_err(|source| {
+        if source.kind() == io::ErrorKind::Unsupported {
+            Error::RestoreAclUnsupported {
+                path: path.to_owned(),
+            }
+        } else {
+            Error::RestoreAcl {
+                path: path.to_owned(),
+                source,
+            }
+        }
+    })
+}
+
+/// Recreate a device node, FIFO, or socket.
+///
+/// Creating device nodes typically requires restoring as root.
+#[cfg(unix)]
+pub(crate) fn restore_special(path: &Path, entry: &IndexEntry) -> Result<()> {
+    use nix::sys::stat::{mknod, Mode, SFlag};
+
+    let error = |source: io::Error| Error::RestoreSpecialFile {
+        path: path.to_owned(),
+        source,
+    };
+    let kind = match entry.kind() {
+        Kind::CharDevice => SFlag::S_IFCHR,
+        Kind::BlockDevice => SFlag::S_IFBLK,
+        Kind::Fifo => SFlag::S_IFIFO,
+        Kind::Socket => SFlag::S_IFSOCK,
+        other => panic!("{other:?} is not a special file kind"),
+    };
+    #[cfg(target_os = "linux")]
+    let rdev = entry.device().map_or(0, |device| device.rdev());
+    #[cfg(not(target_os = "linux"))]
+    let rdev = if entry.kind().is_device() {
+        return Err(error(io::ErrorKind::Unsupported.into()));
+    } else {
+        0
+    };
+    mknod(path, kind, Mode::S_IRUSR | Mode::S_IWUSR, rdev).map_err(|errno| error(errno.into()))?;
+    if let Err(source) = entry.owner().set_owner(path) {
+        return Err(Error::RestoreOwnership {
+            path: path.to_owned(),
+            source,
+        });
+    }
+    if let Err(source) = entry.unix_mode().set_permissions(path) {
+        return Err(Error::RestorePermissions {
+            path: path.to_owned(),
+            source,
+        });
+    }
+    // Set the times without opening the file, which might block or have side effects.
+    let mtime = entry.mtime().to_file_time();
+    set_symlink_file_times(path, mtime, mtime).map_err(|source| Error::RestoreModificationTime {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+#[cfg(not(unix))]
+pub(crate) fn restore_special(_restore_path: &Path, entry: &IndexEntry) -> Result<()> {
+    warn!("Can't restore special files on non-Unix: {}", entry.apath());
     Ok(())
 }
 
+/// True if two paths are the same file, such as hard links to each other.
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::symlink_metadata(a), fs::symlink_metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// Restore a file as a hard link to a file that was already restored.
+///
+/// The link shares the content and metadata of the target, so nothing else needs to be set.
+fn restore_hardlink(target: &Path, path: &Path) -> Result<()> {
+    fs::hard_link(target, path).map_err(|source| Error::RestoreHardlink {
+        path: path.to_owned(),
+        target: target.to_owned(),
+        source,
+    })
+}
+
 #[cfg(unix)]
-fn restore_symlink(path: &Path, entry: &IndexEntry) -> Result<()> {
+pub(crate) fn restore_symlink(path: &Path, entry: &IndexEntry) -> Result<()> {
     use std::os::unix::fs as unix_fs;
     if let Some(ref target) = entry.symlink_target() {
         if let Err(source) = unix_fs::symlink(target, path) {
@@ -289,7 +1511,7 @@ fn restore_symlink(path: &Path, entry: &IndexEntry) -> Result<()> {
 
 #[cfg(not(unix))]
 #[mutants::skip]
-fn restore_symlink(_restore_path: &Path, entry: &IndexEntry) -> Result<()> {
+pub(crate) fn restore_symlink(_restore_path: &Path, entry: &IndexEntry) -> Result<()> {
     // TODO: Add a test with a canned index containing a symlink, and expect
     // it cannot be restored on Windows and can be on Unix.
     warn!("Can't restore symlinks on non-Unix: {}", entry.apath());
@@ -0,0 +1,212 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Salvage whatever files can still be read from a damaged archive.
+//!
+//! Unlike [restore], this doesn't depend on band heads or tails, or on whole
+//! indexes being readable: every index hunk that can be read, in any band, is
+//! searched for entries, and each file whose blocks are all present and intact
+//! is written out. Where several bands hold the same apath, the newest version
+//! that can be recovered is used.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+use rayon::prelude::*;
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::bandid::serialize_band_id;
+use crate::io::{directory_is_empty, ensure_dir_exists};
+use crate::monitor::Monitor;
+use crate::restore::{restore_file, restore_special, restore_symlink};
+use crate::stats::SalvageStats;
+use crate::*;
+
+/// Name of the manifest file written at the top of the recovery directory.
+pub const MANIFEST_NAME: &str = "conserve-salvage.json";
+
+/// Describes what could and couldn't be salvaged, written as json to
+/// [MANIFEST_NAME] in the recovery directory.
+#[derive(Debug, Default, Serialize)]
+pub struct SalvageManifest {
+    /// Entries that were recovered, in apath order.
+    pub recovered: Vec<SalvagedEntry>,
+    /// Entries that couldn't be recovered from any band, in apath order.
+    pub lost: Vec<SalvagedEntry>,
+    /// Index hunks, or whole indexes, that couldn't be read.
+    pub unreadable: Vec<String>,
+}
+
+/// One entry in a [SalvageManifest].
+#[derive(Debug, Clone, Serialize)]
+pub struct SalvagedEntry {
+    pub apath: Apath,
+    pub kind: Kind,
+    /// The band this version of the entry was found in.
+    #[serde(serialize_with = "serialize_band_id")]
+    pub band_id: BandId,
+    /// Why the entry couldn't be recovered, for lost entries.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub problem: Option<String>,
+}
+
+/// Write every entry that can be recovered from any band of a damaged archive
+/// into an empty destination directory, along with a manifest.
+///
+/// Directories are created, but their permissions and mtimes aren't restored.
+/// Hard links are written as independent copies.
+pub fn salvage(
+    archive: &Archive,
+    destination: &Path,
+    monitor: Arc<dyn Monitor>,
+) -> Result<SalvageStats> {
+    let start = Instant::now();
+    ensure_dir_exists(destination)?;
+    if !directory_is_empty(destination)? {
+        return Err(Error::DestinationNotEmpty);
+    }
+    let manifest_path = destination.join(MANIFEST_NAME);
+    let block_dir = archive.block_dir();
+    let present_blocks: HashSet<BlockHash> = block_dir.blocks(monitor.clone())?.collect();
+    let set_owners = owner::running_as_root();
+    let mut stats = SalvageStats::default();
+    let mut manifest = SalvageManifest::default();
+    let mut recovered: HashSet<Apath> = HashSet::new();
+    let mut lost: BTreeMap<Apath, SalvagedEntry> = BTreeMap::new();
+    let band_ids = archive.list_band_ids()?;
+    let task = monitor.start_task("Salvage".to_string());
+    task.set_total(band_ids.len());
+    for band_id in band_ids.into_iter().rev() {
+        task.increment(1);
+        stats.bands += 1;
+        let hunks = match Band::open_index(archive, band_id).read_each_hunk() {
+            Ok(hunks) => hunks,
+            Err(err) => {
+                warn!(%band_id, "Index is unreadable: {err}");
+                manifest.unreadable.push(format!("{band_id} index: {err}"));
+                continue;
+            }
+        };
+        for (hunk_number, result) in hunks {
+            let entries = match result {
+                Ok(entries) => entries,
+                Err(err) => {
+                    warn!(%band_id, hunk_number, "Index hunk is unreadable: {err}");
+                    stats.unreadable_index_hunks += 1;
+                    manifest
+                        .unreadable
+                        .push(format!("{band_id} index hunk {hunk_number}: {err}"));
+                    continue;
+                }
+            };
+            for mut entry in entries {
+                if recovered.contains(&entry.apath) {
+                    continue;
+                }
+                if !set_owners {
+                    entry.owner.clear();
+                }
+                let path = entry.apath.below(destination);
+                let result = if path == manifest_path {
+                    Err(Error::InvalidMetadata {
+                        details: "Entry has the same name as the salvage manifest".to_owned(),
+                    })
+                } else {
+                    salvage_entry(&path, &entry, block_dir, &present_blocks, monitor.clone())
+                };
+                let salvaged = SalvagedEntry {
+                    apath: entry.apath.clone(),
+                    kind: entry.kind(),
+                    band_id,
+                    problem: None,
+                };
+                match result {
+                    Ok(()) => {
+                        debug!(%band_id, apath = %entry.apath, "Salvaged entry");
+                        lost.remove(&entry.apath);
+                        recovered.insert(entry.apath);
+                        manifest.recovered.push(salvaged);
+                    }
+                    Err(err) => {
+                        debug!(%band_id, apath = %entry.apath, "Failed to salvage entry: {err}");
+                        // Keep the problem with the newest version.
+                        lost.entry(entry.apath).or_insert(SalvagedEntry {
+                            problem: Some(err.to_string()),
+                            ..salvaged
+                        });
+                    }
+                }
+            }
+        }
+    }
+    manifest.recovered.sort_by(|a, b| a.apath.cmp(&b.apath));
+    manifest.lost = lost.into_values().collect();
+    stats.recovered_entries = manifest.recovered.len();
+    stats.lost_entries = manifest.lost.len();
+    fs::write(&manifest_path, serde_json::to_vec_pretty(&manifest)?)?;
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
+
+/// Write one entry, creating any missing parent directories.
+fn salvage_entry(
+    path: &Path,
+    entry: &IndexEntry,
+    block_dir: &BlockDir,
+    present_blocks: &HashSet<BlockHash>,
+    monitor: Arc<dyn Monitor>,
+) -> Result<()> {
+    let create_dir_all = |path: &Path| {
+        fs::create_dir_all(path).map_err(|source| Error::RestoreDirectory {
+            path: path.to_owned(),
+            source,
+        })
+    };
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    match entry.kind() {
+        Kind::Dir => create_dir_all(path),
+        Kind::File => {
+            // Check all the blocks are present before writing anything.
+            if let Some(addr) = entry
+                .addrs
+                .iter()
+                .find(|addr| !present_blocks.contains(&addr.hash))
+            {
+                return Err(Error::BlockMissing {
+                    hash: addr.hash.clone(),
+                });
+            }
+            let result = restore_file(
+                path.to_owned(),
+                entry,
+                block_dir,
+                None,
+                &[],
+                &RestoreOptions::default(),
+                monitor,
+            );
+            if result.is_err() {
+                // Don't leave a partial file that looks like it was recovered.
+                let _ = fs::remove_file(path);
+            }
+            result.map(|_copied| ())
+        }
+        Kind::Symlink => restore_symlink(path, entry),
+        _ => restore_special(path, entry),
+    }
+}
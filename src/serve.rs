@@ -0,0 +1,566 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A minimal read-only web interface to an archive, so that people can recover
+//! the occasional file with a browser rather than the command line.
+//!
+//! The pages are:
+//!
+//! * `/`: the list of versions in the archive.
+//! * `/b0000/some/dir/`: the contents of a directory in a version.
+//! * `/b0000/some/file`: download a file.
+//! * `/b0000/some/dir/?tar`: download a directory and everything below it as
+//!   a tar file, as written by [export_tar].
+//!
+//! Directories are downloaded as tar rather than zip: tar can be streamed
+//! while the tree is read, without knowing the sizes or checksums of files in
+//! advance, and it reuses [export_tar]. Current Windows and macOS can both
+//! extract tar files.
+//!
+//! The server speaks just enough HTTP/1.1 for browsers and tools like curl:
+//! it handles one GET or HEAD request per connection, with each connection on
+//! its own thread, up to [MAX_CONNECTIONS] at once. It has no TLS or
+//! authentication, so it should normally listen only on localhost.
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use time::format_description::well_known::Rfc3339;
+use tracing::{debug, warn};
+
+use crate::monitor::Monitor;
+use crate::parallelism::Semaphore;
+use crate::*;
+
+/// Give up on clients that don't send their request within this time.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Give up on clients that stop reading the response for this long.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Handle at most this many connections at once; others wait to be accepted.
+pub const MAX_CONNECTIONS: usize = 8;
+
+/// Refuse requests with more header lines than this.
+const MAX_HEADER_LINES: usize = 100;
+
+/// Serve a read-only web interface to an archive, from connections accepted
+/// on `listener`.
+///
+/// Each connection is handled on its own thread, so that a slow client doesn't
+/// hold up the others.
+///
+/// This runs until accepting a connection fails. Errors handling a single
+/// request are logged and the server continues.
+pub fn serve(archive: &Archive, listener: TcpListener, monitor: Arc<dyn Monitor>) -> Result<()> {
+    let connections = Semaphore::new(MAX_CONNECTIONS);
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let permit = connections.acquire();
+            let stream = stream?;
+            let monitor = monitor.clone();
+            scope.spawn(move || {
+                if let Err(err) = handle_connection(archive, stream, monitor) {
+                    warn!("Error serving request: {err}");
+                }
+                drop(permit);
+            });
+        }
+        Ok(())
+    })
+}
+
+fn handle_connection(
+    archive: &Archive,
+    stream: TcpStream,
+    monitor: Arc<dyn Monitor>,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers aren't used, but must be read before replying.
+    let mut header_lines = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+        header_lines += 1;
+        if header_lines > MAX_HEADER_LINES {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Too many request headers",
+            ));
+        }
+    }
+    let mut out = BufWriter::new(stream);
+    handle_request(archive, request_line.trim_end(), &mut out, monitor)?;
+    out.flush()
+}
+
+/// Answer one request, given its request line like `GET /b0000/ HTTP/1.1`.
+fn handle_request(
+    archive: &Archive,
+    request_line: &str,
+    out: &mut dyn Write,
+    monitor: Arc<dyn Monitor>,
+) -> io::Result<()> {
+    debug!(?request_line, "HTTP request");
+    let mut words = request_line.split(' ');
+    let (Some(method), Some(target)) = (words.next(), words.next()) else {
+        return Response::error(400, "Bad Request", "Malformed request").write(out, false);
+    };
+    let head_only = match method {
+        "GET" => false,
+        "HEAD" => true,
+        _ => {
+            return Response::error(405, "Method Not Allowed", "Only GET is supported")
+                .write(out, false)
+        }
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let response = match percent_decode(path) {
+        Some(path) => route(archive, &path, query, monitor).unwrap_or_else(|err| {
+            warn!("Error serving {path:?}: {err}");
+            Response::error(500, "Internal Server Error", &err.to_string())
+        }),
+        None => Response::error(400, "Bad Request", "Invalid percent-encoding in path"),
+    };
+    response.write(out, head_only)
+}
+
+fn route(
+    archive: &Archive,
+    path: &str,
+    query: &str,
+    monitor: Arc<dyn Monitor>,
+) -> Result<Response> {
+    if path == "/" {
+        return versions_page(archive, monitor);
+    }
+    let not_found = || Ok(Response::error(404, "Not Found", "Not found"));
+    let Some(rest) = path.strip_prefix('/') else {
+        return not_found();
+    };
+    let (band_name, rest) = rest.split_once('/').unwrap_or((rest, ""));
+    let Ok(band_id) = BandId::from_str(band_name) else {
+        return not_found();
+    };
+    if !archive.band_exists(band_id)? {
+        return not_found();
+    }
+    let is_dir_path = path.ends_with('/');
+    let apath = format!("/{}", rest.trim_end_matches('/'));
+    if !Apath::is_valid(&apath) {
+        return not_found();
+    }
+    let apath = Apath::from(apath);
+    let stored_tree = archive.open_stored_tree(BandSelectionPolicy::Specified(band_id))?;
+    let kind = if apath == Apath::root() {
+        Kind::Dir
+    } else if let Some(entry) = stored_tree.lookup(&apath)? {
+        entry.kind()
+    } else {
+        return not_found();
+    };
+    match kind {
+        Kind::Dir if !is_dir_path => Ok(Response::redirect(format!(
+            "{}/",
+            percent_encode_path(path)
+        ))),
+        Kind::Dir if query == "tar" => {
+            let name = match apath.rsplit('/').next() {
+                Some(name) if !name.is_empty() => format!("{band_id}-{name}.tar"),
+                _ => format!("{band_id}.tar"),
+            };
+            Ok(Response {
+                status: 200,
+                reason: "OK",
+                content_type: "application/x-tar",
+                attachment: Some(name),
+                location: None,
+                content_length: None,
+                body: Body::Tar(Box::new(stored_tree), apath, monitor),
+            })
+        }
+        Kind::Dir => directory_page(&stored_tree, band_id, &apath, monitor),
+        Kind::File => {
+            let file = stored_tree.open_file(&apath, monitor)?;
+            let size = file.entry().size().unwrap_or_default();
+            Ok(Response {
+                status: 200,
+                reason: "OK",
+                content_type: "application/octet-stream",
+                attachment: apath.rsplit('/').next().map(str::to_owned),
+                location: None,
+                content_length: Some(size),
+                body: Body::File(Box::new(file)),
+            })
+        }
+        _ => Ok(Response::error(
+            404,
+            "Not Found",
+            &format!("{apath} is a {kind:?}, which can't be downloaded"),
+        )),
+    }
+}
+
+fn versions_page(archive: &Archive, monitor: Arc<dyn Monitor>) -> Result<Response> {
+    let mut html = String::new();
+    html.push_str(
+        "<table>\n<tr><th>Version</th><th>Started</th><th>Complete</th><th>Label</th></tr>\n",
+    );
+    for info in archive.list_band_infos(monitor)? {
+        writeln!(
+            html,
+            "<tr><td><a href=\"/{id}/\">{id}</a></td><td>{start}</td><td>{complete}</td><td>{label}</td></tr>",
+            id = info.id,
+            start = info.start_time.format(&Rfc3339).unwrap_or_default(),
+            complete = if info.is_closed { "yes" } else { "no" },
+            label = escape_html(info.label.as_deref().unwrap_or_default()),
+        )
+        .unwrap();
+    }
+    html.push_str("</table>\n");
+    Ok(Response::page("Versions", &html))
+}
+
+fn directory_page(
+    stored_tree: &StoredTree,
+    band_id: BandId,
+    dir: &Apath,
+    monitor: Arc<dyn Monitor>,
+) -> Result<Response> {
+    let mut html = String::new();
+    writeln!(
+        html,
+        "<p><a href=\"/\">All versions</a> | <a href=\"?tar\">Download as tar</a></p>"
+    )
+    .unwrap();
+    html.push_str("<table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n");
+    if *dir != Apath::root() {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in stored_tree.iter_entries(dir.clone(), Exclude::nothing(), monitor)? {
+        if entry.apath.parent().as_ref() != Some(dir) {
+            continue;
+        }
+        let name = entry.apath.rsplit('/').next().unwrap_or_default();
+        let (href, label, size) = match entry.kind() {
+            Kind::Dir => (
+                Some(format!("{}/", percent_encode(name))),
+                format!("{name}/"),
+                String::new(),
+            ),
+            Kind::File => (
+                Some(percent_encode(name)),
+                name.to_owned(),
+                entry.size().unwrap_or_default().to_string(),
+            ),
+            Kind::Symlink => (
+                None,
+                format!("{name} -> {}", entry.symlink_target().unwrap_or_default()),
+                String::new(),
+            ),
+            _ => (None, name.to_owned(), String::new()),
+        };
+        let label = escape_html(&label);
+        let name_cell = match href {
+            Some(href) => format!("<a href=\"{}\">{label}</a>", escape_html(&href)),
+            None => label,
+        };
+        writeln!(
+            html,
+            "<tr><td>{name_cell}</td><td>{size}</td><td>{mtime}</td></tr>",
+            mtime = entry.mtime().format(&Rfc3339).unwrap_or_default(),
+        )
+        .unwrap();
+    }
+    html.push_str("</table>\n");
+    Ok(Response::page(&format!("{band_id}:{dir}"), &html))
+}
+
+enum Body {
+    Html(String),
+    File(Box<StoredFile>),
+    /// A tar of a subtree, which is generated while it's sent.
+    Tar(Box<StoredTree>, Apath, Arc<dyn Monitor>),
+}
+
+struct Response {
+    status: u16,
+    reason: &'static str,
+    content_type: &'static str,
+    /// Offer the body as a download with this file name.
+    attachment: Option<String>,
+    /// Redirect to this URL.
+    location: Option<String>,
+    content_length: Option<u64>,
+    body: Body,
+}
+
+impl Response {
+    fn page(title: &str, body_html: &str) -> Response {
+        let title = escape_html(title);
+        let html = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{title}</title></head>\n\
+            <body>\n<h1>{title}</h1>\n{body_html}</body></html>\n"
+        );
+        Response {
+            status: 200,
+            reason: "OK",
+            content_type: "text/html; charset=utf-8",
+            attachment: None,
+            location: None,
+            content_length: Some(html.len() as u64),
+            body: Body::Html(html),
+        }
+    }
+
+    fn error(status: u16, reason: &'static str, message: &str) -> Response {
+        Response {
+            status,
+            reason,
+            ..Response::page(reason, &format!("<p>{}</p>\n", escape_html(message)))
+        }
+    }
+
+    fn redirect(location: String) -> Response {
+        let mut response = Response::page("Moved", "");
+        response.status = 301;
+        response.reason = "Moved Permanently";
+        response.location = Some(location);
+        response
+    }
+
+    fn write(self, out: &mut dyn Write, head_only: bool) -> io::Result<()> {
+        write!(out, "HTTP/1.1 {} {}\r\n", self.status, self.reason)?;
+        write!(out, "Content-Type: {}\r\n", self.content_type)?;
+        if let Some(len) = self.content_length {
+            write!(out, "Content-Length: {len}\r\n")?;
+        }
+        if let Some(name) = &self.attachment {
+            write!(
+                out,
+                "Content-Disposition: attachment; filename*=UTF-8''{}\r\n",
+                percent_encode(name)
+            )?;
+        }
+        if let Some(location) = &self.location {
+            write!(out, "Location: {location}\r\n")?;
+        }
+        write!(out, "Connection: close\r\n\r\n")?;
+        if head_only {
+            return Ok(());
+        }
+        match self.body {
+            Body::Html(html) => out.write_all(html.as_bytes()),
+            Body::File(mut file) => io::copy(&mut file, out).map(|_| ()),
+            Body::Tar(stored_tree, subtree, monitor) => {
+                let options = ExportTarOptions {
+                    subtree,
+                    ..Default::default()
+                };
+                export_tar(&stored_tree, out, &options, monitor).map_err(io::Error::other)
+            }
+        }
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encode everything except unreserved characters.
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for &b in s.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            write!(encoded, "%{b:02X}").unwrap();
+        }
+    }
+    encoded
+}
+
+/// Percent-encode each component of a path, leaving the slashes.
+fn percent_encode_path(path: &str) -> String {
+    path.split('/')
+        .map(percent_encode)
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Decode a percent-encoded path, returning None if it's malformed or not UTF-8.
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+    while let Some(b) = input.next() {
+        if b == b'%' {
+            let hex = [input.next()?, input.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::monitor::test::TestMonitor;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    fn get(archive: &Archive, request_line: &str) -> (String, Vec<u8>) {
+        let mut out = Vec::new();
+        handle_request(archive, request_line, &mut out, TestMonitor::arc()).unwrap();
+        let split = out.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(out[..split].to_vec()).unwrap();
+        (head, out[split + 4..].to_vec())
+    }
+
+    fn setup() -> ScratchArchive {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_dir("sub dir");
+        srcdir.create_file_with_contents("sub dir/a<b>.txt", b"hello\n");
+        backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+        af
+    }
+
+    #[test]
+    fn list_versions_and_directories() {
+        let af = setup();
+        let (head, body) = get(&af, "GET / HTTP/1.1");
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("<a href=\"/b0000/\">b0000</a>"), "{body}");
+
+        let (_head, body) = get(&af, "GET /b0000/ HTTP/1.1");
+        let body = String::from_utf8(body).unwrap();
+        assert!(
+            body.contains("<a href=\"sub%20dir/\">sub dir/</a>"),
+            "{body}"
+        );
+
+        let (_head, body) = get(&af, "GET /b0000/sub%20dir/ HTTP/1.1");
+        let body = String::from_utf8(body).unwrap();
+        assert!(
+            body.contains("<a href=\"a%3Cb%3E.txt\">a&lt;b&gt;.txt</a>"),
+            "{body}"
+        );
+    }
+
+    #[test]
+    fn download_file() {
+        let af = setup();
+        let (head, body) = get(&af, "GET /b0000/sub%20dir/a%3Cb%3E.txt HTTP/1.1");
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(head.contains("Content-Length: 6\r\n"), "{head}");
+        assert!(
+            head.contains("Content-Disposition: attachment; filename*=UTF-8''a%3Cb%3E.txt\r\n"),
+            "{head}"
+        );
+        assert_eq!(body, b"hello\n");
+
+        let (head, body) = get(&af, "HEAD /b0000/sub%20dir/a%3Cb%3E.txt HTTP/1.1");
+        assert!(head.contains("Content-Length: 6\r\n"), "{head}");
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn download_subtree_as_tar() {
+        let af = setup();
+        let (head, body) = get(&af, "GET /b0000/sub%20dir/?tar HTTP/1.1");
+        assert!(
+            head.contains("Content-Type: application/x-tar\r\n"),
+            "{head}"
+        );
+        assert!(
+            head.contains("filename*=UTF-8''b0000-sub%20dir.tar\r\n"),
+            "{head}"
+        );
+        assert_eq!(&body[..9], b"sub dir/\0");
+        assert_eq!(&body[512..528], b"sub dir/a<b>.txt");
+    }
+
+    #[test]
+    fn directory_without_slash_redirects() {
+        let af = setup();
+        let (head, _body) = get(&af, "GET /b0000/sub%20dir HTTP/1.1");
+        assert!(head.starts_with("HTTP/1.1 301 "), "{head}");
+        assert!(head.contains("Location: /b0000/sub%20dir/\r\n"), "{head}");
+    }
+
+    #[test]
+    fn errors() {
+        let af = setup();
+        for target in ["/b0009/", "/b0000/nothing", "/bogus/", "/b0000/../x"] {
+            let (head, _body) = get(&af, &format!("GET {target} HTTP/1.1"));
+            assert!(head.starts_with("HTTP/1.1 404 "), "{target}: {head}");
+        }
+        let (head, _body) = get(&af, "GET /b0000/%zz HTTP/1.1");
+        assert!(head.starts_with("HTTP/1.1 400 "), "{head}");
+        let (head, _body) = get(&af, "POST / HTTP/1.1");
+        assert!(head.starts_with("HTTP/1.1 405 "), "{head}");
+    }
+
+    #[test]
+    fn stalled_client_does_not_block_others() {
+        let af = setup();
+        let archive = Archive::clone(&af);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(&archive, listener, TestMonitor::arc()));
+
+        // This client connects but never sends its request.
+        let _stalled = TcpStream::connect(addr).unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        io::Read::read_to_string(&mut client, &mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+    }
+
+    #[test]
+    fn percent_coding() {
+        assert_eq!(percent_encode("a b/ü"), "a%20b%2F%C3%BC");
+        assert_eq!(percent_decode("a%20b%2F%C3%BC").unwrap(), "a b/ü");
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%ff"), None);
+        assert_eq!(percent_encode_path("/b0000/a b"), "/b0000/a%20b");
+    }
+}
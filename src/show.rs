@@ -17,13 +17,17 @@
 //! file (typically stdout).
 
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::io::{BufWriter, Write};
 use std::sync::Arc;
 
+use serde::Serialize;
 use time::format_description::well_known::Rfc3339;
-use time::UtcOffset;
+use time::macros::format_description;
+use time::{OffsetDateTime, UtcOffset};
 use tracing::error;
 
+use crate::bandid::serialize_band_id;
 use crate::misc::duration_to_hms;
 use crate::termui::TermUiMonitor;
 use crate::*;
@@ -42,6 +46,58 @@ pub struct ShowVersionsOptions {
     pub backup_duration: bool,
     /// Show times in this zone.
     pub timezone: Option<UtcOffset>,
+    /// Show the label and message given to each backup, if any.
+    pub description: bool,
+    /// Show the user, host, Conserve version, and source directories that made
+    /// each backup, if they were recorded.
+    pub origin: bool,
+    /// After the versions, show when the archive was last validated without problems.
+    pub last_validated: bool,
+    /// Print each version as a line of json, with every field, rather than as text.
+    ///
+    /// The other options are ignored, except that `newest_first` sets the order, and
+    /// `tree_size` adds the tree size.
+    pub json: bool,
+}
+
+/// One version, as printed by `conserve versions --json`.
+///
+/// Fields other than `band_id` and `damaged` are omitted for damaged bands
+/// whose head can't be read.
+#[derive(Debug, Serialize)]
+struct VersionJson {
+    #[serde(serialize_with = "serialize_band_id")]
+    band_id: BandId,
+    damaged: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    complete: Option<bool>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_time: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_time: Option<OffsetDateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tree_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires: Option<OffsetDateTime>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovered: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    held: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    conserve_version: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sources: Vec<String>,
 }
 
 /// Print a list of versions, one per line, on stdout.
@@ -50,28 +106,48 @@ pub fn show_versions(
     options: &ShowVersionsOptions,
     monitor: Arc<TermUiMonitor>,
 ) -> Result<()> {
-    let mut band_ids = archive.list_band_ids()?;
-    if options.newest_first {
-        band_ids.reverse();
-    }
-    for band_id in band_ids {
-        if !(options.tree_size || options.start_time || options.backup_duration) {
+    if !(options.json
+        || options.tree_size
+        || options.start_time
+        || options.backup_duration
+        || options.description
+        || options.origin)
+    {
+        let mut band_ids = archive.list_band_ids()?;
+        if options.newest_first {
+            band_ids.reverse();
+        }
+        for band_id in band_ids {
             println!("{}", band_id);
-            continue;
         }
+        if options.last_validated {
+            show_last_validated(archive, options, &monitor);
+        }
+        return Ok(());
+    }
+    let mut band_infos = archive.read_band_infos()?;
+    let damaged_bands = archive.damaged_bands().unwrap_or_else(|err| {
+        error!("Failed to read the list of damaged bands: {err}");
+        Default::default()
+    });
+    if options.newest_first {
+        band_infos.reverse();
+    }
+    if options.json {
+        return show_versions_json(archive, band_infos, &damaged_bands, options, monitor);
+    }
+    for (band_id, info) in band_infos {
         let mut l: Vec<String> = Vec::new();
         l.push(format!("{band_id:<20}"));
-        let band = match Band::open(archive, band_id) {
-            Ok(band) => band,
-            Err(err) => {
-                error!("Failed to open band {band_id:?}: {err}");
+        let info = match info {
+            Ok(info) => info,
+            Err(_) if damaged_bands.contains_key(&band_id) => {
+                monitor.clear_progress_bars();
+                println!("{band_id:<20} (damaged)");
                 continue;
             }
-        };
-        let info = match band.get_info() {
-            Ok(info) => info,
             Err(err) => {
-                error!("Failed to read band tail {band_id:?}: {err}");
+                error!("Failed to read band {band_id:?}: {err}");
                 continue;
             }
         };
@@ -114,9 +190,142 @@ pub fn show_versions(
             );
             l.push(format!("{tree_mb_str:>14}",));
         }
+
+        if options.origin {
+            if info.username.is_some() || info.hostname.is_some() {
+                l.push(format!(
+                    "{}@{}",
+                    info.username.as_deref().unwrap_or("?"),
+                    info.hostname.as_deref().unwrap_or("?")
+                ));
+            }
+            if let Some(version) = &info.conserve_version {
+                l.push(format!("conserve-{version}"));
+            }
+            l.extend(info.sources.iter().cloned());
+        }
+
+        if options.description {
+            if damaged_bands.contains_key(&band_id) {
+                l.push("(damaged)".to_owned());
+            } else if info.recovered {
+                l.push("(recovered)".to_owned());
+            }
+            if info.held {
+                l.push("(held)".to_owned());
+            }
+            if let Some(label) = &info.label {
+                l.push(format!("[{label}]"));
+            }
+            if let Some(mut expires) = info.expires {
+                if let Some(timezone) = options.timezone {
+                    expires = expires.to_offset(timezone);
+                }
+                l.push(format!("(expires {})", expires.format(&Rfc3339).unwrap()));
+            }
+            if let Some(message) = &info.message {
+                l.push(message.clone());
+            }
+        }
         monitor.clear_progress_bars(); // to avoid fighting with stdout
         println!("{}", l.join(" "));
     }
+    if options.last_validated {
+        show_last_validated(archive, options, &monitor);
+    }
+    Ok(())
+}
+
+/// Print when the archive was last validated without problems, if it has been.
+fn show_last_validated(archive: &Archive, options: &ShowVersionsOptions, monitor: &TermUiMonitor) {
+    match archive.last_validated() {
+        Ok(Some(marker)) => {
+            let mut time = marker.time;
+            if let Some(timezone) = options.timezone {
+                time = time.to_offset(timezone);
+            }
+            let mut l = format!(
+                "Archive validated OK at {} by conserve {}",
+                time.format(&Rfc3339).unwrap(),
+                marker.conserve_version
+            );
+            if marker.skip_block_hashes {
+                l.push_str(" (quick)");
+            } else if let Some(fraction) = marker.sample_fraction {
+                l.push_str(&format!(" (sampled {}% of blocks)", fraction * 100.0));
+            } else if marker.incremental {
+                l.push_str(" (incremental)");
+            }
+            monitor.clear_progress_bars();
+            println!("{l}");
+        }
+        Ok(None) => (),
+        Err(err) => error!("Failed to read when the archive was last validated: {err}"),
+    }
+}
+
+/// Print each version as a line of json.
+fn show_versions_json(
+    archive: &Archive,
+    band_infos: Vec<(BandId, Result<BandInfo>)>,
+    damaged_bands: &BTreeMap<BandId, String>,
+    options: &ShowVersionsOptions,
+    monitor: Arc<TermUiMonitor>,
+) -> Result<()> {
+    for (band_id, info) in band_infos {
+        let damaged = damaged_bands.contains_key(&band_id);
+        let version = match info {
+            Ok(info) => VersionJson {
+                band_id,
+                damaged,
+                complete: Some(info.is_closed),
+                start_time: Some(info.start_time),
+                end_time: info.end_time,
+                tree_size: if options.tree_size {
+                    Some(
+                        archive
+                            .open_stored_tree(BandSelectionPolicy::Specified(band_id))?
+                            .size(Exclude::nothing(), monitor.clone())?
+                            .file_bytes,
+                    )
+                } else {
+                    None
+                },
+                label: info.label,
+                message: info.message,
+                expires: info.expires,
+                recovered: Some(info.recovered),
+                held: Some(info.held),
+                hostname: info.hostname,
+                username: info.username,
+                conserve_version: info.conserve_version,
+                sources: info.sources,
+            },
+            Err(_) if damaged => VersionJson {
+                band_id,
+                damaged,
+                complete: None,
+                start_time: None,
+                end_time: None,
+                tree_size: None,
+                label: None,
+                message: None,
+                expires: None,
+                recovered: None,
+                held: None,
+                hostname: None,
+                username: None,
+                conserve_version: None,
+                sources: Vec::new(),
+            },
+            Err(err) => {
+                error!("Failed to read band {band_id:?}: {err}");
+                continue;
+            }
+        };
+        monitor.clear_progress_bars();
+        println!("{}", serde_json::to_string(&version)?);
+    }
     Ok(())
 }
 
@@ -149,3 +358,122 @@ pub fn show_entry_names<E: EntryTrait, I: Iterator<Item = E>>(
     }
     Ok(())
 }
+
+/// Show entries like `ls -l`: the kind and permissions, owner and group, size,
+/// mtime, and apath of each entry, followed by the target of symlinks.
+///
+/// Device nodes show their major and minor numbers in place of the size. Times are
+/// shown in `timezone`, or UTC if it's None.
+pub fn show_long_listing<E: EntryTrait, I: Iterator<Item = E>>(
+    it: I,
+    timezone: Option<UtcOffset>,
+    w: &mut dyn Write,
+) -> Result<()> {
+    let mut bw = BufWriter::new(w);
+    let mtime_format = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+    for entry in it {
+        let kind = match entry.kind() {
+            Kind::File => '-',
+            Kind::Dir => 'd',
+            Kind::Symlink => 'l',
+            Kind::CharDevice => 'c',
+            Kind::BlockDevice => 'b',
+            Kind::Fifo => 'p',
+            Kind::Socket => 's',
+            Kind::Unknown => '?',
+        };
+        let size = if let Some(DeviceNumber { major, minor }) = entry.device() {
+            format!("{major}, {minor}")
+        } else {
+            entry.size().map(|s| s.to_string()).unwrap_or_default()
+        };
+        let mut mtime = entry.mtime();
+        if let Some(timezone) = timezone {
+            mtime = mtime.to_offset(timezone);
+        }
+        write!(
+            bw,
+            "{kind}{mode} {owner} {size:>12} {mtime} {apath}",
+            mode = entry.unix_mode(),
+            owner = entry.owner(),
+            mtime = mtime.format(mtime_format).unwrap(),
+            apath = entry.apath(),
+        )?;
+        if let Some(target) = entry.symlink_target() {
+            write!(bw, " -> {target}")?;
+        }
+        writeln!(bw)?;
+    }
+    Ok(())
+}
+
+/// Show versions found by [find], grouped under each apath.
+///
+/// If `changes_only` is true, versions unchanged from the previous band are not shown.
+pub fn show_found_versions(
+    versions: &[FoundVersion],
+    timezone: Option<UtcOffset>,
+    changes_only: bool,
+    w: &mut dyn Write,
+) -> Result<()> {
+    let mut bw = BufWriter::new(w);
+    let mut last_apath: Option<&Apath> = None;
+    for version in versions {
+        if changes_only && version.change == VersionChange::Unchanged {
+            continue;
+        }
+        if last_apath != Some(&version.apath) {
+            writeln!(bw, "{}", version.apath)?;
+            last_apath = Some(&version.apath);
+        }
+        let mut mtime = version.mtime;
+        if let Some(timezone) = timezone {
+            mtime = mtime.to_offset(timezone);
+        }
+        let size = version.size.map(|s| s.to_string()).unwrap_or_default();
+        let detail: Cow<str> = if let Some(hash) = &version.content_hash {
+            hash.to_string()[..12].to_owned().into()
+        } else if let Some(target) = &version.symlink_target {
+            format!("-> {target}").into()
+        } else {
+            Cow::Borrowed("")
+        };
+        let change = match version.change {
+            VersionChange::Added => "added",
+            VersionChange::Changed => "changed",
+            VersionChange::Unchanged => "",
+        };
+        writeln!(
+            bw,
+            "  {band_id:<8} {mtime:<25} {kind:<8} {size:>12}  {detail:<12}  {change}",
+            band_id = version.band_id,
+            mtime = mtime.format(&Rfc3339).unwrap(),
+            kind = format!("{:?}", version.kind),
+        )?;
+    }
+    Ok(())
+}
+
+/// Show the space taken by each directory, as found by [disk_usage]: the apparent
+/// size of its files, then their stored size in the archive, then its apath.
+pub fn show_disk_usage(usage: &[DirUsage], bytes: bool, w: &mut dyn Write) -> Result<()> {
+    let mut bw = BufWriter::new(w);
+    let format_size = |size: u64| {
+        if bytes {
+            size.to_string()
+        } else {
+            bytes_to_human_mb(size)
+        }
+    };
+    writeln!(bw, "{:>14} {:>14}  directory", "apparent", "stored")?;
+    for dir in usage {
+        writeln!(
+            bw,
+            "{:>14} {:>14}  {}",
+            format_size(dir.apparent_bytes),
+            format_size(dir.stored_bytes),
+            dir.apath
+        )?;
+    }
+    Ok(())
+}
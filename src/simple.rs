@@ -0,0 +1,110 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! One-call backup and restore, for programs that embed Conserve and don't need
+//! to show progress.
+//!
+//! These open the archive from a URL or path, collect the problems reported while
+//! they run, and return them with a summary, rather than needing a [Monitor].
+//!
+//! ```no_run
+//! let summary = conserve::backup_paths("/backup/home.cons", "/home", &Default::default())?;
+//! println!("Stored {} files in {}", summary.stats.files, summary.band_id);
+//! let summary = conserve::restore_band("/backup/home.cons", None, "/tmp/home", Default::default())?;
+//! assert!(summary.errors.is_empty());
+//! # Ok::<(), conserve::Error>(())
+//! ```
+
+use std::path::Path;
+
+use crate::counters::Counter;
+use crate::monitor::test::TestMonitor;
+use crate::*;
+
+/// What happened in a backup made by [backup_paths].
+#[derive(Debug)]
+pub struct BackupSummary {
+    /// The band holding the new backup.
+    pub band_id: BandId,
+    pub stats: BackupStats,
+    /// Problems, such as unreadable files, that didn't stop the backup.
+    pub errors: Vec<Error>,
+}
+
+/// What happened in a restore made by [restore_band].
+#[derive(Debug)]
+pub struct RestoreSummary {
+    /// The band that was restored.
+    pub band_id: BandId,
+    pub files: usize,
+    pub file_bytes: u64,
+    pub dirs: usize,
+    pub symlinks: usize,
+    /// Problems, such as damaged blocks or files that couldn't be written, that
+    /// didn't stop the restore.
+    pub errors: Vec<Error>,
+    /// Entries that already existed in the destination.
+    pub conflicts: Vec<RestoreConflict>,
+}
+
+/// Back up a directory into a new band of an existing archive, given its URL or
+/// local path.
+pub fn backup_paths<P: AsRef<Path>>(
+    archive_url: &str,
+    source: P,
+    options: &BackupOptions,
+) -> Result<BackupSummary> {
+    let archive = Archive::open(open_transport(archive_url)?)?;
+    let monitor = TestMonitor::arc();
+    let stats = backup(&archive, source.as_ref(), options, monitor.clone())?;
+    let band_id = archive
+        .last_band_id()?
+        .expect("Archive has a band after a backup");
+    Ok(BackupSummary {
+        band_id,
+        stats,
+        errors: monitor.take_errors(),
+    })
+}
+
+/// Restore a band from an archive, given its URL or local path, into a destination
+/// directory.
+///
+/// If `band` is None, the band is chosen by `options.band_selection`, which by
+/// default is the latest complete band.
+pub fn restore_band<P: AsRef<Path>>(
+    archive_url: &str,
+    band: Option<BandId>,
+    destination: P,
+    options: RestoreOptions,
+) -> Result<RestoreSummary> {
+    let archive = Archive::open(open_transport(archive_url)?)?;
+    let band_id = match band {
+        Some(band_id) => band_id,
+        None => archive.resolve_band_id(options.band_selection.clone())?,
+    };
+    let options = RestoreOptions {
+        band_selection: BandSelectionPolicy::Specified(band_id),
+        ..options
+    };
+    let monitor = TestMonitor::arc();
+    restore(&archive, destination.as_ref(), &options, monitor.clone())?;
+    Ok(RestoreSummary {
+        band_id,
+        files: monitor.get_counter(Counter::Files),
+        file_bytes: monitor.get_counter(Counter::FileBytes) as u64,
+        dirs: monitor.get_counter(Counter::Dirs),
+        symlinks: monitor.get_counter(Counter::Symlinks),
+        errors: monitor.take_errors(),
+        conflicts: monitor.take_conflicts(),
+    })
+}
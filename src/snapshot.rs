@@ -0,0 +1,394 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Snapshots of the source filesystems, so that a backup reads a crash-consistent
+//! view of live data.
+//!
+//! Before the backup starts, each source directory is mapped to the same directory
+//! within a snapshot of the filesystem that contains it. The snapshots are deleted
+//! when the backup finishes, whether or not it succeeded.
+//!
+//! Btrfs, ZFS, and LVM snapshots are made by running the usual command-line tools,
+//! which typically needs root privileges. On Windows, Volume Shadow Copies are
+//! handled by the `vss` module.
+
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use tempfile::TempDir;
+use time::OffsetDateTime;
+use tracing::{debug, info, warn};
+
+use crate::vss::ShadowCopies;
+use crate::*;
+
+/// How to snapshot the filesystems containing the source directories before
+/// backing them up.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SnapshotMethod {
+    /// Make a read-only snapshot of the mounted Btrfs subvolume, alongside it.
+    ///
+    /// Subvolumes nested inside the mounted subvolume are not included in the snapshot.
+    Btrfs,
+    /// Make a ZFS snapshot of the dataset, and read it from the `.zfs/snapshot`
+    /// directory.
+    Zfs,
+    /// Make an LVM snapshot of the logical volume, and mount it read-only in
+    /// a temporary directory.
+    Lvm {
+        /// Space reserved for changes to the origin while the snapshot exists, like
+        /// `"10G"`, or `"20%ORIGIN"` as understood by `lvcreate --extents`; by default,
+        /// 10% of the origin volume.
+        size: Option<String>,
+    },
+}
+
+impl fmt::Display for SnapshotMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotMethod::Btrfs => write!(f, "btrfs"),
+            SnapshotMethod::Zfs => write!(f, "zfs"),
+            SnapshotMethod::Lvm { size: None } => write!(f, "lvm"),
+            SnapshotMethod::Lvm { size: Some(size) } => write!(f, "lvm:{size}"),
+        }
+    }
+}
+
+impl FromStr for SnapshotMethod {
+    type Err = Error;
+
+    /// Parse a snapshot method like `btrfs`, `zfs`, `lvm`, or `lvm:10G`.
+    fn from_str(s: &str) -> Result<SnapshotMethod> {
+        match s.split_once(':') {
+            None if s == "btrfs" => Ok(SnapshotMethod::Btrfs),
+            None if s == "zfs" => Ok(SnapshotMethod::Zfs),
+            None if s == "lvm" => Ok(SnapshotMethod::Lvm { size: None }),
+            Some(("lvm", size)) if !size.is_empty() => Ok(SnapshotMethod::Lvm {
+                size: Some(size.to_owned()),
+            }),
+            _ => Err(Error::InvalidSnapshotMethod { spec: s.to_owned() }),
+        }
+    }
+}
+
+/// Snapshots of the filesystems containing the source directories of one backup.
+///
+/// The snapshots are deleted when this is dropped, so it must outlive any reads
+/// from the snapshot paths.
+#[derive(Debug, Default)]
+pub(crate) struct SourceSnapshots {
+    method: Option<SnapshotMethod>,
+    use_vss: bool,
+    shadow_copies: ShadowCopies,
+    snapshots: Vec<FilesystemSnapshot>,
+}
+
+impl SourceSnapshots {
+    pub(crate) fn new(options: &BackupOptions) -> SourceSnapshots {
+        SourceSnapshots {
+            method: options.snapshot.clone(),
+            use_vss: options.use_vss,
+            ..Default::default()
+        }
+    }
+
+    /// Return the path from which to read a source directory: either the
+    /// directory itself, or the same directory within a snapshot.
+    ///
+    /// Each filesystem is snapshotted once, the first time a source on it is seen.
+    pub(crate) fn source_path(&mut self, path: &Path) -> Result<PathBuf> {
+        if self.use_vss {
+            return self.shadow_copies.snapshot_path(path);
+        }
+        let Some(method) = &self.method else {
+            return Ok(path.to_owned());
+        };
+        let snapshot_error = |details: String| Error::Snapshot {
+            path: path.to_owned(),
+            details,
+        };
+        let path = path
+            .canonicalize()
+            .map_err(|source| Error::ListSourceTree {
+                path: path.to_owned(),
+                source,
+            })?;
+        let mount = find_mount(&path).map_err(snapshot_error)?;
+        let relative = path
+            .strip_prefix(&mount.target)
+            .expect("source is within its mount point");
+        if let Some(snapshot) = self
+            .snapshots
+            .iter()
+            .find(|s| s.mount_point == mount.target)
+        {
+            return Ok(snapshot.root.join(relative));
+        }
+        let snapshot = FilesystemSnapshot::create(method, &mount).map_err(snapshot_error)?;
+        let source_path = snapshot.root.join(relative);
+        self.snapshots.push(snapshot);
+        Ok(source_path)
+    }
+}
+
+/// A filesystem mount, from `findmnt`.
+#[derive(Debug, Eq, PartialEq)]
+struct Mount {
+    /// Where the filesystem is mounted.
+    target: PathBuf,
+    /// The device, dataset, or other source of the filesystem.
+    source: String,
+    fstype: String,
+}
+
+/// Find the mount containing a path.
+fn find_mount(path: &Path) -> std::result::Result<Mount, String> {
+    let mut command = Command::new("findmnt");
+    command
+        .args(["--raw", "--noheadings", "--output", "TARGET,SOURCE,FSTYPE"])
+        .arg("--target")
+        .arg(path);
+    parse_findmnt(&run(&mut command)?)
+}
+
+fn parse_findmnt(output: &str) -> std::result::Result<Mount, String> {
+    let fields: Vec<&str> = output.split_whitespace().collect();
+    match fields.as_slice() {
+        [target, source, fstype] => Ok(Mount {
+            target: PathBuf::from(unescape_findmnt(target)),
+            source: unescape_findmnt(source),
+            fstype: (*fstype).to_owned(),
+        }),
+        _ => Err(format!("Unexpected output from findmnt: {output:?}")),
+    }
+}
+
+/// Decode `\xNN` escapes used by `findmnt --raw` for spaces and other special characters.
+fn unescape_findmnt(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'\\' && tail.len() >= 3 && tail[0] == b'x' {
+            if let Ok(c) = u8::from_str_radix(&String::from_utf8_lossy(&tail[1..3]), 16) {
+                bytes.push(c);
+                rest = &tail[3..];
+                continue;
+            }
+        }
+        bytes.push(b);
+        rest = tail;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// A snapshot of one filesystem.
+#[derive(Debug)]
+struct FilesystemSnapshot {
+    /// Where the original filesystem is mounted.
+    mount_point: PathBuf,
+    /// The directory corresponding to `mount_point` within the snapshot.
+    root: PathBuf,
+    /// Commands to delete the snapshot, run in order when it's dropped.
+    ///
+    /// Each step of creating the snapshot adds the command to undo it, so that a
+    /// partly created snapshot is also cleaned up.
+    cleanup: Vec<Command>,
+    /// Temporary directory on which the snapshot is mounted, if it needs to be
+    /// mounted; removed after the cleanup commands are run.
+    mount_dir: Option<TempDir>,
+}
+
+impl FilesystemSnapshot {
+    fn create(method: &SnapshotMethod, mount: &Mount) -> std::result::Result<Self, String> {
+        let name = format!(
+            "conserve-{}-{}",
+            OffsetDateTime::now_utc().unix_timestamp(),
+            std::process::id()
+        );
+        let mut snapshot = FilesystemSnapshot {
+            mount_point: mount.target.clone(),
+            root: PathBuf::new(),
+            cleanup: Vec::new(),
+            mount_dir: None,
+        };
+        let expect_fstype = |fstype: &str| {
+            if mount.fstype == fstype {
+                Ok(())
+            } else {
+                Err(format!(
+                    "{:?} has filesystem type {}, not {fstype}",
+                    mount.target, mount.fstype
+                ))
+            }
+        };
+        match method {
+            SnapshotMethod::Btrfs => {
+                expect_fstype("btrfs")?;
+                let root = mount.target.join(format!(".{name}"));
+                run(Command::new("btrfs")
+                    .args(["subvolume", "snapshot", "-r"])
+                    .arg(&mount.target)
+                    .arg(&root))?;
+                snapshot.cleanup.push(command(
+                    "btrfs",
+                    [OsStr::new("subvolume"), OsStr::new("delete"), root.as_ref()],
+                ));
+                snapshot.root = root;
+            }
+            SnapshotMethod::Zfs => {
+                expect_fstype("zfs")?;
+                let zfs_snapshot = format!("{}@{name}", mount.source);
+                run(Command::new("zfs").args(["snapshot", &zfs_snapshot]))?;
+                snapshot
+                    .cleanup
+                    .push(command("zfs", ["destroy", zfs_snapshot.as_str()]));
+                snapshot.root = mount.target.join(".zfs/snapshot").join(&name);
+            }
+            SnapshotMethod::Lvm { size } => {
+                let lvs = run(Command::new("lvs")
+                    .args(["--noheadings", "--options", "vg_name,lv_name"])
+                    .arg(&mount.source))?;
+                let (vg, lv) = match lvs.split_whitespace().collect::<Vec<_>>().as_slice() {
+                    [vg, lv] => (vg.to_string(), lv.to_string()),
+                    _ => {
+                        return Err(format!(
+                            "{:?} is not on an LVM logical volume",
+                            mount.target
+                        ))
+                    }
+                };
+                let snap_lv = format!("{lv}-{name}");
+                let mut lvcreate = Command::new("lvcreate");
+                lvcreate.args(["--snapshot", "--name", &snap_lv]);
+                match size {
+                    Some(size) if size.contains('%') => lvcreate.args(["--extents", size]),
+                    Some(size) => lvcreate.args(["--size", size]),
+                    None => lvcreate.args(["--extents", "10%ORIGIN"]),
+                };
+                run(lvcreate.arg(format!("{vg}/{lv}")))?;
+                snapshot.cleanup.push(command(
+                    "lvremove",
+                    ["--yes".to_owned(), format!("{vg}/{snap_lv}")],
+                ));
+                let mount_dir = TempDir::new().map_err(|err| err.to_string())?;
+                let root = mount_dir.path().to_owned();
+                snapshot.mount_dir = Some(mount_dir);
+                // XFS refuses to mount a snapshot with the same UUID as its origin.
+                let options = if mount.fstype == "xfs" {
+                    "ro,nouuid"
+                } else {
+                    "ro"
+                };
+                run(Command::new("mount")
+                    .args(["-o", options])
+                    .arg(format!("/dev/{vg}/{snap_lv}"))
+                    .arg(&root))?;
+                snapshot
+                    .cleanup
+                    .insert(0, command("umount", [root.as_os_str()]));
+                snapshot.root = root;
+            }
+        }
+        info!(%method, mount_point = ?mount.target, root = ?snapshot.root, "Created snapshot");
+        Ok(snapshot)
+    }
+}
+
+impl Drop for FilesystemSnapshot {
+    fn drop(&mut self) {
+        for command in &mut self.cleanup {
+            match run(command) {
+                Ok(_) => debug!(?command, "Cleaned up snapshot"),
+                Err(err) => warn!(?command, %err, "Failed to clean up snapshot"),
+            }
+        }
+    }
+}
+
+fn command<I, S>(program: &str, args: I) -> Command
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut command = Command::new(program);
+    command.args(args);
+    command
+}
+
+/// Run a command, returning its stdout, or a description of the failure.
+fn run(command: &mut Command) -> std::result::Result<String, String> {
+    debug!(?command, "Run");
+    let program: OsString = command.get_program().to_owned();
+    let output = command
+        .output()
+        .map_err(|err| format!("Failed to run {program:?}: {err}"))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(format!(
+            "{program:?} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_snapshot_methods() {
+        for spec in ["btrfs", "zfs", "lvm", "lvm:10G"] {
+            assert_eq!(
+                SnapshotMethod::from_str(spec).unwrap().to_string(),
+                spec,
+                "round trip {spec:?}"
+            );
+        }
+        assert_eq!(
+            SnapshotMethod::from_str("lvm:5%ORIGIN").unwrap(),
+            SnapshotMethod::Lvm {
+                size: Some("5%ORIGIN".to_owned())
+            }
+        );
+        for spec in ["", "ext4", "lvm:", "zfs:1G"] {
+            assert!(SnapshotMethod::from_str(spec).is_err(), "{spec:?}");
+        }
+    }
+
+    #[test]
+    fn parse_findmnt_output() {
+        assert_eq!(
+            parse_findmnt("/home/my\\x20files tank/home zfs\n").unwrap(),
+            Mount {
+                target: PathBuf::from("/home/my files"),
+                source: "tank/home".to_owned(),
+                fstype: "zfs".to_owned(),
+            }
+        );
+        assert!(parse_findmnt("").is_err());
+    }
+
+    #[test]
+    fn no_snapshot_by_default() {
+        let mut snapshots = SourceSnapshots::new(&BackupOptions::default());
+        assert_eq!(
+            snapshots.source_path(Path::new("/home")).unwrap(),
+            Path::new("/home")
+        );
+        assert!(snapshots.snapshots.is_empty());
+    }
+}
@@ -0,0 +1,207 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Sparse files: finding the holes in source files, and recreating them on restore.
+//!
+//! Holes read as zeros but aren't allocated on disk. They're recorded in the index
+//! rather than being stored as data blocks.
+
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+/// Runs of zeros are only left as holes on whole pages of this size, aligned in the file.
+const ZERO_PAGE_SIZE: usize = 4096;
+
+/// Runs of zeros shorter than this are written, to avoid fragmenting the file.
+const MIN_ZERO_RUN: usize = 64 << 10;
+
+/// A run of bytes in a file that reads as zeros and is not allocated on disk.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Hole {
+    /// Offset of the start of the hole in the file.
+    pub start: u64,
+    /// Length of the hole in bytes.
+    pub len: u64,
+}
+
+impl Hole {
+    pub fn end(&self) -> u64 {
+        self.start + self.len
+    }
+}
+
+/// Find the holes in a file of a given size, in order.
+///
+/// On platforms or filesystems that can't report holes, this returns no holes.
+///
+/// This moves the file's position, and leaves it at the start of the file.
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+pub(crate) fn find_holes(file: &File, size: u64) -> io::Result<Vec<Hole>> {
+    use std::os::fd::AsRawFd;
+
+    use nix::errno::Errno;
+    use nix::unistd::{lseek, Whence};
+
+    let fd = file.as_raw_fd();
+    let mut holes = Vec::new();
+    let mut pos: u64 = 0;
+    while pos < size {
+        let data_start = match lseek(fd, pos as i64, Whence::SeekData) {
+            Ok(offset) => (offset as u64).min(size),
+            // There is no more data after pos: the rest of the file is a hole.
+            Err(Errno::ENXIO) => size,
+            // The filesystem doesn't support finding holes.
+            Err(Errno::EINVAL) => {
+                lseek(fd, 0, Whence::SeekSet)?;
+                return Ok(Vec::new());
+            }
+            Err(errno) => return Err(errno.into()),
+        };
+        if data_start > pos {
+            holes.push(Hole {
+                start: pos,
+                len: data_start - pos,
+            });
+        }
+        if data_start >= size {
+            break;
+        }
+        pos = match lseek(fd, data_start as i64, Whence::SeekHole) {
+            Ok(offset) => offset as u64,
+            Err(errno) => return Err(errno.into()),
+        };
+    }
+    lseek(fd, 0, Whence::SeekSet)?;
+    Ok(holes)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd")))]
+pub(crate) fn find_holes(_file: &File, _size: u64) -> io::Result<Vec<Hole>> {
+    Ok(Vec::new())
+}
+
+/// Return the (start, len) ranges of data between the holes in a file of a given size.
+pub(crate) fn data_ranges(holes: &[Hole], size: u64) -> Vec<(u64, u64)> {
+    let mut ranges = Vec::with_capacity(holes.len() + 1);
+    let mut pos = 0;
+    for hole in holes {
+        if hole.start > pos {
+            ranges.push((pos, hole.start - pos));
+        }
+        pos = hole.end();
+    }
+    if size > pos {
+        ranges.push((pos, size - pos));
+    }
+    ranges
+}
+
+/// Find the long runs of zeros in data that will be written at a given offset in
+/// a file, which can be left as holes.
+///
+/// Runs are made of whole pages, aligned in the file, and are returned in order as
+/// ranges of `data`.
+pub(crate) fn zero_runs(data: &[u8], offset: u64) -> Vec<Range<usize>> {
+    let mut runs = Vec::new();
+    let mut run_start = None;
+    let mut page_start =
+        (ZERO_PAGE_SIZE - (offset % ZERO_PAGE_SIZE as u64) as usize) % ZERO_PAGE_SIZE;
+    loop {
+        let page = data.get(page_start..page_start + ZERO_PAGE_SIZE);
+        let is_zero = page.is_some_and(|page| page.iter().all(|&b| b == 0));
+        match (is_zero, run_start) {
+            (true, None) => run_start = Some(page_start),
+            (false, Some(start)) => {
+                if page_start - start >= MIN_ZERO_RUN {
+                    runs.push(start..page_start);
+                }
+                run_start = None;
+            }
+            _ => (),
+        }
+        if page.is_none() {
+            return runs;
+        }
+        page_start += ZERO_PAGE_SIZE;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn data_ranges_between_holes() {
+        assert_eq!(data_ranges(&[], 100), [(0, 100)]);
+        assert_eq!(data_ranges(&[Hole { start: 0, len: 100 }], 100), []);
+        assert_eq!(
+            data_ranges(
+                &[Hole { start: 0, len: 10 }, Hole { start: 50, len: 20 }],
+                100
+            ),
+            [(10, 40), (70, 30)]
+        );
+        assert_eq!(data_ranges(&[Hole { start: 10, len: 90 }], 100), [(0, 10)]);
+    }
+
+    #[test]
+    fn find_zero_runs() {
+        let mut data = vec![1; 1 << 20];
+        assert!(zero_runs(&data, 0).is_empty());
+        // Too short to be a hole.
+        data[..8192].fill(0);
+        assert!(zero_runs(&data, 0).is_empty());
+        data[100_000..400_000].fill(0);
+        // Rounded in to whole pages.
+        assert_eq!(zero_runs(&data, 0), vec![102_400..397_312]);
+        // Pages are aligned in the file, not in the data.
+        assert_eq!(zero_runs(&data, 100), vec![102_300..397_212]);
+        // A run can extend to the end.
+        data[900_000..].fill(0);
+        assert_eq!(zero_runs(&data, 0), [102_400..397_312, 901_120..1_048_576]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn find_holes_in_sparse_file() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let mut file = tempfile::tempfile().unwrap();
+        let mib = 1 << 20;
+        file.seek(SeekFrom::Start(4 * mib)).unwrap();
+        file.write_all(&[1; 4096]).unwrap();
+        file.set_len(8 * mib).unwrap();
+        let holes = find_holes(&file, 8 * mib).unwrap();
+        if holes.is_empty() {
+            // The temp filesystem doesn't support holes.
+            return;
+        }
+        assert_eq!(
+            holes[0],
+            Hole {
+                start: 0,
+                len: 4 * mib
+            }
+        );
+        assert_eq!(holes.last().unwrap().end(), 8 * mib);
+        assert_eq!(
+            data_ranges(&holes, 8 * mib)
+                .iter()
+                .map(|(_start, len)| len)
+                .sum::<u64>(),
+            4096
+        );
+    }
+}
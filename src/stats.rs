@@ -15,16 +15,22 @@ use std::fmt;
 use std::time::Duration;
 
 use derive_more::{Add, AddAssign};
+use serde::{Deserialize, Serialize};
 use thousands::Separable;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
 
+use crate::bandid::serialize_band_id;
+use crate::blockdir::BlockDirStats;
 use crate::misc::duration_to_hms;
+use crate::{BackupStats, BandId, Compression};
 
 pub fn mb_string(s: u64) -> String {
     (s / 1_000_000).separate_with_commas()
 }
 
 /// Describe the compression ratio: higher is better.
-fn ratio(uncompressed: u64, compressed: u64) -> f64 {
+pub(crate) fn ratio(uncompressed: u64, compressed: u64) -> f64 {
     if compressed > 0 {
         uncompressed as f64 / compressed as f64
     } else {
@@ -67,15 +73,44 @@ pub(crate) fn write_duration(
     writeln!(w, "{:>12}      {}", duration_to_hms(duration), label)
 }
 
+/// Statistics from an operation that can be recorded as JSON, for example by a
+/// program that keeps the results of its backups in a database.
+///
+/// Durations are written as an object with `secs` and `nanos` fields.
+pub trait StatsJson: Serialize {
+    /// Describe these statistics as a JSON object.
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("Serialize stats")
+    }
+}
+
+impl StatsJson for ArchiveInfo {}
+impl StatsJson for ArchiveStats {}
+impl StatsJson for BackupStats {}
+impl StatsJson for BlockDirStats {}
+impl StatsJson for CompactStats {}
+impl StatsJson for CopyBandStats {}
+impl StatsJson for DeleteStats {}
+impl StatsJson for IndexReadStats {}
+impl StatsJson for LiveTreeIterStats {}
+impl StatsJson for RecompressStats {}
+impl StatsJson for RepairStats {}
+impl StatsJson for ReplicateStats {}
+impl StatsJson for RestoreStats {}
+impl StatsJson for SalvageStats {}
+impl StatsJson for Sizes {}
+impl StatsJson for ValidateStats {}
+impl StatsJson for VerifyStats {}
+
 /// Describes sizes of data read or written, with both the
 /// compressed and uncompressed size.
-#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
 pub struct Sizes {
     pub compressed: u64,
     pub uncompressed: u64,
 }
 
-#[derive(Default, Debug, Clone, Eq, PartialEq)]
+#[derive(Default, Debug, Clone, Eq, PartialEq, Serialize)]
 pub struct IndexReadStats {
     pub index_hunks: usize,
     pub uncompressed_index_bytes: u64,
@@ -83,21 +118,47 @@ pub struct IndexReadStats {
     pub errors: usize,
 }
 
-#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[derive(Debug, Default, Clone, Eq, PartialEq, Serialize)]
 pub struct LiveTreeIterStats {
     pub directories_visited: usize,
     pub exclusions: usize,
+    /// Directories skipped because they contain a `CACHEDIR.TAG` file.
+    pub cachedirs_skipped: usize,
+    /// Directories skipped because they contain a marker file named by
+    /// [crate::Exclude::with_exclude_if_present].
+    pub marked_dirs_skipped: usize,
+    /// Files skipped because they're larger than the limit set by
+    /// [crate::Exclude::with_exclude_larger_than].
+    pub large_files_skipped: usize,
+    /// Total size of large files that were skipped.
+    pub large_file_bytes_skipped: u64,
+    /// Files skipped because they were last modified before the cutoff set by
+    /// [crate::Exclude::with_exclude_modified_before].
+    pub old_files_skipped: usize,
+    /// Mount points whose contents were skipped because of
+    /// [crate::Exclude::with_one_file_system].
+    pub mount_points_skipped: usize,
     pub metadata_error: usize,
     pub entries_returned: usize,
 }
 
-#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
 pub struct DeleteStats {
     pub deleted_band_count: usize,
     pub unreferenced_block_count: usize,
     pub unreferenced_block_bytes: u64,
+    /// Unreferenced blocks that were kept because they're newer than
+    /// [crate::DeleteOptions::min_block_age].
+    pub recent_block_count: usize,
+    /// Unreferenced blocks that are old enough to delete: in a dry run, those that
+    /// would have been deleted.
+    pub reclaimable_block_count: usize,
+    /// Compressed size of the reclaimable blocks.
+    pub reclaimable_block_bytes: u64,
     pub deletion_errors: usize,
     pub deleted_block_count: usize,
+    /// Compressed size of the blocks that were deleted: the space reclaimed.
+    pub deleted_block_bytes: u64,
     pub elapsed: Duration,
 }
 
@@ -110,7 +171,11 @@ impl fmt::Display for DeleteStats {
 
         write_count(w, "unreferenced blocks", self.unreferenced_block_count);
         write_size(w, "  unreferenced", self.unreferenced_block_bytes);
+        write_count(w, "  too recent to delete", self.recent_block_count);
+        write_count(w, "  can be deleted", self.reclaimable_block_count);
+        write_size(w, "  can be reclaimed", self.reclaimable_block_bytes);
         write_count(w, "  deleted", self.deleted_block_count);
+        write_size(w, "  reclaimed", self.deleted_block_bytes);
         writeln!(w)?;
 
         write_count(w, "deletion errors", self.deletion_errors);
@@ -121,3 +186,501 @@ impl fmt::Display for DeleteStats {
         Ok(())
     }
 }
+
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct CompactStats {
+    pub loose_blocks: usize,
+    /// Blocks chosen to be packed, including in a dry run.
+    pub packable_blocks: usize,
+    pub packed_blocks: usize,
+    pub removed_duplicates: usize,
+    pub errors: usize,
+    /// Packs that the chosen blocks are grouped into, including in a dry run.
+    pub packs_to_write: usize,
+    /// Compressed size of the blocks chosen to be packed.
+    pub packable_bytes: u64,
+    pub packs_written: usize,
+    pub packed_bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for CompactStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(w, "compact stats",)?;
+
+        write_count(w, "block files", self.loose_blocks);
+        write_count(w, "  can be packed", self.packable_blocks);
+        write_count(w, "  packed", self.packed_blocks);
+        write_count(w, "  already packed", self.removed_duplicates);
+        write_count(w, "  errors", self.errors);
+        writeln!(w)?;
+
+        write_count(w, "packs to write", self.packs_to_write);
+        write_size(w, "  size to pack", self.packable_bytes);
+        write_count(w, "packs written", self.packs_written);
+        write_size(w, "  packed size", self.packed_bytes);
+        writeln!(w)?;
+
+        write_duration(w, "elapsed", self.elapsed)?;
+
+        Ok(())
+    }
+}
+
+/// Statistics from copying a band to another archive, by [crate::Archive::copy_band_to].
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub struct CopyBandStats {
+    /// Id of the new band in the destination archive.
+    #[serde(serialize_with = "serialize_band_id")]
+    pub band_id: BandId,
+    /// Distinct blocks referenced by the band.
+    pub blocks: usize,
+    /// Blocks that were already present in the destination.
+    pub deduplicated_blocks: usize,
+    /// Blocks copied to the destination.
+    pub copied_blocks: usize,
+    /// Compressed size of the copied blocks, as written to the destination.
+    pub copied_block_bytes: u64,
+    pub index_hunks: usize,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for CopyBandStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(w, "copy band stats",)?;
+
+        writeln!(w, "{:>12}      new band", self.band_id.to_string())?;
+        write_count(w, "index hunks", self.index_hunks);
+        writeln!(w)?;
+
+        write_count(w, "blocks", self.blocks);
+        write_count(w, "  already present", self.deduplicated_blocks);
+        write_count(w, "  copied", self.copied_blocks);
+        write_size(w, "  copied size", self.copied_block_bytes);
+        writeln!(w)?;
+
+        write_duration(w, "elapsed", self.elapsed)?;
+
+        Ok(())
+    }
+}
+
+/// Statistics from [crate::replicate].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct ReplicateStats {
+    /// Complete bands in the source archive.
+    pub bands: usize,
+    /// Source bands that were already in the destination.
+    pub present_bands: usize,
+    /// Source bands copied to the destination.
+    pub copied_bands: usize,
+    /// Copied bands that finished a copy that was interrupted earlier.
+    pub resumed_bands: usize,
+    /// Incomplete bands in the source, which were not copied.
+    pub incomplete_bands: usize,
+    /// Distinct blocks referenced by each copied band, summed over the bands.
+    pub blocks: usize,
+    /// Blocks that were already present in the destination.
+    pub deduplicated_blocks: usize,
+    /// Blocks copied to the destination.
+    pub copied_blocks: usize,
+    /// Compressed size of the copied blocks, as written to the destination.
+    pub copied_block_bytes: u64,
+    pub index_hunks: usize,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for ReplicateStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(w, "replicate stats",)?;
+
+        write_count(w, "bands", self.bands);
+        write_count(w, "  already present", self.present_bands);
+        write_count(w, "  copied", self.copied_bands);
+        write_count(w, "  resumed", self.resumed_bands);
+        write_count(w, "incomplete bands skipped", self.incomplete_bands);
+        write_count(w, "index hunks", self.index_hunks);
+        writeln!(w)?;
+
+        write_count(w, "blocks", self.blocks);
+        write_count(w, "  already present", self.deduplicated_blocks);
+        write_count(w, "  copied", self.copied_blocks);
+        write_size(w, "  copied size", self.copied_block_bytes);
+        writeln!(w)?;
+
+        write_duration(w, "elapsed", self.elapsed)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct RecompressStats {
+    pub blocks: usize,
+    pub rewritten_blocks: usize,
+    pub errors: usize,
+    pub old_compressed_bytes: u64,
+    pub new_compressed_bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for RecompressStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(w, "recompress stats",)?;
+
+        write_count(w, "blocks", self.blocks);
+        write_count(w, "  rewritten", self.rewritten_blocks);
+        write_count(w, "  errors", self.errors);
+        writeln!(w)?;
+
+        write_size(w, "compressed before", self.old_compressed_bytes);
+        write_size(w, "compressed after", self.new_compressed_bytes);
+        writeln!(w)?;
+
+        write_duration(w, "elapsed", self.elapsed)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct RepairStats {
+    pub bands: usize,
+    pub repaired_bands: usize,
+    pub replaced_heads: usize,
+    pub removed_index_hunks: usize,
+    pub rewritten_tails: usize,
+    pub errors: usize,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for RepairStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(w, "repair stats",)?;
+
+        write_count(w, "bands", self.bands);
+        write_count(w, "  repaired", self.repaired_bands);
+        write_count(w, "  errors", self.errors);
+        writeln!(w)?;
+
+        write_count(w, "replaced band heads", self.replaced_heads);
+        write_count(w, "removed index hunks", self.removed_index_hunks);
+        write_count(w, "rewritten band tails", self.rewritten_tails);
+        writeln!(w)?;
+
+        write_duration(w, "elapsed", self.elapsed)?;
+
+        Ok(())
+    }
+}
+
+/// Counts of entries written by [crate::restore].
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct RestoreStats {
+    pub files: usize,
+    /// Content bytes of the files written.
+    pub file_bytes: u64,
+    /// Files restored as hard links to another restored file.
+    pub hardlinks: usize,
+    /// Files whose content was cloned or copied within the filesystem, from the
+    /// original file or from uncompressed blocks, rather than written from blocks.
+    pub copied_files: usize,
+    pub dirs: usize,
+    pub symlinks: usize,
+    /// Devices, fifos, and sockets.
+    pub special_files: usize,
+    /// Files already present and unchanged in the destination, which weren't written again.
+    pub unchanged_files: usize,
+    /// Entries kept as they were in the destination, because of the overwrite policy.
+    pub skipped: usize,
+    /// Entries that couldn't be restored.
+    pub errors: usize,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for RestoreStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(w, "restore stats",)?;
+
+        write_count(w, "files", self.files);
+        write_size(w, "  content", self.file_bytes);
+        write_count(w, "  hard links", self.hardlinks);
+        write_count(w, "  copied in place", self.copied_files);
+        write_count(w, "  unchanged", self.unchanged_files);
+        write_count(w, "directories", self.dirs);
+        write_count(w, "symlinks", self.symlinks);
+        write_count(w, "special files", self.special_files);
+        writeln!(w)?;
+
+        write_count(w, "skipped", self.skipped);
+        write_count(w, "errors", self.errors);
+        writeln!(w)?;
+
+        write_duration(w, "elapsed", self.elapsed)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct SalvageStats {
+    pub bands: usize,
+    pub unreadable_index_hunks: usize,
+    pub recovered_entries: usize,
+    pub lost_entries: usize,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for SalvageStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(w, "salvage stats",)?;
+
+        write_count(w, "bands", self.bands);
+        write_count(w, "  unreadable index hunks", self.unreadable_index_hunks);
+        writeln!(w)?;
+
+        write_count(w, "recovered entries", self.recovered_entries);
+        write_count(w, "lost entries", self.lost_entries);
+        writeln!(w)?;
+
+        write_duration(w, "elapsed", self.elapsed)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ValidateStats {
+    /// Bands whose metadata and index were checked.
+    pub bands: usize,
+    /// Bands skipped because an earlier incremental validation found them valid.
+    pub skipped_bands: usize,
+    pub damaged_bands: usize,
+    /// Blocks whose content was read and matched their hash.
+    pub blocks: usize,
+    /// Blocks that were present but not read.
+    pub skipped_blocks: usize,
+    /// Referenced blocks that are missing, unreadable, or shorter than the data referenced.
+    pub missing_blocks: usize,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for ValidateStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(w, "validate stats",)?;
+
+        write_count(w, "bands", self.bands);
+        write_count(w, "  skipped", self.skipped_bands);
+        write_count(w, "  damaged", self.damaged_bands);
+        writeln!(w)?;
+
+        write_count(w, "blocks", self.blocks);
+        write_count(w, "  skipped", self.skipped_blocks);
+        write_count(w, "  missing or damaged", self.missing_blocks);
+        writeln!(w)?;
+
+        write_duration(w, "elapsed", self.elapsed)?;
+
+        Ok(())
+    }
+}
+
+/// The results of comparing a stored tree to a live tree with [crate::verify].
+#[derive(Add, AddAssign, Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct VerifyStats {
+    /// Entries in either tree.
+    pub entries: usize,
+    /// Files whose content was compared, because they're the same size in both trees.
+    pub files: usize,
+    /// Total size of the files whose content was compared.
+    pub file_bytes: u64,
+    /// Entries that were added or removed, or whose metadata changed.
+    pub metadata_drift: usize,
+    /// Files whose content changed, though their size didn't.
+    pub content_drift: usize,
+    /// Files that couldn't be compared, because of errors reading them.
+    pub errors: usize,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for VerifyStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(w, "verify stats",)?;
+
+        write_count(w, "entries", self.entries);
+        write_count(w, "  metadata changed", self.metadata_drift);
+        writeln!(w)?;
+
+        write_count(w, "files compared", self.files);
+        write_size(w, "  size", self.file_bytes);
+        write_count(w, "  content changed", self.content_drift);
+        write_count(w, "  errors", self.errors);
+        writeln!(w)?;
+
+        write_duration(w, "elapsed", self.elapsed)?;
+
+        Ok(())
+    }
+}
+
+/// A summary of an archive, from [crate::Archive::info].
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ArchiveInfo {
+    /// The archive format version, from its header.
+    pub format_version: String,
+    /// Compression for new blocks, unless a backup chooses otherwise.
+    pub compression: Compression,
+    /// True if the archive has a zstd dictionary for small files.
+    pub zstd_dictionary: bool,
+    pub bands: usize,
+    /// Bands that aren't complete, because their backup is still running or was
+    /// interrupted.
+    pub incomplete_bands: usize,
+    /// Start time of the first band.
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub first_backup_time: Option<OffsetDateTime>,
+    /// Start time of the last band.
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub last_backup_time: Option<OffsetDateTime>,
+    /// Blocks present, whether they're referenced or not.
+    pub blocks: usize,
+    /// Compressed size of all the blocks.
+    pub stored_bytes: u64,
+    /// Uncompressed size of all the blocks, estimated from a sample of them.
+    ///
+    /// This is the size of the distinct content in the archive, after
+    /// deduplication.
+    pub estimated_content_bytes: u64,
+    /// Bands or blocks that couldn't be read.
+    pub errors: usize,
+}
+
+impl fmt::Display for ArchiveInfo {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = |t: Option<OffsetDateTime>| {
+            t.and_then(|t| t.format(&Rfc3339).ok())
+                .unwrap_or_else(|| "none".to_owned())
+        };
+        writeln!(w, "{:<24}{}", "archive format", self.format_version)?;
+        let dictionary = if self.zstd_dictionary {
+            ", with zstd dictionary"
+        } else {
+            ""
+        };
+        writeln!(w, "{:<24}{}{dictionary}", "compression", self.compression)?;
+        writeln!(
+            w,
+            "{:<24}{} ({} incomplete)",
+            "bands",
+            self.bands.separate_with_commas(),
+            self.incomplete_bands.separate_with_commas()
+        )?;
+        writeln!(w, "{:<24}{}", "first backup", time(self.first_backup_time))?;
+        writeln!(w, "{:<24}{}", "last backup", time(self.last_backup_time))?;
+        writeln!(w, "{:<24}{}", "blocks", self.blocks.separate_with_commas())?;
+        writeln!(
+            w,
+            "{:<24}{} MB",
+            "stored size",
+            mb_string(self.stored_bytes)
+        )?;
+        writeln!(
+            w,
+            "{:<24}{} MB (estimated)",
+            "unique content size",
+            mb_string(self.estimated_content_bytes)
+        )?;
+        if self.errors > 0 {
+            writeln!(w, "{:<24}{}", "errors", self.errors.separate_with_commas())?;
+        }
+        Ok(())
+    }
+}
+
+/// How much of one band's content is in blocks that no earlier band references.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct BandDedupStats {
+    #[serde(serialize_with = "serialize_band_id")]
+    pub band_id: BandId,
+    /// Distinct blocks referenced by this band.
+    pub blocks: usize,
+    /// Blocks referenced by this band and not by any earlier band.
+    pub new_blocks: usize,
+    /// Total length of the file content in this band.
+    pub referenced_bytes: u64,
+    /// Uncompressed length of the new blocks, as far as this band references them.
+    pub new_bytes: u64,
+}
+
+/// Archive-wide totals of how much content is stored, and how well it's
+/// deduplicated, as found by [crate::archive_stats].
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct ArchiveStats {
+    /// Statistics for each band whose index could be read, oldest first.
+    pub bands: Vec<BandDedupStats>,
+    /// Distinct blocks referenced by any band.
+    pub unique_blocks: usize,
+    /// Total length of the file content in all bands.
+    pub referenced_bytes: u64,
+    /// Uncompressed length of the referenced blocks, counting each block once.
+    pub unique_bytes: u64,
+    /// Compressed size of the referenced blocks in the archive.
+    pub stored_bytes: u64,
+    /// Index hunks or blocks that couldn't be read or measured.
+    pub errors: usize,
+}
+
+impl ArchiveStats {
+    /// How many times over the content of all bands would fill the distinct blocks
+    /// holding it: higher is better.
+    pub fn dedup_ratio(&self) -> f64 {
+        ratio(self.referenced_bytes, self.unique_bytes)
+    }
+}
+
+impl fmt::Display for ArchiveStats {
+    fn fmt(&self, w: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(w, "archive stats")?;
+
+        write_count(w, "bands", self.bands.len());
+        write_count(w, "unique blocks", self.unique_blocks);
+        write_size(w, "content of all bands", self.referenced_bytes);
+        write_size(
+            w,
+            &format!(
+                "in unique blocks, after {:.1}x deduplication",
+                self.dedup_ratio()
+            ),
+            self.unique_bytes,
+        );
+        write_size(
+            w,
+            &format!(
+                "stored, after {:.1}x compression",
+                ratio(self.unique_bytes, self.stored_bytes)
+            ),
+            self.stored_bytes,
+        );
+        write_count(w, "errors", self.errors);
+        writeln!(w)?;
+
+        writeln!(
+            w,
+            "{:<10} {:>12} {:>12} {:>12} {:>12}",
+            "band", "blocks", "new blocks", "content MB", "new MB"
+        )?;
+        for band in &self.bands {
+            writeln!(
+                w,
+                "{:<10} {:>12} {:>12} {:>12} {:>12}",
+                band.band_id.to_string(),
+                band.blocks.separate_with_commas(),
+                band.new_blocks.separate_with_commas(),
+                mb_string(band.referenced_bytes),
+                mb_string(band.new_bytes),
+            )?;
+        }
+
+        Ok(())
+    }
+}
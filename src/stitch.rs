@@ -30,7 +30,7 @@
 
 use std::sync::Arc;
 
-use tracing::trace;
+use tracing::{trace, warn};
 
 use crate::index::{IndexEntryIter, IndexHunkIter};
 use crate::monitor::Monitor;
@@ -44,6 +44,10 @@ pub struct IterStitchedIndexHunks {
 
     state: State,
 
+    /// Skip forward in each band's index to this apath, because nothing
+    /// before it is wanted.
+    seek: Option<Apath>,
+
     monitor: Arc<dyn Monitor>,
 }
 
@@ -84,6 +88,7 @@ impl IterStitchedIndexHunks {
             archive: archive.clone(),
             last_apath: None,
             state: State::BeforeBand(band_id),
+            seek: None,
             monitor,
         }
     }
@@ -93,6 +98,7 @@ impl IterStitchedIndexHunks {
             archive: archive.clone(),
             last_apath: None,
             state: State::Done,
+            seek: None,
             monitor,
         }
     }
@@ -102,7 +108,16 @@ impl IterStitchedIndexHunks {
         subtree: Apath,
         exclude: Exclude,
     ) -> IndexEntryIter<IterStitchedIndexHunks> {
-        IndexEntryIter::new(self, subtree, exclude)
+        IndexEntryIter::new(self.seek_to_subtree(&subtree), subtree, exclude)
+    }
+
+    /// Skip the hunks in each band that only hold entries before this subtree.
+    #[must_use]
+    pub(crate) fn seek_to_subtree(mut self, subtree: &Apath) -> IterStitchedIndexHunks {
+        if *subtree != Apath::root() {
+            self.seek = Some(subtree.clone());
+        }
+        self
     }
 }
 
@@ -133,10 +148,14 @@ impl Iterator for IterStitchedIndexHunks {
                     // Start reading this new index and skip forward until after last_apath
                     match Band::open(&self.archive, *band_id) {
                         Ok(band) => {
-                            let mut index_hunks = band.index().iter_hunks();
+                            let mut index_hunks =
+                                band.index().iter_hunks().with_monitor(self.monitor.clone());
                             if let Some(last) = &self.last_apath {
                                 index_hunks = index_hunks.advance_to_after(last)
                             }
+                            if let Some(seek) = &self.seek {
+                                index_hunks = index_hunks.seek_to(seek)
+                            }
                             State::InBand {
                                 band_id: *band_id,
                                 index_hunks,
@@ -170,6 +189,41 @@ impl Iterator for IterStitchedIndexHunks {
     }
 }
 
+/// Find the entry for one apath in the stitched index of a band, reading only the
+/// hunks that might hold it.
+///
+/// As when iterating, if the band is incomplete and its index stops before the
+/// apath, the entry is looked up in the previous band, and so on back to a
+/// complete band. Bands that can't be opened are skipped.
+pub(crate) fn lookup_stitched(
+    archive: &Archive,
+    mut band_id: BandId,
+    apath: &Apath,
+) -> Result<Option<IndexEntry>> {
+    loop {
+        match Band::open(archive, band_id) {
+            Ok(band) => {
+                let index = band.index();
+                if let Some(entry) = index.lookup(apath)? {
+                    return Ok(Some(entry));
+                }
+                if archive.band_is_closed(band_id).unwrap_or(false) {
+                    return Ok(None);
+                }
+                // An incomplete index that got past this apath doesn't have it.
+                if index.last_apath()?.is_some_and(|last| last >= *apath) {
+                    return Ok(None);
+                }
+            }
+            Err(err) => warn!(?band_id, ?err, "Failed to open band while looking up entry"),
+        }
+        match previous_existing_band(archive, band_id) {
+            Some(prev_band_id) => band_id = prev_band_id,
+            None => return Ok(None),
+        }
+    }
+}
+
 fn previous_existing_band(archive: &Archive, mut band_id: BandId) -> Option<BandId> {
     loop {
         // TODO: It might be faster to list the present bands and calculate
@@ -203,6 +257,11 @@ mod test {
             addrs: Vec::new(),
             unix_mode: Default::default(),
             owner: Default::default(),
+            acls: Default::default(),
+            capability: None,
+            link_group: None,
+            holes: Vec::new(),
+            device: None,
         }
     }
 
@@ -316,6 +375,23 @@ mod test {
             "/0:b5 /00:b5 /2:b2 /3:b1"
         );
 
+        // Looking up single entries finds the same versions.
+        let lookup = |band: u32, apath: &str| {
+            lookup_stitched(&archive, BandId::new(&[band]), &apath.into())
+                .unwrap()
+                .map(|entry| entry.target.unwrap())
+        };
+        assert_eq!(lookup(0, "/2").as_deref(), Some("b0"));
+        assert_eq!(lookup(0, "/3"), None);
+        assert_eq!(lookup(2, "/1"), None);
+        assert_eq!(lookup(2, "/3").as_deref(), Some("b1"));
+        assert_eq!(lookup(4, "/0").as_deref(), Some("b2"));
+        assert_eq!(lookup(5, "/00").as_deref(), Some("b5"));
+        assert_eq!(lookup(5, "/1"), None);
+        assert_eq!(lookup(5, "/2").as_deref(), Some("b2"));
+        assert_eq!(lookup(5, "/3").as_deref(), Some("b1"));
+        assert_eq!(lookup(5, "/4"), None);
+
         Ok(())
     }
 
@@ -18,10 +18,24 @@
 //! across incremental backups, hiding from the caller that data may be distributed across
 //! multiple index files, bands, and blocks.
 
+use std::io::{self, Read, Seek, SeekFrom};
+#[cfg(feature = "async")]
+use std::pin::Pin;
 use std::sync::Arc;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
 
+use bytes::{Buf, Bytes};
+#[cfg(feature = "async")]
+use futures::channel::oneshot;
+#[cfg(feature = "async")]
+use futures::io::AsyncRead;
+#[cfg(feature = "async")]
+use futures::{ready, FutureExt};
+
+use crate::blockdir::Address;
 use crate::monitor::Monitor;
-use crate::stitch::IterStitchedIndexHunks;
+use crate::stitch::{lookup_stitched, IterStitchedIndexHunks};
 use crate::*;
 
 /// Read index and file contents for a version stored in the archive.
@@ -52,6 +66,285 @@ impl StoredTree {
     pub fn block_dir(&self) -> &BlockDir {
         &self.block_dir
     }
+
+    /// Find the entry for one apath, if it's in this tree.
+    ///
+    /// Only the index hunks that might hold the entry are read, using the apath
+    /// order of the index, so this is much faster than iterating the tree. If the
+    /// band is incomplete, earlier bands are searched as for [ReadTree::iter_entries].
+    pub fn lookup(&self, apath: &Apath) -> Result<Option<IndexEntry>> {
+        lookup_stitched(&self.archive, self.band.id(), apath)
+    }
+
+    /// Return a stream of the entries in a subtree, in apath order, with errors
+    /// reading the index returned in the stream rather than only reported to the
+    /// monitor.
+    ///
+    /// Index hunks are read on a background thread, which reads at most `readahead`
+    /// hunks ahead of the consumer, so very large trees can be processed in
+    /// constant memory. Dropping the stream stops the reader.
+    #[cfg(feature = "async")]
+    pub fn entry_stream(
+        &self,
+        subtree: Apath,
+        exclude: Exclude,
+        readahead: usize,
+        monitor: Arc<dyn Monitor>,
+    ) -> IndexEntryStream {
+        IndexEntryStream::new(
+            &self.archive,
+            self.band.id(),
+            subtree,
+            exclude,
+            readahead,
+            monitor,
+        )
+    }
+
+    /// Open a stored file to read its content, fetching its blocks as they're needed.
+    pub fn open_file(&self, apath: &Apath, monitor: Arc<dyn Monitor>) -> Result<StoredFile> {
+        let entry = self
+            .lookup(apath)?
+            .ok_or_else(|| Error::StoredFileNotFound {
+                apath: apath.clone(),
+            })?;
+        self.open_entry(entry, monitor)
+    }
+
+    /// Open the content of a file entry from this tree, such as one returned by
+    /// [ReadTree::iter_entries].
+    pub fn open_entry(&self, entry: IndexEntry, monitor: Arc<dyn Monitor>) -> Result<StoredFile> {
+        if entry.kind() != Kind::File {
+            return Err(Error::NotAFile {
+                kind: entry.kind(),
+                apath: entry.apath,
+            });
+        }
+        Ok(StoredFile {
+            entry,
+            block_dir: self.block_dir.clone(),
+            monitor,
+            next_addr: 0,
+            block: Bytes::new(),
+            buf: Bytes::new(),
+            skip: 0,
+            pos: 0,
+            next_hole: 0,
+            #[cfg(feature = "async")]
+            pending: None,
+        })
+    }
+}
+
+/// Reads the content of a file stored in the archive.
+///
+/// Blocks are fetched and decompressed as they're needed, so only one block of the
+/// file is held in memory at a time. With the `async` feature, the file can also be
+/// read asynchronously, in which case blocks are read on the rayon thread pool.
+///
+/// Holes in sparse files are read as zeros.
+pub struct StoredFile {
+    entry: IndexEntry,
+    block_dir: Arc<BlockDir>,
+    monitor: Arc<dyn Monitor>,
+    /// Index in the entry of the next address to read.
+    next_addr: usize,
+    /// Content of the address before `next_addr`, so that seeking within it doesn't
+    /// read it again.
+    block: Bytes,
+    /// Content of the current address that has not been returned yet.
+    buf: Bytes,
+    /// Bytes to skip from the start of the next address, after seeking into it.
+    skip: usize,
+    /// Position in the file of the next byte to return.
+    pos: u64,
+    /// Index in the entry of the first hole not yet passed.
+    next_hole: usize,
+    /// The next address, being read by an asynchronous read.
+    #[cfg(feature = "async")]
+    pending: Option<oneshot::Receiver<io::Result<Bytes>>>,
+}
+
+impl StoredFile {
+    /// The index entry describing this file.
+    pub fn entry(&self) -> &IndexEntry {
+        &self.entry
+    }
+
+    /// Copy content that's already available into `out`, returning None if the next
+    /// address must be read first.
+    fn read_available(&mut self, out: &mut [u8]) -> Option<usize> {
+        if out.is_empty() {
+            return Some(0);
+        }
+        loop {
+            let hole = self.entry.holes.get(self.next_hole);
+            if let Some(hole) = hole {
+                if hole.end() <= self.pos {
+                    self.next_hole += 1;
+                    continue;
+                } else if hole.start <= self.pos {
+                    let len = (hole.end() - self.pos).min(out.len() as u64) as usize;
+                    out[..len].fill(0);
+                    self.pos += len as u64;
+                    return Some(len);
+                }
+            }
+            if self.buf.is_empty() {
+                if self.next_addr < self.entry.addrs.len() {
+                    return None;
+                }
+                return Some(0);
+            }
+            let mut len = self.buf.len().min(out.len());
+            if let Some(hole) = hole {
+                len = len.min((hole.start - self.pos) as usize);
+            }
+            out[..len].copy_from_slice(&self.buf[..len]);
+            self.buf.advance(len);
+            self.pos += len as u64;
+            return Some(len);
+        }
+    }
+
+    /// Record the content of the next address, once it's been read.
+    fn set_block(&mut self, block: Bytes) {
+        self.buf = block.slice(self.skip.min(block.len())..);
+        self.block = block;
+        self.skip = 0;
+        self.next_addr += 1;
+    }
+
+    /// Move to a position in the file, without reading anything yet.
+    fn seek_to(&mut self, pos: u64) {
+        self.pos = pos;
+        #[cfg(feature = "async")]
+        {
+            self.pending = None;
+        }
+        let holes = &self.entry.holes;
+        self.next_hole = holes.partition_point(|hole| hole.end() <= pos);
+        let hole_bytes: u64 = holes[..self.next_hole]
+            .iter()
+            .map(|hole| hole.len)
+            .sum::<u64>()
+            + holes
+                .get(self.next_hole)
+                .map_or(0, |hole| pos.saturating_sub(hole.start));
+        // The position within the concatenated content of the addresses.
+        let mut data_pos = pos - hole_bytes;
+        for (i, addr) in self.entry.addrs.iter().enumerate() {
+            if data_pos < addr.len {
+                if i + 1 == self.next_addr && !self.block.is_empty() {
+                    self.buf = self.block.slice(data_pos as usize..);
+                    self.skip = 0;
+                } else {
+                    self.next_addr = i;
+                    self.block = Bytes::new();
+                    self.buf = Bytes::new();
+                    self.skip = data_pos as usize;
+                }
+                return;
+            }
+            data_pos -= addr.len;
+        }
+        self.next_addr = self.entry.addrs.len();
+        self.block = Bytes::new();
+        self.buf = Bytes::new();
+        self.skip = 0;
+    }
+}
+
+/// Read the content of one address of a stored file.
+fn read_file_address(
+    block_dir: &BlockDir,
+    apath: &Apath,
+    addr: &Address,
+    monitor: Arc<dyn Monitor>,
+) -> io::Result<Bytes> {
+    block_dir.read_address(addr, monitor).map_err(|source| {
+        io::Error::other(Error::RestoreFileBlock {
+            apath: apath.clone(),
+            hash: addr.hash.clone(),
+            source: Box::new(source),
+        })
+    })
+}
+
+impl Read for StoredFile {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(len) = self.read_available(out) {
+                return Ok(len);
+            }
+            let block = read_file_address(
+                &self.block_dir,
+                &self.entry.apath,
+                &self.entry.addrs[self.next_addr],
+                self.monitor.clone(),
+            )?;
+            self.set_block(block);
+        }
+    }
+}
+
+impl Seek for StoredFile {
+    /// Move to a position in the file.
+    ///
+    /// Seeking past the end is allowed, and then reads return nothing.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self
+                .entry
+                .size()
+                .unwrap_or_default()
+                .checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek position"))?;
+        if new_pos != self.pos {
+            self.seek_to(new_pos);
+        }
+        Ok(new_pos)
+    }
+}
+
+#[cfg(feature = "async")]
+impl AsyncRead for StoredFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(len) = this.read_available(out) {
+                return Poll::Ready(Ok(len));
+            }
+            let pending = this.pending.get_or_insert_with(|| {
+                let (sender, receiver) = oneshot::channel();
+                let block_dir = this.block_dir.clone();
+                let apath = this.entry.apath.clone();
+                let addr = this.entry.addrs[this.next_addr].clone();
+                let monitor = this.monitor.clone();
+                rayon::spawn(move || {
+                    // If the file was dropped, nobody wants the content.
+                    let _ = sender.send(read_file_address(&block_dir, &apath, &addr, monitor));
+                });
+                receiver
+            });
+            let result = ready!(pending.poll_unpin(cx));
+            this.pending = None;
+            match result {
+                Ok(Ok(block)) => this.set_block(block),
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(oneshot::Canceled) => {
+                    return Poll::Ready(Err(io::Error::other("Block read was abandoned")))
+                }
+            }
+        }
+    }
 }
 
 impl ReadTree for StoredTree {
@@ -124,6 +417,54 @@ mod test {
         );
     }
 
+    #[test]
+    fn open_file() {
+        use std::io::Read;
+
+        let archive = Archive::open_path(Path::new("testdata/archive/minimal/v0.6.3/")).unwrap();
+        let st = archive
+            .open_stored_tree(BandSelectionPolicy::Latest)
+            .unwrap();
+        let monitor = TestMonitor::arc();
+
+        let mut file = st
+            .open_file(&"/subdir/subfile".into(), monitor.clone())
+            .unwrap();
+        assert_eq!(file.entry().size(), Some(12));
+        let mut content = String::new();
+        file.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "I like Rust\n");
+
+        assert!(matches!(
+            st.open_file(&"/nonexistent".into(), monitor.clone()),
+            Err(Error::StoredFileNotFound { .. })
+        ));
+        assert!(matches!(
+            st.open_file(&"/subdir".into(), monitor.clone()),
+            Err(Error::NotAFile {
+                kind: Kind::Dir,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn lookup() {
+        let archive = Archive::open_path(Path::new("testdata/archive/minimal/v0.6.3/")).unwrap();
+        let st = archive
+            .open_stored_tree(BandSelectionPolicy::Latest)
+            .unwrap();
+
+        let entry = st.lookup(&"/subdir/subfile".into()).unwrap().unwrap();
+        assert_eq!(entry.apath, "/subdir/subfile");
+        assert_eq!(entry.kind(), Kind::File);
+        assert_eq!(
+            st.lookup(&"/subdir".into()).unwrap().unwrap().kind(),
+            Kind::Dir
+        );
+        assert!(st.lookup(&"/nothing".into()).unwrap().is_none());
+    }
+
     #[test]
     fn iter_entries() {
         let archive = Archive::open_path(Path::new("testdata/archive/minimal/v0.6.3/")).unwrap();
@@ -140,4 +481,21 @@ mod test {
 
         assert_eq!(names.as_slice(), ["/subdir", "/subdir/subfile"]);
     }
+
+    #[test]
+    fn iter_entries_of_one_kind() {
+        let archive = Archive::open_path(Path::new("testdata/archive/minimal/v0.6.3/")).unwrap();
+        let st = archive
+            .open_stored_tree(BandSelectionPolicy::Latest)
+            .unwrap();
+
+        let exclude = Exclude::nothing().with_only_kinds(Some(vec![Kind::File]));
+        let names: Vec<String> = st
+            .iter_entries(Apath::root(), exclude, TestMonitor::arc())
+            .unwrap()
+            .map(|entry| entry.apath.into())
+            .collect();
+
+        assert_eq!(names.as_slice(), ["/hello", "/subdir/subfile"]);
+    }
 }
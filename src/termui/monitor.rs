@@ -10,12 +10,12 @@ use std::time::Duration;
 
 use nutmeg::{Destination, View};
 use thousands::Separable;
-use tracing::error;
+use tracing::{error, info, warn};
 
 use crate::counters::{Counter, Counters};
 use crate::monitor::task::{Task, TaskList};
 use crate::monitor::Monitor;
-use crate::Error;
+use crate::{ConflictResolution, Error, ErrorClass, RestoreConflict};
 
 pub struct TermUiMonitor {
     // operation: Operation,
@@ -32,6 +32,8 @@ pub struct TermUiMonitor {
     stop_poller: Arc<AtomicBool>,
     /// Number of errors reported.
     error_count: AtomicUsize,
+    /// Number of errors reported that describe damage to the archive.
+    damage_count: AtomicUsize,
 }
 
 /// The nutmeg model.
@@ -77,6 +79,7 @@ impl TermUiMonitor {
             poller,
             stop_poller,
             error_count: AtomicUsize::new(0),
+            damage_count: AtomicUsize::new(0),
         }
     }
 
@@ -97,6 +100,12 @@ impl TermUiMonitor {
     pub fn error_count(&self) -> usize {
         self.error_count.load(Relaxed)
     }
+
+    /// Return the number of errors reported that describe damage to the archive,
+    /// such as missing blocks.
+    pub fn damage_count(&self) -> usize {
+        self.damage_count.load(Relaxed)
+    }
 }
 
 impl Drop for TermUiMonitor {
@@ -120,8 +129,18 @@ impl Monitor for TermUiMonitor {
     }
 
     fn error(&self, error: Error) {
-        error!(target: "conserve", "{error}");
+        error!(target: "conserve", code = error.code(), "{error}");
         self.error_count.fetch_add(1, Relaxed);
+        if error.class() == ErrorClass::ArchiveDamaged {
+            self.damage_count.fetch_add(1, Relaxed);
+        }
+    }
+
+    fn restore_conflict(&self, conflict: RestoreConflict) {
+        match conflict.resolution {
+            ConflictResolution::Kept => warn!(target: "conserve", "{conflict}"),
+            ConflictResolution::Replaced => info!(target: "conserve", "{conflict}"),
+        }
     }
 
     fn start_task(&self, name: String) -> Task {
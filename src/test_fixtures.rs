@@ -67,7 +67,9 @@ impl ScratchArchive {
         let options = &BackupOptions::default();
         backup(&self.archive, srcdir.path(), options, TestMonitor::arc()).unwrap();
 
-        srcdir.create_file("hello2");
+        // Different content, so that the second version stores a new block rather
+        // than reusing the small file from the first.
+        srcdir.create_file_with_contents("hello2", b"content2");
         backup(&self.archive, srcdir.path(), options, TestMonitor::arc()).unwrap();
     }
 
@@ -133,6 +135,8 @@ impl TreeFixture {
     }
 
     /// Create a file with a specified length. The first bytes of the file are the `prefix` and the remainder is zeros.
+    ///
+    /// The zeros are written out, so the file is not sparse.
     pub fn create_file_of_length_with_prefix(
         &self,
         relative_path: &str,
@@ -140,9 +144,9 @@ impl TreeFixture {
         prefix: &[u8],
     ) -> PathBuf {
         let full_path = self.root.join(relative_path);
-        let mut f = fs::File::create(&full_path).unwrap();
-        f.write_all(prefix).unwrap();
-        f.set_len(length).expect("set file length");
+        let mut content = prefix.to_vec();
+        content.resize(length as usize, 0);
+        fs::write(&full_path, content).unwrap();
         full_path
     }
 
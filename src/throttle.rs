@@ -0,0 +1,159 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Limit the resources used by a backup, so that it can run in the background
+//! without making the machine unresponsive.
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::*;
+
+/// Limit the average rate at which data is read, across any number of readers.
+///
+/// This is a token bucket holding up to one second's worth of reads, so short
+/// bursts are allowed after idle periods, but sustained reads are slowed to the
+/// rate.
+#[derive(Debug)]
+pub(crate) struct ReadThrottle {
+    bytes_per_second: u64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    /// Bytes that can be read without waiting; negative if reads are ahead of the rate.
+    available: f64,
+    refilled: Instant,
+}
+
+impl ReadThrottle {
+    pub(crate) fn new(bytes_per_second: u64) -> ReadThrottle {
+        assert!(bytes_per_second > 0, "Read rate must be positive");
+        ReadThrottle {
+            bytes_per_second,
+            state: Mutex::new(BucketState {
+                available: bytes_per_second as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Account for `len` bytes that were read, and return how long to sleep to
+    /// bring the rate back under the limit.
+    fn consume(&self, len: usize) -> Duration {
+        let rate = self.bytes_per_second as f64;
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.refilled).as_secs_f64();
+        state.refilled = now;
+        state.available = (state.available + elapsed * rate).min(rate) - len as f64;
+        if state.available < 0.0 {
+            Duration::from_secs_f64(-state.available / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// A reader that sleeps as necessary to stay under the rate of a [ReadThrottle].
+pub(crate) struct ThrottledRead<R> {
+    inner: R,
+    throttle: Option<Arc<ReadThrottle>>,
+}
+
+impl<R> ThrottledRead<R> {
+    /// Wrap a reader; if `throttle` is None, reads are not limited.
+    pub(crate) fn new(inner: R, throttle: Option<Arc<ReadThrottle>>) -> ThrottledRead<R> {
+        ThrottledRead { inner, throttle }
+    }
+}
+
+impl<R: Read> Read for ThrottledRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if let Some(throttle) = &self.throttle {
+            let delay = throttle.consume(len);
+            if !delay.is_zero() {
+                sleep(delay);
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl<R: Seek> Seek for ThrottledRead<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Make this process run at a lower scheduling priority, by adding `increment`
+/// to its niceness.
+///
+/// On Linux, with the BFQ I/O scheduler, the I/O priority follows the niceness
+/// unless it was set explicitly. Only Unix is supported; on other platforms this
+/// fails.
+pub fn lower_priority(increment: i32) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use nix::errno::Errno;
+        // nice(2) can legitimately return -1, so errors are distinguished by errno.
+        Errno::clear();
+        let result = unsafe { nix::libc::nice(increment) };
+        if result == -1 && Errno::last_raw() != 0 {
+            return Err(Error::SetPriority {
+                source: io::Error::last_os_error(),
+            });
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = increment;
+        Err(Error::SetPriority {
+            source: io::ErrorKind::Unsupported.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_within_the_burst_are_not_delayed() {
+        let throttle = ReadThrottle::new(1000);
+        assert_eq!(throttle.consume(600), Duration::ZERO);
+        assert_eq!(throttle.consume(300), Duration::ZERO);
+    }
+
+    #[test]
+    fn reads_beyond_the_rate_are_delayed() {
+        let throttle = ReadThrottle::new(1000);
+        let delay = throttle.consume(3000);
+        // 2000 bytes past the initial burst, at 1000 bytes/s, less any refill.
+        assert!(delay > Duration::from_millis(1900), "{delay:?}");
+        assert!(delay <= Duration::from_secs(2), "{delay:?}");
+    }
+
+    #[test]
+    fn throttled_read_passes_through_data() {
+        let mut reader =
+            ThrottledRead::new(&b"hello"[..], Some(Arc::new(ReadThrottle::new(1 << 20))));
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello");
+    }
+}
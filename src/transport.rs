@@ -14,8 +14,9 @@
 //!
 //! Transport operations return std::io::Result to reflect their narrower focus.
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::SystemTime;
 use std::{error, fmt, io, result};
 
 use bytes::Bytes;
@@ -27,6 +28,8 @@ use crate::*;
 pub mod local;
 use local::LocalTransport;
 
+pub mod readonly;
+
 #[cfg(feature = "s3")]
 pub mod s3;
 
@@ -68,8 +71,15 @@ pub fn open_local_transport(path: &Path) -> crate::Result<Arc<dyn Transport>> {
 ///
 /// All Transports must be `Send + Sync`, so they can be passed across or shared across threads.
 ///
+/// Applications can implement their own transports and pass them to [Archive::open].
+/// Reading an archive, by listing its bands, reading their indexes, and reading
+/// stored files, only uses `list_dir`, `read_file`, `read_range`, `metadata`, and
+/// `sub_transport`, so a read-only transport, such as one fetching files over HTTP,
+/// can leave the other methods returning errors.
+///
 /// Files in Conserve archives have bounded size and fit in memory so this does not need to
-/// support streaming or partial reads and writes.
+/// support streaming or partial writes. Ranges can be read from files, so that single blocks
+/// can be read from block packs.
 pub trait Transport: Send + Sync + std::fmt::Debug {
     /// List a directory, separating out file and subdirectory names.
     ///
@@ -84,6 +94,11 @@ pub trait Transport: Send + Sync + std::fmt::Debug {
     /// memory, and this is simple to support on all implementations.
     fn read_file(&self, path: &str) -> Result<Bytes>;
 
+    /// Read `len` bytes starting at `start` from a file.
+    ///
+    /// It's an error if the file is shorter than the end of the range.
+    fn read_range(&self, path: &str, start: u64, len: u64) -> Result<Bytes>;
+
     /// Check if a regular file exists.
     fn is_file(&self, path: &str) -> Result<bool> {
         match self.metadata(path) {
@@ -111,6 +126,23 @@ pub trait Transport: Send + Sync + std::fmt::Debug {
     /// we can't rely on detecting existing files.)
     fn write_file(&self, relpath: &str, content: &[u8]) -> Result<()>;
 
+    /// Write a complete file, failing with [ErrorKind::AlreadyExists] if it already exists.
+    ///
+    /// Checking for the file and writing it are a single atomic step, so if several
+    /// processes try to create the same file only one succeeds. This is used to claim
+    /// names, such as band ids and locks, that are shared between processes.
+    ///
+    /// The default implementation fails with [ErrorKind::Unsupported], so that
+    /// transports written before this method was added still build; they can read
+    /// archives but not create bands or take the gc lock.
+    fn write_new_file(&self, relpath: &str, _content: &[u8]) -> Result<()> {
+        Err(Error {
+            kind: ErrorKind::Unsupported,
+            source: None,
+            path: Some(relpath.to_owned()),
+        })
+    }
+
     /// Get metadata about a file.
     fn metadata(&self, relpath: &str) -> Result<Metadata>;
 
@@ -122,6 +154,12 @@ pub trait Transport: Send + Sync + std::fmt::Debug {
 
     /// Make a new transport addressing a subdirectory.
     fn sub_transport(&self, relpath: &str) -> Arc<dyn Transport>;
+
+    /// Return the path of a file on the local filesystem, if this transport stores
+    /// files there, so that they can be copied without reading them into memory.
+    fn local_path(&self, _relpath: &str) -> Option<PathBuf> {
+        None
+    }
 }
 
 /// A directory entry read from a transport.
@@ -141,6 +179,9 @@ pub struct Metadata {
 
     /// Kind of file.
     pub kind: Kind,
+
+    /// Last modification time, if the transport reports it.
+    pub modified: Option<SystemTime>,
 }
 
 /// A list of all the files and directories in a directory.
@@ -173,6 +214,14 @@ pub enum ErrorKind {
     #[display(fmt = "Permission denied")]
     PermissionDenied,
 
+    /// The archive was opened read-only, and this would have changed it.
+    #[display(fmt = "Archive is open read-only")]
+    ReadOnly,
+
+    /// The transport doesn't implement this operation.
+    #[display(fmt = "Not supported by this transport")]
+    Unsupported,
+
     #[display(fmt = "Other transport error")]
     Other,
 }
@@ -196,10 +245,23 @@ impl Error {
         }
     }
 
+    /// An error for an attempt to change a read-only transport.
+    pub(self) fn read_only(path: &str) -> Error {
+        Error {
+            kind: ErrorKind::ReadOnly,
+            source: None,
+            path: Some(path.to_owned()),
+        }
+    }
+
     pub fn is_not_found(&self) -> bool {
         self.kind == ErrorKind::NotFound
     }
 
+    pub fn is_already_exists(&self) -> bool {
+        self.kind == ErrorKind::AlreadyExists
+    }
+
     /// The transport-relative path where this error occurred, if known.
     pub fn path(&self) -> Option<&str> {
         self.path.as_deref()
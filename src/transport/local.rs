@@ -40,6 +40,42 @@ impl LocalTransport {
         debug_assert!(!relpath.contains("/../"), "path must not contain /../");
         self.root.join(relpath)
     }
+
+    /// Write a file through a temporary file, optionally failing if it already exists.
+    fn write_file_inner(
+        &self,
+        relpath: &str,
+        content: &[u8],
+        overwrite: bool,
+    ) -> super::Result<()> {
+        let full_path = self.full_path(relpath);
+        let dir = full_path.parent().unwrap();
+        let context = |err| super::Error::io_error(&full_path, err);
+        let mut temp = tempfile::Builder::new()
+            .prefix(crate::TMP_PREFIX)
+            .tempfile_in(dir)
+            .map_err(context)?;
+        if let Err(err) = temp.write_all(content) {
+            let _ = temp.close();
+            warn!("Failed to write {:?}: {:?}", relpath, err);
+            return Err(context(err));
+        }
+        let persisted = if overwrite {
+            temp.persist(&full_path)
+        } else {
+            temp.persist_noclobber(&full_path)
+        };
+        if let Err(persist_error) = persisted {
+            if overwrite || persist_error.error.kind() != io::ErrorKind::AlreadyExists {
+                warn!("Failed to persist {:?}: {:?}", full_path, persist_error);
+            }
+            persist_error.file.close().map_err(context)?;
+            Err(context(persist_error.error))
+        } else {
+            trace!("Wrote {} bytes", content.len());
+            Ok(())
+        }
+    }
 }
 
 impl Transport for LocalTransport {
@@ -86,6 +122,19 @@ impl Transport for LocalTransport {
         try_block(path).map_err(|err| Error::io_error(path, err))
     }
 
+    fn read_range(&self, relpath: &str, start: u64, len: u64) -> Result<Bytes> {
+        fn try_range(path: &Path, start: u64, len: u64) -> io::Result<Bytes> {
+            let mut file = File::open(path)?;
+            file.seek(io::SeekFrom::Start(start))?;
+            let mut out_buf = vec![0; len.try_into().expect("Range length fits in usize")];
+            file.read_exact(&mut out_buf)?;
+            trace!("Read {len} bytes at {start}");
+            Ok(out_buf.into())
+        }
+        let path = &self.full_path(relpath);
+        try_range(path, start, len).map_err(|err| Error::io_error(path, err))
+    }
+
     fn is_file(&self, relpath: &str) -> Result<bool> {
         let path = self.full_path(relpath);
         Ok(path.is_file())
@@ -104,26 +153,12 @@ impl Transport for LocalTransport {
 
     #[instrument(skip(self, content))]
     fn write_file(&self, relpath: &str, content: &[u8]) -> super::Result<()> {
-        let full_path = self.full_path(relpath);
-        let dir = full_path.parent().unwrap();
-        let context = |err| super::Error::io_error(&full_path, err);
-        let mut temp = tempfile::Builder::new()
-            .prefix(crate::TMP_PREFIX)
-            .tempfile_in(dir)
-            .map_err(context)?;
-        if let Err(err) = temp.write_all(content) {
-            let _ = temp.close();
-            warn!("Failed to write {:?}: {:?}", relpath, err);
-            return Err(context(err));
-        }
-        if let Err(persist_error) = temp.persist(&full_path) {
-            warn!("Failed to persist {:?}: {:?}", full_path, persist_error);
-            persist_error.file.close().map_err(context)?;
-            Err(context(persist_error.error))
-        } else {
-            trace!("Wrote {} bytes", content.len());
-            Ok(())
-        }
+        self.write_file_inner(relpath, content, true)
+    }
+
+    #[instrument(skip(self, content))]
+    fn write_new_file(&self, relpath: &str, content: &[u8]) -> super::Result<()> {
+        self.write_file_inner(relpath, content, false)
     }
 
     fn remove_file(&self, relpath: &str) -> super::Result<()> {
@@ -142,12 +177,17 @@ impl Transport for LocalTransport {
         })
     }
 
+    fn local_path(&self, relpath: &str) -> Option<PathBuf> {
+        Some(self.full_path(relpath))
+    }
+
     fn metadata(&self, relpath: &str) -> Result<Metadata> {
         let path = self.root.join(relpath);
         let fsmeta = path.metadata().map_err(|err| Error::io_error(&path, err))?;
         Ok(Metadata {
             len: fsmeta.len(),
             kind: fsmeta.file_type().into(),
+            modified: fsmeta.modified().ok(),
         })
     }
 }
@@ -161,6 +201,7 @@ impl AsRef<dyn Transport> for LocalTransport {
 #[cfg(test)]
 mod test {
     use std::error::Error;
+    use std::time::Duration;
 
     use assert_fs::prelude::*;
     use predicates::prelude::*;
@@ -184,6 +225,20 @@ mod test {
         temp.close().unwrap();
     }
 
+    #[test]
+    fn read_range() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        temp.child("poem.txt")
+            .write_str("the ribs of the disaster")
+            .unwrap();
+        let transport = LocalTransport::new(temp.path());
+        assert_eq!(transport.read_range("poem.txt", 4, 4).unwrap(), "ribs");
+        assert_eq!(transport.read_range("poem.txt", 0, 0).unwrap(), "");
+        let err = transport.read_range("poem.txt", 20, 10).unwrap_err();
+        assert_eq!(err.kind(), transport::ErrorKind::Other);
+        temp.close().unwrap();
+    }
+
     #[test]
     fn read_file_not_found() {
         let temp = assert_fs::TempDir::new().unwrap();
@@ -215,13 +270,10 @@ mod test {
 
         let transport = LocalTransport::new(temp.path());
 
-        assert_eq!(
-            transport.metadata(filename).unwrap(),
-            Metadata {
-                len: 24,
-                kind: Kind::File
-            }
-        );
+        let metadata = transport.metadata(filename).unwrap();
+        assert_eq!(metadata.len, 24);
+        assert_eq!(metadata.kind, Kind::File);
+        assert!(metadata.modified.unwrap().elapsed().unwrap() < Duration::from_secs(60));
         assert!(transport.metadata("nopoem").unwrap_err().is_not_found());
     }
 
@@ -302,6 +354,26 @@ mod test {
         );
     }
 
+    #[test]
+    fn write_new_file_does_not_overwrite() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        let transport = LocalTransport::new(temp.path());
+        let filename = "filename";
+        transport
+            .write_new_file(filename, b"original content")
+            .expect("first write succeeds");
+        let err = transport
+            .write_new_file(filename, b"new content")
+            .expect_err("write over existing file fails");
+        assert!(err.is_already_exists());
+        assert_eq!(
+            transport.read_file(filename).unwrap().as_ref(),
+            b"original content"
+        );
+        // No temporary files are left behind.
+        assert_eq!(transport.list_dir("").unwrap().files, [filename]);
+    }
+
     #[test]
     fn create_existing_dir() {
         let temp = assert_fs::TempDir::new().unwrap();
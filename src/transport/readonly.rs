@@ -0,0 +1,130 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! A transport that refuses all writes, for archives opened read-only.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use super::{Error, ListDir, Metadata, Result, Transport};
+
+/// Wraps another transport, passing through reads and failing every write,
+/// create, or delete with [super::ErrorKind::ReadOnly].
+///
+/// Transports for subdirectories are also read-only.
+#[derive(Clone, Debug)]
+pub struct ReadOnlyTransport {
+    inner: Arc<dyn Transport>,
+}
+
+impl ReadOnlyTransport {
+    pub fn new(inner: Arc<dyn Transport>) -> Self {
+        ReadOnlyTransport { inner }
+    }
+}
+
+impl Transport for ReadOnlyTransport {
+    fn list_dir(&self, relpath: &str) -> Result<ListDir> {
+        self.inner.list_dir(relpath)
+    }
+
+    fn read_file(&self, path: &str) -> Result<Bytes> {
+        self.inner.read_file(path)
+    }
+
+    fn read_range(&self, path: &str, start: u64, len: u64) -> Result<Bytes> {
+        self.inner.read_range(path, start, len)
+    }
+
+    fn is_file(&self, path: &str) -> Result<bool> {
+        self.inner.is_file(path)
+    }
+
+    fn create_dir(&self, relpath: &str) -> Result<()> {
+        Err(Error::read_only(relpath))
+    }
+
+    fn write_file(&self, relpath: &str, _content: &[u8]) -> Result<()> {
+        Err(Error::read_only(relpath))
+    }
+
+    fn write_new_file(&self, relpath: &str, _content: &[u8]) -> Result<()> {
+        Err(Error::read_only(relpath))
+    }
+
+    fn metadata(&self, relpath: &str) -> Result<Metadata> {
+        self.inner.metadata(relpath)
+    }
+
+    fn remove_file(&self, relpath: &str) -> Result<()> {
+        Err(Error::read_only(relpath))
+    }
+
+    fn remove_dir_all(&self, relpath: &str) -> Result<()> {
+        Err(Error::read_only(relpath))
+    }
+
+    fn sub_transport(&self, relpath: &str) -> Arc<dyn Transport> {
+        Arc::new(ReadOnlyTransport::new(self.inner.sub_transport(relpath)))
+    }
+
+    fn local_path(&self, relpath: &str) -> Option<PathBuf> {
+        self.inner.local_path(relpath)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_fs::prelude::*;
+
+    use super::*;
+    use crate::transport::local::LocalTransport;
+    use crate::transport::ErrorKind;
+
+    #[test]
+    fn reads_pass_through_and_writes_fail() {
+        let temp = assert_fs::TempDir::new().unwrap();
+        temp.child("sub").create_dir_all().unwrap();
+        temp.child("sub/file").write_str("content").unwrap();
+        let transport = ReadOnlyTransport::new(Arc::new(LocalTransport::new(temp.path())));
+        let sub = transport.sub_transport("sub");
+
+        assert_eq!(sub.read_file("file").unwrap().as_ref(), b"content");
+        assert_eq!(sub.read_range("file", 1, 3).unwrap().as_ref(), b"ont");
+        assert!(transport.is_file("sub/file").unwrap());
+        assert_eq!(transport.list_dir("").unwrap().dirs, ["sub"]);
+
+        let err = sub.write_file("file", b"new").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ReadOnly);
+        assert_eq!(err.path(), Some("file"));
+        assert_eq!(
+            sub.write_new_file("other", b"new").unwrap_err().kind(),
+            ErrorKind::ReadOnly
+        );
+        assert_eq!(
+            transport.create_dir("new").unwrap_err().kind(),
+            ErrorKind::ReadOnly
+        );
+        assert_eq!(
+            sub.remove_file("file").unwrap_err().kind(),
+            ErrorKind::ReadOnly
+        );
+        assert_eq!(
+            transport.remove_dir_all("sub").unwrap_err().kind(),
+            ErrorKind::ReadOnly
+        );
+        temp.child("sub/file").assert("content");
+        temp.child("new").assert(predicates::path::missing());
+    }
+}
@@ -27,9 +27,10 @@
 use std::fmt;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use aws_config::{AppName, BehaviorVersion};
-use aws_sdk_s3::error::SdkError;
+use aws_sdk_s3::error::{ProvideErrorMetadata, SdkError};
 use aws_sdk_s3::operation::delete_object::DeleteObjectError;
 use aws_sdk_s3::operation::get_object::GetObjectError;
 use aws_sdk_s3::operation::head_object::HeadObjectError;
@@ -251,6 +252,44 @@ impl Transport for S3Transport {
         Ok(body_bytes)
     }
 
+    fn read_range(&self, relpath: &str, start: u64, len: u64) -> Result<Bytes> {
+        let _span = trace_span!("S3Transport::read_range", %relpath, start, len).entered();
+        if len == 0 {
+            return Ok(Bytes::new());
+        }
+        let key = self.join_path(relpath);
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .range(format!("bytes={}-{}", start, start + len - 1));
+        let response = self
+            .runtime
+            .block_on(request.send())
+            .map_err(|source| s3_error(key.clone(), source))?;
+        let body_bytes = self
+            .runtime
+            .block_on(response.body.collect())
+            .map_err(|source| Error {
+                kind: ErrorKind::Other,
+                path: Some(key.clone()),
+                source: Some(Box::new(source)),
+            })?
+            .into_bytes();
+        if body_bytes.len() as u64 != len {
+            return Err(Error {
+                kind: ErrorKind::Other,
+                path: Some(key),
+                source: Some(
+                    format!("Read {} bytes but {len} were requested", body_bytes.len()).into(),
+                ),
+            });
+        }
+        trace!(body_len = body_bytes.len(), "read range");
+        Ok(body_bytes)
+    }
+
     #[mutants::skip] // does nothing so hard to observe!
     fn create_dir(&self, relpath: &str) -> Result<()> {
         // There are no directory objects, so there's nothing to create.
@@ -260,22 +299,12 @@ impl Transport for S3Transport {
 
     fn write_file(&self, relpath: &str, content: &[u8]) -> Result<()> {
         let _span = trace_span!("S3Transport::write_file", %relpath).entered();
-        let key = self.join_path(relpath);
-        let crc32c =
-            base64::engine::general_purpose::STANDARD.encode(crc32c::crc32c(content).to_be_bytes());
-        let request = self
-            .client
-            .put_object()
-            .bucket(&self.bucket)
-            .key(&key)
-            .storage_class(self.storage_class.clone())
-            .checksum_crc32_c(crc32c)
-            .body(content.to_owned().into());
-        let response = self.runtime.block_on(request.send());
-        // trace!(?response);
-        response.map_err(|err| s3_error(key, err))?;
-        trace!(body_len = content.len(), "wrote file");
-        Ok(())
+        self.put_object(relpath, content, false)
+    }
+
+    fn write_new_file(&self, relpath: &str, content: &[u8]) -> Result<()> {
+        let _span = trace_span!("S3Transport::write_new_file", %relpath).entered();
+        self.put_object(relpath, content, true)
     }
 
     fn remove_file(&self, relpath: &str) -> Result<()> {
@@ -344,6 +373,9 @@ impl Transport for S3Transport {
                 Ok(Metadata {
                     kind: Kind::File,
                     len,
+                    modified: response
+                        .last_modified
+                        .and_then(|time| SystemTime::try_from(time).ok()),
                 })
             }
             Err(err) => {
@@ -373,6 +405,29 @@ impl S3Transport {
     fn join_path(&self, relpath: &str) -> String {
         join_paths(&self.base_path, relpath)
     }
+
+    /// Write an object, optionally only if it doesn't already exist.
+    fn put_object(&self, relpath: &str, content: &[u8], if_not_exists: bool) -> Result<()> {
+        let key = self.join_path(relpath);
+        let crc32c =
+            base64::engine::general_purpose::STANDARD.encode(crc32c::crc32c(content).to_be_bytes());
+        let mut request = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .storage_class(self.storage_class.clone())
+            .checksum_crc32_c(crc32c)
+            .body(content.to_owned().into());
+        if if_not_exists {
+            request = request.if_none_match("*");
+        }
+        let response = self.runtime.block_on(request.send());
+        // trace!(?response);
+        response.map_err(|err| s3_error(key, err))?;
+        trace!(body_len = content.len(), "wrote file");
+        Ok(())
+    }
 }
 
 impl AsRef<dyn Transport> for S3Transport {
@@ -420,8 +475,11 @@ impl From<&ListObjectsV2Error> for ErrorKind {
 
 impl From<&PutObjectError> for ErrorKind {
     fn from(source: &PutObjectError) -> Self {
-        let _ = source;
-        ErrorKind::Other
+        // A conditional write found the object already exists.
+        match source.code() {
+            Some("PreconditionFailed" | "ConditionalRequestConflict") => ErrorKind::AlreadyExists,
+            _ => ErrorKind::Other,
+        }
     }
 }
 
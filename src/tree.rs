@@ -15,6 +15,8 @@
 
 use std::sync::Arc;
 
+use serde::Serialize;
+
 use crate::counters::Counter;
 use crate::monitor::Monitor;
 use crate::*;
@@ -56,6 +58,7 @@ pub trait ReadTree {
 }
 
 /// The measured size of a tree.
+#[derive(Debug, Clone, Serialize)]
 pub struct TreeSize {
     pub file_bytes: u64,
 }
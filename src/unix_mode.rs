@@ -79,6 +79,11 @@ impl PartialEq for UnixMode {
 impl Eq for UnixMode {}
 
 impl UnixMode {
+    /// The permission, set-id, and sticky bits, if they're known.
+    pub fn bits(self) -> Option<u32> {
+        self.0
+    }
+
     pub fn readonly(self) -> bool {
         // determine if a file is readonly based on whether the owning user can write to it
         // if the mode is None, then we assume it is not readonly
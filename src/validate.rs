@@ -11,64 +11,217 @@
 // GNU General Public License for more details.
 
 use std::cmp::max;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use tracing::debug;
+use blake2_rfc::blake2b::blake2b;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tracing::{debug, debug_span, Span};
 
 use crate::monitor::Monitor;
+use crate::stats::ValidateStats;
 use crate::*;
 
 /// Options to [Archive::validate].
+///
+/// New options may be added in future, so construct this from [Default] and the
+/// `with_` methods, or by setting fields of a default value.
 #[derive(Debug, Default)]
+#[non_exhaustive]
 pub struct ValidateOptions {
     /// Assume blocks that are present have the right content: don't read and hash them.
+    ///
+    /// The structure of the archive is still checked, and that every referenced block
+    /// file exists and has a plausible size.
     pub skip_block_hashes: bool,
+
+    /// Only check bands and blocks that weren't found to be valid by an earlier incremental
+    /// validation, and record in the archive which were found to be valid this time.
+    ///
+    /// Blocks referenced only by bands that are skipped aren't checked to still be present.
+    pub incremental: bool,
+
+    /// Only read and check the content of a random subset of the blocks.
+    ///
+    /// All referenced blocks are still checked to be present.
+    pub sample: Option<BlockSample>,
+
+    /// Check bands, and read and hash blocks, on this many threads.
+    pub parallelism: Parallelism,
+}
+
+impl ValidateOptions {
+    /// Don't read and hash the content of blocks.
+    pub fn with_skip_block_hashes(self, skip_block_hashes: bool) -> Self {
+        ValidateOptions {
+            skip_block_hashes,
+            ..self
+        }
+    }
+
+    /// Only check content that wasn't found to be valid by an earlier incremental validation.
+    pub fn with_incremental(self, incremental: bool) -> Self {
+        ValidateOptions {
+            incremental,
+            ..self
+        }
+    }
+
+    /// Only read the content of this random sample of blocks.
+    pub fn with_sample(self, sample: Option<BlockSample>) -> Self {
+        ValidateOptions { sample, ..self }
+    }
+
+    /// Limit the threads used to check bands and blocks.
+    pub fn with_parallelism(self, parallelism: Parallelism) -> Self {
+        ValidateOptions {
+            parallelism,
+            ..self
+        }
+    }
+
+    /// True if the content of this block should be read and checked.
+    pub(crate) fn should_read_block(&self, hash: &BlockHash) -> bool {
+        !self.skip_block_hashes
+            && self
+                .sample
+                .as_ref()
+                .map_or(true, |sample| sample.contains(hash))
+    }
+}
+
+/// A random subset of blocks to check, chosen by a seed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlockSample {
+    /// The fraction of blocks to check, from 0 to 1.
+    pub fraction: f64,
+    /// Validations with the same seed and fraction check the same blocks.
+    pub seed: u64,
+}
+
+impl BlockSample {
+    /// True if this block is in the sample.
+    pub fn contains(&self, hash: &BlockHash) -> bool {
+        if self.fraction >= 1.0 {
+            return true;
+        }
+        let keyed = blake2b(8, &self.seed.to_le_bytes(), hash.as_bytes());
+        let x = u64::from_le_bytes(keyed.as_bytes().try_into().unwrap());
+        (x as f64) < self.fraction * (u64::MAX as f64)
+    }
+}
+
+/// A record that an archive was validated without finding any problems.
+///
+/// Written into the archive by [Archive::set_last_validated], so that other clients
+/// can tell when the archive was last known to be good.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ValidationMarker {
+    /// When validation finished.
+    #[serde(with = "time::serde::rfc3339")]
+    pub time: OffsetDateTime,
+    /// The version of Conserve that validated the archive.
+    pub conserve_version: String,
+    /// True if block content wasn't read and hashed.
+    pub skip_block_hashes: bool,
+    /// True if only content that wasn't validated before was checked.
+    pub incremental: bool,
+    /// The fraction of blocks whose content was checked, if only a sample was checked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_fraction: Option<f64>,
+    pub stats: ValidateStats,
+}
+
+impl ValidationMarker {
+    /// Describe a validation that just finished, with the given options and stats.
+    pub fn new(options: &ValidateOptions, stats: ValidateStats) -> ValidationMarker {
+        ValidationMarker {
+            time: OffsetDateTime::now_utc(),
+            conserve_version: crate::VERSION.to_owned(),
+            skip_block_hashes: options.skip_block_hashes,
+            incremental: options.incremental,
+            sample_fraction: options.sample.map(|sample| sample.fraction),
+            stats,
+        }
+    }
+}
+
+/// The result of validating the indexes of some bands.
+pub(crate) struct BandsValidation {
+    /// The largest referenced length of each block.
+    pub block_lens: HashMap<BlockHash, u64>,
+    /// Bands whose metadata couldn't be read, with a description of the problem.
+    pub damaged: BTreeMap<BandId, String>,
+    /// Closed bands that were found to be valid.
+    pub valid: BTreeSet<BandId>,
 }
 
 /// Validate the indexes of all bands.
 ///
+/// Bands are validated in parallel on the rayon thread pool.
+///
 /// Returns the lengths of all blocks that were referenced, so that the caller can check
-/// that all blocks are present and long enough.
+/// that all blocks are present and long enough, along with which bands were damaged
+/// and which were valid.
 pub(crate) fn validate_bands(
     archive: &Archive,
     band_ids: &[BandId],
     monitor: Arc<dyn Monitor>,
-) -> Result<HashMap<BlockHash, u64>> {
-    let mut block_lens = HashMap::new();
+) -> Result<BandsValidation> {
+    let block_lens = Mutex::new(HashMap::new());
+    let damaged = Mutex::new(BTreeMap::new());
+    let valid = Mutex::new(BTreeSet::new());
     let task = monitor.start_task("Validate indexes".to_string());
     task.set_total(band_ids.len());
-    'band: for band_id in band_ids.iter() {
-        task.increment(1);
-        let band = match Band::open(archive, *band_id) {
-            Ok(band) => band,
-            Err(err) => {
-                monitor.error(err);
-                continue 'band;
-            }
-        };
-        if let Err(err) = band.validate(monitor.clone()) {
-            monitor.error(err);
-            continue 'band;
-        };
-        let st = match archive.open_stored_tree(BandSelectionPolicy::Specified(*band_id)) {
-            Err(err) => {
-                monitor.error(err);
-                continue 'band;
+    // Rayon workers don't inherit the caller's span, so each band's span names it as the parent.
+    let parent = Span::current();
+    band_ids.par_iter().for_each(|band_id| {
+        if monitor.should_cancel() {
+            return;
+        }
+        let _span = debug_span!(parent: &parent, "validate_band", %band_id).entered();
+        match validate_band(archive, *band_id, monitor.clone()) {
+            Ok((band_block_lens, is_closed)) => {
+                merge_block_lens(&mut block_lens.lock().unwrap(), &band_block_lens);
+                if is_closed {
+                    valid.lock().unwrap().insert(*band_id);
+                }
             }
-            Ok(st) => st,
-        };
-        let band_block_lens = match validate_stored_tree(&st, monitor.clone()) {
             Err(err) => {
+                // Record the problem, unless it's only that this version can't read the band.
+                if !matches!(
+                    err,
+                    Error::UnsupportedBandVersion { .. } | Error::UnsupportedBandFormatFlags { .. }
+                ) {
+                    damaged.lock().unwrap().insert(*band_id, err.to_string());
+                }
                 monitor.error(err);
-                continue 'band;
             }
-            Ok(block_lens) => block_lens,
-        };
-        merge_block_lens(&mut block_lens, &band_block_lens);
-    }
-    Ok(block_lens)
+        }
+        task.increment(1);
+    });
+    Ok(BandsValidation {
+        block_lens: block_lens.into_inner().unwrap(),
+        damaged: damaged.into_inner().unwrap(),
+        valid: valid.into_inner().unwrap(),
+    })
+}
+
+/// Validate one band's metadata and index, returning the lengths of the blocks it references,
+/// and whether the band is closed.
+fn validate_band(
+    archive: &Archive,
+    band_id: BandId,
+    monitor: Arc<dyn Monitor>,
+) -> Result<(HashMap<BlockHash, u64>, bool)> {
+    let band = Band::open(archive, band_id)?;
+    band.validate(monitor.clone())?;
+    let info = band.get_info()?;
+    let st = archive.open_stored_tree(BandSelectionPolicy::Specified(band_id))?;
+    Ok((validate_stored_tree(&st, monitor)?, info.is_closed))
 }
 
 fn merge_block_lens(into: &mut HashMap<BlockHash, u64>, from: &HashMap<BlockHash, u64>) {
@@ -95,7 +248,18 @@ fn validate_stored_tree(
         // TODO: Read index hunks, count into the task per hunk. Then, we can
         // read hunks in parallel.
         for addr in entry.addrs {
-            let end = addr.start + addr.len;
+            let end = addr
+                .start
+                .checked_add(addr.len)
+                .ok_or_else(|| Error::InvalidMetadata {
+                    details: format!(
+                        "Index entry {} in {} has an address whose end overflows: start {}, len {}",
+                        entry.apath,
+                        st.band().id(),
+                        addr.start,
+                        addr.len
+                    ),
+                })?;
             block_lens
                 .entry(addr.hash.clone())
                 .and_modify(|l| *l = max(*l, end))
@@ -105,3 +269,41 @@ fn validate_stored_tree(
     debug!(blocks = %block_lens.len(), band_id = ?st.band().id(), "Validated stored tree");
     Ok(block_lens)
 }
+
+/// Report each index entry in the given bands that references data past the end of
+/// one of the `short_blocks`, whose actual uncompressed lengths are given.
+///
+/// This is only called once blocks that are too short have been found, to say which
+/// entries are affected.
+pub(crate) fn report_addresses_out_of_range(
+    archive: &Archive,
+    band_ids: &[BandId],
+    short_blocks: &HashMap<BlockHash, usize>,
+    monitor: Arc<dyn Monitor>,
+) {
+    band_ids.par_iter().for_each(|&band_id| {
+        // Problems opening the band were already reported.
+        let Ok(entries) = archive
+            .open_stored_tree(BandSelectionPolicy::Specified(band_id))
+            .and_then(|st| st.iter_entries(Apath::root(), Exclude::nothing(), monitor.clone()))
+        else {
+            return;
+        };
+        for entry in entries {
+            for addr in &entry.addrs {
+                if let Some(&block_len) = short_blocks.get(&addr.hash) {
+                    if addr.start.saturating_add(addr.len) > block_len as u64 {
+                        monitor.error(Error::AddressOutOfRange {
+                            band_id,
+                            apath: entry.apath.clone(),
+                            hash: addr.hash.clone(),
+                            start: addr.start,
+                            len: addr.len,
+                            block_len,
+                        });
+                    }
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,286 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Verify that a stored tree still matches a live tree, including the content of
+//! files, without restoring it.
+//!
+//! Unlike [diff], which only looks at metadata, this reads every live file whose
+//! size matches the stored file, so it finds content that changed without its
+//! mtime changing, such as bit rot on the source disk.
+//!
+//! Where a stored block holds exactly one piece of a file, which is the usual case
+//! for large files, the live content is hashed and compared to the block hash
+//! without reading the archive. Only blocks shared with other small files, and
+//! blocks that don't match, are read from the archive.
+
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::counters::Counter;
+use crate::merge::{merge_trees, MatchedEntries};
+use crate::monitor::{check_cancel, Monitor};
+use crate::*;
+
+/// Options for [verify].
+pub struct VerifyOptions<'cb> {
+    pub exclude: Exclude,
+
+    /// Called for each entry that differs.
+    pub drift_callback: Option<DriftCallback<'cb>>,
+}
+
+impl Default for VerifyOptions<'_> {
+    fn default() -> Self {
+        VerifyOptions {
+            exclude: Exclude::nothing(),
+            drift_callback: None,
+        }
+    }
+}
+
+/// A callback when [verify] finds a difference.
+pub type DriftCallback<'cb> = Box<dyn Fn(&Drift) -> Result<()> + 'cb>;
+
+/// A difference found by [verify] between the stored and live trees.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(tag = "drift", rename_all = "snake_case")]
+pub enum Drift {
+    /// The entry was added or removed, or its metadata changed, as shown by [diff].
+    Metadata(Box<EntryChange>),
+    /// The file's content differs from the stored content, though it's the same size.
+    Content { apath: Apath },
+}
+
+impl Drift {
+    pub fn apath(&self) -> &Apath {
+        match self {
+            Drift::Metadata(change) => &change.apath,
+            Drift::Content { apath } => apath,
+        }
+    }
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Drift::Metadata(change) => write!(f, "{change}"),
+            Drift::Content { apath } => write!(f, "! {apath}"),
+        }
+    }
+}
+
+/// Compare a stored tree to a live tree, including the content of files that have
+/// the same size in both.
+///
+/// Errors reading live files or stored blocks are reported to the monitor and
+/// counted, and verification continues.
+pub fn verify(
+    stored_tree: &StoredTree,
+    live_tree: &LiveTree,
+    options: &VerifyOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<VerifyStats> {
+    let start = Instant::now();
+    let mut stats = VerifyStats::default();
+    let task = monitor.start_task("Verify".to_string());
+    let report = |drift: Drift| -> Result<()> {
+        if let Some(cb) = &options.drift_callback {
+            cb(&drift)?;
+        }
+        Ok(())
+    };
+    for matched in merge_trees(stored_tree, live_tree, &options.exclude, monitor.clone())? {
+        check_cancel(monitor.as_ref())?;
+        stats.entries += 1;
+        let change = matched.to_entry_change();
+        if !change.change.is_unchanged() {
+            stats.metadata_drift += 1;
+            report(Drift::Metadata(Box::new(change)))?;
+        }
+        let MatchedEntries::Both(stored, live) = matched else {
+            continue;
+        };
+        if stored.kind() != Kind::File || live.kind() != Kind::File || stored.size() != live.size()
+        {
+            continue;
+        }
+        task.set_name(format!("Verify {}", stored.apath));
+        stats.files += 1;
+        match content_matches(stored_tree, live_tree, &stored, &live, monitor.clone()) {
+            Ok(true) => (),
+            Ok(false) => {
+                stats.content_drift += 1;
+                report(Drift::Content {
+                    apath: stored.apath.clone(),
+                })?;
+            }
+            Err(err) => {
+                stats.errors += 1;
+                monitor.error(err);
+            }
+        }
+        let size = stored.size().unwrap_or_default();
+        stats.file_bytes += size;
+        monitor.count(Counter::FileBytes, size as usize);
+    }
+    stats.elapsed = start.elapsed();
+    Ok(stats)
+}
+
+/// Compare the content of a live file to a stored file of the same size.
+fn content_matches(
+    stored_tree: &StoredTree,
+    live_tree: &LiveTree,
+    stored: &IndexEntry,
+    live: &EntryValue,
+    monitor: Arc<dyn Monitor>,
+) -> Result<bool> {
+    let mut live_file = live_tree.open_file(live)?;
+    if !stored.holes.is_empty() {
+        // The blocks don't line up with the file, so compare the whole stream.
+        let stored_file = stored_tree.open_entry(stored.clone(), monitor)?;
+        return streams_match(stored_file, live_file);
+    }
+    let block_dir = stored_tree.block_dir();
+    let mut buf = Vec::new();
+    for addr in &stored.addrs {
+        buf.resize(addr.len as usize, 0);
+        if !read_fully(&mut live_file, &mut buf)? {
+            return Ok(false);
+        }
+        // A block that holds exactly this piece of the file can be checked by its hash.
+        if addr.start == 0 && BlockHash::hash_bytes(&buf) == addr.hash {
+            continue;
+        }
+        if block_dir.read_address(addr, monitor.clone())? != buf {
+            return Ok(false);
+        }
+    }
+    // The file may have grown since its size was read.
+    Ok(live_file.read(&mut [0])? == 0)
+}
+
+/// Read until the buffer is full, returning false if the file ends first.
+fn read_fully(r: &mut dyn Read, buf: &mut [u8]) -> io::Result<bool> {
+    match r.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn streams_match(mut a: impl Read, mut b: impl Read) -> Result<bool> {
+    const BUF_SIZE: usize = 1 << 20;
+    let mut abuf = vec![0; BUF_SIZE];
+    let mut bbuf = vec![0; BUF_SIZE];
+    loop {
+        let alen = read_up_to(&mut a, &mut abuf)?;
+        let blen = read_up_to(&mut b, &mut bbuf)?;
+        if abuf[..alen] != bbuf[..blen] {
+            return Ok(false);
+        } else if alen == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Read until the buffer is full or the stream ends, returning the number of bytes read.
+fn read_up_to(r: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match r.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::Mutex;
+
+    use filetime::{set_file_mtime, FileTime};
+
+    use super::*;
+    use crate::monitor::test::TestMonitor;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    fn verify_drift(af: &ScratchArchive, srcdir: &TreeFixture) -> (VerifyStats, Vec<String>) {
+        let drift = Mutex::new(Vec::new());
+        let options = VerifyOptions {
+            drift_callback: Some(Box::new(|d| {
+                drift.lock().unwrap().push(d.to_string());
+                Ok(())
+            })),
+            ..Default::default()
+        };
+        let monitor = TestMonitor::arc();
+        let stats = verify(
+            &af.open_stored_tree(BandSelectionPolicy::Latest).unwrap(),
+            &srcdir.live_tree(),
+            &options,
+            monitor.clone(),
+        )
+        .unwrap();
+        monitor.assert_no_errors();
+        drop(options);
+        (stats, drift.into_inner().unwrap())
+    }
+
+    #[test]
+    fn unchanged_tree_verifies() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file_with_contents("small", b"hello");
+        srcdir.create_file_with_contents("big", &vec![b'x'; 3 << 20]);
+        backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+        let (stats, drift) = verify_drift(&af, &srcdir);
+        assert_eq!(drift, Vec::<String>::new());
+        assert_eq!(stats.files, 2);
+        assert_eq!(stats.file_bytes, (3 << 20) + 5);
+        assert_eq!(stats.content_drift, 0);
+    }
+
+    #[test]
+    fn content_change_with_same_mtime_is_found() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        let small = srcdir.create_file_with_contents("small", b"hello");
+        let big = srcdir.create_file_with_contents("big", &vec![b'x'; 3 << 20]);
+        srcdir.create_file_with_contents("same", b"same");
+        backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+        // Simulate bit rot: change content, keeping the size and mtime.
+        for path in [&small, &big] {
+            let mtime = FileTime::from_last_modification_time(&path.metadata().unwrap());
+            let mut file = OpenOptions::new().write(true).open(path).unwrap();
+            file.seek(SeekFrom::Start(2)).unwrap();
+            file.write_all(b"Z").unwrap();
+            drop(file);
+            set_file_mtime(path, mtime).unwrap();
+        }
+        srcdir.create_file("added");
+
+        let (stats, drift) = verify_drift(&af, &srcdir);
+        assert_eq!(drift, ["+ /added", "! /big", "! /small"]);
+        assert_eq!(stats.content_drift, 2);
+        assert_eq!(stats.metadata_drift, 1);
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Windows Volume Shadow Copies, so that a backup can read a consistent snapshot
+//! of the source, including files that are locked open by other programs.
+//!
+//! Shadow copies are created and deleted through the `Win32_ShadowCopy` WMI class,
+//! using PowerShell, which needs Administrator privileges.
+
+use std::path::{Path, PathBuf};
+
+use crate::*;
+
+/// Shadow copies of the volumes containing some source directories.
+///
+/// The shadow copies are deleted when this is dropped, so it must outlive
+/// any reads from the snapshot paths.
+#[derive(Debug, Default)]
+pub(crate) struct ShadowCopies {
+    #[cfg(windows)]
+    copies: Vec<ShadowCopy>,
+}
+
+/// A shadow copy of one volume.
+#[cfg(windows)]
+#[derive(Debug)]
+struct ShadowCopy {
+    /// The volume that was copied, like `C:\`.
+    volume: String,
+    /// The WMI ID of the shadow copy.
+    id: String,
+    /// Device path from which the snapshot can be read, like
+    /// `\\?\GLOBALROOT\Device\HarddiskVolumeShadowCopy3`.
+    device_object: PathBuf,
+}
+
+#[cfg(windows)]
+impl ShadowCopies {
+    /// Return the path of a source directory within a shadow copy of its volume,
+    /// creating the shadow copy if this volume doesn't have one yet.
+    pub(crate) fn snapshot_path(&mut self, path: &Path) -> Result<PathBuf> {
+        let (volume, relative) = split_volume(path)?;
+        let index = match self.copies.iter().position(|c| c.volume == volume) {
+            Some(index) => index,
+            None => {
+                let copy = ShadowCopy::create(&volume).map_err(|details| Error::ShadowCopy {
+                    path: path.to_owned(),
+                    details,
+                })?;
+                self.copies.push(copy);
+                self.copies.len() - 1
+            }
+        };
+        Ok(self.copies[index].device_object.join(relative))
+    }
+}
+
+#[cfg(not(windows))]
+impl ShadowCopies {
+    pub(crate) fn snapshot_path(&mut self, _path: &Path) -> Result<PathBuf> {
+        Err(Error::ShadowCopyUnsupported)
+    }
+}
+
+/// Split an absolute path into its volume, like `C:\`, and the path within the volume.
+#[cfg(windows)]
+fn split_volume(path: &Path) -> Result<(String, PathBuf)> {
+    use std::path::{Component, Prefix};
+
+    let unsupported = |details: &str| Error::ShadowCopy {
+        path: path.to_owned(),
+        details: details.to_owned(),
+    };
+    let path = path
+        .canonicalize()
+        .map_err(|source| Error::ListSourceTree {
+            path: path.to_owned(),
+            source,
+        })?;
+    let mut components = path.components();
+    let volume = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::VerbatimDisk(letter) | Prefix::Disk(letter) => {
+                format!("{}:\\", letter as char)
+            }
+            _ => return Err(unsupported("Only local drive letters can be shadow copied")),
+        },
+        _ => return Err(unsupported("Path has no drive letter")),
+    };
+    let relative = components
+        .filter(|c| !matches!(c, Component::RootDir))
+        .collect();
+    Ok((volume, relative))
+}
+
+#[cfg(windows)]
+impl ShadowCopy {
+    fn create(volume: &str) -> std::result::Result<ShadowCopy, String> {
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'
+            $result = (Get-WmiObject -List Win32_ShadowCopy).Create('{volume}', 'ClientAccessible')
+            if ($result.ReturnValue -ne 0) {{
+                throw \"Win32_ShadowCopy.Create returned $($result.ReturnValue)\"
+            }}
+            $copy = Get-WmiObject Win32_ShadowCopy -Filter \"ID='$($result.ShadowID)'\"
+            $copy.ID
+            $copy.DeviceObject"
+        );
+        let output = powershell(&script)?;
+        let mut lines = output.lines().map(str::trim).filter(|l| !l.is_empty());
+        match (lines.next(), lines.next()) {
+            (Some(id), Some(device_object)) => {
+                tracing::info!(%volume, %id, %device_object, "Created shadow copy");
+                Ok(ShadowCopy {
+                    volume: volume.to_owned(),
+                    id: id.to_owned(),
+                    device_object: PathBuf::from(device_object),
+                })
+            }
+            _ => Err(format!("Unexpected output from PowerShell: {output:?}")),
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ShadowCopy {
+    fn drop(&mut self) {
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'
+            Get-WmiObject Win32_ShadowCopy -Filter \"ID='{id}'\" | ForEach-Object {{ $_.Delete() }}",
+            id = self.id
+        );
+        match powershell(&script) {
+            Ok(_) => tracing::debug!(id = %self.id, "Deleted shadow copy"),
+            Err(err) => tracing::warn!(id = %self.id, %err, "Failed to delete shadow copy"),
+        }
+    }
+}
+
+/// Run a PowerShell script, returning its stdout, or its stderr if it fails.
+#[cfg(windows)]
+fn powershell(script: &str) -> std::result::Result<String, String> {
+    let output = std::process::Command::new("powershell.exe")
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .output()
+        .map_err(|err| format!("Failed to run PowerShell: {err}"))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(String::from_utf8_lossy(&output.stderr).trim().to_owned())
+    }
+}
@@ -0,0 +1,210 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Make backups repeatedly at an interval, optionally pruning old backups after
+//! each one, so that small deployments don't need an external scheduler.
+//!
+//! The outcome of each run is logged as a `tracing` event with fields for the
+//! main statistics, so that it can be recorded with `--log-json`.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use tracing::{error, info, info_span};
+
+use crate::monitor::{check_cancel, Monitor};
+use crate::*;
+
+/// How often to check for cancellation while waiting for the next run.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Options for [watch].
+#[derive(Debug, Clone, Default)]
+pub struct WatchOptions {
+    /// Time from the start of one backup to the start of the next. If a backup
+    /// takes longer than this, the next starts as soon as it finishes.
+    pub interval: Duration,
+
+    /// Wait up to this much longer, chosen at random each time, before each
+    /// backup after the first, so that many machines backing up on the same
+    /// schedule don't all start at once.
+    pub jitter: Duration,
+
+    /// After each backup that completes, delete old backups with this policy.
+    pub prune: Option<PruneOptions>,
+
+    /// Stop after this many backups; by default, run until cancelled.
+    pub max_runs: Option<usize>,
+}
+
+/// Back up the source directories into the archive repeatedly.
+///
+/// If there's more than one source, each is stored under its own name, as by
+/// [backup_sources].
+///
+/// A backup or prune that fails is logged, and the next one is still attempted
+/// at the usual time. This returns only when [WatchOptions::max_runs] backups have
+/// been attempted, or with [Error::Cancelled] when the monitor cancels it.
+pub fn watch<P: AsRef<Path>>(
+    archive: &Archive,
+    sources: &[P],
+    backup_options: &BackupOptions,
+    options: &WatchOptions,
+    monitor: Arc<dyn Monitor>,
+) -> Result<()> {
+    let mut run = 0;
+    loop {
+        check_cancel(monitor.as_ref())?;
+        run += 1;
+        let start = Instant::now();
+        let _span = info_span!("watch", run).entered();
+        let result = if let [source] = sources {
+            backup(archive, source.as_ref(), backup_options, monitor.clone())
+        } else {
+            backup_sources(archive, sources, backup_options, monitor.clone())
+        };
+        match result {
+            Ok(stats) => {
+                info!(
+                    files = stats.files,
+                    new_files = stats.new_files,
+                    modified_files = stats.modified_files,
+                    written_blocks = stats.written_blocks,
+                    compressed_bytes = stats.compressed_bytes,
+                    errors = stats.errors,
+                    elapsed_ms = stats.elapsed.as_millis() as u64,
+                    "Backup complete"
+                );
+                if let Some(prune_options) = &options.prune {
+                    match prune(archive, prune_options, monitor.clone()) {
+                        Ok(stats) => info!(
+                            deleted_bands = stats.deleted_band_count,
+                            deleted_blocks = stats.deleted_block_count,
+                            "Pruned old backups"
+                        ),
+                        Err(Error::Cancelled) => return Err(Error::Cancelled),
+                        Err(err) => error!(code = err.code(), "Prune failed: {err}"),
+                    }
+                }
+            }
+            Err(Error::Cancelled) => return Err(Error::Cancelled),
+            Err(err) => error!(code = err.code(), "Backup failed: {err}"),
+        }
+        if options.max_runs.is_some_and(|max_runs| run >= max_runs) {
+            return Ok(());
+        }
+        let delay = options
+            .interval
+            .saturating_sub(start.elapsed())
+            .saturating_add(random_fraction(options.jitter));
+        info!(delay_secs = delay.as_secs(), "Waiting for next backup");
+        sleep_unless_cancelled(delay, monitor.as_ref())?;
+    }
+}
+
+/// Return a random duration up to `max`.
+fn random_fraction(max: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    max.mul_f64(random as f64 / u64::MAX as f64)
+}
+
+fn sleep_unless_cancelled(duration: Duration, monitor: &dyn Monitor) -> Result<()> {
+    let deadline = Instant::now() + duration;
+    loop {
+        check_cancel(monitor)?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(());
+        }
+        sleep(remaining.min(CANCEL_POLL_INTERVAL));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::monitor::test::TestMonitor;
+    use crate::test_fixtures::{ScratchArchive, TreeFixture};
+
+    #[test]
+    fn repeated_backups_are_pruned() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        srcdir.create_file("hello");
+        let options = WatchOptions {
+            max_runs: Some(3),
+            prune: Some(PruneOptions {
+                keep_last: 2,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let monitor = TestMonitor::arc();
+        watch(
+            &af,
+            &[srcdir.path()],
+            &BackupOptions::default(),
+            &options,
+            monitor.clone(),
+        )
+        .unwrap();
+        monitor.assert_no_errors();
+        assert_eq!(
+            af.list_band_ids().unwrap(),
+            [BandId::new(&[1]), BandId::new(&[2])]
+        );
+    }
+
+    #[test]
+    fn cancel_while_waiting() {
+        let af = ScratchArchive::new();
+        let srcdir = TreeFixture::new();
+        let options = WatchOptions {
+            interval: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let monitor = TestMonitor::arc();
+        let canceller = monitor.clone();
+        let thread = std::thread::spawn(move || {
+            // Cancel once the first backup has started: it stops either during the
+            // backup or while waiting for the next.
+            while canceller.get_counter(crate::counters::Counter::Dirs) == 0 {
+                sleep(Duration::from_millis(10));
+            }
+            canceller.cancel();
+        });
+        let err = watch(
+            &af,
+            &[srcdir.path()],
+            &BackupOptions::default(),
+            &options,
+            monitor,
+        )
+        .unwrap_err();
+        thread.join().unwrap();
+        assert!(matches!(err, Error::Cancelled), "{err:?}");
+        assert_eq!(af.list_band_ids().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn jitter_is_bounded() {
+        let max = Duration::from_secs(10);
+        for _ in 0..100 {
+            assert!(random_fraction(max) <= max);
+        }
+        assert_eq!(random_fraction(Duration::ZERO), Duration::ZERO);
+    }
+}
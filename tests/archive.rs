@@ -125,3 +125,112 @@ fn create_bands() {
         0
     );
 }
+
+#[test]
+fn list_band_infos() {
+    let af = ScratchArchive::new();
+    assert!(af.list_band_infos(TestMonitor::arc()).unwrap().is_empty());
+
+    Band::create(&af).unwrap().close(0).unwrap();
+    Band::create(&af).unwrap();
+    Band::create(&af).unwrap().close(0).unwrap();
+    fs::remove_file(af.path().join("b0001/BANDHEAD")).unwrap();
+
+    let monitor = TestMonitor::arc();
+    let infos = af.list_band_infos(monitor.clone()).unwrap();
+    let ids: Vec<BandId> = infos.iter().map(|info| info.id).collect();
+    assert_eq!(ids, [BandId::new(&[0]), BandId::new(&[2])]);
+    assert!(infos
+        .iter()
+        .all(|info| info.is_closed && info.end_time.is_some()));
+    // The band with no head is reported and left out.
+    assert_eq!(monitor.take_errors().len(), 1);
+}
+
+#[test]
+fn archive_info() {
+    let af = ScratchArchive::new();
+    let info = af.info(TestMonitor::arc()).unwrap();
+    assert_eq!(info.format_version, conserve::ARCHIVE_VERSION);
+    assert_eq!(info.bands, 0);
+    assert_eq!(info.first_backup_time, None);
+    assert_eq!(info.blocks, 0);
+    assert_eq!(info.estimated_content_bytes, 0);
+
+    af.store_two_versions();
+    Band::create(&af).unwrap();
+    let monitor = TestMonitor::arc();
+    let info = af.info(monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    let infos = af.list_band_infos(TestMonitor::arc()).unwrap();
+    assert_eq!(info.bands, 3);
+    assert_eq!(info.incomplete_bands, 1);
+    assert_eq!(info.first_backup_time, Some(infos[0].start_time));
+    assert_eq!(info.last_backup_time, Some(infos[2].start_time));
+    assert_eq!(info.errors, 0);
+    let block_count = af.block_dir().blocks(TestMonitor::arc()).unwrap().count();
+    assert!(block_count > 0);
+    assert_eq!(info.blocks, block_count);
+    assert!(info.stored_bytes > 0);
+    // Every block is in the sample, so the estimate is exact.
+    let content_bytes: u64 = af
+        .block_dir()
+        .validate(TestMonitor::arc())
+        .unwrap()
+        .values()
+        .map(|len| *len as u64)
+        .sum();
+    assert_eq!(info.estimated_content_bytes, content_bytes);
+}
+
+#[test]
+fn readonly_archive_is_never_changed() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let archive =
+        Archive::open_readonly(conserve::transport::open_local_transport(af.path()).unwrap())
+            .unwrap();
+    assert!(archive.is_readonly());
+    assert!(!af.is_readonly());
+
+    let monitor = TestMonitor::arc();
+    archive
+        .validate(
+            &conserve::ValidateOptions::default().with_incremental(true),
+            monitor.clone(),
+        )
+        .unwrap();
+    monitor.assert_no_errors();
+    assert!(!af.path().join("VALIDATED_CONTENT").exists());
+
+    let restore_dir = TempDir::new().unwrap();
+    conserve::restore(
+        &archive,
+        restore_dir.path(),
+        &conserve::RestoreOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    restore_dir
+        .child("hello2")
+        .assert(predicates::path::exists());
+
+    let srcdir = conserve::test_fixtures::TreeFixture::new();
+    let err = conserve::backup(
+        &archive,
+        srcdir.path(),
+        &Default::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap_err();
+    assert!(
+        matches!(&err, conserve::Error::Transport { source }
+            if source.kind() == conserve::transport::ErrorKind::ReadOnly),
+        "{err:?}"
+    );
+    assert!(archive
+        .delete_bands(&[BandId::zero()], &Default::default(), TestMonitor::arc())
+        .is_err());
+    assert_eq!(archive.list_band_ids().unwrap().len(), 2);
+    assert!(!af.path().join("GC_LOCK").exists());
+}
@@ -0,0 +1,80 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for archive-wide deduplication statistics.
+
+use std::fs;
+
+use rayon::prelude::ParallelIterator;
+
+use conserve::blockdir::block_relpath;
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+/// Back up a tree, then again unchanged, then with a new file.
+fn archive_with_three_bands() -> ScratchArchive {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", &[1; 1000]);
+    let options = BackupOptions::default().with_small_file_cap(0);
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    srcdir.create_file_with_contents("b", &[2; 500]);
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    af
+}
+
+#[test]
+fn count_new_and_shared_blocks() {
+    let af = archive_with_three_bands();
+    let monitor = TestMonitor::arc();
+    let stats = archive_stats(&af, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+
+    let summary: Vec<(usize, usize, u64, u64)> = stats
+        .bands
+        .iter()
+        .map(|band| {
+            (
+                band.blocks,
+                band.new_blocks,
+                band.referenced_bytes,
+                band.new_bytes,
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [(1, 1, 1000, 1000), (1, 0, 1000, 0), (2, 1, 1500, 500)]
+    );
+    assert_eq!(stats.unique_blocks, 2);
+    assert_eq!(stats.referenced_bytes, 3500);
+    assert_eq!(stats.unique_bytes, 1500);
+    assert_eq!(stats.dedup_ratio(), 3500.0 / 1500.0);
+    assert!(stats.stored_bytes > 0);
+    assert_eq!(stats.errors, 0);
+}
+
+#[test]
+fn missing_blocks_are_errors() {
+    let af = archive_with_three_bands();
+    let blocks: Vec<BlockHash> = af.block_dir().blocks(TestMonitor::arc()).unwrap().collect();
+    let hash = &blocks[0];
+    fs::remove_file(af.path().join("d").join(block_relpath(hash))).unwrap();
+
+    let monitor = TestMonitor::arc();
+    let stats = archive_stats(&af, monitor.clone()).unwrap();
+    assert_eq!(stats.errors, 1);
+    assert_eq!(monitor.take_errors().len(), 1);
+    assert_eq!(stats.unique_blocks, 2);
+}
@@ -12,6 +12,7 @@
 
 //! Tests focused on backup behavior.
 
+use std::fs;
 use std::sync::Arc;
 
 use assert_fs::prelude::*;
@@ -22,6 +23,7 @@ use tracing_test::traced_test;
 
 use conserve::counters::Counter;
 use conserve::monitor::test::TestMonitor;
+use conserve::monitor::EntryOutcome;
 use conserve::test_fixtures::{ScratchArchive, TreeFixture};
 use conserve::*;
 
@@ -68,6 +70,90 @@ pub fn simple_backup() {
     monitor.assert_counter(Counter::FileBytes, 8);
 }
 
+#[test]
+fn band_records_origin() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .expect("backup");
+
+    let info = Band::open(&af, BandId::zero()).unwrap().get_info().unwrap();
+    assert_eq!(info.conserve_version.as_deref(), Some(conserve::version()));
+    assert_eq!(
+        info.sources,
+        [srcdir.path().canonicalize().unwrap().display().to_string()]
+    );
+    #[cfg(unix)]
+    {
+        assert!(info.hostname.is_some_and(|name| !name.is_empty()));
+        assert!(info.username.is_some());
+    }
+}
+
+#[test]
+fn backup_reports_entries_to_monitor() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_dir("subdir");
+
+    let monitor = TestMonitor::arc();
+    backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default(),
+        monitor.clone(),
+    )
+    .expect("backup");
+    assert_eq!(monitor.take_started_files(), [Apath::from("/hello")]);
+    assert_eq!(
+        monitor.take_finished_entries(),
+        [
+            (Apath::from("/"), EntryOutcome::Written, 0),
+            (Apath::from("/hello"), EntryOutcome::Written, 8),
+            (Apath::from("/subdir"), EntryOutcome::Written, 0),
+        ]
+    );
+
+    // Files that are the same as in the basis aren't written again.
+    let monitor = TestMonitor::arc();
+    backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default(),
+        monitor.clone(),
+    )
+    .expect("backup");
+    assert_eq!(
+        monitor.take_finished_entries()[1],
+        (Apath::from("/hello"), EntryOutcome::Unchanged, 0)
+    );
+}
+
+#[test]
+fn backup_stats_as_json() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_dir("subdir");
+
+    let stats = backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    let json = stats.to_json();
+    assert_eq!(json["files"], 1);
+    assert_eq!(json["directories"], 2);
+    assert_eq!(json["new_files"], 1);
+    assert_eq!(json["errors"], 0);
+    assert_eq!(json["uncompressed_bytes"], 8);
+    assert!(json["elapsed"]["secs"].is_u64());
+    assert!(json["elapsed"]["nanos"].is_u64());
+}
+
 #[test]
 #[traced_test]
 pub fn simple_backup_with_excludes() -> Result<()> {
@@ -79,10 +165,7 @@ pub fn simple_backup_with_excludes() -> Result<()> {
     srcdir.create_file("baz");
     // TODO: Include a symlink only on Unix.
     let exclude = Exclude::from_strings(["/**/baz", "/**/bar", "/**/fooo*"]).unwrap();
-    let options = BackupOptions {
-        exclude,
-        ..BackupOptions::default()
-    };
+    let options = BackupOptions::default().with_exclude(exclude);
     let monitor = TestMonitor::arc();
     let stats = backup(&af, srcdir.path(), &options, monitor.clone()).expect("backup");
 
@@ -126,6 +209,42 @@ pub fn simple_backup_with_excludes() -> Result<()> {
     Ok(())
 }
 
+#[test]
+#[traced_test]
+fn backup_restore_and_validate_spans() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &af,
+        restore_dir.path(),
+        &RestoreOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    af.validate(&ValidateOptions::default(), TestMonitor::arc())
+        .unwrap();
+    assert!(logs_contain(
+        "backup{band_id=b0000}:flush_group:write_index_hunk{hunk=0 entries=2 uncompressed_bytes="
+    ));
+    assert!(logs_contain("restore{band_id=b0000}:restore_file{path=\""));
+    assert!(logs_contain(
+        "restore{band_id=b0000}:read_index_hunk{hunk=0}"
+    ));
+    assert!(logs_contain("validate:validate_band{band_id=b0000}"));
+    assert!(logs_contain(
+        "validate:validate_blocks{blocks=1 skipped_blocks=0}"
+    ));
+}
+
 #[test]
 pub fn backup_more_excludes() {
     let af = ScratchArchive::new();
@@ -141,10 +260,7 @@ pub fn backup_more_excludes() {
     srcdir.create_file("bar");
 
     let exclude = Exclude::from_strings(["/**/foo*", "/**/baz"]).unwrap();
-    let options = BackupOptions {
-        exclude,
-        ..Default::default()
-    };
+    let options = BackupOptions::default().with_exclude(exclude);
     let stats = backup(&af, srcdir.path(), &options, TestMonitor::arc()).expect("backup");
 
     assert_eq!(1, stats.written_blocks);
@@ -215,10 +331,7 @@ fn large_file() {
     let backup_stats = backup(
         &af,
         tf.path(),
-        &BackupOptions {
-            max_block_size: 1 << 20,
-            ..Default::default()
-        },
+        &BackupOptions::default().with_max_block_size(1 << 20),
         monitor.clone(),
     )
     .expect("backup");
@@ -309,6 +422,44 @@ fn mtime_before_epoch() {
     .expect("backup shouldn't crash on before-epoch mtimes");
 }
 
+/// The mode, owner, and precise mtime of stored entries are available through
+/// [EntryTrait].
+#[cfg(unix)]
+#[test]
+fn stored_unix_metadata() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let tf = TreeFixture::new();
+    let file_path = tf.create_file("script");
+    fs::set_permissions(&file_path, fs::Permissions::from_mode(0o750)).unwrap();
+    set_file_mtime(
+        &file_path,
+        FileTime::from_unix_time(1_700_000_000, 123_456_789),
+    )
+    .unwrap();
+    let metadata = fs::metadata(&file_path).unwrap();
+
+    let af = ScratchArchive::new();
+    backup(
+        &af,
+        tf.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .expect("backup");
+    let entry = af
+        .open_stored_tree(BandSelectionPolicy::Latest)
+        .unwrap()
+        .lookup(&"/script".into())
+        .unwrap()
+        .expect("entry is stored");
+    assert_eq!(entry.mode_bits(), Some(0o750));
+    assert_eq!(entry.uid(), Some(metadata.uid()));
+    assert_eq!(entry.gid(), Some(metadata.gid()));
+    assert_eq!(entry.mtime().unix_timestamp(), 1_700_000_000);
+    assert_eq!(entry.mtime().nanosecond(), 123_456_789);
+}
+
 #[cfg(unix)]
 #[test]
 pub fn symlink() {
@@ -465,16 +616,15 @@ fn small_files_combined_two_backups() {
         TestMonitor::arc(),
     )
     .unwrap();
-    // Although the two files have the same content, we do not yet dedupe them
-    // within a combined block, so the block is different to when one identical
-    // file is stored alone. This could be fixed.
+    // The two files have the same content, so it's stored once in the combined block.
     assert_eq!(stats1.combined_blocks, 1);
     assert_eq!(stats1.new_files, 2);
     assert_eq!(stats1.written_blocks, 1);
-    assert_eq!(stats1.new_files, 2);
+    assert_eq!(stats1.small_combined_files, 2);
+    assert_eq!(stats1.deduplicated_small_files, 1);
 
-    // Add one more file, also identical, but it is not combined with the previous blocks.
-    // This is a shortcoming of the current dedupe approach.
+    // Add one more file, also identical. Its combined block has the same content
+    // as the first, so it's deduplicated.
     srcdir.create_file("file3");
     let stats2 = backup(
         &af,
@@ -485,12 +635,13 @@ fn small_files_combined_two_backups() {
     .unwrap();
     assert_eq!(stats2.new_files, 1);
     assert_eq!(stats2.unmodified_files, 2);
-    assert_eq!(stats2.written_blocks, 1);
+    assert_eq!(stats2.written_blocks, 0);
+    assert_eq!(stats2.deduplicated_blocks, 1);
     assert_eq!(stats2.combined_blocks, 1);
 
     assert_eq!(
         af.block_dir().blocks(TestMonitor::arc()).unwrap().count(),
-        2
+        1
     );
 }
 
@@ -507,10 +658,7 @@ fn many_small_files_combined_to_one_block() {
             format!("something about {i}").as_bytes(),
         );
     }
-    let backup_options = BackupOptions {
-        max_entries_per_hunk: 1000,
-        ..Default::default()
-    };
+    let backup_options = BackupOptions::default().with_max_entries_per_hunk(1000);
     let monitor = TestMonitor::arc();
     let stats = backup(&af, srcdir.path(), &backup_options, monitor.clone()).expect("backup");
     assert_eq!(
@@ -545,6 +693,43 @@ fn many_small_files_combined_to_one_block() {
     );
 }
 
+#[test]
+fn combined_blocks_fit_in_max_block_size() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..10 {
+        let content = format!("file {i} ").repeat(50);
+        srcdir.create_file_with_contents(&format!("file{i}"), content.as_bytes());
+        // An identical copy of each file is stored by reference to the first.
+        srcdir.create_file_with_contents(&format!("same{i}"), content.as_bytes());
+    }
+    let backup_options = BackupOptions::default().with_max_block_size(1000);
+    let stats = backup(&af, srcdir.path(), &backup_options, TestMonitor::arc()).unwrap();
+    assert_eq!(stats.small_combined_files, 20);
+    assert_eq!(stats.deduplicated_small_files, 10);
+    // Each file is 350 bytes, so only two fit in each block.
+    assert_eq!(stats.combined_blocks, 5);
+    assert_eq!(stats.uncompressed_bytes, 10 * 350);
+
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &af,
+        restore_dir.path(),
+        &RestoreOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    for i in 0..10 {
+        let content = format!("file {i} ").repeat(50);
+        for name in [format!("file{i}"), format!("same{i}")] {
+            assert_eq!(
+                fs::read_to_string(restore_dir.path().join(name)).unwrap(),
+                content
+            );
+        }
+    }
+}
+
 #[test]
 pub fn mixed_medium_small_files_two_hunks() {
     let af = ScratchArchive::new();
@@ -559,11 +744,9 @@ pub fn mixed_medium_small_files_two_hunks() {
             srcdir.create_file(&name);
         }
     }
-    let backup_options = BackupOptions {
-        max_entries_per_hunk: 1000,
-        small_file_cap: 100_000,
-        ..Default::default()
-    };
+    let backup_options = BackupOptions::default()
+        .with_max_entries_per_hunk(1000)
+        .with_small_file_cap(100_000);
     let monitor = TestMonitor::arc();
     let stats = backup(&af, srcdir.path(), &backup_options, monitor.clone()).expect("backup");
     assert_eq!(
@@ -579,8 +762,10 @@ pub fn mixed_medium_small_files_two_hunks() {
     assert_eq!(stats.single_block_files, 20);
     assert_eq!(stats.small_combined_files, 1999 - 20);
     assert_eq!(stats.errors, 0);
-    // There's one deduped block for all the large files, and then one per hunk for all the small combined files.
-    assert_eq!(stats.written_blocks, 3);
+    // There's one deduped block for all the large files. The small files all have the same
+    // content, so each hunk's combined block holds one copy, and the second is deduped.
+    assert_eq!(stats.written_blocks, 2);
+    assert_eq!(stats.combined_blocks, 2);
 
     let tree = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
     let mut entry_iter = tree
@@ -609,10 +794,7 @@ fn detect_unchanged_from_stitched_index() {
     let stats = backup(
         &af,
         srcdir.path(),
-        &BackupOptions {
-            max_entries_per_hunk: 1,
-            ..Default::default()
-        },
+        &BackupOptions::default().with_max_entries_per_hunk(1),
         monitor.clone(),
     )
     .unwrap();
@@ -626,10 +808,7 @@ fn detect_unchanged_from_stitched_index() {
     let stats = backup(
         &af,
         srcdir.path(),
-        &BackupOptions {
-            max_entries_per_hunk: 1,
-            ..Default::default()
-        },
+        &BackupOptions::default().with_max_entries_per_hunk(1),
         monitor.clone(),
     )
     .unwrap();
@@ -649,13 +828,687 @@ fn detect_unchanged_from_stitched_index() {
     let stats = backup(
         &af,
         srcdir.path(),
-        &BackupOptions {
-            max_entries_per_hunk: 1,
-            ..Default::default()
-        },
+        &BackupOptions::default().with_max_entries_per_hunk(1),
         monitor.clone(),
     )
     .unwrap();
     assert_eq!(stats.unmodified_files, 2, "both files are unmodified");
     assert_eq!(monitor.get_counter(Counter::IndexWrites), 3);
 }
+
+#[test]
+fn backup_with_zstd_compression() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_of_length_with_prefix("big", 2 << 20, b"something");
+    srcdir.create_file("small");
+    let options = BackupOptions::default().with_compression(Some(Compression::Zstd { level: 19 }));
+    let stats = backup(&af, srcdir.path(), &options, TestMonitor::arc()).expect("backup");
+    assert_eq!(stats.written_blocks, 2);
+    assert!(stats.compressed_bytes < stats.uncompressed_bytes);
+
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    assert_eq!(band.format_flags(), ["tagged_blocks"]);
+    assert_eq!(band.band_format_version(), Some("23.2.0"));
+    let info = band.get_info().unwrap();
+    assert_eq!(
+        info.block_format,
+        Some(conserve::compress::BlockFormat::Tagged)
+    );
+    assert_eq!(info.compression, Some(Compression::Zstd { level: 19 }));
+    assert_eq!(info.block_hash.as_deref(), Some("blake2b-512"));
+
+    // A later backup with the default compression can still reference the zstd blocks,
+    // so it must also be flagged.
+    backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .expect("second backup");
+    let band = Band::open(&af, BandId::new(&[1])).unwrap();
+    assert_eq!(band.format_flags(), ["tagged_blocks"]);
+
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &af,
+        restore_dir.path(),
+        &RestoreOptions::default(),
+        TestMonitor::arc(),
+    )
+    .expect("restore");
+    restore_dir.child("small").assert("contents");
+    assert_eq!(
+        std::fs::read(restore_dir.child("big").path()).unwrap(),
+        std::fs::read(srcdir.path().join("big")).unwrap()
+    );
+}
+
+#[test]
+fn backup_with_binary_index() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_dir("subdir");
+    srcdir.create_file("subdir/subfile");
+    let options = BackupOptions::default().with_index_format(IndexFormat::Binary);
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).expect("backup");
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    assert_eq!(band.format_flags(), ["binary_index"]);
+    assert_eq!(band.band_format_version(), Some("23.2.0"));
+    assert_eq!(band.get_info().unwrap().index_format, IndexFormat::Binary);
+
+    // The next backup, in json, finds the unchanged files in the binary basis index.
+    let stats = backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .expect("second backup");
+    assert_eq!(stats.unmodified_files, 2);
+    let band = Band::open(&af, BandId::new(&[1])).unwrap();
+    assert!(band.format_flags().is_empty());
+    assert_eq!(band.index_format(), IndexFormat::Json);
+
+    let monitor = TestMonitor::arc();
+    af.validate(&ValidateOptions::default(), monitor.clone())
+        .unwrap();
+    monitor.assert_no_errors();
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &af,
+        restore_dir.path(),
+        &RestoreOptions::default()
+            .with_band_selection(BandSelectionPolicy::Specified(BandId::zero())),
+        TestMonitor::arc(),
+    )
+    .expect("restore");
+    restore_dir.child("hello").assert("contents");
+    restore_dir.child("subdir/subfile").assert("contents");
+}
+
+#[test]
+fn backup_with_index_checksums() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_dir("subdir");
+    srcdir.create_file("subdir/subfile");
+    let options = BackupOptions::default().with_index_checksums(true);
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).expect("backup");
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    assert_eq!(band.format_flags(), ["index_checksums"]);
+    assert_eq!(band.band_format_version(), Some("23.2.0"));
+
+    let monitor = TestMonitor::arc();
+    af.validate(&ValidateOptions::default(), monitor.clone())
+        .unwrap();
+    monitor.assert_no_errors();
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &af,
+        restore_dir.path(),
+        &RestoreOptions::default(),
+        TestMonitor::arc(),
+    )
+    .expect("restore");
+    restore_dir.child("hello").assert("contents");
+    restore_dir.child("subdir/subfile").assert("contents");
+}
+
+#[test]
+fn archive_default_compression_is_used_by_backup() {
+    let tempdir = TempDir::new().unwrap();
+    let archive_path = tempdir.child("archive");
+    let archive = Archive::create_with_compression(
+        open_transport(archive_path.path().to_str().unwrap()).unwrap(),
+        Some(Compression::None),
+    )
+    .unwrap();
+    assert_eq!(archive.default_compression(), Compression::None);
+    let archive = Archive::open_path(archive_path.path()).unwrap();
+    assert_eq!(archive.default_compression(), Compression::None);
+
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    let stats = backup(
+        &archive,
+        srcdir.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .expect("backup");
+    // Uncompressed data plus the two-byte block header.
+    assert_eq!(stats.uncompressed_bytes, 8);
+    assert_eq!(stats.compressed_bytes, 10);
+    archive
+        .validate(&ValidateOptions::default(), TestMonitor::arc())
+        .expect("validate");
+}
+
+#[test]
+fn backup_with_zstd_dictionary() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..500 {
+        srcdir.create_file_with_contents(
+            &format!("msg{i:04}"),
+            format!("From: user{i}@example.com\nSubject: Message {i}\n\nHello from {i}!\n")
+                .as_bytes(),
+        );
+    }
+    let options = BackupOptions::default().with_zstd_dictionary(true);
+    let stats = backup(&af, srcdir.path(), &options, TestMonitor::arc()).expect("backup");
+    assert_eq!(stats.errors, 0);
+    let dictionary = af.zstd_dictionary().expect("archive has a dictionary");
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    assert_eq!(band.format_flags(), ["tagged_blocks"]);
+
+    // The dictionary is kept by later backups, and read back when the archive is reopened.
+    srcdir.create_file_with_contents("msg9999", b"From: new@example.com\nSubject: New\n");
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).expect("second backup");
+    let archive = Archive::open_path(af.path()).unwrap();
+    assert_eq!(archive.zstd_dictionary(), Some(dictionary));
+    archive
+        .validate(&ValidateOptions::default(), TestMonitor::arc())
+        .expect("validate");
+
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &archive,
+        restore_dir.path(),
+        &RestoreOptions::default(),
+        TestMonitor::arc(),
+    )
+    .expect("restore");
+    restore_dir
+        .child("msg0123")
+        .assert("From: user123@example.com\nSubject: Message 123\n\nHello from 123!\n");
+    restore_dir
+        .child("msg9999")
+        .assert("From: new@example.com\nSubject: New\n");
+}
+
+#[test]
+fn zstd_dictionary_stored_by_another_process_is_used() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..500 {
+        srcdir.create_file_with_contents(
+            &format!("msg{i:04}"),
+            format!("From: user{i}@example.com\nSubject: Message {i}\n\nHello from {i}!\n")
+                .as_bytes(),
+        );
+    }
+    let options = BackupOptions::default().with_zstd_dictionary(true);
+    // Another process stores a dictionary after this one opened the archive.
+    let other = Archive::open_path(af.path()).unwrap();
+    backup(&other, srcdir.path(), &options, TestMonitor::arc()).expect("other backup");
+    let dictionary = other.zstd_dictionary().expect("archive has a dictionary");
+    assert!(af.zstd_dictionary().is_none());
+
+    srcdir.create_file_with_contents("msg9999", b"From: new@example.com\nSubject: New\n");
+    let stats = backup(&af, srcdir.path(), &options, TestMonitor::arc()).expect("backup");
+    assert_eq!(stats.errors, 0);
+    assert_eq!(af.zstd_dictionary(), Some(dictionary.clone()));
+    let archive = Archive::open_path(af.path()).unwrap();
+    assert_eq!(archive.zstd_dictionary(), Some(dictionary));
+    archive
+        .validate(&ValidateOptions::default(), TestMonitor::arc())
+        .expect("validate");
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &archive,
+        restore_dir.path(),
+        &RestoreOptions::default(),
+        TestMonitor::arc(),
+    )
+    .expect("restore");
+    restore_dir
+        .child("msg9999")
+        .assert("From: new@example.com\nSubject: New\n");
+}
+
+#[test]
+fn zstd_dictionary_not_trained_from_too_few_files() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    let options = BackupOptions::default().with_zstd_dictionary(true);
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).expect("backup");
+    assert!(af.zstd_dictionary().is_none());
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    assert!(band.format_flags().is_empty());
+}
+
+#[test]
+fn backup_multiple_sources() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("etc");
+    srcdir.create_file("etc/hosts");
+    srcdir.create_dir("home");
+    srcdir.create_file("home/notes");
+
+    let monitor = TestMonitor::arc();
+    let stats = backup_sources(
+        &af,
+        &[srcdir.path().join("home"), srcdir.path().join("etc")],
+        &BackupOptions::default().with_exclude(Exclude::from_strings(["/home/notes"]).unwrap()),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.directories, 3);
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    let apaths = band
+        .index()
+        .iter_entries()
+        .map(|entry| entry.apath.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(apaths, ["/", "/etc", "/home", "/etc/hosts"]);
+
+    let err = backup_sources(
+        &af,
+        &[srcdir.path().join("etc"), srcdir.path().join("etc")],
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap_err();
+    assert!(matches!(err, Error::DuplicateSourceName { ref name } if name == "etc"));
+}
+
+#[test]
+fn multiple_sources_are_excluded_like_subdirectories() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("etc");
+    srcdir.create_file("etc/hosts");
+    srcdir.create_dir("cache");
+    srcdir.create_file("cache/junk");
+    cachedir::add_tag(srcdir.path().join("cache")).unwrap();
+    let sources = [srcdir.path().join("cache"), srcdir.path().join("etc")];
+
+    let stats =
+        backup_sources(&af, &sources, &BackupOptions::default(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.cachedirs_skipped, 1);
+    assert_eq!(stats.files, 1);
+    let tree = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let entries = tree
+        .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+        .unwrap()
+        .collect::<Vec<_>>();
+    let apaths = entries
+        .iter()
+        .map(|entry| entry.apath().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(apaths, ["/", "/etc", "/etc/hosts"]);
+
+    // The root, which isn't on the filesystem, has the newest mtime of the sources.
+    let newest = sources
+        .iter()
+        .map(|path| path.metadata().unwrap().modified().unwrap())
+        .max()
+        .unwrap();
+    assert_eq!(entries[0].mtime(), time::OffsetDateTime::from(newest));
+}
+
+#[test]
+fn backup_stream_from_reader() {
+    let af = ScratchArchive::new();
+    let content = b"CREATE TABLE dumps;\n".repeat(100_000);
+    let monitor = TestMonitor::arc();
+    let stats = backup_stream(
+        &af,
+        &Apath::from("/dumps/db.sql"),
+        &mut content.as_slice(),
+        &BackupOptions::default().with_max_block_size(1 << 20),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.directories, 2);
+    assert_eq!(stats.multi_block_files, 1);
+
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    assert!(band.is_closed().unwrap());
+    let entries = band.index().iter_entries().collect::<Vec<IndexEntry>>();
+    assert_eq!(
+        entries
+            .iter()
+            .map(|entry| entry.apath.to_string())
+            .collect::<Vec<_>>(),
+        ["/", "/dumps", "/dumps/db.sql"]
+    );
+    assert_eq!(entries[2].kind, Kind::File);
+    assert_eq!(entries[2].size(), Some(content.len() as u64));
+
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &af,
+        restore_dir.path(),
+        &RestoreOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    assert_eq!(
+        std::fs::read(restore_dir.path().join("dumps/db.sql")).unwrap(),
+        content
+    );
+}
+
+#[test]
+fn change_cache_detects_replaced_file() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", b"hello");
+    srcdir.create_file_with_contents("b", b"world");
+    let cache_dir = TempDir::new().unwrap();
+    let cache_path = cache_dir.child("conserve.cache");
+    let options = BackupOptions::default().with_change_cache(Some(cache_path.to_path_buf()));
+
+    let stats = backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    assert_eq!(stats.new_files, 2);
+    cache_path.assert(predicates::path::is_file());
+
+    // Replace "a" with a new file of the same size and mtime, which can only be
+    // noticed because it has a different inode.
+    let a_path = srcdir.path().join("a");
+    let mtime = FileTime::from_last_modification_time(&a_path.metadata().unwrap());
+    let new_path = srcdir.create_file_with_contents("a.new", b"jello");
+    set_file_mtime(&new_path, mtime).unwrap();
+    std::fs::rename(&new_path, &a_path).unwrap();
+
+    let monitor = TestMonitor::arc();
+    let stats = backup(&af, srcdir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    if cfg!(unix) {
+        assert_eq!(stats.modified_files, 1);
+        assert_eq!(stats.unmodified_files, 1);
+
+        let restore_dir = TempDir::new().unwrap();
+        restore(
+            &af,
+            restore_dir.path(),
+            &RestoreOptions::default(),
+            TestMonitor::arc(),
+        )
+        .unwrap();
+        restore_dir.child("a").assert("jello");
+        restore_dir.child("b").assert("world");
+    }
+}
+
+#[test]
+fn cachedir_tagged_directories_are_skipped() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    srcdir.create_dir("cache");
+    srcdir.create_file("cache/junk");
+    cachedir::add_tag(srcdir.path().join("cache")).unwrap();
+
+    let stats = backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    assert_eq!(stats.cachedirs_skipped, 1);
+    assert_eq!(stats.files, 1);
+
+    let stats = backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default().with_exclude(Exclude::nothing().with_skip_cachedirs(false)),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    assert_eq!(stats.cachedirs_skipped, 0);
+    assert_eq!(stats.files, 3);
+    let names = af
+        .open_stored_tree(BandSelectionPolicy::Latest)
+        .unwrap()
+        .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+        .unwrap()
+        .map(|entry| entry.apath().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [
+            "/",
+            "/cache",
+            "/hello",
+            "/cache/CACHEDIR.TAG",
+            "/cache/junk"
+        ]
+    );
+}
+
+#[test]
+fn exclude_files_larger_than_limit() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("small", b"hello");
+    srcdir.create_dir("sub");
+    srcdir.create_file_with_contents("sub/core", &[0; 10_000]);
+
+    let stats = backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default()
+            .with_exclude(Exclude::nothing().with_exclude_larger_than(Some(1000))),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.large_files_skipped, 1);
+    assert_eq!(stats.large_file_bytes_skipped, 10_000);
+    let names = af
+        .open_stored_tree(BandSelectionPolicy::Latest)
+        .unwrap()
+        .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+        .unwrap()
+        .map(|entry| entry.apath().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["/", "/small", "/sub"]);
+}
+
+#[test]
+fn exclude_files_modified_before_cutoff() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("new");
+    srcdir.create_dir("old_dir");
+    let old_path = srcdir.create_file("old_dir/old");
+    set_file_mtime(&old_path, FileTime::from_unix_time(1_000_000_000, 0)).unwrap();
+    set_file_mtime(
+        srcdir.path().join("old_dir"),
+        FileTime::from_unix_time(1_000_000_000, 0),
+    )
+    .unwrap();
+
+    let cutoff = time::OffsetDateTime::now_utc() - time::Duration::days(30);
+    let stats = backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default()
+            .with_exclude(Exclude::nothing().with_exclude_modified_before(Some(cutoff))),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.old_files_skipped, 1);
+    let names = af
+        .open_stored_tree(BandSelectionPolicy::Latest)
+        .unwrap()
+        .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+        .unwrap()
+        .map(|entry| entry.apath().to_string())
+        .collect::<Vec<_>>();
+    // Old directories are still included, in case they contain new files.
+    assert_eq!(names, ["/", "/new", "/old_dir"]);
+}
+
+#[test]
+fn select_band_by_label() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    for label in ["daily", "pre-upgrade", "daily"] {
+        backup(
+            &af,
+            srcdir.path(),
+            &BackupOptions::default()
+                .with_label(Some(label.to_owned()))
+                .with_message(Some(format!("{label} snapshot"))),
+            TestMonitor::arc(),
+        )
+        .unwrap();
+    }
+
+    assert_eq!(
+        af.resolve_band_id(BandSelectionPolicy::Label("daily".to_owned()))
+            .unwrap(),
+        BandId::new(&[2])
+    );
+    let band_id = af
+        .resolve_band_id(BandSelectionPolicy::Label("pre-upgrade".to_owned()))
+        .unwrap();
+    assert_eq!(band_id, BandId::new(&[1]));
+    let info = Band::open(&af, band_id).unwrap().get_info().unwrap();
+    assert_eq!(info.label.as_deref(), Some("pre-upgrade"));
+    assert_eq!(info.message.as_deref(), Some("pre-upgrade snapshot"));
+    assert!(matches!(
+        af.resolve_band_id(BandSelectionPolicy::Label("weekly".to_owned())),
+        Err(Error::NoBandWithLabel { .. })
+    ));
+}
+
+#[test]
+fn content_defined_chunking_deduplicates_after_insertion() {
+    use rand::{RngCore, SeedableRng};
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let mut content = vec![0; 12 << 20];
+    rand::rngs::StdRng::seed_from_u64(1).fill_bytes(&mut content);
+    srcdir.create_file_with_contents("big", &content);
+    let options = BackupOptions::default().with_content_defined_chunking(true);
+    let stats = backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    assert!(stats.written_blocks > 1);
+    let first_blocks = stats.written_blocks;
+
+    // Inserting a few bytes at the start changes only the first block.
+    content.splice(0..0, *b"inserted");
+    srcdir.create_file_with_contents("big", &content);
+    let stats = backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    assert_eq!(stats.modified_files, 1);
+    assert!(stats.written_blocks <= 2, "{stats:?}");
+    assert!(stats.deduplicated_blocks >= first_blocks - 2, "{stats:?}");
+
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &af,
+        restore_dir.path(),
+        &RestoreOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    assert_eq!(fs::read(restore_dir.path().join("big")).unwrap(), content);
+}
+
+#[cfg(not(windows))]
+#[test]
+fn use_vss_is_unsupported_on_this_platform() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file("hello");
+    let result = backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default().with_use_vss(true),
+        TestMonitor::arc(),
+    );
+    assert!(matches!(result, Err(Error::ShadowCopyUnsupported)));
+    assert_eq!(af.list_band_ids().unwrap(), []);
+}
+
+#[test]
+fn max_read_rate_slows_backup() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    // One second's worth of reads is allowed immediately; the rest must wait.
+    srcdir.create_file_with_contents("large", &[7; 300_000]);
+    let options = BackupOptions::default().with_max_read_rate(Some(100_000));
+    let start = std::time::Instant::now();
+    let stats = backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    assert!(
+        start.elapsed() >= std::time::Duration::from_millis(1900),
+        "{:?}",
+        start.elapsed()
+    );
+    assert_eq!(stats.files, 1);
+}
+
+#[test]
+fn blocks_written_on_several_threads() {
+    use rand::{RngCore, SeedableRng};
+
+    let srcdir = TreeFixture::new();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(2);
+    for i in 0..20 {
+        let mut content = vec![0; 40_000 + i * 1000];
+        rng.fill_bytes(&mut content);
+        srcdir.create_file_with_contents(&format!("distinct{i:02}"), &content);
+        // Many copies of the same content, which should be written only once
+        // even when several threads store it at the same time.
+        srcdir.create_file_with_contents(&format!("same{i:02}"), &[42; 50_000]);
+        srcdir.create_file_with_contents(&format!("small{i:02}"), format!("{i}").as_bytes());
+    }
+    let mut all_stats = Vec::new();
+    for threads in [1, 4] {
+        let af = ScratchArchive::new();
+        let options = BackupOptions::default()
+            .with_small_file_cap(1000)
+            .with_max_block_size(10_000)
+            .with_max_entries_per_hunk(7)
+            .with_parallelism(Parallelism::default().with_threads(threads));
+        let stats = backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+        assert_eq!(stats.errors, 0);
+        let restore_dir = TempDir::new().unwrap();
+        restore(
+            &af,
+            restore_dir.path(),
+            &RestoreOptions::default(),
+            TestMonitor::arc(),
+        )
+        .unwrap();
+        for i in 0..20 {
+            for name in [
+                format!("distinct{i:02}"),
+                format!("same{i:02}"),
+                format!("small{i:02}"),
+            ] {
+                assert_eq!(
+                    fs::read(restore_dir.path().join(&name)).unwrap(),
+                    fs::read(srcdir.path().join(&name)).unwrap(),
+                    "{name} with {threads} threads"
+                );
+            }
+        }
+        all_stats.push(stats);
+    }
+    let [one, four] = all_stats.as_slice() else {
+        unreachable!()
+    };
+    assert_eq!(one.written_blocks, four.written_blocks);
+    assert_eq!(one.deduplicated_blocks, four.deduplicated_blocks);
+    assert_eq!(one.compressed_bytes, four.compressed_bytes);
+    assert_eq!(one.combined_blocks, four.combined_blocks);
+}
@@ -0,0 +1,91 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests of the public BlockDir API, as used by external tools.
+
+use std::fs;
+
+use rayon::prelude::ParallelIterator;
+
+use conserve::blockdir::{block_relpath, PACK_DIR};
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+#[test]
+fn list_check_and_read_blocks() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", &[b'a'; 100]);
+    srcdir.create_file_with_contents("b", &[b'b'; 100]);
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    srcdir.create_file_with_contents("c", &[b'c'; 100]);
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    let block_dir = af.block_dir();
+
+    let blocks: Vec<BlockHash> = block_dir.blocks(TestMonitor::arc()).unwrap().collect();
+    assert_eq!(blocks.len(), 2);
+    for hash in &blocks {
+        assert!(block_dir.contains(hash, TestMonitor::arc()).unwrap());
+        let content = block_dir
+            .get_block_content(hash, TestMonitor::arc())
+            .unwrap();
+        assert_eq!(BlockHash::hash_bytes(&content), *hash);
+        let compressed = block_dir.read_compressed(hash).unwrap();
+        assert_eq!(
+            compressed,
+            fs::read(af.path().join("d").join(block_relpath(hash))).unwrap()
+        );
+        assert_eq!(
+            block_dir.compressed_size(hash).unwrap(),
+            compressed.len() as u64
+        );
+        assert_eq!(block_dir.pack_holding(hash).unwrap(), None);
+    }
+
+    let absent = BlockHash::hash_bytes(b"not stored");
+    assert!(!block_dir.contains(&absent, TestMonitor::arc()).unwrap());
+    assert!(block_dir.read_compressed(&absent).is_err());
+    assert_eq!(block_dir.pack_holding(&absent).unwrap(), None);
+}
+
+#[test]
+fn read_packed_blocks() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for name in ["a", "b"] {
+        srcdir.create_file_with_contents(name, name.repeat(100).as_bytes());
+        backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    }
+    let mut compressed = Vec::new();
+    for hash in af
+        .block_dir()
+        .blocks(TestMonitor::arc())
+        .unwrap()
+        .collect::<Vec<_>>()
+    {
+        compressed.push((hash.clone(), af.block_dir().read_compressed(&hash).unwrap()));
+    }
+    compact(&af, &CompactOptions::default(), TestMonitor::arc()).unwrap();
+
+    let archive = Archive::open_path(af.path()).unwrap();
+    let block_dir = archive.block_dir();
+    for (hash, compressed) in compressed {
+        let pack = block_dir
+            .pack_holding(&hash)
+            .unwrap()
+            .expect("block is packed");
+        assert!(af.path().join("d").join(PACK_DIR).join(&*pack).is_file());
+        assert!(block_dir.contains(&hash, TestMonitor::arc()).unwrap());
+        assert_eq!(block_dir.read_compressed(&hash).unwrap(), compressed);
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for cancelling long operations through the monitor.
+
+use assert_matches::assert_matches;
+
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+fn source_tree() -> TreeFixture {
+    let srcdir = TreeFixture::new();
+    for i in 0..10 {
+        srcdir.create_file(&format!("file{i}"));
+    }
+    srcdir
+}
+
+#[test]
+fn cancelled_backup_leaves_incomplete_band() {
+    let af = ScratchArchive::new();
+    let srcdir = source_tree();
+    let monitor = TestMonitor::arc();
+    let cancel_monitor = monitor.clone();
+    let options =
+        BackupOptions::default().with_change_callback(Some(Box::new(move |entry_change| {
+            if entry_change.apath == "/file3" {
+                cancel_monitor.cancel();
+            }
+            Ok(())
+        })));
+    let err = backup(&af, srcdir.path(), &options, monitor.clone()).unwrap_err();
+    assert_matches!(err, Error::Cancelled);
+    assert_eq!(af.list_band_ids().unwrap(), [BandId::zero()]);
+    assert!(!af.band_is_closed(BandId::zero()).unwrap());
+
+    // The archive is still valid, and the next backup completes.
+    let monitor = TestMonitor::arc();
+    af.validate(&ValidateOptions::default(), monitor.clone())
+        .unwrap();
+    monitor.assert_no_errors();
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    assert!(af.band_is_closed(BandId::new(&[1])).unwrap());
+}
+
+#[test]
+fn cancelled_restore() {
+    let af = ScratchArchive::new();
+    let srcdir = source_tree();
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let destdir = TreeFixture::new();
+    let monitor = TestMonitor::arc();
+    let cancel_monitor = monitor.clone();
+    let options =
+        RestoreOptions::default().with_change_callback(Some(Box::new(move |entry_change| {
+            if entry_change.apath == "/file3" {
+                cancel_monitor.cancel();
+            }
+            Ok(())
+        })));
+    let err = restore(&af, destdir.path(), &options, monitor.clone()).unwrap_err();
+    assert_matches!(err, Error::Cancelled);
+    assert!(destdir.path().join("file3").is_file());
+    assert!(!destdir.path().join("file4").exists());
+}
+
+#[test]
+fn cancelled_validate_records_nothing() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let monitor = TestMonitor::arc();
+    monitor.cancel();
+    let options = ValidateOptions::default().with_incremental(true);
+    let err = af.validate(&options, monitor.clone()).unwrap_err();
+    assert_matches!(err, Error::Cancelled);
+    monitor.assert_no_errors();
+    assert!(af.damaged_bands().unwrap().is_empty());
+    assert!(af.last_validated().unwrap().is_none());
+
+    let err = af
+        .validate_band(BandId::zero(), &options, monitor.clone())
+        .unwrap_err();
+    assert_matches!(err, Error::Cancelled);
+}
+
+#[test]
+fn cancelled_delete_keeps_bands() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let monitor = TestMonitor::arc();
+    monitor.cancel();
+    let err = af
+        .delete_bands(&[BandId::zero()], &Default::default(), monitor)
+        .unwrap_err();
+    assert_matches!(err, Error::Cancelled);
+    assert_eq!(af.list_band_ids().unwrap().len(), 2);
+    // The gc lock was released.
+    af.delete_bands(&[BandId::zero()], &Default::default(), TestMonitor::arc())
+        .unwrap();
+    assert_eq!(af.list_band_ids().unwrap(), [BandId::new(&[1])]);
+}
@@ -139,6 +139,14 @@ fn basic_backup() {
         .stderr(predicate::str::is_empty())
         .stdout("0 MB\n"); // "contents"
 
+    run_conserve()
+        .args(["size", "--json"])
+        .arg(&arch_dir)
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty())
+        .stdout("{\"file_bytes\":24}\n");
+
     run_conserve()
         .args(["versions", "--short"])
         .arg(&arch_dir)
@@ -348,15 +356,26 @@ fn incomplete_version() {
     // ls succeeds on an incomplete band
     run_conserve().arg("ls").arg(af.path()).assert().success();
 
-    // Cannot gc with an empty band.
+    // Cannot gc with an empty band, which might be a backup still in progress.
     run_conserve()
         .arg("gc")
         .arg(af.path())
         .assert()
-        .failure()
+        .code(4)
         .stderr(predicate::str::contains("incomplete and may be in use"));
 }
 
+#[test]
+fn restore_from_damaged_archive() {
+    let dest = TempDir::new().unwrap();
+    run_conserve()
+        .args(["restore", "testdata/damaged/missing-block"])
+        .arg(dest.path())
+        .assert()
+        .code(3)
+        .stderr(predicate::str::contains("code=\"restore_file_block\""));
+}
+
 #[test]
 fn restore_only_subtree() {
     let dest = TempDir::new().unwrap();
@@ -380,6 +399,83 @@ fn restore_only_subtree() {
     dest.close().unwrap();
 }
 
+#[test]
+fn restore_only_glob() {
+    let dest = TempDir::new().unwrap();
+    run_conserve()
+        .args([
+            "restore",
+            "testdata/archive/minimal/v0.6.3/",
+            "--only",
+            "/**/sub*",
+            "--only",
+            "/nonexistent",
+        ])
+        .arg(dest.path())
+        .assert()
+        .success();
+
+    dest.child("hello").assert(predicate::path::missing());
+    dest.child("subdir")
+        .child("subfile")
+        .assert("I like Rust\n");
+
+    dest.close().unwrap();
+}
+
+#[test]
+fn restore_skip_existing() {
+    let dest = TempDir::new().unwrap();
+    dest.child("hello").write_str("mine\n").unwrap();
+    run_conserve()
+        .args([
+            "restore",
+            "testdata/archive/minimal/v0.6.3/",
+            "--overwrite=skip-existing",
+        ])
+        .arg(dest.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Kept existing"));
+
+    dest.child("hello").assert("mine\n");
+    dest.child("subdir")
+        .child("subfile")
+        .assert("I like Rust\n");
+
+    dest.close().unwrap();
+}
+
+#[test]
+fn export_tar_to_stdout() {
+    let output = run_conserve()
+        .args(["export-tar", "testdata/archive/minimal/v0.6.3/"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    assert_eq!(output.len() % 10240, 0);
+    let text = String::from_utf8_lossy(&output);
+    assert!(text.contains("subdir/subfile"));
+    assert!(text.contains("I like Rust\n"));
+}
+
+#[test]
+fn cat_stored_file() {
+    run_conserve()
+        .args(["cat", "testdata/archive/minimal/v0.6.3/", "/subdir/subfile"])
+        .assert()
+        .success()
+        .stdout("I like Rust\n");
+
+    run_conserve()
+        .args(["cat", "testdata/archive/minimal/v0.6.3/", "/subdir"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("/subdir is a Dir, not a file"));
+}
+
 #[test]
 fn size_exclude() {
     let source = TreeFixture::new();
@@ -99,3 +99,197 @@ fn verbose_backup_does_not_print_unchanged_files() {
             * /b
         "});
 }
+
+#[test]
+fn backup_with_compression() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    run_conserve()
+        .args(["backup", "--no-stats", "--compression", "zstd:7"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    run_conserve()
+        .args(["validate"])
+        .arg(af.path())
+        .assert()
+        .success();
+}
+
+#[cfg(unix)]
+#[test]
+fn backup_with_resource_limits() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+
+    run_conserve()
+        .args([
+            "--threads",
+            "2",
+            "backup",
+            "--nice",
+            "5",
+            "--max-read-rate",
+            "10MB",
+        ])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    assert_eq!(af.last_band_id().unwrap(), Some(conserve::BandId::zero()));
+}
+
+#[test]
+fn backup_with_invalid_compression() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+
+    run_conserve()
+        .args(["backup", "--compression", "gzip"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "Invalid compression setting \"gzip\"",
+        ));
+}
+
+#[test]
+fn backup_with_invalid_snapshot_method() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+
+    run_conserve()
+        .args(["backup", "--snapshot", "ext4"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "Invalid snapshot method \"ext4\"",
+        ));
+}
+
+#[test]
+fn init_with_compression() {
+    let testdir = assert_fs::TempDir::new().unwrap();
+    let arch_dir = testdir.path().join("a");
+    run_conserve()
+        .args(["init", "--compression", "none"])
+        .arg(&arch_dir)
+        .assert()
+        .success();
+    let archive = conserve::Archive::open_path(&arch_dir).unwrap();
+    assert_eq!(archive.default_compression(), conserve::Compression::None);
+}
+
+#[test]
+fn backup_with_lz4_compression() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    src.create_file_of_length_with_prefix("big", 2 << 20, b"lz4");
+
+    run_conserve()
+        .args(["backup", "--no-stats", "--compression", "lz4"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    let restore_dir = assert_fs::TempDir::new().unwrap();
+    run_conserve()
+        .args(["restore"])
+        .arg(af.path())
+        .arg(restore_dir.path())
+        .assert()
+        .success();
+    assert_eq!(
+        std::fs::read(restore_dir.path().join("big")).unwrap(),
+        std::fs::read(src.path().join("big")).unwrap()
+    );
+}
+
+#[test]
+fn backup_multiple_sources() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_dir("etc");
+    src.create_file("etc/hosts");
+    src.create_dir("home");
+    src.create_dir("home/alice");
+    src.create_file("home/alice/notes");
+
+    run_conserve()
+        .args(["backup", "--no-stats"])
+        .arg(af.path())
+        .arg(src.path().join("home"))
+        .arg(src.path().join("etc"))
+        .assert()
+        .success();
+
+    run_conserve()
+        .args(["ls"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(indoc! { "
+            /
+            /etc
+            /home
+            /etc/hosts
+            /home/alice
+            /home/alice/notes
+        " });
+}
+
+#[test]
+fn backup_sources_with_the_same_name_fails() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_dir("a");
+    src.create_dir("a/data");
+    src.create_dir("b");
+    src.create_dir("b/data");
+
+    run_conserve()
+        .args(["backup"])
+        .arg(af.path())
+        .arg(src.path().join("a/data"))
+        .arg(src.path().join("b/data"))
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "More than one source directory is named \"data\"",
+        ));
+}
+
+#[test]
+fn backup_from_stdin() {
+    let af = ScratchArchive::new();
+
+    assert_cmd::Command::from_std(run_conserve())
+        .args(["backup", "--no-stats", "--stdin-name", "/dumps/db.sql"])
+        .arg(af.path())
+        .write_stdin("SELECT 1;\n")
+        .assert()
+        .success();
+
+    let restore_dir = assert_fs::TempDir::new().unwrap();
+    run_conserve()
+        .args(["restore"])
+        .arg(af.path())
+        .arg(restore_dir.path())
+        .assert()
+        .success();
+    assert_eq!(
+        read_to_string(restore_dir.path().join("dumps/db.sql")).unwrap(),
+        "SELECT 1;\n"
+    );
+}
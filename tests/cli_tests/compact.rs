@@ -0,0 +1,49 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+use assert_cmd::prelude::*;
+use predicates::prelude::*;
+
+use conserve::test_fixtures::ScratchArchive;
+
+use crate::run_conserve;
+
+#[test]
+fn compact_archive() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(["compact", "--dry-run"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("compact stats"))
+        .stderr(predicate::str::is_match(r"(?m)^ +2 +  can be packed$").unwrap())
+        .stderr(predicate::str::is_match(r"(?m)^ +1 +packs to write$").unwrap())
+        .stderr(predicate::str::is_match(r"(?m)^ +0 +packs written$").unwrap());
+    assert!(!af.path().join("d/packs").exists());
+
+    run_conserve()
+        .args(["compact"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::is_match(r"(?m)^ +1 +packs written$").unwrap());
+    assert!(af.path().join("d/packs").is_dir());
+
+    run_conserve()
+        .args(["validate"])
+        .arg(af.path())
+        .assert()
+        .success();
+}
@@ -77,7 +77,7 @@ fn delete_first_version() {
     rd.child("subdir/subfile").assert(predicate::eq("contents"));
 
     // File added in b1 has been restored.
-    rd.child("hello2").assert(predicate::eq("contents"));
+    rd.child("hello2").assert(predicate::eq("content2"));
 
     run_conserve()
         .arg("validate")
@@ -125,6 +125,27 @@ fn delete_second_version() {
         .success();
 }
 
+#[test]
+fn delete_dry_run_reports_reclaimable_space() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(["delete", "--dry-run"])
+        .args(["-b", "b1"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match(r"(?m)^ +1 +can be deleted$").unwrap())
+        .stdout(predicate::str::contains("can be reclaimed"));
+
+    assert_eq!(af.list_band_ids().unwrap().len(), 2);
+    assert_eq!(
+        af.block_dir().blocks(TestMonitor::arc()).unwrap().count(),
+        2
+    );
+}
+
 #[test]
 fn delete_nonexistent_band() {
     let af = ScratchArchive::new();
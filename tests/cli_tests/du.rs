@@ -0,0 +1,47 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test `conserve du`.
+
+use assert_cmd::prelude::*;
+use predicates::prelude::*;
+
+use conserve::test_fixtures::ScratchArchive;
+
+use crate::run_conserve;
+
+#[test]
+fn du_shows_each_directory() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(["du", "--bytes"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::starts_with("      apparent         stored  directory\n")
+                .and(predicate::str::contains("  /\n"))
+                .and(predicate::str::contains("  /subdir\n")),
+        );
+
+    run_conserve()
+        .args(["du", "-b", "b0", "--depth", "0", "--json"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::starts_with(r#"{"apath":"/","files":2,"#)
+                .and(predicate::str::contains("/subdir").not()),
+        );
+}
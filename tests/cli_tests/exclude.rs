@@ -149,6 +149,77 @@ fn exclude_from_file() {
         .success();
 }
 
+#[test]
+fn conserveignore_files_are_honored() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+
+    src.create_file_with_contents(".conserveignore", b"# Build outputs\n*.o\n");
+    src.create_dir("src");
+    src.create_file("src/hello.c");
+    src.create_file("src/hello.o");
+    src.create_file_with_contents("src/.conserveignore", b"!hello.o\n");
+    src.create_dir("lib");
+    src.create_file("lib/lib.o");
+
+    run_conserve()
+        .args(["backup", "-v", "--no-stats"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    run_conserve()
+        .args(["ls"])
+        .arg(af.path())
+        .assert()
+        .stdout(indoc! { "
+            /
+            /.conserveignore
+            /lib
+            /src
+            /src/.conserveignore
+            /src/hello.c
+            /src/hello.o
+        "})
+        .success();
+}
+
+#[test]
+fn exclude_if_present() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+
+    src.create_file("hello");
+    src.create_dir("junk");
+    src.create_file("junk/.nobackup");
+    src.create_file("junk/big");
+    src.create_dir("src");
+    src.create_file("src/hello.c");
+
+    run_conserve()
+        .args(["backup", "--exclude-if-present", ".nobackup"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains(
+            "1      skipped marked directories",
+        ));
+
+    run_conserve()
+        .args(["ls"])
+        .arg(af.path())
+        .assert()
+        .stdout(indoc! { "
+            /
+            /hello
+            /src
+            /src/hello.c
+        "})
+        .success();
+}
+
 /// `--exclude /subtree` should also exclude everything under it.
 ///
 /// <https://github.com/sourcefrog/conserve/issues/160>
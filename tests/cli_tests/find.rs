@@ -0,0 +1,82 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test `conserve find`.
+
+use assert_cmd::prelude::*;
+use predicates::prelude::*;
+
+use conserve::test_fixtures::ScratchArchive;
+
+use crate::run_conserve;
+
+#[test]
+fn find_file_in_all_versions() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(["find", "--utc"])
+        .arg(af.path())
+        .arg("hello*")
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::starts_with("/hello\n  b0000 ")
+                .and(predicate::str::contains("\n/hello2\n  b0001 "))
+                .and(predicate::str::contains("added")),
+        );
+
+    run_conserve()
+        .args(["find", "--changes"])
+        .arg(af.path())
+        .arg("/hello")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("b0000").and(predicate::str::contains("b0001").not()));
+}
+
+#[test]
+fn find_regex_as_json() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    let output = run_conserve()
+        .args(["find", "--json", "--regex"])
+        .arg(af.path())
+        .arg("^/subdir/")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let versions: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(versions.len(), 2);
+    assert_eq!(versions[0]["apath"], "/subdir/subfile");
+    assert_eq!(versions[0]["band_id"], "b0000");
+    assert_eq!(versions[0]["change"], "added");
+    assert_eq!(versions[1]["band_id"], "b0001");
+    assert_eq!(versions[1]["change"], "unchanged");
+}
+
+#[test]
+fn invalid_regex_fails() {
+    let af = ScratchArchive::new();
+    run_conserve()
+        .args(["find", "--regex"])
+        .arg(af.path())
+        .arg("(")
+        .assert()
+        .failure();
+}
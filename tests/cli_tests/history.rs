@@ -0,0 +1,71 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test `conserve history`.
+
+use assert_cmd::prelude::*;
+use predicates::prelude::*;
+
+use conserve::test_fixtures::ScratchArchive;
+
+use crate::run_conserve;
+
+#[test]
+fn history_shows_distinct_versions() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(["history", "--utc"])
+        .arg(af.path())
+        .arg("/hello")
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::starts_with("/hello\n  b0000 ")
+                .and(predicate::str::contains("added"))
+                .and(predicate::str::contains("b0001").not()),
+        );
+
+    run_conserve()
+        .args(["history", "--all"])
+        .arg(af.path())
+        .arg("/hello")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("b0000").and(predicate::str::contains("b0001")));
+
+    run_conserve()
+        .args(["history", "--json"])
+        .arg(af.path())
+        .arg("/hello2")
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::contains(r#""band_id":"b0001""#)
+                .and(predicate::str::contains(r#""change":"added""#)),
+        );
+}
+
+#[test]
+fn history_of_missing_file_is_empty() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(["history"])
+        .arg(af.path())
+        .arg("/nonexistent")
+        .assert()
+        .success()
+        .stdout("");
+}
@@ -0,0 +1,64 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test `conserve hold`.
+
+use assert_cmd::prelude::*;
+use predicates::prelude::*;
+
+use conserve::test_fixtures::ScratchArchive;
+use conserve::BandId;
+
+use crate::run_conserve;
+
+#[test]
+fn held_backup_survives_prune_and_delete() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(["hold", "-b", "b0"])
+        .arg(af.path())
+        .assert()
+        .success();
+    run_conserve()
+        .args(["versions"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("b0000").and(predicate::str::contains("(held)")));
+
+    run_conserve()
+        .args(["prune", "--keep-last", "1", "--no-stats"])
+        .arg(af.path())
+        .assert()
+        .success();
+    run_conserve()
+        .args(["delete", "-b", "b0"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("held"));
+    assert_eq!(af.list_band_ids().unwrap().len(), 2);
+
+    run_conserve()
+        .args(["hold", "--release", "-b", "b0"])
+        .arg(af.path())
+        .assert()
+        .success();
+    run_conserve()
+        .args(["prune", "--keep-last", "1", "--no-stats"])
+        .arg(af.path())
+        .assert()
+        .success();
+    assert_eq!(af.list_band_ids().unwrap(), [BandId::new(&[1])]);
+}
@@ -35,3 +35,26 @@ fn ls_json() {
         "# }
     );
 }
+
+#[test]
+fn ls_only_files() {
+    run_conserve()
+        .args(["ls", "--kind", "file", "./testdata/archive/minimal/v0.6.17"])
+        .assert()
+        .success()
+        .stdout("/hello\n/subdir/subfile\n");
+}
+
+#[test]
+fn ls_only_dirs_and_symlinks() {
+    run_conserve()
+        .args([
+            "ls",
+            "--kind",
+            "dir,symlink",
+            "./testdata/archive/minimal/v0.6.17",
+        ])
+        .assert()
+        .success()
+        .stdout("/\n/subdir\n");
+}
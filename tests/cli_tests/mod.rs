@@ -14,12 +14,21 @@
 //! Run conserve CLI as a subprocess and test it.
 
 mod backup;
+mod compact;
 mod delete;
 mod diff;
+mod du;
 mod exclude;
+mod find;
+mod history;
+mod hold;
 pub mod ls;
+mod prune;
+mod recompress;
+mod stats;
 mod trace;
 mod validate;
+mod verify;
 mod versions;
 
 #[cfg(unix)]
@@ -0,0 +1,152 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test `conserve prune`.
+
+use assert_cmd::prelude::*;
+use predicates::prelude::*;
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::BandId;
+
+use crate::run_conserve;
+
+#[test]
+fn prune_keeps_newest_backup_of_the_day() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .args(["prune", "--keep-daily", "1", "--dry-run", "--no-stats"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("");
+    assert_eq!(af.list_band_ids().unwrap().len(), 2);
+
+    run_conserve()
+        .args(["prune", "--keep-daily", "1"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("bands deleted"));
+    assert_eq!(af.list_band_ids().unwrap(), [BandId::new(&[1])]);
+}
+
+#[test]
+fn prune_requires_a_retention_rule() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    run_conserve()
+        .arg("prune")
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--keep-daily"));
+    assert_eq!(af.list_band_ids().unwrap().len(), 2);
+}
+
+#[test]
+fn prune_deletes_expired_backups() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    for expire_after in ["0s", "90d"] {
+        run_conserve()
+            .args(["backup", "--no-stats", "--expire-after", expire_after])
+            .arg(af.path())
+            .arg(src.path())
+            .assert()
+            .success();
+    }
+    run_conserve()
+        .args(["backup", "--no-stats"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    run_conserve()
+        .args(["versions", "--utc"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::function(|s: &str| {
+            let lines: Vec<&str> = s.lines().collect();
+            lines.len() == 3
+                && lines[0].contains("(expires ")
+                && lines[1].contains("(expires ")
+                && !lines[2].contains("(expires ")
+        }));
+
+    run_conserve()
+        .args(["prune", "--keep-daily", "1", "--no-stats"])
+        .arg(af.path())
+        .assert()
+        .success();
+    assert_eq!(
+        af.list_band_ids().unwrap(),
+        [BandId::new(&[1]), BandId::new(&[2])]
+    );
+}
+
+#[test]
+fn prune_deletes_backups_older_than_a_duration() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    // Make the first backup look like it was made long ago.
+    let head_path = af.path().join("b0000/BANDHEAD");
+    let mut head: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&head_path).unwrap()).unwrap();
+    head["start_time"] = 1_000_000_000.into();
+    std::fs::write(&head_path, head.to_string()).unwrap();
+
+    run_conserve()
+        .args(["prune", "--delete-older-than", "180d", "--no-stats"])
+        .arg(af.path())
+        .assert()
+        .success();
+    assert_eq!(af.list_band_ids().unwrap(), [BandId::new(&[1])]);
+}
+
+#[test]
+fn prune_keep_last_skips_damaged_backups() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for name in ["a", "b", "c", "d"] {
+        srcdir.create_file(name);
+        run_conserve()
+            .args(["backup", "--no-stats"])
+            .arg(af.path())
+            .arg(srcdir.path())
+            .assert()
+            .success();
+    }
+    std::fs::write(
+        af.path().join("DAMAGED_BANDS"),
+        r#"{"b0002": "Index hunk is unreadable"}"#,
+    )
+    .unwrap();
+
+    run_conserve()
+        .args(["prune", "--keep-last", "2", "--no-stats"])
+        .arg(af.path())
+        .assert()
+        .success();
+    // The damaged band doesn't count as one of the last two, so it's deleted
+    // along with the oldest band.
+    assert_eq!(
+        af.list_band_ids().unwrap(),
+        [BandId::new(&[1]), BandId::new(&[3])]
+    );
+}
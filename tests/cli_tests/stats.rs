@@ -0,0 +1,39 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test `conserve stats`.
+
+use assert_cmd::prelude::*;
+use predicates::prelude::*;
+
+use crate::run_conserve;
+
+#[test]
+fn stats_of_archive() {
+    run_conserve()
+        .args(["stats", "testdata/archive/simple/v0.6.10"])
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::starts_with("archive stats\n")
+                .and(predicate::str::contains("2      unique blocks"))
+                .and(predicate::str::is_match(r"(?m)^b0001 +1 +0 +0 +0$").unwrap()),
+        );
+
+    run_conserve()
+        .args(["stats", "--json", "testdata/archive/simple/v0.6.10"])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(
+            r#""unique_blocks":2,"referenced_bytes":70,"unique_bytes":52,"#,
+        ));
+}
@@ -114,7 +114,7 @@ fn backup_unix_permissions() {
         .arg(&*restore_dir)
         .assert()
         .success()
-        .stderr(predicate::str::is_empty())
+        .stderr(predicate::str::contains("Restore complete."))
         .stdout(predicate::str::diff(formatdoc! {"
              + rwxr-xr-x {user:<10} {group:<10} /
              + r--r--r-- {user:<10} {group:<10} /hello
@@ -260,3 +260,28 @@ fn list_testdata_with_permissions() {
             ",
         ));
 }
+
+#[test]
+fn long_listing_shows_size_mtime_and_symlink_targets() {
+    run_conserve()
+        .args(["ls", "--long", "--utc"])
+        .arg("testdata/archive/minimal/v0.6.17")
+        .assert()
+        .success()
+        .stderr(predicate::str::is_empty())
+        .stdout(predicate::str::diff(indoc! { "
+            drwxrwxr-x mbp        mbp                     2020-06-16 00:15:23 /
+            -rw-rw-r-- mbp        mbp                  12 2020-06-16 00:15:23 /hello
+            drwxrwxr-x mbp        mbp                     2020-06-16 00:15:23 /subdir
+            -rw-rw-r-- mbp        mbp                  12 2020-06-16 00:15:23 /subdir/subfile
+        " }));
+
+    let af = conserve::test_fixtures::ScratchArchive::new();
+    af.store_two_versions();
+    run_conserve()
+        .args(["ls", "--long"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match(r"(?m)^l.* /link -> target$").unwrap());
+}
@@ -12,6 +12,8 @@ use serde_json::json;
 use serde_json::{Deserializer, Value};
 use tracing::Level;
 
+use conserve::test_fixtures::ScratchArchive;
+
 use crate::run_conserve;
 
 fn read_log_json(path: &Path) -> Vec<serde_json::Value> {
@@ -73,7 +75,7 @@ fn validate_non_fatal_problems_nonzero_result_and_json_log() {
         .arg(log_temp.path())
         .assert()
         .stderr(predicate::str::contains("Archive has some problems."))
-        .code(2);
+        .code(3);
     let events = read_log_json(log_temp.path());
     dbg!(&events);
     let errors = filter_by_level(&events, Level::ERROR);
@@ -83,6 +85,110 @@ fn validate_non_fatal_problems_nonzero_result_and_json_log() {
         errors[0]["fields"],
         json!({
             "message": "Referenced block fec91c70284c72d0d4e3684788a90de9338a5b2f47f01fedbe203cafd68708718ae5672d10eca804a8121904047d40d1d6cf11e7a76419357a9469af41f22d01 is missing",
+            "code": "block_missing",
         })
     );
+    // Only archives without problems are recorded as validated.
+    assert!(!Path::new("testdata/damaged/missing-block/LAST_VALIDATED").exists());
+}
+
+#[test]
+fn successful_validation_is_shown_by_versions() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    run_conserve()
+        .args(["versions", "--utc"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("validated").not());
+
+    run_conserve()
+        .args(["validate", "--incremental"])
+        .arg(af.path())
+        .assert()
+        .success();
+
+    run_conserve()
+        .args(["versions", "--utc"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::is_match(
+                r"(?m)^Archive validated OK at \d{4}-\d\d-\d\dT[0-9:.]+Z by conserve [0-9.]+ \(incremental\)\n\z",
+            )
+            .unwrap(),
+        );
+    run_conserve()
+        .args(["versions", "--short"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("b0000\nb0001\n");
+}
+
+#[test]
+fn validate_sample_of_blocks() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    run_conserve()
+        .args(["validate", "--sample", "5"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Invalid percentage"));
+    run_conserve()
+        .args(["validate", "--sample", "50%", "--sample-seed", "42"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("with --sample-seed 42"));
+    run_conserve()
+        .args(["versions"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("(sampled 50% of blocks)"));
+}
+
+#[test]
+fn validate_one_backup() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    run_conserve()
+        .args(["validate", "-b", "b0000"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Backup b0000 is OK."));
+    // Checking one backup doesn't record the whole archive as validated.
+    assert!(!af.path().join("LAST_VALIDATED").exists());
+    run_conserve()
+        .args(["validate", "-b", "b0009"])
+        .arg(af.path())
+        .assert()
+        .failure();
+}
+
+#[test]
+fn readonly_validate_records_nothing() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    run_conserve()
+        .args(["validate", "--readonly", "--incremental"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stderr(predicate::str::contains("Archive is OK."));
+    assert!(!af.path().join("LAST_VALIDATED").exists());
+    assert!(!af.path().join("VALIDATED_CONTENT").exists());
+
+    run_conserve()
+        .args(["--readonly", "delete", "-b", "b0000"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Archive is open read-only"));
+    assert!(af.path().join("b0000").is_dir());
 }
@@ -0,0 +1,74 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Test `conserve verify`.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use assert_cmd::prelude::*;
+use filetime::{set_file_mtime, FileTime};
+use predicates::prelude::*;
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+
+use crate::run_conserve;
+
+#[test]
+fn verify_finds_content_drift() {
+    let af = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    let path = tf.create_file_with_contents("hello.c", b"void main() {}");
+    run_conserve()
+        .arg("backup")
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .success();
+
+    run_conserve()
+        .args(["verify", "--no-stats"])
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::is_empty());
+
+    // Overwrite the content in place, keeping the size and mtime.
+    let mtime = FileTime::from_last_modification_time(&path.metadata().unwrap());
+    OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .write_all(b"void main() {]")
+        .unwrap();
+    set_file_mtime(&path, mtime).unwrap();
+
+    run_conserve()
+        .args(["verify", "--no-stats"])
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .code(5)
+        .stdout("! /hello.c\n");
+
+    run_conserve()
+        .args(["verify", "--no-stats", "--json"])
+        .arg(af.path())
+        .arg(tf.path())
+        .assert()
+        .code(5)
+        .stdout(predicate::str::starts_with(
+            "{\"type\":\"drift\",\"drift\":\"content\",\"apath\":\"/hello.c\"}\n\
+             {\"type\":\"stats\",",
+        ));
+}
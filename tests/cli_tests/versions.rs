@@ -14,7 +14,7 @@
 //! Tests of the `conserve versions` command.
 
 use assert_cmd::prelude::*;
-use conserve::test_fixtures::ScratchArchive;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
 use indoc::indoc;
 use predicates::function::function;
 use predicates::prelude::*;
@@ -97,6 +97,25 @@ fn tree_sizes() {
             "});
 }
 
+#[test]
+fn json() {
+    run_conserve()
+        .args([
+            "versions",
+            "--json",
+            "--sizes",
+            "--newest",
+            "testdata/archive/simple/v0.6.10",
+        ])
+        .assert()
+        .success()
+        .stdout(indoc! { r#"
+            {"band_id":"b0002","damaged":false,"complete":true,"start_time":"2021-03-04T13:27:28Z","end_time":"2021-03-04T13:27:28Z","tree_size":34,"recovered":false,"held":false}
+            {"band_id":"b0001","damaged":false,"complete":true,"start_time":"2021-03-04T13:21:30Z","end_time":"2021-03-04T13:21:30Z","tree_size":18,"recovered":false,"held":false}
+            {"band_id":"b0000","damaged":false,"complete":true,"start_time":"2021-03-04T13:21:15Z","end_time":"2021-03-04T13:21:15Z","tree_size":18,"recovered":false,"held":false}
+            "#});
+}
+
 #[test]
 fn short_newest_first() {
     let af = ScratchArchive::new();
@@ -110,3 +129,161 @@ fn short_newest_first() {
         .stderr(predicate::str::is_empty())
         .stdout("b0001\nb0000\n");
 }
+
+#[test]
+fn labels_and_messages() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    run_conserve()
+        .args([
+            "backup",
+            "--no-stats",
+            "--label",
+            "pre-upgrade",
+            "--message",
+        ])
+        .arg("before upgrading")
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    run_conserve()
+        .args(["backup", "--no-stats"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+
+    run_conserve()
+        .args(["versions", "--utc"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(function(|s: &str| {
+            let lines: Vec<&str> = s.lines().collect();
+            lines.len() == 2
+                && lines[0].starts_with("b0000")
+                && lines[0].ends_with(" [pre-upgrade] before upgrading")
+                && !lines[1].contains('[')
+        }));
+
+    run_conserve()
+        .args(["versions", "--short"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("b0000\nb0001\n");
+
+    run_conserve()
+        .args(["ls", "--label", "pre-upgrade"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout("/\n/hello\n");
+
+    run_conserve()
+        .args(["ls", "--label", "nonexistent"])
+        .arg(af.path())
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "No band has label \"nonexistent\"",
+        ));
+}
+
+#[test]
+fn verbose_shows_origin() {
+    let af = ScratchArchive::new();
+    let src = TreeFixture::new();
+    src.create_file("hello");
+    run_conserve()
+        .args(["backup", "--no-stats"])
+        .arg(af.path())
+        .arg(src.path())
+        .assert()
+        .success();
+    let source = src.path().canonicalize().unwrap();
+
+    run_conserve()
+        .args(["versions", "--verbose"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(predicate::str::contains(format!(
+            " conserve-{} {}\n",
+            conserve::version(),
+            source.display()
+        )));
+
+    // Old archives don't record where backups came from.
+    run_conserve()
+        .args(["versions", "--verbose", "--utc"])
+        .arg("testdata/archive/simple/v0.6.10")
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with(
+            "b0000                2021-03-04T13:21:15Z            0:00\n",
+        ));
+}
+
+#[test]
+fn select_version_before_time() {
+    let archive = "testdata/archive/simple/v0.6.10";
+    let b1_listing = run_conserve()
+        .args(["ls", "--backup", "b1", archive])
+        .output()
+        .unwrap()
+        .stdout;
+
+    run_conserve()
+        .args(["ls", "--before", "2021-03-04T13:25:00Z", archive])
+        .assert()
+        .success()
+        .stdout(b1_listing);
+
+    run_conserve()
+        .args(["ls", "--before", "2021-01-01", archive])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains(
+            "No complete band started at or before",
+        ));
+}
+
+#[test]
+fn damaged_bands_are_marked() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    std::fs::remove_file(af.path().join("b0000/BANDHEAD")).unwrap();
+
+    run_conserve()
+        .args(["validate"])
+        .arg(af.path())
+        .assert()
+        .failure();
+
+    run_conserve()
+        .args(["versions", "--utc"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(function(|s: &str| {
+            let lines: Vec<&str> = s.lines().collect();
+            lines.len() == 2
+                && lines[0].starts_with("b0000")
+                && lines[0].ends_with(" (damaged)")
+                && !lines[1].contains("damaged")
+        }));
+
+    run_conserve()
+        .args(["versions", "--json"])
+        .arg(af.path())
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::starts_with("{\"band_id\":\"b0000\",\"damaged\":true}\n").and(
+                predicate::str::contains("{\"band_id\":\"b0001\",\"damaged\":false,"),
+            ),
+        );
+}
@@ -0,0 +1,252 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for gathering small blocks into packs.
+
+use std::fs;
+
+use rayon::prelude::ParallelIterator;
+use tempfile::TempDir;
+
+use conserve::blockdir::{block_relpath, PACK_DIR};
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+/// Make an archive with three bands, each of which adds a file in a new block.
+fn archive_with_three_blocks() -> ScratchArchive {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for name in ["a", "b", "c"] {
+        srcdir.create_file_with_contents(name, name.repeat(100).as_bytes());
+        backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    }
+    af
+}
+
+fn sorted_blocks(archive: &Archive) -> Vec<BlockHash> {
+    let mut blocks: Vec<BlockHash> = archive
+        .block_dir()
+        .blocks(TestMonitor::arc())
+        .unwrap()
+        .collect();
+    blocks.sort();
+    blocks
+}
+
+/// Count the blocks stored in their own files.
+fn block_files(af: &ScratchArchive) -> usize {
+    fs::read_dir(af.path().join("d"))
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_name() != PACK_DIR)
+        .map(|entry| fs::read_dir(entry.path()).unwrap().count())
+        .sum()
+}
+
+fn pack_count(af: &ScratchArchive) -> usize {
+    fs::read_dir(af.path().join("d").join(PACK_DIR)).map_or(0, |entries| entries.count())
+}
+
+fn assert_valid(archive: &Archive) {
+    let monitor = TestMonitor::arc();
+    let stats = archive
+        .validate(&ValidateOptions::default(), monitor.clone())
+        .unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.missing_blocks, 0);
+}
+
+#[test]
+fn compact_small_blocks_into_a_pack() {
+    let af = archive_with_three_blocks();
+    let blocks_before = sorted_blocks(&af);
+    assert_eq!(blocks_before.len(), 3);
+
+    let stats = compact(&af, &CompactOptions::default(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.loose_blocks, 3);
+    assert_eq!(stats.packable_blocks, 3);
+    assert_eq!(stats.packed_blocks, 3);
+    assert_eq!(stats.packs_to_write, 1);
+    assert_eq!(stats.packs_written, 1);
+    assert_eq!(stats.errors, 0);
+    assert!(stats.packed_bytes > 0);
+    assert_eq!(block_files(&af), 0);
+    assert_eq!(pack_count(&af), 1);
+    for band_id in af.list_band_ids().unwrap() {
+        let band = Band::open(&af, band_id).unwrap();
+        assert!(band.format_flags().iter().any(|f| f == "packed_blocks"));
+    }
+
+    // The blocks are still found by their hashes in a freshly opened archive.
+    let archive = Archive::open_path(af.path()).unwrap();
+    assert_eq!(sorted_blocks(&archive), blocks_before);
+    assert_valid(&archive);
+    let dest = TempDir::new().unwrap();
+    restore(
+        &archive,
+        dest.path(),
+        &RestoreOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    assert_eq!(
+        fs::read_to_string(dest.path().join("c")).unwrap(),
+        "c".repeat(100)
+    );
+
+    // There's nothing more to pack.
+    let stats = compact(&archive, &CompactOptions::default(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.loose_blocks, 0);
+    assert_eq!(stats.packs_written, 0);
+    assert_eq!(pack_count(&af), 1);
+
+    // Later backups may refer to the packed blocks, so they're marked too.
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("c", "c".repeat(100).as_bytes());
+    backup(
+        &archive,
+        srcdir.path(),
+        &Default::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    let band = Band::open(&archive, BandId::new(&[3])).unwrap();
+    assert_eq!(band.format_flags(), ["packed_blocks"]);
+}
+
+#[test]
+fn dry_run_changes_nothing() {
+    let af = archive_with_three_blocks();
+    let options = CompactOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    let stats = compact(&af, &options, TestMonitor::arc()).unwrap();
+    assert_eq!(stats.packable_blocks, 3);
+    assert_eq!(stats.packs_to_write, 1);
+    assert!(stats.packable_bytes > 0);
+    assert_eq!(stats.packed_blocks, 0);
+    assert_eq!(stats.packs_written, 0);
+    assert_eq!(block_files(&af), 3);
+    assert_eq!(pack_count(&af), 0);
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    assert!(band.format_flags().is_empty());
+}
+
+#[test]
+fn large_blocks_and_full_packs() {
+    let af = archive_with_three_blocks();
+    let sizes: Vec<u64> = sorted_blocks(&af)
+        .iter()
+        .map(|hash| af.block_dir().compressed_size(hash).unwrap())
+        .collect();
+
+    // Blocks as large as the limit stay in their own files.
+    let options = CompactOptions {
+        max_block_size: *sizes.iter().min().unwrap(),
+        ..Default::default()
+    };
+    let stats = compact(&af, &options, TestMonitor::arc()).unwrap();
+    assert_eq!(stats.packed_blocks, 0);
+
+    // With packs only big enough for one block, there's nothing worth packing.
+    let options = CompactOptions {
+        pack_size: *sizes.iter().max().unwrap(),
+        ..Default::default()
+    };
+    let stats = compact(&af, &options, TestMonitor::arc()).unwrap();
+    assert_eq!(stats.packs_written, 0);
+    assert_eq!(block_files(&af), 3);
+}
+
+#[test]
+fn gc_removes_unreferenced_blocks_from_packs() {
+    let af = archive_with_three_blocks();
+    compact(&af, &CompactOptions::default(), TestMonitor::arc()).unwrap();
+    let pack_before = fs::read_dir(af.path().join("d").join(PACK_DIR))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .file_name();
+
+    // Deleting the last band leaves the block holding "c" unreferenced.
+    let stats = af
+        .delete_bands(
+            &[BandId::new(&[2])],
+            &DeleteOptions::default(),
+            TestMonitor::arc(),
+        )
+        .unwrap();
+    assert_eq!(stats.deleted_block_count, 1);
+    assert_eq!(stats.deletion_errors, 0);
+    assert!(stats.deleted_block_bytes > 0);
+
+    // The pack was rewritten without that block.
+    assert_eq!(pack_count(&af), 1);
+    assert!(!af
+        .path()
+        .join("d")
+        .join(PACK_DIR)
+        .join(&pack_before)
+        .exists());
+    let archive = Archive::open_path(af.path()).unwrap();
+    assert_eq!(sorted_blocks(&archive).len(), 2);
+    assert_valid(&archive);
+
+    // Once no blocks in the pack are referenced, the pack is removed.
+    archive
+        .delete_bands(
+            &[BandId::new(&[0]), BandId::new(&[1])],
+            &DeleteOptions::default(),
+            TestMonitor::arc(),
+        )
+        .unwrap();
+    assert_eq!(pack_count(&af), 0);
+    assert!(sorted_blocks(&archive).is_empty());
+}
+
+#[test]
+fn block_files_for_packed_blocks_are_removed() {
+    let af = archive_with_three_blocks();
+    let hash = sorted_blocks(&af).remove(0);
+    let block_path = af.path().join("d").join(block_relpath(&hash));
+    let block_content = fs::read(&block_path).unwrap();
+    compact(&af, &CompactOptions::default(), TestMonitor::arc()).unwrap();
+
+    // As if compaction was interrupted before the block file was deleted.
+    fs::create_dir_all(block_path.parent().unwrap()).unwrap();
+    fs::write(&block_path, block_content).unwrap();
+    assert_eq!(sorted_blocks(&af).len(), 3);
+
+    let stats = compact(&af, &CompactOptions::default(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.removed_duplicates, 1);
+    assert_eq!(stats.packs_written, 0);
+    assert!(!block_path.exists());
+    assert_valid(&af);
+}
+
+#[test]
+fn recompress_leaves_packed_blocks_alone() {
+    let af = archive_with_three_blocks();
+    compact(&af, &CompactOptions::default(), TestMonitor::arc()).unwrap();
+    let options = RecompressOptions {
+        compression: Compression::Zstd { level: 3 },
+        ..Default::default()
+    };
+    let stats = recompress(&af, &options, TestMonitor::arc()).unwrap();
+    assert_eq!(stats.blocks, 3);
+    assert_eq!(stats.rewritten_blocks, 0);
+    assert_eq!(stats.errors, 0);
+    assert_valid(&Archive::open_path(af.path()).unwrap());
+}
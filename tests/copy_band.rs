@@ -0,0 +1,136 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for copying bands between archives.
+
+use assert_fs::prelude::*;
+use assert_fs::TempDir;
+
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+#[test]
+fn copy_bands_deduplicates_blocks() {
+    let source = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", &[b'a'; 100]);
+    let options = BackupOptions::default().with_label(Some("first".to_owned()));
+    backup(&source, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    srcdir.create_file_with_contents("b", &[b'b'; 100]);
+    backup(
+        &source,
+        srcdir.path(),
+        &Default::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+
+    let dest = ScratchArchive::new();
+    let stats = source
+        .copy_band_to(&dest, BandId::zero(), TestMonitor::arc())
+        .unwrap();
+    assert_eq!(stats.band_id, BandId::zero());
+    assert_eq!(stats.blocks, 1);
+    assert_eq!(stats.copied_blocks, 1);
+    assert_eq!(stats.deduplicated_blocks, 0);
+    assert!(stats.copied_block_bytes > 0);
+    assert_eq!(stats.index_hunks, 1);
+
+    // Only the new block of the second band is copied.
+    let stats = source
+        .copy_band_to(&dest, BandId::new(&[1]), TestMonitor::arc())
+        .unwrap();
+    assert_eq!(stats.band_id, BandId::new(&[1]));
+    assert_eq!(stats.blocks, 2);
+    assert_eq!(stats.copied_blocks, 1);
+    assert_eq!(stats.deduplicated_blocks, 1);
+
+    let dest = Archive::open_path(dest.path()).unwrap();
+    let source_info = Band::open(&source, BandId::zero())
+        .unwrap()
+        .get_info()
+        .unwrap();
+    let dest_info = Band::open(&dest, BandId::zero())
+        .unwrap()
+        .get_info()
+        .unwrap();
+    assert!(dest_info.is_closed);
+    assert_eq!(dest_info.label.as_deref(), Some("first"));
+    assert_eq!(dest_info.start_time, source_info.start_time);
+    assert_eq!(dest_info.end_time, source_info.end_time);
+    let monitor = TestMonitor::arc();
+    dest.validate(&ValidateOptions::default(), monitor.clone())
+        .unwrap();
+    monitor.assert_no_errors();
+
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &dest,
+        restore_dir.path(),
+        &RestoreOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    restore_dir.child("a").assert(&[b'a'; 100][..]);
+    restore_dir.child("b").assert(&[b'b'; 100][..]);
+}
+
+#[test]
+fn copy_band_recompresses_blocks_using_dictionary() {
+    let source = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..500 {
+        srcdir.create_file_with_contents(
+            &format!("msg{i:04}"),
+            format!("From: user{i}@example.com\nSubject: Message {i}\n\nHello from {i}!\n")
+                .as_bytes(),
+        );
+    }
+    let options = BackupOptions::default().with_zstd_dictionary(true);
+    backup(&source, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    assert!(source.zstd_dictionary().is_some());
+
+    let dest = ScratchArchive::new();
+    source
+        .copy_band_to(&dest, BandId::zero(), TestMonitor::arc())
+        .unwrap();
+    assert!(dest.zstd_dictionary().is_none());
+    let monitor = TestMonitor::arc();
+    dest.validate(&ValidateOptions::default(), monitor.clone())
+        .unwrap();
+    monitor.assert_no_errors();
+}
+
+#[test]
+fn copy_incomplete_band_fails() {
+    let source = ScratchArchive::new();
+    source.setup_incomplete_empty_band();
+    let dest = ScratchArchive::new();
+    let err = source
+        .copy_band_to(&dest, BandId::zero(), TestMonitor::arc())
+        .unwrap_err();
+    assert!(matches!(err, Error::BandIncomplete { .. }), "{err:?}");
+    assert!(dest.list_band_ids().unwrap().is_empty());
+}
+
+#[test]
+fn copy_band_to_locked_archive_fails() {
+    let source = ScratchArchive::new();
+    source.store_two_versions();
+    let dest = ScratchArchive::new();
+    let _lock = GarbageCollectionLock::new(&dest).unwrap();
+    let err = source
+        .copy_band_to(&dest, BandId::zero(), TestMonitor::arc())
+        .unwrap_err();
+    assert!(matches!(err, Error::GarbageCollectionLockHeld), "{err:?}");
+}
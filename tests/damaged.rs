@@ -13,9 +13,11 @@
 
 //! Test validation of archives with some problems.
 
+use std::fs;
 use std::path::Path;
 
 use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
 use tracing_test::traced_test;
 
 use conserve::*;
@@ -41,9 +43,7 @@ fn missing_block_skip_block_hashes() -> Result<()> {
     let archive = Archive::open_path(Path::new("testdata/damaged/missing-block"))?;
     let monitor = TestMonitor::arc();
     archive.validate(
-        &ValidateOptions {
-            skip_block_hashes: true,
-        },
+        &ValidateOptions::default().with_skip_block_hashes(true),
         monitor.clone(),
     )?;
     let errors = monitor.take_errors();
@@ -52,3 +52,32 @@ fn missing_block_skip_block_hashes() -> Result<()> {
     assert!(matches!(errors[0], Error::BlockMissing { .. }));
     Ok(())
 }
+
+#[test]
+fn bands_with_unreadable_metadata_are_quarantined() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", b"old");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    srcdir.create_file_with_contents("a", b"new");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    fs::write(af.path().join("b0001/BANDTAIL"), b"{").unwrap();
+
+    let monitor = TestMonitor::arc();
+    af.validate(&ValidateOptions::default(), monitor.clone())
+        .unwrap();
+    assert_eq!(monitor.take_errors().len(), 1);
+    let damaged = af.damaged_bands().unwrap();
+    assert_eq!(damaged.keys().collect::<Vec<_>>(), [&BandId::new(&[1])]);
+
+    // The next backup compares to the last undamaged band, so the file is
+    // stored again rather than assumed unchanged.
+    let stats = backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.modified_files, 1);
+    assert_eq!(stats.unmodified_files, 0);
+
+    // Once repaired, the band is no longer listed as damaged.
+    repair(&af, &RepairOptions::default(), TestMonitor::arc()).unwrap();
+    assert!(af.damaged_bands().unwrap().is_empty());
+    assert!(!af.path().join("DAMAGED_BANDS").exists());
+}
@@ -12,8 +12,10 @@
 
 //! Test deletion.
 
+use rayon::prelude::ParallelIterator;
+
 use conserve::monitor::test::TestMonitor;
-use conserve::test_fixtures::ScratchArchive;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
 use conserve::*;
 
 #[test]
@@ -32,3 +34,93 @@ fn delete_all_bands() {
     assert_eq!(stats.deleted_block_count, 2);
     assert_eq!(stats.deleted_band_count, 2);
 }
+
+#[test]
+fn delete_reports_reclaimed_space() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("hello", b"hello");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    srcdir.create_file_with_contents("hello2", b"hello again");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    let referenced = af
+        .referenced_blocks(&[BandId::zero()], TestMonitor::arc())
+        .unwrap();
+    let unreferenced_bytes: u64 = af
+        .block_dir()
+        .blocks(TestMonitor::arc())
+        .unwrap()
+        .collect::<Vec<BlockHash>>()
+        .iter()
+        .filter(|hash| !referenced.contains(hash))
+        .map(|hash| af.block_dir().compressed_size(hash).unwrap())
+        .sum();
+    assert!(unreferenced_bytes > 0);
+
+    let stats = af
+        .delete_bands(
+            &[BandId::new(&[1])],
+            &Default::default(),
+            TestMonitor::arc(),
+        )
+        .unwrap();
+    assert_eq!(stats.deleted_band_count, 1);
+    assert_eq!(stats.unreferenced_block_bytes, unreferenced_bytes);
+    assert_eq!(stats.deleted_block_bytes, unreferenced_bytes);
+    assert_eq!(af.list_band_ids().unwrap(), [BandId::zero()]);
+}
+
+#[test]
+fn delete_nonexistent_band_changes_nothing() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+
+    for dry_run in [true, false] {
+        let options = DeleteOptions {
+            dry_run,
+            break_lock: false,
+            ..Default::default()
+        };
+        let result = af.delete_bands(
+            &[BandId::zero(), BandId::new(&[7])],
+            &options,
+            TestMonitor::arc(),
+        );
+        assert!(matches!(
+            result,
+            Err(Error::BandNotFound { band_id }) if band_id == BandId::new(&[7])
+        ));
+        assert_eq!(af.list_band_ids().unwrap().len(), 2);
+    }
+}
+
+#[test]
+fn held_band_is_not_deleted_until_released() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let mut band = Band::open(&af, BandId::zero()).unwrap();
+    band.set_held(true).unwrap();
+    assert!(
+        Band::open(&af, BandId::zero())
+            .unwrap()
+            .get_info()
+            .unwrap()
+            .held
+    );
+
+    let result = af.delete_bands(
+        &[BandId::zero(), BandId::new(&[1])],
+        &Default::default(),
+        TestMonitor::arc(),
+    );
+    assert!(matches!(
+        result,
+        Err(Error::BandHeld { band_id }) if band_id == BandId::zero()
+    ));
+    assert_eq!(af.list_band_ids().unwrap().len(), 2);
+
+    band.set_held(false).unwrap();
+    af.delete_bands(&[BandId::zero()], &Default::default(), TestMonitor::arc())
+        .unwrap();
+    assert_eq!(af.list_band_ids().unwrap(), [BandId::new(&[1])]);
+}
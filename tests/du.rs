@@ -0,0 +1,96 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for measuring the space taken by each directory of a stored tree.
+
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+/// Back up a tree where `/a` holds two copies of one file, `/b` holds another copy
+/// and a different file, and `/b/c` is empty.
+fn archive_with_duplicates() -> ScratchArchive {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("a");
+    srcdir.create_dir("b");
+    srcdir.create_dir("b/c");
+    srcdir.create_file_with_contents("a/one", &[1; 1000]);
+    srcdir.create_file_with_contents("a/two", &[1; 1000]);
+    srcdir.create_file_with_contents("b/three", &[1; 1000]);
+    srcdir.create_file_with_contents("b/four", &[2; 3000]);
+    // Store each file in its own block, so that duplicates share a block.
+    let options = BackupOptions::default().with_small_file_cap(0);
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    af
+}
+
+#[test]
+fn directories_are_measured_with_their_subdirectories() {
+    let af = archive_with_duplicates();
+    let tree = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let monitor = TestMonitor::arc();
+    let usage = disk_usage(&tree, &DiskUsageOptions::default(), monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+
+    let apaths: Vec<&str> = usage.iter().map(|dir| dir.apath.as_ref()).collect();
+    assert_eq!(apaths, ["/", "/a", "/b", "/b/c"]);
+    let [root, a, b, c] = usage.as_slice() else {
+        panic!("unexpected usage {usage:?}");
+    };
+    assert_eq!(root.files, 4);
+    assert_eq!(root.apparent_bytes, 6000);
+    assert_eq!(a.files, 2);
+    assert_eq!(a.apparent_bytes, 2000);
+    assert_eq!(b.apparent_bytes, 4000);
+    assert_eq!(c.files, 0);
+    assert_eq!(c.stored_bytes, 0);
+
+    // The duplicate files in /a share one block, which is also used by /b.
+    let ones = tree
+        .block_dir()
+        .compressed_size(
+            &tree
+                .open_file(&"/a/one".into(), monitor.clone())
+                .unwrap()
+                .entry()
+                .addrs[0]
+                .hash,
+        )
+        .unwrap();
+    assert_eq!(a.stored_bytes, ones);
+    assert!(b.stored_bytes > ones);
+    assert_eq!(root.stored_bytes, b.stored_bytes);
+}
+
+#[test]
+fn max_depth_limits_reported_directories() {
+    let af = archive_with_duplicates();
+    let tree = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let options = DiskUsageOptions {
+        max_depth: Some(0),
+        ..Default::default()
+    };
+    let usage = disk_usage(&tree, &options, TestMonitor::arc()).unwrap();
+    assert_eq!(usage.len(), 1);
+    assert_eq!(usage[0].apath, "/");
+    assert_eq!(usage[0].files, 4);
+
+    let options = DiskUsageOptions {
+        exclude: Exclude::from_strings(["/b"]).unwrap(),
+        ..Default::default()
+    };
+    let usage = disk_usage(&tree, &options, TestMonitor::arc()).unwrap();
+    let apaths: Vec<&str> = usage.iter().map(|dir| dir.apath.as_ref()).collect();
+    assert_eq!(apaths, ["/", "/a"]);
+    assert_eq!(usage[0].apparent_bytes, 2000);
+}
@@ -0,0 +1,99 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for streaming the entries of a stored tree.
+
+#![cfg(feature = "async")]
+
+use std::fs;
+
+use futures::executor::block_on_stream;
+
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+/// Back up a tree with a few entries in each index hunk.
+fn archive_with_small_hunks() -> ScratchArchive {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for name in ["a", "b", "c"] {
+        srcdir.create_dir(name);
+        for i in 0..5 {
+            srcdir.create_file(&format!("{name}/{i}"));
+        }
+    }
+    let options = BackupOptions::default().with_max_entries_per_hunk(3);
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    af
+}
+
+fn stream_apaths(stream: IndexEntryStream) -> Vec<String> {
+    block_on_stream(stream)
+        .map(|entry| entry.unwrap().apath.to_string())
+        .collect()
+}
+
+#[test]
+fn stream_matches_iter_entries() {
+    let af = archive_with_small_hunks();
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let expected: Vec<String> = st
+        .iter_entries(Apath::root(), Exclude::nothing(), TestMonitor::arc())
+        .unwrap()
+        .map(|entry| entry.apath.to_string())
+        .collect();
+    assert_eq!(expected.len(), 19);
+
+    let stream = st.entry_stream(Apath::root(), Exclude::nothing(), 1, TestMonitor::arc());
+    assert_eq!(stream_apaths(stream), expected);
+}
+
+#[test]
+fn stream_subtree_with_exclusions() {
+    let af = archive_with_small_hunks();
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let exclude = Exclude::from_strings(["/b/3"])
+        .unwrap()
+        .with_only_kinds(Some(vec![Kind::File]));
+    let stream = st.entry_stream("/b".into(), exclude, 2, TestMonitor::arc());
+    assert_eq!(stream_apaths(stream), ["/b/0", "/b/1", "/b/2", "/b/4"]);
+}
+
+#[test]
+fn dropping_stream_stops_reading() {
+    let af = archive_with_small_hunks();
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let stream = st.entry_stream(Apath::root(), Exclude::nothing(), 1, TestMonitor::arc());
+    let first: Vec<String> = block_on_stream(stream)
+        .take(2)
+        .map(|entry| entry.unwrap().apath.to_string())
+        .collect();
+    assert_eq!(first, ["/", "/a"]);
+}
+
+#[test]
+fn damaged_hunk_is_an_error_in_the_stream() {
+    let af = archive_with_small_hunks();
+    fs::write(af.path().join("b0000/i/00000/000000001"), b"garbage").unwrap();
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let monitor = TestMonitor::arc();
+    let results: Vec<Result<IndexEntry>> =
+        block_on_stream(st.entry_stream(Apath::root(), Exclude::nothing(), 1, monitor.clone()))
+            .collect();
+    assert_eq!(results.len(), 19 - 3 + 1);
+    assert!(results[0].is_ok());
+    let errors: Vec<&Error> = results.iter().filter_map(|r| r.as_ref().err()).collect();
+    assert_eq!(errors.len(), 1);
+    // Errors go to the stream, not the monitor.
+    assert!(monitor.take_errors().is_empty());
+}
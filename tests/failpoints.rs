@@ -17,6 +17,7 @@ use std::path::Path;
 
 use assert_fs::TempDir;
 use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
 use conserve::transport::open_local_transport;
 use fail::FailScenario;
 
@@ -29,9 +30,7 @@ fn create_dir_permission_denied() {
     let archive =
         Archive::open(open_local_transport(Path::new("testdata/archive/simple/v0.6.10")).unwrap())
             .unwrap();
-    let options = RestoreOptions {
-        ..RestoreOptions::default()
-    };
+    let options = RestoreOptions::default();
     let restore_tmp = TempDir::new().unwrap();
     let monitor = TestMonitor::arc();
     let stats = restore(&archive, restore_tmp.path(), &options, monitor.clone()).expect("Restore");
@@ -53,3 +52,107 @@ fn create_dir_permission_denied() {
     }
     scenario.teardown();
 }
+
+#[test]
+fn verify_reports_corrupted_files() {
+    let scenario = FailScenario::setup();
+    fail::cfg("restore::corrupt-file", "return").unwrap();
+    let archive =
+        Archive::open(open_local_transport(Path::new("testdata/archive/simple/v0.6.10")).unwrap())
+            .unwrap();
+    let options = RestoreOptions::default().with_verify(true);
+    let restore_tmp = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    restore(&archive, restore_tmp.path(), &options, monitor.clone()).expect("Restore");
+    let errors = monitor.take_errors();
+    assert!(!errors.is_empty());
+    for error in &errors {
+        assert!(
+            matches!(error, Error::RestoredFileMismatch { .. }),
+            "Unexpected error {error:?}"
+        );
+    }
+    monitor.assert_counter(counters::Counter::VerifiedFiles, 0);
+    scenario.teardown();
+}
+
+#[test]
+fn concurrent_band_creation_gets_distinct_ids() {
+    let scenario = FailScenario::setup();
+    // Both threads choose b0000 before either claims it.
+    fail::cfg("band::create::chose-id", "sleep(200)").unwrap();
+    let archive = ScratchArchive::new();
+    let mut band_ids: Vec<BandId> = std::thread::scope(|scope| {
+        let threads: Vec<_> = (0..2)
+            .map(|_| scope.spawn(|| Band::create(&archive).unwrap().id()))
+            .collect();
+        threads.into_iter().map(|t| t.join().unwrap()).collect()
+    });
+    band_ids.sort();
+    assert_eq!(band_ids, [BandId::zero(), BandId::new(&[1])]);
+    assert_eq!(archive.list_band_ids().unwrap(), band_ids);
+    scenario.teardown();
+}
+
+#[test]
+fn failed_block_write_fails_only_files_using_the_block() {
+    let scenario = FailScenario::setup();
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let contents: Vec<Vec<u8>> = (0..8u32)
+        .map(|i| {
+            (0..10_000u32)
+                .flat_map(|j| (i << 24 | j).to_le_bytes())
+                .collect()
+        })
+        .collect();
+    for (i, content) in contents.iter().enumerate() {
+        srcdir.create_file_with_contents(&format!("file{i}"), content);
+    }
+    // Each file is several blocks, written on several threads; one write fails.
+    fail::cfg("blockdir::write-block", "5*off->1*return->off").unwrap();
+    let options = BackupOptions::default()
+        .with_max_block_size(8_000)
+        .with_parallelism(Parallelism::default().with_threads(4));
+    let monitor = TestMonitor::arc();
+    let stats = backup(&af, srcdir.path(), &options, monitor.clone()).expect("backup");
+    fail::remove("blockdir::write-block");
+    let errors = monitor.take_errors();
+    assert_eq!(errors.len(), 1, "{errors:?}");
+    assert!(
+        matches!(&errors[0], Error::StoreFileBlock { .. }),
+        "Unexpected error {:?}",
+        errors[0]
+    );
+    assert_eq!(stats.errors, 1);
+
+    // The index refers only to blocks that were written, and every other file
+    // has its own content.
+    let monitor = TestMonitor::arc();
+    af.validate(&ValidateOptions::default(), monitor.clone())
+        .unwrap();
+    monitor.assert_no_errors();
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &af,
+        restore_dir.path(),
+        &RestoreOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    let restored = contents
+        .iter()
+        .enumerate()
+        .filter(
+            |(i, content)| match std::fs::read(restore_dir.path().join(format!("file{i}"))) {
+                Ok(restored) => {
+                    assert_eq!(&restored, *content);
+                    true
+                }
+                Err(_) => false,
+            },
+        )
+        .count();
+    assert_eq!(restored, contents.len() - 1);
+    scenario.teardown();
+}
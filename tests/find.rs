@@ -0,0 +1,219 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for finding versions of files across all bands.
+
+use std::fs;
+
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+/// Back up a tree five times: adding `a.txt`, changing it, leaving it alone,
+/// deleting it, and finally putting it back.
+fn archive_with_history() -> ScratchArchive {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a.txt", b"one");
+    srcdir.create_file_with_contents("b.dat", b"bbb");
+    let back_up = || {
+        backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    };
+    back_up();
+    srcdir.create_file_with_contents("a.txt", b"two, longer");
+    back_up();
+    back_up();
+    fs::remove_file(srcdir.path().join("a.txt")).unwrap();
+    back_up();
+    srcdir.create_file_with_contents("a.txt", b"one");
+    back_up();
+    af
+}
+
+fn summarize(versions: &[FoundVersion]) -> Vec<(String, String, VersionChange)> {
+    versions
+        .iter()
+        .map(|v| (v.apath.to_string(), v.band_id.to_string(), v.change))
+        .collect()
+}
+
+#[test]
+fn find_versions_of_a_file() {
+    let af = archive_with_history();
+    let monitor = TestMonitor::arc();
+    let versions = find(&af, &FindPattern::glob("*.txt").unwrap(), monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(
+        summarize(&versions),
+        [
+            (
+                "/a.txt".to_owned(),
+                "b0000".to_owned(),
+                VersionChange::Added
+            ),
+            (
+                "/a.txt".to_owned(),
+                "b0001".to_owned(),
+                VersionChange::Changed
+            ),
+            (
+                "/a.txt".to_owned(),
+                "b0002".to_owned(),
+                VersionChange::Unchanged
+            ),
+            (
+                "/a.txt".to_owned(),
+                "b0004".to_owned(),
+                VersionChange::Added
+            ),
+        ]
+    );
+    assert!(versions.iter().all(|v| v.kind == Kind::File));
+    assert_eq!(versions[0].size, Some(3));
+    assert_eq!(versions[1].size, Some(11));
+    assert_eq!(versions[1].content_hash, versions[2].content_hash);
+    assert_ne!(versions[0].content_hash, versions[1].content_hash);
+}
+
+#[test]
+fn find_with_regex() {
+    let af = archive_with_history();
+    let versions = find(
+        &af,
+        &FindPattern::regex(r"^/b\.").unwrap(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    assert_eq!(versions.len(), 5);
+    assert!(versions.iter().all(|v| v.apath == "/b.dat"));
+    assert_eq!(versions[0].change, VersionChange::Added);
+    assert!(versions[1..]
+        .iter()
+        .all(|v| v.change == VersionChange::Unchanged));
+}
+
+#[test]
+fn nothing_found() {
+    let af = archive_with_history();
+    let versions = find(&af, &FindPattern::glob("*.rs").unwrap(), TestMonitor::arc()).unwrap();
+    assert!(versions.is_empty());
+}
+
+#[test]
+fn damaged_index_is_reported_and_skipped() {
+    let af = archive_with_history();
+    let hunk_path = af
+        .path()
+        .join("b0002")
+        .join("i")
+        .join("00000")
+        .join("000000000");
+    fs::write(&hunk_path, b"not an index hunk").unwrap();
+
+    let monitor = TestMonitor::arc();
+    let versions = find(&af, &FindPattern::glob("/a.txt").unwrap(), monitor.clone()).unwrap();
+    assert_eq!(monitor.take_errors().len(), 1);
+    assert_eq!(
+        versions
+            .iter()
+            .map(|v| v.band_id.to_string())
+            .collect::<Vec<_>>(),
+        ["b0000", "b0001", "b0004"]
+    );
+}
+
+#[test]
+fn found_versions_serialize_to_json() {
+    let af = archive_with_history();
+    let versions = find(
+        &af,
+        &FindPattern::glob("/a.txt").unwrap(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    let json = serde_json::to_value(&versions[1]).unwrap();
+    assert_eq!(json["apath"], "/a.txt");
+    assert_eq!(json["band_id"], "b0001");
+    assert_eq!(json["kind"], "File");
+    assert_eq!(json["size"], 11);
+    assert_eq!(json["change"], "changed");
+    assert!(json["content_hash"].is_string());
+    assert!(json.get("symlink_target").is_none());
+}
+
+#[test]
+fn history_of_one_file() {
+    let af = archive_with_history();
+    let monitor = TestMonitor::arc();
+    let versions = history(&af, &"/a.txt".into(), monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(
+        summarize(&versions),
+        [
+            (
+                "/a.txt".to_owned(),
+                "b0000".to_owned(),
+                VersionChange::Added
+            ),
+            (
+                "/a.txt".to_owned(),
+                "b0001".to_owned(),
+                VersionChange::Changed
+            ),
+            (
+                "/a.txt".to_owned(),
+                "b0002".to_owned(),
+                VersionChange::Unchanged
+            ),
+            (
+                "/a.txt".to_owned(),
+                "b0004".to_owned(),
+                VersionChange::Added
+            ),
+        ]
+    );
+    assert_eq!(versions[0].size, Some(3));
+    assert_eq!(versions[1].size, Some(11));
+
+    assert!(history(&af, &"/missing".into(), TestMonitor::arc())
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn history_skips_band_with_missing_index() {
+    let af = archive_with_history();
+    fs::remove_dir_all(af.path().join("b0001").join("i")).unwrap();
+    let monitor = TestMonitor::arc();
+    let versions = history(&af, &"/a.txt".into(), monitor.clone()).unwrap();
+    assert_eq!(monitor.take_errors().len(), 1);
+    assert_eq!(
+        summarize(&versions),
+        [
+            (
+                "/a.txt".to_owned(),
+                "b0000".to_owned(),
+                VersionChange::Added
+            ),
+            (
+                "/a.txt".to_owned(),
+                "b0002".to_owned(),
+                VersionChange::Changed
+            ),
+            (
+                "/a.txt".to_owned(),
+                "b0004".to_owned(),
+                VersionChange::Added
+            ),
+        ]
+    );
+}
@@ -12,6 +12,11 @@
 
 //! Test garbage collection.
 
+use std::time::{Duration, SystemTime};
+
+use filetime::{set_file_mtime, FileTime};
+
+use conserve::blockdir::block_relpath;
 use conserve::monitor::test::TestMonitor;
 use conserve::test_fixtures::{ScratchArchive, TreeFixture};
 use conserve::*;
@@ -53,6 +58,7 @@ fn unreferenced_blocks() {
             &DeleteOptions {
                 dry_run: true,
                 break_lock: false,
+                ..Default::default()
             },
             monitor.clone(),
         )
@@ -62,8 +68,12 @@ fn unreferenced_blocks() {
         DeleteStats {
             unreferenced_block_count: 1,
             unreferenced_block_bytes: 10,
+            recent_block_count: 0,
+            reclaimable_block_count: 1,
+            reclaimable_block_bytes: 10,
             deletion_errors: 0,
             deleted_block_count: 0,
+            deleted_block_bytes: 0,
             deleted_band_count: 0,
             elapsed: delete_stats.elapsed,
         }
@@ -73,6 +83,7 @@ fn unreferenced_blocks() {
     let options = DeleteOptions {
         dry_run: false,
         break_lock: false,
+        ..Default::default()
     };
     let delete_stats = archive
         .delete_bands(&[], &options, monitor.clone())
@@ -82,8 +93,12 @@ fn unreferenced_blocks() {
         DeleteStats {
             unreferenced_block_count: 1,
             unreferenced_block_bytes: 10,
+            recent_block_count: 0,
+            reclaimable_block_count: 1,
+            reclaimable_block_bytes: 10,
             deletion_errors: 0,
             deleted_block_count: 1,
+            deleted_block_bytes: 10,
             deleted_band_count: 0,
             elapsed: delete_stats.elapsed,
         }
@@ -98,14 +113,75 @@ fn unreferenced_blocks() {
         DeleteStats {
             unreferenced_block_count: 0,
             unreferenced_block_bytes: 0,
+            recent_block_count: 0,
+            reclaimable_block_count: 0,
+            reclaimable_block_bytes: 0,
             deletion_errors: 0,
             deleted_block_count: 0,
+            deleted_block_bytes: 0,
             deleted_band_count: 0,
             elapsed: delete_stats.elapsed,
         }
     );
 }
 
+#[test]
+fn recent_unreferenced_blocks_are_kept() {
+    let archive = ScratchArchive::new();
+    let tf = TreeFixture::new();
+    tf.create_file("hello");
+    backup(
+        &archive,
+        tf.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .expect("backup");
+    std::fs::remove_dir_all(archive.path().join("b0000")).unwrap();
+    let options = DeleteOptions {
+        min_block_age: Some(Duration::from_secs(3600)),
+        ..Default::default()
+    };
+
+    let delete_stats = archive
+        .delete_bands(&[], &options, TestMonitor::arc())
+        .unwrap();
+    assert_eq!(delete_stats.unreferenced_block_count, 1);
+    assert_eq!(delete_stats.recent_block_count, 1);
+    assert_eq!(delete_stats.reclaimable_block_count, 0);
+    assert_eq!(delete_stats.reclaimable_block_bytes, 0);
+    assert_eq!(delete_stats.deleted_block_count, 0);
+    assert_eq!(
+        archive
+            .unreferenced_blocks(TestMonitor::arc())
+            .unwrap()
+            .count(),
+        1
+    );
+
+    // Once the block is older than the threshold, it's deleted.
+    let hash = archive
+        .unreferenced_blocks(TestMonitor::arc())
+        .unwrap()
+        .collect::<Vec<BlockHash>>()
+        .remove(0);
+    let block_path = archive.path().join("d").join(block_relpath(&hash));
+    let two_hours_ago = FileTime::from_system_time(SystemTime::now() - Duration::from_secs(7200));
+    set_file_mtime(block_path, two_hours_ago).unwrap();
+    let delete_stats = archive
+        .delete_bands(&[], &options, TestMonitor::arc())
+        .unwrap();
+    assert_eq!(delete_stats.recent_block_count, 0);
+    assert_eq!(delete_stats.deleted_block_count, 1);
+    assert_eq!(
+        archive
+            .unreferenced_blocks(TestMonitor::arc())
+            .unwrap()
+            .count(),
+        0
+    );
+}
+
 #[test]
 fn backup_prevented_by_gc_lock() -> Result<()> {
     let archive = ScratchArchive::new();
@@ -224,15 +224,12 @@ fn restore_modify_backup() {
         let backup_stats = backup(
             &new_archive,
             working_tree.path(),
-            &BackupOptions {
-                change_callback: Some(Box::new(|change| {
-                    emitted
-                        .borrow_mut()
-                        .push((change.change.sigil(), change.apath.to_string()));
-                    Ok(())
-                })),
-                ..Default::default()
-            },
+            &BackupOptions::default().with_change_callback(Some(Box::new(|change| {
+                emitted
+                    .borrow_mut()
+                    .push((change.change.sigil(), change.apath.to_string()));
+                Ok(())
+            }))),
             TestMonitor::arc(),
         )
         .expect("Backup modified tree");
@@ -13,6 +13,9 @@
 
 //! Try backing up and restoring various sequences of changes to a tree.
 
+// proptest-derive 0.4 generates impls inside a const block.
+#![allow(non_local_definitions)]
+
 use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
@@ -51,7 +54,7 @@ enum TreeChange {
 }
 
 fn backup_sequential_changes(changes: &[TreeChange]) {
-    use TreeChange::*;
+    use self::TreeChange::*;
     let tf = TreeFixture::new();
     let archive = ScratchArchive::new();
     let mut live_files: Vec<String> = Vec::new();
@@ -90,10 +93,7 @@ fn backup_sequential_changes(changes: &[TreeChange]) {
                 // Wait a little bit to let files get distinct mtimes: very
                 // close-spaced updates might not be distinguishable by mtime.
                 std::thread::sleep(std::time::Duration::from_millis(10));
-                let options = BackupOptions {
-                    max_entries_per_hunk: 3,
-                    ..BackupOptions::default()
-                };
+                let options = BackupOptions::default().with_max_entries_per_hunk(3);
                 backup(&archive, tf.path(), &options, TestMonitor::arc()).unwrap();
                 let snapshot = TempDir::new().unwrap();
                 cp_r::CopyOptions::default()
@@ -122,10 +122,8 @@ fn backup_sequential_changes(changes: &[TreeChange]) {
 fn check_restore_against_snapshot(archive: &Archive, band_id: BandId, snapshot: &Path) {
     let restore_dir = tempfile::tempdir().unwrap();
     // TODO: Select the right band.
-    let options = RestoreOptions {
-        band_selection: BandSelectionPolicy::Specified(band_id),
-        ..RestoreOptions::default()
-    };
+    let options =
+        RestoreOptions::default().with_band_selection(BandSelectionPolicy::Specified(band_id));
     restore(archive, restore_dir.path(), &options, TestMonitor::arc()).unwrap();
     dir_assert::assert_paths(restore_dir.path(), snapshot).unwrap();
 }
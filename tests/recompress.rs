@@ -0,0 +1,108 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for rewriting blocks with different compression.
+
+use assert_fs::prelude::*;
+use assert_fs::TempDir;
+use rayon::prelude::ParallelIterator;
+
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+#[test]
+fn recompress_to_zstd_and_back() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("hello", &b"hello world ".repeat(1000));
+    srcdir.create_file_of_length_with_prefix("big", 2 << 20, b"big");
+    backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    let blocks_before: Vec<BlockHash> =
+        af.block_dir().blocks(TestMonitor::arc()).unwrap().collect();
+    assert!(Band::open(&af, BandId::zero())
+        .unwrap()
+        .format_flags()
+        .is_empty());
+
+    let options = RecompressOptions {
+        compression: Compression::Zstd { level: 19 },
+        ..Default::default()
+    };
+    let stats = recompress(&af, &options, TestMonitor::arc()).unwrap();
+    assert_eq!(stats.blocks, 2);
+    assert_eq!(stats.rewritten_blocks, 2);
+    assert_eq!(stats.errors, 0);
+    assert!(stats.new_compressed_bytes < stats.old_compressed_bytes);
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    assert_eq!(band.format_flags(), ["tagged_blocks"]);
+    assert_eq!(band.band_format_version(), Some("23.2.0"));
+    assert_eq!(
+        band.get_info().unwrap().compression,
+        Some(Compression::Zstd { level: 19 })
+    );
+
+    // Recompressing again with the same settings leaves the blocks alone.
+    let stats = recompress(&af, &options, TestMonitor::arc()).unwrap();
+    assert_eq!(stats.rewritten_blocks, 0);
+
+    let archive = Archive::open_path(af.path()).unwrap();
+    let mut blocks_after: Vec<BlockHash> = archive
+        .block_dir()
+        .blocks(TestMonitor::arc())
+        .unwrap()
+        .collect();
+    let mut blocks_before = blocks_before;
+    blocks_before.sort();
+    blocks_after.sort();
+    assert_eq!(blocks_before, blocks_after);
+    archive
+        .validate(&ValidateOptions::default(), TestMonitor::arc())
+        .unwrap();
+
+    let stats = recompress(
+        &archive,
+        &RecompressOptions {
+            compression: Compression::Snappy,
+            ..Default::default()
+        },
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    assert_eq!(stats.rewritten_blocks, 2);
+
+    let restore_dir = TempDir::new().unwrap();
+    restore(
+        &archive,
+        restore_dir.path(),
+        &RestoreOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    restore_dir
+        .child("hello")
+        .assert("hello world ".repeat(1000));
+}
+
+#[test]
+fn recompress_is_blocked_by_gc_lock() {
+    let af = ScratchArchive::new();
+    let _lock = GarbageCollectionLock::new(&af).unwrap();
+    let err = recompress(&af, &RecompressOptions::default(), TestMonitor::arc()).unwrap_err();
+    assert!(matches!(err, Error::GarbageCollectionLockHeld));
+}
@@ -0,0 +1,111 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for repairing damaged bands.
+
+use std::fs;
+
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+/// Make an archive with one band whose index has a hunk for each of four files.
+fn archive_with_four_hunks() -> ScratchArchive {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for name in ["a", "b", "c"] {
+        srcdir.create_file(name);
+    }
+    let options = BackupOptions::default().with_max_entries_per_hunk(1);
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    af
+}
+
+fn apaths(af: &Archive) -> Vec<String> {
+    af.iter_entries(
+        BandSelectionPolicy::Latest,
+        Apath::root(),
+        Exclude::nothing(),
+        TestMonitor::arc(),
+    )
+    .unwrap()
+    .map(|entry| entry.apath.to_string())
+    .collect()
+}
+
+#[test]
+fn undamaged_archive_is_unchanged() {
+    let af = archive_with_four_hunks();
+    let stats = repair(&af, &RepairOptions::default(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.bands, 1);
+    assert_eq!(stats.repaired_bands, 0);
+    assert_eq!(stats.errors, 0);
+    assert!(!Band::open(&af, BandId::zero()).unwrap().is_recovered());
+}
+
+#[test]
+fn remove_corrupt_index_hunk() {
+    let af = archive_with_four_hunks();
+    let hunk_path = af.path().join("b0000/i/00000/000000002");
+    fs::write(&hunk_path, b"not an index hunk").unwrap();
+
+    let dry_run = RepairOptions { dry_run: true };
+    let stats = repair(&af, &dry_run, TestMonitor::arc()).unwrap();
+    assert_eq!(stats.repaired_bands, 1);
+    assert_eq!(stats.removed_index_hunks, 1);
+    assert!(hunk_path.is_file());
+
+    let stats = repair(&af, &RepairOptions::default(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.repaired_bands, 1);
+    assert_eq!(stats.removed_index_hunks, 1);
+    assert_eq!(stats.rewritten_tails, 1);
+    assert!(!hunk_path.exists());
+
+    // Entries in later hunks are still readable.
+    assert_eq!(apaths(&af), ["/", "/a", "/c"]);
+    let info = Band::open(&af, BandId::zero()).unwrap().get_info().unwrap();
+    assert!(info.recovered);
+    assert!(info.is_closed);
+    assert_eq!(info.index_hunk_count, Some(3));
+
+    // Once repaired, there's nothing more to do.
+    let stats = repair(&af, &RepairOptions::default(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.repaired_bands, 0);
+}
+
+#[test]
+fn replace_missing_band_head() {
+    let af = archive_with_four_hunks();
+    fs::remove_file(af.path().join("b0000/BANDHEAD")).unwrap();
+    assert!(Band::open(&af, BandId::zero()).is_err());
+
+    let stats = repair(&af, &RepairOptions::default(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.replaced_heads, 1);
+    assert_eq!(stats.repaired_bands, 1);
+
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    assert!(band.is_recovered());
+    assert!(band.is_closed().unwrap());
+    assert_eq!(apaths(&af), ["/", "/a", "/b", "/c"]);
+}
+
+#[test]
+fn rewrite_unreadable_tail() {
+    let af = archive_with_four_hunks();
+    fs::write(af.path().join("b0000/BANDTAIL"), b"{").unwrap();
+
+    let stats = repair(&af, &RepairOptions::default(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.rewritten_tails, 1);
+    let info = Band::open(&af, BandId::zero()).unwrap().get_info().unwrap();
+    assert!(info.recovered);
+    assert_eq!(info.index_hunk_count, Some(4));
+}
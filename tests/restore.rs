@@ -19,6 +19,7 @@ use std::path::PathBuf;
 
 use conserve::counters::Counter;
 use conserve::monitor::test::TestMonitor;
+use conserve::monitor::EntryOutcome;
 use filetime::{set_symlink_file_times, FileTime};
 use tempfile::TempDir;
 
@@ -33,18 +34,20 @@ fn simple_restore() {
     let destdir = TreeFixture::new();
     let restore_archive = Archive::open_path(af.path()).unwrap();
     let restored_names = RefCell::new(Vec::new());
-    let options = RestoreOptions {
-        change_callback: Some(Box::new(|entry_change| {
-            restored_names.borrow_mut().push(entry_change.apath.clone());
-            Ok(())
-        })),
-        ..Default::default()
-    };
+    let options = RestoreOptions::default().with_change_callback(Some(Box::new(|entry_change| {
+        restored_names.borrow_mut().push(entry_change.apath.clone());
+        Ok(())
+    })));
     let monitor = TestMonitor::arc();
-    restore(&restore_archive, destdir.path(), &options, monitor.clone()).expect("restore");
+    let stats =
+        restore(&restore_archive, destdir.path(), &options, monitor.clone()).expect("restore");
 
     monitor.assert_no_errors();
     monitor.assert_counter(Counter::Files, 3);
+    assert_eq!(stats.files, 3);
+    assert_eq!(stats.dirs, 2);
+    assert_eq!(stats.symlinks, usize::from(SYMLINKS_SUPPORTED));
+    assert_eq!(stats.errors, 0);
     let mut expected_names = vec![
         "/",
         "/hello",
@@ -72,6 +75,35 @@ fn simple_restore() {
     // TODO: Test file contents are as expected.
 }
 
+#[test]
+fn restore_reports_entries_to_monitor() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TreeFixture::new();
+    destdir.create_file("hello");
+    let options = RestoreOptions::default().with_overwrite(Overwrite::SkipExisting);
+    let monitor = TestMonitor::arc();
+    restore(&af, destdir.path(), &options, monitor.clone()).expect("restore");
+    monitor.assert_no_errors();
+
+    assert_eq!(
+        monitor.take_started_files(),
+        ["/hello", "/hello2", "/subdir/subfile"].map(Apath::from)
+    );
+    let mut expected = vec![
+        (Apath::from("/"), EntryOutcome::Skipped, 0),
+        (Apath::from("/hello"), EntryOutcome::Skipped, 0),
+        (Apath::from("/hello2"), EntryOutcome::Written, 8),
+        (Apath::from("/link"), EntryOutcome::Written, 0),
+        (Apath::from("/subdir"), EntryOutcome::Written, 0),
+        (Apath::from("/subdir/subfile"), EntryOutcome::Written, 8),
+    ];
+    if !SYMLINKS_SUPPORTED {
+        expected.retain(|(apath, _, _)| *apath != "/link");
+    }
+    assert_eq!(monitor.take_finished_entries(), expected);
+}
+
 #[test]
 fn restore_specified_band() {
     let af = ScratchArchive::new();
@@ -79,10 +111,8 @@ fn restore_specified_band() {
     let destdir = TreeFixture::new();
     let archive = Archive::open_path(af.path()).unwrap();
     let band_id = BandId::new(&[0]);
-    let options = RestoreOptions {
-        band_selection: BandSelectionPolicy::Specified(band_id),
-        ..RestoreOptions::default()
-    };
+    let options =
+        RestoreOptions::default().with_band_selection(BandSelectionPolicy::Specified(band_id));
     let monitor = TestMonitor::arc();
     restore(&archive, destdir.path(), &options, monitor.clone()).expect("restore");
     monitor.assert_no_errors();
@@ -96,10 +126,12 @@ pub fn decline_to_overwrite() {
     af.store_two_versions();
     let destdir = TreeFixture::new();
     destdir.create_file("existing");
-    let options = RestoreOptions {
-        ..RestoreOptions::default()
-    };
-    assert!(!options.overwrite, "overwrite is false by default");
+    let options = RestoreOptions::default();
+    assert_eq!(
+        options.overwrite,
+        Overwrite::Fail,
+        "overwrite fails by default"
+    );
     let restore_err_str = restore(&af, destdir.path(), &options, TestMonitor::arc())
         .expect_err("restore should fail if the destination exists")
         .to_string();
@@ -117,10 +149,7 @@ pub fn forced_overwrite() {
     destdir.create_file("existing");
 
     let restore_archive = Archive::open_path(af.path()).unwrap();
-    let options = RestoreOptions {
-        overwrite: true,
-        ..RestoreOptions::default()
-    };
+    let options = RestoreOptions::default().with_overwrite(Overwrite::Always);
     let monitor = TestMonitor::arc();
     restore(&restore_archive, destdir.path(), &options, monitor.clone()).expect("restore");
     monitor.assert_no_errors();
@@ -130,17 +159,125 @@ pub fn forced_overwrite() {
     assert!(dest.join("existing").is_file());
 }
 
+#[test]
+#[cfg(unix)]
+fn symlink_at_directory_path_is_replaced() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("subdir");
+    srcdir.create_file("subdir/subfile");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    for overwrite in [Overwrite::SkipExisting, Overwrite::IfNewer] {
+        let outside = TempDir::new().unwrap();
+        let destdir = TreeFixture::new();
+        destdir.create_symlink("subdir", outside.path().to_str().unwrap());
+        let options = RestoreOptions::default().with_overwrite(overwrite);
+        let monitor = TestMonitor::arc();
+        restore(&af, destdir.path(), &options, monitor.clone()).unwrap();
+        monitor.assert_no_errors();
+        assert!(
+            symlink_metadata(destdir.path().join("subdir"))
+                .unwrap()
+                .is_dir(),
+            "{overwrite:?}"
+        );
+        assert!(destdir.path().join("subdir/subfile").is_file());
+        assert!(
+            std::fs::read_dir(outside.path()).unwrap().next().is_none(),
+            "nothing is restored outside the destination with {overwrite:?}"
+        );
+    }
+}
+
+#[test]
+fn verify_restored_files() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TreeFixture::new();
+    let options = RestoreOptions::default().with_verify(true);
+    let monitor = TestMonitor::arc();
+    restore(&af, destdir.path(), &options, monitor.clone()).expect("restore");
+    monitor.assert_no_errors();
+    monitor.assert_counter(Counter::VerifiedFiles, 3);
+}
+
+#[test]
+fn overwrite_policies() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let stored_mtime = FileTime::from_unix_time(1_000_000_000, 0);
+    for name in ["older", "newer", "missing"] {
+        let path = srcdir.create_file_with_contents(name, format!("stored {name}").as_bytes());
+        filetime::set_file_mtime(path, stored_mtime).unwrap();
+    }
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let restore_with = |overwrite| {
+        let destdir = TreeFixture::new();
+        let older = destdir.create_file_with_contents("older", b"existing");
+        filetime::set_file_mtime(older, FileTime::from_unix_time(900_000_000, 0)).unwrap();
+        let newer = destdir.create_file_with_contents("newer", b"existing");
+        filetime::set_file_mtime(newer, FileTime::from_unix_time(1_100_000_000, 0)).unwrap();
+        destdir.create_file_with_contents("extra", b"existing");
+        let options = RestoreOptions::default().with_overwrite(overwrite);
+        let monitor = TestMonitor::arc();
+        restore(&af, destdir.path(), &options, monitor.clone()).unwrap();
+        monitor.assert_no_errors();
+        let content = ["older", "newer", "missing", "extra"]
+            .map(|name| std::fs::read_to_string(destdir.path().join(name)).unwrap());
+        let conflicts = monitor
+            .take_conflicts()
+            .into_iter()
+            .map(|conflict| (conflict.apath.to_string(), conflict.resolution))
+            .collect::<Vec<_>>();
+        (content, conflicts)
+    };
+
+    use ConflictResolution::*;
+    let (content, conflicts) = restore_with(Overwrite::Always);
+    assert_eq!(
+        content,
+        ["stored older", "stored newer", "stored missing", "existing"]
+    );
+    assert_eq!(
+        conflicts,
+        [
+            ("/newer".to_owned(), Replaced),
+            ("/older".to_owned(), Replaced)
+        ]
+    );
+
+    let (content, conflicts) = restore_with(Overwrite::SkipExisting);
+    assert_eq!(
+        content,
+        ["existing", "existing", "stored missing", "existing"]
+    );
+    assert_eq!(
+        conflicts,
+        [("/newer".to_owned(), Kept), ("/older".to_owned(), Kept)]
+    );
+
+    let (content, conflicts) = restore_with(Overwrite::IfNewer);
+    assert_eq!(
+        content,
+        ["stored older", "existing", "stored missing", "existing"]
+    );
+    assert_eq!(
+        conflicts,
+        [("/newer".to_owned(), Kept), ("/older".to_owned(), Replaced)]
+    );
+}
+
 #[test]
 fn exclude_files() {
     let af = ScratchArchive::new();
     af.store_two_versions();
     let destdir = TreeFixture::new();
     let restore_archive = Archive::open_path(af.path()).unwrap();
-    let options = RestoreOptions {
-        overwrite: true,
-        exclude: Exclude::from_strings(["/**/subfile"]).unwrap(),
-        ..RestoreOptions::default()
-    };
+    let options = RestoreOptions::default()
+        .with_overwrite(Overwrite::Always)
+        .with_exclude(Exclude::from_strings(["/**/subfile"]).unwrap());
     let monitor = TestMonitor::arc();
     restore(&restore_archive, destdir.path(), &options, monitor.clone()).expect("restore");
 
@@ -152,6 +289,24 @@ fn exclude_files() {
     monitor.assert_counter(Counter::Files, 2);
 }
 
+#[test]
+fn exclude_directory_and_its_contents() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let destdir = TreeFixture::new();
+    let options =
+        RestoreOptions::default().with_exclude(Exclude::from_strings(["/subdir"]).unwrap());
+    let monitor = TestMonitor::arc();
+    restore(&af, destdir.path(), &options, monitor.clone()).expect("restore");
+
+    let dest = destdir.path();
+    assert!(dest.join("hello").is_file());
+    assert!(dest.join("hello2").is_file());
+    assert!(!dest.join("subdir").exists());
+    monitor.assert_no_errors();
+    monitor.assert_counter(Counter::Files, 2);
+}
+
 #[test]
 #[cfg(unix)]
 fn restore_symlink() {
@@ -186,3 +341,712 @@ fn restore_symlink() {
         PathBuf::from("target")
     );
 }
+
+#[test]
+#[cfg(target_os = "linux")]
+fn restore_acls() {
+    use conserve::acl::Acl;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let path = srcdir.create_file("file");
+    let acls = Acls {
+        access: Some(
+            "user::rw-,user:1234:r--,group::r--,mask::r--,other::---"
+                .parse::<Acl>()
+                .unwrap(),
+        ),
+        default: None,
+    };
+    if acls.write(&path).is_err() {
+        // The temp filesystem might not support ACLs.
+        return;
+    }
+
+    let monitor = TestMonitor::arc();
+    backup(&af, srcdir.path(), &Default::default(), monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    let entry = band
+        .index()
+        .iter_entries()
+        .find(|entry| entry.apath == "/file")
+        .unwrap();
+    assert_eq!(entry.acls, acls);
+
+    let restore_dir = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    restore(
+        &af,
+        restore_dir.path(),
+        &Default::default(),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(Acls::read(restore_dir.path().join("file")).unwrap(), acls);
+
+    let restore_dir = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    restore(
+        &af,
+        restore_dir.path(),
+        &RestoreOptions::default().with_acls(false),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    assert!(Acls::read(restore_dir.path().join("file"))
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn restore_capabilities() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let path = srcdir.create_file("ping");
+    // cap_net_raw=ep
+    let capability = Capability::from_xattr(&[
+        1, 0, 0, 2, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ])
+    .unwrap();
+    if capability.write(&path).is_err() {
+        // Setting capabilities needs root, and a filesystem that supports them.
+        return;
+    }
+
+    let monitor = TestMonitor::arc();
+    backup(
+        &af,
+        srcdir.path(),
+        // Capabilities are kept even when ACLs are not.
+        &BackupOptions::default().with_acls(false),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    let entry = band
+        .index()
+        .iter_entries()
+        .find(|entry| entry.apath == "/ping")
+        .unwrap();
+    assert_eq!(entry.capability.as_ref(), Some(&capability));
+
+    let restore_dir = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    restore(
+        &af,
+        restore_dir.path(),
+        &Default::default(),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(
+        Capability::read(restore_dir.path().join("ping")).unwrap(),
+        Some(capability)
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn restore_owner_by_id_when_name_is_unknown() {
+    use std::os::unix::fs::{chown, MetadataExt};
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let path = srcdir.create_file("file");
+    // Presumably no user or group on the test machine has this id.
+    let id = 54_321;
+    if chown(&path, Some(id), Some(id)).is_err() {
+        // Changing ownership needs root.
+        return;
+    }
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    let entry = Band::open(&af, BandId::zero())
+        .unwrap()
+        .index()
+        .iter_entries()
+        .find(|entry| entry.apath == "/file")
+        .unwrap();
+    assert_eq!(entry.owner.uid, Some(id));
+    assert_eq!(entry.owner.gid, Some(id));
+
+    for numeric_ids in [false, true] {
+        let restore_dir = TempDir::new().unwrap();
+        let monitor = TestMonitor::arc();
+        restore(
+            &af,
+            restore_dir.path(),
+            &RestoreOptions::default().with_numeric_ids(numeric_ids),
+            monitor.clone(),
+        )
+        .unwrap();
+        monitor.assert_no_errors();
+        let metadata = std::fs::metadata(restore_dir.path().join("file")).unwrap();
+        assert_eq!(metadata.uid(), id);
+        assert_eq!(metadata.gid(), id);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn restore_mapped_owners_or_as_current_user() {
+    use std::os::unix::fs::{chown, MetadataExt};
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let path = srcdir.create_file("file");
+    let id = 54_321;
+    if chown(&path, Some(id), Some(id)).is_err() {
+        // Changing ownership needs root.
+        return;
+    }
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let restore_with = |options: RestoreOptions| {
+        let restore_dir = TempDir::new().unwrap();
+        let monitor = TestMonitor::arc();
+        restore(&af, restore_dir.path(), &options, monitor.clone()).unwrap();
+        monitor.assert_no_errors();
+        let metadata = std::fs::metadata(restore_dir.path().join("file")).unwrap();
+        (metadata.uid(), metadata.gid())
+    };
+
+    let owner_map = OwnerMap::from_strings(["54321:54322"], ["54321:54323"]).unwrap();
+    assert_eq!(
+        restore_with(RestoreOptions::default().with_owner_map(owner_map)),
+        (54_322, 54_323)
+    );
+
+    let current = std::fs::metadata(TempDir::new().unwrap().path()).unwrap();
+    assert_eq!(
+        restore_with(RestoreOptions::default().with_set_owners(SetOwners::Never)),
+        (current.uid(), current.gid())
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn restore_hardlinks() {
+    use std::fs::hard_link;
+    use std::os::unix::fs::MetadataExt;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let first = srcdir.create_file_with_contents("a", b"linked content");
+    srcdir.create_dir("sub");
+    hard_link(&first, srcdir.path().join("b")).unwrap();
+    hard_link(&first, srcdir.path().join("sub/c")).unwrap();
+    srcdir.create_file("unlinked");
+
+    let monitor = TestMonitor::arc();
+    let stats = backup(&af, srcdir.path(), &Default::default(), monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.hardlinks, 2);
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    let link_groups = band
+        .index()
+        .iter_entries()
+        .filter(|entry| entry.kind == Kind::File)
+        .map(|entry| (entry.apath.to_string(), entry.link_group))
+        .collect::<Vec<_>>();
+    let first_apath = Some(Apath::from("/a"));
+    assert_eq!(
+        link_groups,
+        [
+            ("/a".to_owned(), first_apath.clone()),
+            ("/b".to_owned(), first_apath.clone()),
+            ("/unlinked".to_owned(), None),
+            ("/sub/c".to_owned(), first_apath.clone()),
+        ]
+    );
+
+    let restore_dir = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    restore(
+        &af,
+        restore_dir.path(),
+        &Default::default(),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    monitor.assert_counter(Counter::Files, 4);
+    monitor.assert_counter(Counter::Hardlinks, 2);
+    let a_meta = symlink_metadata(restore_dir.path().join("a")).unwrap();
+    assert_eq!(a_meta.nlink(), 3);
+    for path in ["b", "sub/c"] {
+        let meta = symlink_metadata(restore_dir.path().join(path)).unwrap();
+        assert_eq!(meta.ino(), a_meta.ino());
+    }
+    assert_eq!(
+        std::fs::read(restore_dir.path().join("sub/c")).unwrap(),
+        b"linked content"
+    );
+    assert_eq!(
+        symlink_metadata(restore_dir.path().join("unlinked"))
+            .unwrap()
+            .nlink(),
+        1
+    );
+
+    // A member of the group that was replaced by an identical copy is linked again,
+    // even when unchanged files are kept.
+    let b_path = restore_dir.path().join("b");
+    std::fs::remove_file(&b_path).unwrap();
+    std::fs::copy(restore_dir.path().join("a"), &b_path).unwrap();
+    filetime::set_file_mtime(&b_path, FileTime::from_last_modification_time(&a_meta)).unwrap();
+    let monitor = TestMonitor::arc();
+    restore(
+        &af,
+        restore_dir.path(),
+        &RestoreOptions::default().with_skip_unchanged(Some(UnchangedCheck::Metadata)),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    monitor.assert_counter(Counter::UnchangedFiles, 3);
+    monitor.assert_counter(Counter::Hardlinks, 1);
+    assert_eq!(symlink_metadata(&b_path).unwrap().ino(), a_meta.ino());
+
+    // When the first link is excluded, the next one is restored as a copy and the rest
+    // linked to it.
+    let restore_dir = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    restore(
+        &af,
+        restore_dir.path(),
+        &RestoreOptions::default().with_exclude(Exclude::from_strings(["/a"]).unwrap()),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    let b_meta = symlink_metadata(restore_dir.path().join("b")).unwrap();
+    assert_eq!(b_meta.nlink(), 2);
+    assert_eq!(
+        symlink_metadata(restore_dir.path().join("sub/c"))
+            .unwrap()
+            .ino(),
+        b_meta.ino()
+    );
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn restore_sparse_file() {
+    use std::fs::OpenOptions;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::unix::fs::MetadataExt;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let mib = 1 << 20;
+    let path = srcdir.create_file_with_contents("sparse", b"");
+    let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.seek(SeekFrom::Start(4 * mib)).unwrap();
+    file.write_all(&[1; 8192]).unwrap();
+    file.set_len(16 * mib).unwrap();
+    drop(file);
+    if symlink_metadata(&path).unwrap().blocks() * 512 >= 16 * mib {
+        // The temp filesystem doesn't support holes.
+        return;
+    }
+
+    let monitor = TestMonitor::arc();
+    let stats = backup(&af, srcdir.path(), &Default::default(), monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.sparse_files, 1);
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    assert!(band.format_flags().iter().any(|f| f == "sparse_files"));
+    let entry = band
+        .index()
+        .iter_entries()
+        .find(|entry| entry.apath == "/sparse")
+        .unwrap();
+    assert!(!entry.holes.is_empty());
+    assert_eq!(entry.size(), Some(16 * mib));
+    assert!(entry.addrs.iter().map(|addr| addr.len).sum::<u64>() < mib);
+
+    let restore_dir = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    restore(
+        &af,
+        restore_dir.path(),
+        &Default::default(),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    let restored_path = restore_dir.path().join("sparse");
+    assert_eq!(
+        std::fs::read(&restored_path).unwrap(),
+        std::fs::read(&path).unwrap()
+    );
+    let restored_meta = symlink_metadata(&restored_path).unwrap();
+    assert_eq!(restored_meta.len(), 16 * mib);
+    assert!(restored_meta.blocks() * 512 < mib);
+
+    // Holes are read as zeros from a stored file.
+    let mut content = Vec::new();
+    af.open_stored_tree(BandSelectionPolicy::Latest)
+        .unwrap()
+        .open_file(&"/sparse".into(), TestMonitor::arc())
+        .unwrap()
+        .read_to_end(&mut content)
+        .unwrap();
+    assert_eq!(content, std::fs::read(&path).unwrap());
+
+    // The restored file, holes and all, matches the stored content.
+    let options = RestoreOptions::default().with_skip_unchanged(Some(UnchangedCheck::Content));
+    let monitor = TestMonitor::arc();
+    restore(&af, restore_dir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    monitor.assert_counter(Counter::UnchangedFiles, 1);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn restore_zero_runs_as_holes() {
+    use std::os::unix::fs::MetadataExt;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let mib = 1 << 20;
+    // Written in full, so it's not recorded as sparse.
+    let mut content = vec![0; 8 * mib];
+    content[4 * mib..4 * mib + 8192].fill(1);
+    srcdir.create_file_with_contents("zeros", &content);
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let restore_dir = TempDir::new().unwrap();
+    let options = RestoreOptions::default()
+        .with_zero_runs_as_holes(true)
+        .with_verify(true);
+    let monitor = TestMonitor::arc();
+    restore(&af, restore_dir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    let restored_path = restore_dir.path().join("zeros");
+    assert_eq!(std::fs::read(&restored_path).unwrap(), content);
+    let restored_meta = symlink_metadata(&restored_path).unwrap();
+    assert_eq!(restored_meta.len(), 8 * mib as u64);
+    let allocated = restored_meta.blocks() * 512;
+    if allocated >= 8 * mib as u64 {
+        // The temp filesystem doesn't support holes.
+        return;
+    }
+    assert!(allocated < mib as u64, "{allocated} bytes allocated");
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn restore_special_files() {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    use std::os::unix::net::UnixListener;
+
+    use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+    use nix::unistd::mkfifo;
+
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    mkfifo(&srcdir.path().join("fifo"), Mode::S_IRUSR | Mode::S_IWUSR).unwrap();
+    let _listener = UnixListener::bind(srcdir.path().join("socket")).unwrap();
+    // Making device nodes needs privileges, so only test them if possible.
+    let devices = mknod(
+        &srcdir.path().join("null"),
+        SFlag::S_IFCHR,
+        Mode::S_IRUSR | Mode::S_IWUSR,
+        makedev(1, 3),
+    )
+    .is_ok();
+
+    let monitor = TestMonitor::arc();
+    let stats = backup(&af, srcdir.path(), &Default::default(), monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.special_files, if devices { 3 } else { 2 });
+    let band = Band::open(&af, BandId::zero()).unwrap();
+    assert_eq!(band.format_flags(), ["special_files"]);
+    let entries = band
+        .index()
+        .iter_entries()
+        .map(|entry| (entry.apath.to_string(), entry.kind, entry.device))
+        .collect::<Vec<_>>();
+    let mut expected = vec![
+        ("/".to_owned(), Kind::Dir, None),
+        ("/fifo".to_owned(), Kind::Fifo, None),
+    ];
+    if devices {
+        expected.push((
+            "/null".to_owned(),
+            Kind::CharDevice,
+            Some(DeviceNumber { major: 1, minor: 3 }),
+        ));
+    }
+    expected.push(("/socket".to_owned(), Kind::Socket, None));
+    assert_eq!(entries, expected);
+
+    let restore_dir = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    restore(
+        &af,
+        restore_dir.path(),
+        &Default::default(),
+        monitor.clone(),
+    )
+    .unwrap();
+    monitor.assert_no_errors();
+    monitor.assert_counter(Counter::SpecialFiles, if devices { 3 } else { 2 });
+    let file_type = |name: &str| {
+        symlink_metadata(restore_dir.path().join(name))
+            .unwrap()
+            .file_type()
+    };
+    assert!(file_type("fifo").is_fifo());
+    assert!(file_type("socket").is_socket());
+    if devices {
+        assert!(file_type("null").is_char_device());
+        assert_eq!(
+            symlink_metadata(restore_dir.path().join("null"))
+                .unwrap()
+                .rdev(),
+            makedev(1, 3)
+        );
+    }
+}
+
+#[test]
+fn restore_only_selected_globs_and_subtrees() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("src");
+    srcdir.create_file("src/a.rs");
+    srcdir.create_file("src/b.c");
+    srcdir.create_dir("src/empty");
+    srcdir.create_dir("src/sub");
+    srcdir.create_file("src/sub/c.rs");
+    srcdir.create_dir("doc");
+    srcdir.create_file("doc/x.md");
+    srcdir.create_dir("doc/deep");
+    srcdir.create_dir("doc/deep/er");
+    srcdir.create_file("doc/deep/er/y.md");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let restore_dir = TempDir::new().unwrap();
+    let restored_names = RefCell::new(Vec::new());
+    let options = RestoreOptions::default()
+        .with_only(Include::from_strings(["/src/**/*.rs", "/doc/deep/er"]).unwrap())
+        .with_change_callback(Some(Box::new(|entry_change| {
+            restored_names
+                .borrow_mut()
+                .push(entry_change.apath.to_string());
+            Ok(())
+        })));
+    let monitor = TestMonitor::arc();
+    restore(&af, restore_dir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    drop(options);
+    assert_eq!(
+        restored_names.into_inner(),
+        [
+            "/src",
+            "/src/a.rs",
+            "/src/sub",
+            "/src/sub/c.rs",
+            "/doc",
+            "/doc/deep",
+            "/doc/deep/er",
+            "/doc/deep/er/y.md",
+        ]
+    );
+    let dest = restore_dir.path();
+    assert!(dest.join("src/a.rs").is_file());
+    assert!(dest.join("src/sub/c.rs").is_file());
+    assert!(dest.join("doc/deep/er/y.md").is_file());
+    assert!(!dest.join("src/b.c").exists());
+    assert!(!dest.join("src/empty").exists());
+    assert!(!dest.join("doc/x.md").exists());
+}
+
+#[test]
+fn restore_skips_unchanged_existing_files() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("same", b"same content");
+    srcdir.create_file_with_contents("edited", b"original");
+    srcdir.create_file_with_contents("longer", b"short");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    let restore_dir = TempDir::new().unwrap();
+    let dest = restore_dir.path();
+    restore(&af, dest, &Default::default(), TestMonitor::arc()).unwrap();
+    // Change the content without changing the size or mtime, so that only
+    // comparing the content notices.
+    let edited = dest.join("edited");
+    let mtime = FileTime::from_last_modification_time(&edited.metadata().unwrap());
+    std::fs::write(&edited, b"modified").unwrap();
+    filetime::set_file_mtime(&edited, mtime).unwrap();
+    std::fs::write(dest.join("longer"), b"much longer").unwrap();
+
+    let restore_again = |check| {
+        let changed = RefCell::new(Vec::new());
+        let options = RestoreOptions::default()
+            .with_skip_unchanged(Some(check))
+            .with_change_callback(Some(Box::new(|entry_change| {
+                if !matches!(entry_change.change, change::Change::Unchanged { .. }) {
+                    changed.borrow_mut().push(entry_change.apath.to_string());
+                }
+                Ok(())
+            })));
+        let monitor = TestMonitor::arc();
+        restore(&af, dest, &options, monitor.clone()).unwrap();
+        monitor.assert_no_errors();
+        drop(options);
+        (
+            changed.into_inner(),
+            monitor.get_counter(Counter::UnchangedFiles),
+        )
+    };
+
+    let (changed, unchanged_files) = restore_again(UnchangedCheck::Metadata);
+    assert_eq!(changed, ["/", "/longer"]);
+    assert_eq!(unchanged_files, 2);
+    assert_eq!(std::fs::read(&edited).unwrap(), b"modified");
+    assert_eq!(std::fs::read(dest.join("longer")).unwrap(), b"short");
+
+    let (changed, unchanged_files) = restore_again(UnchangedCheck::Content);
+    assert_eq!(changed, ["/", "/edited"]);
+    assert_eq!(unchanged_files, 2);
+    assert_eq!(std::fs::read(&edited).unwrap(), b"original");
+}
+
+#[test]
+fn restore_reads_blocks_ahead() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let content = (0..200_000u32)
+        .flat_map(|i| i.to_le_bytes())
+        .collect::<Vec<u8>>();
+    srcdir.create_file_with_contents("big", &content);
+    let backup_options = BackupOptions::default().with_max_block_size(10_000);
+    backup(&af, srcdir.path(), &backup_options, TestMonitor::arc()).unwrap();
+
+    // Limits that allow several blocks to be read at once, and just one.
+    for parallelism in [
+        Parallelism::default().with_transport_concurrency(8),
+        Parallelism::default()
+            .with_transport_concurrency(4)
+            .with_max_buffered_bytes(1),
+        Parallelism::default().with_transport_concurrency(1),
+    ] {
+        let destdir = TempDir::new().unwrap();
+        let options = RestoreOptions::default().with_parallelism(parallelism);
+        let monitor = TestMonitor::arc();
+        restore(&af, destdir.path(), &options, monitor.clone()).unwrap();
+        monitor.assert_no_errors();
+        assert_eq!(
+            std::fs::read(destdir.path().join("big")).unwrap(),
+            content,
+            "{parallelism:?}"
+        );
+    }
+}
+
+#[test]
+fn combined_block_is_read_once_while_cached() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..20 {
+        srcdir.create_file_with_contents(&format!("small{i:02}"), format!("{i}").as_bytes());
+    }
+    backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+
+    for (cache_bytes, expected_reads, expected_cached) in [(1 << 20, 1, 1), (0, 20, 0)] {
+        let archive = Archive::open_path(af.path()).unwrap();
+        archive.block_dir().set_cache_capacity(cache_bytes);
+        let destdir = TempDir::new().unwrap();
+        let monitor = TestMonitor::arc();
+        restore(
+            &archive,
+            destdir.path(),
+            &RestoreOptions::default(),
+            monitor.clone(),
+        )
+        .unwrap();
+        monitor.assert_no_errors();
+        assert_eq!(
+            monitor.get_counter(Counter::BlockReads),
+            expected_reads,
+            "cache of {cache_bytes} bytes"
+        );
+        assert_eq!(archive.block_dir().cache_usage().0, expected_cached);
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn zero_copy_restore_from_unchanged_originals() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("same", b"original content");
+    let edited = srcdir.create_file_with_contents("edited", b"original content");
+    backup(
+        &af,
+        srcdir.path(),
+        &BackupOptions::default(),
+        TestMonitor::arc(),
+    )
+    .unwrap();
+    std::fs::write(&edited, b"changed content!").unwrap();
+    filetime::set_file_mtime(&edited, FileTime::from_unix_time(1_000_000_000, 0)).unwrap();
+
+    let destdir = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    let options = RestoreOptions::default()
+        .with_zero_copy(true)
+        .with_verify(true);
+    let stats = restore(&af, destdir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.copied_files, 1);
+    monitor.assert_counter(Counter::CopiedFiles, 1);
+    monitor.assert_counter(Counter::VerifiedFiles, 2);
+    for name in ["same", "edited"] {
+        assert_eq!(
+            std::fs::read(destdir.path().join(name)).unwrap(),
+            b"original content"
+        );
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn zero_copy_restore_from_uncompressed_blocks() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let content = (0..20_000u32)
+        .flat_map(|i| i.to_le_bytes())
+        .collect::<Vec<u8>>();
+    let big = srcdir.create_file_with_contents("big", &content);
+    let backup_options = BackupOptions::default()
+        .with_compression(Some(Compression::None))
+        .with_max_block_size(10_000);
+    backup(&af, srcdir.path(), &backup_options, TestMonitor::arc()).unwrap();
+    std::fs::remove_file(big).unwrap();
+
+    let destdir = TempDir::new().unwrap();
+    let monitor = TestMonitor::arc();
+    let options = RestoreOptions::default().with_zero_copy(true);
+    let stats = restore(&af, destdir.path(), &options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.copied_files, 1);
+    monitor.assert_counter(Counter::BlockReads, 0);
+    assert_eq!(std::fs::read(destdir.path().join("big")).unwrap(), content);
+}
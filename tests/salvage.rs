@@ -0,0 +1,134 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for salvaging files from damaged archives.
+
+use std::fs;
+
+use rayon::prelude::ParallelIterator;
+use serde_json::Value;
+use tempfile::TempDir;
+
+use conserve::blockdir::block_relpath;
+use conserve::monitor::test::TestMonitor;
+use conserve::salvage::MANIFEST_NAME;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+fn read_manifest(dir: &TempDir) -> Value {
+    serde_json::from_slice(&fs::read(dir.path().join(MANIFEST_NAME)).unwrap()).unwrap()
+}
+
+fn apaths(manifest_entries: &Value) -> Vec<&str> {
+    manifest_entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["apath"].as_str().unwrap())
+        .collect()
+}
+
+#[test]
+fn salvage_from_bands_without_heads() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_dir("subdir");
+    srcdir.create_file_with_contents("subdir/a", b"old a");
+    srcdir.create_file_with_contents("b", b"b");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    srcdir.create_file_with_contents("subdir/a", b"new a");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    for band in ["b0000", "b0001"] {
+        fs::remove_file(af.path().join(band).join("BANDHEAD")).unwrap();
+    }
+
+    let dest = TempDir::new().unwrap();
+    let stats = salvage(&af, dest.path(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.bands, 2);
+    assert_eq!(stats.recovered_entries, 4);
+    assert_eq!(stats.lost_entries, 0);
+    // The newest version is recovered.
+    assert_eq!(fs::read(dest.path().join("subdir/a")).unwrap(), b"new a");
+    assert_eq!(fs::read(dest.path().join("b")).unwrap(), b"b");
+
+    let manifest = read_manifest(&dest);
+    assert_eq!(
+        apaths(&manifest["recovered"]),
+        ["/", "/b", "/subdir", "/subdir/a"]
+    );
+    assert_eq!(manifest["recovered"][3]["band_id"], "b0001");
+}
+
+#[test]
+fn salvage_older_version_when_blocks_are_missing() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", b"old a");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    let old_blocks: Vec<BlockHash> = af.block_dir().blocks(TestMonitor::arc()).unwrap().collect();
+    srcdir.create_file_with_contents("a", b"new a");
+    srcdir.create_file_with_contents("c", b"c");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    let new_blocks: Vec<BlockHash> = af.block_dir().blocks(TestMonitor::arc()).unwrap().collect();
+    for hash in new_blocks {
+        if !old_blocks.contains(&hash) {
+            fs::remove_file(af.path().join("d").join(block_relpath(&hash))).unwrap();
+        }
+    }
+
+    let dest = TempDir::new().unwrap();
+    let stats = salvage(&af, dest.path(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.recovered_entries, 2);
+    assert_eq!(stats.lost_entries, 1);
+    assert_eq!(fs::read(dest.path().join("a")).unwrap(), b"old a");
+    assert!(!dest.path().join("c").exists());
+
+    let manifest = read_manifest(&dest);
+    assert_eq!(manifest["recovered"][1]["band_id"], "b0000");
+    assert_eq!(apaths(&manifest["lost"]), ["/c"]);
+    assert!(manifest["lost"][0]["problem"]
+        .as_str()
+        .unwrap()
+        .contains("missing"));
+}
+
+#[test]
+fn unreadable_index_hunks_are_listed() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for name in ["a", "b", "c"] {
+        srcdir.create_file(name);
+    }
+    let options = BackupOptions::default().with_max_entries_per_hunk(1);
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    fs::write(af.path().join("b0000/i/00000/000000002"), b"garbage").unwrap();
+
+    let dest = TempDir::new().unwrap();
+    let stats = salvage(&af, dest.path(), TestMonitor::arc()).unwrap();
+    assert_eq!(stats.unreadable_index_hunks, 1);
+    assert_eq!(stats.recovered_entries, 3);
+    assert!(dest.path().join("c").is_file());
+    let manifest = read_manifest(&dest);
+    assert_eq!(manifest["unreadable"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn destination_must_be_empty() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let dest = TempDir::new().unwrap();
+    fs::write(dest.path().join("x"), b"x").unwrap();
+    assert!(matches!(
+        salvage(&af, dest.path(), TestMonitor::arc()),
+        Err(Error::DestinationNotEmpty)
+    ));
+}
@@ -0,0 +1,74 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for the one-call backup and restore functions.
+
+use assert_fs::prelude::*;
+use assert_fs::TempDir;
+
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+#[test]
+fn backup_and_restore_by_path() {
+    let af = ScratchArchive::new();
+    let archive_path = af.path().to_str().unwrap();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("hello", b"hello world");
+    srcdir.create_dir("subdir");
+    srcdir.create_file("subdir/subfile");
+
+    let summary = backup_paths(archive_path, srcdir.path(), &Default::default()).unwrap();
+    assert_eq!(summary.band_id, BandId::zero());
+    assert_eq!(summary.stats.files, 2);
+    assert!(summary.errors.is_empty());
+
+    srcdir.create_file("new");
+    let summary = backup_paths(archive_path, srcdir.path(), &Default::default()).unwrap();
+    assert_eq!(summary.band_id, BandId::new(&[1]));
+    assert_eq!(summary.stats.new_files, 1);
+
+    let restore_dir = TempDir::new().unwrap();
+    let summary = restore_band(archive_path, None, restore_dir.path(), Default::default()).unwrap();
+    assert_eq!(summary.band_id, BandId::new(&[1]));
+    assert_eq!(summary.files, 3);
+    assert_eq!(summary.dirs, 2);
+    assert_eq!(summary.file_bytes, 11 + 8 + 8);
+    assert!(summary.errors.is_empty());
+    restore_dir.child("hello").assert("hello world");
+    restore_dir.child("new").assert("contents");
+
+    let restore_dir = TempDir::new().unwrap();
+    let summary = restore_band(
+        archive_path,
+        Some(BandId::zero()),
+        restore_dir.path(),
+        Default::default(),
+    )
+    .unwrap();
+    assert_eq!(summary.band_id, BandId::zero());
+    assert_eq!(summary.files, 2);
+    restore_dir.child("new").assert(predicates::path::missing());
+}
+
+#[test]
+fn backup_to_missing_archive_fails() {
+    let tempdir = TempDir::new().unwrap();
+    let srcdir = TreeFixture::new();
+    let archive_path = tempdir.child("nothing");
+    assert!(backup_paths(
+        archive_path.path().to_str().unwrap(),
+        srcdir.path(),
+        &Default::default()
+    )
+    .is_err());
+}
@@ -0,0 +1,148 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for reading the content of stored files.
+
+use std::io::{Read, Seek, SeekFrom};
+
+#[cfg(feature = "async")]
+use futures::executor::block_on;
+
+#[cfg(feature = "async")]
+use conserve::blockdir::block_relpath;
+use conserve::monitor::test::TestMonitor;
+use conserve::sparse::Hole;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+/// Back up a file stored in several blocks, and return the archive and its content.
+fn archive_with_file_in_blocks() -> (ScratchArchive, Vec<u8>) {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    let content: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    srcdir.create_file_with_contents("file", &content);
+    let options = BackupOptions::default()
+        .with_max_block_size(1000)
+        .with_small_file_cap(0);
+    backup(&af, srcdir.path(), &options, TestMonitor::arc()).unwrap();
+    (af, content)
+}
+
+#[test]
+fn seek_within_and_between_blocks() {
+    let (af, content) = archive_with_file_in_blocks();
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let mut file = st.open_file(&"/file".into(), TestMonitor::arc()).unwrap();
+    assert!(file.entry().addrs.len() >= 10);
+
+    let mut buf = [0; 100];
+    for pos in [5000, 5100, 4950, 0, 9950, 1234] {
+        assert_eq!(file.seek(SeekFrom::Start(pos)).unwrap(), pos);
+        file.read_exact(&mut buf[..50]).unwrap();
+        assert_eq!(
+            buf[..50],
+            content[pos as usize..pos as usize + 50],
+            "at {pos}"
+        );
+    }
+
+    assert_eq!(file.seek(SeekFrom::Current(-100)).unwrap(), 1184);
+    let mut rest = Vec::new();
+    file.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, content[1184..]);
+
+    assert_eq!(file.seek(SeekFrom::End(-10)).unwrap(), 9990);
+    rest.clear();
+    file.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, content[9990..]);
+
+    assert_eq!(file.seek(SeekFrom::End(10)).unwrap(), 10_010);
+    assert_eq!(file.read(&mut buf).unwrap(), 0);
+    assert!(file.seek(SeekFrom::Current(-20_000)).is_err());
+}
+
+#[test]
+fn seek_into_holes() {
+    let (af, content) = archive_with_file_in_blocks();
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let mut entry = st.lookup(&"/file".into()).unwrap().unwrap();
+    // Pretend the file has holes at the start, in the middle, and at the end.
+    entry.holes = vec![
+        Hole { start: 0, len: 100 },
+        Hole {
+            start: 3100,
+            len: 500,
+        },
+        Hole {
+            start: 10_600,
+            len: 400,
+        },
+    ];
+    let mut expected = vec![0; 100];
+    expected.extend_from_slice(&content[..3000]);
+    expected.extend_from_slice(&[0; 500]);
+    expected.extend_from_slice(&content[3000..]);
+    expected.extend_from_slice(&[0; 400]);
+    assert_eq!(entry.size(), Some(expected.len() as u64));
+
+    let mut file = st.open_entry(entry, TestMonitor::arc()).unwrap();
+    let mut buf = [0; 300];
+    for pos in [3000, 50, 3300, 3599, 10_500, 10_700, 200] {
+        file.seek(SeekFrom::Start(pos)).unwrap();
+        let len = file.read(&mut buf).unwrap();
+        assert!(len > 0);
+        assert_eq!(
+            buf[..len],
+            expected[pos as usize..pos as usize + len],
+            "at {pos}"
+        );
+    }
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let mut all = Vec::new();
+    file.read_to_end(&mut all).unwrap();
+    assert_eq!(all, expected);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_read() {
+    let (af, content) = archive_with_file_in_blocks();
+    let st = af.open_stored_tree(BandSelectionPolicy::Latest).unwrap();
+    let mut file = st.open_file(&"/file".into(), TestMonitor::arc()).unwrap();
+    let mut read = Vec::new();
+    block_on(futures::io::AsyncReadExt::read_to_end(&mut file, &mut read)).unwrap();
+    assert_eq!(read, content);
+
+    file.seek(SeekFrom::Start(4321)).unwrap();
+    let mut buf = [0; 2000];
+    block_on(futures::io::AsyncReadExt::read_exact(&mut file, &mut buf)).unwrap();
+    assert_eq!(buf, content[4321..6321]);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn async_read_of_missing_block_is_an_error() {
+    let (af, _content) = archive_with_file_in_blocks();
+    // Open the archive again so that blocks aren't cached from the backup.
+    let archive = Archive::open_path(af.path()).unwrap();
+    let st = archive
+        .open_stored_tree(BandSelectionPolicy::Latest)
+        .unwrap();
+    let mut file = st.open_file(&"/file".into(), TestMonitor::arc()).unwrap();
+    let hash = file.entry().addrs[3].hash.clone();
+    af.transport()
+        .remove_file(&format!("d/{}", block_relpath(&hash)))
+        .unwrap();
+    let mut read = Vec::new();
+    assert!(block_on(futures::io::AsyncReadExt::read_to_end(&mut file, &mut read)).is_err());
+    assert_eq!(read.len(), 3000);
+}
@@ -10,10 +10,17 @@
 // MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
 // GNU General Public License for more details.
 
+use std::io::Read;
+use std::sync::Arc;
+
 use assert_fs::prelude::*;
+use bytes::Bytes;
 use url::Url;
 
-use conserve::transport::{open_transport, ListDir};
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::ScratchArchive;
+use conserve::transport::{self, open_local_transport, open_transport, ListDir, Metadata};
+use conserve::*;
 
 #[test]
 fn open_local() {
@@ -68,3 +75,92 @@ fn unsupported_location_urls() {
         "Unsupported URL scheme \"ftp\""
     );
 }
+
+/// A transport that can only read, as for an archive served over HTTP.
+#[derive(Debug)]
+struct ReadOnlyTransport(Arc<dyn Transport>);
+
+type TransportResult<T> = std::result::Result<T, transport::Error>;
+
+impl Transport for ReadOnlyTransport {
+    fn list_dir(&self, relpath: &str) -> TransportResult<ListDir> {
+        self.0.list_dir(relpath)
+    }
+
+    fn read_file(&self, path: &str) -> TransportResult<Bytes> {
+        self.0.read_file(path)
+    }
+
+    fn read_range(&self, path: &str, start: u64, len: u64) -> TransportResult<Bytes> {
+        self.0.read_range(path, start, len)
+    }
+
+    fn metadata(&self, relpath: &str) -> TransportResult<Metadata> {
+        self.0.metadata(relpath)
+    }
+
+    fn sub_transport(&self, relpath: &str) -> Arc<dyn Transport> {
+        Arc::new(ReadOnlyTransport(self.0.sub_transport(relpath)))
+    }
+
+    fn create_dir(&self, relpath: &str) -> TransportResult<()> {
+        panic!("create_dir({relpath:?}) on a read-only transport")
+    }
+
+    fn write_file(&self, relpath: &str, _content: &[u8]) -> TransportResult<()> {
+        panic!("write_file({relpath:?}) on a read-only transport")
+    }
+
+    fn remove_file(&self, relpath: &str) -> TransportResult<()> {
+        panic!("remove_file({relpath:?}) on a read-only transport")
+    }
+
+    fn remove_dir_all(&self, relpath: &str) -> TransportResult<()> {
+        panic!("remove_dir_all({relpath:?}) on a read-only transport")
+    }
+}
+
+#[test]
+fn read_archive_through_read_only_transport() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let transport = ReadOnlyTransport(open_local_transport(af.path()).unwrap());
+    let archive = Archive::open(Arc::new(transport)).unwrap();
+    let monitor = TestMonitor::arc();
+
+    let infos = archive.list_band_infos(monitor.clone()).unwrap();
+    assert_eq!(infos.len(), 2);
+    assert!(infos.iter().all(|info| info.is_closed));
+
+    let stored_tree = archive
+        .open_stored_tree(BandSelectionPolicy::Latest)
+        .unwrap();
+    let entry = stored_tree.lookup(&"/hello2".into()).unwrap().unwrap();
+    assert_eq!(entry.kind(), Kind::File);
+    let mut content = String::new();
+    stored_tree
+        .open_file(&"/subdir/subfile".into(), monitor.clone())
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, "contents");
+    monitor.assert_no_errors();
+}
+
+/// Transports that don't implement `write_new_file` still build, and refuse
+/// to take the gc lock rather than taking it unsafely.
+#[test]
+fn write_new_file_is_unsupported_by_default() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let transport = ReadOnlyTransport(open_local_transport(af.path()).unwrap());
+    let err = transport.write_new_file("GC_LOCK", b"{}\n").unwrap_err();
+    assert_eq!(err.kind(), transport::ErrorKind::Unsupported);
+
+    let archive = Archive::open(Arc::new(transport)).unwrap();
+    let err = GarbageCollectionLock::new(&archive).unwrap_err();
+    assert!(
+        err.to_string().contains("Not supported by this transport"),
+        "{err}"
+    );
+}
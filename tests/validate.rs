@@ -0,0 +1,374 @@
+// Copyright 2024 Martin Pool.
+
+// This program is free software; you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation; either version 2 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+//! Tests for validation stats and records of validation kept in the archive.
+
+use std::fs;
+use std::path::Path;
+
+use rayon::prelude::ParallelIterator;
+
+use conserve::blockdir::block_relpath;
+use conserve::counters::Counter;
+use conserve::monitor::test::TestMonitor;
+use conserve::test_fixtures::{ScratchArchive, TreeFixture};
+use conserve::*;
+
+fn incremental() -> ValidateOptions {
+    ValidateOptions::default().with_incremental(true)
+}
+
+#[test]
+fn incremental_validation_only_checks_new_content() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", b"a");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    let old_blocks: Vec<BlockHash> = af.block_dir().blocks(TestMonitor::arc()).unwrap().collect();
+    assert_eq!(old_blocks.len(), 1);
+
+    // The first incremental validation checks everything.
+    let monitor = TestMonitor::arc();
+    af.validate(&incremental(), monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(monitor.get_counter(Counter::BlockReads), 1);
+
+    srcdir.create_file_with_contents("b", b"b");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    // Damage to content that was already validated isn't noticed by incremental validation...
+    fs::write(
+        af.path().join("d").join(block_relpath(&old_blocks[0])),
+        b"garbage",
+    )
+    .unwrap();
+    let monitor = TestMonitor::arc();
+    af.validate(&incremental(), monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(monitor.get_counter(Counter::BlockReads), 1);
+
+    // ... but is by a full validation.
+    let monitor = TestMonitor::arc();
+    af.validate(&ValidateOptions::default(), monitor.clone())
+        .unwrap();
+    assert!(!monitor.take_errors().is_empty());
+}
+
+#[test]
+fn problems_are_reported_again_by_incremental_validation() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", b"a");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    let blocks: Vec<BlockHash> = af.block_dir().blocks(TestMonitor::arc()).unwrap().collect();
+    fs::remove_file(af.path().join("d").join(block_relpath(&blocks[0]))).unwrap();
+
+    for _ in 0..2 {
+        let monitor = TestMonitor::arc();
+        af.validate(&incremental(), monitor.clone()).unwrap();
+        let errors = monitor.take_errors();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], Error::BlockMissing { .. }));
+    }
+}
+
+#[test]
+fn validation_marker_round_trip() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    assert_eq!(af.last_validated().unwrap(), None);
+
+    let options = ValidateOptions::default();
+    let monitor = TestMonitor::arc();
+    let stats = af.validate(&options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.bands, 2);
+    assert_eq!(stats.skipped_bands, 0);
+    assert_eq!(stats.damaged_bands, 0);
+    assert_eq!(
+        stats.blocks,
+        af.block_dir().blocks(TestMonitor::arc()).unwrap().count()
+    );
+    assert_eq!(stats.missing_blocks, 0);
+    // Validating through the library doesn't write a marker by itself.
+    assert_eq!(af.last_validated().unwrap(), None);
+
+    let marker = ValidationMarker::new(&options, stats);
+    af.set_last_validated(&marker).unwrap();
+    let read_back = af.last_validated().unwrap().unwrap();
+    assert_eq!(read_back, marker);
+    assert_eq!(read_back.conserve_version, conserve::version());
+    // The marker doesn't upset later validations.
+    let monitor = TestMonitor::arc();
+    af.validate(&options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+}
+
+#[test]
+fn sampled_validation_reads_some_blocks() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    for i in 0..100 {
+        srcdir.create_file_with_contents(&format!("f{i}"), format!("content {i}").as_bytes());
+    }
+    // Store each file in its own block.
+    let backup_options = BackupOptions::default().with_small_file_cap(0);
+    backup(&af, srcdir.path(), &backup_options, TestMonitor::arc()).unwrap();
+    let blocks: Vec<BlockHash> = af.block_dir().blocks(TestMonitor::arc()).unwrap().collect();
+    assert_eq!(blocks.len(), 100);
+
+    let sample = BlockSample {
+        fraction: 0.2,
+        seed: 1234,
+    };
+    let options = ValidateOptions::default().with_sample(Some(sample));
+    let monitor = TestMonitor::arc();
+    let stats = af.validate(&options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    let sampled: Vec<&BlockHash> = blocks.iter().filter(|hash| sample.contains(hash)).collect();
+    assert_eq!(stats.blocks, sampled.len());
+    assert_eq!(stats.skipped_blocks, 100 - sampled.len());
+    assert!(stats.blocks > 5 && stats.blocks < 40, "{stats:?}");
+    assert_eq!(monitor.get_counter(Counter::BlockReads), sampled.len());
+
+    // Damage in sampled blocks is found; damage elsewhere isn't.
+    let unsampled = blocks.iter().find(|hash| !sample.contains(hash)).unwrap();
+    fs::write(af.path().join("d").join(block_relpath(unsampled)), b"junk").unwrap();
+    let monitor = TestMonitor::arc();
+    af.validate(&options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    fs::write(af.path().join("d").join(block_relpath(sampled[0])), b"junk").unwrap();
+    let monitor = TestMonitor::arc();
+    af.validate(&options, monitor.clone()).unwrap();
+    assert!(!monitor.take_errors().is_empty());
+
+    // A different seed picks different blocks.
+    let other = BlockSample {
+        seed: 5678,
+        ..sample
+    };
+    assert_ne!(
+        blocks
+            .iter()
+            .filter(|hash| other.contains(hash))
+            .collect::<Vec<_>>(),
+        sampled
+    );
+}
+
+#[test]
+fn sampled_validation_finds_missing_blocks() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", b"a");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    let blocks: Vec<BlockHash> = af.block_dir().blocks(TestMonitor::arc()).unwrap().collect();
+    fs::remove_file(af.path().join("d").join(block_relpath(&blocks[0]))).unwrap();
+
+    let options = ValidateOptions::default().with_sample(Some(BlockSample {
+        fraction: 0.0,
+        seed: 0,
+    }));
+    let monitor = TestMonitor::arc();
+    let stats = af.validate(&options, monitor.clone()).unwrap();
+    assert_eq!(stats.missing_blocks, 1);
+    let errors = monitor.take_errors();
+    assert!(matches!(errors[..], [Error::BlockMissing { .. }]));
+}
+
+#[test]
+fn validate_one_band() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", b"old a");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    let old_blocks: Vec<BlockHash> = af.block_dir().blocks(TestMonitor::arc()).unwrap().collect();
+    srcdir.create_file_with_contents("a", b"new a");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    // Damage the block used only by the first band.
+    fs::write(
+        af.path().join("d").join(block_relpath(&old_blocks[0])),
+        b"garbage",
+    )
+    .unwrap();
+
+    let options = ValidateOptions::default();
+    let monitor = TestMonitor::arc();
+    let stats = af
+        .validate_band(BandId::new(&[1]), &options, monitor.clone())
+        .unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.bands, 1);
+    assert_eq!(stats.blocks, 1);
+    assert_eq!(stats.missing_blocks, 0);
+    assert_eq!(monitor.get_counter(Counter::BlockReads), 1);
+
+    let monitor = TestMonitor::arc();
+    let stats = af
+        .validate_band(BandId::zero(), &options, monitor.clone())
+        .unwrap();
+    assert_eq!(stats.missing_blocks, 1);
+    assert_eq!(monitor.take_errors().len(), 1);
+
+    // Without reading blocks, only their presence is checked.
+    let quick = ValidateOptions::default().with_skip_block_hashes(true);
+    let monitor = TestMonitor::arc();
+    let stats = af
+        .validate_band(BandId::zero(), &quick, monitor.clone())
+        .unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.skipped_blocks, 1);
+    fs::remove_file(af.path().join("d").join(block_relpath(&old_blocks[0]))).unwrap();
+    let monitor = TestMonitor::arc();
+    let stats = af
+        .validate_band(BandId::zero(), &quick, monitor.clone())
+        .unwrap();
+    assert_eq!(stats.missing_blocks, 1);
+    assert!(matches!(
+        monitor.take_errors()[..],
+        [Error::BlockMissing { .. }]
+    ));
+
+    assert!(matches!(
+        af.validate_band(BandId::new(&[9]), &options, TestMonitor::arc()),
+        Err(Error::BandNotFound { .. })
+    ));
+}
+
+#[test]
+fn quick_validation_finds_implausible_block_files() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", b"a");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+    let blocks: Vec<BlockHash> = af.block_dir().blocks(TestMonitor::arc()).unwrap().collect();
+    let quick = ValidateOptions::default().with_skip_block_hashes(true);
+    let monitor = TestMonitor::arc();
+    let stats = af.validate(&quick, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.blocks, 0);
+    assert_eq!(stats.skipped_blocks, 1);
+    assert_eq!(monitor.get_counter(Counter::BlockReads), 0);
+
+    // Garbage of a plausible size isn't noticed without reading the block...
+    let block_path = af.path().join("d").join(block_relpath(&blocks[0]));
+    fs::write(&block_path, b"garbage").unwrap();
+    let monitor = TestMonitor::arc();
+    af.validate(&quick, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+
+    // ... but a truncated block file is.
+    fs::write(&block_path, b"").unwrap();
+    for validate_band in [false, true] {
+        let monitor = TestMonitor::arc();
+        let stats = if validate_band {
+            af.validate_band(BandId::zero(), &quick, monitor.clone())
+        } else {
+            af.validate(&quick, monitor.clone())
+        }
+        .unwrap();
+        assert_eq!(stats.missing_blocks, 1);
+        let errors = monitor.take_errors();
+        assert!(
+            matches!(errors[..], [Error::BlockFileImplausible { size: 0, .. }]),
+            "{errors:?}"
+        );
+    }
+}
+
+#[test]
+fn index_addresses_past_the_end_of_a_block_are_reported() {
+    let af = ScratchArchive::new();
+    let srcdir = TreeFixture::new();
+    srcdir.create_file_with_contents("a", b"hello");
+    backup(&af, srcdir.path(), &Default::default(), TestMonitor::arc()).unwrap();
+
+    // Make the index claim the file is longer than its block. The block itself is
+    // intact, so hashing blocks doesn't find the problem.
+    let hunk_path = af.path().join("b0000/i/00000/000000000");
+    let json = snap::raw::Decoder::new()
+        .decompress_vec(&fs::read(&hunk_path).unwrap())
+        .unwrap();
+    let mut entries: serde_json::Value = serde_json::from_slice(&json).unwrap();
+    let entry = entries
+        .as_array_mut()
+        .unwrap()
+        .iter_mut()
+        .find(|entry| entry["apath"] == "/a")
+        .unwrap();
+    entry["addrs"][0]["len"] = 100.into();
+    write_hunk(&hunk_path, &entries);
+
+    for validate_band in [false, true] {
+        let monitor = TestMonitor::arc();
+        let stats = if validate_band {
+            af.validate_band(BandId::zero(), &ValidateOptions::default(), monitor.clone())
+        } else {
+            af.validate(&ValidateOptions::default(), monitor.clone())
+        }
+        .unwrap();
+        assert_eq!(stats.missing_blocks, 1);
+        let errors = monitor.take_errors();
+        match &errors[..] {
+            [Error::AddressOutOfRange {
+                band_id,
+                apath,
+                len,
+                block_len,
+                ..
+            }] => {
+                assert_eq!(*band_id, BandId::zero());
+                assert_eq!(apath, "/a");
+                assert_eq!(*len, 100);
+                assert_eq!(*block_len, 5);
+            }
+            _ => panic!("Unexpected errors {errors:?}"),
+        }
+    }
+
+    // An address whose end overflows is also reported.
+    let entry = &mut entries.as_array_mut().unwrap()[1];
+    entry["addrs"][0]["start"] = 1.into();
+    entry["addrs"][0]["len"] = u64::MAX.into();
+    write_hunk(&hunk_path, &entries);
+    let monitor = TestMonitor::arc();
+    let stats = af
+        .validate(&ValidateOptions::default(), monitor.clone())
+        .unwrap();
+    assert_eq!(stats.damaged_bands, 1);
+    let errors = monitor.take_errors();
+    assert!(
+        matches!(&errors[..], [Error::InvalidMetadata { details }] if details.contains("overflows")),
+        "{errors:?}"
+    );
+}
+
+#[test]
+fn validate_on_one_thread() {
+    let af = ScratchArchive::new();
+    af.store_two_versions();
+    let options =
+        ValidateOptions::default().with_parallelism(Parallelism::default().with_threads(1));
+    let monitor = TestMonitor::arc();
+    let stats = af.validate(&options, monitor.clone()).unwrap();
+    monitor.assert_no_errors();
+    assert_eq!(stats.bands, 2);
+    let stats = af
+        .validate_band(BandId::new(&[1]), &options, monitor.clone())
+        .unwrap();
+    assert_eq!(stats.bands, 1);
+    monitor.assert_no_errors();
+}
+
+fn write_hunk(path: &Path, entries: &serde_json::Value) {
+    let json = serde_json::to_vec(entries).unwrap();
+    fs::write(path, snap::raw::Encoder::new().compress_vec(&json).unwrap()).unwrap();
+}